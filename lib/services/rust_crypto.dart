@@ -585,73 +585,68 @@ class RustCrypto {
   }) {
     _loadLibrary();
 
-    final decryptFileToMemoryFunc = _lib!.lookupFunction<
-      ffi.Int32 Function(
+    final decryptFileToBufferFunc = _lib!.lookupFunction<
+      ffi.Pointer<ffi.Void> Function(
         ffi.Pointer<ffi.Char> inputPathPtr,
         ffi.Pointer<ffi.Uint8> passwordPtr,
         ffi.Size passwordLen,
-        ffi.Pointer<ffi.Uint8> outputPtr,
-        ffi.Pointer<ffi.Size> outputLen,
         ffi.Bool isMobile,
         ffi.Size cpuCores,
       ),
-      int Function(
+      ffi.Pointer<ffi.Void> Function(
         ffi.Pointer<ffi.Char> inputPathPtr,
         ffi.Pointer<ffi.Uint8> passwordPtr,
         int passwordLen,
-        ffi.Pointer<ffi.Uint8> outputPtr,
-        ffi.Pointer<ffi.Size> outputLen,
         bool isMobile,
         int cpuCores,
       )
-    >('decrypt_file_to_memory');
+    >('decrypt_file_to_buffer');
+    final bufferDataFunc = _lib!.lookupFunction<
+      ffi.Pointer<ffi.Uint8> Function(ffi.Pointer<ffi.Void> buffer),
+      ffi.Pointer<ffi.Uint8> Function(ffi.Pointer<ffi.Void> buffer)
+    >('kyrie_buffer_data');
+    final bufferLenFunc = _lib!.lookupFunction<
+      ffi.Size Function(ffi.Pointer<ffi.Void> buffer),
+      int Function(ffi.Pointer<ffi.Void> buffer)
+    >('kyrie_buffer_len');
+    final bufferFreeFunc = _lib!.lookupFunction<
+      ffi.Void Function(ffi.Pointer<ffi.Void> buffer),
+      void Function(ffi.Pointer<ffi.Void> buffer)
+    >('kyrie_buffer_free');
+    final lastErrorFunc = _lib!.lookupFunction<
+      ffi.Int32 Function(),
+      int Function()
+    >('kyrie_last_error');
 
     final inputPathPtr = inputPath.toNativeUtf8().cast<ffi.Char>();
     final passwordPtr = _allocateUint8List(password);
-    final outputLenPtr = calloc<ffi.Size>();
     final cpuCores = Platform.numberOfProcessors;
 
     try {
-      var result = decryptFileToMemoryFunc(
+      final buffer = decryptFileToBufferFunc(
         inputPathPtr,
         passwordPtr,
         password.length,
-        ffi.nullptr,
-        outputLenPtr,
         isMobile,
         cpuCores,
       );
 
-      if (result != 0) {
-        throw Exception('File decryption to memory failed with code: $result');
+      if (buffer == ffi.nullptr) {
+        throw Exception('File decryption to memory failed with code: ${lastErrorFunc()}');
       }
 
-      final outputLen = outputLenPtr.value;
-      final outputPtr = calloc<ffi.Uint8>(outputLen);
-
       try {
-        result = decryptFileToMemoryFunc(
-          inputPathPtr,
-          passwordPtr,
-          password.length,
-          outputPtr,
-          outputLenPtr,
-          isMobile,
-          cpuCores,
-        );
-
-        if (result != 0) {
-          throw Exception('File decryption to memory failed with code: $result');
+        final outputLen = bufferLenFunc(buffer);
+        if (outputLen == 0) {
+          return Uint8List(0);
         }
-
-        return Uint8List.fromList(outputPtr.asTypedList(outputLen));
+        return Uint8List.fromList(bufferDataFunc(buffer).asTypedList(outputLen));
       } finally {
-        calloc.free(outputPtr);
+        bufferFreeFunc(buffer);
       }
     } finally {
       calloc.free(inputPathPtr);
       calloc.free(passwordPtr);
-      calloc.free(outputLenPtr);
     }
   }
 
@@ -0,0 +1,83 @@
+use crate::errors;
+
+pub const BACKEND_SOFTWARE: u32 = 0;
+pub const BACKEND_X86_AES_NI: u32 = 1;
+pub const BACKEND_ARMV8_CRYPTO: u32 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct KyrieAccelerationInfo {
+    pub backend: u32,
+    pub aes: bool,
+    pub carryless_multiply: bool,
+    pub simd: bool,
+    pub sha2: bool,
+}
+
+#[no_mangle]
+pub extern "C" fn get_acceleration_info(info_ptr: *mut KyrieAccelerationInfo) -> i32 {
+    if info_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe { *info_ptr = detect() };
+    0
+}
+
+// aes and polyval pick their backend at runtime with the same CPU feature
+// checks, so this reports the code path AES-GCM actually takes: AES needs
+// the AES instructions and GHASH needs carry-less multiply (PCLMULQDQ on
+// x86, PMULL on ARMv8). On aarch64 the ARMv8 backends are only compiled
+// in with `--cfg aes_armv8` and `--cfg polyval_armv8`, which
+// .cargo/config.toml sets; a build without them stays in software.
+pub fn detect() -> KyrieAccelerationInfo {
+    let mut info = cpu_features();
+    if info.aes && info.carryless_multiply && hardware_backend_built() {
+        info.backend = if cfg!(target_arch = "aarch64") { BACKEND_ARMV8_CRYPTO } else { BACKEND_X86_AES_NI };
+    }
+    info
+}
+
+fn hardware_backend_built() -> bool {
+    !cfg!(target_arch = "aarch64") || cfg!(all(aes_armv8, polyval_armv8))
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn cpu_features() -> KyrieAccelerationInfo {
+    KyrieAccelerationInfo {
+        backend: BACKEND_SOFTWARE,
+        aes: std::arch::is_x86_feature_detected!("aes"),
+        carryless_multiply: std::arch::is_x86_feature_detected!("pclmulqdq"),
+        simd: std::arch::is_x86_feature_detected!("avx2"),
+        sha2: std::arch::is_x86_feature_detected!("sha"),
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn cpu_features() -> KyrieAccelerationInfo {
+    KyrieAccelerationInfo {
+        backend: BACKEND_SOFTWARE,
+        aes: std::arch::is_aarch64_feature_detected!("aes"),
+        carryless_multiply: std::arch::is_aarch64_feature_detected!("pmull"),
+        simd: std::arch::is_aarch64_feature_detected!("neon"),
+        sha2: std::arch::is_aarch64_feature_detected!("sha2"),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn cpu_features() -> KyrieAccelerationInfo {
+    KyrieAccelerationInfo::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_matches_detected_features() {
+        let mut info = KyrieAccelerationInfo::default();
+        assert_eq!(get_acceleration_info(&mut info), 0);
+        assert_eq!(info, detect());
+        assert_eq!(info.backend == BACKEND_SOFTWARE, !(info.aes && info.carryless_multiply && hardware_backend_built()));
+        assert_eq!(get_acceleration_info(std::ptr::null_mut()), -1);
+    }
+}
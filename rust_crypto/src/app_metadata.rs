@@ -0,0 +1,229 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::slice;
+use zeroize::Zeroizing;
+
+use crate::cipher::BodyCipher;
+use crate::format::{read_header, ContainerHeader};
+use crate::kdf::{self, KeyPurpose};
+use crate::{errors, random_nonce, reencrypt, NONCE_SIZE, TAG_SIZE};
+
+// Tags, album ids and the like that the app attaches to a file. Entries are
+// sorted by key and sealed as one blob, so neither keys nor sizes show in
+// the header.
+pub type Entries = BTreeMap<String, Zeroizing<Vec<u8>>>;
+
+// A null `value_ptr` removes the key. Only the header is rewritten.
+#[no_mangle]
+pub extern "C" fn set_metadata(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    key_ptr: *const c_char,
+    value_ptr: *const u8,
+    value_len: usize,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let key = match CStr::from_ptr(key_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let value = if value_ptr.is_null() {
+            None
+        } else {
+            Some(slice::from_raw_parts(value_ptr, value_len))
+        };
+
+        match set_metadata_internal(Path::new(path), password, key, value) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_metadata(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    key_ptr: *const c_char,
+    value_ptr: *mut u8,
+    value_len: *mut usize,
+) -> i32 {
+    if value_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let key = match CStr::from_ptr(key_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        match get_metadata_internal(Path::new(path), password, key) {
+            Ok(value) => {
+                *value_len = value.len();
+                if !value_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(value.as_ptr(), value_ptr, value.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Writes the keys as a JSON array of strings.
+#[no_mangle]
+pub extern "C" fn list_metadata_keys(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    json_ptr: *mut u8,
+    json_len: *mut usize,
+) -> i32 {
+    if json_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        match list_metadata_keys_internal(Path::new(path), password).and_then(|keys| Ok(serde_json::to_vec(&keys)?)) {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn set_metadata_internal(
+    path: &Path,
+    password: &[u8],
+    key: &str,
+    value: Option<&[u8]>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if key.is_empty() || key.len() > u8::MAX as usize || value.is_some_and(|v| v.len() > u16::MAX as usize) {
+        return Err("Invalid metadata entry".into());
+    }
+    reencrypt::rewrite_header(path, password, |header, master| {
+        let mut entries = open(header, master)?;
+        match value {
+            Some(value) => entries.insert(key.to_string(), Zeroizing::new(value.to_vec())),
+            None => entries.remove(key),
+        };
+        header.app_metadata = if entries.is_empty() { Vec::new() } else { seal(header, master, &entries)? };
+        Ok(())
+    })
+}
+
+pub fn get_metadata_internal(path: &Path, password: &[u8], key: &str) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+    read_entries(path, password)?.remove(key).ok_or_else(|| "Metadata key not found".into())
+}
+
+pub fn list_metadata_keys_internal(path: &Path, password: &[u8]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(read_entries(path, password)?.into_keys().collect())
+}
+
+fn read_entries(path: &Path, password: &[u8]) -> Result<Entries, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    let master = Zeroizing::new(header.master_key(password)?);
+    // An empty section would otherwise accept any password.
+    header.verify_file_id(&master).map_err(|_| "Invalid password")?;
+    open(&header, &master)
+}
+
+pub fn seal(header: &ContainerHeader, master: &[u8; 32], entries: &Entries) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut plain = Zeroizing::new(Vec::new());
+    for (key, value) in entries {
+        plain.push(key.len() as u8);
+        plain.extend_from_slice(key.as_bytes());
+        plain.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        plain.extend_from_slice(value);
+    }
+    let cipher = BodyCipher::new(header.cipher, &kdf::subkey(master, KeyPurpose::AppMetadata))?;
+    let nonce = random_nonce();
+    let sealed = cipher.encrypt(Nonce::from_slice(&nonce), plain.as_slice()).map_err(|_| "Encryption failed")?;
+    Ok([&nonce[..], &sealed].concat())
+}
+
+pub fn open(header: &ContainerHeader, master: &[u8; 32]) -> Result<Entries, Box<dyn std::error::Error>> {
+    let mut entries = Entries::new();
+    if header.app_metadata.is_empty() {
+        return Ok(entries);
+    }
+    if header.app_metadata.len() < NONCE_SIZE + TAG_SIZE {
+        return Err("Invalid header field".into());
+    }
+    let (nonce, sealed) = header.app_metadata.split_at(NONCE_SIZE);
+    let cipher = BodyCipher::new(header.cipher, &kdf::subkey(master, KeyPurpose::AppMetadata))?;
+    let plain = Zeroizing::new(cipher.decrypt(Nonce::from_slice(nonce), sealed).map_err(|_| "Decryption failed")?);
+
+    let mut rest = plain.as_slice();
+    while let Some((&key_len, tail)) = rest.split_first() {
+        let (key, tail) = tail.split_at_checked(key_len as usize).ok_or("Invalid header field")?;
+        let (value_len, tail) = tail.split_at_checked(2).ok_or("Invalid header field")?;
+        let value_len = u16::from_le_bytes(value_len.try_into()?) as usize;
+        let (value, tail) = tail.split_at_checked(value_len).ok_or("Invalid header field")?;
+        entries.insert(String::from_utf8(key.to_vec())?, Zeroizing::new(value.to_vec()));
+        rest = tail;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, encrypt_file_internal, errors};
+    use std::fs;
+
+    #[test]
+    fn test_entries_survive_header_rewrites_and_leave_the_body_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("photo.jpg");
+        let encrypted = dir.path().join("photo.kyl");
+        let decrypted = dir.path().join("photo.out");
+        fs::write(&plain, vec![3u8; 100_000]).unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        let (_, body_start) = read_header(&encrypted).unwrap();
+        let body = fs::read(&encrypted).unwrap()[body_start as usize..].to_vec();
+
+        let exif = vec![9u8; 600];
+        set_metadata_internal(&encrypted, b"pw", "album", Some(b"summer")).unwrap();
+        set_metadata_internal(&encrypted, b"pw", "exif", Some(&exif)).unwrap();
+        set_metadata_internal(&encrypted, b"pw", "tags", Some(b"beach")).unwrap();
+        set_metadata_internal(&encrypted, b"pw", "tags", None).unwrap();
+        assert_eq!(list_metadata_keys_internal(&encrypted, b"pw").unwrap(), ["album", "exif"]);
+        assert_eq!(*get_metadata_internal(&encrypted, b"pw", "exif").unwrap(), exif);
+        let (_, body_start) = read_header(&encrypted).unwrap();
+        assert_eq!(fs::read(&encrypted).unwrap()[body_start as usize..], body);
+
+        let missing = get_metadata_internal(&encrypted, b"pw", "tags").unwrap_err();
+        assert_eq!(errors::classify(missing.as_ref()), errors::ERR_INVALID_ARGUMENT);
+        let wrong = list_metadata_keys_internal(&encrypted, b"wrong").unwrap_err();
+        assert_eq!(errors::classify(wrong.as_ref()), errors::ERR_WRONG_PASSWORD);
+        assert!(set_metadata_internal(&encrypted, b"pw", "huge", Some(&[0; u16::MAX as usize])).is_err());
+        decrypt_file_internal(encrypted.to_str().unwrap(), decrypted.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), fs::read(&plain).unwrap());
+    }
+}
@@ -0,0 +1,549 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::raw::c_char;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+use crate::cipher::BodyCipher;
+use crate::config::KyrieConfig;
+use crate::errors;
+use crate::format::{ContainerHeader, NONCE_SCHEME_COUNTER};
+use crate::kdf::KEY_PURPOSE_FILE_DATA;
+use crate::nonce::NonceSequence;
+use crate::range::DecryptingReader;
+use crate::secure_temp::{SecureTempFile, TempContents};
+use crate::vault::file_blake3;
+use crate::{text, write_frame, write_header};
+
+const ARCHIVE_VERSION: u32 = 2;
+const MAX_MANIFEST_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ArchiveManifest {
+    pub version: u32,
+    pub entries: Vec<ArchiveEntry>,
+    pub blobs: Vec<ArchiveBlob>,
+}
+
+impl Default for ArchiveManifest {
+    fn default() -> Self {
+        ArchiveManifest {
+            version: ARCHIVE_VERSION,
+            entries: Vec::new(),
+            blobs: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ArchiveBlob {
+    pub len: u64,
+    // Version 1 manifests stored SHA-256 digests under this name.
+    #[serde(alias = "sha256")]
+    pub blake3: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ArchiveEntry {
+    pub path: String,
+    #[serde(flatten)]
+    pub kind: EntryKind,
+    // Seconds since the epoch; zero when unknown or archived before
+    // metadata was recorded.
+    #[serde(default)]
+    pub modified: u64,
+    // Unix permission bits, absent on other platforms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntryKind {
+    Directory,
+    File { blob: usize },
+    HardLink { target: String },
+}
+
+#[derive(Clone, Copy)]
+pub struct ExtractOptions {
+    pub restore_links: bool,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        ExtractOptions { restore_links: true }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_archive_directory(
+    dir_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+) -> i32 {
+    unsafe {
+        let dir = match CStr::from_ptr(dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match archive_directory(Path::new(dir), Path::new(output_path), password, hint, is_mobile, KyrieConfig::current().chunk_size(is_mobile)) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_extract_archive(
+    archive_path_ptr: *const c_char,
+    output_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    restore_links: bool,
+) -> i32 {
+    unsafe {
+        let archive_path = match CStr::from_ptr(archive_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_dir = match CStr::from_ptr(output_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let options = ExtractOptions { restore_links };
+        match extract_archive(Path::new(archive_path), Path::new(output_dir), password, &options) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_archive_list_entries(
+    archive_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    json_ptr: *mut u8,
+    json_len: *mut usize,
+) -> i32 {
+    if json_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let archive_path = match CStr::from_ptr(archive_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match list_entries(Path::new(archive_path), password).and_then(|manifest| Ok(serde_json::to_vec(&manifest)?)) {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// The container payload is the manifest length (u32 LE), the manifest JSON,
+// then every distinct file content once, in order of first use. Hard links
+// and files with identical content all point at the same blob.
+pub fn archive_directory(
+    dir: &Path,
+    output_path: &Path,
+    password: &[u8],
+    hint: Option<&str>,
+    is_mobile: bool,
+    chunk_size: usize,
+) -> Result<ArchiveManifest, Box<dyn std::error::Error>> {
+    let mut builder = ManifestBuilder::default();
+    builder.collect(dir, Path::new(""))?;
+    let ManifestBuilder { manifest, sources, .. } = builder;
+
+    let manifest_json = serde_json::to_vec(&manifest)?;
+    let payload_len = 4 + manifest_json.len() as u64 + manifest.blobs.iter().map(|b| b.len).sum::<u64>();
+    let hint_bytes = text::hint_bytes(hint);
+    let mut header = ContainerHeader::with_hint(&hint_bytes);
+    header.nonce_scheme = NONCE_SCHEME_COUNTER;
+    header.key_purpose = KEY_PURPOSE_FILE_DATA;
+    header.use_password_kdf(is_mobile);
+    let single_chunk = payload_len <= chunk_size as u64;
+    header.set_framing(single_chunk, chunk_size, payload_len.div_ceil(chunk_size as u64).max(1) as usize);
+    let key = Zeroizing::new(header.new_master_key(password)?);
+    header.seal_file_id(&key);
+    let cipher = header.body_cipher(&key)?;
+
+    let mut temp = SecureTempFile::for_target(output_path, TempContents::Ciphertext)?;
+    {
+        let mut output = BufWriter::new(temp.file());
+        write_header(&mut output, &header)?;
+        let mut frames = FrameWriter {
+            output: &mut output,
+            cipher: &cipher,
+            nonce_seq: NonceSequence::for_header(&header, &key)?,
+            chunk_size,
+            single_chunk,
+            buffer: Vec::new(),
+        };
+        frames.write_all(&(manifest_json.len() as u32).to_le_bytes())?;
+        frames.write_all(&manifest_json)?;
+        for (blob, source) in manifest.blobs.iter().zip(&sources) {
+            if io::copy(&mut File::open(source)?.take(blob.len), &mut frames)? != blob.len {
+                return Err("File changed while archiving".into());
+            }
+        }
+        frames.finish()?;
+    }
+    temp.persist(output_path)?;
+    Ok(manifest)
+}
+
+// Only the chunks holding the manifest are decrypted.
+pub fn list_entries(archive_path: &Path, password: &[u8]) -> Result<ArchiveManifest, Box<dyn std::error::Error>> {
+    read_manifest(&mut DecryptingReader::open(archive_path, password)?)
+}
+
+pub fn extract_archive(
+    archive_path: &Path,
+    output_dir: &Path,
+    password: &[u8],
+    options: &ExtractOptions,
+) -> Result<ArchiveManifest, Box<dyn std::error::Error>> {
+    let mut reader = DecryptingReader::open(archive_path, password)?;
+    let manifest = read_manifest(&mut reader)?;
+
+    let mut blob_paths: Vec<PathBuf> = Vec::with_capacity(manifest.blobs.len());
+    for entry in &manifest.entries {
+        let path = entry_path(output_dir, &entry.path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &entry.kind {
+            EntryKind::Directory => fs::create_dir_all(&path)?,
+            EntryKind::File { blob } if *blob < blob_paths.len() => {
+                fs::copy(&blob_paths[*blob], &path)?;
+                restore_metadata(&path, entry)?;
+            }
+            EntryKind::File { blob } if *blob == blob_paths.len() => {
+                let len = manifest.blobs.get(*blob).ok_or("Invalid file format")?.len;
+                let mut output = File::create(&path)?;
+                if io::copy(&mut (&mut reader).take(len), &mut output).map_err(decrypt_error)? != len {
+                    return Err("Invalid file format".into());
+                }
+                drop(output);
+                restore_metadata(&path, entry)?;
+                blob_paths.push(path);
+            }
+            EntryKind::HardLink { target } => {
+                let target = entry_path(output_dir, target)?;
+                // Filesystems without hard links (FAT on removable storage)
+                // still get the content, just not the shared inode.
+                if !options.restore_links || fs::hard_link(&target, &path).is_err() {
+                    fs::copy(&target, &path)?;
+                    restore_metadata(&path, entry)?;
+                }
+            }
+            EntryKind::File { .. } => return Err("Invalid file format".into()),
+        }
+    }
+    // Directories last and deepest first: writing their contents would move
+    // their mtimes, and a read-only directory could not be filled.
+    for entry in manifest.entries.iter().rev().filter(|e| e.kind == EntryKind::Directory) {
+        restore_metadata(&entry_path(output_dir, &entry.path)?, entry)?;
+    }
+    Ok(manifest)
+}
+
+fn restore_metadata(path: &Path, entry: &ArchiveEntry) -> io::Result<()> {
+    if entry.modified > 0 {
+        let file = if path.is_dir() { File::open(path)? } else { File::options().write(true).open(path)? };
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.modified))?;
+    }
+    set_mode(path, entry.mode)
+}
+
+fn read_manifest<R: Read>(reader: &mut R) -> Result<ArchiveManifest, Box<dyn std::error::Error>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).map_err(decrypt_error)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MANIFEST_LEN {
+        return Err("Invalid file format".into());
+    }
+    let mut json = vec![0u8; len];
+    reader.read_exact(&mut json).map_err(decrypt_error)?;
+    let manifest: ArchiveManifest = serde_json::from_slice(&json).map_err(|_| "Invalid file format")?;
+    if !(1..=ARCHIVE_VERSION).contains(&manifest.version) {
+        return Err("Unsupported version".into());
+    }
+    Ok(manifest)
+}
+
+// DecryptingReader surfaces authentication failures as InvalidData; keep
+// their message so they are still reported as a wrong password.
+pub fn decrypt_error(error: io::Error) -> Box<dyn std::error::Error> {
+    if error.kind() == io::ErrorKind::InvalidData {
+        error.to_string().into()
+    } else {
+        error.into()
+    }
+}
+
+pub fn entry_path(output_dir: &Path, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = output_dir.to_path_buf();
+    for part in name.split('/') {
+        match Path::new(part).components().collect::<Vec<_>>().as_slice() {
+            [Component::Normal(_)] => path.push(part),
+            _ => return Err("Invalid archive entry path".into()),
+        }
+    }
+    Ok(path)
+}
+
+#[derive(Default)]
+struct ManifestBuilder {
+    manifest: ArchiveManifest,
+    sources: Vec<PathBuf>,
+    links: HashMap<(u64, u64), String>,
+    blobs_by_digest: HashMap<String, usize>,
+}
+
+impl ManifestBuilder {
+    fn collect(&mut self, root: &Path, relative: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut children = fs::read_dir(root.join(relative))?.collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let relative = relative.join(child.file_name());
+            let name = relative
+                .components()
+                .map(|c| c.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>()
+                .ok_or("Invalid path")?
+                .join("/");
+            let metadata = fs::symlink_metadata(child.path())?;
+            if metadata.is_dir() {
+                self.push(name, EntryKind::Directory, &metadata);
+                self.collect(root, &relative)?;
+            } else if metadata.is_file() {
+                self.add_file(&child.path(), name, &metadata)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_file(&mut self, source: &Path, name: String, metadata: &fs::Metadata) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(id) = link_id(metadata) {
+            if let Some(target) = self.links.get(&id) {
+                let target = target.clone();
+                self.push(name, EntryKind::HardLink { target }, metadata);
+                return Ok(());
+            }
+            self.links.insert(id, name.clone());
+        }
+
+        let blake3 = file_blake3(source)?;
+        let blob = match self.blobs_by_digest.get(&blake3) {
+            Some(blob) => *blob,
+            None => {
+                let blob = self.manifest.blobs.len();
+                self.blobs_by_digest.insert(blake3.clone(), blob);
+                self.manifest.blobs.push(ArchiveBlob {
+                    len: metadata.len(),
+                    blake3,
+                });
+                self.sources.push(source.to_path_buf());
+                blob
+            }
+        };
+        self.push(name, EntryKind::File { blob }, metadata);
+        Ok(())
+    }
+
+    fn push(&mut self, path: String, kind: EntryKind, metadata: &fs::Metadata) {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.manifest.entries.push(ArchiveEntry { path, kind, modified, mode: file_mode(metadata) });
+    }
+}
+
+#[cfg(unix)]
+fn link_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn link_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+struct FrameWriter<'a, W: Write> {
+    output: W,
+    cipher: &'a BodyCipher,
+    nonce_seq: NonceSequence,
+    chunk_size: usize,
+    single_chunk: bool,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> FrameWriter<'_, W> {
+    fn emit(&mut self) -> io::Result<()> {
+        write_frame(&mut self.output, self.cipher, &mut self.nonce_seq, &self.buffer, self.single_chunk)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.emit()?;
+        }
+        self.output.flush()
+    }
+}
+
+impl<W: Write> Write for FrameWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == self.chunk_size {
+            self.emit()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_links_and_duplicates_are_stored_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::create_dir(source.join("empty")).unwrap();
+        fs::write(source.join("a.txt"), b"shared content").unwrap();
+        fs::write(source.join("sub").join("b.txt"), b"shared content").unwrap();
+        fs::hard_link(source.join("a.txt"), source.join("c.txt")).unwrap();
+        fs::write(source.join("d.txt"), b"unique").unwrap();
+
+        let archive = dir.path().join("source.kyl");
+        let manifest = archive_directory(&source, &archive, b"pw", None, false, 16).unwrap();
+        assert_eq!(manifest.blobs.len(), 2);
+        assert!(matches!(crate::format::read_header(&archive).unwrap().0.kdf, crate::kdf::KdfParams::Argon2id { .. }));
+        let kinds: Vec<(&str, &EntryKind)> = manifest.entries.iter().map(|e| (e.path.as_str(), &e.kind)).collect();
+        assert_eq!(kinds[0], ("a.txt", &EntryKind::File { blob: 0 }));
+        assert_eq!(kinds[5], ("sub/b.txt", &EntryKind::File { blob: 0 }));
+        #[cfg(unix)]
+        assert_eq!(kinds[1], ("c.txt", &EntryKind::HardLink { target: "a.txt".to_string() }));
+
+        let linked = dir.path().join("linked");
+        assert_eq!(extract_archive(&archive, &linked, b"pw", &ExtractOptions::default()).unwrap(), manifest);
+        for name in ["a.txt", "c.txt", "sub/b.txt"] {
+            assert_eq!(fs::read(linked.join(name)).unwrap(), b"shared content");
+        }
+        assert_eq!(fs::read(linked.join("d.txt")).unwrap(), b"unique");
+        assert!(linked.join("empty").is_dir());
+
+        let copied = dir.path().join("copied");
+        extract_archive(&archive, &copied, b"pw", &ExtractOptions { restore_links: false }).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let nlink = |path: &Path| fs::metadata(path).unwrap().nlink();
+            assert_eq!((nlink(&linked.join("c.txt")), nlink(&copied.join("c.txt"))), (2, 1));
+            assert_eq!(nlink(&linked.join("sub/b.txt")), 1);
+        }
+
+        let err = extract_archive(&archive, &dir.path().join("wrong"), b"no", &ExtractOptions::default()).unwrap_err();
+        assert_eq!(errors::classify(err.as_ref()), errors::ERR_WRONG_PASSWORD);
+        assert!(entry_path(&copied, "../escape").is_err());
+    }
+
+    #[test]
+    fn test_metadata_is_restored_and_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs").join("plan.txt"), b"the plan").unwrap();
+        let then = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::options().write(true).open(source.join("docs").join("plan.txt")).unwrap().set_modified(then).unwrap();
+        File::open(source.join("docs")).unwrap().set_modified(then).unwrap();
+        set_mode(&source.join("docs").join("plan.txt"), Some(0o640)).unwrap();
+
+        let archive = dir.path().join("source.kyl");
+        let manifest = archive_directory(&source, &archive, b"pw", None, false, 16).unwrap();
+        assert_eq!(list_entries(&archive, b"pw").unwrap(), manifest);
+        assert_eq!(manifest.entries[1].modified, 1_600_000_000);
+        assert!(list_entries(&archive, b"wrong").is_err());
+
+        let output = dir.path().join("output");
+        extract_archive(&archive, &output, b"pw", &ExtractOptions::default()).unwrap();
+        for name in ["docs", "docs/plan.txt"] {
+            assert_eq!(fs::metadata(output.join(name)).unwrap().modified().unwrap(), then);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(output.join("docs/plan.txt")).unwrap().permissions().mode() & 0o777, 0o640);
+        }
+    }
+}
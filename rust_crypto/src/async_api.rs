@@ -0,0 +1,132 @@
+// Tokio entry points for servers that embed the crate. The pipeline mixes
+// file IO with rayon work, so each call runs whole on the blocking pool and
+// reports progress back over a channel instead of holding a runtime worker.
+use std::ffi::c_void;
+use std::path::Path;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use zeroize::Zeroizing;
+
+use crate::kyrie_core::{KyrieError, Result};
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::{decrypt_file_reporting, encrypt_file_reporting, EncryptOptions};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProgressEvent {
+    // One of the PROGRESS_PHASE_* values.
+    pub phase: i32,
+    pub fraction: f64,
+}
+
+pub struct AsyncJob {
+    // Ends once the operation has finished, successfully or not.
+    pub progress: UnboundedReceiverStream<ProgressEvent>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl AsyncJob {
+    pub async fn result(self) -> Result<()> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) => Err(kyrie_error(e)),
+        }
+    }
+}
+
+pub async fn encrypt_file_async(input: &Path, output: &Path, password: &[u8], hint: Option<&str>) -> Result<AsyncJob> {
+    check_input(input).await?;
+    let (input, output) = (path_string(input)?, path_string(output)?);
+    let password = Zeroizing::new(password.to_vec());
+    let hint = hint.map(str::to_string);
+    Ok(spawn(move |progress| {
+        let options = EncryptOptions {
+            hint: hint.as_deref(),
+            padding: OptionsProfile::current().padding,
+            ..Default::default()
+        };
+        encrypt_file_reporting(&input, &output, &password[..], &options, false, cpu_cores(), progress).map(|_| ())
+    }))
+}
+
+pub async fn decrypt_file_async(input: &Path, output: &Path, password: &[u8]) -> Result<AsyncJob> {
+    check_input(input).await?;
+    let (input, output) = (path_string(input)?, path_string(output)?);
+    let password = Zeroizing::new(password.to_vec());
+    Ok(spawn(move |progress| decrypt_file_reporting(&input, &output, &password[..], false, cpu_cores(), progress)))
+}
+
+// Fails fast on a missing input without tying up a blocking thread.
+async fn check_input(input: &Path) -> Result<()> {
+    tokio::fs::metadata(input).await.map_err(kyrie_error)?;
+    Ok(())
+}
+
+fn spawn<F>(operation: F) -> AsyncJob
+where
+    F: FnOnce(&ProgressSink) -> std::result::Result<(), Box<dyn std::error::Error>> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let task = tokio::task::spawn_blocking(move || {
+        // The sender outlives the sink, and dropping it afterwards is what
+        // ends the progress stream.
+        let progress = ProgressSink::new(Some(forward_progress), &sender as *const UnboundedSender<ProgressEvent> as *mut c_void);
+        Ok(operation(&progress)?)
+    });
+    AsyncJob { progress: UnboundedReceiverStream::new(receiver), task }
+}
+
+extern "C" fn forward_progress(phase: i32, fraction: f64, user_data: *mut c_void) {
+    let sender = unsafe { &*(user_data as *const UnboundedSender<ProgressEvent>) };
+    // Nobody listening is fine; the operation carries on regardless.
+    let _ = sender.send(ProgressEvent { phase, fraction });
+}
+
+fn path_string(path: &Path) -> Result<String> {
+    path.to_str().map(str::to_string).ok_or_else(|| KyrieError::invalid_argument("Path is not valid UTF-8"))
+}
+
+fn kyrie_error<E: Into<Box<dyn std::error::Error>>>(error: E) -> KyrieError {
+    KyrieError::from(error.into())
+}
+
+fn cpu_cores() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::{ERR_FILE_NOT_FOUND, ERR_WRONG_PASSWORD};
+    use crate::progress::PROGRESS_PHASE_WRITING;
+    use std::fs;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn test_async_round_trip_streams_progress() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("upload.bin");
+        let encrypted = dir.path().join("upload.kyl");
+        let decrypted = dir.path().join("upload.out");
+        fs::write(&plain, b"from the upload service").unwrap();
+
+        runtime.block_on(async {
+            let mut job = encrypt_file_async(&plain, &encrypted, b"pw", None).await.unwrap();
+            let mut events = Vec::new();
+            while let Some(event) = job.progress.next().await {
+                events.push(event);
+            }
+            job.result().await.unwrap();
+            assert_eq!(events.last().map(|event| event.phase), Some(PROGRESS_PHASE_WRITING));
+
+            decrypt_file_async(&encrypted, &decrypted, b"pw").await.unwrap().result().await.unwrap();
+            let error = decrypt_file_async(&encrypted, &decrypted, b"wrong").await.unwrap().result().await.unwrap_err();
+            assert_eq!(error.code(), ERR_WRONG_PASSWORD);
+            let missing = dir.path().join("missing.kyl");
+            assert_eq!(decrypt_file_async(&missing, &decrypted, b"pw").await.err().unwrap().code(), ERR_FILE_NOT_FOUND);
+        });
+        assert_eq!(fs::read(&decrypted).unwrap(), b"from the upload service");
+    }
+}
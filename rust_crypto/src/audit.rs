@@ -0,0 +1,173 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::ffi::{c_void, CString};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use crate::errors::{self, ERR_NONE};
+
+// Same numbering as the `log` crate's levels.
+pub const LOG_LEVEL_ERROR: i32 = 1;
+pub const LOG_LEVEL_WARN: i32 = 2;
+pub const LOG_LEVEL_INFO: i32 = 3;
+pub const LOG_LEVEL_DEBUG: i32 = 4;
+pub const LOG_LEVEL_TRACE: i32 = 5;
+
+// Operation events go to this target, one JSON object per message.
+pub const AUDIT_TARGET: &str = "kyrielock::audit";
+
+// Called with a LOG_LEVEL_* value, the record's target and its message, on
+// whichever thread did the work. Both strings are only valid during the call.
+pub type LogCallback = extern "C" fn(level: i32, target: *const c_char, message: *const c_char, user_data: *mut c_void);
+
+struct LogBridge {
+    callback: LogCallback,
+    user_data: usize,
+}
+
+static BRIDGE: Mutex<Option<LogBridge>> = Mutex::new(None);
+static LOGGER: CallbackLogger = CallbackLogger;
+static INSTALLED: OnceLock<bool> = OnceLock::new();
+
+struct CallbackLogger;
+
+impl Log for CallbackLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let Some((callback, user_data)) = BRIDGE.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|b| (b.callback, b.user_data)) else {
+            return;
+        };
+        let target = c_string(record.target());
+        let message = c_string(&record.args().to_string());
+        callback(record.level() as i32, target.as_ptr(), message.as_ptr(), user_data as *mut c_void);
+    }
+
+    fn flush(&self) {}
+}
+
+// Records at `min_level` and above reach the callback; a null callback
+// stops them. This library becomes the process's `log` logger the first
+// time, and fails if another logger already holds that place.
+#[no_mangle]
+pub extern "C" fn kyrie_set_log_callback(callback: Option<LogCallback>, min_level: i32, user_data: *mut c_void) -> i32 {
+    let Some(filter) = level_filter(min_level) else {
+        return errors::invalid_argument();
+    };
+    if !*INSTALLED.get_or_init(|| log::set_logger(&LOGGER).is_ok()) {
+        return errors::fail_with(errors::ERR_INTERNAL);
+    }
+    let mut bridge = BRIDGE.lock().unwrap_or_else(|e| e.into_inner());
+    *bridge = callback.map(|callback| LogBridge { callback, user_data: user_data as usize });
+    log::set_max_level(if bridge.is_some() { filter } else { LevelFilter::Off });
+    0
+}
+
+fn level_filter(level: i32) -> Option<LevelFilter> {
+    Some(match level {
+        LOG_LEVEL_ERROR => LevelFilter::Error,
+        LOG_LEVEL_WARN => LevelFilter::Warn,
+        LOG_LEVEL_INFO => LevelFilter::Info,
+        LOG_LEVEL_DEBUG => LevelFilter::Debug,
+        LOG_LEVEL_TRACE => LevelFilter::Trace,
+        _ => return None,
+    })
+}
+
+fn c_string(text: &str) -> CString {
+    CString::new(text.replace('\0', "\u{fffd}")).expect("interior NULs were replaced")
+}
+
+// The path is hashed so an audit trail can tell files apart without
+// naming them.
+#[derive(Serialize)]
+struct AuditEvent<'a> {
+    operation: &'a str,
+    path_hash: String,
+    result: i32,
+    duration_ms: u64,
+    bytes: u64,
+}
+
+pub fn record<T>(operation: &str, path: &Path, started: Instant, result: &Result<T, Box<dyn std::error::Error>>) {
+    let code = match result {
+        Ok(_) => ERR_NONE,
+        Err(e) => errors::classify(e.as_ref()),
+    };
+    record_code(operation, path, started, code);
+}
+
+// Failures are logged as warnings, so a host listening at that level sees
+// only them. `bytes` is the size of the file the operation read.
+pub fn record_code(operation: &str, path: &Path, started: Instant, code: i32) {
+    let level = if code == ERR_NONE { Level::Info } else { Level::Warn };
+    if !log::log_enabled!(target: AUDIT_TARGET, level) {
+        return;
+    }
+    let event = AuditEvent {
+        operation,
+        path_hash: hex::encode(&Sha256::digest(path.to_string_lossy().as_bytes())[..16]),
+        result: code,
+        duration_ms: started.elapsed().as_millis() as u64,
+        bytes: std::fs::metadata(path).map_or(0, |m| m.len()),
+    };
+    if let Ok(json) = serde_json::to_string(&event) {
+        log::log!(target: AUDIT_TARGET, level, "{}", json);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, encrypt_file_internal};
+    use std::ffi::CStr;
+    use std::fs;
+
+    static EVENTS: Mutex<Vec<(i32, String)>> = Mutex::new(Vec::new());
+
+    extern "C" fn collect(level: i32, target: *const c_char, message: *const c_char, _: *mut c_void) {
+        let (target, message) = unsafe { (CStr::from_ptr(target).to_str().unwrap(), CStr::from_ptr(message).to_str().unwrap()) };
+        if target == AUDIT_TARGET {
+            EVENTS.lock().unwrap().push((level, message.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_operations_reach_the_log_callback() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("audited.txt");
+        let encrypted = dir.path().join("audited.kyl");
+        let decrypted = dir.path().join("audited.out");
+        fs::write(&input, b"quarterly numbers").unwrap();
+        assert_eq!(kyrie_set_log_callback(Some(collect), 0, std::ptr::null_mut()), -1);
+        assert_eq!(kyrie_set_log_callback(Some(collect), LOG_LEVEL_INFO, std::ptr::null_mut()), 0);
+
+        encrypt_file_internal(input.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        assert!(decrypt_file_internal(encrypted.to_str().unwrap(), decrypted.to_str().unwrap(), b"no", false, 4).is_err());
+        kyrie_set_log_callback(None, LOG_LEVEL_INFO, std::ptr::null_mut());
+        decrypt_file_internal(encrypted.to_str().unwrap(), decrypted.to_str().unwrap(), b"pw", false, 4).unwrap();
+
+        // Other tests run alongside, so only events for these paths count.
+        let hash = |path: &Path| hex::encode(&Sha256::digest(path.to_string_lossy().as_bytes())[..16]);
+        let events: Vec<(i32, serde_json::Value)> = EVENTS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(level, message)| (*level, serde_json::from_str::<serde_json::Value>(message).unwrap()))
+            .filter(|(_, event)| event["path_hash"] == hash(&input) || event["path_hash"] == hash(&encrypted))
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].0, &events[0].1["operation"], &events[0].1["result"]), (LOG_LEVEL_INFO, &"encrypt".into(), &0.into()));
+        assert_eq!(events[0].1["bytes"], 17);
+        assert_eq!((events[1].0, &events[1].1["operation"]), (LOG_LEVEL_WARN, &"decrypt".into()));
+        assert_eq!(events[1].1["result"], errors::ERR_WRONG_PASSWORD);
+    }
+}
@@ -0,0 +1,213 @@
+use rayon::prelude::*;
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+use std::slice;
+
+use crate::config;
+use crate::errors::{self, classify, ERR_NONE};
+use crate::kdf::KDF_SALT_SIZE;
+use crate::progress::ProgressSink;
+use crate::rng;
+use crate::session::KyrieSession;
+use crate::{decrypt_file_reporting, encrypt_file_reporting, EncryptOptions};
+
+// Called from worker threads as each file finishes, in no particular order.
+pub type BatchFileCallback = extern "C" fn(index: usize, result: i32, user_data: *mut c_void);
+
+#[no_mangle]
+pub extern "C" fn encrypt_files_batch(
+    input_paths_ptr: *const *const c_char,
+    output_paths_ptr: *const *const c_char,
+    count: usize,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+    results_ptr: *mut i32,
+    callback: Option<BatchFileCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    unsafe {
+        let (Some(inputs), Some(outputs)) = (read_paths(input_paths_ptr, count), read_paths(output_paths_ptr, count)) else {
+            return errors::invalid_argument();
+        };
+        if password_ptr.is_null() || results_ptr.is_null() {
+            return errors::invalid_argument();
+        }
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let results = slice::from_raw_parts_mut(results_ptr, count);
+        let notify = Notifier::new(callback, user_data);
+        results.copy_from_slice(&encrypt_files(&inputs, &outputs, password, hint, is_mobile, cpu_cores, &notify));
+        finish(results)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_files_batch(
+    input_paths_ptr: *const *const c_char,
+    output_paths_ptr: *const *const c_char,
+    count: usize,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+    results_ptr: *mut i32,
+    callback: Option<BatchFileCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    unsafe {
+        let (Some(inputs), Some(outputs)) = (read_paths(input_paths_ptr, count), read_paths(output_paths_ptr, count)) else {
+            return errors::invalid_argument();
+        };
+        if password_ptr.is_null() || results_ptr.is_null() {
+            return errors::invalid_argument();
+        }
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        let results = slice::from_raw_parts_mut(results_ptr, count);
+        let notify = Notifier::new(callback, user_data);
+        results.copy_from_slice(&decrypt_files(&inputs, &outputs, password, is_mobile, cpu_cores, &notify));
+        finish(results)
+    }
+}
+
+// Every file in the batch shares one salt, so the session derives the
+// password key once and only wraps a fresh file key per file.
+pub fn encrypt_files(
+    inputs: &[&str],
+    outputs: &[&str],
+    password: &[u8],
+    hint: Option<&str>,
+    is_mobile: bool,
+    cpu_cores: usize,
+    notify: &Notifier,
+) -> Vec<i32> {
+    let session = KyrieSession::new(password);
+    let options = EncryptOptions {
+        hint,
+        kdf_salt: Some(rng::random::<[u8; KDF_SALT_SIZE]>().to_vec()),
+        ..Default::default()
+    };
+    let results = run(inputs, outputs, notify, |input, output| {
+        encrypt_file_reporting(input, output, &session, &options, is_mobile, cpu_cores, &ProgressSink::none()).map(|_| ())
+    });
+    session.lock();
+    results
+}
+
+// Files written by one encrypt batch share their salt, so the session
+// cache turns their key derivations into one.
+pub fn decrypt_files(
+    inputs: &[&str],
+    outputs: &[&str],
+    password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+    notify: &Notifier,
+) -> Vec<i32> {
+    let session = KyrieSession::new(password);
+    let results = run(inputs, outputs, notify, |input, output| {
+        decrypt_file_reporting(input, output, &session, is_mobile, cpu_cores, &ProgressSink::none()).map(|_| ())
+    });
+    session.lock();
+    results
+}
+
+pub struct Notifier {
+    callback: Option<BatchFileCallback>,
+    user_data: usize,
+}
+
+impl Notifier {
+    pub fn new(callback: Option<BatchFileCallback>, user_data: *mut c_void) -> Self {
+        Notifier { callback, user_data: user_data as usize }
+    }
+
+    fn file_done(&self, index: usize, result: i32) {
+        if let Some(callback) = self.callback {
+            callback(index, result, self.user_data as *mut c_void);
+        }
+    }
+}
+
+fn run<F>(inputs: &[&str], outputs: &[&str], notify: &Notifier, operation: F) -> Vec<i32>
+where
+    F: Fn(&str, &str) -> Result<(), Box<dyn std::error::Error>> + Sync,
+{
+    config::install(|| {
+        inputs
+            .par_iter()
+            .zip(outputs.par_iter())
+            .enumerate()
+            .map(|(index, (input, output))| {
+                let result = match operation(input, output) {
+                    Ok(()) => ERR_NONE,
+                    Err(e) => classify(e.as_ref()),
+                };
+                notify.file_done(index, result);
+                result
+            })
+            .collect()
+    })
+}
+
+// Fails with the first file's error so that kyrie_last_error still says
+// why; the caller finds the rest in the results array.
+fn finish(results: &[i32]) -> i32 {
+    match results.iter().find(|&&result| result != ERR_NONE) {
+        Some(&code) => errors::fail_with(code),
+        None => 0,
+    }
+}
+
+unsafe fn read_paths<'a>(paths_ptr: *const *const c_char, count: usize) -> Option<Vec<&'a str>> {
+    if paths_ptr.is_null() {
+        return None;
+    }
+    slice::from_raw_parts(paths_ptr, count)
+        .iter()
+        .map(|&path| if path.is_null() { None } else { CStr::from_ptr(path).to_str().ok() })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ERR_FILE_NOT_FOUND;
+    use crate::format::read_header;
+    use std::fs;
+
+    #[test]
+    fn test_batch_reports_each_file_and_shares_one_salt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        fs::write(path("a.jpg"), b"first photo").unwrap();
+        fs::write(path("b.jpg"), b"second photo").unwrap();
+
+        let inputs = [path("a.jpg"), path("missing.jpg"), path("b.jpg")];
+        let outputs = [path("a.kyl"), path("missing.kyl"), path("b.kyl")];
+        let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+        let outputs: Vec<&str> = outputs.iter().map(String::as_str).collect();
+        let results = encrypt_files(&inputs, &outputs, b"pw", None, false, 4, &Notifier::new(None, std::ptr::null_mut()));
+        assert_eq!(results, [ERR_NONE, ERR_FILE_NOT_FOUND, ERR_NONE]);
+        let (first, _) = read_header(dir.path().join("a.kyl").as_path()).unwrap();
+        let (second, _) = read_header(dir.path().join("b.kyl").as_path()).unwrap();
+        assert_eq!(first.kdf_salt, second.kdf_salt);
+        assert_ne!(first.wrapped_key, second.wrapped_key);
+
+        let encrypted = [path("a.kyl"), path("b.kyl")];
+        let decrypted = [path("a.out"), path("b.out")];
+        let encrypted: Vec<&str> = encrypted.iter().map(String::as_str).collect();
+        let decrypted: Vec<&str> = decrypted.iter().map(String::as_str).collect();
+        assert_eq!(decrypt_files(&encrypted, &decrypted, b"pw", false, 4, &Notifier::new(None, std::ptr::null_mut())), [ERR_NONE, ERR_NONE]);
+        assert_eq!(fs::read(dir.path().join("a.out")).unwrap(), b"first photo");
+        assert_eq!(fs::read(dir.path().join("b.out")).unwrap(), b"second photo");
+    }
+}
@@ -0,0 +1,354 @@
+// Locks and unlocks files outside the app, for scripts and recovery. The
+// exit status is the ERR_* code of the failure, so scripts can tell a wrong
+// password (2) from a damaged file (20) without parsing messages.
+use std::fs::File;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+use rust_crypto::kyrie_core::{
+    self, Decryptor, Encryptor, FileHeader, KyrieError, Result, PROGRESS_PHASE_DECRYPTING, PROGRESS_PHASE_DERIVING_KEY,
+    PROGRESS_PHASE_ENCRYPTING, PROGRESS_PHASE_READING, PROGRESS_PHASE_WRITING,
+};
+
+const USAGE: &str = "\
+usage: kyrie <command> [options] <file>
+
+commands:
+  encrypt <input> [-o <output>] [--hint <text>]
+  decrypt <input> [-o <output>]
+  info <file>
+  verify <file>
+  change-password <file>
+
+A file of - is stdin or stdout, and is read or written as a stream. Output
+goes to stdout by default when the input is stdin.
+
+The password is prompted for on the terminal unless one of these is given:
+  --password-env <var>       read it from an environment variable
+  --new-password-env <var>   the same, for the new password of change-password
+  --password-stdin           read it from the first line of stdin, and the new
+                             password of change-password from the second
+  -q, --quiet                no progress output";
+
+const ENCRYPTED_EXTENSION: &str = "kyl";
+const STDIO: &str = "-";
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    command: String,
+    input: PathBuf,
+    output: Option<PathBuf>,
+    hint: Option<String>,
+    password_env: Option<String>,
+    new_password_env: Option<String>,
+    password_stdin: bool,
+    quiet: bool,
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("kyrie: {}\n\n{}", e, USAGE);
+            return exit_code(&e);
+        }
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("kyrie: {}", e);
+            exit_code(&e)
+        }
+    }
+}
+
+fn exit_code(error: &KyrieError) -> ExitCode {
+    ExitCode::from(u8::try_from(error.code()).unwrap_or(u8::MAX))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut argv: I) -> Result<Args> {
+    let mut args = Args { command: argv.next().ok_or_else(|| usage("Missing command"))?, ..Default::default() };
+    let mut input = None;
+    while let Some(arg) = argv.next() {
+        let mut value = |name: &str| argv.next().ok_or_else(|| usage(&format!("{} needs a value", name)));
+        match arg.as_str() {
+            "-o" | "--output" => args.output = Some(PathBuf::from(value(&arg)?)),
+            "--hint" => args.hint = Some(value(&arg)?),
+            "--password-env" => args.password_env = Some(value(&arg)?),
+            "--new-password-env" => args.new_password_env = Some(value(&arg)?),
+            "--password-stdin" => args.password_stdin = true,
+            "-q" | "--quiet" => args.quiet = true,
+            _ if arg.starts_with('-') && arg != STDIO => return Err(usage(&format!("Unknown option {}", arg))),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(usage(&format!("Unexpected argument {}", arg))),
+        }
+    }
+    args.input = input.ok_or_else(|| usage("Missing file"))?;
+    let takes_output = matches!(args.command.as_str(), "encrypt" | "decrypt");
+    if (args.output.is_some() && !takes_output) || (args.hint.is_some() && args.command != "encrypt") {
+        return Err(usage(&format!("Option not accepted by {}", args.command)));
+    }
+    Ok(args)
+}
+
+fn run(args: &Args) -> Result<()> {
+    match args.command.as_str() {
+        "encrypt" => {
+            let output = match &args.output {
+                Some(output) => output.clone(),
+                None if is_stdio(&args.input) => PathBuf::from(STDIO),
+                None => with_extension(&args.input),
+            };
+            let password = read_password(args, PasswordRole::New)?;
+            if is_stdio(&args.input) || is_stdio(&output) {
+                let encryptor = Encryptor::new(&password).with_hint(args.hint.as_deref());
+                return encryptor.encrypt_stream(open_input(&args.input)?, create_output(&output)?).map(|_| ());
+            }
+            let progress = ProgressLine::new(args.quiet);
+            let listener = |phase, fraction| progress.report(phase, fraction);
+            let encryptor = Encryptor::new(&password).with_hint(args.hint.as_deref()).with_progress(&listener);
+            let result = encryptor.encrypt_file(&args.input, &output).map(|_| ());
+            progress.finish();
+            result
+        }
+        "decrypt" => {
+            let output = match &args.output {
+                Some(output) => output.clone(),
+                None if is_stdio(&args.input) => PathBuf::from(STDIO),
+                None => without_extension(&args.input)?,
+            };
+            let password = read_password(args, PasswordRole::Current)?;
+            if is_stdio(&args.input) || is_stdio(&output) {
+                return Decryptor::new(&password).decrypt_stream(open_input(&args.input)?, create_output(&output)?).map(|_| ());
+            }
+            let progress = ProgressLine::new(args.quiet);
+            let listener = |phase, fraction| progress.report(phase, fraction);
+            let result = Decryptor::new(&password).with_progress(&listener).decrypt_file(&args.input, &output).map(|_| ());
+            progress.finish();
+            result
+        }
+        "info" => print_info(&args.input),
+        "verify" => {
+            let password = read_password(args, PasswordRole::Current)?;
+            kyrie_core::verify_file(&args.input, &password)?;
+            println!("{}: OK", args.input.display());
+            Ok(())
+        }
+        "change-password" => {
+            let mut stdin = io::stdin().lock();
+            let old_password = password_from(args, args.password_env.as_deref(), &mut stdin, PasswordRole::Current)?;
+            let new_password = password_from(args, args.new_password_env.as_deref(), &mut stdin, PasswordRole::New)?;
+            kyrie_core::change_password(&args.input, &old_password, &new_password)
+        }
+        other => Err(usage(&format!("Unknown command {}", other))),
+    }
+}
+
+fn print_info(path: &Path) -> Result<()> {
+    let header = FileHeader::read(path)?;
+    let info = kyrie_core::file_info(path)?;
+    println!("format version: {}", info.format_version);
+    println!("file id:        {}", header.file_id.as_deref().unwrap_or("-"));
+    println!("hint:           {}", String::from_utf8_lossy(&header.hint));
+    println!("key slots:      {}", info.key_slots);
+    println!("chunks:         {} of {} bytes", info.chunk_count, info.chunk_size);
+    let approximate = if info.plaintext_size_exact { "" } else { " (padded)" };
+    println!("plaintext size: {}{}", info.plaintext_size, approximate);
+    println!("file size:      {}", info.file_size);
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PasswordRole {
+    Current,
+    New,
+}
+
+fn read_password(args: &Args, role: PasswordRole) -> Result<Zeroizing<Vec<u8>>> {
+    password_from(args, args.password_env.as_deref(), &mut io::stdin().lock(), role)
+}
+
+fn password_from<R: BufRead>(args: &Args, env: Option<&str>, stdin: &mut R, role: PasswordRole) -> Result<Zeroizing<Vec<u8>>> {
+    if let Some(name) = env {
+        let value = std::env::var(name).map_err(|_| usage(&format!("{} is not set to a UTF-8 password", name)))?;
+        return Ok(Zeroizing::new(value.into_bytes()));
+    }
+    if args.password_stdin {
+        return read_line(stdin)?.ok_or_else(|| usage("stdin ended before the password"));
+    }
+    let label = if role == PasswordRole::New && args.command == "change-password" { "New password: " } else { "Password: " };
+    let password = prompt(label)?;
+    // A typo in a new password locks the file for good, so it is asked twice.
+    if role == PasswordRole::New && prompt("Repeat password: ")? != password {
+        return Err(usage("Passwords do not match"));
+    }
+    Ok(password)
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    let mut line = Zeroizing::new(Vec::new());
+    if reader.read_until(b'\n', &mut line).map_err(io_error)? == 0 {
+        return Ok(None);
+    }
+    while matches!(line.last(), Some(b'\n' | b'\r')) {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn prompt(label: &str) -> Result<Zeroizing<Vec<u8>>> {
+    eprint!("{}", label);
+    let password = read_hidden().map_err(io_error)?;
+    eprintln!();
+    password.ok_or_else(|| usage("No password given"))
+}
+
+// Reads from the controlling terminal with echo off, so the password never
+// shows up on screen or in a piped stdin.
+#[cfg(unix)]
+fn read_hidden() -> io::Result<Option<Zeroizing<Vec<u8>>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let tty = std::fs::File::open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+    let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut hidden = original;
+    hidden.c_lflag &= !libc::ECHO;
+    hidden.c_lflag |= libc::ECHONL;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &hidden) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let line = read_line(&mut io::BufReader::new(&tty)).map_err(|e| io::Error::other(e.message().to_string()));
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    line
+}
+
+// Windows consoles have no termios; the password is read with echo on.
+#[cfg(not(unix))]
+fn read_hidden() -> io::Result<Option<Zeroizing<Vec<u8>>>> {
+    read_line(&mut io::stdin().lock()).map_err(|e| io::Error::other(e.message().to_string()))
+}
+
+// Redraws one status line on stderr when it is a terminal.
+struct ProgressLine {
+    enabled: bool,
+    last: Mutex<Option<(i32, u32)>>,
+}
+
+impl ProgressLine {
+    fn new(quiet: bool) -> Self {
+        ProgressLine { enabled: !quiet && io::stderr().is_terminal(), last: Mutex::new(None) }
+    }
+
+    fn report(&self, phase: i32, fraction: f64) {
+        if !self.enabled {
+            return;
+        }
+        let percent = (fraction.clamp(0.0, 1.0) * 100.0) as u32;
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        if *last == Some((phase, percent)) {
+            return;
+        }
+        *last = Some((phase, percent));
+        let label = match phase {
+            PROGRESS_PHASE_READING => "reading",
+            PROGRESS_PHASE_DERIVING_KEY => "deriving key",
+            PROGRESS_PHASE_ENCRYPTING => "encrypting",
+            PROGRESS_PHASE_DECRYPTING => "decrypting",
+            PROGRESS_PHASE_WRITING => "writing",
+            _ => "working",
+        };
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[K{} {:>3}%", label, percent);
+        let _ = stderr.flush();
+    }
+
+    fn finish(&self) {
+        if self.enabled && self.last.lock().unwrap_or_else(|e| e.into_inner()).is_some() {
+            eprintln!();
+        }
+    }
+}
+
+fn with_extension(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ENCRYPTED_EXTENSION);
+    PathBuf::from(name)
+}
+
+fn without_extension(path: &Path) -> Result<PathBuf> {
+    match path.extension() {
+        Some(extension) if extension == ENCRYPTED_EXTENSION => Ok(path.with_extension("")),
+        _ => Err(usage("Input does not end in .kyl; give the output with -o")),
+    }
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new(STDIO)
+}
+
+// With --password-stdin the password line has already been taken off stdin,
+// and the data follows it.
+fn open_input(path: &Path) -> Result<Box<dyn Read>> {
+    if is_stdio(path) {
+        return Ok(Box::new(io::stdin().lock()));
+    }
+    Ok(Box::new(File::open(path).map_err(io_error)?))
+}
+
+// A stream cannot be staged in a temp file and renamed, so an existing file
+// is never written over.
+fn create_output(path: &Path) -> Result<Box<dyn Write>> {
+    if is_stdio(path) {
+        return Ok(Box::new(io::stdout().lock()));
+    }
+    Ok(Box::new(File::options().write(true).create_new(true).open(path).map_err(io_error)?))
+}
+
+fn usage(message: &str) -> KyrieError {
+    KyrieError::invalid_argument(message)
+}
+
+fn io_error(error: io::Error) -> KyrieError {
+    KyrieError::from(Box::<dyn std::error::Error>::from(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(argv: &[&str]) -> Result<Args> {
+        parse_args(argv.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_arguments_and_stdin_passwords() {
+        let args = parse(&["encrypt", "notes.txt", "--hint", "cat", "--password-stdin", "-q"]).unwrap();
+        assert_eq!(args.input, PathBuf::from("notes.txt"));
+        assert_eq!(args.hint.as_deref(), Some("cat"));
+        assert!(args.password_stdin && args.quiet);
+        assert_eq!(with_extension(&args.input), PathBuf::from("notes.txt.kyl"));
+        assert_eq!(without_extension(Path::new("notes.txt.kyl")).unwrap(), PathBuf::from("notes.txt"));
+        assert!(without_extension(Path::new("notes.txt")).is_err());
+
+        assert_eq!(parse(&["decrypt", "a.kyl", "--hint", "x"]).unwrap_err().code(), 1);
+        assert!(parse(&["verify"]).is_err());
+        assert!(parse(&["info", "a.kyl", "b.kyl"]).is_err());
+        let args = parse(&["decrypt", "-", "-o", "-", "--password-env", "KYRIE_PW"]).unwrap();
+        assert!(is_stdio(&args.input) && is_stdio(args.output.as_deref().unwrap()));
+        assert!(parse(&["encrypt", "--", "notes.txt"]).is_err());
+
+        let args = parse(&["change-password", "a.kyl", "--password-stdin"]).unwrap();
+        let mut stdin = io::Cursor::new(b"old\r\nnew\n".to_vec());
+        assert_eq!(*password_from(&args, None, &mut stdin, PasswordRole::Current).unwrap(), b"old");
+        assert_eq!(*password_from(&args, None, &mut stdin, PasswordRole::New).unwrap(), b"new");
+        assert!(password_from(&args, None, &mut stdin, PasswordRole::New).is_err());
+    }
+}
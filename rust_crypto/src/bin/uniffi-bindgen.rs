@@ -0,0 +1,6 @@
+// Generates the Kotlin and Swift glue from the built library:
+//   cargo run --features uniffi-cli --bin uniffi-bindgen -- generate \
+//       --library target/release/librust_crypto.so --language kotlin --out-dir out
+fn main() {
+    uniffi::uniffi_bindgen_main()
+}
@@ -0,0 +1,163 @@
+// Objects for the Kotlin and Swift apps, whose glue is generated from the
+// built library by the uniffi-bindgen binary. The Flutter app keeps using the
+// C exports, which are unchanged.
+use std::ffi::c_void;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+use crate::inspect::{self, KyrieFileInfo};
+use crate::kyrie_core::KyrieError;
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::{decrypt_file_reporting, decrypt_file_to_memory_internal, encrypt_file_reporting, EncryptOptions};
+
+#[derive(Debug, uniffi::Error)]
+pub enum KyrieBindingError {
+    // `code` is one of the ERR_* values the C exports return.
+    Failed { code: i32, message: String },
+}
+
+impl fmt::Display for KyrieBindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KyrieBindingError::Failed { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for KyrieBindingError {}
+
+impl From<Box<dyn std::error::Error>> for KyrieBindingError {
+    fn from(error: Box<dyn std::error::Error>) -> Self {
+        KyrieError::from(error).into()
+    }
+}
+
+impl From<KyrieError> for KyrieBindingError {
+    fn from(error: KyrieError) -> Self {
+        KyrieBindingError::Failed { code: error.code(), message: error.message().to_string() }
+    }
+}
+
+type Result<T> = std::result::Result<T, KyrieBindingError>;
+
+// Receives the PROGRESS_PHASE_* values with a fraction of 0 to 1, on
+// whichever thread is doing the work.
+#[uniffi::export(with_foreign)]
+pub trait ProgressListener: Send + Sync {
+    fn on_progress(&self, phase: i32, fraction: f64);
+}
+
+#[derive(uniffi::Object)]
+pub struct Encryptor {
+    password: Zeroizing<Vec<u8>>,
+    hint: Option<String>,
+    is_mobile: bool,
+}
+
+#[uniffi::export]
+impl Encryptor {
+    #[uniffi::constructor]
+    pub fn new(password: Vec<u8>, hint: Option<String>, is_mobile: bool) -> Arc<Self> {
+        Arc::new(Encryptor { password: Zeroizing::new(password), hint, is_mobile })
+    }
+
+    pub fn encrypt_file(&self, input: String, output: String, listener: Option<Arc<dyn ProgressListener>>) -> Result<()> {
+        let options = EncryptOptions {
+            hint: self.hint.as_deref(),
+            padding: OptionsProfile::current().padding,
+            ..Default::default()
+        };
+        let progress = progress_sink(&listener);
+        encrypt_file_reporting(&input, &output, &self.password[..], &options, self.is_mobile, cpu_cores(), &progress)?;
+        Ok(())
+    }
+}
+
+#[derive(uniffi::Object)]
+pub struct Decryptor {
+    password: Zeroizing<Vec<u8>>,
+    is_mobile: bool,
+}
+
+#[uniffi::export]
+impl Decryptor {
+    #[uniffi::constructor]
+    pub fn new(password: Vec<u8>, is_mobile: bool) -> Arc<Self> {
+        Arc::new(Decryptor { password: Zeroizing::new(password), is_mobile })
+    }
+
+    pub fn decrypt_file(&self, input: String, output: String, listener: Option<Arc<dyn ProgressListener>>) -> Result<()> {
+        let progress = progress_sink(&listener);
+        Ok(decrypt_file_reporting(&input, &output, &self.password[..], self.is_mobile, cpu_cores(), &progress)?)
+    }
+
+    pub fn decrypt_to_bytes(&self, input: String) -> Result<Vec<u8>> {
+        Ok(decrypt_file_to_memory_internal(&input, &self.password, self.is_mobile, cpu_cores())?)
+    }
+}
+
+#[uniffi::export]
+pub fn file_info(path: String) -> Result<KyrieFileInfo> {
+    Ok(inspect::file_info(Path::new(&path))?)
+}
+
+// The listener stays borrowed for as long as the sink, which is what keeps
+// the pointer handed to the trampoline alive.
+fn progress_sink(listener: &Option<Arc<dyn ProgressListener>>) -> ProgressSink {
+    match listener {
+        Some(listener) => ProgressSink::new(Some(forward_progress), listener as *const Arc<dyn ProgressListener> as *mut c_void),
+        None => ProgressSink::none(),
+    }
+}
+
+extern "C" fn forward_progress(phase: i32, fraction: f64, user_data: *mut c_void) {
+    let listener = unsafe { &*(user_data as *const Arc<dyn ProgressListener>) };
+    listener.on_progress(phase, fraction);
+}
+
+fn cpu_cores() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ERR_WRONG_PASSWORD;
+    use crate::progress::PROGRESS_PHASE_WRITING;
+    use std::fs;
+    use std::sync::Mutex;
+
+    struct Phases(Mutex<Vec<i32>>);
+
+    impl ProgressListener for Phases {
+        fn on_progress(&self, phase: i32, _fraction: f64) {
+            self.0.lock().unwrap().push(phase);
+        }
+    }
+
+    #[test]
+    fn test_objects_round_trip_with_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        fs::write(path("plain.txt"), b"from kotlin").unwrap();
+
+        let phases = Arc::new(Phases(Mutex::new(Vec::new())));
+        let listener: Arc<dyn ProgressListener> = phases.clone();
+        Encryptor::new(b"pw".to_vec(), Some("hint".into()), false)
+            .encrypt_file(path("plain.txt"), path("plain.kyl"), Some(listener))
+            .unwrap();
+        assert!(phases.0.lock().unwrap().contains(&PROGRESS_PHASE_WRITING));
+        assert!(file_info(path("plain.kyl")).unwrap().has_hint);
+
+        let decryptor = Decryptor::new(b"pw".to_vec(), false);
+        decryptor.decrypt_file(path("plain.kyl"), path("plain.out"), None).unwrap();
+        assert_eq!(fs::read(path("plain.out")).unwrap(), b"from kotlin");
+        match Decryptor::new(b"wrong".to_vec(), false).decrypt_to_bytes(path("plain.kyl")) {
+            Err(KyrieBindingError::Failed { code, .. }) => assert_eq!(code, ERR_WRONG_PASSWORD),
+            Ok(_) => panic!("wrong password opened the file"),
+        }
+    }
+}
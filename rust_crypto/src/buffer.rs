@@ -0,0 +1,99 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::{errors, kyrie_core};
+
+// Plaintext owned by Rust and lent to the caller until kyrie_buffer_free,
+// so the file is decrypted once instead of once for the size and again for
+// the bytes. The contents are scrubbed when the buffer is freed.
+pub struct KyrieBuffer {
+    data: Zeroizing<Vec<u8>>,
+}
+
+impl KyrieBuffer {
+    pub fn new(data: Vec<u8>) -> Self {
+        KyrieBuffer { data: Zeroizing::new(data) }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_to_buffer(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> *mut KyrieBuffer {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                errors::invalid_argument();
+                return std::ptr::null_mut();
+            }
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let decryptor = kyrie_core::Decryptor::new(password).for_mobile(is_mobile).with_cpu_cores(cpu_cores);
+        match decryptor.decrypt_to_vec(Path::new(input_path)) {
+            Ok(data) => Box::into_raw(Box::new(KyrieBuffer::new(data))),
+            Err(e) => {
+                errors::fail(&e);
+                std::ptr::null_mut()
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_buffer_data(buffer: *const KyrieBuffer) -> *const u8 {
+    match unsafe { buffer.as_ref() } {
+        Some(buffer) => buffer.data.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_buffer_len(buffer: *const KyrieBuffer) -> usize {
+    match unsafe { buffer.as_ref() } {
+        Some(buffer) => buffer.data.len(),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_buffer_free(buffer: *mut KyrieBuffer) {
+    if !buffer.is_null() {
+        drop(unsafe { Box::from_raw(buffer) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+    use std::ffi::CString;
+    use std::fs;
+
+    #[test]
+    fn test_buffer_handle_lends_the_plaintext_until_freed() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        fs::write(&plain, b"decrypted once").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+
+        let path = CString::new(encrypted.to_str().unwrap()).unwrap();
+        let buffer = decrypt_file_to_buffer(path.as_ptr(), b"pw".as_ptr(), 2, false, 4);
+        assert!(!buffer.is_null());
+        let data = unsafe { std::slice::from_raw_parts(kyrie_buffer_data(buffer), kyrie_buffer_len(buffer)) };
+        assert_eq!(data, b"decrypted once");
+        kyrie_buffer_free(buffer);
+
+        assert!(decrypt_file_to_buffer(path.as_ptr(), b"no".as_ptr(), 2, false, 4).is_null());
+        assert_eq!(kyrie_buffer_len(std::ptr::null()), 0);
+        kyrie_buffer_free(std::ptr::null_mut());
+    }
+}
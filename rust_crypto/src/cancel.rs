@@ -0,0 +1,151 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::{decrypt_file_reporting, encrypt_file_reporting, errors, EncryptOptions};
+
+// Shared with the running operation, which checks it between batches.
+pub struct KyrieCancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_create_cancel_token() -> *mut KyrieCancelToken {
+    Box::into_raw(Box::new(KyrieCancelToken { cancelled: Arc::new(AtomicBool::new(false)) }))
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_cancel(token: *mut KyrieCancelToken) -> i32 {
+    if token.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe { &*token }.cancelled.store(true, Ordering::SeqCst);
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_free_cancel_token(token: *mut KyrieCancelToken) {
+    if !token.is_null() {
+        drop(unsafe { Box::from_raw(token) });
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_cancellable(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+    token: *mut KyrieCancelToken,
+) -> i32 {
+    if token.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let cancelled = (*token).cancelled.clone();
+        match encrypt_cancellable(input_path, output_path, password, hint, is_mobile, cpu_cores, cancelled) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_cancellable(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+    token: *mut KyrieCancelToken,
+) -> i32 {
+    if token.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        // Decryption writes through a temp file, which is removed when the
+        // operation stops early.
+        let progress = ProgressSink::none().with_cancel_flag((*token).cancelled.clone());
+        match decrypt_file_reporting(input_path, output_path, password, is_mobile, cpu_cores, &progress) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn encrypt_cancellable(
+    input_path: &str,
+    output_path: &str,
+    password: &[u8],
+    hint: Option<&str>,
+    is_mobile: bool,
+    cpu_cores: usize,
+    cancelled: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let progress = ProgressSink::none().with_cancel_flag(cancelled.clone());
+    let options = EncryptOptions { hint, padding: OptionsProfile::current().padding, ..Default::default() };
+    encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &progress)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::{classify, ERR_CANCELLED};
+    use crate::SMALL_FILE_THRESHOLD;
+    use std::fs;
+
+    #[test]
+    fn test_cancelled_encryption_leaves_no_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.bin");
+        let encrypted = dir.path().join("plain.kyl");
+        fs::write(&plain, vec![7u8; SMALL_FILE_THRESHOLD + 1]).unwrap();
+        let (plain, encrypted) = (plain.to_str().unwrap(), encrypted.to_str().unwrap());
+
+        let cancelled = Arc::new(AtomicBool::new(true));
+        let error = encrypt_cancellable(plain, encrypted, b"pw", None, false, 4, cancelled).unwrap_err();
+        assert_eq!(classify(error.as_ref()), ERR_CANCELLED);
+        assert!(!std::path::Path::new(encrypted).exists());
+
+        encrypt_cancellable(plain, encrypted, b"pw", None, false, 4, Arc::new(AtomicBool::new(false))).unwrap();
+        let progress = ProgressSink::none().with_cancel_flag(Arc::new(AtomicBool::new(true)));
+        let decrypted = dir.path().join("plain.out");
+        let error = decrypt_file_reporting(encrypted, decrypted.to_str().unwrap(), &b"pw"[..], false, 4, &progress);
+        assert_eq!(classify(error.unwrap_err().as_ref()), ERR_CANCELLED);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
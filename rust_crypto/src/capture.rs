@@ -0,0 +1,323 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::ffi::CStr;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+use crate::cipher::BodyCipher;
+use crate::config::KyrieConfig;
+use crate::format::ContainerHeader;
+use crate::nonce::NonceSequence;
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::secure_temp::{self, SecureTempFile, TempContents};
+use crate::{
+    encrypt_into, errors, escrow, format, header_backup, kdf, key_usage, padding, text, write_encrypted_frame, write_frame,
+    write_header, EncryptOptions, PlainInput,
+};
+
+// Takes plaintext as a capture pipeline produces it. The container is built
+// in a temp file and only appears at the output path once finished, so an
+// interrupted capture never leaves a truncated file behind.
+pub struct KyrieEncryptSink {
+    output_path: PathBuf,
+    temp: SecureTempFile,
+    header: ContainerHeader,
+    key: Zeroizing<[u8; 32]>,
+    cipher: BodyCipher,
+    nonce_seq: NonceSequence,
+    chunk_size: usize,
+    // Held back until it is known whether the stream outgrows one chunk,
+    // which decides between the single-chunk and the framed layout.
+    pending: Vec<u8>,
+    content_len: u64,
+    frames: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_sink_begin(
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+) -> *mut KyrieEncryptSink {
+    unsafe {
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                errors::invalid_argument();
+                return std::ptr::null_mut();
+            }
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match KyrieEncryptSink::begin(Path::new(output_path), password, hint, is_mobile) {
+            Ok(sink) => Box::into_raw(Box::new(sink)),
+            Err(e) => {
+                errors::fail(e.as_ref());
+                std::ptr::null_mut()
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_sink_write(sink: *mut KyrieEncryptSink, data_ptr: *const u8, data_len: usize) -> i32 {
+    if sink.is_null() || (data_ptr.is_null() && data_len > 0) {
+        return errors::invalid_argument();
+    }
+    let data = if data_len == 0 {
+        &[][..]
+    } else {
+        unsafe { std::slice::from_raw_parts(data_ptr, data_len) }
+    };
+    match unsafe { &mut *sink }.write(data) {
+        Ok(_) => 0,
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+// Consumes the sink whether or not finishing succeeds.
+#[no_mangle]
+pub extern "C" fn kyrie_sink_finish(sink: *mut KyrieEncryptSink) -> i32 {
+    if sink.is_null() {
+        return errors::invalid_argument();
+    }
+    match unsafe { Box::from_raw(sink) }.finish() {
+        Ok(_) => 0,
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_sink_abort(sink: *mut KyrieEncryptSink) {
+    if !sink.is_null() {
+        drop(unsafe { Box::from_raw(sink) });
+    }
+}
+
+// The whole capture at once, as a camera hands over a finished photo. Unlike
+// the sink this knows the size up front, so the container gets the same
+// checksums and manifest as one written by encrypt_file.
+#[no_mangle]
+pub extern "C" fn encrypt_memory_to_file(
+    data_ptr: *const u8,
+    data_len: usize,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if (data_ptr.is_null() && data_len > 0) || password_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let data = if data_len == 0 {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(data_ptr, data_len)
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_memory_to_file_internal(data, Path::new(output_path), password, hint, is_mobile, cpu_cores) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn encrypt_memory_to_file_internal(
+    data: &[u8],
+    output_path: &Path,
+    password: &[u8],
+    hint: Option<&str>,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    secure_temp::check_target(output_path)?;
+    let options = EncryptOptions {
+        hint,
+        padding: OptionsProfile::current().padding,
+        ..Default::default()
+    };
+    let mut temp = SecureTempFile::for_target(output_path, TempContents::Ciphertext)?;
+    // There is no source file to take a name or timestamps from.
+    encrypt_into(PlainInput::Memory(data), temp.file(), password, &options, None, is_mobile, cpu_cores, &ProgressSink::none())?;
+    temp.persist(output_path)?;
+    Ok(())
+}
+
+impl KyrieEncryptSink {
+    pub fn begin(
+        output_path: &Path,
+        password: &[u8],
+        hint: Option<&str>,
+        is_mobile: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut header = ContainerHeader {
+            hint: text::hint_bytes(hint),
+            padding: padding::for_stream(OptionsProfile::current().padding),
+            nonce_scheme: format::NONCE_SCHEME_COUNTER,
+            key_purpose: kdf::KEY_PURPOSE_FILE_DATA,
+            backup: header_backup::enabled(),
+            ..Default::default()
+        };
+        header.use_password_kdf(is_mobile);
+        let key = Zeroizing::new(header.new_master_key(password)?);
+        let cipher = header.body_cipher(&key)?;
+        header.escrow = escrow::configured_slots(&key)?;
+        header.seal_file_id(&key);
+        let nonce_seq = NonceSequence::for_header(&header, &key)?;
+
+        Ok(KyrieEncryptSink {
+            output_path: output_path.to_path_buf(),
+            temp: SecureTempFile::for_target(output_path, TempContents::Ciphertext)?,
+            header,
+            key,
+            cipher,
+            nonce_seq,
+            chunk_size: KyrieConfig::current().chunk_size(is_mobile),
+            pending: Vec::new(),
+            content_len: 0,
+            frames: 0,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.pending.extend_from_slice(data);
+        self.content_len += data.len() as u64;
+        self.flush_full_chunks()
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        padding::padding_reader(self.content_len, self.header.padding).read_to_end(&mut self.pending)?;
+        self.flush_full_chunks()?;
+
+        if self.frames == 0 {
+            self.write_single_chunk()?;
+        } else {
+            if !self.pending.is_empty() {
+                let last = std::mem::take(&mut self.pending);
+                self.write_chunk(&last)?;
+            }
+            // The count was left at zero when the header was first written;
+            // the field keeps its size, so the body does not move.
+            self.header.set_framing(false, self.chunk_size, self.frames as usize);
+            self.temp.file().seek(SeekFrom::Start(0))?;
+            write_header(self.temp.file(), &self.header)?;
+            self.temp.file().seek(SeekFrom::End(0))?;
+        }
+        if self.header.backup {
+            header_backup::write_trailer(self.temp.file(), &self.header.encode())?;
+        }
+        self.temp.persist(&self.output_path)?;
+        Ok(())
+    }
+
+    // A chunk is only written once more data follows it, so a stream that
+    // ends exactly on a chunk boundary still gets the layout encrypt_file
+    // would give it.
+    fn flush_full_chunks(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        while self.pending.len() > self.chunk_size {
+            let rest = self.pending.split_off(self.chunk_size);
+            let chunk = std::mem::replace(&mut self.pending, rest);
+            self.write_chunk(&chunk)?;
+        }
+        Ok(())
+    }
+
+    // The chunk count is not known up front, so framed captures carry no
+    // checksums, only the framing.
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let body_key = self.header.body_key(&self.key);
+        key_usage::check(&body_key, chunk.len() as u64, 1)?;
+        if self.frames == 0 {
+            // Resealing keeps the file id, so the nonces stay the same.
+            self.header.set_framing(false, self.chunk_size, 0);
+            self.header.seal_file_id(&self.key);
+            write_header(self.temp.file(), &self.header)?;
+        }
+        write_frame(self.temp.file(), &self.cipher, &mut self.nonce_seq, chunk, false)?;
+        self.frames += 1;
+        key_usage::record(&body_key, chunk.len() as u64, 1)
+    }
+
+    fn write_single_chunk(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let body_key = self.header.body_key(&self.key);
+        key_usage::check(&body_key, self.pending.len() as u64, 1)?;
+        let nonce_bytes = self.nonce_seq.next_nonce()?;
+        let encrypted = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce_bytes), self.pending.as_ref())
+            .map_err(|_| "Encryption failed")?;
+
+        let mut frame = Vec::new();
+        let checksum = write_encrypted_frame(&mut frame, self.nonce_seq.stored(&nonce_bytes), &encrypted, true)?;
+        self.header.chunk_checksums = vec![checksum];
+        self.header.set_framing(true, self.chunk_size, 1);
+        self.header.seal_file_id(&self.key);
+        write_header(self.temp.file(), &self.header)?;
+        self.temp.file().write_all(&frame)?;
+        key_usage::record(&body_key, self.pending.len() as u64, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_internal;
+    use std::fs;
+
+    #[test]
+    fn test_streamed_capture_decrypts_like_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = dir.path().join("capture.kyl");
+        let decrypted = dir.path().join("capture.out");
+
+        let mut sink = KyrieEncryptSink::begin(&encrypted, b"pw", Some("camera"), false).unwrap();
+        let mut expected = Vec::new();
+        for frame in 0..50u32 {
+            let data = vec![frame as u8; 1000 + frame as usize];
+            sink.write(&data).unwrap();
+            expected.extend_from_slice(&data);
+        }
+        assert!(!encrypted.exists());
+        sink.finish().unwrap();
+
+        decrypt_file_internal(encrypted.to_str().unwrap(), decrypted.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), expected);
+        assert_eq!(crate::get_hint_from_file_internal(encrypted.to_str().unwrap()).unwrap(), b"camera");
+
+        let photo = dir.path().join("photo.kyl");
+        let pixels: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 253) as u8).collect();
+        encrypt_memory_to_file_internal(&pixels, &photo, b"pw", Some("camera"), false, 4).unwrap();
+        let (header, _) = format::read_header(&photo).unwrap();
+        assert!(header.manifest.is_some() && header.chunk_checksums.len() == 1);
+        assert_eq!(crate::decrypt_file_to_memory_internal(photo.to_str().unwrap(), b"pw", false, 4).unwrap(), pixels);
+        fs::remove_file(&photo).unwrap();
+
+        let aborted = dir.path().join("aborted.kyl");
+        let mut sink = KyrieEncryptSink::begin(&aborted, b"pw", None, false).unwrap();
+        sink.write(b"partial").unwrap();
+        drop(sink);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
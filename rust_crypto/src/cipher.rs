@@ -0,0 +1,148 @@
+use aes_gcm::aead::consts::{U0, U12, U16};
+use aes_gcm::aead::{self, AeadCore, AeadInPlace, KeyInit};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+use crate::acceleration::{self, BACKEND_SOFTWARE};
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::{encrypt_file_reporting, errors, EncryptOptions};
+
+pub const CIPHER_AES_256_GCM: u8 = 0;
+pub const CIPHER_CHACHA20_POLY1305: u8 = 1;
+// Not stored in headers; resolved to one of the suites above when a file is
+// created.
+pub const CIPHER_AUTO: u8 = 0xff;
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_with_cipher(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    cipher_suite: u8,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        if resolve_suite(cipher_suite).is_err() {
+            return errors::invalid_argument();
+        }
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let options = EncryptOptions {
+            hint,
+            padding: OptionsProfile::current().padding,
+            cipher: cipher_suite,
+            ..Default::default()
+        };
+        match encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &ProgressSink::none()) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Both suites take a 96-bit nonce and a 128-bit tag, so frames have the same
+// layout whichever one sealed them.
+pub enum BodyCipher {
+    Aes(Box<Aes256Gcm>),
+    ChaCha(ChaCha20Poly1305),
+}
+
+impl BodyCipher {
+    pub fn new(suite: u8, key: &[u8; 32]) -> Result<Self, Box<dyn std::error::Error>> {
+        match suite {
+            CIPHER_AES_256_GCM => Ok(BodyCipher::Aes(Box::new(Aes256Gcm::new_from_slice(key)?))),
+            CIPHER_CHACHA20_POLY1305 => Ok(BodyCipher::ChaCha(ChaCha20Poly1305::new_from_slice(key)?)),
+            _ => Err("Unsupported cipher suite".into()),
+        }
+    }
+}
+
+impl AeadCore for BodyCipher {
+    type NonceSize = U12;
+    type TagSize = U16;
+    type CiphertextOverhead = U0;
+}
+
+impl AeadInPlace for BodyCipher {
+    fn encrypt_in_place_detached(
+        &self,
+        nonce: &aead::Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> aead::Result<aead::Tag<Self>> {
+        match self {
+            BodyCipher::Aes(cipher) => cipher.encrypt_in_place_detached(nonce, associated_data, buffer),
+            BodyCipher::ChaCha(cipher) => cipher.encrypt_in_place_detached(nonce, associated_data, buffer),
+        }
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        nonce: &aead::Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &aead::Tag<Self>,
+    ) -> aead::Result<()> {
+        match self {
+            BodyCipher::Aes(cipher) => cipher.decrypt_in_place_detached(nonce, associated_data, buffer, tag),
+            BodyCipher::ChaCha(cipher) => cipher.decrypt_in_place_detached(nonce, associated_data, buffer, tag),
+        }
+    }
+}
+
+// AES-GCM in software is slow and not constant time, so devices without AES
+// and carry-less multiply instructions get ChaCha20-Poly1305 instead.
+pub fn resolve_suite(suite: u8) -> Result<u8, Box<dyn std::error::Error>> {
+    match suite {
+        CIPHER_AUTO if acceleration::detect().backend == BACKEND_SOFTWARE => Ok(CIPHER_CHACHA20_POLY1305),
+        CIPHER_AUTO => Ok(CIPHER_AES_256_GCM),
+        CIPHER_AES_256_GCM | CIPHER_CHACHA20_POLY1305 => Ok(suite),
+        _ => Err("Unsupported cipher suite".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_internal;
+    use crate::format::read_header;
+    use std::fs;
+
+    #[test]
+    fn test_chacha_files_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        let decrypted = dir.path().join("out.txt");
+        fs::write(&plain, b"no aes instructions here").unwrap();
+
+        let options = EncryptOptions { cipher: CIPHER_CHACHA20_POLY1305, ..Default::default() };
+        let (input, output) = (plain.to_str().unwrap(), encrypted.to_str().unwrap());
+        encrypt_file_reporting(input, output, &b"pw"[..], &options, false, 4, &ProgressSink::none()).unwrap();
+        assert_eq!(read_header(&encrypted).unwrap().0.cipher, CIPHER_CHACHA20_POLY1305);
+
+        decrypt_file_internal(output, decrypted.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"no aes instructions here");
+        assert!(decrypt_file_internal(output, decrypted.to_str().unwrap(), b"wrong", false, 4).is_err());
+        assert!(resolve_suite(7).is_err());
+    }
+}
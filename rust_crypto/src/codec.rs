@@ -0,0 +1,111 @@
+// Byte-level header and chunk record codec for tools that need to read or
+// write KYRIE_LOCK containers without going through files or keys.
+use std::io::Cursor;
+
+pub use crate::format::{
+    ContainerHeader, FileId, KeyShareSlot, SharePolicy, MAX_HEADER_LEN, NONCE_SCHEME_COUNTER, NONCE_SCHEME_RANDOM,
+};
+pub use crate::kdf::{KEY_PURPOSE_DEVICE, KEY_PURPOSE_FILE_DATA, KEY_PURPOSE_LEGACY, KEY_PURPOSE_METADATA};
+pub use crate::padding::{PADDING_NONE, PADDING_PADME, PADDING_POWER_OF_TWO};
+pub use crate::{MAGIC_STRING, NONCE_SIZE, TAG_SIZE};
+
+const CHUNK_LEN_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkRecord<'a> {
+    // None when the nonce scheme derives nonces instead of storing them.
+    pub nonce: Option<[u8; NONCE_SIZE]>,
+    pub ciphertext: &'a [u8],
+}
+
+// Returns the header and the number of bytes it occupies.
+pub fn parse_header(bytes: &[u8]) -> Result<(ContainerHeader, usize), Box<dyn std::error::Error>> {
+    let (header, len) = ContainerHeader::read(&mut Cursor::new(bytes))?;
+    Ok((header, len as usize))
+}
+
+pub fn serialize_header(header: &ContainerHeader) -> Vec<u8> {
+    header.encode()
+}
+
+// A single-chunk body is one record without a length field that runs to
+// the end of the input. Returns the record and the bytes it occupies.
+pub fn parse_chunk_record(
+    bytes: &[u8],
+    nonce_scheme: u8,
+    single_chunk: bool,
+) -> Result<(ChunkRecord<'_>, usize), Box<dyn std::error::Error>> {
+    let (nonce, rest) = if nonce_scheme == NONCE_SCHEME_COUNTER {
+        (None, bytes)
+    } else {
+        let nonce = bytes.get(..NONCE_SIZE).ok_or("Invalid chunk record")?;
+        (Some(nonce.try_into()?), &bytes[NONCE_SIZE..])
+    };
+    let ciphertext = if single_chunk {
+        rest
+    } else {
+        let len_bytes = rest.get(..CHUNK_LEN_SIZE).ok_or("Invalid chunk record")?;
+        let len = u32::from_be_bytes(len_bytes.try_into()?) as usize;
+        rest.get(CHUNK_LEN_SIZE..CHUNK_LEN_SIZE + len).ok_or("Invalid chunk record")?
+    };
+    if ciphertext.len() < TAG_SIZE {
+        return Err("Invalid chunk record".into());
+    }
+    let used = bytes.len() - rest.len() + ciphertext.len() + if single_chunk { 0 } else { CHUNK_LEN_SIZE };
+    Ok((ChunkRecord { nonce, ciphertext }, used))
+}
+
+pub fn parse_chunk_records(
+    body: &[u8],
+    nonce_scheme: u8,
+    single_chunk: bool,
+) -> Result<Vec<ChunkRecord<'_>>, Box<dyn std::error::Error>> {
+    let mut records = Vec::new();
+    let mut rest = body;
+    while !rest.is_empty() {
+        let (record, used) = parse_chunk_record(rest, nonce_scheme, single_chunk)?;
+        records.push(record);
+        rest = &rest[used..];
+    }
+    Ok(records)
+}
+
+pub fn serialize_chunk_record(record: &ChunkRecord, single_chunk: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(NONCE_SIZE + CHUNK_LEN_SIZE + record.ciphertext.len());
+    if let Some(nonce) = &record.nonce {
+        out.extend_from_slice(nonce);
+    }
+    if !single_chunk {
+        out.extend_from_slice(&(record.ciphertext.len() as u32).to_be_bytes());
+    }
+    out.extend_from_slice(record.ciphertext);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_framed_container;
+    use std::fs;
+
+    #[test]
+    fn test_container_round_trips_through_codec() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("framed.kyl");
+        write_framed_container(&path, b"pw", &[b"first", b"second", b"third"]);
+        let bytes = fs::read(&path).unwrap();
+
+        let (header, header_len) = parse_header(&bytes).unwrap();
+        assert_eq!(serialize_header(&header), bytes[..header_len]);
+        let records = parse_chunk_records(&bytes[header_len..], header.nonce_scheme, false).unwrap();
+        assert_eq!(records.iter().map(|r| r.ciphertext.len()).collect::<Vec<_>>(), [5 + 16, 6 + 16, 5 + 16]);
+
+        let body: Vec<u8> = records.iter().flat_map(|r| serialize_chunk_record(r, false)).collect();
+        assert_eq!(body, bytes[header_len..]);
+        assert!(parse_chunk_records(&bytes[header_len..bytes.len() - 1], header.nonce_scheme, false).is_err());
+
+        let counter = ChunkRecord { nonce: None, ciphertext: &[9u8; 20] };
+        let encoded = serialize_chunk_record(&counter, true);
+        assert_eq!(parse_chunk_record(&encoded, NONCE_SCHEME_COUNTER, true).unwrap(), (counter, 20));
+    }
+}
@@ -0,0 +1,149 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::padding::{self, PADDING_NONE};
+use crate::progress::ProgressSink;
+use crate::secure_temp::{self, SecureTempFile, TempContents};
+use crate::{encrypt_file_reporting, encrypt_into, errors, EncryptOptions, PlainInput};
+
+// For notes, thumbnails and other files counted in the millions, where the
+// regular header outweighs the content. Files that fit the small-file path
+// get a compact header, and padding to a multiple of `bucket_size` (0 for
+// none) hides their exact length. Larger files are written as usual.
+#[no_mangle]
+pub extern "C" fn encrypt_file_compact(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    bucket_size: u64,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let Some(padding) = bucket_scheme(bucket_size) else {
+            return errors::invalid_argument();
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let options = EncryptOptions { hint, padding, compact: true, ..Default::default() };
+        match encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &ProgressSink::none()) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_memory_compact(
+    data_ptr: *const u8,
+    data_len: usize,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    bucket_size: u64,
+    is_mobile: bool,
+) -> i32 {
+    if (data_ptr.is_null() && data_len > 0) || password_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let Some(padding) = bucket_scheme(bucket_size) else {
+            return errors::invalid_argument();
+        };
+        let data = if data_len == 0 {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(data_ptr, data_len)
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_memory_compact_internal(data, Path::new(output_path), password, hint, padding, is_mobile) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn encrypt_memory_compact_internal(
+    data: &[u8],
+    output_path: &Path,
+    password: &[u8],
+    hint: Option<&str>,
+    padding: u8,
+    is_mobile: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    secure_temp::check_target(output_path)?;
+    let options = EncryptOptions { hint, padding, compact: true, ..Default::default() };
+    let mut temp = SecureTempFile::for_target(output_path, TempContents::Ciphertext)?;
+    encrypt_into(PlainInput::Memory(data), temp.file(), password, &options, None, is_mobile, 1, &ProgressSink::none())?;
+    temp.persist(output_path)?;
+    Ok(())
+}
+
+fn bucket_scheme(bucket_size: u64) -> Option<u8> {
+    match bucket_size {
+        0 => Some(PADDING_NONE),
+        size => padding::bucket_padding(size),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::read_header;
+    use crate::{decrypt_file_to_memory_internal, encrypt_file_internal, errors};
+    use std::fs;
+
+    #[test]
+    fn test_compact_files_are_small_and_padded_to_their_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let note = dir.path().join("note.txt");
+        let regular = dir.path().join("regular.kyl");
+        let compact = dir.path().join("compact.kyl");
+        let padded = dir.path().join("padded.kyl");
+        fs::write(&note, b"milk, eggs").unwrap();
+        encrypt_file_internal(note.to_str().unwrap(), regular.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        encrypt_memory_compact_internal(b"milk, eggs", &compact, b"pw", None, PADDING_NONE, false).unwrap();
+        let four_kib = bucket_scheme(4096).unwrap();
+        encrypt_memory_compact_internal(b"milk, eggs", &padded, b"pw", None, four_kib, false).unwrap();
+
+        let (regular_len, compact_len) = (fs::metadata(&regular).unwrap().len(), fs::metadata(&compact).unwrap().len());
+        assert!(compact_len < 128 && compact_len < regular_len / 2, "{compact_len} vs {regular_len}");
+        let (header, header_len) = read_header(&padded).unwrap();
+        assert_eq!(header.padding, four_kib);
+        assert!(header.framing.is_none() && header.metadata.is_empty() && header.wrapped_key.is_none());
+        assert_eq!(fs::metadata(&padded).unwrap().len() - header_len, 4096 + crate::TAG_SIZE as u64);
+        for path in [&compact, &padded] {
+            assert_eq!(decrypt_file_to_memory_internal(path.to_str().unwrap(), b"pw", false, 4).unwrap(), b"milk, eggs");
+            let wrong = decrypt_file_to_memory_internal(path.to_str().unwrap(), b"no", false, 4).unwrap_err();
+            assert_eq!(errors::classify(wrong.as_ref()), errors::ERR_WRONG_PASSWORD);
+        }
+        assert!(bucket_scheme(3000).is_none());
+    }
+}
@@ -0,0 +1,238 @@
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+
+use crate::{errors, get_chunk_size, get_parallel_batch_size};
+
+// Smallest chunk a config or memory budget can push encryption down to.
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+
+pub const THREAD_PRIORITY_NORMAL: i32 = 0;
+// Keeps long encryptions from competing with the app's UI thread.
+pub const THREAD_PRIORITY_BACKGROUND: i32 = 1;
+
+// Tuning for devices the mobile/desktop split does not fit. A zero field
+// keeps the built-in value for it, so an all-zero config is the default.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KyrieConfig {
+    pub chunk_size: usize,
+    pub max_parallel_chunks: usize,
+    pub thread_count: usize,
+    pub memory_budget: u64,
+}
+
+struct ActiveConfig {
+    config: KyrieConfig,
+    pool: Option<Arc<ThreadPool>>,
+    // Set by kyrie_init, after which work never falls back to rayon's global
+    // pool, which the host app may be using for its own work.
+    dedicated: Option<DedicatedPool>,
+}
+
+#[derive(Clone, Copy)]
+struct DedicatedPool {
+    threads: usize,
+    priority: i32,
+}
+
+static ACTIVE_CONFIG: Mutex<ActiveConfig> = Mutex::new(ActiveConfig {
+    config: KyrieConfig { chunk_size: 0, max_parallel_chunks: 0, thread_count: 0, memory_budget: 0 },
+    pool: None,
+    dedicated: None,
+});
+
+thread_local! {
+    static THREAD_CONFIG: Cell<Option<KyrieConfig>> = const { Cell::new(None) };
+}
+
+// Moves all parallel chunk work onto threads of the library's own. Zero
+// threads uses one per core; a config's thread count still overrides it.
+#[no_mangle]
+pub extern "C" fn kyrie_init(num_threads: usize, thread_priority: i32) -> i32 {
+    if thread_priority != THREAD_PRIORITY_NORMAL && thread_priority != THREAD_PRIORITY_BACKGROUND {
+        return errors::invalid_argument();
+    }
+    let dedicated = DedicatedPool { threads: num_threads, priority: thread_priority };
+    let mut active = ACTIVE_CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+    let pool = match build_pool(active.config.thread_count, Some(dedicated)) {
+        Ok(pool) => pool,
+        Err(e) => return errors::fail(&e),
+    };
+    active.pool = pool;
+    active.dedicated = Some(dedicated);
+    0
+}
+
+// A null config restores the defaults.
+#[no_mangle]
+pub extern "C" fn kyrie_set_config(config_ptr: *const KyrieConfig) -> i32 {
+    let config = unsafe { config_ptr.as_ref() }.copied().unwrap_or_default();
+    if config.validate().is_err() {
+        return errors::invalid_argument();
+    }
+    let mut active = ACTIVE_CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+    let pool = match build_pool(config.thread_count, active.dedicated) {
+        Ok(pool) => pool,
+        Err(e) => return errors::fail(&e),
+    };
+    active.config = config;
+    active.pool = pool;
+    0
+}
+
+fn build_pool(thread_count: usize, dedicated: Option<DedicatedPool>) -> Result<Option<Arc<ThreadPool>>, rayon::ThreadPoolBuildError> {
+    let (threads, priority) = match (thread_count, dedicated) {
+        (0, None) => return Ok(None),
+        (0, Some(dedicated)) => (dedicated.threads, dedicated.priority),
+        (threads, dedicated) => (threads, dedicated.map_or(THREAD_PRIORITY_NORMAL, |d| d.priority)),
+    };
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("kyrie-worker-{index}"))
+        .start_handler(move |_| set_thread_priority(priority))
+        .build()
+        .map(|pool| Some(Arc::new(pool)))
+}
+
+// Drops the library's pools so their threads exit once in-flight work is
+// done, as a plugin being unloaded needs. A later kyrie_init starts over.
+pub fn release_pool() {
+    let mut active = ACTIVE_CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+    active.pool = None;
+    active.dedicated = None;
+    active.config.thread_count = 0;
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_thread_priority(priority: i32) {
+    if priority == THREAD_PRIORITY_BACKGROUND {
+        // Linux niceness is per thread when given a thread id.
+        unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, 10) };
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn set_thread_priority(priority: i32) {
+    if priority == THREAD_PRIORITY_BACKGROUND {
+        unsafe { libc::pthread_set_qos_class_self_np(libc::qos_class_t::QOS_CLASS_UTILITY, 0) };
+    }
+}
+
+// Elsewhere the threads keep the priority they were started with.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+fn set_thread_priority(_priority: i32) {}
+
+impl KyrieConfig {
+    pub fn current() -> Self {
+        if let Some(config) = THREAD_CONFIG.with(Cell::get) {
+            return config;
+        }
+        ACTIVE_CONFIG.lock().unwrap_or_else(|e| e.into_inner()).config
+    }
+
+    // Runs `op` with this config in place of the active one on this thread
+    // only. The pool is left as it is.
+    #[cfg(test)]
+    pub fn with<R>(self, op: impl FnOnce() -> R) -> R {
+        let previous = THREAD_CONFIG.with(|slot| slot.replace(Some(self)));
+        let result = op();
+        THREAD_CONFIG.with(|slot| slot.set(previous));
+        result
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        // Frames larger than the desktop chunk are rejected by strict mode,
+        // so no config may write them.
+        if self.chunk_size != 0 && !(MIN_CHUNK_SIZE..=get_chunk_size(false)).contains(&self.chunk_size) {
+            return Err("Chunk size out of range".into());
+        }
+        if self.memory_budget != 0 && self.memory_budget < 2 * MIN_CHUNK_SIZE as u64 {
+            return Err("Memory budget too small".into());
+        }
+        Ok(())
+    }
+
+    pub fn chunk_size(&self, is_mobile: bool) -> usize {
+        let size = match self.chunk_size {
+            0 => get_chunk_size(is_mobile),
+            size => size,
+        };
+        match self.memory_budget {
+            0 => size,
+            // Two batches of at least one chunk are in flight at a time.
+            budget => size.min((budget / 2) as usize).max(MIN_CHUNK_SIZE),
+        }
+    }
+
+    pub fn parallel_batch_size(&self, cpu_cores: usize, is_mobile: bool) -> usize {
+        let batch = match self.max_parallel_chunks {
+            0 => get_parallel_batch_size(cpu_cores, is_mobile),
+            batch => batch,
+        };
+        match self.memory_budget {
+            0 => batch,
+            budget => batch.min((budget / 2 / self.chunk_size(is_mobile) as u64) as usize).max(1),
+        }
+    }
+
+    // Overlapping reads, sealing and writes keeps a third batch in flight,
+    // more than a memory budget or a phone's RAM is sized for.
+    pub fn pipelined(&self, is_mobile: bool) -> bool {
+        !is_mobile && self.memory_budget == 0
+    }
+}
+
+// Runs parallel chunk work on the configured or dedicated pool, or on
+// rayon's global pool when neither is set.
+pub fn install<R: Send>(op: impl FnOnce() -> R + Send) -> R {
+    let pool = ACTIVE_CONFIG.lock().unwrap_or_else(|e| e.into_inner()).pool.clone();
+    match pool {
+        Some(pool) => pool.install(op),
+        None => op(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zero_fields_fall_back_and_the_budget_caps_the_rest() {
+        let defaults = KyrieConfig::default();
+        assert_eq!(defaults.chunk_size(true), get_chunk_size(true));
+        assert_eq!(defaults.parallel_batch_size(8, false), get_parallel_batch_size(8, false));
+
+        let tuned = KyrieConfig { chunk_size: 4 * 1024 * 1024, max_parallel_chunks: 3, ..Default::default() };
+        assert_eq!(tuned.chunk_size(false), 4 * 1024 * 1024);
+        assert_eq!(tuned.parallel_batch_size(8, false), 3);
+
+        let budgeted = KyrieConfig { memory_budget: 32 * 1024 * 1024, ..Default::default() };
+        assert_eq!(budgeted.chunk_size(true), 16 * 1024 * 1024);
+        assert_eq!(budgeted.parallel_batch_size(8, true), 1);
+        let budgeted = KyrieConfig { chunk_size: 1024 * 1024, ..budgeted };
+        assert_eq!(budgeted.parallel_batch_size(64, false), 16);
+
+        assert!(KyrieConfig { chunk_size: 1024, ..Default::default() }.validate().is_err());
+        assert!(KyrieConfig { chunk_size: get_chunk_size(false) + 1, ..Default::default() }.validate().is_err());
+        assert!(KyrieConfig { memory_budget: 1024, ..Default::default() }.validate().is_err());
+        assert!(budgeted.validate().is_ok());
+    }
+
+    #[test]
+    fn test_dedicated_pool_names_its_threads_and_lowers_their_priority() {
+        assert!(build_pool(0, None).unwrap().is_none());
+        assert_eq!(build_pool(3, None).unwrap().unwrap().current_num_threads(), 3);
+
+        let dedicated = DedicatedPool { threads: 2, priority: THREAD_PRIORITY_BACKGROUND };
+        let pool = build_pool(0, Some(dedicated)).unwrap().unwrap();
+        assert_eq!(pool.current_num_threads(), 2);
+        let name = pool.install(|| std::thread::current().name().map(String::from));
+        assert!(name.unwrap().starts_with("kyrie-worker-"));
+        #[cfg(target_os = "linux")]
+        assert_eq!(pool.install(|| unsafe { libc::getpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t) }), 10);
+        assert_eq!(build_pool(4, Some(dedicated)).unwrap().unwrap().current_num_threads(), 4);
+
+        assert_eq!(kyrie_init(2, 7), -1);
+    }
+}
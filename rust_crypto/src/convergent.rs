@@ -0,0 +1,447 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::Zeroizing;
+
+use crate::format::{read_header, ContainerHeader, Framing, SealedContentKey, FRAMING_CONVERGENT_CHUNKS};
+use crate::kdf::{self, derive, KdfParams, KeyProvider, KeyPurpose, KDF_SALT_SIZE};
+use crate::pipeline;
+use crate::progress::ProgressSink;
+use crate::secure_temp::{SecureTempFile, TempContents};
+use crate::text;
+use crate::vault::hash_file;
+use crate::{decrypt_file_reporting, encrypt_file_reporting, errors, write_header, EncryptOptions, NONCE_SIZE, TAG_SIZE};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Identical plaintext gives identical ciphertext, which tells anyone holding
+// two files whether they match; callers have to turn the mode on first.
+static CONVERGENT_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+// Fixed so that every device holding the secret derives the same user key.
+const CONVERGENT_SALT: &[u8; KDF_SALT_SIZE] = b"KYRIE_LOCK dedup";
+
+// Fixed rather than configured, so a chunk shared by two files falls on the
+// same boundaries in both and dedupes.
+const DEDUP_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const SEALED_KEY_SIZE: usize = NONCE_SIZE + 32 + TAG_SIZE;
+
+struct ContentKey(Zeroizing<[u8; 32]>);
+
+impl KeyProvider for ContentKey {
+    fn key_for(&self, _salt: &[u8], _params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        Ok(*self.0)
+    }
+
+    fn derives_from_password(&self) -> bool {
+        false
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_convergent_allowed(enabled: bool) {
+    CONVERGENT_ALLOWED.store(enabled, Ordering::SeqCst);
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_convergent(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    secret_ptr: *const u8,
+    secret_len: usize,
+    hint_ptr: *const c_char,
+    cpu_cores: usize,
+) -> i32 {
+    if !CONVERGENT_ALLOWED.load(Ordering::SeqCst) {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let secret = std::slice::from_raw_parts(secret_ptr, secret_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_convergent(input_path, output_path, secret, hint, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_convergent(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    secret_ptr: *const u8,
+    secret_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let secret = std::slice::from_raw_parts(secret_ptr, secret_len);
+
+        match decrypt_convergent(input_path, output_path, secret, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// The chunk mode: every chunk is keyed by its own contents, so two files
+// sharing a chunk store it as the same bytes. The names say what that
+// costs: anyone holding both files learns which chunks they share.
+#[no_mangle]
+pub extern "C" fn encrypt_file_dedup_reveals_duplicates(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    secret_ptr: *const u8,
+    secret_len: usize,
+    hint_ptr: *const c_char,
+) -> i32 {
+    if !CONVERGENT_ALLOWED.load(Ordering::SeqCst) {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let secret = std::slice::from_raw_parts(secret_ptr, secret_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_dedup_reveals_duplicates(input_path, output_path, secret, hint) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_dedup(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    secret_ptr: *const u8,
+    secret_len: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let secret = std::slice::from_raw_parts(secret_ptr, secret_len);
+
+        match decrypt_dedup(input_path, output_path, secret) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn encrypt_convergent(
+    input_path: &str,
+    output_path: &str,
+    secret: &[u8],
+    hint: Option<&str>,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let user_key = user_key(secret)?;
+    let content_key = ContentKey(Zeroizing::new(content_key(&user_key, Path::new(input_path))?));
+    let options = EncryptOptions {
+        hint,
+        convergent: Some(seal_content_key(&user_key, &content_key.0)?),
+        ..Default::default()
+    };
+    // Chunk boundaries have to match on every device, so mobile sizing is
+    // never used here.
+    encrypt_file_reporting(input_path, output_path, &content_key, &options, false, cpu_cores, &ProgressSink::none())?;
+    Ok(())
+}
+
+pub fn decrypt_convergent(
+    input_path: &str,
+    output_path: &str,
+    secret: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (header, _) = read_header(Path::new(input_path))?;
+    let sealed = header.convergent.as_ref().ok_or("Not a convergent container")?;
+    let content_key = open_content_key(&*user_key(secret)?, sealed)?;
+    decrypt_file_reporting(input_path, output_path, &content_key, is_mobile, cpu_cores, &ProgressSink::none())
+}
+
+// Each frame is the chunk's sealed key, the ciphertext length and the
+// ciphertext. A MAC over the header and every sealed key follows the last
+// frame, so frames cannot be dropped or reordered.
+pub fn encrypt_dedup_reveals_duplicates(
+    input_path: &str,
+    output_path: &str,
+    secret: &[u8],
+    hint: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let user_key = user_key(secret)?;
+    let len = std::fs::metadata(input_path)?.len();
+    let header = ContainerHeader {
+        hint: text::hint_bytes(hint),
+        framing: Some(Framing {
+            version: FRAMING_CONVERGENT_CHUNKS,
+            chunk_size: DEDUP_CHUNK_SIZE as u32,
+            chunk_count: len.div_ceil(DEDUP_CHUNK_SIZE as u64).max(1),
+        }),
+        ..Default::default()
+    };
+    let mut order = chunk_mac(&user_key, KeyPurpose::ConvergentOrder);
+    order.update(&header.encode());
+
+    let mut temp = SecureTempFile::for_target(Path::new(output_path), TempContents::Ciphertext)?;
+    let mut output = BufWriter::new(temp.file());
+    write_header(&mut output, &header)?;
+    let mut input = BufReader::new(File::open(input_path)?);
+    let mut chunk = Zeroizing::new(vec![0u8; DEDUP_CHUNK_SIZE]);
+    for _ in 0..header.framing.map_or(0, |framing| framing.chunk_count) {
+        let read = read_full(&mut input, &mut chunk)?;
+        let chunk_key = Zeroizing::new(chunk_key(&user_key, &chunk[..read]));
+        let sealed = seal_content_key(&user_key, &chunk_key)?;
+        let nonce = kdf::subkey(&chunk_key, KeyPurpose::ConvergentContent);
+        let ciphertext = Aes256Gcm::new_from_slice(&*chunk_key)?
+            .encrypt(Nonce::from_slice(&nonce[..NONCE_SIZE]), &chunk[..read])
+            .map_err(|_| "Encryption failed")?;
+        order.update(&sealed.nonce);
+        order.update(&sealed.sealed);
+        output.write_all(&sealed.nonce)?;
+        output.write_all(&sealed.sealed)?;
+        output.write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        output.write_all(&ciphertext)?;
+    }
+    if input.read(&mut [0u8; 1])? != 0 {
+        return Err("Input changed while it was encrypted".into());
+    }
+    output.write_all(&order.finalize().into_bytes())?;
+    output.flush()?;
+    drop(output);
+    temp.persist(Path::new(output_path))?;
+    Ok(())
+}
+
+pub fn decrypt_dedup(input_path: &str, output_path: &str, secret: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let input = File::open(input_path)?;
+    let file_len = input.metadata()?.len();
+    let mut input = BufReader::new(input);
+    let (header, data_start) = ContainerHeader::read(&mut input)?;
+    let framing = header.framing.filter(|_| header.has_convergent_chunks()).ok_or("Not a convergent container")?;
+    if framing.chunk_size as usize > DEDUP_CHUNK_SIZE {
+        return Err("Invalid header field".into());
+    }
+    let mut tag = [0u8; 32];
+    let body_len = file_len.checked_sub(data_start + tag.len() as u64).ok_or("Invalid file format")?;
+    let user_key = user_key(secret)?;
+    let mut order = chunk_mac(&user_key, KeyPurpose::ConvergentOrder);
+    order.update(&header.encode());
+
+    let mut temp = SecureTempFile::for_output(Path::new(output_path))?;
+    let mut output = BufWriter::new(temp.file());
+    let mut body = input.take(body_len);
+    for _ in 0..framing.chunk_count {
+        let mut prefix = [0u8; SEALED_KEY_SIZE + 4];
+        body.read_exact(&mut prefix)?;
+        let sealed = SealedContentKey {
+            nonce: prefix[..NONCE_SIZE].try_into()?,
+            sealed: prefix[NONCE_SIZE..SEALED_KEY_SIZE].try_into()?,
+        };
+        let len_bytes: [u8; 4] = prefix[SEALED_KEY_SIZE..].try_into()?;
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        if len < TAG_SIZE || len > framing.chunk_size as usize + TAG_SIZE {
+            return Err("Invalid chunk length".into());
+        }
+        let ciphertext = pipeline::read_frame(&mut body, len_bytes)?;
+        let chunk_key = open_content_key(&user_key, &sealed)?;
+        let nonce = kdf::subkey(&chunk_key.0, KeyPurpose::ConvergentContent);
+        let chunk = Zeroizing::new(
+            Aes256Gcm::new_from_slice(&*chunk_key.0)?
+                .decrypt(Nonce::from_slice(&nonce[..NONCE_SIZE]), ciphertext.as_ref())
+                .map_err(|_| "Decryption failed")?,
+        );
+        // A chunk key that is not the MAC of its own chunk was not made here.
+        chunk_key_mac(&user_key, &chunk).verify_slice(&*chunk_key.0).map_err(|_| "Decryption failed")?;
+        order.update(&sealed.nonce);
+        order.update(&sealed.sealed);
+        output.write_all(&chunk)?;
+    }
+    if body.limit() != 0 {
+        return Err("Invalid file format".into());
+    }
+    let mut input = body.into_inner();
+    input.read_exact(&mut tag)?;
+    order.verify_slice(&tag).map_err(|_| "Decryption failed")?;
+    if input.read(&mut [0u8; 1])? != 0 {
+        return Err("Invalid file format".into());
+    }
+    output.flush()?;
+    drop(output);
+    temp.persist(Path::new(output_path))?;
+    Ok(())
+}
+
+fn chunk_mac(user_key: &[u8; 32], purpose: KeyPurpose) -> HmacSha256 {
+    <HmacSha256 as Mac>::new_from_slice(&kdf::subkey(user_key, purpose)).expect("HMAC accepts any key length")
+}
+
+fn chunk_key_mac(user_key: &[u8; 32], chunk: &[u8]) -> HmacSha256 {
+    let mut mac = chunk_mac(user_key, KeyPurpose::ConvergentChunk);
+    mac.update(chunk);
+    mac
+}
+
+fn chunk_key(user_key: &[u8; 32], chunk: &[u8]) -> [u8; 32] {
+    chunk_key_mac(user_key, chunk).finalize().into_bytes().into()
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn user_key(secret: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+    Ok(Zeroizing::new(derive(secret, CONVERGENT_SALT, &KdfParams::for_new_files(false))?))
+}
+
+fn content_key(user_key: &[u8; 32], path: &Path) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let mut hasher = blake3::Hasher::new_keyed(&kdf::subkey(user_key, KeyPurpose::ConvergentContent));
+    hash_file(&mut hasher, path)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+// The nonce comes from the content key, so it only repeats when the same
+// key is sealed again.
+fn seal_content_key(user_key: &[u8; 32], content_key: &[u8; 32]) -> Result<SealedContentKey, Box<dyn std::error::Error>> {
+    let nonce: [u8; NONCE_SIZE] = kdf::subkey(content_key, KeyPurpose::ConvergentSeal)[..NONCE_SIZE].try_into()?;
+    let cipher = Aes256Gcm::new_from_slice(&kdf::subkey(user_key, KeyPurpose::ConvergentSeal))?;
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), content_key.as_ref())
+        .map_err(|_| "Encryption failed")?;
+    Ok(SealedContentKey { nonce, sealed: sealed.as_slice().try_into()? })
+}
+
+fn open_content_key(user_key: &[u8; 32], sealed: &SealedContentKey) -> Result<ContentKey, Box<dyn std::error::Error>> {
+    let cipher = Aes256Gcm::new_from_slice(&kdf::subkey(user_key, KeyPurpose::ConvergentSeal))?;
+    let key = Zeroizing::new(
+        cipher
+            .decrypt(Nonce::from_slice(&sealed.nonce), sealed.sealed.as_ref())
+            .map_err(|_| "Decryption failed")?,
+    );
+    Ok(ContentKey(Zeroizing::new(key.as_slice().try_into()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_internal;
+    use std::fs;
+
+    #[test]
+    fn test_identical_content_encrypts_identically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        fs::write(path("a.txt"), b"backed up twice").unwrap();
+        fs::write(path("b.txt"), b"backed up twice").unwrap();
+        encrypt_convergent(&path("a.txt"), &path("a.kyl"), b"secret", None, 4).unwrap();
+        encrypt_convergent(&path("b.txt"), &path("b.kyl"), b"secret", None, 4).unwrap();
+        encrypt_convergent(&path("b.txt"), &path("other.kyl"), b"other secret", None, 4).unwrap();
+
+        assert_eq!(fs::read(path("a.kyl")).unwrap(), fs::read(path("b.kyl")).unwrap());
+        assert_ne!(fs::read(path("a.kyl")).unwrap(), fs::read(path("other.kyl")).unwrap());
+        assert!(read_header(Path::new(&path("a.kyl"))).unwrap().0.convergent.is_some());
+
+        decrypt_convergent(&path("a.kyl"), &path("a.out"), b"secret", false, 4).unwrap();
+        assert_eq!(fs::read(path("a.out")).unwrap(), b"backed up twice");
+        assert!(decrypt_convergent(&path("a.kyl"), &path("a.out"), b"other secret", false, 4).is_err());
+        assert!(decrypt_file_internal(&path("a.kyl"), &path("a.out"), b"secret", false, 4).is_err());
+    }
+
+    #[test]
+    fn test_shared_chunks_encrypt_identically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let shared: Vec<u8> = (0..DEDUP_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        fs::write(path("a.bin"), [&shared[..], b"first tail"].concat()).unwrap();
+        fs::write(path("b.bin"), [&shared[..], b"second tail"].concat()).unwrap();
+        encrypt_dedup_reveals_duplicates(&path("a.bin"), &path("a.kyl"), b"secret", None).unwrap();
+        encrypt_dedup_reveals_duplicates(&path("a.bin"), &path("again.kyl"), b"secret", None).unwrap();
+        encrypt_dedup_reveals_duplicates(&path("b.bin"), &path("b.kyl"), b"secret", None).unwrap();
+
+        let (a, b) = (fs::read(path("a.kyl")).unwrap(), fs::read(path("b.kyl")).unwrap());
+        assert_eq!(a, fs::read(path("again.kyl")).unwrap());
+        let header_len = read_header(Path::new(&path("a.kyl"))).unwrap().1 as usize;
+        let frame_len = SEALED_KEY_SIZE + 4 + DEDUP_CHUNK_SIZE + TAG_SIZE;
+        assert_eq!(a[header_len..header_len + frame_len], b[header_len..header_len + frame_len]);
+        assert_ne!(a[header_len + frame_len..], b[header_len + frame_len..]);
+
+        decrypt_dedup(&path("b.kyl"), &path("b.out"), b"secret").unwrap();
+        assert_eq!(fs::read(path("b.out")).unwrap(), fs::read(path("b.bin")).unwrap());
+        assert!(decrypt_dedup(&path("b.kyl"), &path("b.out"), b"other secret").is_err());
+        assert!(decrypt_file_internal(&path("b.kyl"), &path("b.out"), b"secret", false, 4).is_err());
+        fs::write(path("cut.kyl"), &b[..b.len() - 1]).unwrap();
+        assert!(decrypt_dedup(&path("cut.kyl"), &path("b.out"), b"secret").is_err());
+        assert!(encrypt_file_dedup_reveals_duplicates(std::ptr::null(), std::ptr::null(), std::ptr::null(), 0, std::ptr::null()) < 0);
+    }
+
+    #[test]
+    fn test_frame_longer_than_the_body_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        fs::write(path("a.bin"), b"small").unwrap();
+        encrypt_dedup_reveals_duplicates(&path("a.bin"), &path("a.kyl"), b"secret", None).unwrap();
+
+        let mut data = fs::read(path("a.kyl")).unwrap();
+        let len_at = read_header(Path::new(&path("a.kyl"))).unwrap().1 as usize + SEALED_KEY_SIZE;
+        data[len_at..len_at + 4].copy_from_slice(&((DEDUP_CHUNK_SIZE + TAG_SIZE) as u32).to_be_bytes());
+        fs::write(path("long.kyl"), &data).unwrap();
+        let err = decrypt_dedup(&path("long.kyl"), &path("a.out"), b"secret").unwrap_err();
+        assert_eq!(err.to_string(), "Corrupted chunk");
+    }
+}
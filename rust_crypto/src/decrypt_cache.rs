@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use zeroize::Zeroizing;
+
+// Keeps any single file from taking more than a quarter of the cache, so a
+// large file cannot flush every small one it would be evicting.
+const MAX_ENTRY_FRACTION: usize = 4;
+
+pub type Stamp = (u64, Option<SystemTime>);
+
+struct CacheEntry {
+    path: PathBuf,
+    stamp: Stamp,
+    plaintext: Zeroizing<Vec<u8>>,
+}
+
+#[repr(C)]
+#[derive(Default, Debug, PartialEq)]
+pub struct KyrieCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+    pub bytes: u64,
+    pub capacity: u64,
+    pub hit_rate: f64,
+}
+
+// In-memory LRU of decrypted files. Entries are keyed by path and
+// revalidated against the encrypted file's size and mtime on every lookup.
+pub struct DecryptCache {
+    capacity: usize,
+    used: usize,
+    entries: VecDeque<CacheEntry>,
+    hits: u64,
+    misses: u64,
+}
+
+pub fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()))
+}
+
+impl DecryptCache {
+    pub fn new(capacity: usize) -> Self {
+        DecryptCache {
+            capacity,
+            used: 0,
+            entries: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, path: &Path) -> Option<Vec<u8>> {
+        let current = stamp(path);
+        if let Some(index) = self.entries.iter().position(|e| e.path == path) {
+            let entry = self.entries.remove(index).expect("index is in range");
+            if Some(entry.stamp) == current {
+                self.hits += 1;
+                let plaintext = entry.plaintext.to_vec();
+                self.entries.push_back(entry);
+                return Some(plaintext);
+            }
+            self.used -= entry.plaintext.len();
+        }
+        self.misses += 1;
+        None
+    }
+
+    // The stamp is taken before decrypting so a write that races the
+    // decryption leaves a stale entry rather than a wrong one.
+    pub fn insert(&mut self, path: &Path, stamp: Stamp, plaintext: &[u8]) {
+        if plaintext.len() > self.capacity / MAX_ENTRY_FRACTION {
+            return;
+        }
+        self.invalidate(path);
+        self.used += plaintext.len();
+        self.entries.push_back(CacheEntry {
+            path: path.to_path_buf(),
+            stamp,
+            plaintext: Zeroizing::new(plaintext.to_vec()),
+        });
+        while self.used > self.capacity {
+            let evicted = self.entries.pop_front().expect("used bytes belong to an entry");
+            self.used -= evicted.plaintext.len();
+        }
+    }
+
+    pub fn invalidate(&mut self, path: &Path) -> bool {
+        match self.entries.iter().position(|e| e.path == path) {
+            Some(index) => {
+                let entry = self.entries.remove(index).expect("index is in range");
+                self.used -= entry.plaintext.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.used = 0;
+    }
+
+    pub fn stats(&self) -> KyrieCacheStats {
+        let lookups = self.hits + self.misses;
+        KyrieCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len() as u64,
+            bytes: self.used as u64,
+            capacity: self.capacity as u64,
+            hit_rate: if lookups == 0 { 0.0 } else { self.hits as f64 / lookups as f64 },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction_and_revalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = (0..3).map(|i| dir.path().join(format!("{}.kyl", i))).collect();
+        for path in &paths {
+            fs::write(path, b"ciphertext").unwrap();
+        }
+        let mut cache = DecryptCache::new(400);
+        for path in &paths[..2] {
+            assert!(cache.get(path).is_none());
+            cache.insert(path, stamp(path).unwrap(), &[1; 100]);
+        }
+        assert_eq!(cache.get(&paths[0]).unwrap(), vec![1; 100]);
+
+        // Too large to cache at all.
+        cache.insert(&paths[2], stamp(&paths[2]).unwrap(), &[2; 101]);
+        assert!(cache.get(&paths[2]).is_none());
+
+        cache.insert(&paths[2], stamp(&paths[2]).unwrap(), &[2; 100]);
+        cache.insert(&paths[2], stamp(&paths[2]).unwrap(), &[2; 100]);
+        let big = dir.path().join("big.kyl");
+        fs::write(&big, b"ciphertext").unwrap();
+        cache.insert(&big, stamp(&big).unwrap(), &[3; 100]);
+        cache.insert(&paths[0], stamp(&paths[0]).unwrap(), &[1; 100]);
+        cache.insert(&dir.path().join("x"), (0, None), &[4; 100]);
+
+        // paths[1] was the least recently used entry.
+        assert!(cache.get(&paths[1]).is_none());
+        assert_eq!(cache.stats().entries, 4);
+        assert_eq!(cache.stats().bytes, 400);
+
+        fs::write(&paths[0], b"re-encrypted").unwrap();
+        assert!(cache.get(&paths[0]).is_none());
+        assert!(cache.invalidate(&paths[2]));
+        assert!(!cache.invalidate(&paths[2]));
+        cache.clear();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries, stats.bytes), (1, 5, 0, 0));
+        assert_eq!(stats.hit_rate, 1.0 / 6.0);
+    }
+}
@@ -0,0 +1,135 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+use crate::format::read_header;
+use crate::kdf::{KdfParams, KeyProvider, KEY_PURPOSE_DEVICE};
+use crate::progress::ProgressSink;
+use crate::secure_temp::{SecureTempFile, TempContents};
+use crate::{decrypt_file_to_memory_with, encrypt_file_reporting, errors, EncryptOptions};
+
+// Provided by the host from the platform keystore; it never leaves the
+// device, so documents locked with it only open on this install.
+static DEVICE_KEY: Mutex<Option<Zeroizing<[u8; 32]>>> = Mutex::new(None);
+
+struct DeviceKey(Zeroizing<[u8; 32]>);
+
+impl DeviceKey {
+    fn current() -> Result<Self, Box<dyn std::error::Error>> {
+        let key = DEVICE_KEY.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(DeviceKey(key.clone().ok_or("Device key not set")?))
+    }
+}
+
+impl KeyProvider for DeviceKey {
+    fn key_for(&self, _salt: &[u8], _params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        Ok(*self.0)
+    }
+
+    fn derives_from_password(&self) -> bool {
+        false
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_device_key(key_ptr: *const u8, key_len: usize) -> i32 {
+    let key = if key_ptr.is_null() {
+        None
+    } else if key_len == 32 {
+        let mut key = Zeroizing::new([0u8; 32]);
+        key.copy_from_slice(unsafe { std::slice::from_raw_parts(key_ptr, key_len) });
+        Some(key)
+    } else {
+        return errors::invalid_argument();
+    };
+    *DEVICE_KEY.lock().unwrap_or_else(|e| e.into_inner()) = key;
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_lock_app_document(path_ptr: *const c_char, is_mobile: bool, cpu_cores: usize) -> i32 {
+    let path = match unsafe { CStr::from_ptr(path_ptr) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return errors::invalid_argument(),
+    };
+    match lock_app_document(Path::new(path), is_mobile, cpu_cores) {
+        Ok(()) => 0,
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_read_app_document(
+    path_ptr: *const c_char,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+    is_mobile: bool,
+) -> i32 {
+    if output_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        match read_app_document(Path::new(path), is_mobile) {
+            Ok(data) => {
+                *output_len = data.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), output_ptr, data.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Replaces the plaintext file with a container under the device key.
+pub fn lock_app_document(path: &Path, is_mobile: bool, cpu_cores: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let key = DeviceKey::current()?;
+    let input = path.to_str().ok_or("Invalid path")?;
+    let temp = SecureTempFile::for_target(path, TempContents::Ciphertext)?;
+    let output = temp.path().to_str().ok_or("Invalid path")?;
+    let options = EncryptOptions { key_purpose: Some(KEY_PURPOSE_DEVICE), ..Default::default() };
+    encrypt_file_reporting(input, output, &key, &options, is_mobile, cpu_cores, &ProgressSink::none())?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+pub fn read_app_document(path: &Path, is_mobile: bool) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let key = DeviceKey::current()?;
+    if read_header(path)?.0.key_purpose != KEY_PURPOSE_DEVICE {
+        return Err("Not a device-key protected file".into());
+    }
+    decrypt_file_to_memory_with(path.to_str().ok_or("Invalid path")?, &key, is_mobile, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_to_memory_internal;
+    use std::fs;
+
+    #[test]
+    fn test_app_document_locks_in_place_under_device_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let document = dir.path().join("notes.json");
+        fs::write(&document, b"{\"draft\": true}").unwrap();
+        let key = DeviceKey(Zeroizing::new(rand::random()));
+
+        let temp = SecureTempFile::for_target(&document, TempContents::Ciphertext).unwrap();
+        let options = EncryptOptions { key_purpose: Some(KEY_PURPOSE_DEVICE), ..Default::default() };
+        let (input, output) = (document.to_str().unwrap(), temp.path().to_str().unwrap());
+        encrypt_file_reporting(input, output, &key, &options, false, 4, &ProgressSink::none()).unwrap();
+        temp.persist(&document).unwrap();
+
+        assert_eq!(read_header(&document).unwrap().0.key_purpose, KEY_PURPOSE_DEVICE);
+        assert_eq!(decrypt_file_to_memory_with(input, &key, false, 1).unwrap(), b"{\"draft\": true}");
+        assert!(decrypt_file_to_memory_internal(input, b"pw", false, 4).is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
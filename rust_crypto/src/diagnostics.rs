@@ -0,0 +1,127 @@
+use serde::Serialize;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+pub const BOTTLENECK_IO: &str = "io";
+pub const BOTTLENECK_CPU: &str = "cpu";
+
+const SLOWEST_CHUNKS: usize = 5;
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChunkTiming {
+    pub index: u64,
+    pub bytes: u64,
+    pub read_us: u64,
+    pub crypto_us: u64,
+    pub write_us: u64,
+}
+
+impl ChunkTiming {
+    fn total_us(&self) -> u64 {
+        self.read_us + self.crypto_us + self.write_us
+    }
+}
+
+// read_us and write_us are wall clock because chunks are read and written
+// one at a time; chunks are encrypted in parallel, so crypto_us is the wall
+// time of the parallel stages rather than the sum over chunks.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DiagnosticsReport {
+    pub chunks: Vec<ChunkTiming>,
+    pub read_us: u64,
+    pub crypto_us: u64,
+    pub write_us: u64,
+    pub bottleneck: &'static str,
+    pub slowest_chunks: Vec<u64>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    chunks: Vec<ChunkTiming>,
+    crypto_wall_us: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct ChunkRecorder {
+    recorded: Mutex<Recorded>,
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros() as u64
+}
+
+impl ChunkRecorder {
+    fn lock(&self) -> MutexGuard<'_, Recorded> {
+        self.recorded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update<F: FnOnce(&mut ChunkTiming)>(&self, index: usize, apply: F) {
+        let mut recorded = self.lock();
+        while recorded.chunks.len() <= index {
+            let next = recorded.chunks.len() as u64;
+            recorded.chunks.push(ChunkTiming { index: next, ..Default::default() });
+        }
+        apply(&mut recorded.chunks[index]);
+    }
+
+    pub fn read(&self, index: usize, bytes: usize, elapsed: Duration) {
+        self.update(index, |chunk| {
+            chunk.bytes = bytes as u64;
+            chunk.read_us += micros(elapsed);
+        });
+    }
+
+    pub fn crypto(&self, index: usize, elapsed: Duration) {
+        self.update(index, |chunk| chunk.crypto_us += micros(elapsed));
+    }
+
+    pub fn write(&self, index: usize, elapsed: Duration) {
+        self.update(index, |chunk| chunk.write_us += micros(elapsed));
+    }
+
+    pub fn crypto_stage(&self, elapsed: Duration) {
+        *self.lock().crypto_wall_us.get_or_insert(0) += micros(elapsed);
+    }
+
+    pub fn report(&self) -> DiagnosticsReport {
+        let recorded = self.lock();
+        let chunks = recorded.chunks.clone();
+        let read_us = chunks.iter().map(|c| c.read_us).sum::<u64>();
+        let write_us = chunks.iter().map(|c| c.write_us).sum::<u64>();
+        let crypto_us = recorded.crypto_wall_us.unwrap_or_else(|| chunks.iter().map(|c| c.crypto_us).sum());
+
+        let mut slowest: Vec<&ChunkTiming> = chunks.iter().collect();
+        slowest.sort_by_key(|c| std::cmp::Reverse(c.total_us()));
+        let slowest_chunks = slowest.iter().take(SLOWEST_CHUNKS).map(|c| c.index).collect();
+        DiagnosticsReport {
+            bottleneck: if read_us + write_us > crypto_us { BOTTLENECK_IO } else { BOTTLENECK_CPU },
+            chunks,
+            read_us,
+            crypto_us,
+            write_us,
+            slowest_chunks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_ranks_chunks_and_names_bottleneck() {
+        let recorder = ChunkRecorder::default();
+        for index in 0..8 {
+            recorder.read(index, 100, Duration::from_micros(10 * index as u64));
+            recorder.crypto(index, Duration::from_micros(50));
+            recorder.write(index, Duration::from_micros(5));
+        }
+        let report = recorder.report();
+        assert_eq!((report.read_us, report.crypto_us, report.write_us), (280, 400, 40));
+        assert_eq!(report.bottleneck, BOTTLENECK_CPU);
+        assert_eq!(report.slowest_chunks, vec![7, 6, 5, 4, 3]);
+
+        recorder.crypto_stage(Duration::from_micros(100));
+        assert_eq!(recorder.report().bottleneck, BOTTLENECK_IO);
+    }
+}
@@ -0,0 +1,358 @@
+use aes_gcm::{aead::Aead, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::convert::Infallible;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::cipher::BodyCipher;
+use crate::config;
+use crate::format::{read_header, read_layout, ContainerHeader, Hole};
+use crate::kdf::{self, KeyPurpose};
+use crate::padding::{PaddingStripper, PADDING_NONE};
+use crate::profile::OptionsProfile;
+use crate::sparse::{self, HoleCursor, Piece};
+use crate::progress::ProgressSink;
+use crate::{encrypt_file_reporting, errors, for_each_decrypted_chunk, random_nonce, EncryptOptions, NONCE_SIZE, TAG_SIZE};
+
+pub const SEALED_DIGEST_SIZE: usize = NONCE_SIZE + 32 + TAG_SIZE;
+
+type HmacSha256 = Hmac<Sha256>;
+// A file's content hash and the key that opened it.
+type Hashed = ([u8; 32], Zeroizing<[u8; 32]>);
+
+#[no_mangle]
+pub extern "C" fn files_have_same_content(
+    first_path_ptr: *const c_char,
+    second_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+) -> i32 {
+    unsafe {
+        let first_path = match CStr::from_ptr(first_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let second_path = match CStr::from_ptr(second_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match files_have_same_content_internal(first_path, second_path, password, is_mobile) {
+            Ok(true) => 1,
+            Ok(false) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_with_digest(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+    digest_ptr: *mut u8,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let options = EncryptOptions {
+            hint,
+            padding: OptionsProfile::current().padding,
+            ..Default::default()
+        };
+        match encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &ProgressSink::none()) {
+            Ok(digest) => {
+                if !digest_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(digest.as_ptr(), digest_ptr, digest.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_plaintext_digest(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    digest_ptr: *mut u8,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        if digest_ptr.is_null() {
+            return errors::invalid_argument();
+        }
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match plaintext_digest(input_path, password, is_mobile) {
+            Ok(digest) => {
+                std::ptr::copy_nonoverlapping(digest.as_ptr(), digest_ptr, digest.len());
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn files_have_same_content_internal(
+    first_path: &str,
+    second_path: &str,
+    password: &[u8],
+    is_mobile: bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if let (Some(first_len), Some(second_len)) = (plaintext_len(first_path)?, plaintext_len(second_path)?) {
+        if first_len != second_len {
+            return Ok(false);
+        }
+    }
+
+    let (first, second) = config::install(|| {
+        rayon::join(
+            || content_hash(first_path, password, is_mobile).map_err(|e| e.to_string()),
+            || content_hash(second_path, password, is_mobile).map_err(|e| e.to_string()),
+        )
+    });
+    Ok(first?.0 == second?.0)
+}
+
+// Keyed by the file's own key, so the digest of one file says nothing
+// about the content of another.
+pub fn plaintext_digest(
+    input_path: &str,
+    password: &[u8],
+    is_mobile: bool,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let (hash, key) = content_hash(input_path, password, is_mobile)?;
+    Ok(keyed(&key, &hash))
+}
+
+// Files that store their content hash only need the header opened; older
+// ones, and dual-slot containers, are decrypted and hashed.
+fn content_hash(
+    input_path: &str,
+    password: &[u8],
+    is_mobile: bool,
+) -> Result<Hashed, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(Path::new(input_path))?;
+    if !header.content_digest.is_empty() {
+        let key = Zeroizing::new(header.master_key(password)?);
+        header.verify_file_id(&key)?;
+        let hash = open(&header, &key)?.ok_or("Invalid header field")?;
+        return Ok((hash, key));
+    }
+    let mut stripper = PaddingStripper::new(header.padding);
+    let mut hasher = ContentHasher::new(u64::MAX).with_holes(&header.holes);
+    let key = for_each_decrypted_chunk(input_path, password, is_mobile, |chunk, _| {
+        stripper.feed(chunk, |content| {
+            hasher.update(content);
+            Ok(())
+        })
+    })?;
+    stripper.finish()?;
+    Ok((hasher.finalize(), key))
+}
+
+pub fn seal(header: &ContainerHeader, master: &[u8; 32], content_hash: &[u8; 32]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let cipher = BodyCipher::new(header.cipher, &kdf::subkey(master, KeyPurpose::ContentHash))?;
+    let nonce = random_nonce();
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), &content_hash[..])
+        .map_err(|_| "Encryption failed")?;
+    Ok([&nonce[..], &sealed].concat())
+}
+
+pub fn open(header: &ContainerHeader, master: &[u8; 32]) -> Result<Option<[u8; 32]>, Box<dyn std::error::Error>> {
+    if header.content_digest.is_empty() {
+        return Ok(None);
+    }
+    let (nonce, sealed) = header.content_digest.split_at(NONCE_SIZE.min(header.content_digest.len()));
+    if nonce.len() != NONCE_SIZE {
+        return Err("Invalid header field".into());
+    }
+    let cipher = BodyCipher::new(header.cipher, &kdf::subkey(master, KeyPurpose::ContentHash))?;
+    let hash = cipher.decrypt(Nonce::from_slice(nonce), sealed).map_err(|_| "Decryption failed")?;
+    Ok(Some(hash.as_slice().try_into()?))
+}
+
+pub fn keyed(key: &[u8; 32], content_hash: &[u8; 32]) -> [u8; 32] {
+    let mac_key = kdf::subkey(key, KeyPurpose::ContentDigest);
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(&mac_key).expect("HMAC accepts any key length");
+    mac.update(content_hash);
+    mac.finalize().into_bytes().into()
+}
+
+// Hashes the content while a file is being encrypted, ignoring anything
+// past it such as padding. The hash is only ever handed out keyed.
+pub struct ContentHasher {
+    hasher: blake3::Hasher,
+    remaining: u64,
+    holes: HoleCursor,
+}
+
+impl ContentHasher {
+    pub fn new(content_len: u64) -> Self {
+        ContentHasher {
+            hasher: blake3::Hasher::new(),
+            remaining: content_len,
+            holes: HoleCursor::default(),
+        }
+    }
+
+    // The digest is of the whole file, so holes left out of the body are
+    // hashed as the zeros they read as.
+    pub fn with_holes(mut self, holes: &[Hole]) -> Self {
+        self.holes = HoleCursor::new(holes);
+        self
+    }
+
+    pub fn update(&mut self, plaintext: &[u8]) {
+        let n = self.remaining.min(plaintext.len() as u64) as usize;
+        let hasher = &mut self.hasher;
+        let _ = self.holes.feed(&plaintext[..n], |piece| hash_piece(hasher, piece));
+        self.remaining -= n as u64;
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        let hasher = &mut self.hasher;
+        let _ = self.holes.finish(|piece| hash_piece(hasher, piece));
+        self.hasher.finalize().into()
+    }
+}
+
+fn hash_piece(hasher: &mut blake3::Hasher, piece: Piece) -> Result<(), Infallible> {
+    match piece {
+        Piece::Data(data) => {
+            hasher.update(data);
+        }
+        Piece::Hole(len) => sparse::zero_blocks(len).for_each(|zeros| {
+            hasher.update(zeros);
+        }),
+    }
+    Ok(())
+}
+
+fn plaintext_len(input_path: &str) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let layout = read_layout(Path::new(input_path))?;
+    if layout.padding != PADDING_NONE {
+        return Ok(None);
+    }
+    let (header, _) = read_header(Path::new(input_path))?;
+    Ok(Some(layout.frames.iter().map(|f| f.len - TAG_SIZE as u64).sum::<u64>() + sparse::hole_bytes(&header.holes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+    use crate::padding::PADDING_PADME;
+    use std::fs;
+
+    #[test]
+    fn test_same_content_detection() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain_a = dir.path().join("a.txt");
+        let plain_b = dir.path().join("b.txt");
+        fs::write(&plain_a, b"identical photo bytes").unwrap();
+        fs::write(&plain_b, b"different photo bytes").unwrap();
+
+        let paths: Vec<String> = ["a1.kyl", "a2.kyl", "b.kyl"]
+            .iter()
+            .map(|n| dir.path().join(n).to_str().unwrap().to_string())
+            .collect();
+        encrypt_file_internal(plain_a.to_str().unwrap(), &paths[0], b"pw", None, false, 4).unwrap();
+        encrypt_file_internal(plain_a.to_str().unwrap(), &paths[1], b"pw", Some("hint"), false, 4).unwrap();
+        encrypt_file_internal(plain_b.to_str().unwrap(), &paths[2], b"pw", None, false, 4).unwrap();
+
+        assert_ne!(fs::read(&paths[0]).unwrap(), fs::read(&paths[1]).unwrap());
+        assert!(files_have_same_content_internal(&paths[0], &paths[1], b"pw", false).unwrap());
+        assert_ne!(plaintext_digest(&paths[0], b"pw", false).unwrap(), plaintext_digest(&paths[1], b"pw", false).unwrap());
+        assert!(!files_have_same_content_internal(&paths[0], &paths[2], b"pw", false).unwrap());
+        assert!(files_have_same_content_internal(&paths[0], &paths[1], b"wrong", false).is_err());
+
+        let first = std::ffi::CString::new(paths[0].as_str()).unwrap();
+        let second = std::ffi::CString::new(paths[1].as_str()).unwrap();
+        assert_eq!(files_have_same_content(first.as_ptr(), second.as_ptr(), b"wrong".as_ptr(), 5, false), -2);
+        assert_eq!(errors::kyrie_last_error(), errors::ERR_WRONG_PASSWORD);
+    }
+
+    #[test]
+    fn test_comparison_reads_only_the_stored_digests() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("a.txt");
+        fs::write(&plain, vec![3u8; 5000]).unwrap();
+        let paths: Vec<String> = ["a1.kyl", "a2.kyl"].iter().map(|n| dir.path().join(n).to_str().unwrap().to_string()).collect();
+        for path in &paths {
+            encrypt_file_internal(plain.to_str().unwrap(), path, b"pw", None, false, 4).unwrap();
+        }
+        let (header, _) = read_header(Path::new(&paths[0])).unwrap();
+        assert_eq!(header.content_digest.len(), SEALED_DIGEST_SIZE);
+
+        // A damaged body would fail to decrypt, so the answer comes from
+        // the headers alone.
+        let mut bytes = fs::read(&paths[1]).unwrap();
+        let body = read_layout(Path::new(&paths[1])).unwrap().frames[0].offset as usize;
+        bytes[body + 20] ^= 1;
+        fs::write(&paths[1], &bytes).unwrap();
+        assert!(crate::decrypt_file_to_memory_internal(&paths[1], b"pw", false, 4).is_err());
+        assert!(files_have_same_content_internal(&paths[0], &paths[1], b"pw", false).unwrap());
+
+        // Changing the stored digest breaks the header's authentication.
+        let mut bytes = fs::read(&paths[0]).unwrap();
+        let digest_at = bytes.windows(header.content_digest.len()).position(|w| w == header.content_digest).unwrap();
+        bytes[digest_at + NONCE_SIZE] ^= 1;
+        fs::write(&paths[0], &bytes).unwrap();
+        assert!(files_have_same_content_internal(&paths[0], &paths[1], b"pw", false).is_err());
+    }
+
+    #[test]
+    fn test_digest_is_computed_while_encrypting() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.bin");
+        let encrypted = dir.path().join("plain.kyl");
+        let content: Vec<u8> = (0..1_500_000u32).map(|i| (i % 251) as u8).collect();
+
+        for (len, padding) in [(content.len(), PADDING_PADME), (1000, PADDING_NONE), (1000, PADDING_PADME)] {
+            fs::write(&plain, &content[..len]).unwrap();
+            let options = EncryptOptions { hint: None, padding, ..Default::default() };
+            let digest = encrypt_file_reporting(
+                plain.to_str().unwrap(),
+                encrypted.to_str().unwrap(),
+                &b"pw"[..],
+                &options,
+                false,
+                4,
+                &ProgressSink::none(),
+            )
+            .unwrap();
+            assert_eq!(digest, plaintext_digest(encrypted.to_str().unwrap(), b"pw", false).unwrap());
+        }
+    }
+}
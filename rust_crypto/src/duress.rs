@@ -0,0 +1,328 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::cipher::CIPHER_AES_256_GCM;
+use crate::config::KyrieConfig;
+use crate::format::{read_layout, ContainerHeader, NONCE_SCHEME_RANDOM};
+use crate::kdf::{KeyProvider, KEY_PURPOSE_FILE_DATA};
+use crate::padding::{pad_to, padded_len, PaddingStripper, PADDING_SLOTS};
+use crate::secure_temp::{self, SecureTempFile, TempContents};
+use crate::{errors, file_lock, random_nonce, rng, text, write_header, NONCE_SIZE, TAG_SIZE};
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_with_decoy(
+    real_path_ptr: *const c_char,
+    decoy_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    duress_password_ptr: *const u8,
+    duress_password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+) -> i32 {
+    unsafe {
+        let real_path = match CStr::from_ptr(real_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let decoy_path = match CStr::from_ptr(decoy_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let duress_password = std::slice::from_raw_parts(duress_password_ptr, duress_password_len);
+        if password == duress_password {
+            return errors::invalid_argument();
+        }
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_with_decoy_internal(
+            Path::new(real_path),
+            Path::new(decoy_path),
+            Path::new(output_path),
+            password,
+            duress_password,
+            hint,
+            is_mobile,
+        ) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn encrypt_with_decoy_internal(
+    real_path: &Path,
+    decoy_path: &Path,
+    output_path: &Path,
+    password: &[u8],
+    duress_password: &[u8],
+    hint: Option<&str>,
+    is_mobile: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if password == duress_password {
+        return Err("Duress password must differ from the primary password".into());
+    }
+    secure_temp::check_target(output_path)?;
+    let _lock = file_lock::lock_output(output_path)?;
+    let real = File::open(real_path)?;
+    let decoy = File::open(decoy_path)?;
+    let real_len = real.metadata()?.len();
+    let decoy_len = decoy.metadata()?.len();
+
+    let mut temp = SecureTempFile::for_target(output_path, TempContents::Ciphertext)?;
+    let mut output = BufWriter::new(temp.file());
+    let slots = [
+        Some(SlotPayload { input: &mut BufReader::new(real), len: real_len, keys: password }),
+        Some(SlotPayload { input: &mut BufReader::new(decoy), len: decoy_len, keys: duress_password }),
+    ];
+    write_slots(&mut output, slot_header(hint, is_mobile), slots, KyrieConfig::current().chunk_size(is_mobile), |_| {})?;
+    output.flush()?;
+    drop(output);
+    temp.persist(output_path)?;
+    Ok(())
+}
+
+pub struct SlotPayload<'a, K: KeyProvider + ?Sized> {
+    pub input: &'a mut dyn Read,
+    pub len: u64,
+    pub keys: &'a K,
+}
+
+// Only the hint is taken from the caller. Metadata, a preview or key slots
+// could only be sealed under one slot's key, and counter nonces would need
+// a file id of their own, so a two-slot container carries none of them
+// whether it holds one payload or two.
+pub fn slot_header(hint: Option<&str>, is_mobile: bool) -> ContainerHeader {
+    let mut header = ContainerHeader {
+        hint: text::hint_bytes(hint),
+        padding: PADDING_SLOTS,
+        nonce_scheme: NONCE_SCHEME_RANDOM,
+        key_purpose: KEY_PURPOSE_FILE_DATA,
+        cipher: CIPHER_AES_256_GCM,
+        ..Default::default()
+    };
+    // One salt for both slots; each password derives its own key from it,
+    // so the header holds nothing that belongs to only one of them.
+    header.use_password_kdf(is_mobile);
+    header
+}
+
+// Both slots are padded to the same length and chunked identically, and
+// their order is random, so neither slot size nor position tells a payload
+// from random fill or the real password from the duress one. `visit` sees
+// the padded plaintext of the first payload given, and its key is returned.
+pub fn write_slots<K, W, F>(
+    output: &mut W,
+    mut header: ContainerHeader,
+    mut slots: [Option<SlotPayload<K>>; 2],
+    chunk_size: usize,
+    mut visit: F,
+) -> Result<Option<Zeroizing<[u8; 32]>>, Box<dyn std::error::Error>>
+where
+    K: KeyProvider + ?Sized,
+    W: Write,
+    F: FnMut(&[u8]),
+{
+    let slot_plaintext_len = padded_len(slots.iter().flatten().map(|slot| slot.len).max().unwrap_or(0), PADDING_SLOTS);
+    let mut first = 0;
+    if rng::random::<[u8; 1]>()[0] & 1 == 1 {
+        slots.swap(0, 1);
+        first = 1;
+    }
+    let mut keys = slots
+        .iter()
+        .map(|slot| slot.as_ref().map(|slot| header.master_key(slot.keys).map(Zeroizing::new)).transpose())
+        .collect::<Result<Vec<_>, _>>()?;
+    header.seal_slot_file_ids([keys[0].as_deref(), keys[1].as_deref()]);
+    write_header(output, &header)?;
+
+    for (index, (slot, key)) in slots.into_iter().zip(&keys).enumerate() {
+        match (slot, key) {
+            (Some(slot), Some(key)) => {
+                let mut reader = slot.input.take(slot.len).chain(pad_to(slot.len, slot_plaintext_len));
+                let visit: &mut dyn FnMut(&[u8]) = if index == first { &mut visit } else { &mut |_| {} };
+                write_slot(output, &mut reader, slot_plaintext_len, &header.body_key(key), chunk_size, visit)?;
+            }
+            _ => write_random_slot(output, slot_plaintext_len, chunk_size)?,
+        }
+    }
+    Ok(keys.swap_remove(first))
+}
+
+fn write_slot<W: Write>(
+    output: &mut W,
+    reader: &mut dyn Read,
+    slot_plaintext_len: u64,
+    key: &[u8; 32],
+    chunk_size: usize,
+    visit: &mut dyn FnMut(&[u8]),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cipher = Aes256Gcm::new_from_slice(key)?;
+    let mut chunk = vec![0u8; slot_plaintext_len.min(chunk_size as u64) as usize];
+    let mut remaining = slot_plaintext_len;
+
+    while remaining > 0 {
+        let n = remaining.min(chunk_size as u64) as usize;
+        reader.read_exact(&mut chunk[..n])?;
+        visit(&chunk[..n]);
+        let nonce_bytes = random_nonce();
+        let encrypted = cipher
+            .encrypt(Nonce::from_slice(&nonce_bytes), &chunk[..n])
+            .map_err(|_| "Encryption failed")?;
+        output.write_all(&nonce_bytes)?;
+        output.write_all(&(encrypted.len() as u32).to_be_bytes())?;
+        output.write_all(&encrypted)?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+// Frames of the same sizes as a written slot, but random throughout.
+fn write_random_slot<W: Write>(output: &mut W, slot_plaintext_len: u64, chunk_size: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let mut frame = vec![0u8; NONCE_SIZE + slot_plaintext_len.min(chunk_size as u64) as usize + TAG_SIZE];
+    let mut remaining = slot_plaintext_len;
+
+    while remaining > 0 {
+        let n = remaining.min(chunk_size as u64) as usize;
+        let (nonce, sealed) = frame.split_at_mut(NONCE_SIZE);
+        let sealed = &mut sealed[..n + TAG_SIZE];
+        rng::fill(nonce);
+        rng::fill(sealed);
+        output.write_all(nonce)?;
+        output.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        output.write_all(sealed)?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+// Takes the key the password derives, which opens whichever slot it was
+// used for.
+pub fn for_each_slot_chunk<F>(input_path: &Path, key: &[u8; 32], mut visit: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(&[u8]) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let mut reader = BufReader::new(File::open(input_path)?);
+    let layout = read_layout(input_path)?.unlock(&mut reader, key)?;
+    let cipher = layout.body_cipher(key)?;
+
+    for frame in &layout.frames {
+        let (nonce, ciphertext) = layout.read_frame(&mut reader, frame)?;
+        let decrypted = cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| "Decryption failed")?;
+        visit(&decrypted)?;
+    }
+    Ok(())
+}
+
+pub fn decrypt_slot<W: Write>(input_path: &Path, key: &[u8; 32], output: &mut W) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stripper = PaddingStripper::new(PADDING_SLOTS);
+    for_each_slot_chunk(input_path, key, |chunk| stripper.feed(chunk, |content| Ok(output.write_all(content)?)))?;
+    stripper.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_header, read_layout};
+    use crate::kdf::KdfParams;
+    use crate::progress::ProgressSink;
+    use crate::range::decrypt_prefix_internal;
+    use crate::{decrypt_file_internal, decrypt_file_to_memory_internal, encrypt_file_reporting, get_hint_from_file_internal, EncryptOptions};
+    use std::fs;
+
+    #[test]
+    fn test_each_password_opens_its_own_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real.txt");
+        let decoy = dir.path().join("decoy.txt");
+        let output = dir.path().join("dual.kyl");
+        fs::write(&real, b"the actual secret notes").unwrap();
+        fs::write(&decoy, b"groceries").unwrap();
+
+        crate::kyrie_core::encrypt_with_decoy(&real, &decoy, &output, b"primary", b"duress", Some("hint")).unwrap();
+        let same = encrypt_with_decoy_internal(&real, &decoy, &dir.path().join("same.kyl"), b"same", b"same", None, false);
+        assert_eq!(errors::classify(same.unwrap_err().as_ref()), errors::ERR_INVALID_ARGUMENT);
+        let writer = file_lock::lock_output(&output).unwrap();
+        let busy = encrypt_with_decoy_internal(&real, &decoy, &output, b"primary", b"duress", None, false);
+        assert_eq!(errors::classify(busy.unwrap_err().as_ref()), errors::ERR_FILE_BUSY);
+        drop(writer);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+        // Both slots take their keys from the same salted KDF.
+        let (header, _) = crate::format::read_header(&output).unwrap();
+        assert!(header.dual() && header.kdf != KdfParams::LegacySha256 && header.wrapped_key.is_none());
+
+        let path = output.to_str().unwrap();
+        assert_eq!(decrypt_file_to_memory_internal(path, b"primary", false, 4).unwrap(), b"the actual secret notes");
+        assert_eq!(decrypt_file_to_memory_internal(path, b"duress", false, 4).unwrap(), b"groceries");
+        assert!(decrypt_file_to_memory_internal(path, b"wrong", false, 4).is_err());
+        assert_eq!(decrypt_prefix_internal(&output, b"duress", 5).unwrap(), b"groce");
+        assert_eq!(get_hint_from_file_internal(path).unwrap(), b"hint");
+
+        let decrypted = dir.path().join("decrypted.txt");
+        decrypt_file_internal(path, decrypted.to_str().unwrap(), b"duress", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"groceries");
+
+        let layout = read_layout(&output).unwrap();
+        let (first, second) = layout.frames.split_at(layout.frames.len() / 2);
+        assert!(first.iter().zip(second).all(|(a, b)| a.len == b.len));
+
+        // Either password notices a changed hint.
+        let mut bytes = fs::read(&output).unwrap();
+        let at = bytes.windows(4).position(|w| w == b"hint").unwrap();
+        bytes[at] = b'H';
+        let tampered = dir.path().join("tampered.kyl");
+        fs::write(&tampered, &bytes).unwrap();
+        for password in [&b"primary"[..], b"duress"] {
+            let error = decrypt_file_to_memory_internal(tampered.to_str().unwrap(), password, false, 4).unwrap_err();
+            assert_eq!(error.to_string(), "Header authentication failed");
+        }
+    }
+
+    #[test]
+    fn test_single_payload_slots_look_like_a_decoy_container() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real.txt");
+        let decoy = dir.path().join("decoy.txt");
+        fs::write(&real, b"the actual secret notes").unwrap();
+        fs::write(&decoy, b"groceries").unwrap();
+        let dual = dir.path().join("dual.kyl");
+        let single = dir.path().join("single.kyl");
+        encrypt_with_decoy_internal(&real, &decoy, &dual, b"primary", b"duress", Some("hint"), false).unwrap();
+        let options = EncryptOptions { hint: Some("hint"), padding: PADDING_SLOTS, ..Default::default() };
+        encrypt_file_reporting(&real, &single, &b"primary"[..], &options, false, 4, &ProgressSink::none()).unwrap();
+
+        let (dual_header, dual_len) = read_header(&dual).unwrap();
+        let (single_header, single_len) = read_header(&single).unwrap();
+        assert_eq!(dual_len, single_len);
+        assert_eq!(fs::metadata(&dual).unwrap().len(), fs::metadata(&single).unwrap().len());
+        let shape = |header: &ContainerHeader| ContainerHeader { kdf_salt: Vec::new(), file_id: None, ..header.clone() };
+        assert_eq!(shape(&dual_header), shape(&single_header));
+        assert!(single_header.file_id.is_some());
+
+        let path = single.to_str().unwrap();
+        assert_eq!(decrypt_file_to_memory_internal(path, b"primary", false, 4).unwrap(), b"the actual secret notes");
+        assert!(decrypt_file_to_memory_internal(path, b"duress", false, 4).is_err());
+        let sealed = EncryptOptions { padding: PADDING_SLOTS, private_hint: true, ..Default::default() };
+        assert!(encrypt_file_reporting(&real, dir.path().join("x.kyl"), &b"pw"[..], &sealed, false, 4, &ProgressSink::none()).is_err());
+    }
+}
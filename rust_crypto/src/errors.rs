@@ -0,0 +1,208 @@
+use std::cell::Cell;
+use std::io;
+
+use crate::kyrie_core::KyrieError;
+
+pub const ERR_NONE: i32 = 0;
+pub const ERR_INVALID_ARGUMENT: i32 = 1;
+pub const ERR_WRONG_PASSWORD: i32 = 2;
+pub const ERR_INVALID_FORMAT: i32 = 3;
+pub const ERR_UNSUPPORTED_VERSION: i32 = 4;
+pub const ERR_FILE_NOT_FOUND: i32 = 5;
+pub const ERR_PERMISSION_DENIED: i32 = 6;
+pub const ERR_DISK_FULL: i32 = 7;
+pub const ERR_IO: i32 = 8;
+pub const ERR_INTERNAL: i32 = 9;
+pub const ERR_OUTPUT_TOO_LARGE: i32 = 10;
+pub const ERR_KEY_ROTATION_REQUIRED: i32 = 11;
+pub const ERR_PASSWORD_EXPIRED: i32 = 12;
+pub const ERR_CANCELLED: i32 = 13;
+pub const ERR_VAULT_CONFLICT: i32 = 14;
+pub const ERR_VAULT_BUSY: i32 = 15;
+pub const ERR_SHARE_EXPIRED: i32 = 16;
+pub const ERR_SHARE_NOT_PERMITTED: i32 = 17;
+pub const ERR_KEYFILE_REQUIRED: i32 = 18;
+pub const ERR_HEADER_TAMPERED: i32 = 19;
+pub const ERR_CORRUPTED_CHUNK: i32 = 20;
+pub const ERR_OUTPUT_EXISTS: i32 = 21;
+pub const ERR_FILE_BUSY: i32 = 22;
+pub const ERR_TRUNCATED: i32 = 23;
+pub const ERR_GARBAGE_AT_END: i32 = 24;
+
+const CATALOG: [(i32, &str, &str); 25] = [
+    (ERR_NONE, "ERR_NONE", "No error."),
+    (ERR_INVALID_ARGUMENT, "ERR_INVALID_ARGUMENT", "An invalid argument was passed."),
+    (ERR_WRONG_PASSWORD, "ERR_WRONG_PASSWORD", "The password is incorrect or the file is damaged."),
+    (ERR_INVALID_FORMAT, "ERR_INVALID_FORMAT", "The file is not a valid encrypted file."),
+    (ERR_UNSUPPORTED_VERSION, "ERR_UNSUPPORTED_VERSION", "The file was created by a newer version of the app."),
+    (ERR_FILE_NOT_FOUND, "ERR_FILE_NOT_FOUND", "The file could not be found."),
+    (ERR_PERMISSION_DENIED, "ERR_PERMISSION_DENIED", "Permission to access the file was denied."),
+    (ERR_DISK_FULL, "ERR_DISK_FULL", "There is not enough free storage space."),
+    (ERR_IO, "ERR_IO", "The file could not be read or written."),
+    (ERR_INTERNAL, "ERR_INTERNAL", "An unexpected error occurred."),
+    (ERR_OUTPUT_TOO_LARGE, "ERR_OUTPUT_TOO_LARGE", "The decrypted file is larger than the allowed size."),
+    (ERR_KEY_ROTATION_REQUIRED, "ERR_KEY_ROTATION_REQUIRED", "This password has encrypted too much data and must be changed."),
+    (ERR_PASSWORD_EXPIRED, "ERR_PASSWORD_EXPIRED", "This is the previous password; the vault was rekeyed."),
+    (ERR_CANCELLED, "ERR_CANCELLED", "The operation was cancelled."),
+    (ERR_VAULT_CONFLICT, "ERR_VAULT_CONFLICT", "The vault was changed on another device and needs to be merged."),
+    (ERR_VAULT_BUSY, "ERR_VAULT_BUSY", "The vault is being changed on another device."),
+    (ERR_SHARE_EXPIRED, "ERR_SHARE_EXPIRED", "The share key has expired."),
+    (ERR_SHARE_NOT_PERMITTED, "ERR_SHARE_NOT_PERMITTED", "The share key does not allow this."),
+    (ERR_KEYFILE_REQUIRED, "ERR_KEYFILE_REQUIRED", "This file also needs its keyfile to open."),
+    (ERR_HEADER_TAMPERED, "ERR_HEADER_TAMPERED", "The file header was changed after the file was encrypted."),
+    (ERR_CORRUPTED_CHUNK, "ERR_CORRUPTED_CHUNK", "Part of the file is damaged."),
+    (ERR_OUTPUT_EXISTS, "ERR_OUTPUT_EXISTS", "A file with that name already exists."),
+    (ERR_FILE_BUSY, "ERR_FILE_BUSY", "The file is in use by another operation."),
+    (ERR_TRUNCATED, "ERR_TRUNCATED", "The file ends early; it may not have been copied completely."),
+    (ERR_GARBAGE_AT_END, "ERR_GARBAGE_AT_END", "The file has unexpected data after its end."),
+];
+
+thread_local! {
+    static LAST_ERROR: Cell<i32> = const { Cell::new(ERR_NONE) };
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_last_error() -> i32 {
+    LAST_ERROR.with(|last| last.get())
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_error_id(code: i32, id_ptr: *mut u8, id_len: *mut usize) -> i32 {
+    match describe(code) {
+        Some((id, _)) => copy_out(id, id_ptr, id_len),
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_error_description(code: i32, text_ptr: *mut u8, text_len: *mut usize) -> i32 {
+    match describe(code) {
+        Some((_, description)) => copy_out(description, text_ptr, text_len),
+        None => -1,
+    }
+}
+
+fn copy_out(value: &str, ptr: *mut u8, len: *mut usize) -> i32 {
+    unsafe {
+        *len = value.len();
+        if !ptr.is_null() {
+            std::ptr::copy_nonoverlapping(value.as_ptr(), ptr, value.len());
+        }
+    }
+    0
+}
+
+pub fn describe(code: i32) -> Option<(&'static str, &'static str)> {
+    CATALOG.iter().find(|(c, _, _)| *c == code).map(|(_, id, description)| (*id, *description))
+}
+
+pub fn classify(error: &(dyn std::error::Error + 'static)) -> i32 {
+    if let Some(e) = error.downcast_ref::<KyrieError>() {
+        return e.code();
+    }
+    if let Some(e) = error.downcast_ref::<io::Error>() {
+        return match e.kind() {
+            io::ErrorKind::NotFound => ERR_FILE_NOT_FOUND,
+            io::ErrorKind::PermissionDenied => ERR_PERMISSION_DENIED,
+            io::ErrorKind::StorageFull => ERR_DISK_FULL,
+            io::ErrorKind::AlreadyExists => ERR_OUTPUT_EXISTS,
+            io::ErrorKind::UnexpectedEof if e.to_string() == "Truncated file" => ERR_TRUNCATED,
+            io::ErrorKind::UnexpectedEof => ERR_INVALID_FORMAT,
+            io::ErrorKind::InvalidData if e.to_string() == "Corrupted chunk" => ERR_CORRUPTED_CHUNK,
+            io::ErrorKind::InvalidData if e.to_string() == "Unexpected data after end of file" => ERR_GARBAGE_AT_END,
+            _ => ERR_IO,
+        };
+    }
+    match error.to_string().as_str() {
+        "Decryption failed" | "Invalid password" | "Wrong vault password" | "Not enough key shares" => {
+            ERR_WRONG_PASSWORD
+        }
+        "Invalid recovery share"
+        | "Recovery share belongs to another file"
+        | "Invalid parity percentage"
+        | "Volume size is smaller than a chunk"
+        | "Invalid metadata entry"
+        | "Metadata key not found"
+        | "Journal entry not found"
+        | "Private mode needs a password"
+        | "Header too large"
+        | "Invalid output path"
+        | "Duress password must differ from the primary password" => ERR_INVALID_ARGUMENT,
+        "Invalid file format"
+        | "Invalid padding"
+        | "Body does not match its manifest"
+        | "Invalid header field"
+        | "Rejected by strict AEAD policy"
+        | "Not a convergent container" => ERR_INVALID_FORMAT,
+        "Truncated file" | "Unexpected end of file" => ERR_TRUNCATED,
+        "Unexpected data after end of file" => ERR_GARBAGE_AT_END,
+        "Unsupported version"
+        | "Unsupported header field"
+        | "Unsupported nonce scheme"
+        | "Unsupported key purpose"
+        | "Unsupported cipher suite"
+        | "Unsupported framing version"
+        | "Unsupported KDF parameters" => ERR_UNSUPPORTED_VERSION,
+        "Key usage limit reached" => ERR_KEY_ROTATION_REQUIRED,
+        "Password expired: vault was rekeyed" => ERR_PASSWORD_EXPIRED,
+        "Operation cancelled" => ERR_CANCELLED,
+        "Vault manifest conflict" => ERR_VAULT_CONFLICT,
+        "Vault is locked by another device" => ERR_VAULT_BUSY,
+        "File is locked by another operation" => ERR_FILE_BUSY,
+        "Share key has expired" => ERR_SHARE_EXPIRED,
+        "Share key does not allow re-sharing" => ERR_SHARE_NOT_PERMITTED,
+        "Keyfile required" => ERR_KEYFILE_REQUIRED,
+        "Header authentication failed" => ERR_HEADER_TAMPERED,
+        "Corrupted chunk" => ERR_CORRUPTED_CHUNK,
+        _ => ERR_INTERNAL,
+    }
+}
+
+pub fn invalid_argument() -> i32 {
+    LAST_ERROR.with(|last| last.set(ERR_INVALID_ARGUMENT));
+    -1
+}
+
+pub fn fail(error: &(dyn std::error::Error + 'static)) -> i32 {
+    fail_with(classify(error))
+}
+
+pub fn fail_with(code: i32) -> i32 {
+    LAST_ERROR.with(|last| last.set(code));
+    -2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, encrypt_file_internal};
+    use std::fs;
+
+    #[test]
+    fn test_failures_map_to_catalog_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        let output = dir.path().join("out.txt");
+        fs::write(&input, b"catalogued").unwrap();
+        encrypt_file_internal(input.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+
+        let decrypt = |path: &std::path::Path, password: &[u8]| {
+            decrypt_file_internal(path.to_str().unwrap(), output.to_str().unwrap(), password, false, 4).unwrap_err()
+        };
+        assert_eq!(classify(decrypt(&encrypted, b"wrong").as_ref()), ERR_WRONG_PASSWORD);
+        assert_eq!(classify(decrypt(&dir.path().join("missing.kyl"), b"pw").as_ref()), ERR_FILE_NOT_FOUND);
+        assert_eq!(classify(decrypt(&input, b"pw").as_ref()), ERR_INVALID_FORMAT);
+
+        assert_eq!(fail(decrypt(&encrypted, b"wrong").as_ref()), -2);
+        assert_eq!(kyrie_last_error(), ERR_WRONG_PASSWORD);
+
+        let mut len = 0;
+        assert_eq!(kyrie_error_id(ERR_WRONG_PASSWORD, std::ptr::null_mut(), &mut len), 0);
+        let mut id = vec![0u8; len];
+        kyrie_error_id(ERR_WRONG_PASSWORD, id.as_mut_ptr(), &mut len);
+        assert_eq!(id, b"ERR_WRONG_PASSWORD");
+        assert_eq!(kyrie_error_description(99, std::ptr::null_mut(), &mut len), -1);
+        assert!(CATALOG.iter().enumerate().all(|(i, (code, _, _))| *code == i as i32));
+    }
+}
@@ -0,0 +1,295 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use sha2::{Digest, Sha256};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::sync::Mutex;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::format::{read_header, EscrowSlot, ESCROW_AGENT_ID_SIZE};
+use crate::kdf::{self, KdfParams, KeyProvider, KeyPurpose};
+use crate::progress::ProgressSink;
+use crate::reencrypt;
+use crate::rng;
+use crate::{decrypt_file_reporting, errors, random_nonce};
+
+const AGENT_ID_CONTEXT: &[u8] = b"KYRIE_LOCK escrow agent";
+
+// Configured by the organization; every new password-protected file gets a
+// slot wrapping its master key for this agent.
+static RECOVERY_AGENT: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+
+struct EscrowKey(Zeroizing<[u8; 32]>);
+
+impl KeyProvider for EscrowKey {
+    fn key_for(&self, _salt: &[u8], _params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        Ok(*self.0)
+    }
+
+    fn derives_from_password(&self) -> bool {
+        false
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_recovery_agent(public_key_ptr: *const u8, public_key_len: usize) -> i32 {
+    let agent = if public_key_ptr.is_null() {
+        None
+    } else if public_key_len == 32 {
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(unsafe { std::slice::from_raw_parts(public_key_ptr, public_key_len) });
+        Some(public_key)
+    } else {
+        return errors::invalid_argument();
+    };
+    *RECOVERY_AGENT.lock().unwrap_or_else(|e| e.into_inner()) = agent;
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_list_escrow_slots(path_ptr: *const c_char, json_ptr: *mut u8, json_len: *mut usize) -> i32 {
+    if json_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        match list_escrow_slots(Path::new(path)).and_then(|ids| Ok(serde_json::to_vec(&ids)?)) {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_add_escrow_slot(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    public_key_ptr: *const u8,
+) -> i32 {
+    if public_key_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(std::slice::from_raw_parts(public_key_ptr, 32));
+
+        match add_escrow_slot(Path::new(path), password, &public_key) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_strip_escrow_slots(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    removed_ptr: *mut usize,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match strip_escrow_slots(Path::new(path), password) {
+            Ok(removed) => {
+                if !removed_ptr.is_null() {
+                    *removed_ptr = removed;
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_decrypt_with_recovery_key(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    secret_key_ptr: *const u8,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if secret_key_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let mut secret_key = Zeroizing::new([0u8; 32]);
+        secret_key.copy_from_slice(std::slice::from_raw_parts(secret_key_ptr, 32));
+
+        match decrypt_with_recovery_key(input_path, output_path, &secret_key, is_mobile, cpu_cores) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn configured_slots(master: &[u8; 32]) -> Result<Vec<EscrowSlot>, Box<dyn std::error::Error>> {
+    let agent = *RECOVERY_AGENT.lock().unwrap_or_else(|e| e.into_inner());
+    agent.map(|public_key| wrap_for_agent(&public_key, master)).into_iter().collect()
+}
+
+pub fn agent_id(public_key: &[u8; 32]) -> [u8; ESCROW_AGENT_ID_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(AGENT_ID_CONTEXT);
+    hasher.update(public_key);
+    hasher.finalize()[..ESCROW_AGENT_ID_SIZE].try_into().expect("SHA-256 output is longer than the id")
+}
+
+pub fn wrap_for_agent(public_key: &[u8; 32], master: &[u8; 32]) -> Result<EscrowSlot, Box<dyn std::error::Error>> {
+    seal_to_key(public_key, master, KeyPurpose::EscrowWrap)
+}
+
+// Shared with recipient slots, which differ only in the wrap purpose.
+pub fn seal_to_key(
+    public_key: &[u8; 32],
+    master: &[u8; 32],
+    purpose: KeyPurpose,
+) -> Result<EscrowSlot, Box<dyn std::error::Error>> {
+    let ephemeral = StaticSecret::from(rng::random::<[u8; 32]>());
+    let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(*public_key));
+    if !shared.was_contributory() {
+        return Err("Invalid public key".into());
+    }
+
+    let agent_id = agent_id(public_key);
+    let wrap_key = Zeroizing::new(kdf::subkey(shared.as_bytes(), purpose));
+    let nonce = random_nonce();
+    let sealed = Aes256Gcm::new_from_slice(wrap_key.as_slice())?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: master, aad: &[&agent_id[..], &ephemeral_public].concat() })
+        .map_err(|_| "Encryption failed")?;
+    Ok(EscrowSlot {
+        agent_id,
+        ephemeral: ephemeral_public,
+        nonce,
+        sealed: sealed.as_slice().try_into()?,
+    })
+}
+
+pub fn open_slot(
+    slot: &EscrowSlot,
+    secret: &StaticSecret,
+    purpose: KeyPurpose,
+) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+    let shared = secret.diffie_hellman(&PublicKey::from(slot.ephemeral));
+    let wrap_key = Zeroizing::new(kdf::subkey(shared.as_bytes(), purpose));
+    let master = Zeroizing::new(
+        Aes256Gcm::new_from_slice(wrap_key.as_slice())?
+            .decrypt(Nonce::from_slice(&slot.nonce), Payload { msg: &slot.sealed, aad: &[&slot.agent_id[..], &slot.ephemeral].concat() })
+            .map_err(|_| "Decryption failed")?,
+    );
+    Ok(Zeroizing::new(master.as_slice().try_into()?))
+}
+
+// Agent ids, hex encoded, of every escrow slot in the file.
+pub fn list_escrow_slots(path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    Ok(header.escrow.iter().map(|slot| hex::encode(slot.agent_id)).collect())
+}
+
+// Replaces any slot the agent already holds in the file.
+pub fn add_escrow_slot(path: &Path, password: &[u8], public_key: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+    reencrypt::rewrite_header(path, password, |header, master| {
+        let slot = wrap_for_agent(public_key, master)?;
+        header.escrow.retain(|existing| existing.agent_id != slot.agent_id);
+        header.escrow.push(slot);
+        Ok(())
+    })
+}
+
+pub fn strip_escrow_slots(path: &Path, password: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut removed = 0;
+    reencrypt::rewrite_header(path, password, |header, _| {
+        removed = header.escrow.len();
+        header.escrow.clear();
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+pub fn decrypt_with_recovery_key(
+    input_path: &str,
+    output_path: &str,
+    secret_key: &[u8; 32],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let secret = StaticSecret::from(*secret_key);
+    let agent_id = agent_id(PublicKey::from(&secret).as_bytes());
+    let (header, _) = read_header(Path::new(input_path))?;
+    let slot = header
+        .escrow
+        .iter()
+        .find(|slot| slot.agent_id == agent_id)
+        .ok_or("No escrow slot for this recovery key")?;
+    let master = open_slot(slot, &secret, KeyPurpose::EscrowWrap)?;
+    header.verify_file_id(&master)?;
+    decrypt_file_reporting(input_path, output_path, &EscrowKey(master), is_mobile, cpu_cores, &ProgressSink::none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, encrypt_file_internal};
+    use std::fs;
+
+    #[test]
+    fn test_escrow_slot_recovers_and_strips() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        let recovered = dir.path().join("recovered.txt");
+        fs::write(&plain, b"payroll").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+
+        let agent_secret = [7u8; 32];
+        let agent_public = PublicKey::from(&StaticSecret::from(agent_secret)).to_bytes();
+        assert!(add_escrow_slot(&encrypted, b"wrong", &agent_public).is_err());
+        add_escrow_slot(&encrypted, b"pw", &agent_public).unwrap();
+        add_escrow_slot(&encrypted, b"pw", &agent_public).unwrap();
+        assert_eq!(list_escrow_slots(&encrypted).unwrap(), vec![hex::encode(agent_id(&agent_public))]);
+
+        let (input, output) = (encrypted.to_str().unwrap(), recovered.to_str().unwrap());
+        decrypt_with_recovery_key(input, output, &agent_secret, false, 4).unwrap();
+        assert_eq!(fs::read(&recovered).unwrap(), b"payroll");
+        assert!(decrypt_with_recovery_key(input, output, &[9u8; 32], false, 4).is_err());
+
+        assert_eq!(strip_escrow_slots(&encrypted, b"pw").unwrap(), 1);
+        assert!(list_escrow_slots(&encrypted).unwrap().is_empty());
+        assert!(decrypt_with_recovery_key(input, output, &agent_secret, false, 4).is_err());
+        decrypt_file_internal(input, output, b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&recovered).unwrap(), b"payroll");
+    }
+}
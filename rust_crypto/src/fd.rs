@@ -0,0 +1,238 @@
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::mem::ManuallyDrop;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use crate::file_lock;
+use crate::metadata::FileMetadata;
+use crate::progress::ProgressSink;
+use crate::{decrypt_into, encrypt_into, errors, EncryptOptions, PlainInput};
+
+// A file descriptor on Unix, a HANDLE on Windows. Either way it stays owned
+// by the caller and is never closed here.
+#[cfg(unix)]
+pub type KyrieDescriptor = std::os::unix::io::RawFd;
+#[cfg(windows)]
+pub type KyrieDescriptor = isize;
+
+#[no_mangle]
+pub extern "C" fn encrypt_fd(
+    input_fd: KyrieDescriptor,
+    output_fd: KyrieDescriptor,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_fd_internal(input_fd, output_fd, password, hint, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_fd(
+    input_fd: KyrieDescriptor,
+    output_fd: KyrieDescriptor,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    let password = unsafe { std::slice::from_raw_parts(password_ptr, password_len) };
+    match decrypt_fd_internal(input_fd, output_fd, password, is_mobile, cpu_cores) {
+        Ok(_) => 0,
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+// The output cannot be renamed into place, so it is written directly and
+// truncated again if anything fails.
+pub fn encrypt_fd_internal(
+    input_fd: KyrieDescriptor,
+    output_fd: KyrieDescriptor,
+    password: &[u8],
+    hint: Option<&str>,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_path = descriptor_path(input_fd)?;
+    let file_metadata = FileMetadata { name: descriptor_name(&input_path), ..FileMetadata::for_input(&input_path)? };
+    // Written in place, so a second writer would interleave with this one.
+    let output = borrow(output_fd);
+    file_lock::lock_descriptor(&output, true)?;
+    let result = encrypt_path_to_fd(&input_path, output_fd, password, hint, &file_metadata, is_mobile, cpu_cores);
+    let _ = output.unlock();
+    result
+}
+
+fn encrypt_path_to_fd(
+    input_path: &Path,
+    output_fd: KyrieDescriptor,
+    password: &[u8],
+    hint: Option<&str>,
+    file_metadata: &FileMetadata,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output = borrow_output(output_fd)?;
+    let options = EncryptOptions { hint, ..Default::default() };
+    let result = encrypt_into(
+        PlainInput::Path(input_path),
+        &mut *output,
+        password,
+        &options,
+        Some(file_metadata),
+        is_mobile,
+        cpu_cores,
+        &ProgressSink::none(),
+    );
+    finish_output(&output, result.map(|_| ()))
+}
+
+pub fn decrypt_fd_internal(
+    input_fd: KyrieDescriptor,
+    output_fd: KyrieDescriptor,
+    password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_path = descriptor_path(input_fd)?;
+    // Held on the caller's descriptor, as the path reopens the same file.
+    let input = borrow(input_fd);
+    file_lock::lock_descriptor(&input, false)?;
+    let result = decrypt_path_to_fd(&input_path, output_fd, password, is_mobile, cpu_cores);
+    let _ = input.unlock();
+    result
+}
+
+fn decrypt_path_to_fd(
+    input_path: &Path,
+    output_fd: KyrieDescriptor,
+    password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output = borrow_output(output_fd)?;
+    let result = decrypt_into(input_path, &mut *output, password, is_mobile, cpu_cores, &ProgressSink::none())
+        .and_then(|(header, key)| header.verify_file_id(&key));
+    finish_output(&output, result)
+}
+
+fn borrow_output(fd: KyrieDescriptor) -> io::Result<ManuallyDrop<File>> {
+    let mut file = borrow(fd);
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+// Wrapped without being taken over, so dropping it leaves the descriptor
+// open for the caller.
+pub fn borrow(fd: KyrieDescriptor) -> ManuallyDrop<File> {
+    #[cfg(unix)]
+    let file = unsafe { <File as std::os::unix::io::FromRawFd>::from_raw_fd(fd) };
+    #[cfg(windows)]
+    let file = unsafe { <File as std::os::windows::io::FromRawHandle>::from_raw_handle(fd as _) };
+    ManuallyDrop::new(file)
+}
+
+fn finish_output(output: &File, result: Result<(), Box<dyn std::error::Error>>) -> Result<(), Box<dyn std::error::Error>> {
+    match result {
+        Ok(()) => Ok(output.sync_all()?),
+        Err(e) => {
+            let _ = output.set_len(0);
+            Err(e)
+        }
+    }
+}
+
+// The pipeline reopens its input by path, so the descriptor is turned
+// into a path that leads back to the same file.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn descriptor_path(fd: KyrieDescriptor) -> io::Result<PathBuf> {
+    Ok(PathBuf::from(format!("/proc/self/fd/{fd}")))
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn descriptor_path(fd: KyrieDescriptor) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    let mut buf = [0u8; libc::PATH_MAX as usize];
+    if unsafe { libc::fcntl(fd, libc::F_GETPATH, buf.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(&buf[..len])))
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))))]
+fn descriptor_path(fd: KyrieDescriptor) -> io::Result<PathBuf> {
+    Ok(PathBuf::from(format!("/dev/fd/{fd}")))
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetFinalPathNameByHandleW(file: *mut std::ffi::c_void, path: *mut u16, len: u32, flags: u32) -> u32;
+}
+
+#[cfg(windows)]
+fn descriptor_path(handle: KyrieDescriptor) -> io::Result<PathBuf> {
+    use std::os::windows::ffi::OsStringExt;
+    let mut buf = vec![0u16; 32768];
+    let len = unsafe { GetFinalPathNameByHandleW(handle as _, buf.as_mut_ptr(), buf.len() as u32, 0) } as usize;
+    if len == 0 || len >= buf.len() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PathBuf::from(std::ffi::OsString::from_wide(&buf[..len])))
+}
+
+// The name recorded in the header is the file's own, not that of the
+// descriptor path it was opened through.
+fn descriptor_name(path: &Path) -> Option<String> {
+    let resolved = fs::canonicalize(path).ok()?;
+    resolved.file_name()?.to_str().map(str::to_string)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::metadata::get_file_metadata_internal;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_descriptors_round_trip_without_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("photo.txt");
+        let encrypted = dir.path().join("photo.kyl");
+        let decrypted = dir.path().join("photo.out");
+        fs::write(&plain, b"from a document provider").unwrap();
+
+        let input = File::open(&plain).unwrap();
+        let output = File::options().read(true).write(true).create(true).truncate(false).open(&encrypted).unwrap();
+        encrypt_fd_internal(input.as_raw_fd(), output.as_raw_fd(), b"pw", None, false, 4).unwrap();
+        assert_eq!(get_file_metadata_internal(&encrypted, &b"pw"[..]).unwrap().name.as_deref(), Some("photo.txt"));
+
+        let input = File::open(&encrypted).unwrap();
+        let output = File::create(&decrypted).unwrap();
+        decrypt_fd_internal(input.as_raw_fd(), output.as_raw_fd(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"from a document provider");
+
+        // A failed decryption leaves the caller's file empty, and the
+        // descriptor is still open afterwards.
+        assert!(decrypt_fd_internal(input.as_raw_fd(), output.as_raw_fd(), b"wrong", false, 4).is_err());
+        assert_eq!(fs::metadata(&decrypted).unwrap().len(), 0);
+        assert!(output.metadata().is_ok());
+    }
+}
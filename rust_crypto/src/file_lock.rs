@@ -0,0 +1,166 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Advisory locks that keep two operations off the same file: whatever
+// writes a container holds it exclusively, whatever decrypts one holds it
+// shared. Only programs that take the locks are kept out.
+pub const BUSY: &str = "File is locked by another operation";
+
+// Windows locks are mandatory, so a lock on a container would keep this
+// process's own second handle from reading or renaming it. Every lock is
+// therefore taken on a file beside the container rather than on the
+// container itself, which also covers outputs that do not exist until they
+// are renamed into place. Writers hold it exclusively, readers shared.
+pub struct PathLock {
+    sidecar: PathBuf,
+    file: Option<File>,
+}
+
+impl PathLock {
+    fn acquire(target: &Path, exclusive: bool, wait: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = target.file_name().ok_or("Invalid output path")?;
+        let mut sidecar_name = std::ffi::OsString::from(".");
+        sidecar_name.push(name);
+        sidecar_name.push(".kyrie-lock");
+        let sidecar = target.with_file_name(sidecar_name);
+
+        let file = loop {
+            let file = match open_sidecar(&sidecar) {
+                Ok(file) => file,
+                // Nothing can replace a container in a directory nobody may
+                // write to, so a reader goes on without the lock.
+                Err(e) if !exclusive && matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem) => {
+                    return Ok(PathLock { sidecar, file: None });
+                }
+                Err(e) => return Err(e.into()),
+            };
+            lock(match (exclusive, wait) {
+                (true, true) => file.lock().map_err(TryLockError::Error),
+                (true, false) => file.try_lock(),
+                (false, _) => file.try_lock_shared(),
+            })?;
+            // A holder that just finished may have unlinked the file opened
+            // here, which would leave this lock on nothing.
+            if still_linked(&file, &sidecar) {
+                break file;
+            }
+        };
+        Ok(PathLock { sidecar, file: Some(file) })
+    }
+}
+
+// Removed while still locked exclusively, so a waiting process either sees
+// it gone or finds its own copy unlinked and opens it again. A reader only
+// removes it when no other reader shares it.
+impl Drop for PathLock {
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            if cfg!(unix) && file.try_lock().is_ok() {
+                let _ = fs::remove_file(&self.sidecar);
+            }
+        }
+    }
+}
+
+// Held while the output is written and renamed into place.
+pub fn lock_output(target: &Path) -> Result<PathLock, Box<dyn std::error::Error>> {
+    PathLock::acquire(target, true, false)
+}
+
+// Waits for the holder instead of failing, for small files that are only
+// ever held for one read-modify-write.
+pub fn wait_for_output(target: &Path) -> Result<PathLock, Box<dyn std::error::Error>> {
+    PathLock::acquire(target, true, true)
+}
+
+// Held for as long as the input is read. Dropping it releases the lock.
+pub fn lock_input(path: &Path) -> Result<PathLock, Box<dyn std::error::Error>> {
+    PathLock::acquire(path, false, false)
+}
+
+// For descriptors the caller owns; the lock goes with the descriptor's
+// open file, and is released by `File::unlock` or when the caller closes it.
+pub fn lock_descriptor(file: &File, exclusive: bool) -> Result<(), Box<dyn std::error::Error>> {
+    lock(if exclusive { file.try_lock() } else { file.try_lock_shared() })
+}
+
+fn lock(result: Result<(), TryLockError>) -> Result<(), Box<dyn std::error::Error>> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(BUSY.into()),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+#[cfg(unix)]
+fn open_sidecar(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+}
+
+// Windows cannot unlink an open file, so the last handle to close deletes it.
+#[cfg(windows)]
+fn open_sidecar(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x0400_0000;
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).custom_flags(FILE_FLAG_DELETE_ON_CLOSE).open(path)
+}
+
+#[cfg(unix)]
+fn still_linked(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(held), Ok(named)) => held.dev() == named.dev() && held.ino() == named.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(windows)]
+fn still_linked(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, encrypt_file_internal, errors};
+
+    #[test]
+    fn test_writers_exclude_each_other_and_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("photo.jpg");
+        let encrypted = dir.path().join("photo.kyl");
+        let decrypted = dir.path().join("photo.out");
+        fs::write(&plain, b"locked").unwrap();
+        let (plain_str, encrypted_str, decrypted_str) =
+            (plain.to_str().unwrap(), encrypted.to_str().unwrap(), decrypted.to_str().unwrap());
+
+        // Another process writing the same output.
+        let writer = lock_output(&encrypted).unwrap();
+        let busy = encrypt_file_internal(plain_str, encrypted_str, b"pw", None, false, 4).unwrap_err();
+        assert_eq!(errors::classify(busy.as_ref()), errors::ERR_FILE_BUSY);
+        drop(writer);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        encrypt_file_internal(plain_str, encrypted_str, b"pw", None, false, 4).unwrap();
+
+        // Readers share the input, but keep it from being replaced.
+        let reader = lock_input(&encrypted).unwrap();
+        decrypt_file_internal(encrypted_str, decrypted_str, b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"locked");
+        let busy = encrypt_file_internal(plain_str, encrypted_str, b"pw", None, false, 4).unwrap_err();
+        assert_eq!(errors::classify(busy.as_ref()), errors::ERR_FILE_BUSY);
+        drop(reader);
+
+        let writer = lock_output(&encrypted).unwrap();
+        // The container itself stays unlocked, so the holder's own handles
+        // can still read it where locks are mandatory.
+        File::open(&encrypted).unwrap().try_lock().unwrap();
+        fs::remove_file(&decrypted).unwrap();
+        let busy = decrypt_file_internal(encrypted_str, decrypted_str, b"pw", false, 4).unwrap_err();
+        assert_eq!(errors::classify(busy.as_ref()), errors::ERR_FILE_BUSY);
+        drop(writer);
+        decrypt_file_internal(encrypted_str, decrypted_str, b"pw", false, 4).unwrap();
+        crate::reencrypt::set_hint(&encrypted, b"pw", "new hint").unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+}
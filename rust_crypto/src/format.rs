@@ -0,0 +1,1488 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Take};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::cipher::{BodyCipher, CIPHER_AES_256_GCM, CIPHER_CHACHA20_POLY1305};
+use crate::digest::SEALED_DIGEST_SIZE;
+use crate::header_backup;
+use crate::kdf::{self, KdfParams, KeyProvider, KeyPurpose, KDF_SALT_SIZE, KEY_PURPOSE_DEVICE, KEY_PURPOSE_LEGACY};
+use crate::manifest::{Manifest, MANIFEST_SIZE};
+use crate::nonce::{self, NonceSequence};
+use crate::padding::{self, PADDING_NONE, PADDING_SLOTS};
+use crate::pipeline::{self, Frame, MAX_FRAME_LEN};
+use crate::rng;
+use crate::strict;
+use crate::text;
+use crate::{HEADER_SIZE, MAGIC_STRING, MAX_CHUNK_SIZE, MAX_HINT_LENGTH, NONCE_SIZE, TAG_SIZE, VERSION};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const CHUNK_LEN_SIZE: u64 = 4;
+pub const EXTENDED_VERSION: u32 = 2;
+pub const FIELD_CRITICAL: u8 = 0x80;
+pub const FIELD_FILE_ID: u8 = 0x03;
+pub const FIELD_CHUNK_CHECKSUMS: u8 = 0x04;
+pub const FIELD_CHUNK_INDEX: u8 = 0x05;
+pub const FIELD_ESCROW: u8 = 0x06;
+pub const FIELD_KEY_SLOT: u8 = 0x07;
+pub const FIELD_FRAMING: u8 = 0x08;
+pub const FIELD_METADATA: u8 = 0x09;
+pub const FIELD_MANIFEST: u8 = 0x0a;
+pub const FIELD_PREVIEW: u8 = 0x0b;
+pub const FIELD_HINT: u8 = 0x0c;
+pub const FIELD_DEVICE_SLOT: u8 = 0x0d;
+pub const FIELD_APP_METADATA: u8 = 0x0e;
+pub const FIELD_PRIVATE_HINT: u8 = 0x0f;
+pub const FIELD_CONTENT_DIGEST: u8 = 0x10;
+pub const FIELD_PADDING: u8 = 0x81;
+pub const FIELD_NONCE_SCHEME: u8 = 0x83;
+pub const FIELD_KEY_PURPOSE: u8 = 0x84;
+pub const FIELD_SHARE_POLICY: u8 = 0x85;
+pub const FIELD_KEY_SHARE: u8 = 0x86;
+pub const FIELD_KDF: u8 = 0x87;
+pub const FIELD_CONVERGENT: u8 = 0x88;
+pub const FIELD_HEADER_BACKUP: u8 = 0x89;
+pub const FIELD_WRAPPED_KEY: u8 = 0x8a;
+pub const FIELD_CIPHER_SUITE: u8 = 0x8b;
+pub const FIELD_KEYFILE: u8 = 0x8c;
+pub const FIELD_RECIPIENT: u8 = 0x8d;
+pub const FIELD_PARITY: u8 = 0x8e;
+pub const FIELD_HOLES: u8 = 0x8f;
+pub const KDF_ARGON2ID: u8 = 1;
+// KDF_ARGON2ID || memory_kib u32 LE || iterations u32 LE || lanes u8
+pub const KDF_PARAMS_SIZE: usize = 1 + 4 + 4 + 1;
+pub const KDF_FIELD_SIZE: usize = KDF_PARAMS_SIZE + KDF_SALT_SIZE;
+pub const FILE_ID_SIZE: usize = 16;
+const FILE_ID_CONTEXT: &[u8] = b"KYRIE_LOCK_FILE_ID";
+pub const CHECKSUM_SIZE: usize = 4;
+pub const CHUNK_INDEX_SIZE: usize = 4;
+pub const FRAMING_SIZE: usize = 1 + 4 + 8;
+pub const FRAMING_SINGLE: u8 = 0;
+pub const FRAMING_LENGTH_PREFIXED: u8 = 1;
+// Length-prefixed frames of unknown count, written by a pipe. An empty
+// last frame marks the end, so a cut-off stream does not pass as whole.
+pub const FRAMING_STREAMED: u8 = 2;
+// Frames keyed by their own contents, each led by its sealed key, and a MAC
+// over the header and those keys after the last. Only the convergent
+// chunk functions read it.
+pub const FRAMING_CONVERGENT_CHUNKS: u8 = 3;
+pub const CHECKSUMS_PER_FIELD: usize = u8::MAX as usize / CHECKSUM_SIZE;
+// Keeps the checksum fields inside the u16 length of the field area.
+pub const MAX_CHUNK_CHECKSUMS: usize = 16_000;
+pub const NONCE_SCHEME_RANDOM: u8 = 0;
+pub const NONCE_SCHEME_COUNTER: u8 = 1;
+pub const SHARE_SALT_SIZE: usize = 16;
+pub const SEALED_CONTENT_KEY_SIZE: usize = NONCE_SIZE + 32 + TAG_SIZE;
+pub const WRAPPED_KEY_SIZE: usize = NONCE_SIZE + 32 + TAG_SIZE;
+pub const KEY_SLOT_SIZE: usize = KDF_SALT_SIZE + WRAPPED_KEY_SIZE;
+// Slot 0 is the wrapped key under the header salt.
+pub const MAX_KEY_SLOTS: usize = 8;
+pub const SEALED_SHARE_SIZE: usize = 32 + TAG_SIZE;
+pub const KEY_SHARE_SIZE: usize = 1 + SHARE_SALT_SIZE + NONCE_SIZE + SEALED_SHARE_SIZE;
+pub const ESCROW_AGENT_ID_SIZE: usize = 8;
+pub const ESCROW_SLOT_SIZE: usize = ESCROW_AGENT_ID_SIZE + 32 + NONCE_SIZE + 32 + TAG_SIZE;
+pub const PARITY_FIELD_SIZE: usize = 1 + 4 + 8;
+// Platform keystores return blobs of their own making; DPAPI's run to a
+// few hundred bytes for a 32-byte key.
+pub const MAX_DEVICE_BLOB_LEN: usize = 1024;
+// offset u64 LE || length u64 LE
+pub const HOLE_SIZE: usize = 16;
+// Only the largest holes are kept; the rest are sealed as zeros.
+pub const MAX_HOLES: usize = 1024;
+pub const PRIVATE_HINT_PADDED: u8 = 0;
+pub const PRIVATE_HINT_SEALED: u8 = 1;
+// salt(16) || nonce(12) || sealed length(1) and padded hint || tag(16)
+pub const SEALED_HINT_SIZE: usize = KDF_SALT_SIZE + NONCE_SIZE + 1 + MAX_HINT_LENGTH + TAG_SIZE;
+pub const MAX_HEADER_LEN: usize = HEADER_SIZE + 1 + u8::MAX as usize + 2 + u16::MAX as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileId {
+    pub uuid: [u8; FILE_ID_SIZE],
+    tag: [u8; FILE_ID_SIZE],
+    // Two-slot containers carry a tag for each slot, in slot order; one
+    // without a payload gets random bytes.
+    slot_tag: Option<[u8; FILE_ID_SIZE]>,
+}
+
+impl FileId {
+    pub fn to_uuid_string(self) -> String {
+        let hex = hex::encode(self.uuid);
+        format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+    }
+}
+
+// A slot holds one share of the file key, sealed under a key derived from
+// that holder's password and the slot salt.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyShareSlot {
+    pub x: u8,
+    pub salt: [u8; SHARE_SALT_SIZE],
+    pub nonce: [u8; NONCE_SIZE],
+    pub sealed: [u8; SEALED_SHARE_SIZE],
+}
+
+// Slots of files written before the KDF was kept here derive their keys
+// from the legacy password hash.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SharePolicy {
+    pub threshold: u8,
+    pub slots: Vec<KeyShareSlot>,
+    pub kdf: KdfParams,
+}
+
+// The master key wrapped for a recovery agent's X25519 public key through
+// an ephemeral key agreement. Readers without escrow support skip it.
+// Recipient slots have the same layout but are the only way into the file,
+// so their field is critical.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EscrowSlot {
+    pub agent_id: [u8; ESCROW_AGENT_ID_SIZE],
+    pub ephemeral: [u8; 32],
+    pub nonce: [u8; NONCE_SIZE],
+    pub sealed: [u8; 32 + TAG_SIZE],
+}
+
+impl EscrowSlot {
+    fn encode(&self) -> Vec<u8> {
+        [&self.agent_id[..], &self.ephemeral, &self.nonce, &self.sealed].concat()
+    }
+
+    fn parse(value: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let (agent_id, rest) = value.split_at(ESCROW_AGENT_ID_SIZE);
+        let (ephemeral, rest) = rest.split_at(32);
+        let (nonce, sealed) = rest.split_at(NONCE_SIZE);
+        Ok(EscrowSlot {
+            agent_id: agent_id.try_into()?,
+            ephemeral: ephemeral.try_into()?,
+            nonce: nonce.try_into()?,
+            sealed: sealed.try_into()?,
+        })
+    }
+}
+
+// Reed-Solomon parity over every stored frame, kept between the body and
+// the backup trailer. Readers that do not know it would take the parity
+// for body bytes, so the field is critical.
+// A hint stored at one length whatever it says, so neither its text nor
+// its length shows without the hint password when it is sealed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrivateHint {
+    Padded,
+    Sealed(Vec<u8>),
+}
+
+// A run of a sparse input that was never allocated, by plaintext offset.
+// The body holds only the data around the holes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hole {
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Parity {
+    pub percent: u8,
+    pub shard_size: u32,
+    pub len: u64,
+}
+
+// The file key wrapped by a key that never leaves a device's keystore, so
+// the file also opens with a fingerprint on that device. Readers without
+// keystore support skip it and ask for the password.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceSlot {
+    pub kind: u8,
+    pub blob: Vec<u8>,
+}
+
+// The content key of a convergent container, sealed under the user secret.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SealedContentKey {
+    pub nonce: [u8; NONCE_SIZE],
+    pub sealed: [u8; 32 + TAG_SIZE],
+}
+
+// How the body is cut into frames, written by the encryptor so decryptors
+// never infer it from their own chunk size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Framing {
+    pub version: u8,
+    pub chunk_size: u32,
+    pub chunk_count: u64,
+}
+
+// The random file key, sealed under a key derived from the password, so a
+// password change only rewrites this field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WrappedKey {
+    pub nonce: [u8; NONCE_SIZE],
+    pub sealed: [u8; 32 + TAG_SIZE],
+}
+
+impl WrappedKey {
+    fn seal(password_key: &[u8; 32], file_key: &[u8; 32], salt: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let cipher = Aes256Gcm::new_from_slice(&kdf::subkey(password_key, KeyPurpose::KeyWrap))?;
+        let nonce: [u8; NONCE_SIZE] = rng::random();
+        let sealed = cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: file_key, aad: salt })
+            .map_err(|_| "Encryption failed")?;
+        Ok(WrappedKey { nonce, sealed: sealed.as_slice().try_into()? })
+    }
+
+    fn open(&self, password_key: &[u8; 32], salt: &[u8]) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        let cipher = Aes256Gcm::new_from_slice(&kdf::subkey(password_key, KeyPurpose::KeyWrap))?;
+        let file_key = Zeroizing::new(
+            cipher
+                .decrypt(Nonce::from_slice(&self.nonce), Payload { msg: &self.sealed, aad: salt })
+                .map_err(|_| "Decryption failed")?,
+        );
+        Ok(file_key.as_slice().try_into()?)
+    }
+}
+
+// A further copy of the file key, wrapped under another password with its
+// own salt and the header's KDF parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeySlot {
+    pub salt: [u8; KDF_SALT_SIZE],
+    pub wrapped: WrappedKey,
+}
+
+// Password keys only unwrap the file key, trying every slot in turn;
+// providers holding a random key already hand over the file key itself.
+fn unlock_slot<K: KeyProvider + ?Sized>(
+    keys: &K,
+    kdf: &KdfParams,
+    salt: &[u8],
+    wrapped_key: Option<&WrappedKey>,
+    key_slots: &[KeySlot],
+    keyfile: bool,
+) -> Result<(usize, [u8; 32]), Box<dyn std::error::Error>> {
+    if keyfile && keys.derives_from_password() && !keys.uses_keyfile() {
+        return Err("Keyfile required".into());
+    }
+    let Some(wrapped_key) = wrapped_key.filter(|_| keys.derives_from_password()) else {
+        return Ok((0, keys.key_for(salt, kdf)?));
+    };
+    let slots = std::iter::once((salt, wrapped_key)).chain(key_slots.iter().map(|slot| (&slot.salt[..], &slot.wrapped)));
+    for (index, (salt, wrapped)) in slots.enumerate() {
+        if let Ok(file_key) = wrapped.open(&Zeroizing::new(keys.key_for(salt, kdf)?), salt) {
+            return Ok((index, file_key));
+        }
+    }
+    Err("Decryption failed".into())
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerHeader {
+    pub hint: Vec<u8>,
+    // Replaces the fixed slot and long hint field when set; a sealed hint
+    // leaves `hint` empty.
+    pub private_hint: Option<PrivateHint>,
+    pub padding: u8,
+    // A copy of the header is kept at the end of the file, after the body.
+    pub backup: bool,
+    pub parity: Option<Parity>,
+    pub nonce_scheme: u8,
+    pub key_purpose: u8,
+    pub cipher: u8,
+    pub file_id: Option<FileId>,
+    pub chunk_checksums: Vec<u32>,
+    // The plaintext size of every frame but the last. Frames are otherwise
+    // equal, so this stands in for a table of frame offsets.
+    pub chunk_size: Option<u32>,
+    pub framing: Option<Framing>,
+    pub manifest: Option<Manifest>,
+    pub share_policy: Option<SharePolicy>,
+    pub escrow: Vec<EscrowSlot>,
+    pub device_slots: Vec<DeviceSlot>,
+    pub recipients: Vec<EscrowSlot>,
+    pub kdf: KdfParams,
+    pub kdf_salt: Vec<u8>,
+    // The password key also depends on a keyfile.
+    pub keyfile: bool,
+    pub wrapped_key: Option<WrappedKey>,
+    pub key_slots: Vec<KeySlot>,
+    pub convergent: Option<SealedContentKey>,
+    // The original name, size and mtime, sealed under the master key. Too
+    // long for one field, so it is split across consecutive ones.
+    pub metadata: Vec<u8>,
+    // A caller-supplied thumbnail, sealed and split the same way.
+    pub preview: Vec<u8>,
+    // Sorted and apart; split the same way, but in the clear and covered
+    // by the file id tag like every other field.
+    pub holes: Vec<Hole>,
+    // The app's own key-value entries, sealed and split the same way.
+    pub app_metadata: Vec<u8>,
+    // The content hash taken while encrypting, sealed under the master key,
+    // so files can be compared without decrypting their bodies.
+    pub content_digest: Vec<u8>,
+}
+
+impl ContainerHeader {
+    pub fn with_hint(hint: &[u8]) -> Self {
+        ContainerHeader {
+            hint: hint.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut fields = Vec::new();
+        if self.padding != PADDING_NONE {
+            push_field(&mut fields, FIELD_PADDING, &[self.padding]);
+        }
+        if self.backup {
+            push_field(&mut fields, FIELD_HEADER_BACKUP, &[]);
+        }
+        if let Some(parity) = &self.parity {
+            let value = [&[parity.percent][..], &parity.shard_size.to_le_bytes(), &parity.len.to_le_bytes()].concat();
+            push_field(&mut fields, FIELD_PARITY, &value);
+        }
+        if self.nonce_scheme != NONCE_SCHEME_RANDOM {
+            push_field(&mut fields, FIELD_NONCE_SCHEME, &[self.nonce_scheme]);
+        }
+        if self.key_purpose != KEY_PURPOSE_LEGACY {
+            push_field(&mut fields, FIELD_KEY_PURPOSE, &[self.key_purpose]);
+        }
+        if self.cipher != CIPHER_AES_256_GCM {
+            push_field(&mut fields, FIELD_CIPHER_SUITE, &[self.cipher]);
+        }
+        if self.kdf != KdfParams::LegacySha256 {
+            let mut value = encode_kdf_params(&self.kdf);
+            value.extend_from_slice(&self.kdf_salt);
+            push_field(&mut fields, FIELD_KDF, &value);
+        }
+        if self.keyfile {
+            push_field(&mut fields, FIELD_KEYFILE, &[]);
+        }
+        if let Some(wrapped) = &self.wrapped_key {
+            push_field(&mut fields, FIELD_WRAPPED_KEY, &[&wrapped.nonce[..], &wrapped.sealed].concat());
+        }
+        for slot in &self.key_slots {
+            push_field(&mut fields, FIELD_KEY_SLOT, &[&slot.salt[..], &slot.wrapped.nonce, &slot.wrapped.sealed].concat());
+        }
+        for slot in &self.recipients {
+            push_field(&mut fields, FIELD_RECIPIENT, &slot.encode());
+        }
+        if let Some(content_key) = &self.convergent {
+            push_field(&mut fields, FIELD_CONVERGENT, &[&content_key.nonce[..], &content_key.sealed].concat());
+        }
+        if let Some(policy) = &self.share_policy {
+            let mut value = vec![policy.threshold, policy.slots.len() as u8];
+            if policy.kdf != KdfParams::LegacySha256 {
+                value.extend_from_slice(&encode_kdf_params(&policy.kdf));
+            }
+            push_field(&mut fields, FIELD_SHARE_POLICY, &value);
+            for slot in &policy.slots {
+                push_field(&mut fields, FIELD_KEY_SHARE, &[&[slot.x][..], &slot.salt, &slot.nonce, &slot.sealed].concat());
+            }
+        }
+        for slot in &self.escrow {
+            push_field(&mut fields, FIELD_ESCROW, &slot.encode());
+        }
+        // Blobs outgrow one field, so the slots are joined as kind(1) ||
+        // length u16 LE || blob and split like the metadata.
+        let device_slots: Vec<u8> = self
+            .device_slots
+            .iter()
+            .flat_map(|slot| [&[slot.kind][..], &(slot.blob.len() as u16).to_le_bytes(), &slot.blob].concat())
+            .collect();
+        for part in device_slots.chunks(u8::MAX as usize) {
+            push_field(&mut fields, FIELD_DEVICE_SLOT, part);
+        }
+        // Hints past the fixed slot go whole into their own field, with the
+        // slot keeping a prefix for readers that predate it.
+        if let Some(private) = &self.private_hint {
+            let value = match private {
+                PrivateHint::Padded => {
+                    let hint = text::hint_prefix(&self.hint);
+                    let mut value = vec![PRIVATE_HINT_PADDED, hint.len() as u8];
+                    value.extend_from_slice(hint);
+                    value.resize(2 + MAX_HINT_LENGTH, 0);
+                    value
+                }
+                PrivateHint::Sealed(sealed) => [&[PRIVATE_HINT_SEALED][..], sealed].concat(),
+            };
+            push_field(&mut fields, FIELD_PRIVATE_HINT, &value);
+        } else if self.hint.len() > MAX_HINT_LENGTH {
+            let value = [&(self.hint.len() as u16).to_le_bytes()[..], &self.hint].concat();
+            for part in value.chunks(u8::MAX as usize) {
+                push_field(&mut fields, FIELD_HINT, part);
+            }
+        }
+        for part in self.metadata.chunks(u8::MAX as usize) {
+            push_field(&mut fields, FIELD_METADATA, part);
+        }
+        for part in self.preview.chunks(u8::MAX as usize) {
+            push_field(&mut fields, FIELD_PREVIEW, part);
+        }
+        for part in self.app_metadata.chunks(u8::MAX as usize) {
+            push_field(&mut fields, FIELD_APP_METADATA, part);
+        }
+        let holes: Vec<u8> = self.holes.iter().flat_map(|hole| [hole.offset.to_le_bytes(), hole.len.to_le_bytes()].concat()).collect();
+        for part in holes.chunks(u8::MAX as usize) {
+            push_field(&mut fields, FIELD_HOLES, part);
+        }
+        if let Some(framing) = &self.framing {
+            let mut value = vec![framing.version];
+            value.extend_from_slice(&framing.chunk_size.to_le_bytes());
+            value.extend_from_slice(&framing.chunk_count.to_le_bytes());
+            push_field(&mut fields, FIELD_FRAMING, &value);
+        } else if let Some(chunk_size) = self.chunk_size {
+            push_field(&mut fields, FIELD_CHUNK_INDEX, &chunk_size.to_le_bytes());
+        }
+        if let Some(manifest) = &self.manifest {
+            push_field(&mut fields, FIELD_MANIFEST, &manifest.encode());
+        }
+        if !self.content_digest.is_empty() {
+            push_field(&mut fields, FIELD_CONTENT_DIGEST, &self.content_digest);
+        }
+        for group in self.chunk_checksums.chunks(CHECKSUMS_PER_FIELD) {
+            let value: Vec<u8> = group.iter().flat_map(|c| c.to_le_bytes()).collect();
+            push_field(&mut fields, FIELD_CHUNK_CHECKSUMS, &value);
+        }
+        if let Some(id) = &self.file_id {
+            let slot_tag = id.slot_tag.as_ref().map_or(&[][..], |tag| &tag[..]);
+            push_field(&mut fields, FIELD_FILE_ID, &[&id.uuid[..], &id.tag, slot_tag].concat());
+        }
+        let version = if fields.is_empty() { VERSION } else { EXTENDED_VERSION };
+
+        let hint = if self.private_hint.is_some() { &[][..] } else { text::hint_prefix(&self.hint) };
+        let mut header = Vec::with_capacity(HEADER_SIZE + 1 + hint.len() + 2 + fields.len());
+        header.extend_from_slice(MAGIC_STRING);
+        header.extend_from_slice(&version.to_le_bytes());
+        header.push(hint.len() as u8);
+        header.extend_from_slice(hint);
+        if version == EXTENDED_VERSION {
+            header.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+            header.extend_from_slice(&fields);
+        }
+        header
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<(Self, u64), Box<dyn std::error::Error>> {
+        let mut fixed = [0u8; HEADER_SIZE + 1];
+        reader.read_exact(&mut fixed[..MAGIC_STRING.len()])?;
+        if &fixed[..MAGIC_STRING.len()] != MAGIC_STRING {
+            return Err("Invalid file format".into());
+        }
+        // Past the magic, running out of bytes means the file was cut short.
+        let reader = &mut Strict(reader);
+        reader.read_exact(&mut fixed[MAGIC_STRING.len()..])?;
+        let version = u32::from_le_bytes(fixed[MAGIC_STRING.len()..HEADER_SIZE].try_into()?);
+        if version != VERSION && version != EXTENDED_VERSION {
+            return Err("Unsupported version".into());
+        }
+
+        if fixed[HEADER_SIZE] as usize > MAX_HINT_LENGTH {
+            return Err("Invalid file format".into());
+        }
+        let mut hint = vec![0u8; fixed[HEADER_SIZE] as usize];
+        reader.read_exact(&mut hint)?;
+        let mut header_len = (fixed.len() + hint.len()) as u64;
+        let mut header = ContainerHeader::with_hint(&hint);
+
+        if version == EXTENDED_VERSION {
+            let mut fields_len = [0u8; 2];
+            reader.read_exact(&mut fields_len)?;
+            let mut fields = vec![0u8; u16::from_le_bytes(fields_len) as usize];
+            reader.read_exact(&mut fields)?;
+            header.apply_fields(&fields)?;
+            header_len += (fields_len.len() + fields.len()) as u64;
+        }
+        Ok((header, header_len))
+    }
+
+    // Falls back to the copy at the end of the file when the header at the
+    // start cannot be parsed, and leaves the reader at the start of the body
+    // either way.
+    pub fn read_recovering<R: Read + Seek>(reader: &mut R) -> Result<(Self, u64), Box<dyn std::error::Error>> {
+        let error = match ContainerHeader::read(reader) {
+            Ok(parsed) => return Ok(parsed),
+            Err(e) => e,
+        };
+        let Some(backup) = header_backup::read_trailer(reader)? else {
+            return Err(error);
+        };
+        match ContainerHeader::read(&mut backup.as_slice()) {
+            Ok((header, header_len)) if header.backup && header_len == backup.len() as u64 => {
+                reader.seek(SeekFrom::Start(header_len))?;
+                Ok((header, header_len))
+            }
+            _ => Err(error),
+        }
+    }
+
+    // Where the body stops: the end of the file, or the start of the
+    // trailer when the header is backed up there, less any parity.
+    pub fn body_end(&self, header_len: u64, file_size: u64) -> u64 {
+        self.trailer_start(header_len, file_size).saturating_sub(self.parity.map_or(0, |parity| parity.len))
+    }
+
+    // The end of the body and its parity, where any backup trailer begins.
+    pub fn trailer_start(&self, header_len: u64, file_size: u64) -> u64 {
+        if self.backup {
+            file_size.saturating_sub(header_len + header_backup::TRAILER_SUFFIX_SIZE)
+        } else {
+            file_size
+        }
+    }
+
+    fn apply_fields(&mut self, mut fields: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut share_count = 0;
+        let mut long_hint = Vec::new();
+        let mut device_slots = Vec::new();
+        let mut holes = Vec::new();
+        while !fields.is_empty() {
+            let (tag, len) = match fields {
+                [tag, len, ..] => (*tag, *len as usize),
+                _ => return Err("Invalid header field".into()),
+            };
+            let value = fields.get(2..2 + len).ok_or("Invalid header field")?;
+            match tag {
+                FIELD_PADDING => match value {
+                    [scheme] if padding::is_valid_scheme(*scheme) => self.padding = *scheme,
+                    _ => return Err("Invalid padding field".into()),
+                },
+                FIELD_HEADER_BACKUP if len == 0 => self.backup = true,
+                FIELD_PARITY if len == PARITY_FIELD_SIZE => {
+                    let (shard_size, parity_len) = value[1..].split_at(4);
+                    let parity = Parity {
+                        percent: value[0],
+                        shard_size: u32::from_le_bytes(shard_size.try_into()?),
+                        len: u64::from_le_bytes(parity_len.try_into()?),
+                    };
+                    if parity.percent == 0 || parity.shard_size == 0 {
+                        return Err("Invalid header field".into());
+                    }
+                    self.parity = Some(parity);
+                }
+                FIELD_KEYFILE if len == 0 => self.keyfile = true,
+                FIELD_NONCE_SCHEME => match value {
+                    [scheme] if *scheme <= NONCE_SCHEME_COUNTER => self.nonce_scheme = *scheme,
+                    _ => return Err("Unsupported nonce scheme".into()),
+                },
+                FIELD_KEY_PURPOSE => match value {
+                    [purpose] if *purpose <= KEY_PURPOSE_DEVICE => self.key_purpose = *purpose,
+                    _ => return Err("Unsupported key purpose".into()),
+                },
+                FIELD_CIPHER_SUITE => match value {
+                    [suite] if *suite <= CIPHER_CHACHA20_POLY1305 => self.cipher = *suite,
+                    _ => return Err("Unsupported cipher suite".into()),
+                },
+                FIELD_KDF if len == KDF_FIELD_SIZE => {
+                    let (params, salt) = value.split_at(KDF_PARAMS_SIZE);
+                    self.kdf = parse_kdf_params(params)?;
+                    self.kdf_salt = salt.to_vec();
+                }
+                FIELD_KDF => return Err("Unsupported KDF parameters".into()),
+                FIELD_WRAPPED_KEY if len == WRAPPED_KEY_SIZE => {
+                    let (nonce, sealed) = value.split_at(NONCE_SIZE);
+                    self.wrapped_key = Some(WrappedKey {
+                        nonce: nonce.try_into()?,
+                        sealed: sealed.try_into()?,
+                    });
+                }
+                FIELD_KEY_SLOT if len == KEY_SLOT_SIZE && self.key_slots.len() < MAX_KEY_SLOTS - 1 => {
+                    let (salt, rest) = value.split_at(KDF_SALT_SIZE);
+                    let (nonce, sealed) = rest.split_at(NONCE_SIZE);
+                    self.key_slots.push(KeySlot {
+                        salt: salt.try_into()?,
+                        wrapped: WrappedKey {
+                            nonce: nonce.try_into()?,
+                            sealed: sealed.try_into()?,
+                        },
+                    });
+                }
+                FIELD_CONVERGENT if len == SEALED_CONTENT_KEY_SIZE => {
+                    let (nonce, sealed) = value.split_at(NONCE_SIZE);
+                    self.convergent = Some(SealedContentKey {
+                        nonce: nonce.try_into()?,
+                        sealed: sealed.try_into()?,
+                    });
+                }
+                FIELD_SHARE_POLICY => match value {
+                    [threshold, count, params @ ..] if self.share_policy.is_none() && 0 < *threshold && threshold <= count => {
+                        let kdf = match params {
+                            [] => KdfParams::LegacySha256,
+                            _ if params.len() == KDF_PARAMS_SIZE => parse_kdf_params(params)?,
+                            _ => return Err("Invalid header field".into()),
+                        };
+                        share_count = *count as usize;
+                        self.share_policy = Some(SharePolicy {
+                            threshold: *threshold,
+                            slots: Vec::with_capacity(share_count),
+                            kdf,
+                        });
+                    }
+                    _ => return Err("Invalid header field".into()),
+                },
+                FIELD_KEY_SHARE if len == KEY_SHARE_SIZE => {
+                    let policy = self.share_policy.as_mut().ok_or("Invalid header field")?;
+                    let (salt, rest) = value[1..].split_at(SHARE_SALT_SIZE);
+                    let (nonce, sealed) = rest.split_at(NONCE_SIZE);
+                    policy.slots.push(KeyShareSlot {
+                        x: value[0],
+                        salt: salt.try_into()?,
+                        nonce: nonce.try_into()?,
+                        sealed: sealed.try_into()?,
+                    });
+                }
+                FIELD_ESCROW if len == ESCROW_SLOT_SIZE => self.escrow.push(EscrowSlot::parse(value)?),
+                FIELD_RECIPIENT if len == ESCROW_SLOT_SIZE => self.recipients.push(EscrowSlot::parse(value)?),
+                FIELD_METADATA => self.metadata.extend_from_slice(value),
+                FIELD_PREVIEW => self.preview.extend_from_slice(value),
+                FIELD_APP_METADATA => self.app_metadata.extend_from_slice(value),
+                FIELD_HINT => long_hint.extend_from_slice(value),
+                FIELD_PRIVATE_HINT => match value {
+                    [PRIVATE_HINT_PADDED, len, padded @ ..] if padded.len() == MAX_HINT_LENGTH && *len as usize <= MAX_HINT_LENGTH => {
+                        let hint = &padded[..*len as usize];
+                        std::str::from_utf8(hint).map_err(|_| "Invalid header field")?;
+                        self.hint = hint.to_vec();
+                        self.private_hint = Some(PrivateHint::Padded);
+                    }
+                    [PRIVATE_HINT_SEALED, sealed @ ..] if sealed.len() == SEALED_HINT_SIZE => {
+                        self.private_hint = Some(PrivateHint::Sealed(sealed.to_vec()));
+                    }
+                    _ => return Err("Invalid header field".into()),
+                },
+                FIELD_DEVICE_SLOT => device_slots.extend_from_slice(value),
+                FIELD_HOLES => holes.extend_from_slice(value),
+                FIELD_CHUNK_CHECKSUMS if len % CHECKSUM_SIZE == 0 => self.chunk_checksums.extend(
+                    value
+                        .chunks(CHECKSUM_SIZE)
+                        .map(|c| u32::from_le_bytes(c.try_into().expect("chunks are checksum sized"))),
+                ),
+                FIELD_CHUNK_INDEX if len == CHUNK_INDEX_SIZE => {
+                    let chunk_size = u32::from_le_bytes(value.try_into()?);
+                    if chunk_size as usize > MAX_CHUNK_SIZE {
+                        return Err("Invalid header field".into());
+                    }
+                    self.chunk_size = Some(chunk_size).filter(|&size| size > 0);
+                }
+                FIELD_FRAMING if len == FRAMING_SIZE => {
+                    let (chunk_size, chunk_count) = value[1..].split_at(4);
+                    let framing = Framing {
+                        version: value[0],
+                        chunk_size: u32::from_le_bytes(chunk_size.try_into()?),
+                        chunk_count: u64::from_le_bytes(chunk_count.try_into()?),
+                    };
+                    // Readers size their frame buffers from this, so it is
+                    // held to what the format writes.
+                    if framing.chunk_size as usize > MAX_CHUNK_SIZE {
+                        return Err("Invalid header field".into());
+                    }
+                    match framing.version {
+                        FRAMING_SINGLE => {}
+                        FRAMING_LENGTH_PREFIXED | FRAMING_STREAMED if framing.chunk_size > 0 => self.chunk_size = Some(framing.chunk_size),
+                        FRAMING_LENGTH_PREFIXED | FRAMING_STREAMED => return Err("Invalid header field".into()),
+                        FRAMING_CONVERGENT_CHUNKS if framing.chunk_size > 0 => {}
+                        FRAMING_CONVERGENT_CHUNKS => return Err("Invalid header field".into()),
+                        _ => return Err("Unsupported framing version".into()),
+                    }
+                    self.framing = Some(framing);
+                }
+                FIELD_MANIFEST if len == MANIFEST_SIZE => self.manifest = Some(Manifest::parse(value)?),
+                FIELD_CONTENT_DIGEST if len == SEALED_DIGEST_SIZE => self.content_digest = value.to_vec(),
+                FIELD_FILE_ID if len == 2 * FILE_ID_SIZE || len == 3 * FILE_ID_SIZE => {
+                    let (uuid, tags) = value.split_at(FILE_ID_SIZE);
+                    let (tag, slot_tag) = tags.split_at(FILE_ID_SIZE);
+                    self.file_id = Some(FileId {
+                        uuid: uuid.try_into()?,
+                        tag: tag.try_into()?,
+                        slot_tag: slot_tag.try_into().ok(),
+                    });
+                }
+                _ if tag & FIELD_CRITICAL != 0 => return Err("Unsupported header field".into()),
+                _ => {}
+            }
+            fields = &fields[2 + len..];
+        }
+        if let Some(policy) = &self.share_policy {
+            if policy.slots.len() != share_count {
+                return Err("Invalid header field".into());
+            }
+        }
+        if !long_hint.is_empty() {
+            self.hint = match long_hint.split_first_chunk() {
+                Some((len, hint)) if u16::from_le_bytes(*len) as usize == hint.len() && std::str::from_utf8(hint).is_ok() => hint.to_vec(),
+                _ => return Err("Invalid header field".into()),
+            };
+        }
+        let mut rest = device_slots.as_slice();
+        while let Some((&[kind, len_lo, len_hi], tail)) = rest.split_first_chunk() {
+            let len = u16::from_le_bytes([len_lo, len_hi]) as usize;
+            if len == 0 || len > MAX_DEVICE_BLOB_LEN || len > tail.len() {
+                return Err("Invalid header field".into());
+            }
+            self.device_slots.push(DeviceSlot { kind, blob: tail[..len].to_vec() });
+            rest = &tail[len..];
+        }
+        if !rest.is_empty() {
+            return Err("Invalid header field".into());
+        }
+        if holes.len() % HOLE_SIZE != 0 || holes.len() / HOLE_SIZE > MAX_HOLES {
+            return Err("Invalid header field".into());
+        }
+        let mut end = 0;
+        for value in holes.chunks(HOLE_SIZE) {
+            let (offset, len) = value.split_at(8);
+            let hole = Hole { offset: u64::from_le_bytes(offset.try_into()?), len: u64::from_le_bytes(len.try_into()?) };
+            match hole.offset.checked_add(hole.len) {
+                Some(hole_end) if hole.len > 0 && hole.offset >= end => end = hole_end,
+                _ => return Err("Invalid header field".into()),
+            }
+            self.holes.push(hole);
+        }
+        Ok(())
+    }
+
+    pub fn body_key(&self, master: &[u8; 32]) -> [u8; 32] {
+        kdf::body_key(master, self.key_purpose)
+    }
+
+    pub fn body_cipher(&self, master: &[u8; 32]) -> Result<BodyCipher, Box<dyn std::error::Error>> {
+        BodyCipher::new(self.cipher, &self.body_key(master))
+    }
+
+    pub fn set_framing(&mut self, single_chunk: bool, chunk_size: usize, chunk_count: usize) {
+        let version = if single_chunk { FRAMING_SINGLE } else { FRAMING_LENGTH_PREFIXED };
+        self.chunk_size = Some(chunk_size as u32).filter(|_| !single_chunk);
+        self.framing = Some(Framing { version, chunk_size: chunk_size as u32, chunk_count: chunk_count as u64 });
+    }
+
+    pub fn set_streamed(&mut self, chunk_size: usize) {
+        self.chunk_size = Some(chunk_size as u32);
+        self.framing = Some(Framing { version: FRAMING_STREAMED, chunk_size: chunk_size as u32, chunk_count: 0 });
+    }
+
+    pub fn has_convergent_chunks(&self) -> bool {
+        self.framing.is_some_and(|framing| framing.version == FRAMING_CONVERGENT_CHUNKS)
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        self.framing.is_some_and(|framing| framing.version == FRAMING_STREAMED)
+    }
+
+    pub fn master_key<K: KeyProvider + ?Sized>(&self, keys: &K) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        self.unlock_slot(keys).map(|(_, key)| key)
+    }
+
+    pub fn unlock_slot<K: KeyProvider + ?Sized>(&self, keys: &K) -> Result<(usize, [u8; 32]), Box<dyn std::error::Error>> {
+        unlock_slot(keys, &self.kdf, &self.kdf_salt, self.wrapped_key.as_ref(), &self.key_slots, self.keyfile)
+    }
+
+    pub fn slot_count(&self) -> usize {
+        match self.wrapped_key {
+            Some(_) => 1 + self.key_slots.len(),
+            None => 0,
+        }
+    }
+
+    // Picks the master key of a file about to be written: a random file key
+    // wrapped under the password key when the header uses a salted KDF.
+    pub fn new_master_key<K: KeyProvider + ?Sized>(&mut self, keys: &K) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        if !keys.derives_from_password() || self.kdf == KdfParams::LegacySha256 {
+            self.wrapped_key = None;
+            return keys.key_for(&self.kdf_salt, &self.kdf);
+        }
+        // The salt picked by use_password_kdf is kept, so that callers
+        // sharing one salt across files also share the password key.
+        let salt = self.kdf_salt.as_slice().try_into().unwrap_or_else(|_| rng::random());
+        let file_key: [u8; 32] = rng::random();
+        self.key_slots.clear();
+        self.wrap_slot(0, keys, &file_key, salt)?;
+        Ok(file_key)
+    }
+
+    // The password key itself, with no file key wrapped under it, for
+    // headers too small to carry one. Such files take no further slots.
+    pub fn new_derived_key<K: KeyProvider + ?Sized>(&mut self, keys: &K) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        self.wrapped_key = None;
+        self.key_slots.clear();
+        keys.key_for(&self.kdf_salt, &self.kdf)
+    }
+
+    // Wraps the file key under another password with a fresh salt, leaving
+    // the body and everything keyed by the file key untouched. The index
+    // one past the last slot adds a slot.
+    pub fn rewrap_slot<K: KeyProvider + ?Sized>(
+        &mut self,
+        index: usize,
+        keys: &K,
+        file_key: &[u8; 32],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.wrap_slot(index, keys, file_key, rng::random())
+    }
+
+    fn wrap_slot<K: KeyProvider + ?Sized>(
+        &mut self,
+        index: usize,
+        keys: &K,
+        file_key: &[u8; 32],
+        salt: [u8; KDF_SALT_SIZE],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if index > self.key_slots.len() + 1 || index >= MAX_KEY_SLOTS || (index > 0 && self.wrapped_key.is_none()) {
+            return Err("Invalid key slot".into());
+        }
+        let password_key = Zeroizing::new(keys.key_for(&salt, &self.kdf)?);
+        let wrapped = WrappedKey::seal(&password_key, file_key, &salt)?;
+        match index {
+            0 => {
+                self.kdf_salt = salt.to_vec();
+                self.wrapped_key = Some(wrapped);
+            }
+            _ if index == self.key_slots.len() + 1 => self.key_slots.push(KeySlot { salt, wrapped }),
+            _ => self.key_slots[index - 1] = KeySlot { salt, wrapped },
+        }
+        Ok(())
+    }
+
+    // Removing slot 0 moves the next slot into the header salt.
+    pub fn remove_slot(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        if index >= self.slot_count() || self.slot_count() == 1 {
+            return Err("Invalid key slot".into());
+        }
+        if index == 0 {
+            let next = self.key_slots.remove(0);
+            self.kdf_salt = next.salt.to_vec();
+            self.wrapped_key = Some(next.wrapped);
+        } else {
+            self.key_slots.remove(index - 1);
+        }
+        Ok(())
+    }
+
+    // Starts a fresh salt, so the same password yields a different master
+    // key for every file written with this header.
+    pub fn use_password_kdf(&mut self, is_mobile: bool) {
+        self.kdf = KdfParams::for_new_files(is_mobile);
+        self.kdf_salt = rng::random::<[u8; KDF_SALT_SIZE]>().to_vec();
+    }
+
+    // The file id is bound to the rest of the header and to the password key
+    // by a truncated HMAC, keeping an existing uuid so that re-encrypting a
+    // file does not change its identity. Convergent containers take their
+    // uuid from the content key so identical content stays byte-identical.
+    pub fn seal_file_id(&mut self, key: &[u8; 32]) {
+        self.seal_slot_file_ids([Some(key), None]);
+    }
+
+    // A two-slot container is sealed under the key of each slot that holds
+    // a payload, so either password can check the header without telling
+    // which slots are used. Other containers only take the first key.
+    pub fn seal_slot_file_ids(&mut self, keys: [Option<&[u8; 32]>; 2]) {
+        let uuid = match self.file_id {
+            Some(id) => id.uuid,
+            None => {
+                let mut uuid: [u8; FILE_ID_SIZE] = match self.convergent {
+                    Some(_) => match keys[0] {
+                        Some(key) => kdf::subkey(key, KeyPurpose::KeyId)[..FILE_ID_SIZE].try_into().expect("subkeys are 32 bytes"),
+                        None => rng::random(),
+                    },
+                    None => rng::random(),
+                };
+                uuid[6] = (uuid[6] & 0x0f) | 0x40;
+                uuid[8] = (uuid[8] & 0x3f) | 0x80;
+                uuid
+            }
+        };
+        let [tag, slot_tag] = keys.map(|key| match key {
+            Some(key) => self.file_id_mac(uuid, key).finalize().into_bytes()[..FILE_ID_SIZE]
+                .try_into()
+                .expect("HMAC output is longer than the tag"),
+            None => rng::random(),
+        });
+        self.file_id = Some(FileId { uuid, tag, slot_tag: self.dual().then_some(slot_tag) });
+    }
+
+    // The tag covers the magic, version, hint and every field. Every salted
+    // file is written with one, so a missing file id there means the field
+    // was stripped to get an unauthenticated header past this check.
+    pub fn verify_file_id(&self, key: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        match self.file_id {
+            Some(id) => std::iter::once(id.tag)
+                .chain(id.slot_tag)
+                .any(|tag| self.file_id_mac(id.uuid, key).verify_truncated_left(&tag).is_ok())
+                .then_some(())
+                .ok_or_else(|| "Header authentication failed".into()),
+            None if self.kdf != KdfParams::LegacySha256 => Err("Header authentication failed".into()),
+            None => Ok(()),
+        }
+    }
+
+    // The body holds two equal slots, each its own password's payload or
+    // random bytes. Only the padding scheme says so, and it is the same
+    // whether one slot is used or both.
+    pub fn dual(&self) -> bool {
+        self.padding == PADDING_SLOTS
+    }
+
+    fn file_id_mac(&self, uuid: [u8; FILE_ID_SIZE], key: &[u8; 32]) -> HmacSha256 {
+        let mut unsealed = self.clone();
+        unsealed.file_id = Some(FileId {
+            uuid,
+            tag: [0u8; FILE_ID_SIZE],
+            slot_tag: self.dual().then_some([0u8; FILE_ID_SIZE]),
+        });
+        let mac_key: [u8; 32] = if self.key_purpose == KEY_PURPOSE_LEGACY {
+            let mut hasher = Sha256::new();
+            hasher.update(FILE_ID_CONTEXT);
+            hasher.update(key);
+            hasher.finalize().into()
+        } else {
+            kdf::subkey(key, KeyPurpose::HeaderMac)
+        };
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&mac_key).expect("HMAC accepts any key length");
+        mac.update(&unsealed.encode());
+        mac
+    }
+}
+
+fn push_field(fields: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    fields.push(tag);
+    fields.push(value.len() as u8);
+    fields.extend_from_slice(value);
+}
+
+pub fn encode_kdf_params(kdf: &KdfParams) -> Vec<u8> {
+    match *kdf {
+        KdfParams::Argon2id { memory_kib, iterations, lanes } => {
+            let mut value = vec![KDF_ARGON2ID];
+            value.extend_from_slice(&memory_kib.to_le_bytes());
+            value.extend_from_slice(&iterations.to_le_bytes());
+            value.push(lanes as u8);
+            value
+        }
+        KdfParams::LegacySha256 => Vec::new(),
+    }
+}
+
+pub fn parse_kdf_params(value: &[u8]) -> Result<KdfParams, Box<dyn std::error::Error>> {
+    match value {
+        [KDF_ARGON2ID, rest @ ..] if value.len() == KDF_PARAMS_SIZE => {
+            let (memory_kib, rest) = rest.split_at(4);
+            let (iterations, rest) = rest.split_at(4);
+            let kdf = KdfParams::Argon2id {
+                memory_kib: u32::from_le_bytes(memory_kib.try_into()?),
+                iterations: u32::from_le_bytes(iterations.try_into()?),
+                lanes: rest[0] as u32,
+            };
+            kdf.check()?;
+            Ok(kdf)
+        }
+        _ => Err("Unsupported KDF parameters".into()),
+    }
+}
+
+pub fn read_header(path: &Path) -> Result<(ContainerHeader, u64), Box<dyn std::error::Error>> {
+    ContainerHeader::read_recovering(&mut BufReader::new(File::open(path)?))
+}
+
+// Turns an early end of input into a truncation error, for reads that
+// only start once the input is known to be a container.
+struct Strict<R>(R);
+
+impl<R: Read> Read for Strict<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.0.read_exact(buf).map_err(truncated)
+    }
+}
+
+fn truncated(error: io::Error) -> io::Error {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated file"),
+        _ => error,
+    }
+}
+
+// Walks a length-prefixed body front to back. It stops only on a frame
+// boundary, and only once the recorded frame count or a streamed body's
+// empty last frame is reached when the header has one: a body cut short is
+// a truncated file, and bytes left after the last frame are garbage, never
+// a quiet end of output.
+pub struct ContainerReader<R> {
+    body: Take<R>,
+    frames_left: Option<u64>,
+    streamed: bool,
+}
+
+impl<R: BufRead> ContainerReader<R> {
+    // `body` starts after the header and is bounded to the body's length
+    // when that is known, so no trailer is read as frames.
+    pub fn new(body: Take<R>, header: &ContainerHeader) -> Self {
+        let streamed = header.is_streamed();
+        let frames_left = header
+            .framing
+            .filter(|framing| framing.version == FRAMING_LENGTH_PREFIXED)
+            .map(|framing| framing.chunk_count);
+        ContainerReader { body, frames_left, streamed }
+    }
+
+    pub fn next_frame(&mut self, nonce_seq: &mut NonceSequence) -> io::Result<Option<Frame>> {
+        if self.frames_left == Some(0) {
+            return Ok(None);
+        }
+        // Checked before a counter nonce is used up on a frame that is not there.
+        if self.body.fill_buf()?.is_empty() {
+            if self.frames_left.is_some() || self.streamed {
+                return Err(truncated(io::ErrorKind::UnexpectedEof.into()));
+            }
+            return Ok(None);
+        }
+        let nonce = nonce_seq.read_nonce(&mut Strict(&mut self.body))?;
+        let mut len_bytes = [0u8; CHUNK_LEN_SIZE as usize];
+        Strict(&mut self.body).read_exact(&mut len_bytes)?;
+        // A frame of a possible size that runs past the body was cut off.
+        let len = u32::from_be_bytes(len_bytes) as u64;
+        if len <= MAX_FRAME_LEN && len > self.body.limit() {
+            return Err(truncated(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let frame = pipeline::read_frame(&mut self.body, len_bytes).map_err(truncated)?;
+        if self.streamed && frame.len() == TAG_SIZE {
+            self.frames_left = Some(0);
+        } else if let Some(left) = self.frames_left.as_mut() {
+            *left -= 1;
+        }
+        Ok(Some((nonce, frame)))
+    }
+
+    // Call once the last frame has been read.
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.body.fill_buf()?.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected data after end of file"));
+        }
+        Ok(())
+    }
+}
+
+pub struct ChunkFrame {
+    pub offset: u64,
+    pub len: u64,
+    pub index: u64,
+}
+
+pub struct ChunkLayout {
+    pub header: Vec<u8>,
+    pub padding: u8,
+    pub dual: bool,
+    pub single_chunk: bool,
+    pub frames: Vec<ChunkFrame>,
+    pub holes: Vec<Hole>,
+    pub nonce_scheme: u8,
+    pub key_purpose: u8,
+    pub cipher: u8,
+    kdf: KdfParams,
+    kdf_salt: Vec<u8>,
+    keyfile: bool,
+    wrapped_key: Option<WrappedKey>,
+    key_slots: Vec<KeySlot>,
+    file_id: Option<FileId>,
+    nonce_prefix: Option<[u8; NONCE_SIZE - 4]>,
+}
+
+impl ChunkLayout {
+    fn stored_nonce_len(&self) -> u64 {
+        stored_nonce_len(self.nonce_scheme)
+    }
+
+    pub fn frame_size(&self, frame: &ChunkFrame) -> u64 {
+        if self.single_chunk {
+            self.stored_nonce_len() + frame.len
+        } else {
+            self.stored_nonce_len() + CHUNK_LEN_SIZE + frame.len
+        }
+    }
+
+    pub fn read_frame<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        frame: &ChunkFrame,
+    ) -> Result<([u8; NONCE_SIZE], Vec<u8>), Box<dyn std::error::Error>> {
+        reader.seek(SeekFrom::Start(frame.offset))?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        if self.nonce_scheme == NONCE_SCHEME_COUNTER {
+            nonce = nonce::counter_nonce(self.nonce_prefix.as_ref().ok_or("Invalid file format")?, frame.index)?;
+        } else {
+            reader.read_exact(&mut nonce)?;
+        }
+        if !self.single_chunk {
+            reader.seek(SeekFrom::Current(CHUNK_LEN_SIZE as i64))?;
+        }
+        let mut ciphertext = vec![0u8; frame.len as usize];
+        reader.read_exact(&mut ciphertext)?;
+        Ok((nonce, ciphertext))
+    }
+
+    pub fn body_key(&self, master: &[u8; 32]) -> [u8; 32] {
+        kdf::body_key(master, self.key_purpose)
+    }
+
+    pub fn body_cipher(&self, master: &[u8; 32]) -> Result<BodyCipher, Box<dyn std::error::Error>> {
+        BodyCipher::new(self.cipher, &self.body_key(master))
+    }
+
+    pub fn master_key<K: KeyProvider + ?Sized>(&self, keys: &K) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        unlock_slot(keys, &self.kdf, &self.kdf_salt, self.wrapped_key.as_ref(), &self.key_slots, self.keyfile)
+            .map(|(_, key)| key)
+    }
+
+    // Picks the slot of a dual container that the key opens and derives the
+    // nonce prefix of counter-nonce files; frames cannot be read before this.
+    pub fn unlock<R: Read + Seek>(mut self, reader: &mut R, master: &[u8; 32]) -> Result<Self, Box<dyn std::error::Error>> {
+        let key = self.body_key(master);
+        if self.nonce_scheme == NONCE_SCHEME_COUNTER {
+            let file_id = self.file_id.as_ref().ok_or("Invalid file format")?;
+            self.nonce_prefix = Some(nonce::counter_prefix(&key, file_id));
+        }
+        if !self.dual {
+            self.verify_header(reader, master)?;
+            return Ok(self);
+        }
+        let cipher = BodyCipher::new(self.cipher, &key)?;
+        let second = self.frames.split_off(self.frames.len() / 2);
+        let (nonce, ciphertext) = self.read_frame(reader, &second[0])?;
+        if cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref()).is_ok() {
+            self.frames = second;
+        }
+        self.dual = false;
+        self.verify_header(reader, master)?;
+        Ok(self)
+    }
+
+    // Runs before any frame is handed out. A bad tag under a key that also
+    // fails on the first frame is a wrong password, not a tampered header.
+    fn verify_header<R: Read + Seek>(&self, reader: &mut R, master: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        let (header, _) = ContainerHeader::read(&mut self.header.as_slice())?;
+        let Err(error) = header.verify_file_id(master) else {
+            return Ok(());
+        };
+        let (nonce, ciphertext) = self.read_frame(reader, &self.frames[0])?;
+        self.body_cipher(master)?
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| "Decryption failed")?;
+        Err(error)
+    }
+}
+
+pub fn read_layout(path: &Path) -> Result<ChunkLayout, Box<dyn std::error::Error>> {
+    let len = std::fs::metadata(path)?.len();
+    checked_layout(scan_layout(&mut BufReader::new(File::open(path)?), len)?)
+}
+
+// The same for a container already held in memory.
+pub fn read_layout_from_bytes(bytes: &[u8]) -> Result<ChunkLayout, Box<dyn std::error::Error>> {
+    checked_layout(scan_layout(&mut Cursor::new(bytes), bytes.len() as u64)?)
+}
+
+fn checked_layout(layout: ChunkLayout) -> Result<ChunkLayout, Box<dyn std::error::Error>> {
+    if strict::enabled() {
+        strict::check_layout(&layout)?;
+    }
+    Ok(layout)
+}
+
+fn scan_layout<R: Read + Seek>(reader: &mut R, len: u64) -> Result<ChunkLayout, Box<dyn std::error::Error>> {
+    let (info, data_start) = ContainerHeader::read_recovering(reader)?;
+    if info.has_convergent_chunks() {
+        return Err("Unsupported framing version".into());
+    }
+    let file_size = info.body_end(data_start, len);
+    let mut header = vec![0u8; data_start as usize];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut header)?;
+    if ContainerHeader::read(&mut header.as_slice()).is_err() {
+        header = header_backup::read_trailer(reader)?.ok_or("Invalid file format")?;
+    }
+
+    let nonce_len = stored_nonce_len(info.nonce_scheme);
+    if file_size < data_start + nonce_len + TAG_SIZE as u64 {
+        return Err("Invalid file format".into());
+    }
+    let layout = |dual, single_chunk, frames| ChunkLayout {
+        header,
+        padding: info.padding,
+        dual,
+        single_chunk,
+        frames,
+        holes: info.holes.clone(),
+        nonce_scheme: info.nonce_scheme,
+        key_purpose: info.key_purpose,
+        cipher: info.cipher,
+        kdf: info.kdf.clone(),
+        kdf_salt: info.kdf_salt.clone(),
+        keyfile: info.keyfile,
+        wrapped_key: info.wrapped_key.clone(),
+        key_slots: info.key_slots.clone(),
+        file_id: info.file_id,
+        nonce_prefix: None,
+    };
+
+    if info.dual() {
+        let slot_len = (file_size - data_start) / 2;
+        let middle = data_start + slot_len;
+        let first = scan_frames(reader, data_start, middle, nonce_len)?;
+        let second = scan_frames(reader, middle, file_size, nonce_len)?;
+        return match (first, second) {
+            (Some(mut frames), Some(second)) if middle + slot_len == file_size && frames.len() == second.len() => {
+                frames.extend(second);
+                Ok(layout(true, false, frames))
+            }
+            _ => Err("Invalid file format".into()),
+        };
+    }
+
+    let frames = match (info.framing, info.chunk_size) {
+        (Some(framing), _) if framing.version == FRAMING_SINGLE => None,
+        (Some(framing), _) if framing.version == FRAMING_STREAMED => match scan_frames(reader, data_start, file_size, nonce_len)? {
+            Some(frames) if frames.last().is_some_and(|last| last.len == TAG_SIZE as u64) => return Ok(layout(false, false, frames)),
+            _ => return Err("Unexpected end of file".into()),
+        },
+        (Some(framing), _) => match indexed_frames(reader, data_start, file_size, nonce_len, framing.chunk_size as u64)? {
+            Some(frames) if frames.len() as u64 == framing.chunk_count => Some(frames),
+            _ => return Err("Invalid file format".into()),
+        },
+        (None, Some(chunk_size)) => match indexed_frames(reader, data_start, file_size, nonce_len, chunk_size as u64)? {
+            Some(frames) => Some(frames),
+            None => scan_frames(reader, data_start, file_size, nonce_len)?,
+        },
+        (None, None) => scan_frames(reader, data_start, file_size, nonce_len)?,
+    };
+    match frames {
+        Some(frames) if frames.len() > 1 => Ok(layout(false, false, frames)),
+        _ => Ok(layout(
+            false,
+            true,
+            vec![ChunkFrame {
+                offset: data_start,
+                len: file_size - data_start - nonce_len,
+                index: 0,
+            }],
+        )),
+    }
+}
+
+fn stored_nonce_len(scheme: u8) -> u64 {
+    if scheme == NONCE_SCHEME_COUNTER {
+        0
+    } else {
+        NONCE_SIZE as u64
+    }
+}
+
+// Places every frame from the chunk index without walking the body. Only the
+// last frame's length prefix is read, to confirm the index fits the file; a
+// body that does not match is scanned instead.
+fn indexed_frames<R: Read + Seek>(
+    reader: &mut R,
+    data_start: u64,
+    file_size: u64,
+    nonce_len: u64,
+    chunk_size: u64,
+) -> Result<Option<Vec<ChunkFrame>>, Box<dyn std::error::Error>> {
+    let full_len = chunk_size + TAG_SIZE as u64;
+    let stride = nonce_len + CHUNK_LEN_SIZE + full_len;
+    let body = file_size - data_start;
+    let (full, rest) = (body / stride, body % stride);
+    if rest != 0 && rest < nonce_len + CHUNK_LEN_SIZE + TAG_SIZE as u64 {
+        return Ok(None);
+    }
+
+    let mut frames: Vec<ChunkFrame> = (0..full)
+        .map(|index| ChunkFrame { offset: data_start + index * stride, len: full_len, index })
+        .collect();
+    if rest != 0 {
+        frames.push(ChunkFrame { offset: data_start + full * stride, len: rest - nonce_len - CHUNK_LEN_SIZE, index: full });
+    }
+    let Some(last) = frames.last() else {
+        return Ok(None);
+    };
+    reader.seek(SeekFrom::Start(last.offset + nonce_len))?;
+    let mut len_bytes = [0u8; CHUNK_LEN_SIZE as usize];
+    reader.read_exact(&mut len_bytes)?;
+    if u32::from_be_bytes(len_bytes) as u64 != last.len {
+        return Ok(None);
+    }
+    Ok(Some(frames))
+}
+
+fn scan_frames<R: Read + Seek>(
+    reader: &mut R,
+    data_start: u64,
+    file_size: u64,
+    nonce_len: u64,
+) -> Result<Option<Vec<ChunkFrame>>, Box<dyn std::error::Error>> {
+    let mut frames = Vec::new();
+    let mut offset = data_start;
+
+    while offset < file_size {
+        let len_offset = offset + nonce_len;
+        if len_offset + CHUNK_LEN_SIZE > file_size {
+            return Ok(None);
+        }
+        reader.seek(SeekFrom::Start(len_offset))?;
+        let mut len_bytes = [0u8; CHUNK_LEN_SIZE as usize];
+        reader.read_exact(&mut len_bytes)?;
+        let len = u32::from_be_bytes(len_bytes) as u64;
+
+        let next = len_offset + CHUNK_LEN_SIZE + len;
+        if len < TAG_SIZE as u64 || next > file_size {
+            return Ok(None);
+        }
+        frames.push(ChunkFrame {
+            offset,
+            len,
+            index: frames.len() as u64,
+        });
+        offset = next;
+    }
+
+    Ok(Some(frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{write_framed_container, write_framed_container_with};
+
+    #[test]
+    fn test_read_layout_framed_and_single() {
+        let dir = tempfile::tempdir().unwrap();
+        let framed = dir.path().join("framed.kyl");
+        write_framed_container(&framed, b"pw", &[b"first", b"second", b"third"]);
+
+        let layout = read_layout(&framed).unwrap();
+        assert!(!layout.single_chunk);
+        assert_eq!(layout.frames.len(), 3);
+        assert_eq!(layout.frames[1].len, 6 + TAG_SIZE as u64);
+
+        let single = dir.path().join("single.kyl");
+        write_framed_container(&single, b"pw", &[b"only chunk"]);
+        let layout = read_layout(&single).unwrap();
+        assert!(layout.single_chunk);
+        assert_eq!(layout.frames.len(), 1);
+        assert_eq!(layout.frames[0].len, 10 + TAG_SIZE as u64);
+    }
+
+    #[test]
+    fn test_damaged_header_falls_back_to_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backed.kyl");
+        let header = ContainerHeader { backup: true, ..ContainerHeader::with_hint(b"hint") };
+        write_framed_container_with(&path, b"pw", &header, &[b"only chunk"]);
+        assert!(crate::lint::lint_file_internal(&path).unwrap().errors.is_empty());
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[..MAGIC_STRING.len()].fill(0);
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(read_header(&path).unwrap().0.hint, b"hint");
+        let layout = read_layout(&path).unwrap();
+        assert_eq!(layout.header, header.encode());
+        assert_eq!(layout.frames[0].len, 10 + TAG_SIZE as u64);
+        let decrypted = dir.path().join("backed.out");
+        crate::decrypt_file_internal(path.to_str().unwrap(), decrypted.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(std::fs::read(&decrypted).unwrap(), b"only chunk");
+
+        // Without the marker the trailer is not trusted.
+        let plain = dir.path().join("plain.kyl");
+        write_framed_container_with(&plain, b"pw", &ContainerHeader::with_hint(b"hint"), &[b"first", b"second"]);
+        let mut bytes = std::fs::read(&plain).unwrap();
+        header_backup::write_trailer(&mut bytes, &ContainerHeader::with_hint(b"hint").encode()).unwrap();
+        bytes[0] = 0;
+        std::fs::write(&plain, &bytes).unwrap();
+        assert!(read_header(&plain).is_err());
+    }
+
+    #[test]
+    fn test_chunk_index_places_frames_without_scanning() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunks: [&[u8]; 3] = [b"0123", b"4567", b"89"];
+        let scanned = dir.path().join("scanned.kyl");
+        let indexed = dir.path().join("indexed.kyl");
+        write_framed_container(&scanned, b"pw", &chunks);
+        let header = ContainerHeader { chunk_size: Some(4), ..Default::default() };
+        write_framed_container_with(&indexed, b"pw", &header, &chunks);
+        assert_eq!(read_header(&indexed).unwrap().0.chunk_size, Some(4));
+
+        let offsets = |path| read_layout(path).unwrap().frames.iter().map(|f| (f.offset, f.len)).collect::<Vec<_>>();
+        let header_growth = 2 + 2 + CHUNK_INDEX_SIZE as u64;
+        let shifted: Vec<_> = offsets(&scanned).iter().map(|(o, l)| (o + header_growth, *l)).collect();
+        assert_eq!(offsets(&indexed), shifted);
+
+        // An index that does not fit the body falls back to scanning.
+        let wrong = ContainerHeader { chunk_size: Some(5), ..Default::default() };
+        write_framed_container_with(&indexed, b"pw", &wrong, &chunks);
+        assert_eq!(offsets(&indexed), shifted);
+    }
+
+    #[test]
+    fn test_framing_overrides_the_decryptor_chunk_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("framed.kyl");
+        let chunks: [&[u8]; 3] = [b"0123", b"4567", b"89"];
+        let mut header = ContainerHeader::default();
+        header.set_framing(false, 4, 3);
+        write_framed_container_with(&path, b"pw", &header, &chunks);
+        assert_eq!(read_header(&path).unwrap().0.framing, header.framing);
+        assert_eq!(read_layout(&path).unwrap().frames.len(), 3);
+
+        // The body is far smaller than either chunk size, which used to make
+        // it look like a single chunk.
+        let path_str = path.to_str().unwrap();
+        assert_eq!(crate::decrypt_file_to_memory_internal(path_str, b"pw", true, 4).unwrap(), b"0123456789");
+
+        header.set_framing(false, 4, 2);
+        write_framed_container_with(&path, b"pw", &header, &chunks);
+        assert!(read_layout(&path).is_err());
+    }
+
+    #[test]
+    fn test_tampered_or_stripped_header_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let path = dir.path().join("plain.kyl");
+        std::fs::write(&plain, b"authenticated").unwrap();
+        crate::encrypt_file_internal(plain.to_str().unwrap(), path.to_str().unwrap(), b"pw", Some("h"), false, 4).unwrap();
+        let original = std::fs::read(&path).unwrap();
+        let (header, header_len) = ContainerHeader::read(&mut original.as_slice()).unwrap();
+
+        let rewrite = |header: &ContainerHeader| {
+            let mut data = original.clone();
+            data.splice(..header_len as usize, header.encode());
+            std::fs::write(&path, data).unwrap();
+        };
+        rewrite(&ContainerHeader { hint: b"x".to_vec(), ..header.clone() });
+        let err = crate::range::decrypt_range_internal(&path, b"pw", 0, 4).unwrap_err();
+        assert_eq!(crate::errors::classify(err.as_ref()), crate::errors::ERR_HEADER_TAMPERED);
+        let err = crate::range::decrypt_range_internal(&path, b"wrong", 0, 4).unwrap_err();
+        assert_eq!(crate::errors::classify(err.as_ref()), crate::errors::ERR_WRONG_PASSWORD);
+
+        let key = header.master_key(&b"pw"[..]).unwrap();
+        assert!(header.verify_file_id(&key).is_ok());
+        assert!(ContainerHeader { file_id: None, ..header }.verify_file_id(&key).is_err());
+    }
+
+    #[test]
+    fn test_oversized_chunk_size_is_rejected() {
+        let mut header = ContainerHeader::default();
+        header.set_framing(false, MAX_CHUNK_SIZE, 2);
+        assert!(ContainerHeader::read(&mut header.encode().as_slice()).is_ok());
+        header.set_framing(false, MAX_CHUNK_SIZE + 1, 2);
+        assert!(ContainerHeader::read(&mut header.encode().as_slice()).is_err());
+
+        let indexed = ContainerHeader { chunk_size: Some(u32::MAX), ..Default::default() };
+        assert!(ContainerHeader::read(&mut indexed.encode().as_slice()).is_err());
+    }
+}
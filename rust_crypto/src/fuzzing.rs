@@ -0,0 +1,105 @@
+use std::io::{Cursor, Read};
+
+use crate::format::{self, ContainerHeader, ContainerReader};
+use crate::nonce::NonceSequence;
+
+// Entry points for the cargo-fuzz targets under fuzz/. Each takes any bytes
+// at all and has to come back with an error for whatever is malformed,
+// never a panic, a hang or an allocation larger than the input could fill.
+
+pub fn parse_header(data: &[u8]) {
+    if let Ok((_, header_len)) = ContainerHeader::read(&mut &data[..]) {
+        assert!(header_len <= data.len() as u64);
+    }
+    let _ = ContainerHeader::read_recovering(&mut Cursor::new(data));
+}
+
+pub fn read_frames(data: &[u8]) {
+    if let Ok(read) = walk_frames(data) {
+        assert!(read <= data.len());
+    }
+}
+
+pub fn read_layout(data: &[u8]) {
+    if let Ok(layout) = format::read_layout_from_bytes(data) {
+        assert!(layout.frames.iter().all(|frame| frame.offset + frame.len <= data.len() as u64));
+    }
+}
+
+// Reads the body the way the decryptors do and returns how many frame
+// bytes it held.
+fn walk_frames(data: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut reader = Cursor::new(data);
+    let (header, data_start) = ContainerHeader::read_recovering(&mut reader)?;
+    let body_len = header
+        .body_end(data_start, data.len() as u64)
+        .checked_sub(data_start)
+        .ok_or("Invalid file format")?;
+    // Only how many bytes each nonce takes matters here, not its value.
+    let mut nonce_seq = NonceSequence::for_header(&header, &[0; 32])?;
+    let mut body = ContainerReader::new(reader.take(body_len), &header);
+    let mut read = 0;
+    while let Some((_, frame)) = body.next_frame(&mut nonce_seq)? {
+        read += frame.len();
+    }
+    body.finish()?;
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::{self, ERR_GARBAGE_AT_END, ERR_TRUNCATED};
+    use crate::pipe::encrypt_stream_internal;
+    use crate::progress::ProgressSink;
+    use crate::test_util::write_framed_container_with;
+    use crate::{encrypt_file_internal, TAG_SIZE};
+    use std::fs;
+
+    #[test]
+    fn test_damaged_containers_fail_cleanly() {
+        let dir = tempfile::tempdir().unwrap();
+        let (plain, sealed, framed) = (dir.path().join("plain.txt"), dir.path().join("sealed.kyl"), dir.path().join("framed.kyl"));
+        fs::write(&plain, b"fuzzing seed").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), sealed.to_str().unwrap(), b"pw", Some("hint"), false, 4).unwrap();
+        let mut header = ContainerHeader::default();
+        header.set_framing(false, 6, 3);
+        write_framed_container_with(&framed, b"pw", &header, &[b"first", b"second", b"third"]);
+        let mut streamed = Vec::new();
+        encrypt_stream_internal(&b"streamed seed"[..], &mut streamed, b"pw", None, 0, false, 4, &ProgressSink::none()).unwrap();
+        let framed = fs::read(&framed).unwrap();
+
+        assert_eq!(walk_frames(&framed).unwrap(), 16 + 3 * TAG_SIZE);
+        assert_eq!(walk_frames(&streamed).unwrap(), 13 + 2 * TAG_SIZE);
+        for container in [&framed, &streamed] {
+            // Every cut inside the body is reported as one; the header has
+            // its own checks.
+            let data_start = ContainerHeader::read(&mut container.as_slice()).unwrap().1 as usize;
+            for cut in data_start..container.len() {
+                let error = walk_frames(&container[..cut]).unwrap_err();
+                assert_eq!(errors::classify(error.as_ref()), ERR_TRUNCATED, "cut at {}", cut);
+            }
+            let error = walk_frames(&[&container[..], &[0; 20]].concat()).unwrap_err();
+            assert_eq!(errors::classify(error.as_ref()), ERR_GARBAGE_AT_END);
+        }
+        let error = ContainerHeader::read(&mut &framed[..12]).unwrap_err();
+        assert_eq!(errors::classify(error.as_ref()), ERR_TRUNCATED);
+
+        // The same sweep the fuzz targets make, small enough to run here.
+        for container in [fs::read(&sealed).unwrap(), framed, streamed] {
+            for i in 0..container.len() {
+                for data in [&container[..i], &flipped(&container, i)] {
+                    parse_header(data);
+                    read_frames(data);
+                    read_layout(data);
+                }
+            }
+        }
+    }
+
+    fn flipped(data: &[u8], at: usize) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data[at] ^= 0xff;
+        data
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::SystemTime;
+
+type Identity = (u64, Option<SystemTime>);
+
+struct Slot {
+    path: PathBuf,
+    writable: bool,
+    position: u64,
+    // Inputs are checked on reopen so a file replaced while released is
+    // not silently read from the old offset.
+    identity: Option<Identity>,
+    file: Option<File>,
+}
+
+impl Slot {
+    fn file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let mut file = OpenOptions::new().read(true).write(self.writable).open(&self.path)?;
+            if self.identity.is_some() && Some(identity(&file.metadata()?)) != self.identity {
+                return Err(io::Error::other("Input file changed while paused"));
+            }
+            file.seek(SeekFrom::Start(self.position))?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().expect("file was just opened"))
+    }
+}
+
+fn identity(metadata: &Metadata) -> Identity {
+    (metadata.len(), metadata.modified().ok())
+}
+
+// The files an operation holds open, so a paused operation can close them
+// and let each one reopen at the same position on its next use.
+#[derive(Default)]
+pub struct HandleSet {
+    slots: Mutex<Vec<Weak<Mutex<Slot>>>>,
+}
+
+impl HandleSet {
+    fn lock(&self) -> MutexGuard<'_, Vec<Weak<Mutex<Slot>>>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn release_all(&self) {
+        let mut slots = self.lock();
+        slots.retain(|slot| slot.strong_count() > 0);
+        for slot in slots.iter().filter_map(Weak::upgrade) {
+            slot.lock().unwrap_or_else(|e| e.into_inner()).file = None;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn open_count(&self) -> usize {
+        self.lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|slot| slot.lock().unwrap_or_else(|e| e.into_inner()).file.is_some())
+            .count()
+    }
+}
+
+pub struct ReleasableFile {
+    slot: Arc<Mutex<Slot>>,
+}
+
+impl ReleasableFile {
+    pub fn open(path: &Path, handles: Option<&HandleSet>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let identity = Some(identity(&file.metadata()?));
+        Ok(Self::track(path, file, false, identity, handles))
+    }
+
+    pub fn create(path: &Path, handles: Option<&HandleSet>) -> io::Result<Self> {
+        Ok(Self::track(path, File::create(path)?, true, None, handles))
+    }
+
+    fn track(path: &Path, file: File, writable: bool, identity: Option<Identity>, handles: Option<&HandleSet>) -> Self {
+        let slot = Arc::new(Mutex::new(Slot {
+            path: path.to_path_buf(),
+            writable,
+            position: 0,
+            identity,
+            file: Some(file),
+        }));
+        if let Some(handles) = handles {
+            handles.lock().push(Arc::downgrade(&slot));
+        }
+        ReleasableFile { slot }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Slot> {
+        self.slot.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        self.lock().file()?.metadata()
+    }
+}
+
+impl Read for ReleasableFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut slot = self.lock();
+        let n = slot.file()?.read(buf)?;
+        slot.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for ReleasableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut slot = self.lock();
+        let n = slot.file()?.write(buf)?;
+        slot.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.lock().file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Seek for ReleasableFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut slot = self.lock();
+        slot.position = slot.file()?.seek(pos)?;
+        Ok(slot.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_released_files_reopen_at_their_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("input.bin");
+        let output_path = dir.path().join("output.bin");
+        fs::write(&input_path, b"0123456789").unwrap();
+
+        let handles = HandleSet::default();
+        let mut input = ReleasableFile::open(&input_path, Some(&handles)).unwrap();
+        let mut output = ReleasableFile::create(&output_path, Some(&handles)).unwrap();
+        let mut buf = [0u8; 4];
+        input.read_exact(&mut buf).unwrap();
+        output.write_all(&buf).unwrap();
+
+        handles.release_all();
+        assert_eq!(handles.open_count(), 0);
+        let mut rest = Vec::new();
+        input.read_to_end(&mut rest).unwrap();
+        output.write_all(&rest).unwrap();
+        assert_eq!(handles.open_count(), 2);
+        drop(output);
+        assert_eq!(fs::read(&output_path).unwrap(), b"0123456789");
+
+        handles.release_all();
+        fs::write(&input_path, b"replaced while paused").unwrap();
+        assert!(input.read(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::format::MAX_HEADER_LEN;
+
+// The trailer is a copy of the header followed by its length and this
+// magic, so it can be found from the end of the file alone.
+pub const TRAILER_MAGIC: &[u8] = b"KYRIE_LOCK_BACKUP";
+const TRAILER_LEN_SIZE: u64 = 4;
+pub const TRAILER_SUFFIX_SIZE: u64 = TRAILER_LEN_SIZE + TRAILER_MAGIC.len() as u64;
+
+static HEADER_BACKUP: AtomicBool = AtomicBool::new(false);
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_header_backup(enabled: bool) {
+    HEADER_BACKUP.store(enabled, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    HEADER_BACKUP.load(Ordering::SeqCst)
+}
+
+pub fn write_trailer<W: Write>(writer: &mut W, header_bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(header_bytes)?;
+    writer.write_all(&(header_bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(TRAILER_MAGIC)
+}
+
+// Returns the header copy, or None when the file ends in no trailer.
+pub fn read_trailer<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < TRAILER_SUFFIX_SIZE {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(file_len - TRAILER_SUFFIX_SIZE))?;
+    let mut suffix = [0u8; TRAILER_SUFFIX_SIZE as usize];
+    reader.read_exact(&mut suffix)?;
+    let (len_bytes, magic) = suffix.split_at(TRAILER_LEN_SIZE as usize);
+    let header_len = u32::from_le_bytes(len_bytes.try_into().expect("length field is four bytes")) as u64;
+    if magic != TRAILER_MAGIC || header_len > MAX_HEADER_LEN as u64 || header_len + TRAILER_SUFFIX_SIZE > file_len {
+        return Ok(None);
+    }
+
+    reader.seek(SeekFrom::Start(file_len - TRAILER_SUFFIX_SIZE - header_len))?;
+    let mut header_bytes = vec![0u8; header_len as usize];
+    reader.read_exact(&mut header_bytes)?;
+    Ok(Some(header_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_trailer_is_found_from_the_end() {
+        let mut file = b"header and body".to_vec();
+        write_trailer(&mut file, b"header").unwrap();
+        assert_eq!(read_trailer(&mut Cursor::new(&file)).unwrap().unwrap(), b"header");
+
+        assert!(read_trailer(&mut Cursor::new(b"header and body")).unwrap().is_none());
+        let last = file.len() - 1;
+        file[last] ^= 0xFF;
+        assert!(read_trailer(&mut Cursor::new(&file)).unwrap().is_none());
+    }
+}
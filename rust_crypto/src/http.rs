@@ -0,0 +1,334 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::format::{ContainerHeader, FRAMING_SINGLE};
+use crate::nonce::NonceSequence;
+use crate::padding::PaddingStripper;
+use crate::pipeline;
+use crate::sparse;
+use crate::{get_chunk_size, TAG_SIZE};
+
+const MAX_RESUME_ATTEMPTS: u32 = 5;
+
+type RangeResponse = (Box<dyn Read + Send>, u64);
+
+pub trait RangeSource {
+    fn open_at(&mut self, offset: u64) -> Result<RangeResponse, Box<dyn std::error::Error>>;
+}
+
+// Fetches with ureq's blocking client, so ranges are read on the calling
+// thread like any other input.
+pub struct HttpSource {
+    url: String,
+}
+
+impl RangeSource for HttpSource {
+    fn open_at(&mut self, offset: u64) -> Result<RangeResponse, Box<dyn std::error::Error>> {
+        let mut request = ureq::get(&self.url);
+        if offset > 0 {
+            request = request.set("Range", &format!("bytes={}-", offset));
+        }
+        let response = request.call()?;
+        let total_len = if offset > 0 {
+            if response.status() != 206 {
+                return Err("Server does not support range requests".into());
+            }
+            response.header("Content-Range").and_then(content_range_total)
+        } else {
+            response.header("Content-Length").and_then(|len| len.parse().ok())
+        };
+        let total_len = total_len.ok_or("Missing content length")?;
+        Ok((Box::new(response.into_reader()), total_len))
+    }
+}
+
+struct ResumingReader<'a, S: RangeSource> {
+    source: &'a mut S,
+    reader: Box<dyn Read + Send>,
+    position: u64,
+    total_len: u64,
+}
+
+impl<'a, S: RangeSource> ResumingReader<'a, S> {
+    fn open(source: &'a mut S) -> Result<Self, Box<dyn std::error::Error>> {
+        let (reader, total_len) = source.open_at(0)?;
+        Ok(ResumingReader {
+            source,
+            reader,
+            position: 0,
+            total_len,
+        })
+    }
+
+    fn reopen(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (reader, total_len) = self.source.open_at(self.position)?;
+        if total_len != self.total_len {
+            return Err("Remote file changed during download".into());
+        }
+        self.reader = reader;
+        Ok(())
+    }
+}
+
+impl<S: RangeSource> Read for ResumingReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut attempts = 0;
+        loop {
+            let error = match self.reader.read(buf) {
+                Ok(0) if !buf.is_empty() && self.position < self.total_len => {
+                    io::Error::from(io::ErrorKind::UnexpectedEof)
+                }
+                Ok(n) => {
+                    self.position += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+            // Pick the stream back up where it broke off with a range request,
+            // rather than starting the whole download again.
+            loop {
+                attempts += 1;
+                if attempts > MAX_RESUME_ATTEMPTS {
+                    return Err(error);
+                }
+                if self.reopen().is_ok() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_decrypt_from_http(
+    url_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+) -> i32 {
+    unsafe {
+        let url = match CStr::from_ptr(url_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match decrypt_from_http(url, password, Path::new(output_path), is_mobile) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn decrypt_from_http(
+    url: &str,
+    password: &[u8],
+    output_path: &Path,
+    is_mobile: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut source = HttpSource { url: url.to_string() };
+    decrypt_from_source(&mut source, password, output_path, get_chunk_size(is_mobile))
+}
+
+pub fn decrypt_from_source<S: RangeSource>(
+    source: &mut S,
+    password: &[u8],
+    output_path: &Path,
+    chunk_size: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = ResumingReader::open(source)?;
+    let result = File::create(output_path)
+        .map_err(|e| e.into())
+        .and_then(|output| decrypt_stream(&mut reader, password, output, chunk_size));
+    if result.is_err() {
+        let _ = fs::remove_file(output_path);
+    }
+    result
+}
+
+fn decrypt_stream<S: RangeSource>(
+    reader: &mut ResumingReader<'_, S>,
+    password: &[u8],
+    output: File,
+    chunk_size: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (header, data_start) = ContainerHeader::read(reader)?;
+    if header.dual() {
+        return Err("Dual-slot containers cannot be streamed".into());
+    }
+    sparse::require_dense(&header.holes)?;
+    let key = header.master_key(password)?;
+    let mut nonce_seq = NonceSequence::for_header(&header, &key)?;
+    let nonce_len = nonce_seq.stored_len() as u64;
+    let body_len = header.body_end(data_start, reader.total_len).checked_sub(data_start).ok_or("Invalid file format")?;
+    if body_len < nonce_len + TAG_SIZE as u64 {
+        return Err("Invalid file format".into());
+    }
+
+    let cipher = header.body_cipher(&key)?;
+    let mut output = BufWriter::new(output);
+    let mut stripper = PaddingStripper::new(header.padding);
+    // Only files from before the framing field fall back to the caller's
+    // chunk size.
+    let (single_chunk, max_frame) = match header.framing {
+        Some(framing) => (framing.version == FRAMING_SINGLE, framing.chunk_size as u64 + TAG_SIZE as u64),
+        None => (body_len - nonce_len <= (chunk_size + TAG_SIZE) as u64, (chunk_size + TAG_SIZE) as u64),
+    };
+
+    let mut body = reader.take(body_len);
+    while body.limit() > 0 {
+        let nonce = nonce_seq.read_nonce(&mut body)?;
+        let ciphertext = if single_chunk {
+            let len = body.limit();
+            if len > max_frame {
+                return Err("Invalid file format".into());
+            }
+            let mut ciphertext = vec![0u8; len as usize];
+            body.read_exact(&mut ciphertext)?;
+            ciphertext
+        } else {
+            let mut len_bytes = [0u8; 4];
+            body.read_exact(&mut len_bytes)?;
+            if u32::from_be_bytes(len_bytes) as u64 > max_frame {
+                return Err("Invalid file format".into());
+            }
+            pipeline::read_frame(&mut body, len_bytes)?
+        };
+        let decrypted = cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| "Decryption failed")?;
+        stripper.feed(&decrypted, |content| Ok(output.write_all(content)?))?;
+    }
+    stripper.finish()?;
+    output.flush()?;
+    Ok(())
+}
+
+fn content_range_total(value: &str) -> Option<u64> {
+    value.rsplit('/').next()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::padding::PADDING_PADME;
+    use crate::progress::ProgressSink;
+    use crate::test_util::{write_framed_container, write_framed_container_with};
+    use crate::{encrypt_file_reporting, EncryptOptions};
+    use std::io::Cursor;
+
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    struct FlakySource {
+        data: Vec<u8>,
+        cuts: Vec<u64>,
+        requests: Vec<u64>,
+    }
+
+    impl RangeSource for FlakySource {
+        fn open_at(&mut self, offset: u64) -> Result<RangeResponse, Box<dyn std::error::Error>> {
+            self.requests.push(offset);
+            let rest = self.data[offset as usize..].to_vec();
+            let reader: Box<dyn Read + Send> = match self.cuts.iter().position(|cut| *cut > offset) {
+                Some(i) => {
+                    let cut = self.cuts.remove(i);
+                    Box::new(Cursor::new(rest[..(cut - offset) as usize].to_vec()).chain(Broken))
+                }
+                None => Box::new(Cursor::new(rest)),
+            };
+            Ok((reader, self.data.len() as u64))
+        }
+    }
+
+    #[test]
+    fn test_interrupted_download_resumes_with_range_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = dir.path().join("remote.kyl");
+        let output = dir.path().join("restored.bin");
+        write_framed_container(&encrypted, b"pw", &[b"alpha-", b"bravo-", b"charlie"]);
+        let data = fs::read(&encrypted).unwrap();
+
+        let mut source = FlakySource {
+            data,
+            cuts: vec![5, 40],
+            requests: Vec::new(),
+        };
+        decrypt_from_source(&mut source, b"pw", &output, 64).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"alpha-bravo-charlie");
+        assert_eq!(source.requests, vec![0, 5, 40]);
+
+        assert!(decrypt_from_source(&mut source, b"wrong", &output, 64).is_err());
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_single_chunk_padded_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("notes.txt");
+        let encrypted = dir.path().join("notes.kyl");
+        let output = dir.path().join("restored.txt");
+        fs::write(&input, b"short note").unwrap();
+        let options = EncryptOptions {
+            hint: None,
+            padding: PADDING_PADME,
+            ..Default::default()
+        };
+        encrypt_file_reporting(
+            input.to_str().unwrap(),
+            encrypted.to_str().unwrap(),
+            &b"pw"[..],
+            &options,
+            false,
+            4,
+            &ProgressSink::none(),
+        )
+        .unwrap();
+
+        let mut source = FlakySource {
+            data: fs::read(&encrypted).unwrap(),
+            cuts: vec![20],
+            requests: Vec::new(),
+        };
+        decrypt_from_source(&mut source, b"pw", &output, get_chunk_size(false)).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"short note");
+        assert_eq!(content_range_total("bytes 100-199/1234"), Some(1234));
+    }
+
+    #[test]
+    fn test_frame_longer_than_the_stream_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = dir.path().join("remote.kyl");
+        let output = dir.path().join("restored.bin");
+        let mut header = ContainerHeader::default();
+        header.set_framing(false, crate::MAX_CHUNK_SIZE, 2);
+        write_framed_container_with(&encrypted, b"pw", &header, &[b"alpha-", b"bravo-"]);
+        let mut data = fs::read(&encrypted).unwrap();
+        let len_at = ContainerHeader::read(&mut data.as_slice()).unwrap().1 as usize + crate::NONCE_SIZE;
+        data[len_at..len_at + 4].copy_from_slice(&(crate::MAX_CHUNK_SIZE as u32).to_be_bytes());
+
+        let mut source = FlakySource {
+            data,
+            cuts: Vec::new(),
+            requests: Vec::new(),
+        };
+        let err = decrypt_from_source(&mut source, b"pw", &output, 64).unwrap_err();
+        assert_eq!(err.to_string(), "Corrupted chunk");
+        assert!(!output.exists());
+    }
+}
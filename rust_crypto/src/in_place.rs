@@ -0,0 +1,507 @@
+use aes_gcm::{aead::Aead, Nonce};
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+use crate::cipher::BodyCipher;
+use crate::format::{self, read_layout, ContainerHeader, CHUNK_LEN_SIZE, NONCE_SCHEME_COUNTER};
+use crate::manifest::{Manifest, ManifestBuilder};
+use crate::metadata::{self, FileMetadata};
+use crate::padding::PADDING_NONE;
+use crate::secure_temp::{sidecar_path, SecureTempFile, TempContents};
+use crate::shred::shred_file_internal;
+use crate::verify::{verify_file_internal, VerifyOutcome};
+use crate::{errors, escrow, header_backup, key_usage, nonce, write_encrypted_frame, write_header, NONCE_SIZE, TAG_SIZE};
+
+// Only one chunk is ever held in memory and in the spill file, so in-place
+// runs use chunks far smaller than the regular pipeline's.
+const IN_PLACE_CHUNK_SIZE: usize = if cfg!(test) { 4096 } else { 4 * 1024 * 1024 };
+const JOURNAL_SUFFIX: &str = "kyriejournal";
+const SPILL_SUFFIX: &str = "kyriespill";
+
+// The index of the chunk in flight and its plaintext.
+type Spill = (usize, Zeroizing<Vec<u8>>);
+
+pub const IN_PLACE_NONE: i32 = 0;
+pub const IN_PLACE_ENCRYPTING: i32 = 1;
+pub const IN_PLACE_DECRYPTING: i32 = 2;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    Encrypt,
+    Decrypt,
+}
+
+// Where one chunk's plaintext and sealed bytes sit in the file.
+#[derive(Serialize, Deserialize, Debug)]
+struct Region {
+    plain_offset: u64,
+    plain_len: u64,
+    sealed_offset: u64,
+    nonce: String,
+}
+
+// Everything a resumed run needs once the file no longer has a readable
+// header: the header it had or is getting, and where every chunk goes.
+#[derive(Serialize, Deserialize, Debug)]
+struct InPlaceJournal {
+    mode: Mode,
+    header: String,
+    single_chunk: bool,
+    regions: Vec<Region>,
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_in_place(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match encrypt_in_place(Path::new(path), password, is_mobile) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_in_place(path_ptr: *const c_char, password_ptr: *const u8, password_len: usize) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match decrypt_in_place(Path::new(path), password) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Returns IN_PLACE_NONE, or which kind of run was interrupted on the file.
+#[no_mangle]
+pub extern "C" fn kyrie_in_place_status(path_ptr: *const c_char) -> i32 {
+    let path = match unsafe { CStr::from_ptr(path_ptr) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return errors::invalid_argument(),
+    };
+    match in_place_status(Path::new(path)) {
+        Ok(status) => status,
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_in_place_recover(path_ptr: *const c_char, password_ptr: *const u8, password_len: usize) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match recover_in_place(Path::new(path), password) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn encrypt_in_place(path: &Path, password: &[u8], is_mobile: bool) -> Result<(), Box<dyn std::error::Error>> {
+    InPlaceJob::begin_encrypt(path, password, is_mobile)?.run(None)
+}
+
+pub fn decrypt_in_place(path: &Path, password: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    InPlaceJob::begin_decrypt(path, password)?.run(None)
+}
+
+pub fn in_place_status(path: &Path) -> Result<i32, Box<dyn std::error::Error>> {
+    Ok(match load_journal(path)? {
+        None => IN_PLACE_NONE,
+        Some(journal) if journal.mode == Mode::Encrypt => IN_PLACE_ENCRYPTING,
+        Some(_) => IN_PLACE_DECRYPTING,
+    })
+}
+
+// Rolls an interrupted run forward: the chunk in the spill file is written
+// again and the run carries on from there.
+pub fn recover_in_place(path: &Path, password: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let journal = load_journal(path)?.ok_or("No in-place operation is pending")?;
+    let (header, _) = ContainerHeader::read(&mut hex::decode(&journal.header)?.as_slice())?;
+    let key = Zeroizing::new(header.master_key(password)?);
+    header.verify_file_id(&key)?;
+    let job = InPlaceJob::new(path, header, key, journal)?;
+    let resume = read_spill(path)?;
+    job.run(resume)
+}
+
+struct InPlaceJob {
+    path: PathBuf,
+    file: File,
+    header: ContainerHeader,
+    key: Zeroizing<[u8; 32]>,
+    cipher: BodyCipher,
+    journal: InPlaceJournal,
+}
+
+impl InPlaceJob {
+    fn new(path: &Path, header: ContainerHeader, key: Zeroizing<[u8; 32]>, journal: InPlaceJournal) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(InPlaceJob {
+            path: path.to_path_buf(),
+            file: OpenOptions::new().read(true).write(true).open(path)?,
+            cipher: header.body_cipher(&key)?,
+            header,
+            key,
+            journal,
+        })
+    }
+
+    // The body is laid out exactly as the regular pipeline would write it,
+    // with the header's size fixed up front by reserving its checksum index
+    // and manifest.
+    fn begin_encrypt(path: &Path, password: &[u8], is_mobile: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        check_not_pending(path)?;
+        let content_len = fs::metadata(path)?.len();
+        let chunk_count = content_len.div_ceil(IN_PLACE_CHUNK_SIZE as u64).max(1) as usize;
+        let single_chunk = chunk_count == 1;
+
+        let mut header = ContainerHeader {
+            nonce_scheme: NONCE_SCHEME_COUNTER,
+            backup: header_backup::enabled(),
+            ..Default::default()
+        };
+        header.use_password_kdf(is_mobile);
+        header.set_framing(single_chunk, IN_PLACE_CHUNK_SIZE, chunk_count);
+        let key = Zeroizing::new(header.new_master_key(password)?);
+        header.escrow = escrow::configured_slots(&key)?;
+        header.metadata = metadata::seal(&header, &key, &FileMetadata::for_input(path)?)?;
+        key_usage::check(&header.body_key(&key), content_len, chunk_count as u64)?;
+        if chunk_count <= format::MAX_CHUNK_CHECKSUMS {
+            header.chunk_checksums = vec![0; chunk_count];
+        }
+        header.manifest = Some(Manifest::default());
+        header.seal_file_id(&key);
+
+        let header_len = header.encode().len() as u64;
+        let file_id = header.file_id.as_ref().ok_or("Invalid file format")?;
+        let prefix = nonce::counter_prefix(&header.body_key(&key), file_id);
+        let len_prefix = if single_chunk { 0 } else { CHUNK_LEN_SIZE };
+        let chunk_size = IN_PLACE_CHUNK_SIZE as u64;
+        let regions = (0..chunk_count as u64)
+            .map(|index| {
+                Ok(Region {
+                    plain_offset: index * chunk_size,
+                    plain_len: chunk_size.min(content_len - index * chunk_size),
+                    sealed_offset: header_len + index * (len_prefix + chunk_size + TAG_SIZE as u64) + len_prefix,
+                    nonce: hex::encode(nonce::counter_nonce(&prefix, index)?),
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+        let journal = InPlaceJournal { mode: Mode::Encrypt, header: hex::encode(header.encode()), single_chunk, regions };
+        write_journal(path, &journal)?;
+        InPlaceJob::new(path, header, key, journal)
+    }
+
+    // Every chunk is opened once before anything is overwritten, so a wrong
+    // password or a damaged file leaves it as it was.
+    fn begin_decrypt(path: &Path, password: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        check_not_pending(path)?;
+        match verify_file_internal(path, password)? {
+            VerifyOutcome::Ok => {}
+            VerifyOutcome::WrongPassword => return Err("Decryption failed".into()),
+            VerifyOutcome::CorruptedAtChunk(_) => return Err("Corrupted chunk".into()),
+        }
+        let layout = read_layout(path)?;
+        if layout.dual || layout.padding != PADDING_NONE || !layout.holes.is_empty() {
+            return Err("Container cannot be decrypted in place".into());
+        }
+        let (header, _) = ContainerHeader::read(&mut layout.header.as_slice())?;
+        let key = Zeroizing::new(layout.master_key(password)?);
+        let mut file = File::open(path)?;
+        let layout = layout.unlock(&mut file, &key)?;
+        let prefix = match header.nonce_scheme {
+            NONCE_SCHEME_COUNTER => Some(nonce::counter_prefix(&header.body_key(&key), header.file_id.as_ref().ok_or("Invalid file format")?)),
+            _ => None,
+        };
+
+        let mut regions = Vec::with_capacity(layout.frames.len());
+        let mut plain_offset = 0;
+        for frame in &layout.frames {
+            let nonce = match &prefix {
+                Some(prefix) => nonce::counter_nonce(prefix, frame.index)?,
+                None => {
+                    let mut nonce = [0u8; NONCE_SIZE];
+                    file.seek(SeekFrom::Start(frame.offset))?;
+                    file.read_exact(&mut nonce)?;
+                    nonce
+                }
+            };
+            let plain_len = frame.len.checked_sub(TAG_SIZE as u64).ok_or("Invalid file format")?;
+            regions.push(Region {
+                plain_offset,
+                plain_len,
+                sealed_offset: frame.offset + layout.frame_size(frame) - frame.len,
+                nonce: hex::encode(nonce),
+            });
+            plain_offset += plain_len;
+        }
+
+        let journal = InPlaceJournal { mode: Mode::Decrypt, header: hex::encode(&layout.header), single_chunk: layout.single_chunk, regions };
+        write_journal(path, &journal)?;
+        InPlaceJob::new(path, header, key, journal)
+    }
+
+    // Encryption works from the last chunk back and decryption from the
+    // first forward, so each write only lands on bytes already read.
+    fn run(mut self, resume: Option<Spill>) -> Result<(), Box<dyn std::error::Error>> {
+        let count = self.journal.regions.len();
+        match self.journal.mode {
+            Mode::Encrypt => {
+                let start = match resume {
+                    Some((index, plaintext)) => {
+                        self.apply(index, &plaintext)?;
+                        index
+                    }
+                    None => {
+                        self.start()?;
+                        count
+                    }
+                };
+                for index in (0..start).rev() {
+                    self.process(index)?;
+                }
+            }
+            Mode::Decrypt => {
+                let start = match resume {
+                    Some((index, plaintext)) => {
+                        self.apply(index, &plaintext)?;
+                        index + 1
+                    }
+                    None => 0,
+                };
+                for index in start..count {
+                    self.process(index)?;
+                }
+            }
+        }
+        self.finish()
+    }
+
+    fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.journal.mode == Mode::Encrypt {
+            self.file.set_len(self.body_end())?;
+        }
+        Ok(())
+    }
+
+    fn body_end(&self) -> u64 {
+        self.journal.regions.last().map_or(0, |r| r.sealed_offset + r.plain_len + TAG_SIZE as u64)
+    }
+
+    // The chunk's plaintext is spilled before its bytes are overwritten, so
+    // an interruption during the write can always be redone.
+    fn process(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let region = &self.journal.regions[index];
+        let plaintext = match self.journal.mode {
+            Mode::Encrypt => Zeroizing::new(read_at(&mut self.file, region.plain_offset, region.plain_len)?),
+            Mode::Decrypt => {
+                let sealed = read_at(&mut self.file, region.sealed_offset, region.plain_len + TAG_SIZE as u64)?;
+                let nonce = hex::decode(&region.nonce)?;
+                Zeroizing::new(self.cipher.decrypt(Nonce::from_slice(&nonce), sealed.as_ref()).map_err(|_| "Decryption failed")?)
+            }
+        };
+        write_spill(&self.path, index, &plaintext)?;
+        self.apply(index, &plaintext)
+    }
+
+    fn apply(&mut self, index: usize, plaintext: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let region = &self.journal.regions[index];
+        match self.journal.mode {
+            Mode::Encrypt => {
+                let nonce = hex::decode(&region.nonce)?;
+                let sealed = self.cipher.encrypt(Nonce::from_slice(&nonce), plaintext).map_err(|_| "Encryption failed")?;
+                let len_prefix = if self.journal.single_chunk { 0 } else { CHUNK_LEN_SIZE };
+                self.file.seek(SeekFrom::Start(region.sealed_offset - len_prefix))?;
+                write_encrypted_frame(&mut self.file, &[], &sealed, self.journal.single_chunk)?;
+            }
+            Mode::Decrypt => {
+                self.file.seek(SeekFrom::Start(region.plain_offset))?;
+                self.file.write_all(plaintext)?;
+            }
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Checksums and the manifest are taken from the frames as they ended up
+    // on disk, so a resumed run finishes with the same header as one that
+    // was never interrupted.
+    fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.journal.mode {
+            Mode::Encrypt => {
+                let mut manifest = ManifestBuilder::default();
+                let mut checksums = Vec::with_capacity(self.journal.regions.len());
+                for region in &self.journal.regions {
+                    let sealed = read_at(&mut self.file, region.sealed_offset, region.plain_len + TAG_SIZE as u64)?;
+                    manifest.record_frame(&sealed);
+                    manifest.record_plaintext(region.plain_len as usize);
+                    checksums.push(write_encrypted_frame(&mut io::sink(), &[], &sealed, self.journal.single_chunk)?);
+                }
+                if !self.header.chunk_checksums.is_empty() {
+                    self.header.chunk_checksums = checksums;
+                }
+                self.header.manifest = Some(manifest.finish());
+                self.header.seal_file_id(&self.key);
+
+                self.file.set_len(self.body_end())?;
+                self.file.seek(SeekFrom::Start(0))?;
+                write_header(&mut self.file, &self.header)?;
+                if self.header.backup {
+                    self.file.seek(SeekFrom::End(0))?;
+                    header_backup::write_trailer(&mut self.file, &self.header.encode())?;
+                }
+                self.file.sync_all()?;
+                let content_len: u64 = self.journal.regions.iter().map(|r| r.plain_len).sum();
+                key_usage::record(&self.header.body_key(&self.key), content_len, self.journal.regions.len() as u64)?;
+            }
+            Mode::Decrypt => {
+                let content_len: u64 = self.journal.regions.iter().map(|r| r.plain_len).sum();
+                self.file.set_len(content_len)?;
+                self.file.sync_all()?;
+                drop(self.file);
+                if let Some(file_metadata) = metadata::open(&self.header, &self.key)? {
+                    metadata::restore_modified(&self.path, &file_metadata)?;
+                }
+            }
+        }
+
+        let spill = sidecar_path(&self.path, SPILL_SUFFIX);
+        if spill.exists() && shred_file_internal(&spill).is_err() {
+            fs::remove_file(&spill)?;
+        }
+        fs::remove_file(sidecar_path(&self.path, JOURNAL_SUFFIX))?;
+        Ok(())
+    }
+}
+
+fn check_not_pending(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if sidecar_path(path, JOURNAL_SUFFIX).exists() {
+        return Err("An in-place operation is pending on this file".into());
+    }
+    Ok(())
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn load_journal(path: &Path) -> Result<Option<InPlaceJournal>, Box<dyn std::error::Error>> {
+    let journal_path = sidecar_path(path, JOURNAL_SUFFIX);
+    if !journal_path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(journal_path)?)?))
+}
+
+fn write_journal(path: &Path, journal: &InPlaceJournal) -> Result<(), Box<dyn std::error::Error>> {
+    let journal_path = sidecar_path(path, JOURNAL_SUFFIX);
+    let mut temp = SecureTempFile::for_target(&journal_path, TempContents::Metadata)?;
+    serde_json::to_writer(temp.file(), journal)?;
+    temp.persist(&journal_path)?;
+    Ok(())
+}
+
+// Replaced atomically, so the spill on disk is never half written.
+fn write_spill(path: &Path, index: usize, plaintext: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let spill_path = sidecar_path(path, SPILL_SUFFIX);
+    let mut temp = SecureTempFile::for_target(&spill_path, TempContents::Plaintext)?;
+    temp.file().write_all(&(index as u64).to_le_bytes())?;
+    temp.file().write_all(plaintext)?;
+    temp.persist(&spill_path)?;
+    Ok(())
+}
+
+fn read_spill(path: &Path) -> Result<Option<Spill>, Box<dyn std::error::Error>> {
+    let spill_path = sidecar_path(path, SPILL_SUFFIX);
+    if !spill_path.exists() {
+        return Ok(None);
+    }
+    let spill = Zeroizing::new(fs::read(spill_path)?);
+    let (index, plaintext) = spill.split_first_chunk::<8>().ok_or("Invalid file format")?;
+    Ok(Some((u64::from_le_bytes(*index) as usize, Zeroizing::new(plaintext.to_vec()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_to_memory_internal;
+
+    fn content() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn test_in_place_round_trip_leaves_no_sidecars() {
+        let _mode = crate::secure_temp::PLAINTEXT_TEMP_MODE.read().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.bin");
+        fs::write(&path, content()).unwrap();
+
+        encrypt_in_place(&path, b"pw", false).unwrap();
+        assert_eq!(decrypt_file_to_memory_internal(path.to_str().unwrap(), b"pw", false, 4).unwrap(), content());
+        assert!(decrypt_in_place(&path, b"wrong").is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        decrypt_in_place(&path, b"pw").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), content());
+        assert_eq!(in_place_status(&path).unwrap(), IN_PLACE_NONE);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_interrupted_runs_roll_forward() {
+        let _mode = crate::secure_temp::PLAINTEXT_TEMP_MODE.read().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.bin");
+        fs::write(&path, content()).unwrap();
+
+        // Stops after the last two of three chunks, as if the app was killed.
+        let mut job = InPlaceJob::begin_encrypt(&path, b"pw", false).unwrap();
+        job.start().unwrap();
+        job.process(2).unwrap();
+        job.process(1).unwrap();
+        drop(job);
+        assert_eq!(in_place_status(&path).unwrap(), IN_PLACE_ENCRYPTING);
+        assert!(encrypt_in_place(&path, b"pw", false).is_err());
+        assert!(recover_in_place(&path, b"wrong").is_err());
+        recover_in_place(&path, b"pw").unwrap();
+        assert_eq!(decrypt_file_to_memory_internal(path.to_str().unwrap(), b"pw", false, 4).unwrap(), content());
+
+        let mut job = InPlaceJob::begin_decrypt(&path, b"pw").unwrap();
+        job.process(0).unwrap();
+        drop(job);
+        assert_eq!(in_place_status(&path).unwrap(), IN_PLACE_DECRYPTING);
+        recover_in_place(&path, b"pw").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), content());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,185 @@
+use serde::Serialize;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::errors;
+use crate::format::{read_header, read_layout, KDF_ARGON2ID, NONCE_SCHEME_COUNTER};
+use crate::kdf::KdfParams;
+use crate::padding::PADDING_NONE;
+use crate::{text, HEADER_SIZE, MAGIC_STRING, TAG_SIZE};
+
+#[derive(Serialize, Debug)]
+pub struct FileInfo {
+    pub file_id: Option<String>,
+    pub hint: String,
+    pub padding: u8,
+    pub counter_nonces: bool,
+    pub single_chunk: bool,
+    pub chunk_count: usize,
+    pub file_size: u64,
+}
+
+// The same header fields as a fixed struct, for callers that cannot parse
+// JSON. Only what is stored in the clear is here; the original name and
+// timestamps are sealed and need the password.
+#[repr(C)]
+#[derive(Default, Debug, PartialEq)]
+#[cfg_attr(feature = "uniffi", derive(uniffi::Record))]
+pub struct KyrieFileInfo {
+    pub format_version: u32,
+    pub cipher: u8,
+    // 0 for the legacy unsalted hash, otherwise KDF_ARGON2ID.
+    pub kdf: u8,
+    pub padding: u8,
+    pub counter_nonces: bool,
+    pub has_hint: bool,
+    pub has_metadata: bool,
+    pub keyfile: bool,
+    pub key_slots: u32,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    // Exact when the header carries a manifest or the file is unpadded,
+    // otherwise the padded size.
+    pub plaintext_size: u64,
+    pub plaintext_size_exact: bool,
+    pub file_size: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn inspect_file(input_path_ptr: *const c_char, json_ptr: *mut u8, json_len: *mut usize) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+
+        match inspect_file_internal(Path::new(input_path)).and_then(|info| Ok(serde_json::to_vec(&info)?)) {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_file_info(input_path_ptr: *const c_char, info_ptr: *mut KyrieFileInfo) -> i32 {
+    if info_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    let input_path = match unsafe { CStr::from_ptr(input_path_ptr) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return errors::invalid_argument(),
+    };
+    match file_info(Path::new(input_path)) {
+        Ok(info) => {
+            unsafe { *info_ptr = info };
+            0
+        }
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+// Reads only the unencrypted header and frame layout, so no password is
+// needed. The file id is reported as stored; it is checked against the key
+// when the file is decrypted.
+pub fn inspect_file_internal(path: &Path) -> Result<FileInfo, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    let layout = read_layout(path)?;
+    Ok(FileInfo {
+        file_id: header.file_id.map(|id| id.to_uuid_string()),
+        hint: String::from_utf8_lossy(text::readable_hint(&header.hint)).into_owned(),
+        padding: header.padding,
+        counter_nonces: header.nonce_scheme == NONCE_SCHEME_COUNTER,
+        single_chunk: layout.single_chunk,
+        chunk_count: if layout.dual { layout.frames.len() / 2 } else { layout.frames.len() },
+        file_size: std::fs::metadata(path)?.len(),
+    })
+}
+
+pub fn file_info(path: &Path) -> Result<KyrieFileInfo, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    let layout = read_layout(path)?;
+    let frames = if layout.dual { &layout.frames[..layout.frames.len() / 2] } else { &layout.frames[..] };
+    let sealed_size: u64 = frames.iter().map(|frame| frame.len.saturating_sub(TAG_SIZE as u64)).sum();
+    // A file read from its header backup is re-encoded the same way, so the
+    // version comes from the parsed header rather than the first bytes.
+    let encoded = header.encode();
+    Ok(KyrieFileInfo {
+        format_version: u32::from_le_bytes(encoded[MAGIC_STRING.len()..HEADER_SIZE].try_into()?),
+        cipher: header.cipher,
+        kdf: match header.kdf {
+            KdfParams::LegacySha256 => 0,
+            KdfParams::Argon2id { .. } => KDF_ARGON2ID,
+        },
+        padding: header.padding,
+        counter_nonces: header.nonce_scheme == NONCE_SCHEME_COUNTER,
+        has_hint: !header.hint.is_empty(),
+        has_metadata: !header.metadata.is_empty(),
+        keyfile: header.keyfile,
+        key_slots: header.slot_count() as u32,
+        chunk_size: header.framing.as_ref().map(|f| f.chunk_size).or(header.chunk_size).unwrap_or(0) as u64,
+        chunk_count: frames.len() as u64,
+        plaintext_size: header.manifest.map_or(sealed_size, |m| m.plaintext_len),
+        plaintext_size_exact: header.manifest.is_some() || header.padding == PADDING_NONE,
+        file_size: std::fs::metadata(path)?.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::ContainerHeader;
+    use crate::{decrypt_file_internal, encrypt_file_internal};
+    use std::fs;
+
+    #[test]
+    fn test_file_id_survives_rename_and_is_authenticated() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        let moved = dir.path().join("renamed.kyl");
+        let output = dir.path().join("out.txt");
+        fs::write(&plain, b"tracked").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", Some("h"), false, 4).unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), output.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+
+        let info = inspect_file_internal(&encrypted).unwrap();
+        let id = info.file_id.clone().unwrap();
+        assert_eq!((id.len(), &id[14..15]), (36, "4"));
+        assert_ne!(inspect_file_internal(&output).unwrap().file_id, info.file_id);
+        fs::rename(&encrypted, &moved).unwrap();
+        assert_eq!(inspect_file_internal(&moved).unwrap().file_id, Some(id));
+
+        let mut data = fs::read(&moved).unwrap();
+        let (mut header, header_len) = ContainerHeader::read(&mut data.as_slice()).unwrap();
+        header.hint = b"x".to_vec();
+        data.splice(..header_len as usize, header.encode());
+        fs::write(&moved, &data).unwrap();
+        let err = decrypt_file_internal(moved.to_str().unwrap(), output.to_str().unwrap(), b"pw", false, 4).unwrap_err();
+        assert_eq!(err.to_string(), "Header authentication failed");
+    }
+
+    #[test]
+    fn test_file_info_reads_the_header_without_a_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        fs::write(&plain, vec![7u8; 3000]).unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", Some("h"), false, 4).unwrap();
+
+        let path = std::ffi::CString::new(encrypted.to_str().unwrap()).unwrap();
+        let mut info = KyrieFileInfo::default();
+        assert_eq!(get_file_info(path.as_ptr(), &mut info), 0);
+        assert_eq!((info.format_version, info.kdf, info.key_slots), (crate::format::EXTENDED_VERSION, KDF_ARGON2ID, 1));
+        assert_eq!((info.chunk_count, info.plaintext_size, info.plaintext_size_exact), (1, 3000, true));
+        assert!(info.has_hint && info.has_metadata && info.counter_nonces);
+        assert_eq!(info.file_size, fs::metadata(&encrypted).unwrap().len());
+        assert_eq!(get_file_info(path.as_ptr(), std::ptr::null_mut()), -1);
+    }
+}
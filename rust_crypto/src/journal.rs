@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ffi::{c_void, CStr};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::format::read_layout;
+use crate::secure_temp::{SecureTempFile, TempContents};
+
+const HASH_SIZE: usize = 32;
+
+pub type UploadChunkCallback = extern "C" fn(offset: u64, data: *const u8, len: usize, user_data: *mut c_void) -> i32;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct JournalStart {
+    file_len: u64,
+    modified: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalEntry {
+    pub end: u64,
+    pub hash: String,
+}
+
+struct UploadJournal {
+    start: JournalStart,
+    entries: Vec<JournalEntry>,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_journal_upload(
+    encrypted_path_ptr: *const c_char,
+    journal_path_ptr: *const c_char,
+    callback: Option<UploadChunkCallback>,
+    user_data: *mut c_void,
+    hash_out: *mut u8,
+) -> i32 {
+    unsafe {
+        let encrypted_path = match CStr::from_ptr(encrypted_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let journal_path = match CStr::from_ptr(journal_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let callback = match callback {
+            Some(callback) => callback,
+            None => return -1,
+        };
+
+        let result = upload_with_journal(Path::new(encrypted_path), Path::new(journal_path), |offset, data| {
+            match callback(offset, data.as_ptr(), data.len(), user_data) {
+                0 => Ok(()),
+                _ => Err("Chunk upload failed".into()),
+            }
+        });
+        match result {
+            Ok(hash) => {
+                if !hash_out.is_null() {
+                    std::ptr::copy_nonoverlapping(hash.as_ptr(), hash_out, HASH_SIZE);
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn upload_with_journal<F>(
+    encrypted_path: &Path,
+    journal_path: &Path,
+    mut send: F,
+) -> Result<[u8; HASH_SIZE], Box<dyn std::error::Error>>
+where
+    F: FnMut(u64, &[u8]) -> Result<(), Box<dyn std::error::Error>>,
+{
+    // Chunks are the container header followed by each encrypted frame, so a
+    // resumed upload always restarts on a frame boundary.
+    let layout = read_layout(encrypted_path)?;
+    let mut boundaries = vec![layout.header.len() as u64];
+    boundaries.extend(layout.frames.iter().map(|frame| frame.offset + layout.frame_size(frame)));
+
+    let start = journal_start(encrypted_path)?;
+    // A backup trailer after the body goes up as one last chunk.
+    if boundaries.last() != Some(&start.file_len) {
+        boundaries.push(start.file_len);
+    }
+    let journal = match load_journal(journal_path)? {
+        Some(journal) if journal.start != start => {
+            return Err("Encrypted file changed since the interrupted upload".into());
+        }
+        Some(journal) => journal,
+        None => UploadJournal {
+            start,
+            entries: Vec::new(),
+        },
+    };
+    let (mut offset, mut hash) = match journal.entries.last() {
+        Some(entry) if boundaries.contains(&entry.end) => (entry.end, parse_hash(&entry.hash)?),
+        Some(_) => return Err("Upload journal does not match the chunk boundaries".into()),
+        None => (0, [0u8; HASH_SIZE]),
+    };
+    write_journal(journal_path, &journal)?;
+
+    let mut input = File::open(encrypted_path)?;
+    input.seek(SeekFrom::Start(offset))?;
+    let mut appender = OpenOptions::new().append(true).open(journal_path)?;
+    let resume_at = offset;
+    for end in boundaries.into_iter().filter(|end| *end > resume_at) {
+        let mut chunk = vec![0u8; (end - offset) as usize];
+        input.read_exact(&mut chunk)?;
+        send(offset, &chunk)?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(hash);
+        hasher.update(&chunk);
+        hash = hasher.finalize().into();
+        let entry = JournalEntry {
+            end,
+            hash: hex::encode(hash),
+        };
+        writeln!(appender, "{}", serde_json::to_string(&entry)?)?;
+        appender.sync_data()?;
+        offset = end;
+    }
+
+    drop(appender);
+    let _ = fs::remove_file(journal_path);
+    Ok(hash)
+}
+
+fn journal_start(encrypted_path: &Path) -> Result<JournalStart, Box<dyn std::error::Error>> {
+    let metadata = fs::metadata(encrypted_path)?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok(JournalStart {
+        file_len: metadata.len(),
+        modified,
+    })
+}
+
+fn load_journal(journal_path: &Path) -> Result<Option<UploadJournal>, Box<dyn std::error::Error>> {
+    if !journal_path.exists() {
+        return Ok(None);
+    }
+    let mut lines = BufReader::new(File::open(journal_path)?).lines();
+    let start = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Err("Upload journal is empty".into()),
+    };
+    // A crash while appending can leave a torn final line; the chunk it
+    // describes is simply uploaded again and the journal is rewritten clean.
+    let mut entries = Vec::new();
+    for line in lines {
+        match serde_json::from_str(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+    }
+    Ok(Some(UploadJournal { start, entries }))
+}
+
+fn write_journal(journal_path: &Path, journal: &UploadJournal) -> Result<(), Box<dyn std::error::Error>> {
+    let mut temp = SecureTempFile::for_target(journal_path, TempContents::Metadata)?;
+    writeln!(temp.file(), "{}", serde_json::to_string(&journal.start)?)?;
+    for entry in &journal.entries {
+        writeln!(temp.file(), "{}", serde_json::to_string(entry)?)?;
+    }
+    temp.persist(journal_path)?;
+    Ok(())
+}
+
+fn parse_hash(hash: &str) -> Result<[u8; HASH_SIZE], Box<dyn std::error::Error>> {
+    hex::decode(hash)?.try_into().map_err(|_| "Invalid journal hash".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_framed_container;
+
+    #[test]
+    fn test_upload_resumes_from_last_confirmed_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = dir.path().join("data.kyl");
+        let journal = dir.path().join("data.kyl.journal");
+        write_framed_container(&encrypted, b"pw", &[b"first", b"second", b"third", b"fourth"]);
+        let file = fs::read(&encrypted).unwrap();
+
+        let mut uploaded = Vec::new();
+        let result = upload_with_journal(&encrypted, &journal, |offset, data| {
+            if uploaded.len() == 3 {
+                return Err("Connection reset".into());
+            }
+            uploaded.push((offset, data.to_vec()));
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert!(journal.exists());
+
+        // Simulate a torn write after the last confirmed entry.
+        let mut torn = OpenOptions::new().append(true).open(&journal).unwrap();
+        torn.write_all(b"{\"end\":").unwrap();
+        drop(torn);
+
+        let resumed_at = uploaded.last().map(|(offset, data)| offset + data.len() as u64).unwrap();
+        let hash = upload_with_journal(&encrypted, &journal, |offset, data| {
+            assert!(offset >= resumed_at);
+            uploaded.push((offset, data.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        assert!(!journal.exists());
+        assert_eq!(uploaded.len(), 5);
+        assert_eq!(uploaded.iter().flat_map(|(_, data)| data.clone()).collect::<Vec<_>>(), file);
+
+        let fresh = upload_with_journal(&encrypted, &journal, |_, _| Ok(())).unwrap();
+        assert_eq!(hash, fresh);
+    }
+
+    #[test]
+    fn test_changed_file_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = dir.path().join("data.kyl");
+        let journal = dir.path().join("data.kyl.journal");
+        write_framed_container(&encrypted, b"pw", &[b"one", b"two"]);
+        assert!(upload_with_journal(&encrypted, &journal, |_, _| Err("offline".into())).is_err());
+
+        write_framed_container(&encrypted, b"pw", &[b"one", b"two", b"three"]);
+        assert!(upload_with_journal(&encrypted, &journal, |_, _| Ok(())).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use serde::Serialize;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::diagnostics::{ChunkRecorder, DiagnosticsReport};
+use crate::errors::{classify, describe, ERR_INTERNAL, ERR_INVALID_ARGUMENT};
+use crate::naming::NamingPolicy;
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::{
+    decrypt_file_internal, decrypt_file_reporting, encrypt_file_internal, encrypt_file_reporting,
+    get_hint_from_file_internal, EncryptOptions,
+};
+
+const STATUS_OK: &str = "ok";
+const STATUS_ERROR: &str = "error";
+
+#[derive(Serialize)]
+pub struct ErrorDetail {
+    pub code: i32,
+    pub id: &'static str,
+    pub description: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct OperationStats {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Serialize)]
+pub struct FileResult {
+    pub input_path: String,
+    pub output_path: Option<String>,
+    pub status: &'static str,
+    pub error: Option<ErrorDetail>,
+    pub stats: OperationStats,
+}
+
+#[derive(Serialize)]
+pub struct OperationResult {
+    pub status: &'static str,
+    pub code: i32,
+    pub error: Option<ErrorDetail>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<OperationStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<DiagnosticsReport>,
+}
+
+impl OperationResult {
+    fn ok() -> Self {
+        OperationResult {
+            status: STATUS_OK,
+            code: 0,
+            error: None,
+            stats: None,
+            output_path: None,
+            hint: None,
+            files: Vec::new(),
+            diagnostics: None,
+        }
+    }
+
+    fn failed(code: i32, error: ErrorDetail) -> Self {
+        OperationResult {
+            status: STATUS_ERROR,
+            code,
+            error: Some(error),
+            ..Self::ok()
+        }
+    }
+
+    fn invalid_argument(message: &str) -> Self {
+        Self::failed(-1, error_detail(ERR_INVALID_ARGUMENT, message.to_string()))
+    }
+
+    fn from_file(file: FileResult) -> Self {
+        OperationResult {
+            status: file.status,
+            code: if file.error.is_some() { -2 } else { 0 },
+            error: file.error,
+            stats: Some(file.stats),
+            output_path: file.output_path,
+            ..Self::ok()
+        }
+    }
+
+    fn into_raw(self) -> *mut c_char {
+        let json = serde_json::to_string(&self).unwrap_or_default();
+        CString::new(json).map(CString::into_raw).unwrap_or(std::ptr::null_mut())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_free_json(json_ptr: *mut c_char) {
+    if !json_ptr.is_null() {
+        drop(unsafe { CString::from_raw(json_ptr) });
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_json(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> *mut c_char {
+    unsafe {
+        let (input_path, output_path) = match (c_str(input_path_ptr), c_str(output_path_ptr)) {
+            (Some(input), Some(output)) => (input, output),
+            _ => return OperationResult::invalid_argument("Invalid path").into_raw(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = c_str(hint_ptr);
+
+        let file = run_file(input_path, output_path, |input, output| {
+            encrypt_file_internal(input, output, password, hint, is_mobile, cpu_cores)
+        });
+        OperationResult::from_file(file).into_raw()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_json(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> *mut c_char {
+    unsafe {
+        let (input_path, output_path) = match (c_str(input_path_ptr), c_str(output_path_ptr)) {
+            (Some(input), Some(output)) => (input, output),
+            _ => return OperationResult::invalid_argument("Invalid path").into_raw(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let file = run_file(input_path, output_path, |input, output| {
+            decrypt_file_internal(input, output, password, is_mobile, cpu_cores)
+        });
+        OperationResult::from_file(file).into_raw()
+    }
+}
+
+// Same as encrypt_file_json, with per-chunk read, encrypt and write timings
+// added under "diagnostics".
+#[no_mangle]
+pub extern "C" fn encrypt_file_diagnostics_json(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> *mut c_char {
+    unsafe {
+        let (input_path, output_path) = match (c_str(input_path_ptr), c_str(output_path_ptr)) {
+            (Some(input), Some(output)) => (input, output),
+            _ => return OperationResult::invalid_argument("Invalid path").into_raw(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let options = EncryptOptions {
+            hint: c_str(hint_ptr),
+            padding: OptionsProfile::current().padding,
+            ..Default::default()
+        };
+
+        with_diagnostics(input_path, output_path, |input, output, progress| {
+            encrypt_file_reporting(input, output, password, &options, is_mobile, cpu_cores, progress).map(|_| ())
+        })
+        .into_raw()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_diagnostics_json(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> *mut c_char {
+    unsafe {
+        let (input_path, output_path) = match (c_str(input_path_ptr), c_str(output_path_ptr)) {
+            (Some(input), Some(output)) => (input, output),
+            _ => return OperationResult::invalid_argument("Invalid path").into_raw(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        with_diagnostics(input_path, output_path, |input, output, progress| {
+            decrypt_file_reporting(input, output, password, is_mobile, cpu_cores, progress)
+        })
+        .into_raw()
+    }
+}
+
+fn with_diagnostics<F>(input_path: &str, output_path: &str, operation: F) -> OperationResult
+where
+    F: FnOnce(&str, &str, &ProgressSink) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let recorder = Arc::new(ChunkRecorder::default());
+    let progress = ProgressSink::none().with_diagnostics(Arc::clone(&recorder));
+    let file = run_file(input_path, output_path, |input, output| operation(input, output, &progress));
+    OperationResult {
+        diagnostics: file.error.is_none().then(|| recorder.report()),
+        ..OperationResult::from_file(file)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_files_to_dir_json(
+    input_paths_json_ptr: *const c_char,
+    output_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> *mut c_char {
+    unsafe {
+        let input_paths: Vec<String> = match c_str(input_paths_json_ptr).map(serde_json::from_str) {
+            Some(Ok(paths)) => paths,
+            _ => return OperationResult::invalid_argument("Input paths must be a JSON array of strings").into_raw(),
+        };
+        let output_dir = match c_str(output_dir_ptr) {
+            Some(dir) => dir,
+            None => return OperationResult::invalid_argument("Invalid path").into_raw(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = c_str(hint_ptr);
+
+        encrypt_files_to_dir(&input_paths, Path::new(output_dir), password, hint, is_mobile, cpu_cores).into_raw()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_hint_from_file_json(input_path_ptr: *const c_char) -> *mut c_char {
+    unsafe {
+        let input_path = match c_str(input_path_ptr) {
+            Some(path) => path,
+            None => return OperationResult::invalid_argument("Invalid path").into_raw(),
+        };
+        match get_hint_from_file_internal(input_path) {
+            Ok(hint) => OperationResult {
+                hint: Some(String::from_utf8_lossy(&hint).into_owned()),
+                ..OperationResult::ok()
+            }
+            .into_raw(),
+            Err(e) => OperationResult::failed(-2, error_detail(classify(e.as_ref()), e.to_string())).into_raw(),
+        }
+    }
+}
+
+pub fn encrypt_files_to_dir(
+    input_paths: &[String],
+    output_dir: &Path,
+    password: &[u8],
+    hint: Option<&str>,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> OperationResult {
+    let policy = NamingPolicy::current();
+    let started = Instant::now();
+    let mut stats = OperationStats::default();
+    let mut files = Vec::new();
+
+    for input_path in input_paths {
+        let output_path = policy
+            .output_path(output_dir, Path::new(input_path))
+            .and_then(|p| p.to_str().map(str::to_string));
+        let file = match output_path {
+            Some(output_path) => run_file(input_path, &output_path, |input, output| {
+                encrypt_file_internal(input, output, password, hint, is_mobile, cpu_cores)
+            }),
+            None => FileResult {
+                input_path: input_path.clone(),
+                output_path: None,
+                status: STATUS_ERROR,
+                error: Some(error_detail(ERR_INVALID_ARGUMENT, "No output name available".to_string())),
+                stats: OperationStats::default(),
+            },
+        };
+        stats.bytes_in += file.stats.bytes_in;
+        stats.bytes_out += file.stats.bytes_out;
+        files.push(file);
+    }
+    stats.elapsed_ms = started.elapsed().as_millis() as u64;
+
+    let failed = files.iter().filter(|f| f.error.is_some()).count();
+    OperationResult {
+        status: if failed == 0 { STATUS_OK } else { STATUS_ERROR },
+        code: if failed == 0 { 0 } else { -2 },
+        stats: Some(stats),
+        files,
+        ..OperationResult::ok()
+    }
+}
+
+fn run_file<F>(input_path: &str, output_path: &str, operation: F) -> FileResult
+where
+    F: FnOnce(&str, &str) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let started = Instant::now();
+    let result = operation(input_path, output_path);
+    let stats = OperationStats {
+        bytes_in: fs::metadata(input_path).map(|m| m.len()).unwrap_or(0),
+        bytes_out: if result.is_ok() { fs::metadata(output_path).map(|m| m.len()).unwrap_or(0) } else { 0 },
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    };
+    FileResult {
+        input_path: input_path.to_string(),
+        output_path: result.is_ok().then(|| output_path.to_string()),
+        status: if result.is_ok() { STATUS_OK } else { STATUS_ERROR },
+        error: result.err().map(|e| error_detail(classify(e.as_ref()), e.to_string())),
+        stats,
+    }
+}
+
+fn error_detail(code: i32, message: String) -> ErrorDetail {
+    let (id, description) = describe(code).or_else(|| describe(ERR_INTERNAL)).unwrap_or_default();
+    ErrorDetail {
+        code,
+        id,
+        description,
+        message,
+    }
+}
+
+unsafe fn c_str<'a>(ptr: *const c_char) -> Option<&'a str> {
+    if ptr.is_null() {
+        None
+    } else {
+        CStr::from_ptr(ptr).to_str().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn take_json(ptr: *mut c_char) -> Value {
+        let json = serde_json::from_str(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap()).unwrap();
+        kyrie_free_json(ptr);
+        json
+    }
+
+    #[test]
+    fn test_json_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("report.txt");
+        let encrypted = dir.path().join("report.kyl");
+        let output = dir.path().join("restored.txt");
+        fs::write(&input, b"quarterly numbers").unwrap();
+
+        let c = |p: &Path| CString::new(p.to_str().unwrap()).unwrap();
+        let hint = CString::new("q3").unwrap();
+        let result = take_json(encrypt_file_json(
+            c(&input).as_ptr(),
+            c(&encrypted).as_ptr(),
+            b"pw".as_ptr(),
+            2,
+            hint.as_ptr(),
+            false,
+            4,
+        ));
+        assert_eq!(result["status"], "ok");
+        assert_eq!(result["stats"]["bytes_in"], 17);
+        assert_eq!(result["output_path"], encrypted.to_str().unwrap());
+
+        let result = take_json(decrypt_file_json(c(&encrypted).as_ptr(), c(&output).as_ptr(), b"no".as_ptr(), 2, false, 4));
+        assert_eq!((result["status"].clone(), result["code"].clone()), ("error".into(), (-2).into()));
+        assert_eq!(result["error"]["id"], "ERR_WRONG_PASSWORD");
+
+        assert_eq!(take_json(get_hint_from_file_json(c(&encrypted).as_ptr()))["hint"], "q3");
+        assert!(result.get("diagnostics").is_none());
+
+        let result = take_json(encrypt_file_diagnostics_json(
+            c(&input).as_ptr(),
+            c(&encrypted).as_ptr(),
+            b"pw".as_ptr(),
+            2,
+            std::ptr::null(),
+            false,
+            4,
+        ));
+        assert_eq!(result["diagnostics"]["chunks"][0]["bytes"], 17);
+        assert_eq!(result["diagnostics"]["slowest_chunks"], serde_json::json!([0]));
+        let result = take_json(decrypt_file_diagnostics_json(c(&encrypted).as_ptr(), c(&output).as_ptr(), b"pw".as_ptr(), 2, false, 4));
+        assert_eq!(result["status"], "ok");
+        assert!(["io", "cpu"].contains(&result["diagnostics"]["bottleneck"].as_str().unwrap()));
+
+        let out_dir = dir.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        let inputs = vec![input.to_str().unwrap().to_string(), dir.path().join("missing").to_str().unwrap().to_string()];
+        let result = serde_json::to_value(encrypt_files_to_dir(&inputs, &out_dir, b"pw", None, false, 4)).unwrap();
+        assert_eq!(result["status"], "error");
+        assert_eq!(result["files"][0]["status"], "ok");
+        assert_eq!(result["files"][1]["error"]["id"], "ERR_FILE_NOT_FOUND");
+    }
+}
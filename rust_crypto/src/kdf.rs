@@ -0,0 +1,252 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::derive_key;
+
+// Stored in the container header to say which subkey encrypts the body.
+// Legacy containers are encrypted with the password key itself.
+pub const KEY_PURPOSE_LEGACY: u8 = 0;
+pub const KEY_PURPOSE_FILE_DATA: u8 = 1;
+pub const KEY_PURPOSE_METADATA: u8 = 2;
+// Encrypted under the device key rather than a user password.
+pub const KEY_PURPOSE_DEVICE: u8 = 3;
+
+pub const KDF_SALT_SIZE: usize = 16;
+const CONTEXT_LABEL: &[u8] = b"KYRIE_LOCK app context: ";
+// Headers come from untrusted files, so their cost is capped before
+// anything is allocated for it.
+const MAX_ARGON2_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_LANES: u32 = 16;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KdfParams {
+    // Version 1 files: one unsalted SHA-256 of the password.
+    #[default]
+    LegacySha256,
+    Argon2id { memory_kib: u32, iterations: u32, lanes: u32 },
+}
+
+impl KdfParams {
+    pub fn for_new_files(is_mobile: bool) -> Self {
+        if is_mobile {
+            KdfParams::Argon2id { memory_kib: 32 * 1024, iterations: 3, lanes: 2 }
+        } else {
+            KdfParams::Argon2id { memory_kib: 64 * 1024, iterations: 3, lanes: 4 }
+        }
+    }
+
+    // Sealed archives are written once and opened rarely, so they take the
+    // strongest profile every supported device can still open.
+    pub fn for_archives() -> Self {
+        KdfParams::Argon2id { memory_kib: 256 * 1024, iterations: 4, lanes: 4 }
+    }
+
+    pub fn check(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match *self {
+            KdfParams::LegacySha256 => Ok(()),
+            KdfParams::Argon2id { memory_kib, iterations, lanes }
+                if (1..=MAX_ARGON2_LANES).contains(&lanes)
+                    && (1..=MAX_ARGON2_ITERATIONS).contains(&iterations)
+                    && (8 * lanes..=MAX_ARGON2_MEMORY_KIB).contains(&memory_kib) =>
+            {
+                Ok(())
+            }
+            KdfParams::Argon2id { .. } => Err("Unsupported KDF parameters".into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyPurpose {
+    FileData,
+    Metadata,
+    HeaderMac,
+    ContentDigest,
+    FileName,
+    KeyId,
+    KeyShare,
+    PreviousPassword,
+    DeviceDocument,
+    ConvergentContent,
+    ConvergentSeal,
+    ConvergentChunk,
+    ConvergentOrder,
+    EscrowWrap,
+    RecipientWrap,
+    KeyWrap,
+    FileMetadata,
+    Preview,
+    AppMetadata,
+    ContentHash,
+}
+
+impl KeyPurpose {
+    fn label(self) -> &'static [u8] {
+        match self {
+            KeyPurpose::FileData => b"KYRIE_LOCK file data",
+            KeyPurpose::Metadata => b"KYRIE_LOCK metadata",
+            KeyPurpose::HeaderMac => b"KYRIE_LOCK header mac",
+            KeyPurpose::ContentDigest => b"KYRIE_LOCK content digest",
+            KeyPurpose::FileName => b"KYRIE_LOCK file name",
+            KeyPurpose::KeyId => b"KYRIE_LOCK key id",
+            KeyPurpose::KeyShare => b"KYRIE_LOCK key share",
+            KeyPurpose::PreviousPassword => b"KYRIE_LOCK previous password",
+            KeyPurpose::DeviceDocument => b"KYRIE_LOCK device document",
+            KeyPurpose::ConvergentContent => b"KYRIE_LOCK convergent content",
+            KeyPurpose::ConvergentSeal => b"KYRIE_LOCK convergent seal",
+            KeyPurpose::ConvergentChunk => b"KYRIE_LOCK convergent chunk",
+            KeyPurpose::ConvergentOrder => b"KYRIE_LOCK convergent order",
+            KeyPurpose::EscrowWrap => b"KYRIE_LOCK escrow wrap",
+            KeyPurpose::RecipientWrap => b"KYRIE_LOCK recipient wrap",
+            KeyPurpose::KeyWrap => b"KYRIE_LOCK key wrap",
+            KeyPurpose::FileMetadata => b"KYRIE_LOCK file metadata",
+            KeyPurpose::Preview => b"KYRIE_LOCK preview",
+            KeyPurpose::AppMetadata => b"KYRIE_LOCK app metadata",
+            KeyPurpose::ContentHash => b"KYRIE_LOCK content hash",
+        }
+    }
+}
+
+pub trait KeyProvider {
+    fn key_for(&self, salt: &[u8], params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>>;
+
+    // Providers holding a random key ignore the KDF, so new files they
+    // write keep the legacy header instead of an unused salt.
+    fn derives_from_password(&self) -> bool {
+        true
+    }
+
+    fn uses_keyfile(&self) -> bool {
+        false
+    }
+}
+
+impl KeyProvider for [u8] {
+    fn key_for(&self, salt: &[u8], params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        derive(self, salt, params)
+    }
+}
+
+pub fn derive(password: &[u8], salt: &[u8], params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    params.check()?;
+    match *params {
+        KdfParams::LegacySha256 => Ok(derive_key(password)),
+        KdfParams::Argon2id { memory_kib, iterations, lanes } => {
+            let params = Params::new(memory_kib, iterations, lanes, Some(32)).map_err(|_| "Unsupported KDF parameters")?;
+            let mut key = [0u8; 32];
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(password, salt, &mut key)
+                .map_err(|_| "Unsupported KDF parameters")?;
+            Ok(key)
+        }
+    }
+}
+
+pub fn subkey(master: &[u8; 32], purpose: KeyPurpose) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, master)
+        .expand(purpose.label(), &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+pub fn salted_subkey(master: &[u8; 32], salt: &[u8], purpose: KeyPurpose) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(salt), master)
+        .expand(purpose.label(), &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+// Keys for the host's own uses of a password, such as its database records.
+// Each context string gets a key of its own, and none can collide with the
+// labels above, so a key leaking from one use says nothing about another's
+// or about any container's.
+pub fn context_key(master: &[u8; 32], context: &[u8]) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    if context.is_empty() {
+        return Err("Invalid argument".into());
+    }
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, master)
+        .expand_multi_info(&[CONTEXT_LABEL, context], &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    Ok(key)
+}
+
+// The key that encrypts the body of a container with the given purpose.
+pub fn body_key(master: &[u8; 32], key_purpose: u8) -> [u8; 32] {
+    match key_purpose {
+        KEY_PURPOSE_FILE_DATA => subkey(master, KeyPurpose::FileData),
+        KEY_PURPOSE_METADATA => subkey(master, KeyPurpose::Metadata),
+        KEY_PURPOSE_DEVICE => subkey(master, KeyPurpose::DeviceDocument),
+        _ => *master,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_header, ContainerHeader};
+    use crate::test_util::write_framed_container;
+    use crate::{decrypt_file_to_memory_internal, encrypt_file_internal};
+
+    #[test]
+    fn test_subkeys_are_separated_by_purpose() {
+        let master = derive_key(b"pw");
+        let purposes = [
+            KeyPurpose::FileData,
+            KeyPurpose::Metadata,
+            KeyPurpose::HeaderMac,
+            KeyPurpose::ContentDigest,
+            KeyPurpose::FileName,
+        ];
+        let keys: Vec<[u8; 32]> = purposes.iter().map(|p| subkey(&master, *p)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert_ne!(*key, master);
+            assert!(keys[i + 1..].iter().all(|other| other != key));
+        }
+        assert_eq!(body_key(&master, KEY_PURPOSE_LEGACY), master);
+        assert_eq!(body_key(&master, KEY_PURPOSE_METADATA), keys[1]);
+
+        let records = context_key(&master, b"notes.db records").unwrap();
+        assert_eq!(records, context_key(&master, b"notes.db records").unwrap());
+        assert_ne!(records, context_key(&master, b"notes.db index").unwrap());
+        assert_ne!(records, context_key(&derive_key(b"other"), b"notes.db records").unwrap());
+        assert!(keys.iter().all(|key| *key != records) && records != master);
+        assert!(context_key(&master, b"").is_err());
+    }
+
+    #[test]
+    fn test_new_files_get_their_own_argon2id_salt() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let first = dir.path().join("first.kyl");
+        let second = dir.path().join("second.kyl");
+        std::fs::write(&plain, b"salted").unwrap();
+        for path in [&first, &second] {
+            encrypt_file_internal(plain.to_str().unwrap(), path.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        }
+
+        let (header, _) = read_header(&first).unwrap();
+        let (other, _) = read_header(&second).unwrap();
+        assert_eq!(header.kdf, KdfParams::for_new_files(false));
+        assert_eq!(header.kdf_salt.len(), KDF_SALT_SIZE);
+        assert_ne!(header.kdf_salt, other.kdf_salt);
+        assert_ne!(header.master_key(&b"pw"[..]).unwrap(), derive_key(b"pw"));
+        assert_eq!(decrypt_file_to_memory_internal(first.to_str().unwrap(), b"pw", false, 4).unwrap(), b"salted");
+
+        let legacy = dir.path().join("legacy.kyl");
+        write_framed_container(&legacy, b"pw", &[b"v1"]);
+        assert_eq!(read_header(&legacy).unwrap().0.kdf, KdfParams::LegacySha256);
+        assert_eq!(decrypt_file_to_memory_internal(legacy.to_str().unwrap(), b"pw", false, 4).unwrap(), b"v1");
+
+        let costly = ContainerHeader {
+            kdf: KdfParams::Argon2id { memory_kib: u32::MAX, iterations: 1, lanes: 1 },
+            kdf_salt: header.kdf_salt.clone(),
+            ..Default::default()
+        };
+        assert!(ContainerHeader::read(&mut costly.encode().as_slice()).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+use crate::format::{read_header, ContainerHeader, DeviceSlot, MAX_DEVICE_BLOB_LEN};
+use crate::progress::ProgressSink;
+use crate::quorum::FileKey;
+use crate::{decrypt_file_reporting, errors, reencrypt};
+
+// Android Keystore and the iOS Keychain are reached through the host, which
+// shows the biometric prompt before its key unwraps anything.
+pub const PROTECTOR_HOST_KEYSTORE: u8 = 1;
+pub const PROTECTOR_DPAPI: u8 = 2;
+
+// Wraps the file key with a key held outside this library. Slots record
+// the kind, so a device only offers its blobs to a keystore that made them.
+pub trait KeyProtector {
+    fn kind(&self) -> u8;
+    fn wrap(&self, file_key: &[u8; 32]) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+    fn unwrap(&self, blob: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>>;
+}
+
+// Reads `input_len` bytes and writes at most `*output_len` bytes, setting
+// `*output_len` to the length written. Non-zero means the keystore refused,
+// for example because the fingerprint was not accepted.
+pub type KeystoreCallback =
+    extern "C" fn(input: *const u8, input_len: usize, output: *mut u8, output_len: *mut usize, user_data: *mut c_void) -> i32;
+
+struct HostKeystore {
+    wrap: KeystoreCallback,
+    unwrap: KeystoreCallback,
+    user_data: usize,
+}
+
+static HOST_KEYSTORE: Mutex<Option<HostKeystore>> = Mutex::new(None);
+
+impl HostKeystore {
+    fn call(&self, callback: KeystoreCallback, input: &[u8]) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+        let mut output = Zeroizing::new(vec![0u8; MAX_DEVICE_BLOB_LEN]);
+        let mut output_len = output.len();
+        let result = callback(input.as_ptr(), input.len(), output.as_mut_ptr(), &mut output_len, self.user_data as *mut c_void);
+        if result != 0 || output_len > MAX_DEVICE_BLOB_LEN {
+            return Err("Device keystore refused".into());
+        }
+        output.truncate(output_len);
+        Ok(output)
+    }
+}
+
+impl KeyProtector for HostKeystore {
+    fn kind(&self) -> u8 {
+        PROTECTOR_HOST_KEYSTORE
+    }
+
+    fn wrap(&self, file_key: &[u8; 32]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(self.call(self.wrap, file_key)?.to_vec())
+    }
+
+    fn unwrap(&self, blob: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+        let file_key = self.call(self.unwrap, blob)?;
+        Ok(Zeroizing::new(file_key.as_slice().try_into().map_err(|_| "Decryption failed")?))
+    }
+}
+
+#[cfg(all(windows, feature = "dpapi"))]
+mod dpapi {
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Cryptography::{
+        CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN, CRYPT_INTEGER_BLOB,
+    };
+    use zeroize::Zeroizing;
+
+    use super::{KeyProtector, PROTECTOR_DPAPI};
+
+    // Bound to the signed-in Windows user rather than a fingerprint.
+    pub struct Dpapi;
+
+    impl KeyProtector for Dpapi {
+        fn kind(&self) -> u8 {
+            PROTECTOR_DPAPI
+        }
+
+        fn wrap(&self, file_key: &[u8; 32]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            Ok(transform(file_key, true)?.to_vec())
+        }
+
+        fn unwrap(&self, blob: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+            let file_key = transform(blob, false)?;
+            Ok(Zeroizing::new(file_key.as_slice().try_into().map_err(|_| "Decryption failed")?))
+        }
+    }
+
+    fn transform(data: &[u8], protect: bool) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+        let input = CRYPT_INTEGER_BLOB { cbData: data.len() as u32, pbData: data.as_ptr() as *mut u8 };
+        let mut output = CRYPT_INTEGER_BLOB { cbData: 0, pbData: std::ptr::null_mut() };
+        let ok = unsafe {
+            if protect {
+                CryptProtectData(&input, std::ptr::null(), std::ptr::null(), std::ptr::null(), std::ptr::null(), CRYPTPROTECT_UI_FORBIDDEN, &mut output)
+            } else {
+                CryptUnprotectData(&input, std::ptr::null_mut(), std::ptr::null(), std::ptr::null(), std::ptr::null(), CRYPTPROTECT_UI_FORBIDDEN, &mut output)
+            }
+        };
+        if ok == 0 {
+            return Err(if protect { "Encryption failed" } else { "Decryption failed" }.into());
+        }
+        unsafe {
+            let result = Zeroizing::new(std::slice::from_raw_parts(output.pbData, output.cbData as usize).to_vec());
+            std::ptr::write_bytes(output.pbData, 0, output.cbData as usize);
+            LocalFree(output.pbData as _);
+            Ok(result)
+        }
+    }
+}
+
+// The host keystore wins when one is registered, so a Windows build with a
+// host-provided Hello integration does not fall back to DPAPI.
+fn device_protector() -> Result<Box<dyn KeyProtector>, Box<dyn std::error::Error>> {
+    let host = HOST_KEYSTORE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(host) = host.as_ref() {
+        return Ok(Box::new(HostKeystore { wrap: host.wrap, unwrap: host.unwrap, user_data: host.user_data }));
+    }
+    #[cfg(all(windows, feature = "dpapi"))]
+    return Ok(Box::new(dpapi::Dpapi));
+    #[cfg(not(all(windows, feature = "dpapi")))]
+    Err("No device keystore".into())
+}
+
+// Null callbacks unregister the keystore.
+#[no_mangle]
+pub extern "C" fn kyrie_set_keystore(wrap: Option<KeystoreCallback>, unwrap: Option<KeystoreCallback>, user_data: *mut c_void) -> i32 {
+    let keystore = match (wrap, unwrap) {
+        (Some(wrap), Some(unwrap)) => Some(HostKeystore { wrap, unwrap, user_data: user_data as usize }),
+        (None, None) => None,
+        _ => return errors::invalid_argument(),
+    };
+    *HOST_KEYSTORE.lock().unwrap_or_else(|e| e.into_inner()) = keystore;
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_enroll_device(path_ptr: *const c_char, password_ptr: *const u8, password_len: usize) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let result = device_protector().and_then(|protector| enroll_device(Path::new(path), password, protector.as_ref()));
+        match result {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Returns the number of slots removed.
+#[no_mangle]
+pub extern "C" fn kyrie_remove_device_slots(path_ptr: *const c_char, password_ptr: *const u8, password_len: usize) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match remove_device_slots(Path::new(path), password) {
+            Ok(removed) => removed as i32,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_with_device(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+
+        let result = device_protector()
+            .and_then(|protector| decrypt_with_device(input_path, output_path, protector.as_ref(), is_mobile, cpu_cores));
+        match result {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Slots are only ever added; a phone that is replaced is dropped together
+// with the others through remove_device_slots.
+pub fn enroll_device(path: &Path, password: &[u8], protector: &dyn KeyProtector) -> Result<(), Box<dyn std::error::Error>> {
+    reencrypt::rewrite_header(path, password, |header, master| {
+        let blob = protector.wrap(master)?;
+        if blob.is_empty() || blob.len() > MAX_DEVICE_BLOB_LEN {
+            return Err("Device keystore refused".into());
+        }
+        header.device_slots.push(DeviceSlot { kind: protector.kind(), blob });
+        Ok(())
+    })
+}
+
+pub fn remove_device_slots(path: &Path, password: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut removed = 0;
+    reencrypt::rewrite_header(path, password, |header, _| {
+        removed = header.device_slots.len();
+        header.device_slots.clear();
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+pub fn unlock_with_device(header: &ContainerHeader, protector: &dyn KeyProtector) -> Result<FileKey, Box<dyn std::error::Error>> {
+    for slot in header.device_slots.iter().filter(|slot| slot.kind == protector.kind()) {
+        let Ok(file_key) = protector.unwrap(&slot.blob) else {
+            continue;
+        };
+        if header.verify_file_id(&file_key).is_ok() {
+            return Ok(FileKey(file_key));
+        }
+    }
+    Err("No device slot for this keystore".into())
+}
+
+pub fn decrypt_with_device(
+    input_path: &str,
+    output_path: &str,
+    protector: &dyn KeyProtector,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (header, _) = read_header(Path::new(input_path))?;
+    let file_key = unlock_with_device(&header, protector)?;
+    decrypt_file_reporting(input_path, output_path, &file_key, is_mobile, cpu_cores, &ProgressSink::none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, encrypt_file_internal};
+    use aes_gcm::aead::{Aead, KeyInit};
+    use aes_gcm::{Aes256Gcm, Nonce};
+    use std::fs;
+
+    // Stands in for a hardware key; the filler makes its blobs as long as
+    // DPAPI's so they span several header fields.
+    struct SoftKeystore([u8; 32]);
+
+    impl KeyProtector for SoftKeystore {
+        fn kind(&self) -> u8 {
+            PROTECTOR_HOST_KEYSTORE
+        }
+
+        fn wrap(&self, file_key: &[u8; 32]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            let nonce: [u8; 12] = rand::random();
+            let sealed = Aes256Gcm::new_from_slice(&self.0)?.encrypt(Nonce::from_slice(&nonce), &file_key[..]).unwrap();
+            Ok([&nonce[..], &sealed, &[0u8; 250]].concat())
+        }
+
+        fn unwrap(&self, blob: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+            let (nonce, sealed) = blob[..60].split_at(12);
+            let file_key = Aes256Gcm::new_from_slice(&self.0)?.decrypt(Nonce::from_slice(nonce), sealed).map_err(|_| "Decryption failed")?;
+            Ok(Zeroizing::new(file_key.as_slice().try_into()?))
+        }
+    }
+
+    #[test]
+    fn test_enrolled_device_opens_without_the_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("passport.jpg");
+        let encrypted = dir.path().join("passport.kyl");
+        let opened = dir.path().join("opened.jpg");
+        fs::write(&plain, b"scan").unwrap();
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+        encrypt_file_internal(&path(&plain), &path(&encrypted), b"pw", None, false, 4).unwrap();
+
+        let phone = SoftKeystore(rand::random());
+        let tablet = SoftKeystore(rand::random());
+        assert!(enroll_device(&encrypted, b"wrong", &phone).is_err());
+        enroll_device(&encrypted, b"pw", &phone).unwrap();
+        enroll_device(&encrypted, b"pw", &tablet).unwrap();
+        assert_eq!(read_header(&encrypted).unwrap().0.device_slots.len(), 2);
+
+        decrypt_with_device(&path(&encrypted), &path(&opened), &tablet, false, 4).unwrap();
+        assert_eq!(fs::read(&opened).unwrap(), b"scan");
+        assert!(decrypt_with_device(&path(&encrypted), &path(&opened), &SoftKeystore(rand::random()), false, 4).is_err());
+        decrypt_file_internal(&path(&encrypted), &path(&opened), b"pw", false, 4).unwrap();
+
+        assert_eq!(remove_device_slots(&encrypted, b"pw").unwrap(), 2);
+        assert!(decrypt_with_device(&path(&encrypted), &path(&opened), &phone, false, 4).is_err());
+        assert_eq!(kyrie_set_keystore(None, None, std::ptr::null_mut()), 0);
+        assert!(device_protector().is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::errors;
+use crate::file_lock;
+use crate::format::read_header;
+use crate::kdf::{self, KeyPurpose};
+use crate::secure_temp::{SecureTempFile, TempContents};
+
+// NIST SP 800-38D allows at most 2^32 encryptions under one key with
+// random 96-bit nonces.
+pub const DEFAULT_MAX_MESSAGES: u64 = 1 << 32;
+// 2^44 blocks keeps the AES-GCM confidentiality bound (about blocks^2 /
+// 2^129) below 2^-40.
+pub const DEFAULT_MAX_BYTES: u64 = 1 << 48;
+const WARNING_FRACTION: f64 = 0.5;
+// Every file has a key of its own, so without a cap the ledger would grow
+// with every file ever written.
+const MAX_LEDGER_KEYS: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UsageLimits {
+    pub max_bytes: u64,
+    pub max_messages: u64,
+}
+
+const DEFAULT_LIMITS: UsageLimits = UsageLimits {
+    max_bytes: DEFAULT_MAX_BYTES,
+    max_messages: DEFAULT_MAX_MESSAGES,
+};
+
+// Tracking is off until the app names a ledger file.
+static LEDGER: Mutex<Option<PathBuf>> = Mutex::new(None);
+static LIMITS: Mutex<UsageLimits> = Mutex::new(DEFAULT_LIMITS);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyUsage {
+    pub bytes: u64,
+    pub messages: u64,
+}
+
+#[repr(C)]
+#[derive(Default, Debug, PartialEq)]
+pub struct KyrieKeyUsage {
+    pub bytes: u64,
+    pub messages: u64,
+    pub max_bytes: u64,
+    pub max_messages: u64,
+    pub warning: bool,
+    pub rotation_required: bool,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_key_usage_ledger(path_ptr: *const c_char) -> i32 {
+    let path = if path_ptr.is_null() {
+        None
+    } else {
+        match unsafe { CStr::from_ptr(path_ptr) }.to_str() {
+            Ok(s) => Some(PathBuf::from(s)),
+            Err(_) => return errors::invalid_argument(),
+        }
+    };
+    *LEDGER.lock().unwrap_or_else(|e| e.into_inner()) = path;
+    0
+}
+
+// Zero restores the default for that limit.
+#[no_mangle]
+pub extern "C" fn kyrie_set_key_usage_limits(max_bytes: u64, max_messages: u64) -> i32 {
+    *LIMITS.lock().unwrap_or_else(|e| e.into_inner()) = UsageLimits {
+        max_bytes: if max_bytes == 0 { DEFAULT_MAX_BYTES } else { max_bytes },
+        max_messages: if max_messages == 0 { DEFAULT_MAX_MESSAGES } else { max_messages },
+    };
+    0
+}
+
+// Reports on the key that encrypts the body of the given container, which
+// in-place edits and resumed writes go on using.
+#[no_mangle]
+pub extern "C" fn get_key_usage(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    usage_ptr: *mut KyrieKeyUsage,
+) -> i32 {
+    if path_ptr.is_null() || password_ptr.is_null() || usage_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    let Ok(path) = unsafe { CStr::from_ptr(path_ptr) }.to_str() else {
+        return errors::invalid_argument();
+    };
+    let password = unsafe { std::slice::from_raw_parts(password_ptr, password_len) };
+    match file_usage(Path::new(path), password) {
+        Ok(usage) => {
+            unsafe { *usage_ptr = summarize(usage, &limits()) };
+            0
+        }
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+fn file_usage(path: &Path, password: &[u8]) -> Result<KeyUsage, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    let key = zeroize::Zeroizing::new(header.master_key(password)?);
+    match LEDGER.lock().unwrap_or_else(|e| e.into_inner()).as_deref() {
+        Some(ledger) => usage_in(ledger, &header.body_key(&key)),
+        None => Ok(KeyUsage::default()),
+    }
+}
+
+fn limits() -> UsageLimits {
+    *LIMITS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Identifies a key in the ledger without revealing it.
+pub fn key_id(body_key: &[u8; 32]) -> String {
+    hex::encode(&kdf::subkey(body_key, KeyPurpose::KeyId)[..16])
+}
+
+pub fn summarize(usage: KeyUsage, limits: &UsageLimits) -> KyrieKeyUsage {
+    let fraction = (usage.bytes as f64 / limits.max_bytes as f64).max(usage.messages as f64 / limits.max_messages as f64);
+    KyrieKeyUsage {
+        bytes: usage.bytes,
+        messages: usage.messages,
+        max_bytes: limits.max_bytes,
+        max_messages: limits.max_messages,
+        warning: fraction >= WARNING_FRACTION,
+        rotation_required: fraction >= 1.0,
+    }
+}
+
+// Refuses work that would take the key past its limits, so the password
+// has to be rotated before more data is encrypted under it.
+pub fn check(body_key: &[u8; 32], bytes: u64, messages: u64) -> Result<(), Box<dyn std::error::Error>> {
+    match LEDGER.lock().unwrap_or_else(|e| e.into_inner()).as_deref() {
+        Some(path) => check_in(path, &limits(), body_key, bytes, messages),
+        None => Ok(()),
+    }
+}
+
+pub fn record(body_key: &[u8; 32], bytes: u64, messages: u64) -> Result<(), Box<dyn std::error::Error>> {
+    match LEDGER.lock().unwrap_or_else(|e| e.into_inner()).as_deref() {
+        Some(path) => record_in(path, body_key, bytes, messages),
+        None => Ok(()),
+    }
+}
+
+fn load(path: &Path) -> Result<BTreeMap<String, KeyUsage>, Box<dyn std::error::Error>> {
+    match fs::read(path) {
+        Ok(json) => Ok(serde_json::from_slice(&json)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn usage_in(path: &Path, body_key: &[u8; 32]) -> Result<KeyUsage, Box<dyn std::error::Error>> {
+    Ok(load(path)?.get(&key_id(body_key)).copied().unwrap_or_default())
+}
+
+fn check_in(
+    path: &Path,
+    limits: &UsageLimits,
+    body_key: &[u8; 32],
+    bytes: u64,
+    messages: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let usage = usage_in(path, body_key)?;
+    if usage.bytes.saturating_add(bytes) > limits.max_bytes || usage.messages.saturating_add(messages) > limits.max_messages {
+        return Err("Key usage limit reached".into());
+    }
+    Ok(())
+}
+
+// Other processes may share the ledger, so the update is made under its lock.
+fn record_in(path: &Path, body_key: &[u8; 32], bytes: u64, messages: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let _lock = file_lock::wait_for_output(path)?;
+    let mut ledger = load(path)?;
+    let id = key_id(body_key);
+    let usage = ledger.entry(id.clone()).or_default();
+    usage.bytes = usage.bytes.saturating_add(bytes);
+    usage.messages = usage.messages.saturating_add(messages);
+    // The keys that have done the least are furthest from any limit.
+    while ledger.len() > MAX_LEDGER_KEYS {
+        let least = ledger
+            .iter()
+            .filter(|(key, _)| **key != id)
+            .min_by_key(|(_, usage)| (usage.bytes, usage.messages))
+            .map(|(key, _)| key.clone());
+        match least {
+            Some(key) => ledger.remove(&key),
+            None => break,
+        };
+    }
+
+    let mut temp = SecureTempFile::for_target(path, TempContents::Metadata)?;
+    temp.file().write_all(&serde_json::to_vec(&ledger)?)?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_is_tracked_per_key_and_enforced() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = dir.path().join("usage.json");
+        let limits = UsageLimits { max_bytes: 1000, max_messages: 10 };
+        let (first, second) = ([1u8; 32], [2u8; 32]);
+
+        record_in(&ledger, &first, 600, 2).unwrap();
+        record_in(&ledger, &first, 100, 1).unwrap();
+        record_in(&ledger, &second, 10, 1).unwrap();
+        assert_eq!(usage_in(&ledger, &first).unwrap(), KeyUsage { bytes: 700, messages: 3 });
+
+        let summary = summarize(usage_in(&ledger, &first).unwrap(), &limits);
+        assert!(summary.warning && !summary.rotation_required);
+        assert!(!summarize(usage_in(&ledger, &second).unwrap(), &limits).warning);
+
+        assert!(check_in(&ledger, &limits, &first, 300, 1).is_ok());
+        let refused = check_in(&ledger, &limits, &first, 301, 1).unwrap_err();
+        assert_eq!(errors::classify(refused.as_ref()), errors::ERR_KEY_ROTATION_REQUIRED);
+        assert!(check_in(&ledger, &limits, &second, 0, 10).is_err());
+        assert!(!fs::read_to_string(&ledger).unwrap().contains(&hex::encode([1u8; 32])));
+    }
+
+    #[test]
+    fn test_ledger_keeps_the_most_used_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = dir.path().join("usage.json");
+        let full: BTreeMap<_, _> =
+            (0..MAX_LEDGER_KEYS as u64).map(|index| (format!("{index:064x}"), KeyUsage { bytes: 10 + index, messages: 1 })).collect();
+        fs::write(&ledger, serde_json::to_vec(&full).unwrap()).unwrap();
+
+        record_in(&ledger, &[1u8; 32], 1, 1).unwrap();
+        let kept = load(&ledger).unwrap();
+        assert_eq!(kept.len(), MAX_LEDGER_KEYS);
+        assert!(!kept.contains_key(&format!("{:064x}", 0)) && kept.contains_key(&format!("{:064x}", 1)));
+        assert_eq!(usage_in(&ledger, &[1u8; 32]).unwrap(), KeyUsage { bytes: 1, messages: 1 });
+        assert!(!dir.path().join(".usage.json.kyrie-lock").exists());
+    }
+
+    #[test]
+    fn test_encrypting_a_file_counts_against_its_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = dir.path().join("usage.json");
+        let plain = dir.path().join("plain.bin");
+        let encrypted = dir.path().join("plain.kyl");
+        fs::write(&plain, vec![5u8; 3000]).unwrap();
+
+        let ledger_path = std::ffi::CString::new(ledger.to_str().unwrap()).unwrap();
+        assert_eq!(kyrie_set_key_usage_ledger(ledger_path.as_ptr()), 0);
+        let result = crate::encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4);
+        let usage = file_usage(&encrypted, b"pw");
+        kyrie_set_key_usage_ledger(std::ptr::null());
+        result.unwrap();
+        let usage = usage.unwrap();
+        assert!(usage.bytes >= 3000 && usage.messages == 1);
+    }
+}
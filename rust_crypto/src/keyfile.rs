@@ -0,0 +1,160 @@
+use sha2::{Digest, Sha256};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::kdf::{self, KdfParams, KeyProvider};
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::{decrypt_file_reporting, encrypt_file_reporting, errors, EncryptOptions};
+
+const KEYFILE_CONTEXT: &[u8] = b"KYRIE_LOCK keyfile";
+
+// The password followed by a hash of the keyfile goes through the KDF, so
+// neither factor alone gets past the Argon2 work.
+pub struct KeyfileKey<'a> {
+    password: &'a [u8],
+    keyfile_hash: Zeroizing<[u8; 32]>,
+}
+
+impl<'a> KeyfileKey<'a> {
+    pub fn open(password: &'a [u8], keyfile_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut hasher = Sha256::new();
+        hasher.update(KEYFILE_CONTEXT);
+        io::copy(&mut File::open(keyfile_path)?, &mut hasher)?;
+        Ok(KeyfileKey { password, keyfile_hash: Zeroizing::new(hasher.finalize().into()) })
+    }
+}
+
+impl KeyProvider for KeyfileKey<'_> {
+    fn key_for(&self, salt: &[u8], params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        let combined = Zeroizing::new([self.password, &self.keyfile_hash[..]].concat());
+        kdf::derive(&combined, salt, params)
+    }
+
+    fn uses_keyfile(&self) -> bool {
+        true
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_with_keyfile(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    keyfile_path_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let keyfile_path = match CStr::from_ptr(keyfile_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let options = EncryptOptions { hint, padding: OptionsProfile::current().padding, ..Default::default() };
+        let result = KeyfileKey::open(password, Path::new(keyfile_path)).and_then(|keys| {
+            encrypt_file_reporting(input_path, output_path, &keys, &options, is_mobile, cpu_cores, &ProgressSink::none())
+        });
+        match result {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// A null keyfile path decrypts with the password alone, so callers can try
+// that first and ask for the keyfile on ERR_KEYFILE_REQUIRED.
+#[no_mangle]
+pub extern "C" fn decrypt_file_with_keyfile(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    keyfile_path_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let result = if keyfile_path_ptr.is_null() {
+            decrypt_file_reporting(input_path, output_path, password, is_mobile, cpu_cores, &ProgressSink::none())
+        } else {
+            let keyfile_path = match CStr::from_ptr(keyfile_path_ptr).to_str() {
+                Ok(s) => s,
+                Err(_) => return errors::invalid_argument(),
+            };
+            KeyfileKey::open(password, Path::new(keyfile_path)).and_then(|keys| {
+                decrypt_file_reporting(input_path, output_path, &keys, is_mobile, cpu_cores, &ProgressSink::none())
+            })
+        };
+        match result {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_internal;
+    use crate::errors::{classify, ERR_KEYFILE_REQUIRED, ERR_WRONG_PASSWORD};
+    use crate::format::read_header;
+    use std::fs;
+
+    #[test]
+    fn test_keyfile_is_needed_alongside_the_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let keyfile = dir.path().join("photo.jpg");
+        let other = dir.path().join("other.jpg");
+        let encrypted = dir.path().join("plain.kyl");
+        let decrypted = dir.path().join("out.txt");
+        fs::write(&plain, b"two factors").unwrap();
+        fs::write(&keyfile, b"keyfile bytes").unwrap();
+        fs::write(&other, b"other bytes").unwrap();
+
+        let keys = KeyfileKey::open(b"pw", &keyfile).unwrap();
+        let (input, output) = (plain.to_str().unwrap(), encrypted.to_str().unwrap());
+        encrypt_file_reporting(input, output, &keys, &EncryptOptions::default(), false, 4, &ProgressSink::none()).unwrap();
+        assert!(read_header(&encrypted).unwrap().0.keyfile);
+
+        let out = decrypted.to_str().unwrap();
+        let error = decrypt_file_internal(output, out, b"pw", false, 4).unwrap_err();
+        assert_eq!(classify(error.as_ref()), ERR_KEYFILE_REQUIRED);
+        let wrong = KeyfileKey::open(b"pw", &other).unwrap();
+        let error = decrypt_file_reporting(output, out, &wrong, false, 4, &ProgressSink::none()).unwrap_err();
+        assert_eq!(classify(error.as_ref()), ERR_WRONG_PASSWORD);
+
+        decrypt_file_reporting(output, out, &keys, false, 4, &ProgressSink::none()).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"two factors");
+    }
+}
@@ -0,0 +1,341 @@
+// Safe entry points for Rust callers. The core extern "C" functions are thin
+// wrappers over these.
+use std::ffi::c_void;
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::duress;
+use crate::errors::{self, ERR_CORRUPTED_CHUNK, ERR_INVALID_ARGUMENT};
+use crate::format::read_header;
+use crate::pipe::{decrypt_stream_internal, encrypt_stream_internal};
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::reencrypt;
+use crate::stats;
+use crate::upgrade;
+use crate::verify::{verify_file_internal, VerifyOutcome};
+use crate::{decrypt_file_reporting, decrypt_file_to_memory_internal, encrypt_file_reporting, inspect, text, EncryptOptions};
+
+pub use crate::inspect::KyrieFileInfo;
+pub use crate::padding::{bucket_padding, PADDING_NONE, PADDING_PADME, PADDING_POWER_OF_TWO, PADDING_SLOTS};
+pub use crate::progress::{
+    PROGRESS_PHASE_DECRYPTING, PROGRESS_PHASE_DERIVING_KEY, PROGRESS_PHASE_ENCRYPTING, PROGRESS_PHASE_READING, PROGRESS_PHASE_WRITING,
+};
+pub use crate::stats::KyrieStats;
+
+pub type Result<T> = std::result::Result<T, KyrieError>;
+
+// Called with a PROGRESS_PHASE_* value and a fraction of 0 to 1, on
+// whichever thread is doing the work.
+pub type ProgressListener<'a> = &'a (dyn Fn(i32, f64) + Sync);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KyrieError {
+    code: i32,
+    message: String,
+}
+
+impl KyrieError {
+    // One of the ERR_* codes reported over FFI.
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn invalid_argument(message: &str) -> Self {
+        KyrieError { code: ERR_INVALID_ARGUMENT, message: message.to_string() }
+    }
+}
+
+impl fmt::Display for KyrieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for KyrieError {}
+
+impl From<Box<dyn std::error::Error>> for KyrieError {
+    fn from(error: Box<dyn std::error::Error>) -> Self {
+        KyrieError { code: errors::classify(error.as_ref()), message: error.to_string() }
+    }
+}
+
+pub struct Encryptor<'a> {
+    password: &'a [u8],
+    hint: Option<&'a str>,
+    padding: u8,
+    private_hint: bool,
+    hint_password: Option<&'a [u8]>,
+    omit_magic: bool,
+    compact: bool,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: Option<ProgressListener<'a>>,
+}
+
+impl<'a> Encryptor<'a> {
+    pub fn new(password: &'a [u8]) -> Self {
+        Encryptor {
+            password,
+            hint: None,
+            padding: OptionsProfile::current().padding,
+            private_hint: false,
+            hint_password: None,
+            omit_magic: false,
+            compact: false,
+            is_mobile: false,
+            cpu_cores: default_cpu_cores(),
+            progress: None,
+        }
+    }
+
+    pub fn with_hint(mut self, hint: Option<&'a str>) -> Self {
+        self.hint = hint;
+        self
+    }
+
+    pub fn with_padding(mut self, padding: u8) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    // Keeps the hint at one length, cut to the fixed slot, and seals it when
+    // there is a hint password; get_private_hint opens it again.
+    pub fn with_private_hint(mut self, hint_password: Option<&'a [u8]>) -> Self {
+        self.private_hint = true;
+        self.hint_password = hint_password;
+        self
+    }
+
+    // Masks the whole file, magic string included, so it reads as random
+    // bytes. Only decrypt_file sees through the mask.
+    pub fn without_magic(mut self, omit_magic: bool) -> Self {
+        self.omit_magic = omit_magic;
+        self
+    }
+
+    // Small files get a header with only what opening them takes: no name,
+    // mtime, checksum index or extra key slots. Pair it with bucket_padding
+    // to hide their exact sizes as well.
+    pub fn compact(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+
+    pub fn for_mobile(mut self, is_mobile: bool) -> Self {
+        self.is_mobile = is_mobile;
+        self
+    }
+
+    pub fn with_cpu_cores(mut self, cpu_cores: usize) -> Self {
+        self.cpu_cores = cpu_cores;
+        self
+    }
+
+    pub fn with_progress(mut self, listener: ProgressListener<'a>) -> Self {
+        self.progress = Some(listener);
+        self
+    }
+
+    pub fn encrypt_file(&self, input: &Path, output: &Path) -> Result<KyrieStats> {
+        let options = EncryptOptions {
+            hint: self.hint,
+            padding: self.padding,
+            private_hint: self.private_hint,
+            hint_password: self.hint_password,
+            omit_magic: self.omit_magic,
+            compact: self.compact,
+            ..Default::default()
+        };
+        Ok(stats::collect(progress_sink(&self.progress), self.is_mobile, self.cpu_cores, |progress| {
+            encrypt_file_reporting(input, output, self.password, &options, self.is_mobile, self.cpu_cores, progress).map(|_| ())
+        })?)
+    }
+
+    // Neither side is seeked, so either may be a pipe.
+    pub fn encrypt_stream<R: Read, W: Write>(&self, input: R, output: W) -> Result<KyrieStats> {
+        Ok(stats::collect(progress_sink(&self.progress), self.is_mobile, self.cpu_cores, |progress| {
+            encrypt_stream_internal(input, output, self.password, self.hint, self.padding, self.is_mobile, self.cpu_cores, progress)
+        })?)
+    }
+}
+
+pub struct Decryptor<'a> {
+    password: &'a [u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: Option<ProgressListener<'a>>,
+}
+
+impl<'a> Decryptor<'a> {
+    pub fn new(password: &'a [u8]) -> Self {
+        Decryptor { password, is_mobile: false, cpu_cores: default_cpu_cores(), progress: None }
+    }
+
+    pub fn for_mobile(mut self, is_mobile: bool) -> Self {
+        self.is_mobile = is_mobile;
+        self
+    }
+
+    pub fn with_cpu_cores(mut self, cpu_cores: usize) -> Self {
+        self.cpu_cores = cpu_cores;
+        self
+    }
+
+    pub fn with_progress(mut self, listener: ProgressListener<'a>) -> Self {
+        self.progress = Some(listener);
+        self
+    }
+
+    pub fn decrypt_file(&self, input: &Path, output: &Path) -> Result<KyrieStats> {
+        Ok(stats::collect(progress_sink(&self.progress), self.is_mobile, self.cpu_cores, |progress| {
+            decrypt_file_reporting(input, output, self.password, self.is_mobile, self.cpu_cores, progress)
+        })?)
+    }
+
+    // Plaintext is written as it authenticates, so after an error the output
+    // holds a prefix that must be thrown away.
+    pub fn decrypt_stream<R: Read, W: Write>(&self, input: R, output: W) -> Result<KyrieStats> {
+        Ok(stats::collect(progress_sink(&self.progress), self.is_mobile, self.cpu_cores, |progress| {
+            decrypt_stream_internal(input, output, self.password, self.is_mobile, self.cpu_cores, progress)
+        })?)
+    }
+
+    pub fn decrypt_to_vec(&self, input: &Path) -> Result<Vec<u8>> {
+        Ok(decrypt_file_to_memory_internal(path_str(input)?, self.password, self.is_mobile, self.cpu_cores)?)
+    }
+}
+
+// What can be learned about a container without its password.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileHeader {
+    pub hint: Vec<u8>,
+    pub file_id: Option<String>,
+    pub padding: u8,
+    pub convergent: bool,
+    pub header_len: u64,
+}
+
+impl FileHeader {
+    pub fn read(path: &Path) -> Result<Self> {
+        let (header, header_len) = read_header(path)?;
+        Ok(FileHeader {
+            hint: text::readable_hint(&header.hint).to_vec(),
+            file_id: header.file_id.map(|id| id.to_uuid_string()),
+            padding: header.padding,
+            convergent: header.convergent.is_some(),
+            header_len,
+        })
+    }
+}
+
+// Opens every chunk without writing any plaintext out.
+pub fn verify_file(path: &Path, password: &[u8]) -> Result<()> {
+    match verify_file_internal(path, password)? {
+        VerifyOutcome::Ok => Ok(()),
+        VerifyOutcome::WrongPassword => Err(KyrieError::from(Box::<dyn std::error::Error>::from("Invalid password"))),
+        VerifyOutcome::CorruptedAtChunk(index) => {
+            Err(KyrieError { code: ERR_CORRUPTED_CHUNK, message: format!("Corrupted chunk {}", index) })
+        }
+    }
+}
+
+pub fn change_password(path: &Path, old_password: &[u8], new_password: &[u8]) -> Result<()> {
+    Ok(reencrypt::change_password(path, old_password, new_password, false)?)
+}
+
+// Each password opens its own payload, and nothing in the file tells which
+// of the two is the real one.
+pub fn encrypt_with_decoy(
+    real_input: &Path,
+    decoy_input: &Path,
+    output: &Path,
+    real_password: &[u8],
+    decoy_password: &[u8],
+    hint: Option<&str>,
+) -> Result<()> {
+    Ok(duress::encrypt_with_decoy_internal(real_input, decoy_input, output, real_password, decoy_password, hint, false)?)
+}
+
+// Returns whether the file needed upgrading.
+pub fn upgrade_file(path: &Path, password: &[u8]) -> Result<bool> {
+    Ok(upgrade::upgrade_file_internal(path, password, false)?)
+}
+
+pub fn file_info(path: &Path) -> Result<KyrieFileInfo> {
+    Ok(inspect::file_info(path)?)
+}
+
+// The listener is borrowed for as long as the sink, which keeps the pointer
+// handed to the trampoline alive.
+fn progress_sink(listener: &Option<ProgressListener>) -> ProgressSink {
+    match listener {
+        Some(listener) => ProgressSink::new(Some(forward_progress), listener as *const ProgressListener as *mut c_void),
+        None => ProgressSink::none(),
+    }
+}
+
+extern "C" fn forward_progress(phase: i32, fraction: f64, user_data: *mut c_void) {
+    let listener = unsafe { &*(user_data as *const ProgressListener) };
+    listener(phase, fraction);
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| KyrieError::invalid_argument("Path is not valid UTF-8"))
+}
+
+fn default_cpu_cores() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ERR_WRONG_PASSWORD;
+    use std::fs;
+
+    #[test]
+    fn test_safe_api_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        let decrypted = dir.path().join("plain.out");
+        fs::write(&plain, b"no unsafe needed").unwrap();
+
+        Encryptor::new(b"pw").with_hint(Some("hint")).with_cpu_cores(2).encrypt_file(&plain, &encrypted).unwrap();
+        let header = FileHeader::read(&encrypted).unwrap();
+        assert_eq!(header.hint, b"hint");
+        assert!(header.file_id.is_some());
+
+        let stats = Decryptor::new(b"pw").decrypt_file(&encrypted, &decrypted).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"no unsafe needed");
+        assert_eq!((stats.chunks, stats.threads), (1, 1));
+        let error = Decryptor::new(b"wrong").decrypt_to_vec(&encrypted).unwrap_err();
+        assert_eq!(error.code(), ERR_WRONG_PASSWORD);
+        assert_eq!(errors::classify(&error), ERR_WRONG_PASSWORD);
+    }
+
+    #[test]
+    fn test_cli_operations_report_progress_and_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        fs::write(&plain, b"scripted").unwrap();
+
+        let phases = std::sync::Mutex::new(Vec::new());
+        let listener = |phase: i32, _fraction: f64| phases.lock().unwrap().push(phase);
+        Encryptor::new(b"pw").with_progress(&listener).encrypt_file(&plain, &encrypted).unwrap();
+        assert!(phases.lock().unwrap().contains(&PROGRESS_PHASE_WRITING));
+
+        verify_file(&encrypted, b"pw").unwrap();
+        assert_eq!(verify_file(&encrypted, b"wrong").unwrap_err().code(), ERR_WRONG_PASSWORD);
+        change_password(&encrypted, b"pw", b"new").unwrap();
+        assert_eq!(Decryptor::new(b"new").decrypt_to_vec(&encrypted).unwrap(), b"scripted");
+        assert_eq!(file_info(&encrypted).unwrap().plaintext_size, 8);
+    }
+}
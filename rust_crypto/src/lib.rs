@@ -1,23 +1,163 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, AeadInPlace, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
 use rayon::prelude::*;
 use sha2::{Digest, Sha256};
 use std::slice;
-use std::sync::Arc;
 use std::fs::File;
 use std::io::{Read, Write, BufReader, BufWriter, Seek, SeekFrom};
 use std::ffi::CStr;
 use std::os::raw::c_char;
-use rand::RngCore;
+use std::time::Instant;
+use zeroize::{Zeroize, Zeroizing};
 
-const NONCE_SIZE: usize = 12;
-const TAG_SIZE: usize = 16;
-const MAGIC_STRING: &[u8] = b"KYRIE_LOCK";
+mod acceleration;
+mod app_metadata;
+mod archive;
+mod audit;
+#[cfg(feature = "async")]
+pub mod async_api;
+mod batch;
+#[cfg(feature = "uniffi")]
+mod bindings;
+mod buffer;
+mod cancel;
+mod capture;
+mod cipher;
+pub mod codec;
+mod compact;
+mod config;
+mod convergent;
+mod decrypt_cache;
+mod device_key;
+mod diagnostics;
+mod digest;
+mod duress;
+mod errors;
+mod escrow;
+#[cfg(any(unix, windows))]
+mod fd;
+mod file_lock;
+mod format;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzzing;
+mod handles;
+mod header_backup;
+#[cfg(feature = "http")]
+mod http;
+mod in_place;
+mod inspect;
+mod journal;
+mod json_api;
+mod kdf;
+mod keyfile;
+mod key_protector;
+mod key_usage;
+pub mod kyrie_core;
+mod lint;
+mod maintenance;
+mod manifest;
+mod mapped;
+mod media;
+mod metadata;
+mod metrics;
+mod name_vault;
+mod naming;
+mod nonce;
+mod operations;
+mod padding;
+mod parity;
+mod password;
+mod pipe;
+mod pipeline;
+#[cfg(not(target_arch = "wasm32"))]
+mod plaintext_view;
+mod preview;
+mod privacy;
+mod previous_password;
+mod profile;
+mod progress;
+mod quorum;
+mod range;
+mod raw;
+mod recipient;
+mod recovery_kit;
+mod reencrypt;
+mod repair;
+mod resume;
+mod rng;
+#[cfg(feature = "s3")]
+mod s3;
+mod salvage;
+mod seal;
+mod secure_memory;
+mod secure_temp;
+mod self_test;
+mod session;
+mod shamir;
+mod share_key;
+mod shred;
+mod shutdown;
+mod spec;
+mod sparse;
+mod split;
+mod stats;
+mod storage;
+mod streaming;
+mod strict;
+mod text;
+mod throttle;
+mod tuning;
+mod undo;
+mod upgrade;
+mod vault;
+mod vault_sync;
+mod vaults;
+mod verify;
+mod volume;
+pub mod wasm;
+#[cfg(not(target_arch = "wasm32"))]
+mod watcher;
+#[cfg(feature = "webdav")]
+mod webdav;
+mod wide_path;
+
+use cipher::BodyCipher;
+use digest::ContentHasher;
+use format::{ContainerHeader, ContainerReader};
+use kdf::{KdfParams, KeyProvider};
+use manifest::ManifestBuilder;
+use nonce::NonceSequence;
+use padding::PaddingStripper;
+use secure_temp::{SecureTempFile, TempContents};
+use progress::{
+    ProgressSink, PROGRESS_PHASE_DECRYPTING, PROGRESS_PHASE_DERIVING_KEY, PROGRESS_PHASE_ENCRYPTING,
+    PROGRESS_PHASE_READING, PROGRESS_PHASE_WRITING,
+};
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!("kyrielock");
+
+#[cfg(test)]
+mod test_util;
+
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+pub const MAGIC_STRING: &[u8] = b"KYRIE_LOCK";
 const VERSION: u32 = 1;
 const HEADER_SIZE: usize = 14;
 const MAX_HINT_LENGTH: usize = 32;
+// Longer hints are kept whole in a header field.
+const MAX_LONG_HINT_LENGTH: usize = 1024;
+const MAX_CHUNK_SIZE: usize = 256 * 1024 * 1024;
+const ENCRYPTED_EXTENSION: &str = "kyl";
+const SMALL_FILE_THRESHOLD: usize = 1024 * 1024;
+// encrypt_data_v2 blobs start with magic || salt || KDF params, which are
+// authenticated along with the caller's AAD. Blobs without the magic are
+// nonce || ciphertext || tag under the legacy key.
+const DATA_BLOB_MAGIC: &[u8] = b"KYD2";
+const DATA_BLOB_PREFIX_LEN: usize = DATA_BLOB_MAGIC.len() + kdf::KDF_SALT_SIZE + format::KDF_PARAMS_SIZE;
 
 fn derive_key(password: &[u8]) -> [u8; 32] {
     let mut hasher = Sha256::new();
@@ -28,19 +168,79 @@ fn derive_key(password: &[u8]) -> [u8; 32] {
     key
 }
 
-fn get_chunk_size(is_mobile: bool) -> usize {
-    if is_mobile {
-        128 * 1024 * 1024
-    } else {
-        256 * 1024 * 1024
+fn write_header<W: Write>(writer: &mut W, header: &ContainerHeader) -> std::io::Result<()> {
+    writer.write_all(&header.encode())
+}
+
+fn write_frame<W: Write>(
+    output: &mut W,
+    cipher: &BodyCipher,
+    nonce_seq: &mut NonceSequence,
+    chunk: &[u8],
+    single_chunk: bool,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let nonce_bytes = nonce_seq.next_nonce()?;
+    let encrypted = cipher.encrypt(Nonce::from_slice(&nonce_bytes), chunk)
+        .map_err(|_| "Encryption failed")?;
+    Ok(write_encrypted_frame(output, nonce_seq.stored(&nonce_bytes), &encrypted, single_chunk)?)
+}
+
+// Returns the CRC32 of the frame exactly as stored, for the keyless
+// corruption scan.
+fn write_encrypted_frame<W: Write>(
+    output: &mut W,
+    nonce_bytes: &[u8],
+    encrypted: &[u8],
+    single_chunk: bool,
+) -> std::io::Result<u32> {
+    let mut checksum = crc32fast::Hasher::new();
+    checksum.update(nonce_bytes);
+    output.write_all(nonce_bytes)?;
+    if !single_chunk {
+        let len_bytes = (encrypted.len() as u32).to_be_bytes();
+        checksum.update(&len_bytes);
+        output.write_all(&len_bytes)?;
     }
+    checksum.update(encrypted);
+    output.write_all(encrypted)?;
+    Ok(checksum.finalize())
+}
+
+fn read_chunk<R: Read>(reader: &mut R, chunk: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < chunk.len() {
+        match reader.read(&mut chunk[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[derive(Default)]
+struct EncryptOptions<'a> {
+    hint: Option<&'a str>,
+    padding: u8,
+    share_policy: Option<format::SharePolicy>,
+    recipients: Vec<format::EscrowSlot>,
+    key_purpose: Option<u8>,
+    convergent: Option<format::SealedContentKey>,
+    kdf: Option<kdf::KdfParams>,
+    kdf_salt: Option<Vec<u8>>,
+    backup: bool,
+    cipher: u8,
+    preview: Option<&'a [u8]>,
+    private_hint: bool,
+    hint_password: Option<&'a [u8]>,
+    omit_magic: bool,
+    compact: bool,
 }
 
-fn get_parallel_batch_threshold(is_mobile: bool) -> usize {
+fn get_chunk_size(is_mobile: bool) -> usize {
     if is_mobile {
-        512 * 1024 * 1024
+        128 * 1024 * 1024
     } else {
-        1024 * 1024 * 1024
+        MAX_CHUNK_SIZE
     }
 }
 
@@ -52,12 +252,14 @@ fn get_parallel_batch_size(cpu_cores: usize, is_mobile: bool) -> usize {
     }
 }
 
-fn generate_nonce() -> [u8; NONCE_SIZE] {
-    let mut nonce = [0u8; NONCE_SIZE];
-    rand::thread_rng().fill_bytes(&mut nonce);
-    nonce
+fn random_nonce() -> [u8; NONCE_SIZE] {
+    rng::random()
 }
 
+// The entry points below predate the unsafe-block style of the other
+// modules and read their pointer arguments directly; callers go through the
+// C ABI, where marking them unsafe would change nothing.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn encrypt_file(
     input_path_ptr: *const c_char,
@@ -71,11 +273,11 @@ pub extern "C" fn encrypt_file(
     unsafe {
         let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
             Ok(s) => s,
-            Err(_) => return -1,
+            Err(_) => return errors::invalid_argument(),
         };
         let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
             Ok(s) => s,
-            Err(_) => return -1,
+            Err(_) => return errors::invalid_argument(),
         };
         let password = slice::from_raw_parts(password_ptr, password_len);
         let hint = if hint_ptr.is_null() {
@@ -84,9 +286,13 @@ pub extern "C" fn encrypt_file(
             CStr::from_ptr(hint_ptr).to_str().ok()
         };
 
-        match encrypt_file_internal(input_path, output_path, password, hint, is_mobile, cpu_cores) {
+        let encryptor = kyrie_core::Encryptor::new(password)
+            .with_hint(hint)
+            .for_mobile(is_mobile)
+            .with_cpu_cores(cpu_cores);
+        match encryptor.encrypt_file(std::path::Path::new(input_path), std::path::Path::new(output_path)) {
             Ok(_) => 0,
-            Err(_) => -2,
+            Err(e) => errors::fail(&e),
         }
     }
 }
@@ -99,127 +305,480 @@ fn encrypt_file_internal(
     is_mobile: bool,
     cpu_cores: usize,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let chunk_size = get_chunk_size(is_mobile);
-    let parallel_threshold = get_parallel_batch_threshold(is_mobile);
-    let batch_size = get_parallel_batch_size(cpu_cores, is_mobile);
+    let options = EncryptOptions { hint, padding: profile::OptionsProfile::current().padding, ..Default::default() };
+    encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &ProgressSink::none())?;
+    Ok(())
+}
+
+fn encrypt_file_reporting<K: KeyProvider + ?Sized>(
+    input_path: impl AsRef<std::path::Path>,
+    output_path: impl AsRef<std::path::Path>,
+    keys: &K,
+    options: &EncryptOptions,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let result = encrypt_file_unlogged(input_path.as_ref(), output_path.as_ref(), keys, options, is_mobile, cpu_cores, progress);
+    audit::record("encrypt", input_path.as_ref(), started, &result);
+    result
+}
+
+fn encrypt_file_unlogged<K: KeyProvider + ?Sized>(
+    input_path: &std::path::Path,
+    output_path: &std::path::Path,
+    keys: &K,
+    options: &EncryptOptions,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    secure_temp::check_target(output_path)?;
+    let _lock = file_lock::lock_output(output_path)?;
+    // Convergent containers must stay byte-identical for identical content,
+    // so they carry no name or mtime.
+    let file_metadata = match options.convergent {
+        Some(_) => None,
+        None => Some(metadata::FileMetadata::for_input(input_path)?),
+    };
+    
+    // The container goes to a temp file next to the output, which only
+    // replaces it once complete; a failed or cancelled run leaves nothing
+    // behind.
+    let mut temp = SecureTempFile::for_target(output_path, TempContents::Ciphertext)?;
+    let output = handles::ReleasableFile::create(temp.path(), progress.handles())?;
+    let digest = encrypt_into(PlainInput::Path(input_path), output, keys, options, file_metadata.as_ref(), is_mobile, cpu_cores, progress)?;
+    if options.omit_magic {
+        privacy::conceal(temp.file(), keys)?;
+    }
+    temp.persist(output_path)?;
+    Ok(digest)
+}
+
+// Where encrypt_into takes the plaintext from.
+#[derive(Clone, Copy)]
+enum PlainInput<'a> {
+    Path(&'a std::path::Path),
+    Memory(&'a [u8]),
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encrypt_into<K: KeyProvider + ?Sized, W: Write + Seek>(
+    input: PlainInput,
+    output: W,
+    keys: &K,
+    options: &EncryptOptions,
+    file_metadata: Option<&metadata::FileMetadata>,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let config = config::KyrieConfig::current();
+    let chunk_size = config.chunk_size(is_mobile);
+    let batch_size = config.parallel_batch_size(cpu_cores, is_mobile);
     
-    let input_file = File::open(input_path)?;
-    let file_size = input_file.metadata()?.len() as usize;
+    // Holes in a sparse input are left out of the body. Convergent files
+    // must not depend on how the input is stored, and padding hides a size
+    // the hole map would give away.
+    let holes = match input {
+        PlainInput::Path(input_path) if options.convergent.is_none() && options.padding == padding::PADDING_NONE => sparse::find_holes(input_path)?,
+        _ => Vec::new(),
+    };
+    let (content_size, source): (u64, Box<dyn Read + Send + '_>) = match input {
+        PlainInput::Path(input_path) => {
+            let input_file = handles::ReleasableFile::open(input_path, progress.handles())?;
+            let len = input_file.metadata()?.len();
+            match holes.is_empty() {
+                true => (len, Box::new(BufReader::new(input_file))),
+                false => (len.saturating_sub(sparse::hole_bytes(&holes)), Box::new(BufReader::new(sparse::ExtentReader::new(input_file, &holes)))),
+            }
+        }
+        PlainInput::Memory(data) => (data.len() as u64, Box::new(data)),
+    };
+    let file_size = padding::padded_len(content_size, options.padding) as usize;
+    
+    // The other slot is random fill, so the file looks the same as one
+    // that also holds a payload for a duress password.
+    if options.padding == padding::PADDING_SLOTS {
+        let unsupported = options.share_policy.is_some() || !options.recipients.is_empty() || options.convergent.is_some() || options.preview.is_some() || options.private_hint;
+        if unsupported || !keys.derives_from_password() || keys.uses_keyfile() {
+            return Err("Invalid argument".into());
+        }
+        let mut header = duress::slot_header(options.hint, is_mobile);
+        if let Some(kdf) = &options.kdf {
+            header.kdf = kdf.clone();
+        }
+        if let Some(salt) = &options.kdf_salt {
+            header.kdf_salt = salt.clone();
+        }
+        let mut source = source;
+        let mut hasher = ContentHasher::new(content_size);
+        let mut output_file = BufWriter::new(output);
+        let slots = [Some(duress::SlotPayload { input: &mut *source, len: content_size, keys }), None];
+        let key = duress::write_slots(&mut output_file, header, slots, chunk_size, |chunk| hasher.update(chunk))?.ok_or("Invalid argument")?;
+        output_file.flush()?;
+        return Ok(digest::keyed(&key, &hasher.finalize()));
+    }
+    
+    let hint_bytes = text::hint_bytes(options.hint);
+    
+    let mut header = ContainerHeader {
+        hint: hint_bytes,
+        padding: options.padding,
+        nonce_scheme: format::NONCE_SCHEME_COUNTER,
+        key_purpose: options.key_purpose.unwrap_or(kdf::KEY_PURPOSE_FILE_DATA),
+        cipher: cipher::resolve_suite(options.cipher)?,
+        share_policy: options.share_policy.clone(),
+        recipients: options.recipients.clone(),
+        convergent: options.convergent.clone(),
+        backup: options.backup || header_backup::enabled(),
+        holes,
+        ..Default::default()
+    };
+    if keys.derives_from_password() {
+        header.use_password_kdf(is_mobile);
+        header.keyfile = keys.uses_keyfile();
+        if let Some(kdf) = &options.kdf {
+            header.kdf = kdf.clone();
+        }
+        if let Some(salt) = &options.kdf_salt {
+            header.kdf_salt = salt.clone();
+        }
+    }
+    if options.private_hint {
+        privacy::protect_hint(&mut header, options.hint_password)?;
+    }
+    header.set_framing(file_size <= chunk_size, chunk_size, file_size.div_ceil(chunk_size).max(1));
     
-    let mut output_file = BufWriter::new(File::create(output_path)?);
+    // A file over the chunk size is framed as several chunks even when it
+    // is small enough to read in one go.
+    if file_size <= SMALL_FILE_THRESHOLD.min(chunk_size) {
+        return encrypt_small_file(source, output, keys, &header, file_metadata, options, progress);
+    }
     
-    let hint_bytes = hint
-        .map(|h| h.as_bytes())
-        .unwrap_or(&[])
-        .iter()
-        .take(MAX_HINT_LENGTH)
-        .copied()
-        .collect::<Vec<u8>>();
-    let hint_len = hint_bytes.len() as u8;
+    let mut output_file = BufWriter::new(output);
+    let mut reader = source.chain(padding::padding_reader(content_size, options.padding));
     
-    output_file.write_all(MAGIC_STRING)?;
-    output_file.write_all(&VERSION.to_le_bytes())?;
-    output_file.write_all(&[hint_len])?;
-    output_file.write_all(&hint_bytes)?;
+    progress.report(PROGRESS_PHASE_DERIVING_KEY, 0.0);
+    let key = Zeroizing::new(header.new_master_key(keys)?);
+    let cipher = header.body_cipher(&key)?;
+    progress.report(PROGRESS_PHASE_DERIVING_KEY, 1.0);
+    if keys.derives_from_password() {
+        header.escrow = escrow::configured_slots(&key)?;
+    }
+    if let Some(file_metadata) = file_metadata {
+        header.metadata = metadata::seal(&header, &key, file_metadata)?;
+    }
+    if let Some(thumbnail) = options.preview {
+        header.preview = preview::seal(&header, &key, thumbnail)?;
+    }
     
-    let key = derive_key(password);
-    let cipher = Aes256Gcm::new_from_slice(&key)?;
+    // Frames are always full except the last, so the checksum index can be
+    // reserved at its final size and filled in once the body is written.
+    let chunk_count = file_size.div_ceil(chunk_size);
+    key_usage::check(&header.body_key(&key), file_size as u64, chunk_count as u64)?;
+    if chunk_count <= format::MAX_CHUNK_CHECKSUMS {
+        header.chunk_checksums = vec![0; chunk_count];
+    }
+    header.manifest = Some(manifest::Manifest::default());
+    // Sealing takes a random nonce, which convergent files cannot carry.
+    if options.convergent.is_none() {
+        header.content_digest = vec![0; digest::SEALED_DIGEST_SIZE];
+    }
+    header.seal_file_id(&key);
+    // A large preview leaves no room for a full checksum index, which is
+    // only an accelerator for repair.
+    if header.encode().len() > format::MAX_HEADER_LEN {
+        header.chunk_checksums.clear();
+    }
+    write_header(&mut output_file, &header)?;
+    let mut nonce_seq = NonceSequence::for_header(&header, &key)?;
+    let mut hasher = ContentHasher::new(content_size).with_holes(&header.holes);
+    let mut checksums = Vec::with_capacity(chunk_count);
+    let mut manifest = ManifestBuilder::default();
+    manifest.record_plaintext(content_size as usize);
+    // Unpadded data already in memory is sealed in place like a mapped file.
+    let map = match input {
+        PlainInput::Path(input_path) if file_size > chunk_size && header.holes.is_empty() => mapped::map_input(input_path, content_size, options.padding, is_mobile, progress)?,
+        _ => None,
+    };
+    let direct_input = match input {
+        PlainInput::Memory(data) if options.padding == padding::PADDING_NONE => Some(data),
+        _ => map.as_deref(),
+    };
     
     if file_size <= chunk_size {
-        let nonce_bytes = generate_nonce();
-        output_file.write_all(&nonce_bytes)?;
+        let nonce_bytes = nonce_seq.next_nonce()?;
         
+        progress.report(PROGRESS_PHASE_READING, 0.0);
+        let started = Instant::now();
         let mut data = Vec::new();
-        let mut reader = BufReader::new(input_file);
         reader.read_to_end(&mut data)?;
+        progress.time_read(0, data.len(), started);
+        progress.report(PROGRESS_PHASE_READING, 1.0);
+        progress.check_cancelled()?;
         
         let nonce = Nonce::from_slice(&nonce_bytes);
-        let encrypted = cipher.encrypt(nonce, data.as_ref())
-            .map_err(|_| "Encryption failed")?;
-        output_file.write_all(&encrypted)?;
-    } else if file_size <= parallel_threshold {
-        let mut all_data = Vec::new();
-        let mut reader = BufReader::new(input_file);
-        reader.read_to_end(&mut all_data)?;
-        
-        let mut chunks = Vec::new();
-        let mut nonces = Vec::new();
-        
-        for chunk in all_data.chunks(chunk_size) {
-            chunks.push(chunk.to_vec());
-            nonces.push(generate_nonce());
-        }
-        
-        let key_arc = Arc::new(key);
-        let encrypted_chunks: Result<Vec<Vec<u8>>, &str> = chunks
-            .par_iter()
-            .zip(nonces.par_iter())
-            .map(|(chunk, nonce_bytes)| {
-                let cipher = Aes256Gcm::new_from_slice(&*key_arc)
-                    .map_err(|_| "Invalid key")?;
-                let nonce = Nonce::from_slice(nonce_bytes);
-                cipher.encrypt(nonce, chunk.as_ref())
-                    .map_err(|_| "Encryption failed")
-            })
-            .collect();
-        
-        let encrypted_chunks = encrypted_chunks?;
-        
-        for (encrypted, nonce_bytes) in encrypted_chunks.iter().zip(nonces.iter()) {
-            output_file.write_all(nonce_bytes)?;
-            output_file.write_all(&(encrypted.len() as u32).to_be_bytes())?;
-            output_file.write_all(encrypted)?;
-        }
+        let started = Instant::now();
+        let (_, encrypted) = config::install(|| {
+            rayon::join(
+                || hasher.update(&data),
+                || cipher.encrypt(nonce, data.as_ref()),
+            )
+        });
+        let encrypted = encrypted.map_err(|_| "Encryption failed")?;
+        progress.time_crypto(0, started);
+        progress.report(PROGRESS_PHASE_ENCRYPTING, 1.0);
+        manifest.record_frame(&encrypted);
+        let started = Instant::now();
+        checksums.push(write_encrypted_frame(&mut output_file, nonce_seq.stored(&nonce_bytes), &encrypted, true)?);
+        progress.time_write(0, started);
+    } else if let Some(map) = direct_input {
+        let stored_len = nonce_seq.stored_len();
+        mapped::encrypt_batches(map, chunk_size, batch_size, &cipher, &mut nonce_seq, &mut hasher, progress, |_, nonce_bytes, encrypted| {
+            manifest.record_frame(encrypted);
+            checksums.push(write_encrypted_frame(&mut output_file, &nonce_bytes[..stored_len], encrypted, false)?);
+            Ok(())
+        })?;
+    } else if config.pipelined(is_mobile) && progress.handles().is_none() {
+        let stored_len = nonce_seq.stored_len();
+        pipeline::encrypt_pipelined(&mut reader, file_size, chunk_size, batch_size, &cipher, &mut nonce_seq, &mut hasher, progress, |_, nonce_bytes, encrypted| {
+            manifest.record_frame(encrypted);
+            checksums.push(write_encrypted_frame(&mut output_file, &nonce_bytes[..stored_len], encrypted, false)?);
+            Ok(())
+        })?;
     } else {
-        let mut reader = BufReader::new(input_file);
-        let key_arc = Arc::new(key);
+        // Reads stay in step with the reports here: a paused operation waits
+        // in one with its handles closed, and nothing may reopen them.
+        let mut processed = 0;
+        let mut batch = pipeline::read_plain_batch(&mut reader, chunk_size, batch_size, progress, 0)?;
         
-        loop {
-            let mut chunks = Vec::new();
-            let mut nonces = Vec::new();
-            
-            for _ in 0..batch_size {
-                let mut chunk = vec![0u8; chunk_size];
-                match reader.read(&mut chunk)? {
-                    0 => break,
-                    n => {
-                        chunk.truncate(n);
-                        chunks.push(chunk);
-                        nonces.push(generate_nonce());
-                    }
-                }
-            }
+        while !batch.is_empty() {
+            progress.check_cancelled()?;
+            let batch_len: usize = batch.iter().map(|c| c.len()).sum();
+            progress.report_bytes(PROGRESS_PHASE_READING, processed + batch_len, file_size);
+            let nonces = batch.iter().map(|_| nonce_seq.next_nonce()).collect::<Result<Vec<_>, _>>()?;
             
-            if chunks.is_empty() {
-                break;
-            }
+            // The next batch is read while this one is hashed and sealed in
+            // place, so no more than two batches are ever held.
+            let first_index = checksums.len();
+            let next_index = first_index + batch.len();
+            let started = Instant::now();
+            let (next, encrypted): (_, Result<(), &str>) = config::install(|| {
+                rayon::join(
+                    || pipeline::read_plain_batch(&mut reader, chunk_size, batch_size, progress, next_index),
+                    || {
+                        batch.iter().for_each(|chunk| hasher.update(chunk));
+                        batch
+                            .par_iter_mut()
+                            .zip(nonces.par_iter())
+                            .enumerate()
+                            .try_for_each(|(index, (chunk, nonce_bytes))| {
+                                let started = Instant::now();
+                                cipher.encrypt_in_place(Nonce::from_slice(nonce_bytes), b"", chunk)
+                                    .map_err(|_| "Encryption failed")?;
+                                progress.time_crypto(first_index + index, started);
+                                Ok(())
+                            })
+                    },
+                )
+            });
             
-            let encrypted_chunks: Result<Vec<Vec<u8>>, &str> = chunks
-                .par_iter()
-                .zip(nonces.par_iter())
-                .map(|(chunk, nonce_bytes)| {
-                    let cipher = Aes256Gcm::new_from_slice(&*key_arc)
-                        .map_err(|_| "Invalid key")?;
-                    let nonce = Nonce::from_slice(nonce_bytes);
-                    cipher.encrypt(nonce, chunk.as_ref())
-                        .map_err(|_| "Encryption failed")
-                })
-                .collect();
+            encrypted?;
+            progress.time_crypto_stage(started);
+            progress.report_bytes(PROGRESS_PHASE_ENCRYPTING, processed + batch_len, file_size);
             
-            let encrypted_chunks = encrypted_chunks?;
-            
-            for (encrypted, nonce_bytes) in encrypted_chunks.iter().zip(nonces.iter()) {
-                output_file.write_all(nonce_bytes)?;
-                output_file.write_all(&(encrypted.len() as u32).to_be_bytes())?;
-                output_file.write_all(encrypted)?;
+            for (encrypted, nonce_bytes) in batch.iter().zip(nonces.iter()) {
+                manifest.record_frame(encrypted);
+                let started = Instant::now();
+                checksums.push(write_encrypted_frame(&mut output_file, nonce_seq.stored(nonce_bytes), encrypted, false)?);
+                progress.time_write(checksums.len() - 1, started);
             }
+            processed += batch_len;
+            progress.report_bytes(PROGRESS_PHASE_WRITING, processed, file_size);
+            batch = next?;
+        }
+    }
+    
+    output_file.flush()?;
+    let mut file = output_file.into_inner().map_err(|e| e.into_error())?;
+    if !header.chunk_checksums.is_empty() {
+        if checksums.len() != header.chunk_checksums.len() {
+            return Err("Input file changed during encryption".into());
+        }
+        header.chunk_checksums = checksums;
+    }
+    header.manifest = Some(manifest.finish());
+    let content_hash = hasher.finalize();
+    if !header.content_digest.is_empty() {
+        header.content_digest = digest::seal(&header, &key, &content_hash)?;
+    }
+    header.seal_file_id(&key);
+    file.seek(SeekFrom::Start(0))?;
+    write_header(&mut file, &header)?;
+    if header.backup {
+        file.seek(SeekFrom::End(0))?;
+        header_backup::write_trailer(&mut file, &header.encode())?;
+    }
+    file.flush()?;
+    drop(file);
+    key_usage::record(&header.body_key(&key), file_size as u64, chunk_count as u64)?;
+    progress.report(PROGRESS_PHASE_WRITING, 1.0);
+    Ok(digest::keyed(&key, &content_hash))
+}
+
+fn encrypt_small_file<K: KeyProvider + ?Sized, R: Read, W: Write>(
+    mut input: R,
+    mut output_file: W,
+    keys: &K,
+    header: &ContainerHeader,
+    file_metadata: Option<&metadata::FileMetadata>,
+    options: &EncryptOptions,
+    progress: &ProgressSink,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    progress.report(PROGRESS_PHASE_DERIVING_KEY, 0.0);
+    let mut header = header.clone();
+    // A compact header keeps only what opening the file takes: one frame
+    // needs no framing, index or manifest, the name and mtime are left out,
+    // and the password key is used as it is rather than wrapping a file key.
+    if options.compact {
+        header.framing = None;
+        header.chunk_size = None;
+    }
+    let file_metadata = file_metadata.filter(|_| !options.compact);
+    let key = Zeroizing::new(match options.compact {
+        true => header.new_derived_key(keys)?,
+        false => header.new_master_key(keys)?,
+    });
+    let cipher = header.body_cipher(&key)?;
+    progress.report(PROGRESS_PHASE_DERIVING_KEY, 1.0);
+    
+    let started = Instant::now();
+    let mut data = Zeroizing::new(Vec::new());
+    input.read_to_end(&mut data)?;
+    progress.time_read(0, data.len(), started);
+    let mut hasher = ContentHasher::new(data.len() as u64).with_holes(&header.holes);
+    hasher.update(&data);
+    let content_hash = hasher.finalize();
+    let mut manifest = ManifestBuilder::default();
+    manifest.record_plaintext(data.len());
+    padding::padding_reader(data.len() as u64, header.padding).read_to_end(&mut data)?;
+    progress.report(PROGRESS_PHASE_READING, 1.0);
+    key_usage::check(&header.body_key(&key), data.len() as u64, 1)?;
+    
+    // Sealing picks the file id, which the counter nonces are derived from.
+    if keys.derives_from_password() {
+        header.escrow = escrow::configured_slots(&key)?;
+    }
+    if let Some(file_metadata) = file_metadata {
+        header.metadata = metadata::seal(&header, &key, file_metadata)?;
+    }
+    if let Some(thumbnail) = options.preview {
+        header.preview = preview::seal(&header, &key, thumbnail)?;
+    }
+    header.seal_file_id(&key);
+    let mut nonce_seq = NonceSequence::for_header(&header, &key)?;
+    let nonce_bytes = nonce_seq.next_nonce()?;
+    let started = Instant::now();
+    let encrypted = cipher.encrypt(Nonce::from_slice(&nonce_bytes), data.as_ref())
+        .map_err(|_| "Encryption failed")?;
+    progress.time_crypto(0, started);
+    progress.report(PROGRESS_PHASE_ENCRYPTING, 1.0);
+    
+    let mut frame = Vec::with_capacity(NONCE_SIZE + encrypted.len());
+    let checksum = write_encrypted_frame(&mut frame, nonce_seq.stored(&nonce_bytes), &encrypted, true)?;
+    if !options.compact {
+        header.chunk_checksums = vec![checksum];
+        manifest.record_frame(&encrypted);
+        header.manifest = Some(manifest.finish());
+        if options.convergent.is_none() {
+            header.content_digest = digest::seal(&header, &key, &content_hash)?;
         }
+        header.seal_file_id(&key);
     }
     
+    let mut output = header.encode();
+    output.extend_from_slice(&frame);
+    if header.backup {
+        header_backup::write_trailer(&mut output, &header.encode())?;
+    }
+    let started = Instant::now();
+    output_file.write_all(&output)?;
     output_file.flush()?;
+    progress.time_write(0, started);
+    key_usage::record(&header.body_key(&key), data.len() as u64, 1)?;
+    progress.report(PROGRESS_PHASE_WRITING, 1.0);
+    Ok(digest::keyed(&key, &content_hash))
+}
+
+fn decrypt_small_file<W: Write>(
+    input_path: &std::path::Path,
+    output: &mut W,
+    key: &[u8; 32],
+    progress: &ProgressSink,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let data = std::fs::read(input_path)?;
+    progress.time_read(0, data.len(), started);
+    progress.report(PROGRESS_PHASE_READING, 1.0);
+    
+    let (header, data_start) = ContainerHeader::read_recovering(&mut std::io::Cursor::new(&data))?;
+    let data = &data[..header.body_end(data_start, data.len() as u64) as usize];
+    let data_start = data_start as usize;
+    
+    let cipher = header.body_cipher(key)?;
+    let mut nonce_seq = NonceSequence::for_header(&header, key)?;
+    if data.len() < data_start + nonce_seq.stored_len() + TAG_SIZE {
+        return Err("Invalid file format".into());
+    }
+    let mut encrypted = &data[data_start..];
+    let nonce_bytes = nonce_seq.read_nonce(&mut encrypted)?;
+    let started = Instant::now();
+    let mut decrypted = Zeroizing::new(cipher.decrypt(Nonce::from_slice(&nonce_bytes), encrypted)
+        .map_err(|_| "Decryption failed")?);
+    progress.time_crypto(0, started);
+    progress.report(PROGRESS_PHASE_DECRYPTING, 1.0);
+    padding::strip(&mut decrypted, header.padding)?;
+    let mut manifest = ManifestBuilder::default();
+    manifest.record_frame(encrypted);
+    manifest.record_plaintext(decrypted.len());
+    manifest.check(header.manifest.as_ref())?;
+    header.verify_file_id(key)?;
+    
+    let started = Instant::now();
+    output.write_all(&decrypted)?;
+    progress.time_write(0, started);
+    progress.report(PROGRESS_PHASE_WRITING, 1.0);
+    Ok(())
+}
+
+fn write_encrypted_atomic(
+    output_path: &std::path::Path,
+    password: &[u8],
+    data: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut header = ContainerHeader { key_purpose: kdf::KEY_PURPOSE_METADATA, ..Default::default() };
+    header.use_password_kdf(false);
+    let key = Zeroizing::new(header.new_master_key(password)?);
+    header.seal_file_id(&key);
+    let cipher = header.body_cipher(&key)?;
+    let nonce_bytes = random_nonce();
+    let encrypted = cipher.encrypt(Nonce::from_slice(&nonce_bytes), data)
+        .map_err(|_| "Encryption failed")?;
+    
+    let mut output = Vec::with_capacity(HEADER_SIZE + 1 + NONCE_SIZE + encrypted.len());
+    write_header(&mut output, &header)?;
+    output.extend_from_slice(&nonce_bytes);
+    output.extend_from_slice(&encrypted);
+    
+    let mut temp = SecureTempFile::for_target(output_path, TempContents::Ciphertext)?;
+    temp.file().write_all(&output)?;
+    temp.persist(output_path)?;
     Ok(())
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn decrypt_file(
     input_path_ptr: *const c_char,
@@ -232,17 +791,18 @@ pub extern "C" fn decrypt_file(
     unsafe {
         let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
             Ok(s) => s,
-            Err(_) => return -1,
+            Err(_) => return errors::invalid_argument(),
         };
         let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
             Ok(s) => s,
-            Err(_) => return -1,
+            Err(_) => return errors::invalid_argument(),
         };
         let password = slice::from_raw_parts(password_ptr, password_len);
 
-        match decrypt_file_internal(input_path, output_path, password, is_mobile, cpu_cores) {
+        let decryptor = kyrie_core::Decryptor::new(password).for_mobile(is_mobile).with_cpu_cores(cpu_cores);
+        match decryptor.decrypt_file(std::path::Path::new(input_path), std::path::Path::new(output_path)) {
             Ok(_) => 0,
-            Err(_) => -2,
+            Err(e) => errors::fail(&e),
         }
     }
 }
@@ -254,160 +814,209 @@ fn decrypt_file_internal(
     is_mobile: bool,
     cpu_cores: usize,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let chunk_size = get_chunk_size(is_mobile);
-    let parallel_threshold = get_parallel_batch_threshold(is_mobile);
-    let batch_size = get_parallel_batch_size(cpu_cores, is_mobile);
-    
-    let mut input_file = BufReader::new(File::open(input_path)?);
-    
-    let mut magic = vec![0u8; MAGIC_STRING.len()];
-    input_file.read_exact(&mut magic)?;
-    if magic != MAGIC_STRING {
-        return Err("Invalid file format".into());
+    decrypt_file_reporting(input_path, output_path, password, is_mobile, cpu_cores, &ProgressSink::none())
+}
+
+fn decrypt_file_reporting<K: KeyProvider + ?Sized>(
+    input_path: impl AsRef<std::path::Path>,
+    output_path: impl AsRef<std::path::Path>,
+    keys: &K,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let result = decrypt_file_unlogged(input_path.as_ref(), output_path.as_ref(), keys, is_mobile, cpu_cores, progress);
+    audit::record("decrypt", input_path.as_ref(), started, &result);
+    result
+}
+
+fn decrypt_file_unlogged<K: KeyProvider + ?Sized>(
+    input_path: &std::path::Path,
+    output_path: &std::path::Path,
+    keys: &K,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<(), Box<dyn std::error::Error>> {
+    secure_temp::check_target(output_path)?;
+    let stitched = split::stitch_volumes(input_path)?;
+    let input_path = match &stitched {
+        Some(joined) => joined.path(),
+        None => input_path,
+    };
+    let _lock = file_lock::lock_input(input_path)?;
+    let revealed = privacy::reveal(input_path, keys)?;
+    let input_path = match &revealed {
+        Some(unmasked) => unmasked.path(),
+        None => input_path,
+    };
+    let mut temp = SecureTempFile::for_output(output_path)?;
+    let (header, key) = if format::read_header(input_path)?.0.holes.is_empty() {
+        decrypt_into(input_path, temp.file(), keys, is_mobile, cpu_cores, progress)?
+    } else {
+        decrypt_into(input_path, &mut sparse::SparseWriter::new(temp.file()), keys, is_mobile, cpu_cores, progress)?
+    };
+    // Checked only once the payload decrypted, so a wrong password is still
+    // reported as such rather than as a damaged header.
+    header.verify_file_id(&key)?;
+    temp.persist(output_path)?;
+    if let Some(file_metadata) = metadata::open(&header, &key)? {
+        metadata::restore_modified(output_path, &file_metadata)?;
     }
-    
-    let mut version_bytes = [0u8; 4];
-    input_file.read_exact(&mut version_bytes)?;
-    let version = u32::from_le_bytes(version_bytes);
-    if version != VERSION {
-        return Err("Unsupported version".into());
+    Ok(())
+}
+
+// A decrypted container's header and the key that opened it, so callers
+// checking the header or restoring metadata need not derive it again.
+type Unlocked = (ContainerHeader, Zeroizing<[u8; 32]>);
+
+// The body of a sparse container holds only the data between its holes,
+// which are filled back in as it is written out.
+fn decrypt_into<K: KeyProvider + ?Sized, W: Write>(
+    input_path: &std::path::Path,
+    output: &mut W,
+    keys: &K,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<Unlocked, Box<dyn std::error::Error>> {
+    let (header, _) = format::read_header(input_path)?;
+    if header.holes.is_empty() {
+        return decrypt_body(input_path, output, keys, is_mobile, cpu_cores, progress);
     }
+    let mut filler = sparse::HoleFiller::new(output, &header.holes);
+    let unlocked = decrypt_body(input_path, &mut filler, keys, is_mobile, cpu_cores, progress)?;
+    filler.finish()?;
+    Ok(unlocked)
+}
+
+// Plaintext only reaches the output once the first frame has shown the
+// password is right and the header is checked against it, since a sink may
+// hand it on before the whole body is read.
+fn decrypt_body<K: KeyProvider + ?Sized, W: Write>(
+    input_path: &std::path::Path,
+    output: &mut W,
+    keys: &K,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<Unlocked, Box<dyn std::error::Error>> {
+    let batch_size = config::KyrieConfig::current().parallel_batch_size(cpu_cores, is_mobile);
     
-    let mut hint_len_bytes = [0u8; 1];
-    input_file.read_exact(&mut hint_len_bytes)?;
-    let hint_len = hint_len_bytes[0] as usize;
+    strict::enforce(input_path)?;
+    let (header, _) = format::read_header(input_path)?;
+    if header.has_convergent_chunks() {
+        return Err("Unsupported framing version".into());
+    }
     
-    let mut hint_bytes = vec![0u8; hint_len];
-    input_file.read_exact(&mut hint_bytes)?;
+    progress.report(PROGRESS_PHASE_DERIVING_KEY, 0.0);
+    let key = Zeroizing::new(header.master_key(keys)?);
+    progress.report(PROGRESS_PHASE_DERIVING_KEY, 1.0);
     
-    let encrypted_data_start = HEADER_SIZE + 1 + hint_len;
+    if header.dual() {
+        duress::decrypt_slot(input_path, &key, output)?;
+        progress.report(PROGRESS_PHASE_WRITING, 1.0);
+        return Ok((header, key));
+    }
     
     let file_size = std::fs::metadata(input_path)?.len() as usize;
-    let encrypted_size = file_size - encrypted_data_start;
+    // A streamed body is framed however small, ending in an empty frame, and
+    // a small chunk size splits small files too.
+    if file_size <= SMALL_FILE_THRESHOLD && format::read_layout(input_path)?.single_chunk {
+        decrypt_small_file(input_path, output, &key, progress)?;
+        return Ok((header, key));
+    }
     
-    let key = derive_key(password);
-    let cipher = Aes256Gcm::new_from_slice(&key)?;
+    let mut input_file = BufReader::new(handles::ReleasableFile::open(input_path, progress.handles())?);
     
-    let mut output_file = BufWriter::new(File::create(output_path)?);
+    let (header, encrypted_data_start) = ContainerHeader::read_recovering(&mut input_file)?;
+    let body_end = header.body_end(encrypted_data_start, file_size as u64) as usize;
+    let encrypted_data_start = encrypted_data_start as usize;
+    let encrypted_size = body_end.checked_sub(encrypted_data_start).ok_or("Invalid file format")?;
+    let mut input_file = input_file.take(encrypted_size as u64);
+    let cipher = header.body_cipher(&key)?;
     
-    let is_single_chunk = {
-        let mut temp_nonce = [0u8; NONCE_SIZE];
-        input_file.read_exact(&mut temp_nonce)?;
-        
-        let remaining = encrypted_size - NONCE_SIZE;
-        let is_single = remaining <= (chunk_size + TAG_SIZE);
-        
-        input_file.seek(SeekFrom::Start(encrypted_data_start as u64))?;
-        is_single
-    };
+    let mut output_file = BufWriter::new(output);
+    let mut stripper = PaddingStripper::new(header.padding);
+    let mut nonce_seq = NonceSequence::for_header(&header, &key)?;
+    let nonce_len = nonce_seq.stored_len();
+    let mut manifest = ManifestBuilder::default();
     
-    if is_single_chunk {
-        let mut nonce_bytes = [0u8; NONCE_SIZE];
-        input_file.read_exact(&mut nonce_bytes)?;
+    if format::read_layout(input_path)?.single_chunk {
+        let nonce_bytes = nonce_seq.read_nonce(&mut input_file)?;
         
+        progress.report(PROGRESS_PHASE_READING, 0.0);
+        let started = Instant::now();
         let mut encrypted_data = Vec::new();
         input_file.read_to_end(&mut encrypted_data)?;
+        progress.time_read(0, encrypted_data.len(), started);
+        progress.report(PROGRESS_PHASE_READING, 1.0);
+        progress.check_cancelled()?;
         
         let nonce = Nonce::from_slice(&nonce_bytes);
-        let decrypted = cipher.decrypt(nonce, encrypted_data.as_ref())
-            .map_err(|_| "Decryption failed")?;
-        
-        output_file.write_all(&decrypted)?;
-    } else if encrypted_size <= parallel_threshold {
-        let mut chunks = Vec::new();
-        let mut nonces = Vec::new();
+        let started = Instant::now();
+        let decrypted = Zeroizing::new(cipher.decrypt(nonce, encrypted_data.as_ref())
+            .map_err(|_| "Decryption failed")?);
+        progress.time_crypto(0, started);
+        progress.report(PROGRESS_PHASE_DECRYPTING, 1.0);
+        manifest.record_frame(&encrypted_data);
+        header.verify_file_id(&key)?;
         
-        while let Ok(nonce_bytes) = {
-            let mut buf = [0u8; NONCE_SIZE];
-            input_file.read_exact(&mut buf).map(|_| buf)
-        } {
-            let mut chunk_len_bytes = [0u8; 4];
-            if input_file.read_exact(&mut chunk_len_bytes).is_err() {
-                break;
-            }
-            let chunk_len = u32::from_be_bytes(chunk_len_bytes) as usize;
-            
-            let mut encrypted_chunk = vec![0u8; chunk_len];
-            input_file.read_exact(&mut encrypted_chunk)?;
-            
-            chunks.push(encrypted_chunk);
-            nonces.push(nonce_bytes);
-        }
-        
-        let key_arc = Arc::new(key);
-        let decrypted_chunks: Result<Vec<Vec<u8>>, &str> = chunks
-            .par_iter()
-            .zip(nonces.par_iter())
-            .map(|(chunk, nonce_bytes)| {
-                let cipher = Aes256Gcm::new_from_slice(&*key_arc)
-                    .map_err(|_| "Invalid key")?;
-                let nonce = Nonce::from_slice(nonce_bytes);
-                cipher.decrypt(nonce, chunk.as_ref())
-                    .map_err(|_| "Decryption failed")
-            })
-            .collect();
-        
-        let decrypted_chunks = decrypted_chunks?;
-        
-        for decrypted in decrypted_chunks.iter() {
-            output_file.write_all(decrypted)?;
-        }
+        let started = Instant::now();
+        stripper.feed(&decrypted, |d| {
+            manifest.record_plaintext(d.len());
+            Ok(output_file.write_all(d)?)
+        })?;
+        progress.time_write(0, started);
     } else {
-        let key_arc = Arc::new(key);
+        let mut processed = 0;
+        let mut chunk_index = 0;
+        let mut body = ContainerReader::new(input_file, &header);
+        let mut batch = pipeline::read_frame_batch(&mut body, &mut nonce_seq, batch_size, progress, 0)?;
         
-        loop {
-            let mut chunks = Vec::new();
-            let mut nonces = Vec::new();
-            
-            for _ in 0..batch_size {
-                let mut nonce_bytes = [0u8; NONCE_SIZE];
-                if input_file.read_exact(&mut nonce_bytes).is_err() {
-                    break;
-                }
-                
-                let mut chunk_len_bytes = [0u8; 4];
-                if input_file.read_exact(&mut chunk_len_bytes).is_err() {
-                    break;
-                }
-                let chunk_len = u32::from_be_bytes(chunk_len_bytes) as usize;
-                
-                let mut encrypted_chunk = vec![0u8; chunk_len];
-                if input_file.read_exact(&mut encrypted_chunk).is_err() {
-                    break;
-                }
-                
-                chunks.push(encrypted_chunk);
-                nonces.push(nonce_bytes);
-            }
-            
-            if chunks.is_empty() {
-                break;
-            }
-            
-            let decrypted_chunks: Result<Vec<Vec<u8>>, &str> = chunks
-                .par_iter()
-                .zip(nonces.par_iter())
-                .map(|(chunk, nonce_bytes)| {
-                    let cipher = Aes256Gcm::new_from_slice(&*key_arc)
-                        .map_err(|_| "Invalid key")?;
-                    let nonce = Nonce::from_slice(nonce_bytes);
-                    cipher.decrypt(nonce, chunk.as_ref())
-                        .map_err(|_| "Decryption failed")
-                })
-                .collect();
-            
-            let decrypted_chunks = decrypted_chunks?;
+        while !batch.is_empty() {
+            progress.check_cancelled()?;
+            let batch_len: usize = batch.iter().map(|(_, c)| nonce_len + 4 + c.len()).sum();
+            progress.report_bytes(PROGRESS_PHASE_READING, processed + batch_len, encrypted_size);
             
-            for decrypted in decrypted_chunks.iter() {
-                output_file.write_all(decrypted)?;
-            }
+            let next_index = chunk_index + batch.len();
+            batch.iter().for_each(|(_, chunk)| manifest.record_frame(chunk));
+            pipeline::open_batch_ordered(
+                batch,
+                chunk_index,
+                &cipher,
+                progress,
+                |index, decrypted| {
+                    if index == 0 {
+                        header.verify_file_id(&key)?;
+                    }
+                    let started = Instant::now();
+                    stripper.feed(decrypted, |d| {
+                        manifest.record_plaintext(d.len());
+                        Ok(output_file.write_all(d)?)
+                    })?;
+                    progress.time_write(index, started);
+                    Ok(())
+                },
+            )?;
+            chunk_index = next_index;
+            progress.report_bytes(PROGRESS_PHASE_DECRYPTING, processed + batch_len, encrypted_size);
+            processed += batch_len;
+            progress.report_bytes(PROGRESS_PHASE_WRITING, processed, encrypted_size);
+            batch = pipeline::read_frame_batch(&mut body, &mut nonce_seq, batch_size, progress, next_index)?;
         }
+        body.finish()?;
     }
     
+    stripper.finish()?;
+    manifest.check(header.manifest.as_ref())?;
     output_file.flush()?;
-    Ok(())
+    progress.report(PROGRESS_PHASE_WRITING, 1.0);
+    Ok((header, key))
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn decrypt_file_to_memory(
     input_path_ptr: *const c_char,
@@ -421,19 +1030,21 @@ pub extern "C" fn decrypt_file_to_memory(
     unsafe {
         let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
             Ok(s) => s,
-            Err(_) => return -1,
+            Err(_) => return errors::invalid_argument(),
         };
         let password = slice::from_raw_parts(password_ptr, password_len);
 
-        match decrypt_file_to_memory_internal(input_path, password, is_mobile, cpu_cores) {
-            Ok(data) => {
+        let decryptor = kyrie_core::Decryptor::new(password).for_mobile(is_mobile).with_cpu_cores(cpu_cores);
+        match decryptor.decrypt_to_vec(std::path::Path::new(input_path)) {
+            Ok(mut data) => {
                 *output_len = data.len();
                 if !output_ptr.is_null() {
                     std::ptr::copy_nonoverlapping(data.as_ptr(), output_ptr, data.len());
                 }
+                data.zeroize();
                 0
             }
-            Err(_) => -2,
+            Err(e) => errors::fail(&e),
         }
     }
 }
@@ -442,109 +1053,175 @@ fn decrypt_file_to_memory_internal(
     input_path: &str,
     password: &[u8],
     is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    decrypt_file_to_memory_with(input_path, password, is_mobile, cpu_cores)
+}
+
+fn decrypt_file_to_memory_with<K: KeyProvider + ?Sized>(
+    input_path: &str,
+    keys: &K,
+    _is_mobile: bool,
     _cpu_cores: usize,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let chunk_size = get_chunk_size(is_mobile);
-    let _parallel_threshold = get_parallel_batch_threshold(is_mobile);
-    
+    strict::enforce(std::path::Path::new(input_path))?;
+    let _lock = file_lock::lock_input(std::path::Path::new(input_path))?;
+    let (header, _) = format::read_header(std::path::Path::new(input_path))?;
+    let key = Zeroizing::new(header.master_key(keys)?);
+    decrypt_file_to_memory_unlocked(input_path, &key)
+}
+
+// For callers that already derived the key; the caller also holds the
+// input lock.
+fn decrypt_file_to_memory_unlocked(input_path: &str, key: &[u8; 32]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut input_file = BufReader::new(File::open(input_path)?);
     
-    let mut magic = vec![0u8; MAGIC_STRING.len()];
-    input_file.read_exact(&mut magic)?;
-    if magic != MAGIC_STRING {
-        return Err("Invalid file format".into());
-    }
-    
-    let mut version_bytes = [0u8; 4];
-    input_file.read_exact(&mut version_bytes)?;
-    let version = u32::from_le_bytes(version_bytes);
-    if version != VERSION {
-        return Err("Unsupported version".into());
+    let (header, encrypted_data_start) = ContainerHeader::read_recovering(&mut input_file)?;
+    let encrypted_data_start = encrypted_data_start as usize;
+    if header.dual() {
+        let mut data = Vec::new();
+        duress::decrypt_slot(std::path::Path::new(input_path), key, &mut data)?;
+        return Ok(data);
     }
     
-    let mut hint_len_bytes = [0u8; 1];
-    input_file.read_exact(&mut hint_len_bytes)?;
-    let hint_len = hint_len_bytes[0] as usize;
-    
-    let mut hint_bytes = vec![0u8; hint_len];
-    input_file.read_exact(&mut hint_bytes)?;
-    
-    let encrypted_data_start = HEADER_SIZE + 1 + hint_len;
-    
-    let file_size = std::fs::metadata(input_path)?.len() as usize;
-    let encrypted_size = file_size - encrypted_data_start;
-    
-    let key = derive_key(password);
-    let cipher = Aes256Gcm::new_from_slice(&key)?;
+    let body_end = header.body_end(encrypted_data_start as u64, std::fs::metadata(input_path)?.len()) as usize;
+    let encrypted_size = body_end.checked_sub(encrypted_data_start).ok_or("Invalid file format")?;
+    let mut input_file = input_file.take(encrypted_size as u64);
     
-    let is_single_chunk = {
-        let mut temp_nonce = [0u8; NONCE_SIZE];
-        input_file.read_exact(&mut temp_nonce)?;
-        
-        let remaining = encrypted_size - NONCE_SIZE;
-        let is_single = remaining <= (chunk_size + TAG_SIZE);
-        
-        input_file.seek(SeekFrom::Start(encrypted_data_start as u64))?;
-        is_single
-    };
+    let cipher = header.body_cipher(key)?;
+    let mut nonce_seq = NonceSequence::for_header(&header, key)?;
     
-    if is_single_chunk {
-        let mut nonce_bytes = [0u8; NONCE_SIZE];
-        input_file.read_exact(&mut nonce_bytes)?;
+    if format::read_layout(std::path::Path::new(input_path))?.single_chunk {
+        let nonce_bytes = nonce_seq.read_nonce(&mut input_file)?;
         
         let mut encrypted_data = Vec::new();
         input_file.read_to_end(&mut encrypted_data)?;
         
         let nonce = Nonce::from_slice(&nonce_bytes);
-        let decrypted = cipher.decrypt(nonce, encrypted_data.as_ref())
+        let mut decrypted = cipher.decrypt(nonce, encrypted_data.as_ref())
             .map_err(|_| "Decryption failed")?;
+        padding::strip(&mut decrypted, header.padding)?;
+        header.verify_file_id(key)?;
+        let mut manifest = ManifestBuilder::default();
+        manifest.record_frame(&encrypted_data);
+        manifest.record_plaintext(decrypted.len());
+        manifest.check(header.manifest.as_ref())?;
         
-        Ok(decrypted)
+        sparse::fill_holes(decrypted, &header.holes)
     } else {
         let mut chunks = Vec::new();
         let mut nonces = Vec::new();
         
-        while let Ok(nonce_bytes) = {
-            let mut buf = [0u8; NONCE_SIZE];
-            input_file.read_exact(&mut buf).map(|_| buf)
-        } {
-            let mut chunk_len_bytes = [0u8; 4];
-            if input_file.read_exact(&mut chunk_len_bytes).is_err() {
-                break;
-            }
-            let chunk_len = u32::from_be_bytes(chunk_len_bytes) as usize;
-            
-            let mut encrypted_chunk = vec![0u8; chunk_len];
-            input_file.read_exact(&mut encrypted_chunk)?;
-            
+        let mut body = ContainerReader::new(input_file, &header);
+        while let Some((nonce_bytes, encrypted_chunk)) = body.next_frame(&mut nonce_seq)? {
             chunks.push(encrypted_chunk);
             nonces.push(nonce_bytes);
         }
+        body.finish()?;
         
-        let key_arc = Arc::new(key);
-        let decrypted_chunks: Result<Vec<Vec<u8>>, &str> = chunks
-            .par_iter()
-            .zip(nonces.par_iter())
-            .map(|(chunk, nonce_bytes)| {
-                let cipher = Aes256Gcm::new_from_slice(&*key_arc)
-                    .map_err(|_| "Invalid key")?;
-                let nonce = Nonce::from_slice(nonce_bytes);
-                cipher.decrypt(nonce, chunk.as_ref())
-                    .map_err(|_| "Decryption failed")
-            })
-            .collect();
+        let decrypted_chunks: Result<Vec<Vec<u8>>, &str> = config::install(|| {
+            chunks
+                .par_iter()
+                .zip(nonces.par_iter())
+                .map(|(chunk, nonce_bytes)| {
+                    let nonce = Nonce::from_slice(nonce_bytes);
+                    cipher.decrypt(nonce, chunk.as_ref())
+                        .map_err(|_| "Decryption failed")
+                })
+                .collect()
+        });
         
-        let decrypted_chunks = decrypted_chunks?;
+        let mut decrypted_chunks = decrypted_chunks?;
         
-        let mut result = Vec::new();
-        for decrypted in decrypted_chunks.iter() {
+        // The recorded size is authenticated, but still never trusted past
+        // what the body could hold.
+        let capacity = match metadata::open(&header, key)? {
+            Some(file_metadata) => file_metadata.size.min(encrypted_size as u64) as usize,
+            None => 0,
+        };
+        let mut result = Vec::with_capacity(capacity);
+        for decrypted in decrypted_chunks.iter_mut() {
             result.extend_from_slice(decrypted);
+            decrypted.zeroize();
         }
+        padding::strip(&mut result, header.padding)?;
+        header.verify_file_id(key)?;
+        let mut manifest = ManifestBuilder::default();
+        chunks.iter().for_each(|chunk| manifest.record_frame(chunk));
+        manifest.record_plaintext(result.len());
+        manifest.check(header.manifest.as_ref())?;
         
-        Ok(result)
+        sparse::fill_holes(result, &header.holes)
+    }
+}
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decrypt_file_to_memory_capped(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    max_output_bytes: u64,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        match decrypt_file_to_memory_capped_internal(input_path, password, max_output_bytes, is_mobile, cpu_cores) {
+            Ok(Ok(data)) => {
+                *output_len = data.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), output_ptr, data.len());
+                }
+                0
+            }
+            Ok(Err(size)) => {
+                *output_len = usize::try_from(size).unwrap_or(usize::MAX);
+                errors::fail_with(errors::ERR_OUTPUT_TOO_LARGE)
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
     }
 }
 
+// Returns Err with the plaintext size, without decrypting the body, when
+// the plaintext would exceed max_output_bytes.
+fn decrypt_file_to_memory_capped_internal(
+    input_path: &str,
+    password: &[u8],
+    max_output_bytes: u64,
+    _is_mobile: bool,
+    _cpu_cores: usize,
+) -> Result<Result<Vec<u8>, u64>, Box<dyn std::error::Error>> {
+    let path = std::path::Path::new(input_path);
+    strict::enforce(path)?;
+    let _lock = file_lock::lock_input(path)?;
+    let layout = format::read_layout(path)?;
+    let key = Zeroizing::new(layout.master_key(password)?);
+    let layout = layout.unlock(&mut BufReader::new(File::open(path)?), &key)?;
+    let padded_len: u64 = layout.frames.iter().map(|f| f.len.saturating_sub(TAG_SIZE as u64)).sum::<u64>() + sparse::hole_bytes(&layout.holes);
+    if padded_len > max_output_bytes {
+        // Only padded files need their last chunk decrypted to find the end.
+        let size = if layout.padding == padding::PADDING_NONE {
+            padded_len
+        } else {
+            range::DecryptingReader::unlocked(path, layout, &key)?.plaintext_len()
+        };
+        if size > max_output_bytes {
+            return Ok(Err(size));
+        }
+    }
+    decrypt_file_to_memory_unlocked(input_path, &key).map(Ok)
+}
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn get_hint_from_file(
     input_path_ptr: *const c_char,
@@ -554,10 +1231,10 @@ pub extern "C" fn get_hint_from_file(
     unsafe {
         let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
             Ok(s) => s,
-            Err(_) => return -1,
+            Err(_) => return errors::invalid_argument(),
         };
 
-        match get_hint_from_file_internal(input_path) {
+        match kyrie_core::FileHeader::read(std::path::Path::new(input_path)).map(|header| header.hint) {
             Ok(hint_bytes) => {
                 *hint_len = hint_bytes.len();
                 if !hint_ptr.is_null() {
@@ -565,33 +1242,77 @@ pub extern "C" fn get_hint_from_file(
                 }
                 0
             }
-            Err(_) => -2,
+            Err(e) => errors::fail(&e),
         }
     }
 }
 
 fn get_hint_from_file_internal(input_path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut input_file = File::open(input_path)?;
+    let (header, _) = format::read_header(std::path::Path::new(input_path))?;
+    Ok(text::readable_hint(&header.hint).to_vec())
+}
+
+// Returns the key that opened the file.
+fn for_each_decrypted_chunk<F>(
+    input_path: &str,
+    password: &[u8],
+    _is_mobile: bool,
+    mut visit: F,
+) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>>
+where
+    F: FnMut(&[u8], bool) -> Result<(), Box<dyn std::error::Error>>,
+{
+    strict::enforce(std::path::Path::new(input_path))?;
+    let mut input_file = BufReader::new(File::open(input_path)?);
     
-    let mut magic = vec![0u8; MAGIC_STRING.len()];
-    input_file.read_exact(&mut magic)?;
-    if magic != MAGIC_STRING {
-        return Err("Invalid file format".into());
+    let (header, encrypted_data_start) = ContainerHeader::read_recovering(&mut input_file)?;
+    let encrypted_data_start = encrypted_data_start as usize;
+    let key = Zeroizing::new(header.master_key(password)?);
+    if header.dual() {
+        duress::for_each_slot_chunk(std::path::Path::new(input_path), &key, |chunk| visit(chunk, false))?;
+        return Ok(key);
     }
+    let cipher = header.body_cipher(&key)?;
+    let mut nonce_seq = NonceSequence::for_header(&header, &key)?;
     
-    let mut version_bytes = [0u8; 4];
-    input_file.read_exact(&mut version_bytes)?;
+    let file_size = header.body_end(encrypted_data_start as u64, std::fs::metadata(input_path)?.len()) as usize;
+    let mut input_file = input_file.take(file_size.saturating_sub(encrypted_data_start) as u64);
+    file_size
+        .checked_sub(encrypted_data_start + nonce_seq.stored_len())
+        .ok_or("Invalid file format")?;
     
-    let mut hint_len_bytes = [0u8; 1];
-    input_file.read_exact(&mut hint_len_bytes)?;
-    let hint_len = hint_len_bytes[0] as usize;
+    if format::read_layout(std::path::Path::new(input_path))?.single_chunk {
+        let nonce_bytes = nonce_seq.read_nonce(&mut input_file)?;
+        
+        let mut encrypted_data = Vec::new();
+        input_file.read_to_end(&mut encrypted_data)?;
+        
+        let decrypted = Zeroizing::new(cipher.decrypt(Nonce::from_slice(&nonce_bytes), encrypted_data.as_ref())
+            .map_err(|_| "Decryption failed")?);
+        header.verify_file_id(&key)?;
+        visit(&decrypted, true)?;
+        return Ok(key);
+    }
     
-    let mut hint_bytes = vec![0u8; hint_len];
-    input_file.read_exact(&mut hint_bytes)?;
+    // The header is checked once the first chunk has shown the password is
+    // right, and before any plaintext reaches the visitor.
+    let mut verified = false;
+    let mut body = ContainerReader::new(input_file, &header);
+    while let Some((nonce_bytes, encrypted_chunk)) = body.next_frame(&mut nonce_seq)? {
+        let decrypted = Zeroizing::new(cipher.decrypt(Nonce::from_slice(&nonce_bytes), encrypted_chunk.as_ref())
+            .map_err(|_| "Decryption failed")?);
+        if !verified {
+            header.verify_file_id(&key)?;
+            verified = true;
+        }
+        visit(&decrypted, false)?;
+    }
     
-    Ok(hint_bytes)
+    body.finish()?;
+    Ok(key)
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn encrypt_data_parallel(
     chunks_ptr: *const *const u8,
@@ -605,8 +1326,11 @@ pub extern "C" fn encrypt_data_parallel(
 ) -> i32 {
     unsafe {
         let password = slice::from_raw_parts(password_ptr, password_len);
-        let key = derive_key(password);
-        let key_arc = Arc::new(key);
+        let key = Zeroizing::new(derive_key(password));
+        let cipher = match Aes256Gcm::new_from_slice(&key[..]) {
+            Ok(c) => c,
+            Err(_) => return errors::invalid_argument(),
+        };
         
         let chunk_ptrs = slice::from_raw_parts(chunks_ptr, num_chunks);
         let chunk_lengths = slice::from_raw_parts(chunk_lens, num_chunks);
@@ -618,20 +1342,19 @@ pub extern "C" fn encrypt_data_parallel(
             .map(|(ptr, len)| slice::from_raw_parts(*ptr, *len))
             .collect();
         
-        let results: Result<Vec<Vec<u8>>, i32> = chunks
-            .par_iter()
-            .enumerate()
-            .map(|(i, chunk)| {
-                let cipher = Aes256Gcm::new_from_slice(&*key_arc)
-                    .map_err(|_| -1)?;
-                
-                let nonce_offset = i * NONCE_SIZE;
-                let nonce = Nonce::from_slice(&nonces[nonce_offset..nonce_offset + NONCE_SIZE]);
-                
-                cipher.encrypt(nonce, chunk.as_ref())
-                    .map_err(|_| -2)
-            })
-            .collect();
+        let results: Result<Vec<Vec<u8>>, i32> = config::install(|| {
+            chunks
+                .par_iter()
+                .enumerate()
+                .map(|(i, chunk)| {
+                    let nonce_offset = i * NONCE_SIZE;
+                    let nonce = Nonce::from_slice(&nonces[nonce_offset..nonce_offset + NONCE_SIZE]);
+                    
+                    cipher.encrypt(nonce, chunk.as_ref())
+                        .map_err(|_| -2)
+                })
+                .collect()
+        });
         
         match results {
             Ok(encrypted_chunks) => {
@@ -655,6 +1378,7 @@ pub extern "C" fn encrypt_data_parallel(
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn decrypt_data_parallel(
     chunks_ptr: *const *const u8,
@@ -668,8 +1392,11 @@ pub extern "C" fn decrypt_data_parallel(
 ) -> i32 {
     unsafe {
         let password = slice::from_raw_parts(password_ptr, password_len);
-        let key = derive_key(password);
-        let key_arc = Arc::new(key);
+        let key = Zeroizing::new(derive_key(password));
+        let cipher = match Aes256Gcm::new_from_slice(&key[..]) {
+            Ok(c) => c,
+            Err(_) => return errors::invalid_argument(),
+        };
         
         let chunk_ptrs = slice::from_raw_parts(chunks_ptr, num_chunks);
         let chunk_lengths = slice::from_raw_parts(chunk_lens, num_chunks);
@@ -681,20 +1408,19 @@ pub extern "C" fn decrypt_data_parallel(
             .map(|(ptr, len)| slice::from_raw_parts(*ptr, *len))
             .collect();
         
-        let results: Result<Vec<Vec<u8>>, i32> = chunks
-            .par_iter()
-            .enumerate()
-            .map(|(i, chunk)| {
-                let cipher = Aes256Gcm::new_from_slice(&*key_arc)
-                    .map_err(|_| -1)?;
-                
-                let nonce_offset = i * NONCE_SIZE;
-                let nonce = Nonce::from_slice(&nonces[nonce_offset..nonce_offset + NONCE_SIZE]);
-                
-                cipher.decrypt(nonce, chunk.as_ref())
-                    .map_err(|_| -2)
-            })
-            .collect();
+        let results: Result<Vec<Vec<u8>>, i32> = config::install(|| {
+            chunks
+                .par_iter()
+                .enumerate()
+                .map(|(i, chunk)| {
+                    let nonce_offset = i * NONCE_SIZE;
+                    let nonce = Nonce::from_slice(&nonces[nonce_offset..nonce_offset + NONCE_SIZE]);
+                    
+                    cipher.decrypt(nonce, chunk.as_ref())
+                        .map_err(|_| -2)
+                })
+                .collect()
+        });
         
         match results {
             Ok(decrypted_chunks) => {
@@ -713,7 +1439,7 @@ pub extern "C" fn decrypt_data_parallel(
                 }
                 0
             }
-            Err(code) => code,
+            Err(_) => errors::fail_with(errors::ERR_WRONG_PASSWORD),
         }
     }
 }
@@ -727,21 +1453,41 @@ pub extern "C" fn encrypt_data(
     nonce_ptr: *const u8,
     output_ptr: *mut u8,
     output_len: *mut usize,
+) -> i32 {
+    encrypt_data_with_aad(data_ptr, data_len, password_ptr, password_len, nonce_ptr, std::ptr::null(), 0, output_ptr, output_len)
+}
+
+// Records sealed with associated data only open under the same AAD, so a
+// ciphertext cannot be moved to another row or table. An empty AAD gives
+// exactly what encrypt_data produces.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn encrypt_data_with_aad(
+    data_ptr: *const u8,
+    data_len: usize,
+    password_ptr: *const u8,
+    password_len: usize,
+    nonce_ptr: *const u8,
+    aad_ptr: *const u8,
+    aad_len: usize,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
 ) -> i32 {
     unsafe {
         let data = slice::from_raw_parts(data_ptr, data_len);
         let password = slice::from_raw_parts(password_ptr, password_len);
         let nonce_bytes = slice::from_raw_parts(nonce_ptr, NONCE_SIZE);
+        let aad = if aad_ptr.is_null() { &[][..] } else { slice::from_raw_parts(aad_ptr, aad_len) };
         
-        let key = derive_key(password);
-        let cipher = match Aes256Gcm::new_from_slice(&key) {
+        let key = Zeroizing::new(derive_key(password));
+        let cipher = match Aes256Gcm::new_from_slice(&key[..]) {
             Ok(c) => c,
-            Err(_) => return -1,
+            Err(_) => return errors::invalid_argument(),
         };
         
         let nonce = Nonce::from_slice(nonce_bytes);
         
-        let encrypted = match cipher.encrypt(nonce, data) {
+        let encrypted = match cipher.encrypt(nonce, Payload { msg: data, aad }) {
             Ok(e) => e,
             Err(_) => return -2,
         };
@@ -765,23 +1511,41 @@ pub extern "C" fn decrypt_data(
     nonce_ptr: *const u8,
     output_ptr: *mut u8,
     output_len: *mut usize,
+) -> i32 {
+    decrypt_data_with_aad(encrypted_ptr, encrypted_len, password_ptr, password_len, nonce_ptr, std::ptr::null(), 0, output_ptr, output_len)
+}
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decrypt_data_with_aad(
+    encrypted_ptr: *const u8,
+    encrypted_len: usize,
+    password_ptr: *const u8,
+    password_len: usize,
+    nonce_ptr: *const u8,
+    aad_ptr: *const u8,
+    aad_len: usize,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
 ) -> i32 {
     unsafe {
         let encrypted = slice::from_raw_parts(encrypted_ptr, encrypted_len);
         let password = slice::from_raw_parts(password_ptr, password_len);
         let nonce_bytes = slice::from_raw_parts(nonce_ptr, NONCE_SIZE);
+        let aad = if aad_ptr.is_null() { &[][..] } else { slice::from_raw_parts(aad_ptr, aad_len) };
         
-        let key = derive_key(password);
-        let cipher = match Aes256Gcm::new_from_slice(&key) {
+        let key = Zeroizing::new(derive_key(password));
+        let cipher = match Aes256Gcm::new_from_slice(&key[..]) {
             Ok(c) => c,
-            Err(_) => return -1,
+            Err(_) => return errors::invalid_argument(),
         };
         
         let nonce = Nonce::from_slice(nonce_bytes);
         
-        let decrypted = match cipher.decrypt(nonce, encrypted) {
-            Ok(d) => d,
-            Err(_) => return -2,
+        // A wrong password and a mismatched AAD fail the same tag check.
+        let decrypted = match cipher.decrypt(nonce, Payload { msg: encrypted, aad }) {
+            Ok(d) => Zeroizing::new(d),
+            Err(_) => return errors::fail_with(errors::ERR_WRONG_PASSWORD),
         };
         
         *output_len = decrypted.len();
@@ -794,6 +1558,117 @@ pub extern "C" fn decrypt_data(
     }
 }
 
+// Self-contained records: the nonce is drawn here and stored in front of
+// the ciphertext, so callers never pick or keep track of one.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn encrypt_data_v2(
+    data_ptr: *const u8,
+    data_len: usize,
+    password_ptr: *const u8,
+    password_len: usize,
+    aad_ptr: *const u8,
+    aad_len: usize,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+) -> i32 {
+    if output_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let data = slice::from_raw_parts(data_ptr, data_len);
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let aad = if aad_ptr.is_null() { &[][..] } else { slice::from_raw_parts(aad_ptr, aad_len) };
+
+        // The size query needs no encryption: the blob is always
+        // prefix || nonce || ciphertext || tag.
+        *output_len = DATA_BLOB_PREFIX_LEN + NONCE_SIZE + data.len() + TAG_SIZE;
+        if output_ptr.is_null() {
+            return 0;
+        }
+        match seal_data_blob(data, password, aad) {
+            Ok(blob) => {
+                std::ptr::copy_nonoverlapping(blob.as_ptr(), output_ptr, blob.len());
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decrypt_data_v2(
+    blob_ptr: *const u8,
+    blob_len: usize,
+    password_ptr: *const u8,
+    password_len: usize,
+    aad_ptr: *const u8,
+    aad_len: usize,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+) -> i32 {
+    if output_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let blob = slice::from_raw_parts(blob_ptr, blob_len);
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let aad = if aad_ptr.is_null() { &[][..] } else { slice::from_raw_parts(aad_ptr, aad_len) };
+
+        match open_data_blob(blob, password, aad) {
+            Ok(decrypted) => {
+                *output_len = decrypted.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(decrypted.as_ptr(), output_ptr, decrypted.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+fn seal_data_blob(data: &[u8], password: &[u8], aad: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let salt: [u8; kdf::KDF_SALT_SIZE] = rng::random();
+    let params = KdfParams::for_new_files(false);
+    let prefix = [DATA_BLOB_MAGIC, &salt, &format::encode_kdf_params(&params)].concat();
+    let key = Zeroizing::new(kdf::derive(password, &salt, &params)?);
+    let cipher = Aes256Gcm::new_from_slice(&key[..])?;
+    let nonce_bytes = random_nonce();
+    let encrypted = cipher.encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: data, aad: &[&prefix[..], aad].concat() })
+        .map_err(|_| "Encryption failed")?;
+    Ok([&prefix[..], &nonce_bytes, &encrypted].concat())
+}
+
+// A legacy blob starts with its random nonce, which may happen to match the
+// magic, so one that fails as a salted blob is still tried the old way.
+fn open_data_blob(blob: &[u8], password: &[u8], aad: &[u8]) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+    if blob.starts_with(DATA_BLOB_MAGIC) && blob.len() >= DATA_BLOB_PREFIX_LEN + NONCE_SIZE + TAG_SIZE {
+        let (prefix, sealed) = blob.split_at(DATA_BLOB_PREFIX_LEN);
+        let (salt, params) = prefix[DATA_BLOB_MAGIC.len()..].split_at(kdf::KDF_SALT_SIZE);
+        if let Ok(params) = format::parse_kdf_params(params) {
+            let key = Zeroizing::new(kdf::derive(password, salt, &params)?);
+            if let Ok(decrypted) = open_sealed(&key, sealed, &[prefix, aad].concat()) {
+                return Ok(decrypted);
+            }
+        }
+    }
+    if blob.len() < NONCE_SIZE + TAG_SIZE {
+        return Err("Invalid file format".into());
+    }
+    open_sealed(&Zeroizing::new(derive_key(password)), blob, aad)
+}
+
+fn open_sealed(key: &[u8; 32], sealed: &[u8], aad: &[u8]) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+    let (nonce_bytes, encrypted) = sealed.split_at(NONCE_SIZE);
+    let cipher = Aes256Gcm::new_from_slice(key)?;
+    let decrypted = cipher.decrypt(Nonce::from_slice(nonce_bytes), Payload { msg: encrypted, aad })
+        .map_err(|_| "Decryption failed")?;
+    Ok(Zeroizing::new(decrypted))
+}
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn derive_key_ffi(
     password_ptr: *const u8,
@@ -802,12 +1677,41 @@ pub extern "C" fn derive_key_ffi(
 ) -> i32 {
     unsafe {
         let password = slice::from_raw_parts(password_ptr, password_len);
-        let key = derive_key(password);
+        let key = Zeroizing::new(derive_key(password));
         std::ptr::copy_nonoverlapping(key.as_ptr(), output_ptr, 32);
         0
     }
 }
 
+// Like derive_key_ffi, but keyed to a context the app names, such as
+// "notes.db records", so each of its uses of one password has its own key.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn derive_key_with_context(
+    password_ptr: *const u8,
+    password_len: usize,
+    context_ptr: *const u8,
+    context_len: usize,
+    output_ptr: *mut u8,
+) -> i32 {
+    if password_ptr.is_null() || context_ptr.is_null() || output_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let context = slice::from_raw_parts(context_ptr, context_len);
+        let master = Zeroizing::new(derive_key(password));
+        match kdf::context_key(&master, context) {
+            Ok(key) => {
+                let key = Zeroizing::new(key);
+                std::ptr::copy_nonoverlapping(key.as_ptr(), output_ptr, 32);
+                0
+            }
+            Err(_) => errors::invalid_argument(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -819,7 +1723,10 @@ mod tests {
         assert_eq!(key.len(), 32);
     }
 
+    // The original test, kept as written; its unsafe blocks mark the raw
+    // pointer calls even though the functions are safe to call.
     #[test]
+    #[allow(unused_unsafe)]
     fn test_encrypt_decrypt() {
         let data = b"Hello, World! This is a test message.";
         let password = b"secure_password";
@@ -862,4 +1769,136 @@ mod tests {
         decrypted.truncate(decrypted_len);
         assert_eq!(decrypted, data);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_aad_binds_a_record_to_its_context() {
+        let data = b"row payload";
+        let password = b"secure_password";
+        let nonce = [1u8; NONCE_SIZE];
+        let (row_7, row_8) = (b"records:7", b"records:8");
+        
+        let mut encrypted = vec![0u8; data.len() + TAG_SIZE];
+        let mut encrypted_len = 0usize;
+        assert_eq!(encrypt_data_with_aad(data.as_ptr(), data.len(), password.as_ptr(), password.len(), nonce.as_ptr(), row_7.as_ptr(), row_7.len(), encrypted.as_mut_ptr(), &mut encrypted_len), 0);
+        
+        let mut decrypted = vec![0u8; encrypted_len];
+        let mut decrypted_len = 0usize;
+        assert_eq!(decrypt_data_with_aad(encrypted.as_ptr(), encrypted_len, password.as_ptr(), password.len(), nonce.as_ptr(), row_7.as_ptr(), row_7.len(), decrypted.as_mut_ptr(), &mut decrypted_len), 0);
+        assert_eq!(&decrypted[..decrypted_len], data);
+        assert_ne!(decrypt_data_with_aad(encrypted.as_ptr(), encrypted_len, password.as_ptr(), password.len(), nonce.as_ptr(), row_8.as_ptr(), row_8.len(), decrypted.as_mut_ptr(), &mut decrypted_len), 0);
+        assert_ne!(decrypt_data(encrypted.as_ptr(), encrypted_len, password.as_ptr(), password.len(), nonce.as_ptr(), decrypted.as_mut_ptr(), &mut decrypted_len), 0);
+    }
+
+    #[test]
+    fn test_data_blob_carries_its_own_nonce() {
+        let blob = seal_data_blob(b"note", b"pw", b"ctx").unwrap();
+        assert_eq!(blob.len(), DATA_BLOB_PREFIX_LEN + NONCE_SIZE + 4 + TAG_SIZE);
+        assert_ne!(blob[..DATA_BLOB_PREFIX_LEN], seal_data_blob(b"note", b"pw", b"ctx").unwrap()[..DATA_BLOB_PREFIX_LEN]);
+        assert_eq!(&open_data_blob(&blob, b"pw", b"ctx").unwrap()[..], b"note");
+        assert!(open_data_blob(&blob, b"pw", b"other").is_err());
+        assert!(open_data_blob(&blob, b"wrong", b"ctx").is_err());
+        assert!(open_data_blob(&blob[..NONCE_SIZE + TAG_SIZE - 1], b"pw", b"ctx").is_err());
+
+        // Blobs written before the salt was stored still open.
+        let nonce = random_nonce();
+        let cipher = Aes256Gcm::new_from_slice(&derive_key(b"pw")).unwrap();
+        let legacy = [&nonce[..], &cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: b"note", aad: b"ctx" }).unwrap()].concat();
+        assert_eq!(&open_data_blob(&legacy, b"pw", b"ctx").unwrap()[..], b"note");
+    }
+
+    #[test]
+    fn test_small_file_fast_path_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("note.txt");
+        let encrypted = dir.path().join("note.kyl");
+        let decrypted = dir.path().join("note.out");
+        std::fs::write(&plain, b"chat attachment").unwrap();
+
+        let (plain, encrypted, decrypted) = (
+            plain.to_str().unwrap(),
+            encrypted.to_str().unwrap(),
+            decrypted.to_str().unwrap(),
+        );
+        encrypt_file_internal(plain, encrypted, b"pw", Some("hint"), false, 4).unwrap();
+        assert_eq!(get_hint_from_file_internal(encrypted).unwrap(), b"hint");
+        assert_eq!(decrypt_file_to_memory_internal(encrypted, b"pw", false, 4).unwrap(), b"chat attachment");
+
+        decrypt_file_internal(encrypted, decrypted, b"pw", false, 4).unwrap();
+        assert_eq!(std::fs::read(decrypted).unwrap(), b"chat attachment");
+        assert!(decrypt_file_internal(encrypted, decrypted, b"wrong", false, 4).is_err());
+        
+        let failed = dir.path().join("failed.out");
+        assert!(decrypt_file_internal(encrypted, failed.to_str().unwrap(), b"wrong", false, 4).is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_decrypting_a_file_derives_its_key_once() {
+        struct Counted<'a>(&'a [u8], std::cell::Cell<usize>);
+        impl KeyProvider for Counted<'_> {
+            fn key_for(&self, salt: &[u8], params: &kdf::KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+                self.1.set(self.1.get() + 1);
+                self.0.key_for(salt, params)
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.bin");
+        let encrypted = dir.path().join("plain.kyl");
+        let decrypted = dir.path().join("plain.out");
+        for len in [100, SMALL_FILE_THRESHOLD + 1] {
+            std::fs::write(&plain, vec![3u8; len]).unwrap();
+            encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+            let keys = Counted(b"pw", std::cell::Cell::new(0));
+            decrypt_file_unlogged(&encrypted, &decrypted, &keys, false, 4, &ProgressSink::none()).unwrap();
+            assert_eq!(keys.1.get(), 1);
+        }
+    }
+
+    #[test]
+    fn test_small_chunk_size_round_trips_files_under_the_small_file_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.bin");
+        let encrypted = dir.path().join("plain.kyl");
+        let decrypted = dir.path().join("plain.out");
+        let (plain, encrypted, decrypted) = (
+            plain.to_str().unwrap(),
+            encrypted.to_str().unwrap(),
+            decrypted.to_str().unwrap(),
+        );
+        let chunk_size = config::MIN_CHUNK_SIZE;
+        let small = config::KyrieConfig { chunk_size, ..Default::default() };
+        for len in [chunk_size - 1, chunk_size, chunk_size + 1, SMALL_FILE_THRESHOLD] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            std::fs::write(plain, &data).unwrap();
+            small.with(|| {
+                encrypt_file_internal(plain, encrypted, b"pw", None, false, 4).unwrap();
+                assert_eq!(format::read_layout(std::path::Path::new(encrypted)).unwrap().frames.len(), len.div_ceil(chunk_size));
+                assert_eq!(decrypt_file_to_memory_internal(encrypted, b"pw", false, 4).unwrap(), data);
+                decrypt_file_internal(encrypted, decrypted, b"pw", false, 4).unwrap();
+            });
+            assert_eq!(std::fs::read(decrypted).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_capped_decrypt_reports_true_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.bin");
+        let padded = dir.path().join("padded.kyl");
+        std::fs::write(&plain, vec![7u8; 3000]).unwrap();
+        let options = EncryptOptions { hint: None, padding: padding::PADDING_POWER_OF_TWO, ..Default::default() };
+        let (plain, padded) = (plain.to_str().unwrap(), padded.to_str().unwrap());
+        encrypt_file_reporting(plain, padded, &b"pw"[..], &options, false, 4, &ProgressSink::none()).unwrap();
+
+        assert_eq!(decrypt_file_to_memory_capped_internal(padded, b"pw", 2999, false, 4).unwrap(), Err(3000));
+        assert_eq!(decrypt_file_to_memory_capped_internal(padded, b"pw", 3000, false, 4).unwrap(), Ok(vec![7u8; 3000]));
+
+        let input_path = std::ffi::CString::new(padded).unwrap();
+        let mut len = 0usize;
+        let result = decrypt_file_to_memory_capped(
+            input_path.as_ptr(), b"pw".as_ptr(), 2, 1024, std::ptr::null_mut(), &mut len, false, 4,
+        );
+        assert_eq!((result, len, errors::kyrie_last_error()), (-2, 3000, errors::ERR_OUTPUT_TOO_LARGE));
+    }
+}
@@ -0,0 +1,456 @@
+use serde::Serialize;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::cipher::CIPHER_CHACHA20_POLY1305;
+use crate::errors;
+use crate::header_backup;
+use crate::format::{
+    read_layout, ContainerHeader, CHECKSUMS_PER_FIELD, ESCROW_SLOT_SIZE, CHECKSUM_SIZE, CHUNK_LEN_SIZE, EXTENDED_VERSION, FIELD_CHUNK_CHECKSUMS,
+    CHUNK_INDEX_SIZE, FIELD_CHUNK_INDEX, FIELD_CONVERGENT, FIELD_CRITICAL, FIELD_ESCROW, FIELD_FILE_ID, FIELD_HEADER_BACKUP, FIELD_KDF, FIELD_KEY_PURPOSE, FIELD_KEY_SHARE,
+    FIELD_NONCE_SCHEME, FIELD_PADDING, FIELD_SHARE_POLICY, FILE_ID_SIZE, KDF_ARGON2ID, KDF_FIELD_SIZE, KEY_SHARE_SIZE,
+    MAX_HEADER_LEN, NONCE_SCHEME_COUNTER, SEALED_CONTENT_KEY_SIZE, FIELD_WRAPPED_KEY, WRAPPED_KEY_SIZE, FIELD_KEY_SLOT, KEY_SLOT_SIZE,
+    FIELD_CIPHER_SUITE, FIELD_KEYFILE, FIELD_RECIPIENT, FIELD_METADATA, FIELD_MANIFEST, FIELD_FRAMING, FRAMING_SIZE, FRAMING_CONVERGENT_CHUNKS,
+    FIELD_PREVIEW, FIELD_HINT, FIELD_DEVICE_SLOT, FIELD_PARITY, FIELD_APP_METADATA, FIELD_PRIVATE_HINT, FIELD_HOLES, FIELD_CONTENT_DIGEST,
+};
+use crate::kdf::KEY_PURPOSE_DEVICE;
+use crate::digest::SEALED_DIGEST_SIZE;
+use crate::manifest::MANIFEST_SIZE;
+use crate::padding;
+use crate::{get_chunk_size, text, HEADER_SIZE, MAGIC_STRING, MAX_HINT_LENGTH, NONCE_SIZE, TAG_SIZE, VERSION};
+
+// The order the reference encoder writes fields in.
+const FIELD_ORDER: [u8; 28] = [
+    FIELD_PADDING,
+    FIELD_HEADER_BACKUP,
+    FIELD_PARITY,
+    FIELD_NONCE_SCHEME,
+    FIELD_KEY_PURPOSE,
+    FIELD_CIPHER_SUITE,
+    FIELD_KDF,
+    FIELD_KEYFILE,
+    FIELD_WRAPPED_KEY,
+    FIELD_KEY_SLOT,
+    FIELD_RECIPIENT,
+    FIELD_CONVERGENT,
+    FIELD_SHARE_POLICY,
+    FIELD_KEY_SHARE,
+    FIELD_ESCROW,
+    FIELD_DEVICE_SLOT,
+    FIELD_HINT,
+    FIELD_PRIVATE_HINT,
+    FIELD_METADATA,
+    FIELD_PREVIEW,
+    FIELD_APP_METADATA,
+    FIELD_HOLES,
+    FIELD_CHUNK_INDEX,
+    FIELD_FRAMING,
+    FIELD_MANIFEST,
+    FIELD_CONTENT_DIGEST,
+    FIELD_CHUNK_CHECKSUMS,
+    FIELD_FILE_ID,
+];
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LintIssue {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+}
+
+// Errors are deviations a conforming reader rejects or misreads; warnings
+// are files that decrypt but that the reference encoder would never write.
+#[derive(Serialize, Debug, Default)]
+pub struct LintReport {
+    pub errors: Vec<LintIssue>,
+    pub warnings: Vec<LintIssue>,
+}
+
+impl LintReport {
+    fn error(&mut self, code: &'static str, message: impl Into<String>, offset: Option<u64>) {
+        self.errors.push(LintIssue { code, message: message.into(), offset });
+    }
+
+    fn warn(&mut self, code: &'static str, message: impl Into<String>, offset: Option<u64>) {
+        self.warnings.push(LintIssue { code, message: message.into(), offset });
+    }
+
+    #[cfg(test)]
+    fn codes(&self) -> Vec<&'static str> {
+        self.errors.iter().chain(&self.warnings).map(|issue| issue.code).collect()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn lint_file(path_ptr: *const c_char, json_ptr: *mut u8, json_len: *mut usize) -> i32 {
+    if json_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let result = lint_file_internal(Path::new(path)).and_then(|report| Ok(serde_json::to_vec(&report)?));
+        match result {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn lint_file_internal(path: &Path) -> Result<LintReport, Box<dyn std::error::Error>> {
+    let mut report = LintReport::default();
+    let mut file = File::open(path)?;
+    let mut bytes = Vec::new();
+    (&mut file).take(MAX_HEADER_LEN as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() < HEADER_SIZE + 1 {
+        report.error("truncated-header", "File is shorter than the fixed header", None);
+        return Ok(report);
+    }
+    if &bytes[..MAGIC_STRING.len()] != MAGIC_STRING {
+        report.error("bad-magic", "File does not start with KYRIE_LOCK", Some(0));
+        return Ok(report);
+    }
+    let version_bytes: [u8; 4] = bytes[MAGIC_STRING.len()..HEADER_SIZE].try_into()?;
+    let version = u32::from_le_bytes(version_bytes);
+    if version != VERSION && version != EXTENDED_VERSION {
+        let message = match u32::from_be_bytes(version_bytes) {
+            VERSION | EXTENDED_VERSION => "Version is stored big-endian; the format is little-endian".to_string(),
+            _ => format!("Unknown format version {}", version),
+        };
+        report.error("unknown-version", message, Some(MAGIC_STRING.len() as u64));
+        return Ok(report);
+    }
+
+    let hint_len = bytes[HEADER_SIZE] as usize;
+    let Some(hint) = bytes.get(HEADER_SIZE + 1..HEADER_SIZE + 1 + hint_len) else {
+        report.error("truncated-header", "Hint runs past the end of the file", Some(HEADER_SIZE as u64));
+        return Ok(report);
+    };
+    lint_hint(&mut report, hint);
+
+    if version == EXTENDED_VERSION {
+        let start = HEADER_SIZE + 1 + hint_len;
+        let fields = bytes
+            .get(start..start + 2)
+            .map(|len| u16::from_le_bytes([len[0], len[1]]) as usize)
+            .and_then(|len| bytes.get(start + 2..start + 2 + len));
+        match fields {
+            Some([]) => report.warn("empty-field-area", "Version 2 header with no fields; write version 1", Some(start as u64)),
+            Some(fields) => lint_fields(&mut report, fields, (start + 2) as u64),
+            None => {
+                report.error("truncated-header", "Field area runs past the end of the file", Some(start as u64));
+                return Ok(report);
+            }
+        }
+    }
+
+    // Everything below needs the header as a conforming reader sees it.
+    let (header, data_start) = match ContainerHeader::read(&mut bytes.as_slice()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            report.error("header-rejected", e.to_string(), None);
+            return Ok(report);
+        }
+    };
+    lint_header(&mut report, &header);
+    if header.backup {
+        match header_backup::read_trailer(&mut file)? {
+            None => report.error("missing-backup", "Header is marked as backed up but the file has no trailer", None),
+            Some(backup) if backup != bytes[..data_start as usize] => {
+                report.warn("stale-backup", "Backup header differs from the header", None)
+            }
+            Some(_) => {}
+        }
+    }
+    lint_body(&mut report, path, &mut file, &header, data_start)?;
+    Ok(report)
+}
+
+fn lint_hint(report: &mut LintReport, hint: &[u8]) {
+    let offset = Some(HEADER_SIZE as u64 + 1);
+    match std::str::from_utf8(hint) {
+        Err(_) => report.warn("hint-not-utf8", "Hint is not valid UTF-8", offset),
+        Ok(hint) if text::normalize(hint) != hint => report.warn("hint-not-nfc", "Hint is not in Unicode NFC form", offset),
+        Ok(_) => {}
+    }
+    if hint.len() > MAX_HINT_LENGTH {
+        report.warn("hint-too-long", format!("Hint is longer than {} bytes", MAX_HINT_LENGTH), offset);
+    }
+}
+
+fn lint_fields(report: &mut LintReport, mut fields: &[u8], mut offset: u64) {
+    let mut last_rank = 0;
+    let mut seen: Vec<u8> = Vec::new();
+    let mut short_checksum_group = None;
+    while !fields.is_empty() {
+        let (tag, len) = match fields {
+            [tag, len, ..] if fields.len() >= 2 + *len as usize => (*tag, *len as usize),
+            _ => {
+                report.error("truncated-field", "Field runs past the end of the field area", Some(offset));
+                return;
+            }
+        };
+        let value = &fields[2..2 + len];
+        let at = Some(offset);
+
+        match FIELD_ORDER.iter().position(|&known| known == tag) {
+            Some(rank) => {
+                if rank < last_rank {
+                    report.warn("field-order", format!("Field 0x{:02x} is out of canonical order", tag), at);
+                }
+                last_rank = rank;
+                let repeatable = tag == FIELD_KEY_SHARE
+                    || tag == FIELD_ESCROW
+                    || tag == FIELD_RECIPIENT
+                    || tag == FIELD_KEY_SLOT
+                    || tag == FIELD_DEVICE_SLOT
+                    || tag == FIELD_HINT
+                    || tag == FIELD_METADATA
+                    || tag == FIELD_PREVIEW
+                    || tag == FIELD_APP_METADATA
+                    || tag == FIELD_HOLES
+                    || tag == FIELD_CHUNK_CHECKSUMS;
+                if !repeatable && seen.contains(&tag) {
+                    report.warn("duplicate-field", format!("Field 0x{:02x} appears more than once", tag), at);
+                }
+                seen.push(tag);
+            }
+            None if tag & FIELD_CRITICAL != 0 => {
+                report.error("unknown-critical-field", format!("Unknown critical field 0x{:02x}", tag), at)
+            }
+            None => report.warn("unknown-field", format!("Unknown field 0x{:02x} is ignored by readers", tag), at),
+        }
+
+        match tag {
+            FIELD_PADDING => match value {
+                [scheme] if *scheme != 0 && padding::is_valid_scheme(*scheme) => {}
+                _ => lint_byte_field(report, value, 0, "padding", at),
+            },
+            FIELD_NONCE_SCHEME => lint_byte_field(report, value, NONCE_SCHEME_COUNTER, "nonce scheme", at),
+            FIELD_KEY_PURPOSE => lint_byte_field(report, value, KEY_PURPOSE_DEVICE, "key purpose", at),
+            FIELD_CIPHER_SUITE => lint_byte_field(report, value, CIPHER_CHACHA20_POLY1305, "cipher suite", at),
+            FIELD_HEADER_BACKUP if len != 0 => report.error("bad-field-length", "Header backup marker must be empty", at),
+            FIELD_KEYFILE if len != 0 => report.error("bad-field-length", "Keyfile marker must be empty", at),
+            FIELD_KEY_SHARE if len != KEY_SHARE_SIZE => report.error("bad-field-length", "Key share has the wrong size", at),
+            FIELD_KDF if len != KDF_FIELD_SIZE => report.error("bad-field-length", "KDF field has the wrong size", at),
+            FIELD_KDF if value[0] != KDF_ARGON2ID => {
+                report.error("bad-field-value", format!("Unknown KDF {}", value[0]), at)
+            }
+            FIELD_WRAPPED_KEY if len != WRAPPED_KEY_SIZE => report.error("bad-field-length", "Wrapped key has the wrong size", at),
+            FIELD_CONVERGENT if len != SEALED_CONTENT_KEY_SIZE => {
+                report.error("bad-field-length", "Sealed content key has the wrong size", at)
+            }
+            FIELD_SHARE_POLICY => match value {
+                [threshold, count] if 0 < *threshold && threshold <= count => {}
+                _ => report.error("bad-field-value", "Share threshold must be between 1 and the share count", at),
+            },
+            FIELD_CHUNK_CHECKSUMS => {
+                if len % CHECKSUM_SIZE != 0 {
+                    report.warn("bad-field-length", "Checksum field is not a whole number of checksums and is ignored", at);
+                }
+                if let Some(short_at) = short_checksum_group.take() {
+                    report.warn("short-checksum-group", "Only the last checksum field may be partly filled", short_at);
+                }
+                if len / CHECKSUM_SIZE < CHECKSUMS_PER_FIELD {
+                    short_checksum_group = Some(at);
+                }
+            }
+            FIELD_CHUNK_INDEX if len != CHUNK_INDEX_SIZE || value == [0; CHUNK_INDEX_SIZE] => {
+                report.warn("bad-field-value", "Chunk index is malformed and is ignored", at)
+            }
+            FIELD_FRAMING if len != FRAMING_SIZE => {
+                report.warn("bad-field-length", "Framing field has the wrong size and is ignored", at)
+            }
+            FIELD_FRAMING if value[0] > FRAMING_CONVERGENT_CHUNKS => {
+                report.error("bad-field-value", format!("Unknown framing version {}", value[0]), at)
+            }
+            FIELD_MANIFEST if len != MANIFEST_SIZE => {
+                report.warn("bad-field-length", "Manifest has the wrong size and is ignored", at)
+            }
+            FIELD_CONTENT_DIGEST if len != SEALED_DIGEST_SIZE => {
+                report.warn("bad-field-length", "Content digest has the wrong size and is ignored", at)
+            }
+            FIELD_KEY_SLOT if len != KEY_SLOT_SIZE => {
+                report.warn("bad-field-length", "Key slot has the wrong size and is ignored", at)
+            }
+            FIELD_RECIPIENT if len != ESCROW_SLOT_SIZE => report.error("bad-field-length", "Recipient slot has the wrong size", at),
+            FIELD_ESCROW if len != ESCROW_SLOT_SIZE => {
+                report.warn("bad-field-length", "Escrow slot has the wrong size and is ignored", at)
+            }
+            FIELD_FILE_ID if len != 2 * FILE_ID_SIZE && len != 3 * FILE_ID_SIZE => {
+                report.warn("bad-field-length", "File id has the wrong size and is ignored", at)
+            }
+            FIELD_FILE_ID if value[6] >> 4 != 4 || value[8] & 0xc0 != 0x80 => {
+                report.warn("file-id-not-uuid4", "File id is not a version 4 UUID", at)
+            }
+            _ => {}
+        }
+
+        fields = &fields[2 + len..];
+        offset += 2 + len as u64;
+    }
+}
+
+fn lint_byte_field(report: &mut LintReport, value: &[u8], max: u8, name: &str, at: Option<u64>) {
+    match value {
+        [0] => report.warn("default-field-value", format!("Explicit default {}; omit the field", name), at),
+        [v] if *v <= max => {}
+        [v] => report.error("bad-field-value", format!("Unknown {} {}", name, v), at),
+        _ => report.error("bad-field-length", format!("The {} field must be one byte", name), at),
+    }
+}
+
+fn lint_header(report: &mut LintReport, header: &ContainerHeader) {
+    if header.nonce_scheme == NONCE_SCHEME_COUNTER && header.file_id.is_none() {
+        report.error("counter-without-file-id", "Counter nonces need a file id to derive their prefix", None);
+    }
+    if header.dual() && header.nonce_scheme == NONCE_SCHEME_COUNTER {
+        report.warn("slots-with-counter-nonces", "Two-slot containers are written with random nonces", None);
+    }
+}
+
+fn lint_body(
+    report: &mut LintReport,
+    path: &Path,
+    file: &mut File,
+    header: &ContainerHeader,
+    data_start: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let layout = match read_layout(path) {
+        Ok(layout) => layout,
+        Err(e) => {
+            report.error("body-rejected", e.to_string(), Some(data_start));
+            return Ok(());
+        }
+    };
+    let file_len = header.body_end(data_start, file.metadata()?.len());
+    let nonce_len = if header.nonce_scheme == NONCE_SCHEME_COUNTER { 0 } else { NONCE_SIZE as u64 };
+
+    if layout.single_chunk {
+        // A lone length-prefixed frame is read as an unframed chunk whose
+        // ciphertext starts with the length, so it never decrypts.
+        if frame_count(file, data_start, file_len, nonce_len, u32::from_be_bytes)? == Some(1) {
+            report.error("framed-single-chunk", "Single-chunk bodies must not carry a length field", Some(data_start));
+        } else if frame_count(file, data_start, file_len, nonce_len, u32::from_le_bytes)?.is_some_and(|n| n > 1) {
+            report.error("chunk-length-endian", "Chunk lengths are stored little-endian; the format is big-endian", Some(data_start));
+        }
+    } else {
+        let max_len = (get_chunk_size(false) + TAG_SIZE) as u64;
+        let first_len = layout.frames[0].len;
+        let body_frames = if layout.dual { layout.frames.len() / 2 } else { layout.frames.len() };
+        for (position, frame) in layout.frames.iter().enumerate() {
+            let last_in_slot = (position + 1) % body_frames == 0;
+            if frame.len > max_len {
+                report.warn("oversized-chunk", format!("Chunk {} is larger than any reader's chunk size", frame.index), Some(frame.offset));
+            }
+            if !last_in_slot && frame.len != first_len {
+                report.warn("uneven-chunks", format!("Chunk {} differs in size from the first chunk", frame.index), Some(frame.offset));
+            }
+            if frame.len == TAG_SIZE as u64 && !last_in_slot {
+                report.warn("empty-chunk", format!("Chunk {} holds no data", frame.index), Some(frame.offset));
+            }
+        }
+    }
+
+    if !header.chunk_checksums.is_empty() && header.chunk_checksums.len() != layout.frames.len() {
+        report.error(
+            "checksum-count",
+            format!("{} checksums for {} chunks", header.chunk_checksums.len(), layout.frames.len()),
+            None,
+        );
+    }
+    Ok(())
+}
+
+fn frame_count(
+    file: &mut File,
+    data_start: u64,
+    file_len: u64,
+    nonce_len: u64,
+    decode: fn([u8; 4]) -> u32,
+) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+    let mut offset = data_start;
+    let mut count = 0;
+    while offset < file_len {
+        let len_offset = offset + nonce_len;
+        if len_offset + CHUNK_LEN_SIZE > file_len {
+            return Ok(None);
+        }
+        let mut len = [0u8; CHUNK_LEN_SIZE as usize];
+        file.seek(SeekFrom::Start(len_offset))?;
+        file.read_exact(&mut len)?;
+        let len = decode(len) as u64;
+        offset = len_offset + CHUNK_LEN_SIZE + len;
+        if len < TAG_SIZE as u64 || offset > file_len {
+            return Ok(None);
+        }
+        count += 1;
+    }
+    Ok(Some(count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+    use crate::test_util::write_framed_container;
+    use std::fs;
+
+    #[test]
+    fn test_reference_files_are_clean() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        fs::write(&plain, b"lint me").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", Some("hint"), false, 4).unwrap();
+        assert!(lint_file_internal(&encrypted).unwrap().codes().is_empty());
+
+        let framed = dir.path().join("framed.kyl");
+        write_framed_container(&framed, b"pw", &[b"first", b"fifth", b"x"]);
+        assert!(lint_file_internal(&framed).unwrap().codes().is_empty());
+    }
+
+    #[test]
+    fn test_deviations_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("odd.kyl");
+        let mut bytes = MAGIC_STRING.to_vec();
+        bytes.extend_from_slice(&EXTENDED_VERSION.to_le_bytes());
+        let hint = "re\u{301}sume\u{301}".as_bytes();
+        bytes.push(hint.len() as u8);
+        bytes.extend_from_slice(hint);
+        let fields = [FIELD_KEY_PURPOSE, 1, 0, FIELD_PADDING, 1, 0, 0x11, 2, 0xaa, 0xbb];
+        bytes.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&fields);
+        bytes.extend_from_slice(&[0u8; NONCE_SIZE]);
+        bytes.extend_from_slice(&(20u32).to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 20]);
+        bytes.extend_from_slice(&[0u8; NONCE_SIZE]);
+        bytes.extend_from_slice(&(16u32).to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 16]);
+        fs::write(&path, &bytes).unwrap();
+
+        let report = lint_file_internal(&path).unwrap();
+        assert_eq!(
+            report.codes(),
+            ["chunk-length-endian", "hint-not-nfc", "default-field-value", "field-order", "default-field-value", "unknown-field"]
+        );
+
+        bytes[MAGIC_STRING.len()..HEADER_SIZE].copy_from_slice(&EXTENDED_VERSION.to_be_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(lint_file_internal(&path).unwrap().codes(), ["unknown-version"]);
+    }
+}
@@ -0,0 +1,234 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::for_each_decrypted_chunk;
+use crate::reencrypt::rekey_in_place;
+use crate::vault::{unix_now, Vault};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+pub struct MaintenancePolicy {
+    pub rotate_after_days: u32,
+    pub verify_after_days: u32,
+    pub max_operations: usize,
+}
+
+#[derive(Default)]
+pub struct MaintenanceReport {
+    pub rotated: Vec<String>,
+    pub verified: Vec<String>,
+    pub failed: Vec<String>,
+    pub remaining: usize,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieMaintenanceReport {
+    pub rotated: u64,
+    pub verified: u64,
+    pub failed: u64,
+    pub remaining: u64,
+}
+
+enum Task {
+    Rotate,
+    Verify,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_run_maintenance(
+    vault_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    rotate_after_days: u32,
+    verify_after_days: u32,
+    max_operations: usize,
+    is_mobile: bool,
+    report_ptr: *mut KyrieMaintenanceReport,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let policy = MaintenancePolicy {
+            rotate_after_days,
+            verify_after_days,
+            max_operations,
+        };
+
+        match run_maintenance(Path::new(vault_dir), password, &policy, is_mobile) {
+            Ok(report) => {
+                if !report_ptr.is_null() {
+                    *report_ptr = KyrieMaintenanceReport {
+                        rotated: report.rotated.len() as u64,
+                        verified: report.verified.len() as u64,
+                        failed: report.failed.len() as u64,
+                        remaining: report.remaining as u64,
+                    };
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn run_maintenance(
+    vault_dir: &Path,
+    password: &[u8],
+    policy: &MaintenancePolicy,
+    is_mobile: bool,
+) -> Result<MaintenanceReport, Box<dyn std::error::Error>> {
+    let mut vault = Vault::open(vault_dir)?;
+    if vault.is_signed() {
+        vault.unlock_signing(password)?;
+    }
+    let now = unix_now();
+    let rotate_after = policy.rotate_after_days as u64 * SECONDS_PER_DAY;
+    let verify_after = policy.verify_after_days as u64 * SECONDS_PER_DAY;
+
+    let mut due: Vec<(u64, String, Task)> = vault
+        .entries()
+        .iter()
+        .filter_map(|entry| {
+            if policy.rotate_after_days > 0 && now.saturating_sub(entry.keyed_at) >= rotate_after {
+                Some((entry.keyed_at, entry.file_name.clone(), Task::Rotate))
+            } else if policy.verify_after_days > 0
+                && now.saturating_sub(entry.verified_at) >= verify_after
+            {
+                Some((entry.verified_at, entry.file_name.clone(), Task::Verify))
+            } else {
+                None
+            }
+        })
+        .collect();
+    due.sort_by_key(|(since, _, _)| *since);
+
+    let limit = if policy.max_operations == 0 {
+        due.len()
+    } else {
+        policy.max_operations.min(due.len())
+    };
+
+    let mut report = MaintenanceReport {
+        remaining: due.len() - limit,
+        ..Default::default()
+    };
+
+    for (_, file_name, task) in due.into_iter().take(limit) {
+        let path = vault.entry_path(&file_name);
+        let result = match task {
+            Task::Rotate => {
+                rekey_in_place(&path, password, is_mobile).and_then(|_| vault.refresh_entry(&file_name))
+            }
+            Task::Verify => verify_file(&path, password, is_mobile),
+        };
+
+        let now = unix_now();
+        match (result, vault.entry_mut(&file_name)) {
+            (Ok(()), Some(entry)) => {
+                entry.verified_at = now;
+                match task {
+                    Task::Rotate => {
+                        entry.keyed_at = now;
+                        report.rotated.push(file_name);
+                    }
+                    Task::Verify => report.verified.push(file_name),
+                }
+            }
+            _ => report.failed.push(file_name),
+        }
+        vault.save()?;
+    }
+
+    vault.save()?;
+    Ok(report)
+}
+
+fn verify_file(
+    path: &Path,
+    password: &[u8],
+    is_mobile: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_path = path.to_str().ok_or("Invalid path")?;
+    for_each_decrypted_chunk(input_path, password, is_mobile, |_, _| Ok(())).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_to_memory_internal, encrypt_file_internal, get_hint_from_file_internal};
+    use std::fs;
+
+    #[test]
+    fn test_maintenance_rotates_and_respects_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        fs::write(&plain, b"vault maintenance").unwrap();
+        for name in ["a.txt.kyl", "b.txt.kyl"] {
+            let output = dir.path().join(name);
+            encrypt_file_internal(
+                plain.to_str().unwrap(),
+                output.to_str().unwrap(),
+                b"pw",
+                Some("hint"),
+                false,
+                4,
+            )
+            .unwrap();
+        }
+
+        let policy = MaintenancePolicy {
+            rotate_after_days: 0,
+            verify_after_days: 0,
+            max_operations: 0,
+        };
+        let report = run_maintenance(dir.path(), b"pw", &policy, false).unwrap();
+        assert!(report.rotated.is_empty() && report.verified.is_empty());
+
+        let mut vault = Vault::open(dir.path()).unwrap();
+        for name in ["a.txt.kyl", "b.txt.kyl"] {
+            vault.entry_mut(name).unwrap().keyed_at = 0;
+        }
+        vault.save().unwrap();
+
+        let policy = MaintenancePolicy {
+            rotate_after_days: 30,
+            verify_after_days: 0,
+            max_operations: 1,
+        };
+        let report = run_maintenance(dir.path(), b"pw", &policy, false).unwrap();
+        assert_eq!(report.rotated.len(), 1);
+        assert_eq!(report.remaining, 1);
+
+        let rotated = dir.path().join(&report.rotated[0]);
+        let data =
+            decrypt_file_to_memory_internal(rotated.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(data, b"vault maintenance");
+        assert_eq!(
+            get_hint_from_file_internal(rotated.to_str().unwrap()).unwrap(),
+            b"hint"
+        );
+    }
+
+    #[test]
+    fn test_maintenance_reports_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("broken.kyl"), b"not an encrypted file").unwrap();
+
+        let mut vault = Vault::open(dir.path()).unwrap();
+        vault.entry_mut("broken.kyl").unwrap().verified_at = 0;
+        vault.save().unwrap();
+
+        let policy = MaintenancePolicy {
+            rotate_after_days: 0,
+            verify_after_days: 30,
+            max_operations: 0,
+        };
+        let report = run_maintenance(dir.path(), b"pw", &policy, false).unwrap();
+        assert_eq!(report.failed, vec!["broken.kyl".to_string()]);
+    }
+}
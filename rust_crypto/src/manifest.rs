@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::TAG_SIZE;
+
+pub const MANIFEST_SIZE: usize = 8 + 8 + 32;
+
+// What the body has to add up to: its frame count, its plaintext length
+// and a hash chained over every frame tag in order. It is kept in the
+// authenticated header, so frames that were dropped, repeated or swapped
+// are caught even in files whose nonces do not encode the frame index.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Manifest {
+    pub chunk_count: u64,
+    pub plaintext_len: u64,
+    pub chain: [u8; 32],
+}
+
+impl Manifest {
+    pub fn encode(&self) -> Vec<u8> {
+        [&self.chunk_count.to_le_bytes()[..], &self.plaintext_len.to_le_bytes(), &self.chain].concat()
+    }
+
+    pub fn parse(value: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let (chunk_count, rest) = value.split_at(8);
+        let (plaintext_len, chain) = rest.split_at(8);
+        Ok(Manifest {
+            chunk_count: u64::from_le_bytes(chunk_count.try_into()?),
+            plaintext_len: u64::from_le_bytes(plaintext_len.try_into()?),
+            chain: chain.try_into()?,
+        })
+    }
+}
+
+// Serializable so that a checkpointed run can pick the chain up again.
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct ManifestBuilder {
+    chunk_count: u64,
+    plaintext_len: u64,
+    chain: [u8; 32],
+}
+
+impl ManifestBuilder {
+    // Takes the frame ciphertext with its tag, before it is opened in place.
+    pub fn record_frame(&mut self, ciphertext: &[u8]) {
+        let tag = &ciphertext[ciphertext.len().saturating_sub(TAG_SIZE)..];
+        self.chain = Sha256::new()
+            .chain_update(self.chain)
+            .chain_update(self.chunk_count.to_le_bytes())
+            .chain_update(tag)
+            .finalize()
+            .into();
+        self.chunk_count += 1;
+    }
+
+    pub fn record_plaintext(&mut self, len: usize) {
+        self.plaintext_len += len as u64;
+    }
+
+    pub fn finish(&self) -> Manifest {
+        Manifest {
+            chunk_count: self.chunk_count,
+            plaintext_len: self.plaintext_len,
+            chain: self.chain,
+        }
+    }
+
+    // Files written before the manifest have nothing to check against.
+    pub fn check(&self, expected: Option<&Manifest>) -> Result<(), Box<dyn std::error::Error>> {
+        match expected {
+            Some(expected) if *expected != self.finish() => Err("Body does not match its manifest".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{read_header, ContainerHeader};
+    use crate::{decrypt_file_internal, decrypt_file_to_memory_internal, encrypt_file_internal};
+    use std::fs;
+
+    #[test]
+    fn test_reordered_or_dropped_frames_fail_the_manifest() {
+        let frames: [&[u8]; 3] = [&[1; 20], &[2; 20], &[3; 20]];
+        let build = |order: &[usize]| {
+            let mut builder = ManifestBuilder::default();
+            for &i in order {
+                builder.record_frame(frames[i]);
+                builder.record_plaintext(4);
+            }
+            builder
+        };
+        let expected = build(&[0, 1, 2]).finish();
+        assert_eq!(Manifest::parse(&expected.encode()).unwrap(), expected);
+        assert!(build(&[0, 1, 2]).check(Some(&expected)).is_ok());
+        assert!(build(&[1, 0, 2]).check(Some(&expected)).is_err());
+        assert!(build(&[0, 1]).check(Some(&expected)).is_err());
+        assert!(build(&[0, 1, 1]).check(Some(&expected)).is_err());
+        assert!(build(&[0, 1]).check(None).is_ok());
+    }
+
+    #[test]
+    fn test_new_files_carry_a_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        let output = dir.path().join("out.txt");
+        fs::write(&plain, b"manifested").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+
+        let (header, header_len) = read_header(&encrypted).unwrap();
+        let manifest = header.manifest.unwrap();
+        assert_eq!((manifest.chunk_count, manifest.plaintext_len), (1, 10));
+        let path = encrypted.to_str().unwrap();
+        assert_eq!(decrypt_file_to_memory_internal(path, b"pw", false, 4).unwrap(), b"manifested");
+
+        // A manifest that disagrees with the body, even under a valid
+        // header tag, stops decryption before anything is written.
+        let mut data = fs::read(&encrypted).unwrap();
+        let key = header.master_key(&b"pw"[..]).unwrap();
+        let mut forged = ContainerHeader { manifest: Some(Manifest { plaintext_len: 9, ..manifest }), ..header };
+        forged.seal_file_id(&key);
+        data.splice(..header_len as usize, forged.encode());
+        fs::write(&encrypted, &data).unwrap();
+        assert!(decrypt_file_to_memory_internal(path, b"pw", false, 4).is_err());
+        assert!(decrypt_file_internal(path, output.to_str().unwrap(), b"pw", false, 4).is_err());
+        assert!(!output.exists());
+    }
+}
@@ -0,0 +1,155 @@
+use aes_gcm::{aead::Aead, Nonce};
+use rayon::prelude::*;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use crate::cipher::BodyCipher;
+use crate::config;
+use crate::digest::ContentHasher;
+use crate::nonce::NonceSequence;
+use crate::padding::PADDING_NONE;
+use crate::progress::{ProgressSink, PROGRESS_PHASE_ENCRYPTING, PROGRESS_PHASE_READING, PROGRESS_PHASE_WRITING};
+use crate::NONCE_SIZE;
+
+// Off by default: a mapped file truncated by another process faults the
+// reader instead of failing a read, so desktop callers opt in.
+static MEMORY_MAPPED_IO: AtomicBool = AtomicBool::new(false);
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_memory_mapped_io(enabled: bool) {
+    MEMORY_MAPPED_IO.store(enabled, Ordering::SeqCst);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub type InputMap = memmap2::Mmap;
+#[cfg(target_arch = "wasm32")]
+pub type InputMap = Vec<u8>;
+
+// Maps the input for encryption, or returns None to stream it. Mobile
+// address space is too tight for whole-file maps, padded files need bytes
+// the map does not have, and releasable handles cannot stay mapped across
+// a pause.
+pub fn map_input(path: &Path, content_size: u64, padding: u8, is_mobile: bool, progress: &ProgressSink) -> io::Result<Option<InputMap>> {
+    if !MEMORY_MAPPED_IO.load(Ordering::SeqCst) || is_mobile || padding != PADDING_NONE || progress.handles().is_some() {
+        return Ok(None);
+    }
+    map(path, content_size)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn map(path: &Path, content_size: u64) -> io::Result<Option<InputMap>> {
+    let file = std::fs::File::open(path)?;
+    // Sized from the length the header was planned with; a file that has
+    // since shrunk is left to the streaming path to report.
+    if file.metadata()?.len() < content_size || content_size > usize::MAX as u64 {
+        return Ok(None);
+    }
+    let map = unsafe { memmap2::MmapOptions::new().len(content_size as usize).map(&file)? };
+    Ok(Some(map))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn map(_path: &Path, _content_size: u64) -> io::Result<Option<InputMap>> {
+    Ok(None)
+}
+
+// Seals each chunk straight from the map, so its bytes are copied once, into
+// the ciphertext, rather than read into a buffer first. Workers take a
+// batch of subslices at a time and `write` gets the frames in order.
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_batches<F>(
+    map: &[u8],
+    chunk_size: usize,
+    batch_size: usize,
+    cipher: &BodyCipher,
+    nonce_seq: &mut NonceSequence,
+    hasher: &mut ContentHasher,
+    progress: &ProgressSink,
+    mut write: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(usize, &[u8; NONCE_SIZE], &[u8]) -> io::Result<()>,
+{
+    let mut processed = 0;
+    let mut index = 0;
+    for batch in map.chunks(chunk_size * batch_size) {
+        progress.check_cancelled()?;
+        progress.report_bytes(PROGRESS_PHASE_READING, processed + batch.len(), map.len());
+        let chunks: Vec<&[u8]> = batch.chunks(chunk_size).collect();
+        let nonces = chunks.iter().map(|_| nonce_seq.next_nonce()).collect::<Result<Vec<_>, _>>()?;
+
+        let first_index = index;
+        let started = Instant::now();
+        let (_, encrypted): (_, Result<Vec<Vec<u8>>, &str>) = config::install(|| {
+            rayon::join(
+                || hasher.update(batch),
+                || {
+                    chunks
+                        .par_iter()
+                        .zip(nonces.par_iter())
+                        .enumerate()
+                        .map(|(offset, (chunk, nonce_bytes))| {
+                            let started = Instant::now();
+                            let encrypted = cipher.encrypt(Nonce::from_slice(nonce_bytes), *chunk).map_err(|_| "Encryption failed")?;
+                            progress.time_crypto(first_index + offset, started);
+                            Ok(encrypted)
+                        })
+                        .collect()
+                },
+            )
+        });
+        let encrypted = encrypted?;
+        progress.time_crypto_stage(started);
+        progress.report_bytes(PROGRESS_PHASE_ENCRYPTING, processed + batch.len(), map.len());
+
+        for (encrypted, nonce_bytes) in encrypted.iter().zip(nonces.iter()) {
+            let started = Instant::now();
+            write(index, nonce_bytes, encrypted)?;
+            progress.time_write(index, started);
+            index += 1;
+        }
+        processed += batch.len();
+        progress.report_bytes(PROGRESS_PHASE_WRITING, processed, map.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::CIPHER_AES_256_GCM;
+    use std::fs;
+
+    #[test]
+    fn test_mapped_batches_match_the_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("video.bin");
+        let content: Vec<u8> = (0..1024 * 1024 + 17u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&plain, &content).unwrap();
+
+        kyrie_set_memory_mapped_io(true);
+        let mobile = map_input(&plain, content.len() as u64, PADDING_NONE, true, &ProgressSink::none()).unwrap();
+        let desktop = map_input(&plain, content.len() as u64, PADDING_NONE, false, &ProgressSink::none()).unwrap();
+        kyrie_set_memory_mapped_io(false);
+        assert!(mobile.is_none());
+        let map = desktop.unwrap();
+
+        let cipher = BodyCipher::new(CIPHER_AES_256_GCM, &[7; 32]).unwrap();
+        let mut nonce_seq = NonceSequence::Counter { prefix: [1; 8], next: 0 };
+        let mut hasher = ContentHasher::new(content.len() as u64);
+        let mut frames = Vec::new();
+        encrypt_batches(&map, 64 * 1024, 3, &cipher, &mut nonce_seq, &mut hasher, &ProgressSink::none(), |index, nonce, encrypted| {
+            assert_eq!(index, frames.len());
+            frames.push(cipher.decrypt(Nonce::from_slice(nonce), encrypted).unwrap());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(frames.len(), content.len().div_ceil(64 * 1024));
+        assert_eq!(frames.concat(), content);
+        let mut expected = ContentHasher::new(content.len() as u64);
+        expected.update(&content);
+        assert_eq!(hasher.finalize(), expected.finalize());
+    }
+}
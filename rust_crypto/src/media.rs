@@ -0,0 +1,493 @@
+use std::ffi::CStr;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::range::{decrypt_prefix_internal, DecryptingReader};
+
+pub const MEDIA_KIND_UNKNOWN: i32 = 0;
+pub const MEDIA_KIND_IMAGE: i32 = 1;
+pub const MEDIA_KIND_VIDEO: i32 = 2;
+
+const MAX_EXIF_SEGMENT: usize = 64 * 1024;
+const MAX_BOX_DEPTH: usize = 8;
+const SNIFF_LEN: usize = 512;
+
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+pub struct KyrieMediaMetadata {
+    pub kind: i32,
+    pub width: u32,
+    pub height: u32,
+    pub duration_ms: u64,
+    pub orientation: u16,
+}
+
+impl Default for KyrieMediaMetadata {
+    fn default() -> Self {
+        KyrieMediaMetadata {
+            kind: MEDIA_KIND_UNKNOWN,
+            width: 0,
+            height: 0,
+            duration_ms: 0,
+            orientation: 1,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_media_metadata(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    metadata_ptr: *mut KyrieMediaMetadata,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        if metadata_ptr.is_null() {
+            return -1;
+        }
+
+        match get_media_metadata_internal(Path::new(input_path), password) {
+            Ok(metadata) => {
+                *metadata_ptr = metadata;
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn get_media_metadata_internal(
+    input_path: &Path,
+    password: &[u8],
+) -> Result<KyrieMediaMetadata, Box<dyn std::error::Error>> {
+    let mut reader = DecryptingReader::open(input_path, password)?;
+    Ok(parse_media(&mut reader)?)
+}
+
+#[no_mangle]
+pub extern "C" fn detect_content_type(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    mime_ptr: *mut u8,
+    mime_len: *mut usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match detect_content_type_internal(Path::new(input_path), password) {
+            Ok(mime) => {
+                *mime_len = mime.len();
+                if !mime_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(mime.as_ptr(), mime_ptr, mime.len());
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn detect_content_type_internal(
+    input_path: &Path,
+    password: &[u8],
+) -> Result<&'static str, Box<dyn std::error::Error>> {
+    let prefix = decrypt_prefix_internal(input_path, password, SNIFF_LEN)?;
+    Ok(sniff_mime(&prefix))
+}
+
+pub fn sniff_mime(data: &[u8]) -> &'static str {
+    let starts = |magic: &[u8]| data.starts_with(magic);
+    let at = |offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+
+    if starts(&[0xFF, 0xD8, 0xFF]) {
+        "image/jpeg"
+    } else if starts(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        "image/gif"
+    } else if starts(b"RIFF") && at(8, b"WEBP") {
+        "image/webp"
+    } else if starts(b"RIFF") && at(8, b"WAVE") {
+        "audio/wav"
+    } else if starts(b"RIFF") && at(8, b"AVI ") {
+        "video/x-msvideo"
+    } else if starts(b"BM") && data.len() >= 14 {
+        "image/bmp"
+    } else if starts(b"II*\0") || starts(b"MM\0*") {
+        "image/tiff"
+    } else if at(4, b"ftyp") {
+        match data.get(8..12) {
+            Some(b"heic") | Some(b"heix") | Some(b"mif1") | Some(b"msf1") => "image/heic",
+            Some(b"avif") => "image/avif",
+            Some(b"qt  ") => "video/quicktime",
+            Some(b"M4A ") => "audio/mp4",
+            _ => "video/mp4",
+        }
+    } else if starts(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        "video/webm"
+    } else if starts(b"ID3") || (data.len() >= 2 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0) {
+        "audio/mpeg"
+    } else if starts(b"fLaC") {
+        "audio/flac"
+    } else if starts(b"OggS") {
+        "audio/ogg"
+    } else if starts(b"%PDF-") {
+        "application/pdf"
+    } else if starts(b"PK\x03\x04") {
+        "application/zip"
+    } else if starts(&[0x1F, 0x8B]) {
+        "application/gzip"
+    } else if starts(b"7z\xBC\xAF\x27\x1C") {
+        "application/x-7z-compressed"
+    } else if looks_like_text(data) {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+fn looks_like_text(data: &[u8]) -> bool {
+    if data.is_empty() {
+        return false;
+    }
+    let valid = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&data[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return false,
+    };
+    valid.chars().all(|c| !c.is_control() || c.is_whitespace())
+}
+
+pub fn parse_media<R: Read + Seek>(reader: &mut R) -> io::Result<KyrieMediaMetadata> {
+    let mut magic = [0u8; 12];
+    let n = read_up_to(reader, &mut magic)?;
+    let magic = &magic[..n];
+    reader.seek(SeekFrom::Start(0))?;
+
+    if magic.starts_with(b"\x89PNG\r\n\x1a\n") {
+        parse_png(reader)
+    } else if magic.starts_with(&[0xFF, 0xD8]) {
+        parse_jpeg(reader)
+    } else if magic.starts_with(b"GIF8") {
+        parse_gif(reader)
+    } else if magic.len() >= 8 && &magic[4..8] == b"ftyp" {
+        parse_mp4(reader)
+    } else {
+        Ok(KyrieMediaMetadata::default())
+    }
+}
+
+fn parse_png<R: Read + Seek>(reader: &mut R) -> io::Result<KyrieMediaMetadata> {
+    let mut header = [0u8; 24];
+    reader.read_exact(&mut header)?;
+    Ok(KyrieMediaMetadata {
+        kind: MEDIA_KIND_IMAGE,
+        width: u32::from_be_bytes(header[16..20].try_into().unwrap()),
+        height: u32::from_be_bytes(header[20..24].try_into().unwrap()),
+        ..Default::default()
+    })
+}
+
+fn parse_gif<R: Read + Seek>(reader: &mut R) -> io::Result<KyrieMediaMetadata> {
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header)?;
+    Ok(KyrieMediaMetadata {
+        kind: MEDIA_KIND_IMAGE,
+        width: u16::from_le_bytes([header[6], header[7]]) as u32,
+        height: u16::from_le_bytes([header[8], header[9]]) as u32,
+        ..Default::default()
+    })
+}
+
+fn parse_jpeg<R: Read + Seek>(reader: &mut R) -> io::Result<KyrieMediaMetadata> {
+    let mut metadata = KyrieMediaMetadata {
+        kind: MEDIA_KIND_IMAGE,
+        ..Default::default()
+    };
+    reader.seek(SeekFrom::Start(2))?;
+
+    loop {
+        let mut marker = [0u8; 2];
+        reader.read_exact(&mut marker)?;
+        if marker[0] != 0xFF {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid JPEG marker"));
+        }
+        if marker[1] == 0xFF {
+            reader.seek(SeekFrom::Current(-1))?;
+            continue;
+        }
+        if marker[1] == 0xD9 || marker[1] == 0xDA {
+            break;
+        }
+
+        let mut len_bytes = [0u8; 2];
+        reader.read_exact(&mut len_bytes)?;
+        let segment_len = (u16::from_be_bytes(len_bytes) as usize).saturating_sub(2);
+
+        match marker[1] {
+            0xC0..=0xCF if !matches!(marker[1], 0xC4 | 0xC8 | 0xCC) => {
+                let mut frame = [0u8; 5];
+                reader.read_exact(&mut frame)?;
+                metadata.height = u16::from_be_bytes([frame[1], frame[2]]) as u32;
+                metadata.width = u16::from_be_bytes([frame[3], frame[4]]) as u32;
+                return Ok(metadata);
+            }
+            0xE1 if segment_len <= MAX_EXIF_SEGMENT => {
+                let mut segment = vec![0u8; segment_len];
+                reader.read_exact(&mut segment)?;
+                if let Some(orientation) = exif_orientation(&segment) {
+                    metadata.orientation = orientation;
+                }
+            }
+            _ => {
+                reader.seek(SeekFrom::Current(segment_len as i64))?;
+            }
+        }
+    }
+
+    Ok(metadata)
+}
+
+fn exif_orientation(segment: &[u8]) -> Option<u16> {
+    let tiff = segment.strip_prefix(b"Exif\0\0")?;
+    let little_endian = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| -> Option<u16> {
+        let bytes: [u8; 2] = tiff.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let bytes: [u8; 4] = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    };
+
+    let ifd_offset = read_u32(4)? as usize;
+    let entry_count = read_u16(ifd_offset)? as usize;
+    (0..entry_count)
+        .map(|i| ifd_offset + 2 + i * 12)
+        .find(|&entry| read_u16(entry) == Some(0x0112))
+        .and_then(|entry| read_u16(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+fn parse_mp4<R: Read + Seek>(reader: &mut R) -> io::Result<KyrieMediaMetadata> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    let mut metadata = KyrieMediaMetadata {
+        kind: MEDIA_KIND_VIDEO,
+        ..Default::default()
+    };
+    walk_boxes(reader, 0, end, 0, &mut metadata)?;
+    Ok(metadata)
+}
+
+fn walk_boxes<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    depth: usize,
+    metadata: &mut KyrieMediaMetadata,
+) -> io::Result<()> {
+    let mut offset = start;
+    while offset + 8 <= end && depth < MAX_BOX_DEPTH {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        let mut box_size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let box_type = &header[4..8];
+        let mut body_start = offset + 8;
+        if box_size == 1 {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large)?;
+            box_size = u64::from_be_bytes(large);
+            body_start += 8;
+        } else if box_size == 0 {
+            box_size = end - offset;
+        }
+        if box_size < body_start - offset {
+            break;
+        }
+        let box_end = offset.saturating_add(box_size).min(end);
+
+        match box_type {
+            b"moov" | b"trak" => walk_boxes(reader, body_start, box_end, depth + 1, metadata)?,
+            b"mvhd" => parse_mvhd(reader, metadata)?,
+            b"tkhd" if metadata.width == 0 => parse_tkhd(reader, metadata)?,
+            _ => {}
+        }
+        offset = box_end;
+    }
+    Ok(())
+}
+
+fn parse_mvhd<R: Read>(reader: &mut R, metadata: &mut KyrieMediaMetadata) -> io::Result<()> {
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    let (timescale, duration) = if version[0] == 1 {
+        let mut body = [0u8; 28];
+        reader.read_exact(&mut body)?;
+        (
+            u32::from_be_bytes(body[16..20].try_into().unwrap()),
+            u64::from_be_bytes(body[20..28].try_into().unwrap()),
+        )
+    } else {
+        let mut body = [0u8; 16];
+        reader.read_exact(&mut body)?;
+        (
+            u32::from_be_bytes(body[8..12].try_into().unwrap()),
+            u32::from_be_bytes(body[12..16].try_into().unwrap()) as u64,
+        )
+    };
+    if timescale > 0 {
+        metadata.duration_ms = duration.saturating_mul(1000) / timescale as u64;
+    }
+    Ok(())
+}
+
+fn parse_tkhd<R: Read + Seek>(reader: &mut R, metadata: &mut KyrieMediaMetadata) -> io::Result<()> {
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    let times_len = if version[0] == 1 { 32 } else { 20 };
+    reader.seek(SeekFrom::Current(times_len + 16))?;
+
+    let mut matrix = [0u8; 36];
+    reader.read_exact(&mut matrix)?;
+    let mut size = [0u8; 8];
+    reader.read_exact(&mut size)?;
+
+    let width = u32::from_be_bytes(size[..4].try_into().unwrap()) >> 16;
+    let height = u32::from_be_bytes(size[4..].try_into().unwrap()) >> 16;
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+
+    let entry = |i: usize| i32::from_be_bytes(matrix[i * 4..i * 4 + 4].try_into().unwrap()) >> 16;
+    metadata.width = width;
+    metadata.height = height;
+    metadata.orientation = match (entry(0), entry(1), entry(3), entry(4)) {
+        (0, 1, -1, 0) => 6,
+        (-1, 0, 0, -1) => 3,
+        (0, -1, 1, 0) => 8,
+        _ => 1,
+    };
+    Ok(())
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_framed_container;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_detect_content_type_from_first_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("doc.kyl");
+        write_framed_container(&path, b"pw", &[b"%PDF-1.7\n", b"rest of the document"]);
+        assert_eq!(detect_content_type_internal(&path, b"pw").unwrap(), "application/pdf");
+        assert!(detect_content_type_internal(&path, b"wrong").is_err());
+
+        assert_eq!(sniff_mime(b"\x00\x00\x00\x18ftypheic"), "image/heic");
+        assert_eq!(sniff_mime(b"RIFF\x00\x00\x00\x00WEBPVP8 "), "image/webp");
+        assert_eq!(sniff_mime("caf\u{e9} notes\n".as_bytes()), "text/plain");
+        assert_eq!(sniff_mime(&[0x00, 0x01, 0x02, 0x03]), "application/octet-stream");
+    }
+
+    fn mp4_box(box_type: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn test_png_dimensions_through_encrypted_reader() {
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+        png.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png.kyl");
+        write_framed_container(&path, b"pw", &[&png[..10], &png[10..], b"trailing pixel data"]);
+
+        let metadata = get_media_metadata_internal(&path, b"pw").unwrap();
+        assert_eq!(metadata.kind, MEDIA_KIND_IMAGE);
+        assert_eq!((metadata.width, metadata.height), (640, 480));
+    }
+
+    #[test]
+    fn test_jpeg_orientation_and_size() {
+        let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08\x00\x01".to_vec();
+        tiff.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1, 0x00, 0x06, 0, 0]);
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend_from_slice(&tiff);
+
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend_from_slice(&((app1.len() + 2) as u16).to_be_bytes());
+        jpeg.extend_from_slice(&app1);
+        jpeg.extend_from_slice(&[0xFF, 0xC0, 0x00, 0x11, 8, 0x03, 0x00, 0x04, 0x00]);
+
+        let metadata = parse_media(&mut Cursor::new(jpeg)).unwrap();
+        assert_eq!((metadata.width, metadata.height, metadata.orientation), (1024, 768, 6));
+    }
+
+    #[test]
+    fn test_mp4_duration_and_rotation() {
+        let mut mvhd = vec![0u8; 4];
+        mvhd.extend_from_slice(&[0u8; 8]);
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&12_500u32.to_be_bytes());
+        mvhd.extend_from_slice(&[0u8; 80]);
+
+        let mut tkhd = vec![0u8; 4 + 20 + 16];
+        for value in [0i32, 1 << 16, 0, -(1 << 16), 0, 0, 0, 0, 1 << 30] {
+            tkhd.extend_from_slice(&value.to_be_bytes());
+        }
+        tkhd.extend_from_slice(&(1920u32 << 16).to_be_bytes());
+        tkhd.extend_from_slice(&(1080u32 << 16).to_be_bytes());
+
+        let mut video = mp4_box(b"ftyp", b"isom\x00\x00\x02\x00");
+        video.extend(mp4_box(b"mdat", &[0u8; 64]));
+        let trak = mp4_box(b"trak", &mp4_box(b"tkhd", &tkhd));
+        let mut moov_body = mp4_box(b"mvhd", &mvhd);
+        moov_body.extend(trak);
+        video.extend(mp4_box(b"moov", &moov_body));
+
+        let metadata = parse_media(&mut Cursor::new(video)).unwrap();
+        assert_eq!(
+            metadata,
+            KyrieMediaMetadata {
+                kind: MEDIA_KIND_VIDEO,
+                width: 1920,
+                height: 1080,
+                duration_ms: 12_500,
+                orientation: 6,
+            }
+        );
+    }
+}
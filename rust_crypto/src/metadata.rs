@@ -0,0 +1,143 @@
+use aes_gcm::{aead::Aead, Nonce};
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Read;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cipher::BodyCipher;
+use crate::format::{read_header, ContainerHeader};
+use crate::kdf::{self, KeyProvider, KeyPurpose};
+use crate::media::sniff_mime;
+use crate::{errors, random_nonce, NONCE_SIZE};
+
+const SNIFF_LEN: usize = 512;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+    pub mime_type: String,
+}
+
+impl FileMetadata {
+    pub fn for_input(input_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut input = File::open(input_path)?;
+        let info = input.metadata()?;
+        let mut prefix = Vec::with_capacity(SNIFF_LEN);
+        (&mut input).take(SNIFF_LEN as u64).read_to_end(&mut prefix)?;
+        Ok(FileMetadata {
+            name: input_path.file_name().and_then(|n| n.to_str()).map(str::to_string),
+            size: info.len(),
+            modified: info.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+            mime_type: sniff_mime(&prefix).to_string(),
+        })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_file_metadata(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    json_ptr: *mut u8,
+    json_len: *mut usize,
+) -> i32 {
+    if json_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match get_file_metadata_internal(Path::new(path), password).and_then(|metadata| Ok(serde_json::to_vec(&metadata)?)) {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn get_file_metadata_internal<K: KeyProvider + ?Sized>(
+    path: &Path,
+    keys: &K,
+) -> Result<FileMetadata, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    open(&header, &header.master_key(keys)?)?.ok_or_else(|| "File has no metadata".into())
+}
+
+// Sealed under its own subkey with the body's cipher suite, so reading the
+// metadata never touches the body key or its nonces.
+pub fn seal(header: &ContainerHeader, master: &[u8; 32], metadata: &FileMetadata) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let cipher = BodyCipher::new(header.cipher, &kdf::subkey(master, KeyPurpose::FileMetadata))?;
+    let nonce = random_nonce();
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), serde_json::to_vec(metadata)?.as_ref())
+        .map_err(|_| "Encryption failed")?;
+    Ok([&nonce[..], &sealed].concat())
+}
+
+pub fn open(header: &ContainerHeader, master: &[u8; 32]) -> Result<Option<FileMetadata>, Box<dyn std::error::Error>> {
+    if header.metadata.is_empty() {
+        return Ok(None);
+    }
+    let (nonce, sealed) = header.metadata.split_at(NONCE_SIZE.min(header.metadata.len()));
+    if nonce.len() != NONCE_SIZE {
+        return Err("Invalid header field".into());
+    }
+    let cipher = BodyCipher::new(header.cipher, &kdf::subkey(master, KeyPurpose::FileMetadata))?;
+    let json = cipher.decrypt(Nonce::from_slice(nonce), sealed).map_err(|_| "Decryption failed")?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+// Gives the decrypted file back the modification time it was encrypted with.
+pub fn restore_modified(output_path: &Path, metadata: &FileMetadata) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(modified) = metadata.modified {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(modified);
+        File::options().write(true).open(output_path)?.set_modified(time.min(SystemTime::now()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, encrypt_file_internal};
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_metadata_survives_the_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("holiday.png");
+        let encrypted = dir.path().join("holiday.kyl");
+        let decrypted = dir.path().join("restored.png");
+        fs::write(&plain, b"\x89PNG\r\n\x1a\nrest of the image").unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::options().write(true).open(&plain).unwrap().set_modified(modified).unwrap();
+
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        let metadata = get_file_metadata_internal(&encrypted, &b"pw"[..]).unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("holiday.png"));
+        assert_eq!(metadata.size, 25);
+        assert_eq!(metadata.modified, Some(1_600_000_000));
+        assert_eq!(metadata.mime_type, "image/png");
+        assert!(get_file_metadata_internal(&encrypted, &b"wrong"[..]).is_err());
+
+        decrypt_file_internal(encrypted.to_str().unwrap(), decrypted.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::metadata(&decrypted).unwrap().modified().unwrap(), modified);
+    }
+}
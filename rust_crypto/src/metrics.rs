@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::vault::unix_now;
+use crate::{decrypt_file_to_memory_internal, write_encrypted_atomic};
+
+const MAX_RECORDS: usize = 2000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MetricRecord {
+    pub operation: String,
+    pub bytes: u64,
+    pub duration_ms: u64,
+    pub recorded_at: u64,
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct MetricsSummary {
+    pub count: u64,
+    pub total_bytes: u64,
+    pub total_duration_ms: u64,
+    pub min_throughput: f64,
+    pub max_throughput: f64,
+}
+
+impl MetricsSummary {
+    pub fn average_throughput(&self) -> f64 {
+        if self.total_duration_ms == 0 {
+            0.0
+        } else {
+            self.total_bytes as f64 * 1000.0 / self.total_duration_ms as f64
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieMetricsSummary {
+    pub count: u64,
+    pub total_bytes: u64,
+    pub total_duration_ms: u64,
+    pub average_throughput: f64,
+    pub min_throughput: f64,
+    pub max_throughput: f64,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_metrics_record(
+    stats_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    operation_ptr: *const c_char,
+    bytes: u64,
+    duration_ms: u64,
+) -> i32 {
+    unsafe {
+        let stats_path = match CStr::from_ptr(stats_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let operation = match CStr::from_ptr(operation_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let record = MetricRecord {
+            operation: operation.to_string(),
+            bytes,
+            duration_ms,
+            recorded_at: unix_now(),
+        };
+        match record_metric(Path::new(stats_path), password, record) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_metrics_query(
+    stats_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    operation_ptr: *const c_char,
+    since: u64,
+    summary_ptr: *mut KyrieMetricsSummary,
+) -> i32 {
+    unsafe {
+        let stats_path = match CStr::from_ptr(stats_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let operation = if operation_ptr.is_null() {
+            None
+        } else {
+            match CStr::from_ptr(operation_ptr).to_str() {
+                Ok(s) => Some(s),
+                Err(_) => return -1,
+            }
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match query_metrics(Path::new(stats_path), password, operation, since) {
+            Ok(summary) => {
+                if !summary_ptr.is_null() {
+                    *summary_ptr = KyrieMetricsSummary {
+                        count: summary.count,
+                        total_bytes: summary.total_bytes,
+                        total_duration_ms: summary.total_duration_ms,
+                        average_throughput: summary.average_throughput(),
+                        min_throughput: summary.min_throughput,
+                        max_throughput: summary.max_throughput,
+                    };
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn record_metric(
+    stats_path: &Path,
+    password: &[u8],
+    record: MetricRecord,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut records = load_records(stats_path, password)?;
+    records.push(record);
+    if records.len() > MAX_RECORDS {
+        records.drain(..records.len() - MAX_RECORDS);
+    }
+    save_records(stats_path, password, &records)
+}
+
+pub fn query_metrics(
+    stats_path: &Path,
+    password: &[u8],
+    operation: Option<&str>,
+    since: u64,
+) -> Result<MetricsSummary, Box<dyn std::error::Error>> {
+    let records = load_records(stats_path, password)?;
+    let mut summary = MetricsSummary::default();
+
+    for record in records
+        .iter()
+        .filter(|r| r.recorded_at >= since)
+        .filter(|r| operation.is_none_or(|op| r.operation == op))
+    {
+        summary.count += 1;
+        summary.total_bytes += record.bytes;
+        summary.total_duration_ms += record.duration_ms;
+
+        if record.duration_ms > 0 {
+            let throughput = record.bytes as f64 * 1000.0 / record.duration_ms as f64;
+            if summary.min_throughput == 0.0 || throughput < summary.min_throughput {
+                summary.min_throughput = throughput;
+            }
+            summary.max_throughput = summary.max_throughput.max(throughput);
+        }
+    }
+
+    Ok(summary)
+}
+
+fn load_records(stats_path: &Path, password: &[u8]) -> Result<Vec<MetricRecord>, Box<dyn std::error::Error>> {
+    if !stats_path.exists() {
+        return Ok(Vec::new());
+    }
+    let path = stats_path.to_str().ok_or("Invalid stats path")?;
+    let data = decrypt_file_to_memory_internal(path, password, false, 1)?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+fn save_records(
+    stats_path: &Path,
+    password: &[u8],
+    records: &[MetricRecord],
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_encrypted_atomic(stats_path, password, &serde_json::to_vec(records)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn record(operation: &str, bytes: u64, duration_ms: u64, recorded_at: u64) -> MetricRecord {
+        MetricRecord {
+            operation: operation.to_string(),
+            bytes,
+            duration_ms,
+            recorded_at,
+        }
+    }
+
+    #[test]
+    fn test_metrics_are_encrypted_and_queryable() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = dir.path().join("stats.kyl");
+
+        record_metric(&stats, b"pw", record("encrypt", 4_000_000, 2000, 100)).unwrap();
+        record_metric(&stats, b"pw", record("encrypt", 1_000_000, 1000, 200)).unwrap();
+        record_metric(&stats, b"pw", record("decrypt", 9_000_000, 1000, 300)).unwrap();
+
+        let raw = fs::read(&stats).unwrap();
+        assert!(!raw.windows(7).any(|w| w == b"encrypt"));
+        assert!(query_metrics(&stats, b"wrong", None, 0).is_err());
+
+        let encrypt = query_metrics(&stats, b"pw", Some("encrypt"), 0).unwrap();
+        assert_eq!(encrypt.count, 2);
+        assert_eq!(encrypt.total_bytes, 5_000_000);
+        assert_eq!(encrypt.min_throughput, 1_000_000.0);
+        assert_eq!(encrypt.max_throughput, 2_000_000.0);
+        assert!((encrypt.average_throughput() - 5_000_000.0 / 3.0).abs() < 1.0);
+
+        let recent = query_metrics(&stats, b"pw", None, 200).unwrap();
+        assert_eq!(recent.count, 2);
+        assert_eq!(recent.total_bytes, 10_000_000);
+    }
+}
@@ -0,0 +1,538 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+use crate::archive::{decrypt_error, entry_path};
+use crate::errors;
+use crate::format::read_header;
+use crate::kdf::{self, KeyPurpose};
+use crate::padding::PADDING_PADME;
+use crate::progress::ProgressSink;
+use crate::range::DecryptingReader;
+use crate::reencrypt::{reencrypt_file_internal, ReencryptOptions};
+use crate::previous_password;
+use crate::vault::{file_blake3, unix_now};
+use crate::{
+    decrypt_file_internal, decrypt_file_to_memory_internal, encrypt_file_reporting, text,
+    write_encrypted_atomic, EncryptOptions, ENCRYPTED_EXTENSION,
+};
+
+const NAME_MANIFEST_FILE_NAME: &str = ".kyrie_names";
+const OBFUSCATED_NAME_LEN: usize = 32;
+const EXPORT_MANIFEST_FILE_NAME: &str = "kyrie_export_manifest.json";
+const EXPORT_BUFFER_SIZE: usize = 1024 * 1024;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NameEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NameManifest {
+    pub vault_id: String,
+    pub entries: BTreeMap<String, NameEntry>,
+    // A random key for the stored names, kept only inside the encrypted
+    // manifest. Vaults from before it existed get one on their next load.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name_key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExportedEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+    pub blake3: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportManifest {
+    pub vault_id: String,
+    pub exported_at: u64,
+    pub entries: Vec<ExportedEntry>,
+}
+
+impl NameManifest {
+    fn load(vault_dir: &Path, password: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = vault_dir.join(NAME_MANIFEST_FILE_NAME);
+        if !path.exists() {
+            return Ok(NameManifest {
+                vault_id: hex::encode(rand::random::<[u8; 16]>()),
+                entries: BTreeMap::new(),
+                name_key: hex::encode(rand::random::<[u8; 32]>()),
+            });
+        }
+        let data = match decrypt_file_to_memory_internal(path.to_str().ok_or("Invalid vault path")?, password, false, 1) {
+            Ok(data) => data,
+            Err(e) => {
+                previous_password::check(vault_dir, password, unix_now())?;
+                return Err(e);
+            }
+        };
+        let mut manifest: NameManifest = serde_json::from_slice(&data)?;
+        if manifest.name_key.is_empty() {
+            manifest.name_key = hex::encode(rand::random::<[u8; 32]>());
+        }
+        Ok(manifest)
+    }
+
+    fn save(&self, vault_dir: &Path, password: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        write_encrypted_atomic(&vault_dir.join(NAME_MANIFEST_FILE_NAME), password, &serde_json::to_vec(self)?)
+    }
+
+    fn obfuscated_name(&self, name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let master: Zeroizing<[u8; 32]> = Zeroizing::new(hex::decode(&self.name_key)?.as_slice().try_into()?);
+        let name_key = Zeroizing::new(kdf::subkey(&master, KeyPurpose::FileName));
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(name_key.as_slice()).expect("HMAC accepts any key length");
+        mac.update(self.vault_id.as_bytes());
+        mac.update(name.as_bytes());
+        let tag = hex::encode(mac.finalize().into_bytes());
+        Ok(format!("{}.{}", &tag[..OBFUSCATED_NAME_LEN], ENCRYPTED_EXTENSION))
+    }
+
+    fn stored_name(&self, name: &str) -> Option<&str> {
+        let name = text::normalize(name);
+        self.entries.iter().find(|(_, e)| text::normalize(&e.name) == name).map(|(stored, _)| stored.as_str())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_name_vault_add(
+    vault_dir_ptr: *const c_char,
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match add_file(Path::new(vault_dir), Path::new(input_path), password, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_name_vault_extract(
+    vault_dir_ptr: *const c_char,
+    name_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let name = match CStr::from_ptr(name_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match extract_file(Path::new(vault_dir), name, output_path, password, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_name_vault_list(
+    vault_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    json_ptr: *mut u8,
+    json_len: *mut usize,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let result = list_entries(Path::new(vault_dir), password).and_then(|entries| Ok(serde_json::to_vec(&entries)?));
+        match result {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_name_vault_export(
+    vault_dir_ptr: *const c_char,
+    dest_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let dest_dir = match CStr::from_ptr(dest_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match export_vault_plaintext(Path::new(vault_dir), Path::new(dest_dir), password) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_name_vault_rekey(
+    vault_dir_ptr: *const c_char,
+    old_password_ptr: *const u8,
+    old_password_len: usize,
+    new_password_ptr: *const u8,
+    new_password_len: usize,
+    grace_seconds: u64,
+    is_mobile: bool,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let old_password = std::slice::from_raw_parts(old_password_ptr, old_password_len);
+        let new_password = std::slice::from_raw_parts(new_password_ptr, new_password_len);
+
+        match rekey_vault(Path::new(vault_dir), old_password, new_password, grace_seconds, is_mobile) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn add_file(
+    vault_dir: &Path,
+    input_path: &Path,
+    password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut manifest = NameManifest::load(vault_dir, password)?;
+    let name = input_path.file_name().and_then(|n| n.to_str()).ok_or("Invalid input path")?;
+    let name = &text::normalize(name);
+    let metadata = fs::metadata(input_path)?;
+    // Names added before the name key was separated keep their stored name.
+    let stored_name = match manifest.stored_name(name) {
+        Some(stored) => stored.to_string(),
+        None => manifest.obfuscated_name(name)?,
+    };
+    let stored_path = vault_dir.join(&stored_name);
+
+    let options = EncryptOptions {
+        hint: None,
+        padding: PADDING_PADME,
+        ..Default::default()
+    };
+    let result = encrypt_file_reporting(
+        input_path.to_str().ok_or("Invalid input path")?,
+        stored_path.to_str().ok_or("Invalid vault path")?,
+        password,
+        &options,
+        is_mobile,
+        cpu_cores,
+        &ProgressSink::none(),
+    );
+    if let Err(e) = result {
+        let _ = fs::remove_file(&stored_path);
+        return Err(e);
+    }
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    manifest.entries.insert(
+        stored_name.clone(),
+        NameEntry {
+            name: name.to_string(),
+            size: metadata.len(),
+            modified,
+        },
+    );
+    manifest.save(vault_dir, password)?;
+    Ok(stored_name)
+}
+
+pub fn extract_file(
+    vault_dir: &Path,
+    name: &str,
+    output_path: &str,
+    password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stored_path = stored_path(vault_dir, name, password)?;
+    decrypt_file_internal(stored_path.to_str().ok_or("Invalid vault path")?, output_path, password, is_mobile, cpu_cores)
+}
+
+pub fn stored_path(vault_dir: &Path, name: &str, password: &[u8]) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let manifest = NameManifest::load(vault_dir, password)?;
+    let stored_name = manifest.stored_name(name).ok_or("Unknown vault entry")?;
+    Ok(vault_dir.join(stored_name))
+}
+
+pub fn list_entries(vault_dir: &Path, password: &[u8]) -> Result<Vec<NameEntry>, Box<dyn std::error::Error>> {
+    let manifest = NameManifest::load(vault_dir, password)?;
+    Ok(manifest.entries.into_values().collect())
+}
+
+// Re-encrypts every entry and the manifest under the new password. Stored
+// names are kept, so the obfuscated names still resolve. With a grace
+// period, the old password keeps failing with a distinct error until it
+// ends.
+pub fn rekey_vault(
+    vault_dir: &Path,
+    old_password: &[u8],
+    new_password: &[u8],
+    grace_seconds: u64,
+    is_mobile: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if old_password == new_password {
+        return Err("New password must differ from the old password".into());
+    }
+    let manifest = NameManifest::load(vault_dir, old_password)?;
+    for stored_name in manifest.entries.keys() {
+        let path = vault_dir.join(stored_name);
+        let path_str = path.to_str().ok_or("Invalid vault path")?;
+        let result = reencrypt_file_internal(path_str, path_str, old_password, new_password, &ReencryptOptions::from_policy(), is_mobile);
+        // Entries finished by an interrupted rekey already open with the new key.
+        if let Err(e) = result {
+            match read_header(&path) {
+                Ok((header, _)) if header.file_id.is_some() && header.master_key(new_password).and_then(|key| header.verify_file_id(&key)).is_ok() => {}
+                _ => return Err(e),
+            }
+        }
+    }
+    manifest.save(vault_dir, new_password)?;
+
+    if grace_seconds > 0 {
+        previous_password::record(vault_dir, old_password, unix_now().saturating_add(grace_seconds))
+    } else {
+        Ok(previous_password::clear(vault_dir)?)
+    }
+}
+
+// Decrypts every entry under its original name and modification time, then
+// writes a manifest of plaintext digests and checks the copies against it.
+// Existing files in dest_dir are never overwritten.
+pub fn export_vault_plaintext(
+    vault_dir: &Path,
+    dest_dir: &Path,
+    password: &[u8],
+) -> Result<ExportManifest, Box<dyn std::error::Error>> {
+    let manifest = NameManifest::load(vault_dir, password)?;
+    fs::create_dir_all(dest_dir)?;
+
+    let mut exported = Vec::with_capacity(manifest.entries.len());
+    for (stored_name, entry) in &manifest.entries {
+        if entry.name == EXPORT_MANIFEST_FILE_NAME {
+            return Err("Invalid archive entry path".into());
+        }
+        let path = entry_path(dest_dir, &entry.name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut reader = DecryptingReader::open(&vault_dir.join(stored_name), password)?;
+        let mut output = File::create_new(&path)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = vec![0u8; EXPORT_BUFFER_SIZE];
+        let mut size = 0u64;
+        loop {
+            match reader.read(&mut buffer).map_err(decrypt_error)? {
+                0 => break,
+                n => {
+                    hasher.update(&buffer[..n]);
+                    output.write_all(&buffer[..n])?;
+                    size += n as u64;
+                }
+            }
+        }
+        if entry.modified > 0 {
+            output.set_modified(UNIX_EPOCH + Duration::from_secs(entry.modified))?;
+        }
+        output.sync_all()?;
+        exported.push(ExportedEntry {
+            name: entry.name.clone(),
+            size,
+            modified: entry.modified,
+            blake3: hasher.finalize().to_hex().to_string(),
+        });
+    }
+
+    let export = ExportManifest {
+        vault_id: manifest.vault_id,
+        exported_at: unix_now(),
+        entries: exported,
+    };
+    let mut manifest_file = File::create_new(dest_dir.join(EXPORT_MANIFEST_FILE_NAME))?;
+    manifest_file.write_all(&serde_json::to_vec_pretty(&export)?)?;
+    manifest_file.sync_all()?;
+
+    verify_export(dest_dir, &export)?;
+    Ok(export)
+}
+
+fn verify_export(dest_dir: &Path, export: &ExportManifest) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in &export.entries {
+        let path = entry_path(dest_dir, &entry.name)?;
+        if fs::metadata(&path)?.len() != entry.size || file_blake3(&path)? != entry.blake3 {
+            return Err(format!("Export verification failed for {}", entry.name).into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_are_hidden_and_deterministic() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault_dir = dir.path().join("vault");
+        fs::create_dir(&vault_dir).unwrap();
+        let input = dir.path().join("tax return 2025.pdf");
+        fs::write(&input, b"first draft").unwrap();
+
+        let stored = add_file(&vault_dir, &input, b"pw", false, 4).unwrap();
+        assert!(!stored.contains("tax"));
+        assert_eq!(stored.len(), OBFUSCATED_NAME_LEN + 1 + ENCRYPTED_EXTENSION.len());
+
+        fs::write(&input, b"final version").unwrap();
+        assert_eq!(add_file(&vault_dir, &input, b"pw", false, 4).unwrap(), stored);
+        assert_eq!(fs::read_dir(&vault_dir).unwrap().count(), 2);
+
+        let entries = list_entries(&vault_dir, b"pw").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].name.as_str(), entries[0].size), ("tax return 2025.pdf", 13));
+        assert!(list_entries(&vault_dir, b"wrong").is_err());
+
+        let output = dir.path().join("restored.pdf");
+        extract_file(&vault_dir, "tax return 2025.pdf", output.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"final version");
+
+        let decomposed = dir.path().join("decomposed.pdf");
+        let composed_input = dir.path().join("r\u{e9}sum\u{e9}.pdf");
+        fs::write(&composed_input, b"cv").unwrap();
+        add_file(&vault_dir, &composed_input, b"pw", false, 4).unwrap();
+        extract_file(&vault_dir, "re\u{301}sume\u{301}.pdf", decomposed.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decomposed).unwrap(), b"cv");
+
+        let other_vault = dir.path().join("other");
+        fs::create_dir(&other_vault).unwrap();
+        assert_ne!(add_file(&other_vault, &input, b"pw", false, 4).unwrap(), stored);
+    }
+
+    #[test]
+    fn test_export_restores_names_and_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault_dir = dir.path().join("vault");
+        let export_dir = dir.path().join("export");
+        fs::create_dir(&vault_dir).unwrap();
+        for (name, contents) in [("notes.txt", &b"remember the milk"[..]), ("empty.bin", b"")] {
+            let input = dir.path().join(name);
+            fs::write(&input, contents).unwrap();
+            File::options()
+                .write(true)
+                .open(&input)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+                .unwrap();
+            add_file(&vault_dir, &input, b"pw", false, 4).unwrap();
+        }
+
+        assert!(export_vault_plaintext(&vault_dir, &export_dir, b"wrong").is_err());
+        let export = export_vault_plaintext(&vault_dir, &export_dir, b"pw").unwrap();
+        assert_eq!(export.entries.len(), 2);
+
+        let notes = export_dir.join("notes.txt");
+        assert_eq!(fs::read(&notes).unwrap(), b"remember the milk");
+        assert_eq!(fs::read(export_dir.join("empty.bin")).unwrap(), b"");
+        let modified = fs::metadata(&notes).unwrap().modified().unwrap();
+        assert_eq!(modified, UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+
+        let written: ExportManifest =
+            serde_json::from_slice(&fs::read(export_dir.join(EXPORT_MANIFEST_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(written.entries, export.entries);
+        assert!(verify_export(&export_dir, &written).is_ok());
+
+        fs::write(&notes, b"remember the eggs").unwrap();
+        assert!(verify_export(&export_dir, &written).is_err());
+        assert!(export_vault_plaintext(&vault_dir, &export_dir, b"pw").is_err());
+    }
+
+    #[test]
+    fn test_rekey_reports_previous_password_during_grace() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault_dir = dir.path().join("vault");
+        fs::create_dir(&vault_dir).unwrap();
+        let input = dir.path().join("plan.txt");
+        fs::write(&input, b"the plan").unwrap();
+        add_file(&vault_dir, &input, b"old", false, 4).unwrap();
+
+        rekey_vault(&vault_dir, b"old", b"new", 3600, false).unwrap();
+        let err = list_entries(&vault_dir, b"old").unwrap_err();
+        assert_eq!(errors::classify(err.as_ref()), errors::ERR_PASSWORD_EXPIRED);
+        let err = list_entries(&vault_dir, b"typo").unwrap_err();
+        assert_eq!(errors::classify(err.as_ref()), errors::ERR_WRONG_PASSWORD);
+
+        let output = dir.path().join("plan.out");
+        extract_file(&vault_dir, "plan.txt", output.to_str().unwrap(), b"new", false, 4).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"the plan");
+        fs::write(&input, b"the new plan").unwrap();
+        add_file(&vault_dir, &input, b"new", false, 4).unwrap();
+        assert_eq!(list_entries(&vault_dir, b"new").unwrap().len(), 1);
+
+        rekey_vault(&vault_dir, b"new", b"newer", 0, false).unwrap();
+        let err = list_entries(&vault_dir, b"old").unwrap_err();
+        assert_eq!(errors::classify(err.as_ref()), errors::ERR_WRONG_PASSWORD);
+    }
+}
@@ -0,0 +1,250 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::vault::unix_now;
+use crate::{encrypt_file_internal, errors, ENCRYPTED_EXTENSION};
+
+const DEFAULT_TEMPLATE: &str = "{name}";
+const HASH_PREFIX_LEN: usize = 8;
+const MAX_COLLISION_SUFFIX: u32 = 10_000;
+
+static NAMING_POLICY: Mutex<Option<NamingPolicy>> = Mutex::new(None);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NamingPolicy {
+    pub template: String,
+    pub extension: String,
+    pub hidden: bool,
+}
+
+impl Default for NamingPolicy {
+    fn default() -> Self {
+        NamingPolicy {
+            template: DEFAULT_TEMPLATE.to_string(),
+            extension: ENCRYPTED_EXTENSION.to_string(),
+            hidden: false,
+        }
+    }
+}
+
+impl NamingPolicy {
+    pub fn current() -> Self {
+        NAMING_POLICY
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .unwrap_or_default()
+    }
+
+    pub fn install(self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.template.is_empty() || self.extension.is_empty() || self.extension.contains(['/', '\\', '.']) {
+            return Err("Invalid naming policy".into());
+        }
+        *NAMING_POLICY.lock().unwrap_or_else(|e| e.into_inner()) = Some(self);
+        Ok(())
+    }
+
+    pub fn render(&self, input_path: &Path, now: u64) -> Option<String> {
+        let name = input_path.file_name()?.to_str()?;
+        let stem = input_path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+        let ext = input_path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let hash = hex::encode(Sha256::digest(name.as_bytes()));
+
+        let rendered = self
+            .template
+            .replace("{name}", name)
+            .replace("{stem}", stem)
+            .replace("{ext}", ext)
+            .replace("{hash}", &hash[..HASH_PREFIX_LEN])
+            .replace("{timestamp}", &now.to_string())
+            .replace(['/', '\\'], "_");
+        if rendered.is_empty() {
+            return None;
+        }
+        if self.hidden && !rendered.starts_with('.') {
+            Some(format!(".{}", rendered))
+        } else {
+            Some(rendered)
+        }
+    }
+
+    pub fn output_path(&self, output_dir: &Path, input_path: &Path) -> Option<PathBuf> {
+        let base = self.render(input_path, unix_now())?;
+        let candidate = output_dir.join(self.with_extension(&base, None));
+        if !candidate.exists() {
+            return Some(candidate);
+        }
+        (1..MAX_COLLISION_SUFFIX)
+            .map(|n| output_dir.join(self.with_extension(&base, Some(n))))
+            .find(|p| !p.exists())
+    }
+
+    fn with_extension(&self, base: &str, suffix: Option<u32>) -> String {
+        match suffix {
+            Some(n) => format!("{} ({}).{}", base, n, self.extension),
+            None => format!("{}.{}", base, self.extension),
+        }
+    }
+
+    pub fn is_encrypted_name(&self, path: &Path) -> bool {
+        let extension = path.extension().and_then(|e| e.to_str());
+        extension == Some(self.extension.as_str()) || extension == Some(ENCRYPTED_EXTENSION)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_naming_policy(
+    template_ptr: *const c_char,
+    extension_ptr: *const c_char,
+    hidden: bool,
+) -> i32 {
+    unsafe {
+        let template = if template_ptr.is_null() {
+            DEFAULT_TEMPLATE
+        } else {
+            match CStr::from_ptr(template_ptr).to_str() {
+                Ok(s) => s,
+                Err(_) => return -1,
+            }
+        };
+        let extension = if extension_ptr.is_null() {
+            ENCRYPTED_EXTENSION
+        } else {
+            match CStr::from_ptr(extension_ptr).to_str() {
+                Ok(s) => s,
+                Err(_) => return -1,
+            }
+        };
+
+        let policy = NamingPolicy {
+            template: template.to_string(),
+            extension: extension.to_string(),
+            hidden,
+        };
+        match policy.install() {
+            Ok(_) => 0,
+            Err(_) => -1,
+        }
+    }
+}
+
+// The output name is reported through `name_ptr`, which holds
+// `name_capacity` bytes. When it is null or too small, nothing is encrypted:
+// the required length is stored in `name_len` and ERR_OUTPUT_TOO_LARGE is
+// set, so the caller can retry with a large enough buffer.
+#[no_mangle]
+pub extern "C" fn encrypt_file_to_dir(
+    input_path_ptr: *const c_char,
+    output_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+    name_ptr: *mut u8,
+    name_capacity: usize,
+    name_len: *mut usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_dir = match CStr::from_ptr(output_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let output_path = match NamingPolicy::current().output_path(Path::new(output_dir), Path::new(input_path)) {
+            Some(p) => p,
+            None => return -1,
+        };
+        let output = match output_path.to_str() {
+            Some(s) => s,
+            None => return -1,
+        };
+        let name = output_path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        *name_len = name.len();
+        if name_ptr.is_null() || name_capacity < name.len() {
+            return errors::fail_with(errors::ERR_OUTPUT_TOO_LARGE);
+        }
+
+        match encrypt_file_internal(input_path, output, password, hint, is_mobile, cpu_cores) {
+            Ok(_) => {
+                std::ptr::copy_nonoverlapping(name.as_ptr(), name_ptr, name.len());
+                0
+            }
+            Err(_) => {
+                let _ = std::fs::remove_file(&output_path);
+                -2
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_default_policy_avoids_collisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("photo.jpg");
+        let policy = NamingPolicy::default();
+
+        let first = policy.output_path(dir.path(), &input).unwrap();
+        assert_eq!(first, dir.path().join("photo.jpg.kyl"));
+
+        fs::write(&first, b"taken").unwrap();
+        let second = policy.output_path(dir.path(), &input).unwrap();
+        assert_eq!(second, dir.path().join("photo.jpg (1).kyl"));
+    }
+
+    #[test]
+    fn test_template_placeholders() {
+        let policy = NamingPolicy {
+            template: "{stem}_{timestamp}_{hash}.{ext}".to_string(),
+            extension: "lock".to_string(),
+            hidden: true,
+        };
+        let rendered = policy.render(Path::new("/tmp/IMG_0001.jpg"), 1700000000).unwrap();
+        let hash = &hex::encode(Sha256::digest(b"IMG_0001.jpg"))[..HASH_PREFIX_LEN];
+        assert_eq!(rendered, format!(".IMG_0001_1700000000_{}.jpg", hash));
+        assert_eq!(policy.with_extension(&rendered, Some(2)), format!("{} (2).lock", rendered));
+    }
+
+    #[test]
+    fn test_encrypt_to_dir_reports_the_name_length_without_encrypting() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("photo.jpg");
+        fs::write(&input, b"pixels").unwrap();
+        let input_c = std::ffi::CString::new(input.to_str().unwrap()).unwrap();
+        let dir_c = std::ffi::CString::new(dir.path().to_str().unwrap()).unwrap();
+        let encrypt = |name: &mut [u8], len: &mut usize| {
+            let name_ptr = if name.is_empty() { std::ptr::null_mut() } else { name.as_mut_ptr() };
+            encrypt_file_to_dir(input_c.as_ptr(), dir_c.as_ptr(), b"pw".as_ptr(), 2, std::ptr::null(), false, 4, name_ptr, name.len(), len)
+        };
+
+        let mut len = 0;
+        assert_eq!(encrypt(&mut [], &mut len), -2);
+        assert_eq!((len, errors::kyrie_last_error()), ("photo.jpg.kyl".len(), errors::ERR_OUTPUT_TOO_LARGE));
+        assert_eq!(encrypt(&mut [0; 4], &mut len), -2);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut name = vec![0; len];
+        assert_eq!(encrypt(&mut name, &mut len), 0);
+        assert_eq!(&name[..len], b"photo.jpg.kyl");
+        assert!(dir.path().join("photo.jpg.kyl").exists());
+    }
+}
@@ -0,0 +1,179 @@
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::io::{self, Read};
+
+use crate::errors;
+use crate::format::{ContainerHeader, FileId, NONCE_SCHEME_COUNTER};
+use crate::rng;
+use crate::{random_nonce, NONCE_SIZE};
+
+pub const PREFIX_SIZE: usize = NONCE_SIZE - 4;
+const NONCE_PREFIX_CONTEXT: &[u8] = b"KYRIE_LOCK_NONCE_PREFIX";
+const MIN_SALT_SIZE: usize = 16;
+const MAX_SALT_SIZE: usize = 64;
+
+pub enum NonceSequence {
+    Random,
+    Counter { prefix: [u8; PREFIX_SIZE], next: u64 },
+}
+
+impl NonceSequence {
+    pub fn for_header(header: &ContainerHeader, master: &[u8; 32]) -> Result<Self, Box<dyn std::error::Error>> {
+        if header.nonce_scheme != NONCE_SCHEME_COUNTER {
+            return Ok(NonceSequence::Random);
+        }
+        let file_id = header.file_id.as_ref().ok_or("Invalid file format")?;
+        Ok(NonceSequence::Counter {
+            prefix: counter_prefix(&header.body_key(master), file_id),
+            next: 0,
+        })
+    }
+
+    pub fn stored_len(&self) -> usize {
+        match self {
+            NonceSequence::Random => NONCE_SIZE,
+            NonceSequence::Counter { .. } => 0,
+        }
+    }
+
+    // The part of a nonce that is written in front of its frame.
+    pub fn stored<'a>(&self, nonce: &'a [u8; NONCE_SIZE]) -> &'a [u8] {
+        &nonce[..self.stored_len()]
+    }
+
+    pub fn next_nonce(&mut self) -> Result<[u8; NONCE_SIZE], Box<dyn std::error::Error>> {
+        match self {
+            NonceSequence::Random => Ok(random_nonce()),
+            NonceSequence::Counter { prefix, next } => {
+                let nonce = counter_nonce(prefix, *next)?;
+                *next += 1;
+                Ok(nonce)
+            }
+        }
+    }
+
+    pub fn read_nonce<R: Read>(&mut self, reader: &mut R) -> io::Result<[u8; NONCE_SIZE]> {
+        match self {
+            NonceSequence::Random => {
+                let mut nonce = [0u8; NONCE_SIZE];
+                reader.read_exact(&mut nonce)?;
+                Ok(nonce)
+            }
+            _ => self
+                .next_nonce()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+    }
+}
+
+// Hands out nonces for callers that encrypt many small buffers under one
+// key: a random prefix with a counter, so nonces from one allocator never
+// repeat, and a fresh random prefix once the counter runs out.
+pub struct NonceAllocator {
+    prefix: [u8; PREFIX_SIZE],
+    next: u64,
+}
+
+impl NonceAllocator {
+    pub fn new() -> Self {
+        let mut prefix = [0u8; PREFIX_SIZE];
+        rng::fill(&mut prefix);
+        NonceAllocator { prefix, next: 0 }
+    }
+
+    pub fn allocate(&mut self) -> [u8; NONCE_SIZE] {
+        if self.next > u32::MAX as u64 {
+            *self = NonceAllocator::new();
+        }
+        let nonce = counter_nonce(&self.prefix, self.next).expect("counter is below u32::MAX");
+        self.next += 1;
+        nonce
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn generate_nonce(nonce_ptr: *mut u8) -> i32 {
+    if nonce_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    let nonce = random_nonce();
+    unsafe { std::ptr::copy_nonoverlapping(nonce.as_ptr(), nonce_ptr, NONCE_SIZE) };
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn generate_salt(salt_ptr: *mut u8, salt_len: usize) -> i32 {
+    if salt_ptr.is_null() || !(MIN_SALT_SIZE..=MAX_SALT_SIZE).contains(&salt_len) {
+        return errors::invalid_argument();
+    }
+    let salt = unsafe { std::slice::from_raw_parts_mut(salt_ptr, salt_len) };
+    rng::fill(salt);
+    0
+}
+
+// The prefix is unique per file because every container gets a fresh
+// random file id, so counters starting at zero never repeat under a key.
+pub fn counter_prefix(key: &[u8; 32], file_id: &FileId) -> [u8; PREFIX_SIZE] {
+    let mut prefix = [0u8; PREFIX_SIZE];
+    Hkdf::<Sha256>::new(Some(&file_id.uuid), key)
+        .expand(NONCE_PREFIX_CONTEXT, &mut prefix)
+        .expect("prefix is a valid HKDF output length");
+    prefix
+}
+
+pub fn counter_nonce(prefix: &[u8; PREFIX_SIZE], index: u64) -> Result<[u8; NONCE_SIZE], Box<dyn std::error::Error>> {
+    let counter = u32::try_from(index).map_err(|_| "Too many chunks")?;
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..].copy_from_slice(&counter.to_be_bytes());
+    Ok(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, encrypt_file_internal};
+    use crate::format::read_layout;
+    use crate::inspect::inspect_file_internal;
+    use std::fs;
+
+    #[test]
+    fn test_counter_nonces_are_not_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        let output = dir.path().join("out.txt");
+        fs::write(&plain, b"no stored nonce").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+
+        let layout = read_layout(&encrypted).unwrap();
+        assert_eq!(layout.frame_size(&layout.frames[0]), 15 + 16);
+        assert!(inspect_file_internal(&encrypted).unwrap().counter_nonces);
+        decrypt_file_internal(encrypted.to_str().unwrap(), output.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"no stored nonce");
+
+        let mut sequence = NonceSequence::Counter { prefix: [7; PREFIX_SIZE], next: u32::MAX as u64 };
+        assert_eq!(sequence.next_nonce().unwrap()[PREFIX_SIZE..], [0xff; 4]);
+        assert!(sequence.next_nonce().is_err());
+    }
+
+    #[test]
+    fn test_allocator_never_repeats_across_rollover() {
+        let mut allocator = NonceAllocator::new();
+        let first = allocator.allocate();
+        assert_eq!(allocator.allocate()[..PREFIX_SIZE], first[..PREFIX_SIZE]);
+        assert_ne!(allocator.allocate(), first);
+
+        allocator.next = u32::MAX as u64;
+        let last = allocator.allocate();
+        assert_eq!(last[PREFIX_SIZE..], [0xff; 4]);
+        let rolled = allocator.allocate();
+        assert_eq!(rolled[PREFIX_SIZE..], [0; 4]);
+        assert_ne!(rolled[..PREFIX_SIZE], last[..PREFIX_SIZE]);
+
+        let mut salt = [0u8; 32];
+        assert_eq!(generate_salt(salt.as_mut_ptr(), salt.len()), 0);
+        assert_ne!(salt, [0u8; 32]);
+        assert_eq!(generate_salt(salt.as_mut_ptr(), 8), -1);
+    }
+}
@@ -0,0 +1,699 @@
+use std::collections::HashMap;
+use std::ffi::{c_void, CStr};
+use std::fs;
+use std::os::raw::c_char;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+use crate::errors::{classify, ERR_NONE};
+use crate::handles::HandleSet;
+use crate::progress::{ProgressSink, PROGRESS_PHASE_READING};
+use crate::shutdown::KyrieShutdownSummary;
+use crate::{decrypt_file_reporting, encrypt_file_reporting, EncryptOptions};
+
+pub const OPERATION_QUEUED: i32 = 0;
+pub const OPERATION_RUNNING: i32 = 1;
+pub const OPERATION_PAUSED: i32 = 2;
+pub const OPERATION_FINISHED: i32 = 3;
+pub const OPERATION_FAILED: i32 = 4;
+pub const OPERATION_CANCELLED: i32 = 5;
+
+pub const PRIORITY_BACKGROUND: i32 = 0;
+pub const PRIORITY_NORMAL: i32 = 1;
+pub const PRIORITY_INTERACTIVE: i32 = 2;
+
+const DEFAULT_CONCURRENCY: usize = 2;
+
+type Job = Box<dyn FnOnce(&ProgressSink) -> Result<u64, Box<dyn std::error::Error>> + Send>;
+
+#[repr(C)]
+#[derive(Default, Debug, PartialEq)]
+pub struct KyrieOperationStatus {
+    pub state: i32,
+    pub phase: i32,
+    pub progress: f64,
+    pub bytes_total: u64,
+    pub bytes_out: u64,
+    pub elapsed_ms: u64,
+    pub error_code: i32,
+}
+
+#[derive(Clone, Copy)]
+pub struct OperationOptions {
+    pub priority: i32,
+}
+
+impl OperationOptions {
+    fn with_priority(priority: i32) -> Self {
+        OperationOptions {
+            priority: priority.clamp(PRIORITY_BACKGROUND, PRIORITY_INTERACTIVE),
+        }
+    }
+}
+
+impl Default for OperationOptions {
+    fn default() -> Self {
+        OperationOptions {
+            priority: PRIORITY_NORMAL,
+        }
+    }
+}
+
+struct OperationRecord {
+    state: i32,
+    phase: i32,
+    progress: f64,
+    bytes_total: u64,
+    bytes_out: u64,
+    started: Option<Instant>,
+    elapsed_ms: u64,
+    error_code: i32,
+    priority: i32,
+    pause_requested: bool,
+    release_handles: bool,
+    preempted: bool,
+}
+
+impl OperationRecord {
+    fn status(&self) -> KyrieOperationStatus {
+        let elapsed_ms = match (self.state, self.started) {
+            (OPERATION_RUNNING | OPERATION_PAUSED, Some(started)) => started.elapsed().as_millis() as u64,
+            _ => self.elapsed_ms,
+        };
+        KyrieOperationStatus {
+            state: self.state,
+            phase: self.phase,
+            progress: self.progress,
+            bytes_total: self.bytes_total,
+            bytes_out: self.bytes_out,
+            elapsed_ms,
+            error_code: self.error_code,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self.state, OPERATION_FINISHED | OPERATION_FAILED | OPERATION_CANCELLED)
+    }
+
+    fn holds_slot(&self) -> bool {
+        self.started.is_some() && !self.is_done() && !self.pause_requested && !self.preempted
+    }
+
+    fn is_waiting(&self) -> bool {
+        !self.is_done() && !self.pause_requested && (self.started.is_none() || self.preempted)
+    }
+}
+
+struct RegistryState {
+    records: HashMap<u64, OperationRecord>,
+    pending: Vec<(u64, Job)>,
+    concurrency: usize,
+    next_id: u64,
+    accepting: bool,
+}
+
+pub struct OperationRegistry {
+    state: Mutex<RegistryState>,
+    changed: Condvar,
+}
+
+struct ProgressContext {
+    registry: Arc<OperationRegistry>,
+    handles: Arc<HandleSet>,
+    id: u64,
+}
+
+// Progress from a worker is routed back into its registry through the
+// callback's user data. Each report is also the point where a paused or
+// preempted operation waits until it may continue, with its files closed
+// if the pause asked for that.
+extern "C" fn record_progress(phase: i32, fraction: f64, user_data: *mut c_void) {
+    let context = unsafe { &*(user_data as *const ProgressContext) };
+    let mut state = context.registry.lock();
+    loop {
+        let record = match state.records.get_mut(&context.id) {
+            Some(record) => record,
+            None => return,
+        };
+        record.phase = phase;
+        record.progress = fraction;
+        if !record.pause_requested && !record.preempted {
+            record.state = OPERATION_RUNNING;
+            return;
+        }
+        if record.state != OPERATION_PAUSED {
+            record.state = OPERATION_PAUSED;
+            if record.pause_requested && record.release_handles {
+                context.handles.release_all();
+            }
+            context.registry.changed.notify_all();
+        }
+        state = context.registry.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+impl OperationRegistry {
+    pub fn new(concurrency: usize) -> Arc<Self> {
+        Arc::new(OperationRegistry {
+            state: Mutex::new(RegistryState {
+                records: HashMap::new(),
+                pending: Vec::new(),
+                concurrency: concurrency.max(1),
+                next_id: 1,
+                accepting: true,
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    pub fn global() -> &'static Arc<Self> {
+        static GLOBAL: OnceLock<Arc<OperationRegistry>> = OnceLock::new();
+        GLOBAL.get_or_init(|| OperationRegistry::new(DEFAULT_CONCURRENCY))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RegistryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn start<F>(self: &Arc<Self>, bytes_total: u64, options: &OperationOptions, job: F) -> u64
+    where
+        F: FnOnce(&ProgressSink) -> Result<u64, Box<dyn std::error::Error>> + Send + 'static,
+    {
+        let mut state = self.lock();
+        if !state.accepting {
+            return 0;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.records.insert(
+            id,
+            OperationRecord {
+                state: OPERATION_QUEUED,
+                phase: PROGRESS_PHASE_READING,
+                progress: 0.0,
+                bytes_total,
+                bytes_out: 0,
+                started: None,
+                elapsed_ms: 0,
+                error_code: ERR_NONE,
+                priority: options.priority,
+                pause_requested: false,
+                release_handles: false,
+                preempted: false,
+            },
+        );
+        state.pending.push((id, Box::new(job)));
+        self.schedule(&mut state);
+        id
+    }
+
+    pub fn status(&self, id: u64) -> Option<KyrieOperationStatus> {
+        self.lock().records.get(&id).map(|record| record.status())
+    }
+
+    pub fn set_paused(self: &Arc<Self>, id: u64, paused: bool, release_handles: bool) -> i32 {
+        let mut state = self.lock();
+        let result = match state.records.get_mut(&id) {
+            Some(record) if record.is_done() => -2,
+            Some(record) => {
+                record.pause_requested = paused;
+                record.release_handles = paused && release_handles;
+                0
+            }
+            None => -1,
+        };
+        self.schedule(&mut state);
+        result
+    }
+
+    pub fn set_concurrency(self: &Arc<Self>, concurrency: usize) {
+        let mut state = self.lock();
+        state.concurrency = concurrency.max(1);
+        self.schedule(&mut state);
+    }
+
+    pub fn forget(&self, id: u64) -> i32 {
+        let mut state = self.lock();
+        match state.records.get(&id).map(|record| record.is_done()) {
+            Some(true) => {
+                state.records.remove(&id);
+                0
+            }
+            Some(false) => -2,
+            None => -1,
+        }
+    }
+
+    // Stops accepting work and cancels everything still queued. Running jobs
+    // get until the timeout to finish; any left after that are asked to park
+    // at their next chunk boundary, where paused and preempted jobs already
+    // wait.
+    pub fn shutdown(self: &Arc<Self>, timeout: Duration) -> KyrieShutdownSummary {
+        let deadline = Instant::now() + timeout;
+        let mut summary = KyrieShutdownSummary::default();
+        let mut state = self.lock();
+        state.accepting = false;
+        for (id, _) in std::mem::take(&mut state.pending) {
+            if let Some(record) = state.records.get_mut(&id) {
+                record.state = OPERATION_CANCELLED;
+                summary.cancelled += 1;
+            }
+        }
+
+        let in_flight: Vec<u64> = state
+            .records
+            .iter()
+            .filter(|(_, record)| !record.is_done())
+            .map(|(id, _)| *id)
+            .collect();
+        loop {
+            let running = in_flight
+                .iter()
+                .filter(|id| state.records.get(id).is_some_and(|r| r.state == OPERATION_RUNNING))
+                .count();
+            let now = Instant::now();
+            if running == 0 || now >= deadline {
+                break;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+        }
+
+        for id in in_flight {
+            let record = match state.records.get_mut(&id) {
+                Some(record) => record,
+                None => continue,
+            };
+            match record.state {
+                OPERATION_FINISHED | OPERATION_FAILED => summary.completed += 1,
+                OPERATION_PAUSED => summary.checkpointed += 1,
+                _ => {
+                    record.pause_requested = true;
+                    summary.interrupted += 1;
+                }
+            }
+        }
+        summary
+    }
+
+    // Fills free slots with the most urgent waiting work, oldest first, and
+    // preempts lower-priority running jobs while more urgent ones are waiting.
+    fn schedule(self: &Arc<Self>, state: &mut RegistryState) {
+        while state.accepting {
+            let next = state
+                .records
+                .iter()
+                .filter(|(_, record)| record.is_waiting())
+                .map(|(id, record)| (*id, record.priority))
+                .max_by_key(|(id, priority)| (*priority, std::cmp::Reverse(*id)));
+            let (next_id, next_priority) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            let active: Vec<(u64, i32)> = state
+                .records
+                .iter()
+                .filter(|(_, record)| record.holds_slot())
+                .map(|(id, record)| (*id, record.priority))
+                .collect();
+            if active.len() < state.concurrency {
+                let record = state.records.get_mut(&next_id).expect("waiting record exists");
+                if record.preempted {
+                    record.preempted = false;
+                } else {
+                    record.started = Some(Instant::now());
+                    record.state = OPERATION_RUNNING;
+                    let index = state.pending.iter().position(|(id, _)| *id == next_id).expect("pending job exists");
+                    let (_, job) = state.pending.remove(index);
+                    self.spawn(next_id, job);
+                }
+                continue;
+            }
+
+            let victim = active
+                .iter()
+                .filter(|(_, priority)| *priority < next_priority)
+                .min_by_key(|(id, priority)| (*priority, std::cmp::Reverse(*id)));
+            match victim.and_then(|(id, _)| state.records.get_mut(id)) {
+                Some(record) => record.preempted = true,
+                None => break,
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    fn spawn(self: &Arc<Self>, id: u64, job: Job) {
+        let registry = Arc::clone(self);
+        thread::spawn(move || {
+            let context = ProgressContext {
+                registry: Arc::clone(&registry),
+                handles: Arc::new(HandleSet::default()),
+                id,
+            };
+            let progress = ProgressSink::new(Some(record_progress), &context as *const _ as *mut c_void)
+                .with_handles(Arc::clone(&context.handles));
+            let result = job(&progress);
+
+            let mut state = registry.lock();
+            if let Some(record) = state.records.get_mut(&id) {
+                record.elapsed_ms = record.started.map(|s| s.elapsed().as_millis() as u64).unwrap_or(0);
+                record.preempted = false;
+                match result {
+                    Ok(bytes_out) => {
+                        record.state = OPERATION_FINISHED;
+                        record.progress = 1.0;
+                        record.bytes_out = bytes_out;
+                    }
+                    Err(e) => {
+                        record.state = OPERATION_FAILED;
+                        record.error_code = classify(e.as_ref());
+                    }
+                }
+            }
+            registry.schedule(&mut state);
+        });
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_operation_status(id: u64, status_ptr: *mut KyrieOperationStatus) -> i32 {
+    match OperationRegistry::global().status(id) {
+        Some(status) => {
+            if !status_ptr.is_null() {
+                unsafe { *status_ptr = status };
+            }
+            0
+        }
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_operation_pause(id: u64) -> i32 {
+    OperationRegistry::global().set_paused(id, true, false)
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_operation_resume(id: u64) -> i32 {
+    OperationRegistry::global().set_paused(id, false, false)
+}
+
+// Suspends at the next chunk boundary. With release_handles the operation
+// also closes its input and output files until it is resumed.
+#[no_mangle]
+pub extern "C" fn pause_operation(id: u64, release_handles: bool) -> i32 {
+    OperationRegistry::global().set_paused(id, true, release_handles)
+}
+
+#[no_mangle]
+pub extern "C" fn resume_operation(id: u64) -> i32 {
+    OperationRegistry::global().set_paused(id, false, false)
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_operation_forget(id: u64) -> i32 {
+    OperationRegistry::global().forget(id)
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_operation_concurrency(concurrency: usize) -> i32 {
+    if concurrency == 0 {
+        return -1;
+    }
+    OperationRegistry::global().set_concurrency(concurrency);
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_operation_encrypt_file(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+    priority: i32,
+) -> u64 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => return 0,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => return 0,
+        };
+        let password = Zeroizing::new(std::slice::from_raw_parts(password_ptr, password_len).to_vec());
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok().map(str::to_string)
+        };
+
+        let bytes_total = fs::metadata(&input_path).map(|m| m.len()).unwrap_or(0);
+        let options = OperationOptions::with_priority(priority);
+        OperationRegistry::global().start(bytes_total, &options, move |progress| {
+            let options = EncryptOptions {
+                hint: hint.as_deref(),
+                ..Default::default()
+            };
+            encrypt_file_reporting(&input_path, &output_path, password.as_slice(), &options, is_mobile, cpu_cores, progress)?;
+            Ok(fs::metadata(&output_path)?.len())
+        })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_operation_decrypt_file(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+    priority: i32,
+) -> u64 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => return 0,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => return 0,
+        };
+        let password = Zeroizing::new(std::slice::from_raw_parts(password_ptr, password_len).to_vec());
+
+        let bytes_total = fs::metadata(&input_path).map(|m| m.len()).unwrap_or(0);
+        let options = OperationOptions::with_priority(priority);
+        OperationRegistry::global().start(bytes_total, &options, move |progress| {
+            decrypt_file_reporting(&input_path, &output_path, password.as_slice(), is_mobile, cpu_cores, progress)?;
+            Ok(fs::metadata(&output_path)?.len())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ERR_WRONG_PASSWORD;
+    use crate::handles::ReleasableFile;
+    use std::io::Read;
+    use std::ffi::CString;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn wait_until(registry: &OperationRegistry, id: u64, states: &[i32]) -> KyrieOperationStatus {
+        for _ in 0..500 {
+            let status = registry.status(id).unwrap();
+            if states.contains(&status.state) {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("operation {} never reached {:?}", id, states);
+    }
+
+    fn wait_for(registry: &OperationRegistry, id: u64) -> KyrieOperationStatus {
+        wait_until(registry, id, &[OPERATION_FINISHED, OPERATION_FAILED])
+    }
+
+    fn blocked_job(
+        order: &Arc<Mutex<Vec<&'static str>>>,
+        name: &'static str,
+    ) -> (mpsc::Sender<()>, Job) {
+        let (go, wait) = mpsc::channel::<()>();
+        let order = Arc::clone(order);
+        let job: Job = Box::new(move |progress| {
+            wait.recv().unwrap();
+            progress.report(PROGRESS_PHASE_READING, 0.5);
+            order.lock().unwrap().push(name);
+            Ok(0)
+        });
+        (go, job)
+    }
+
+    #[test]
+    fn test_operations_can_be_queried_by_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let c = |name: &str| CString::new(dir.path().join(name).to_str().unwrap()).unwrap();
+        fs::write(dir.path().join("plain.txt"), vec![3u8; 5000]).unwrap();
+        let registry = OperationRegistry::global();
+
+        let id = kyrie_operation_encrypt_file(
+            c("plain.txt").as_ptr(),
+            c("plain.kyl").as_ptr(),
+            b"pw".as_ptr(),
+            2,
+            std::ptr::null(),
+            false,
+            4,
+            PRIORITY_INTERACTIVE,
+        );
+        let status = wait_for(registry, id);
+        assert_eq!((status.state, status.progress, status.bytes_total), (OPERATION_FINISHED, 1.0, 5000));
+        assert!(status.bytes_out > 5000);
+
+        let id = kyrie_operation_decrypt_file(
+            c("plain.kyl").as_ptr(),
+            c("out.txt").as_ptr(),
+            b"no".as_ptr(),
+            2,
+            false,
+            4,
+            PRIORITY_INTERACTIVE,
+        );
+        let status = wait_for(registry, id);
+        assert_eq!((status.state, status.error_code), (OPERATION_FAILED, ERR_WRONG_PASSWORD));
+
+        assert_eq!(kyrie_operation_pause(id), -2);
+        let mut reported = KyrieOperationStatus::default();
+        assert_eq!(get_operation_status(id, &mut reported), 0);
+        assert_eq!(reported, status);
+        assert_eq!(kyrie_operation_forget(id), 0);
+        assert_eq!(get_operation_status(id, &mut reported), -1);
+    }
+
+    #[test]
+    fn test_paused_operation_waits_at_next_report() {
+        let registry = OperationRegistry::new(2);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (go, job) = blocked_job(&order, "paused");
+        let id = registry.start(0, &OperationOptions::default(), job);
+        assert_eq!(registry.set_paused(id, true, false), 0);
+        go.send(()).unwrap();
+        wait_until(&registry, id, &[OPERATION_PAUSED]);
+        assert_eq!(registry.forget(id), -2);
+
+        assert_eq!(registry.set_paused(id, false, false), 0);
+        assert_eq!(wait_for(&registry, id).state, OPERATION_FINISHED);
+    }
+
+    #[test]
+    fn test_pause_can_release_file_handles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.bin");
+        fs::write(&path, b"0123456789").unwrap();
+        let registry = OperationRegistry::new(2);
+
+        for release in [false, true] {
+            let (go, wait) = mpsc::channel::<()>();
+            let path = path.clone();
+            let id = registry.start(0, &OperationOptions::default(), move |progress| {
+                let mut input = ReleasableFile::open(&path, progress.handles())?;
+                let mut head = [0u8; 4];
+                input.read_exact(&mut head)?;
+                wait.recv()?;
+                progress.report(PROGRESS_PHASE_READING, 0.5);
+                let open_after_pause = progress.handles().map_or(0, |h| h.open_count());
+                let mut rest = Vec::new();
+                input.read_to_end(&mut rest)?;
+                if [&head[..], &rest].concat() != b"0123456789" {
+                    return Err("Read from the wrong position".into());
+                }
+                Ok(open_after_pause as u64)
+            });
+            assert_eq!(registry.set_paused(id, true, release), 0);
+            go.send(()).unwrap();
+            wait_until(&registry, id, &[OPERATION_PAUSED]);
+            assert_eq!(registry.set_paused(id, false, false), 0);
+            let status = wait_for(&registry, id);
+            assert_eq!((status.state, status.bytes_out), (OPERATION_FINISHED, if release { 0 } else { 1 }));
+        }
+    }
+
+    #[test]
+    fn test_queued_jobs_run_by_priority() {
+        let registry = OperationRegistry::new(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (go, job) = blocked_job(&order, "first");
+        let first = registry.start(0, &OperationOptions { priority: PRIORITY_INTERACTIVE }, job);
+        wait_until(&registry, first, &[OPERATION_RUNNING]);
+
+        let background_order = Arc::clone(&order);
+        let background = registry.start(0, &OperationOptions { priority: PRIORITY_BACKGROUND }, move |_| {
+            background_order.lock().unwrap().push("background");
+            Ok(0)
+        });
+        let normal_order = Arc::clone(&order);
+        let normal = registry.start(0, &OperationOptions::default(), move |_| {
+            normal_order.lock().unwrap().push("normal");
+            Ok(0)
+        });
+        assert_eq!(registry.status(background).unwrap().state, OPERATION_QUEUED);
+
+        go.send(()).unwrap();
+        wait_for(&registry, background);
+        wait_for(&registry, normal);
+        assert_eq!(*order.lock().unwrap(), vec!["first", "normal", "background"]);
+    }
+
+    #[test]
+    fn test_interactive_job_preempts_background_job() {
+        let registry = OperationRegistry::new(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (go, job) = blocked_job(&order, "background");
+        let background = registry.start(0, &OperationOptions { priority: PRIORITY_BACKGROUND }, job);
+        wait_until(&registry, background, &[OPERATION_RUNNING]);
+
+        let (go_interactive, job) = blocked_job(&order, "interactive");
+        let interactive = registry.start(0, &OperationOptions { priority: PRIORITY_INTERACTIVE }, job);
+        wait_until(&registry, interactive, &[OPERATION_RUNNING]);
+
+        go.send(()).unwrap();
+        wait_until(&registry, background, &[OPERATION_PAUSED]);
+        go_interactive.send(()).unwrap();
+        wait_for(&registry, interactive);
+        wait_for(&registry, background);
+        assert_eq!(*order.lock().unwrap(), vec!["interactive", "background"]);
+    }
+
+    #[test]
+    fn test_shutdown_drains_registry() {
+        let registry = OperationRegistry::new(2);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (go, job) = blocked_job(&order, "slow");
+        let slow = registry.start(0, &OperationOptions::default(), job);
+        let quick = registry.start(0, &OperationOptions::default(), |progress: &ProgressSink| {
+            thread::sleep(Duration::from_millis(20));
+            progress.report(PROGRESS_PHASE_READING, 1.0);
+            Ok(0)
+        });
+        let queued = registry.start(0, &OperationOptions::default(), |_: &ProgressSink| Ok(0));
+        wait_until(&registry, slow, &[OPERATION_RUNNING]);
+
+        let summary = registry.shutdown(Duration::from_millis(200));
+        assert_eq!((summary.completed, summary.cancelled, summary.interrupted), (1, 1, 1));
+        assert_eq!(registry.status(quick).unwrap().state, OPERATION_FINISHED);
+        assert_eq!(registry.status(queued).unwrap().state, OPERATION_CANCELLED);
+        assert_eq!(registry.start(0, &OperationOptions::default(), |_: &ProgressSink| Ok(0)), 0);
+
+        go.send(()).unwrap();
+        wait_until(&registry, slow, &[OPERATION_PAUSED]);
+        assert_eq!(registry.shutdown(Duration::ZERO).checkpointed, 1);
+        assert!(order.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,303 @@
+use std::ffi::CStr;
+use std::io::{self, Read};
+use std::os::raw::c_char;
+
+use crate::progress::ProgressSink;
+use crate::{encrypt_file_reporting, EncryptOptions};
+
+pub const PADDING_NONE: u8 = 0;
+pub const PADDING_PADME: u8 = 1;
+pub const PADDING_POWER_OF_TWO: u8 = 2;
+// PADME lengths in a body of two equal slots, one for each of up to two
+// passwords. A slot nobody holds a password for is filled with random bytes,
+// so a file with a decoy looks like any other written this way.
+pub const PADDING_SLOTS: u8 = 3;
+// Multiples of a bucket of 512 << (scheme & 0x0f) bytes, so many small
+// files share a handful of sizes rather than each showing its own.
+pub const PADDING_BUCKET: u8 = 0x10;
+const MIN_BUCKET_SHIFT: u32 = 9;
+
+pub const PADDING_MARKER: u8 = 0x80;
+const MIN_BUCKET: u64 = 256;
+const ZERO_BLOCK: [u8; 8192] = [0; 8192];
+
+type EmitResult = Result<(), Box<dyn std::error::Error>>;
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_padded(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    padding: u8,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        if !is_valid_scheme(padding) {
+            return -1;
+        }
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let options = EncryptOptions { hint, padding, ..Default::default() };
+        match encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &ProgressSink::none()) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn padded_len(len: u64, scheme: u8) -> u64 {
+    match scheme {
+        PADDING_PADME | PADDING_SLOTS => padme(len + 1),
+        PADDING_POWER_OF_TWO => (len + 1).max(MIN_BUCKET).next_power_of_two(),
+        _ => match bucket_size(scheme) {
+            Some(bucket) => (len + 1).div_ceil(bucket) * bucket,
+            None => len,
+        },
+    }
+}
+
+// The scheme for a bucket size, which must be a power of two from 512 bytes
+// to 16 MiB.
+pub fn bucket_padding(bucket_size: u64) -> Option<u8> {
+    let shift = bucket_size.checked_ilog2()?;
+    let offset = shift.checked_sub(MIN_BUCKET_SHIFT).filter(|&offset| offset <= 0x0f && bucket_size.is_power_of_two())?;
+    Some(PADDING_BUCKET | offset as u8)
+}
+
+fn bucket_size(scheme: u8) -> Option<u64> {
+    (scheme & 0xf0 == PADDING_BUCKET).then(|| 1 << (MIN_BUCKET_SHIFT + (scheme & 0x0f) as u32))
+}
+
+pub fn is_valid_scheme(scheme: u8) -> bool {
+    scheme <= PADDING_SLOTS || bucket_size(scheme).is_some()
+}
+
+// Slots need the padded length before the first frame is written, so
+// writers that only learn it at the end use PADME lengths in one slot.
+pub fn for_stream(scheme: u8) -> u8 {
+    if scheme == PADDING_SLOTS {
+        PADDING_PADME
+    } else {
+        scheme
+    }
+}
+
+fn padme(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+    let exponent = 63 - len.leading_zeros();
+    let exponent_bits = 32 - exponent.leading_zeros();
+    let mask = (1u64 << (exponent - exponent_bits)) - 1;
+    (len + mask) & !mask
+}
+
+pub fn padding_reader(len: u64, scheme: u8) -> impl Read {
+    pad_to(len, padded_len(len, scheme))
+}
+
+pub fn pad_to(len: u64, target: u64) -> impl Read {
+    let padding = target.saturating_sub(len);
+    let marker: &'static [u8] = if padding > 0 { &[PADDING_MARKER] } else { &[] };
+    marker.chain(io::repeat(0).take(padding.saturating_sub(1)))
+}
+
+// Appends the padding to a source whose length is only known once it ends.
+pub struct PaddedStream<R> {
+    inner: R,
+    scheme: u8,
+    len: u64,
+    padding: Option<Box<dyn Read>>,
+}
+
+impl<R: Read> PaddedStream<R> {
+    pub fn new(inner: R, scheme: u8) -> Self {
+        PaddedStream { inner, scheme, len: 0, padding: None }
+    }
+}
+
+impl<R: Read> Read for PaddedStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(padding) = &mut self.padding {
+            return padding.read(buf);
+        }
+        match self.inner.read(buf)? {
+            0 if !buf.is_empty() => {
+                let padding = self.padding.insert(Box::new(padding_reader(self.len, self.scheme)));
+                padding.read(buf)
+            }
+            n => {
+                self.len += n as u64;
+                Ok(n)
+            }
+        }
+    }
+}
+
+pub fn strip(data: &mut Vec<u8>, scheme: u8) -> Result<(), Box<dyn std::error::Error>> {
+    if scheme == PADDING_NONE {
+        return Ok(());
+    }
+    match data.iter().rposition(|&b| b != 0) {
+        Some(end) if data[end] == PADDING_MARKER => {
+            data.truncate(end);
+            Ok(())
+        }
+        _ => Err("Invalid padding".into()),
+    }
+}
+
+pub struct PaddingStripper {
+    enabled: bool,
+    pending_marker: bool,
+    pending_zeros: u64,
+}
+
+impl PaddingStripper {
+    pub fn new(scheme: u8) -> Self {
+        PaddingStripper {
+            enabled: scheme != PADDING_NONE,
+            pending_marker: false,
+            pending_zeros: 0,
+        }
+    }
+
+    pub fn feed<F>(&mut self, chunk: &[u8], mut emit: F) -> EmitResult
+    where
+        F: FnMut(&[u8]) -> EmitResult,
+    {
+        if !self.enabled {
+            return emit(chunk);
+        }
+        let last = match chunk.iter().rposition(|&b| b != 0) {
+            Some(last) => last,
+            None => {
+                self.pending_zeros += chunk.len() as u64;
+                return Ok(());
+            }
+        };
+
+        if self.pending_marker {
+            emit(&[PADDING_MARKER])?;
+        }
+        while self.pending_zeros > 0 {
+            let n = self.pending_zeros.min(ZERO_BLOCK.len() as u64) as usize;
+            emit(&ZERO_BLOCK[..n])?;
+            self.pending_zeros -= n as u64;
+        }
+
+        self.pending_marker = chunk[last] == PADDING_MARKER;
+        let content_end = if self.pending_marker { last } else { last + 1 };
+        self.pending_zeros = (chunk.len() - last - 1) as u64;
+        emit(&chunk[..content_end])
+    }
+
+    pub fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.enabled && !self.pending_marker {
+            return Err("Invalid padding".into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::files_have_same_content_internal;
+    use crate::{decrypt_file_internal, decrypt_file_to_memory_internal, get_hint_from_file_internal, HEADER_SIZE};
+    use std::fs;
+
+    #[test]
+    fn test_bucket_sizes() {
+        assert_eq!(padded_len(1000, PADDING_NONE), 1000);
+        assert_eq!(padded_len(0, PADDING_POWER_OF_TWO), 256);
+        assert_eq!(padded_len(1000, PADDING_POWER_OF_TWO), 1024);
+        assert_eq!(padded_len(1023, PADDING_POWER_OF_TWO), 1024);
+        assert_eq!(padded_len(1024, PADDING_POWER_OF_TWO), 2048);
+        assert_eq!(padded_len(1_000_000, PADDING_PADME), 1_015_808);
+        let four_kib = bucket_padding(4096).unwrap();
+        assert_eq!((padded_len(0, four_kib), padded_len(4095, four_kib), padded_len(4096, four_kib)), (4096, 4096, 8192));
+        assert_eq!(bucket_padding(512), Some(PADDING_BUCKET));
+        assert!(bucket_padding(256).is_none() && bucket_padding(3000).is_none() && bucket_padding(32 << 20).is_none());
+        for len in [0u64, 1, 9, 100, 4096, 123_456_789] {
+            let padded = padded_len(len, PADDING_PADME);
+            assert!(padded > len && padded - len <= (len + 1) / 8 + 1);
+        }
+    }
+
+    #[test]
+    fn test_stripper_handles_zero_runs_across_chunks() {
+        let content = [b"data\x80".as_slice(), &[0u8; 20000], b"tail\x80\x00"].concat();
+        let mut padded = content.clone();
+        padding_reader(content.len() as u64, PADDING_POWER_OF_TWO).read_to_end(&mut padded).unwrap();
+
+        let mut stripped = Vec::new();
+        let mut stripper = PaddingStripper::new(PADDING_POWER_OF_TWO);
+        for chunk in padded.chunks(4096) {
+            stripper
+                .feed(chunk, |d| {
+                    stripped.extend_from_slice(d);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        stripper.finish().unwrap();
+        assert_eq!(stripped, content);
+
+        let mut in_memory = padded;
+        strip(&mut in_memory, PADDING_POWER_OF_TWO).unwrap();
+        assert_eq!(in_memory, content);
+        assert!(PaddingStripper::new(PADDING_PADME).finish().is_err());
+    }
+
+    #[test]
+    fn test_padded_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.bin");
+        let padded = dir.path().join("padded.kyl");
+        let unpadded = dir.path().join("unpadded.kyl");
+        let decrypted = dir.path().join("decrypted.bin");
+        fs::write(&plain, vec![0u8; 3000]).unwrap();
+
+        let options = EncryptOptions { hint: Some("hint"), padding: PADDING_POWER_OF_TWO, ..Default::default() };
+        encrypt_file_reporting(
+            plain.to_str().unwrap(),
+            padded.to_str().unwrap(),
+            &b"pw"[..],
+            &options,
+            false,
+            4,
+            &ProgressSink::none(),
+        )
+        .unwrap();
+        crate::encrypt_file_internal(plain.to_str().unwrap(), unpadded.to_str().unwrap(), b"pw", None, false, 4)
+            .unwrap();
+
+        // The sealed metadata holds the mtime, so its length is read back.
+        let metadata_len = crate::format::read_header(&padded).unwrap().0.metadata.len() as u64;
+        let padded = padded.to_str().unwrap();
+        let header_len = HEADER_SIZE as u64 + 1 + 4 + 2 + 3 + 3 + 3 + 28 + 62 + 2 + metadata_len + 15 + 50 + 2 + 4 + 2 + 32 + 2 + 60;
+        assert_eq!(fs::metadata(padded).unwrap().len(), header_len + 4096 + 16);
+        assert_eq!(get_hint_from_file_internal(padded).unwrap(), b"hint");
+        assert_eq!(decrypt_file_to_memory_internal(padded, b"pw", false, 4).unwrap(), vec![0u8; 3000]);
+        decrypt_file_internal(padded, decrypted.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), vec![0u8; 3000]);
+        assert!(files_have_same_content_internal(padded, unpadded.to_str().unwrap(), b"pw", false).unwrap());
+    }
+}
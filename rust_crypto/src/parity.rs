@@ -0,0 +1,271 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::slice;
+
+use crate::format::{read_layout, Parity};
+use crate::progress::ProgressSink;
+use crate::{encrypt_file_reporting, errors, reencrypt, EncryptOptions};
+
+pub const MAX_PARITY_PERCENT: u8 = 50;
+// The largest frame is cut into this many data shards. With at most half as
+// many parity shards, a frame stays within the 256 shards of GF(2^8).
+const DATA_SHARDS: u64 = 128;
+// Shards are whole sectors, the unit storage usually loses.
+const SECTOR_SIZE: u64 = 512;
+const SHARD_CHECKSUM_SIZE: usize = 4;
+
+#[no_mangle]
+pub extern "C" fn kyrie_add_parity(path_ptr: *const c_char, password_ptr: *const u8, password_len: usize, percent: u8) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        match add_parity(Path::new(path), password, percent) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Parity is added once the body is written, which costs a second key
+// derivation and a second read of the ciphertext.
+#[no_mangle]
+pub extern "C" fn encrypt_file_with_parity(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    percent: u8,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if percent == 0 || percent > MAX_PARITY_PERCENT {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+        let options = EncryptOptions { hint, ..Default::default() };
+
+        let result = encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &ProgressSink::none())
+            .and_then(|_| add_parity(Path::new(output_path), password, percent));
+        match result {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Replaces any parity the file already has, so the strength can be changed.
+pub fn add_parity(path: &Path, password: &[u8], percent: u8) -> Result<(), Box<dyn std::error::Error>> {
+    if percent == 0 || percent > MAX_PARITY_PERCENT {
+        return Err("Invalid parity percentage".into());
+    }
+    let layout = read_layout(path)?;
+    let frames: Vec<(u64, u64)> = layout.frames.iter().map(|frame| (frame.offset, layout.frame_size(frame))).collect();
+    let largest = frames.iter().map(|&(_, len)| len).max().unwrap_or(0);
+    let shard_size = largest.div_ceil(DATA_SHARDS).next_multiple_of(SECTOR_SIZE).max(SECTOR_SIZE);
+    let mut parity = Parity { percent, shard_size: u32::try_from(shard_size)?, len: 0 };
+    parity.len = frames.iter().map(|&(_, len)| block_len(len, &parity)).sum();
+
+    let mut source = BufReader::new(File::open(path)?);
+    let mut write_parity = |output: &mut File| -> Result<(), Box<dyn std::error::Error>> {
+        let mut output = BufWriter::new(output);
+        for &(offset, len) in &frames {
+            let frame = read_region(&mut source, offset, len)?;
+            output.write_all(&encode_block(&frame, &parity)?)?;
+        }
+        output.flush()?;
+        Ok(())
+    };
+    reencrypt::rewrite_container(
+        path,
+        password,
+        |header, _| {
+            header.parity = Some(parity);
+            Ok(())
+        },
+        Some(&mut write_parity),
+    )
+}
+
+pub enum ParityCheck {
+    Intact,
+    // The frame as it was written and a fresh parity block for it. Damage
+    // confined to the parity block leaves `frame_damaged` unset.
+    Repaired { frame: Vec<u8>, block: Vec<u8>, frame_damaged: bool },
+    Unrecoverable,
+}
+
+// Each frame's block is its parity shards followed by a CRC32 of every data
+// and parity shard, which is what locates the damage for the decoder.
+pub fn block_len(frame_len: u64, parity: &Parity) -> u64 {
+    let (data, parity_shards) = shard_counts(frame_len, parity);
+    (parity_shards as u64) * parity.shard_size as u64 + ((data + parity_shards) * SHARD_CHECKSUM_SIZE) as u64
+}
+
+fn shard_counts(frame_len: u64, parity: &Parity) -> (usize, usize) {
+    let data = frame_len.div_ceil(parity.shard_size as u64).max(1) as usize;
+    (data, (data * parity.percent as usize).div_ceil(100).max(1))
+}
+
+fn shards(frame: &[u8], parity: &Parity) -> Vec<Vec<u8>> {
+    let shard_size = parity.shard_size as usize;
+    let (data, parity_shards) = shard_counts(frame.len() as u64, parity);
+    let mut shards: Vec<Vec<u8>> = frame
+        .chunks(shard_size)
+        .map(|chunk| {
+            let mut shard = chunk.to_vec();
+            shard.resize(shard_size, 0);
+            shard
+        })
+        .collect();
+    shards.resize(data + parity_shards, vec![0; shard_size]);
+    shards
+}
+
+fn encode_block(frame: &[u8], parity: &Parity) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (data, parity_shards) = shard_counts(frame.len() as u64, parity);
+    let mut shards = shards(frame, parity);
+    ReedSolomon::new(data, parity_shards).and_then(|rs| rs.encode(&mut shards)).map_err(|_| "Invalid parity")?;
+    let mut block: Vec<u8> = shards[data..].concat();
+    for shard in &shards {
+        block.extend_from_slice(&crc32fast::hash(shard).to_le_bytes());
+    }
+    Ok(block)
+}
+
+pub fn check_frame<R: Read + Seek>(reader: &mut R, frame_offset: u64, frame_len: u64, block_offset: u64, parity: &Parity) -> ParityCheck {
+    let regions = read_region(reader, frame_offset, frame_len)
+        .and_then(|frame| Ok((frame, read_region(reader, block_offset, block_len(frame_len, parity))?)));
+    let Ok((frame, block)) = regions else {
+        return ParityCheck::Unrecoverable;
+    };
+    repair_frame(&frame, &block, parity).unwrap_or(ParityCheck::Unrecoverable)
+}
+
+fn repair_frame(frame: &[u8], block: &[u8], parity: &Parity) -> Result<ParityCheck, Box<dyn std::error::Error>> {
+    let shard_size = parity.shard_size as usize;
+    let (data, parity_shards) = shard_counts(frame.len() as u64, parity);
+    let (parity_bytes, checksums) = block.split_at(parity_shards * shard_size);
+    let mut shards: Vec<(Vec<u8>, bool)> = shards(frame, parity)
+        .into_iter()
+        .take(data)
+        .chain(parity_bytes.chunks(shard_size).map(<[u8]>::to_vec))
+        .zip(checksums.chunks(SHARD_CHECKSUM_SIZE))
+        .map(|(shard, checksum)| {
+            let intact = crc32fast::hash(&shard).to_le_bytes() == checksum;
+            (shard, intact)
+        })
+        .collect();
+    if shards.iter().all(|(_, intact)| *intact) {
+        return Ok(ParityCheck::Intact);
+    }
+
+    let frame_damaged = shards[..data].iter().any(|(_, intact)| !intact);
+    ReedSolomon::new(data, parity_shards).and_then(|rs| rs.reconstruct(&mut shards)).map_err(|_| "Too much damage to repair")?;
+    let mut repaired: Vec<u8> = shards[..data].iter().flat_map(|(shard, _)| shard.iter().copied()).collect();
+    repaired.truncate(frame.len());
+    let block = encode_block(&repaired, parity)?;
+    Ok(ParityCheck::Repaired { frame: repaired, block, frame_damaged })
+}
+
+fn read_region<R: Read + Seek>(reader: &mut R, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut region = vec![0u8; len as usize];
+    reader.read_exact(&mut region)?;
+    Ok(region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::read_header;
+    use crate::reencrypt::set_hint;
+    use crate::repair::repair_file_internal;
+    use crate::test_util::write_framed_container;
+    use crate::{decrypt_file_internal, decrypt_file_to_memory_internal, encrypt_file_internal};
+    use std::fs;
+
+    fn flip(path: &Path, offsets: &[u64]) {
+        let mut bytes = fs::read(path).unwrap();
+        for &offset in offsets {
+            bytes[offset as usize] ^= 0x01;
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_parity_rebuilds_damaged_sectors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.kyl");
+        let chunks: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 4000]).collect();
+        let chunk_refs: Vec<&[u8]> = chunks.iter().map(|c| c.as_slice()).collect();
+        write_framed_container(&path, b"pw", &chunk_refs);
+        assert!(add_parity(&path, b"pw", 0).is_err());
+        add_parity(&path, b"pw", 25).unwrap();
+        let parity = read_header(&path).unwrap().0.parity.unwrap();
+        assert_eq!((parity.percent, parity.shard_size), (25, 512));
+
+        // Two sectors of frame 1 fit within its two parity shards; three
+        // sectors of frame 3 do not. Frame 0 only loses a parity shard.
+        let layout = read_layout(&path).unwrap();
+        let (_, data_start) = read_header(&path).unwrap();
+        let parity_start = read_header(&path).unwrap().0.body_end(data_start, fs::metadata(&path).unwrap().len());
+        let frames = &layout.frames;
+        flip(&path, &[frames[1].offset + 10, frames[1].offset + 2000, parity_start + 3]);
+        flip(&path, &[frames[3].offset, frames[3].offset + 600, frames[3].offset + 1200]);
+        assert!(decrypt_file_to_memory_internal(path.to_str().unwrap(), b"pw", false, 4).is_err());
+
+        let report = repair_file_internal(&path, None, &[]).unwrap();
+        assert_eq!(report.damaged_chunks, vec![1, 3]);
+        assert_eq!(report.repaired, vec![(1, path.clone())]);
+        assert_eq!(report.unrecovered_chunks, vec![3]);
+
+        flip(&path, &[frames[3].offset + 1200]);
+        let report = repair_file_internal(&path, None, &[]).unwrap();
+        assert_eq!((report.damaged_chunks, report.unrecovered_chunks), (vec![3], vec![]));
+        let plain: Vec<u8> = chunks.concat();
+        assert_eq!(decrypt_file_to_memory_internal(path.to_str().unwrap(), b"pw", false, 4).unwrap(), plain);
+        assert!(repair_file_internal(&path, None, &[]).unwrap().damaged_chunks.is_empty());
+
+        // Header edits keep the parity and re-encryption writes it anew.
+        let plain_path = dir.path().join("plain.bin");
+        let encrypted = dir.path().join("plain.kyl");
+        let decrypted = dir.path().join("plain.out");
+        fs::write(&plain_path, &plain).unwrap();
+        let p = |path: &Path| path.to_str().unwrap().to_string();
+        encrypt_file_internal(&p(&plain_path), &p(&encrypted), b"pw", None, false, 4).unwrap();
+        assert!(add_parity(&encrypted, b"wrong", 5).is_err());
+        add_parity(&encrypted, b"pw", 5).unwrap();
+        set_hint(&encrypted, b"pw", "the usual").unwrap();
+        assert_eq!(read_header(&encrypted).unwrap().0.parity.map(|parity| parity.percent), Some(5));
+        decrypt_file_internal(&p(&encrypted), &p(&decrypted), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), plain);
+        reencrypt::reencrypt_file_internal(&p(&encrypted), &p(&encrypted), b"pw", b"new", &Default::default(), false).unwrap();
+        assert_eq!(read_header(&encrypted).unwrap().0.parity.map(|parity| parity.percent), Some(5));
+        assert!(repair_file_internal(&encrypted, None, &[]).unwrap().damaged_chunks.is_empty());
+        decrypt_file_internal(&p(&encrypted), &p(&decrypted), b"new", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), plain);
+    }
+}
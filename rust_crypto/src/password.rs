@@ -0,0 +1,191 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::slice;
+use zeroize::Zeroizing;
+
+use crate::{errors, throttle};
+
+// Most common first; a match costs an attacker its rank in guesses.
+const COMMON_PASSWORDS: [&str; 60] = [
+    "password", "123456", "qwerty", "abc123", "letmein", "monkey", "dragon", "111111", "baseball", "iloveyou",
+    "trustno1", "sunshine", "master", "welcome", "shadow", "ashley", "football", "jesus", "michael", "ninja",
+    "mustang", "access", "login", "admin", "princess", "starwars", "solo", "flower", "passw0rd", "hello",
+    "freedom", "whatever", "qazwsx", "charlie", "donald", "batman", "zaq1zaq1", "secret", "summer", "winter",
+    "spring", "autumn", "love", "lovely", "family", "photo", "photos", "album", "kyrie", "kyrielock",
+    "secure", "private", "hidden", "vault", "locked", "open", "test", "user", "root", "default",
+];
+
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+// What the substituted digits and symbols most often stand for.
+const LEET: [(char, char); 8] = [('4', 'a'), ('@', 'a'), ('3', 'e'), ('1', 'i'), ('0', 'o'), ('5', 's'), ('$', 's'), ('7', 't')];
+
+// Guess counts at which the score steps up, as in zxcvbn.
+const SCORE_THRESHOLDS: [f64; 4] = [1e3, 1e6, 1e8, 1e10];
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct KyriePasswordStrength {
+    // 0 (guessed at once) to 4 (out of reach of an offline attack).
+    pub score: u32,
+    // log2 of the guesses the cheapest reading of the password needs.
+    pub entropy_bits: f64,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_password_strength(
+    password_ptr: *const u8,
+    password_len: usize,
+    strength_ptr: *mut KyriePasswordStrength,
+) -> i32 {
+    if strength_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        *strength_ptr = password_strength(password);
+    }
+    0
+}
+
+// Returns 1 when the password opens the file and 0 when it does not, without
+// decrypting the body.
+#[no_mangle]
+pub extern "C" fn kyrie_validate_password(path_ptr: *const c_char, password_ptr: *const u8, password_len: usize) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        match throttle::password_matches(Path::new(path), password) {
+            Ok(matches) => matches as i32,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Reads the password as the cheapest run of known patterns (common
+// passwords, l33t spellings of them, keyboard runs, sequences, repeats and
+// years) with brute force filling the gaps, and scores what that costs.
+pub fn password_strength(password: &[u8]) -> KyriePasswordStrength {
+    let text = Zeroizing::new(String::from_utf8_lossy(password).into_owned());
+    let chars: Zeroizing<Vec<char>> = Zeroizing::new(text.chars().collect());
+    let lower: Zeroizing<Vec<char>> = Zeroizing::new(chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect());
+    if chars.is_empty() {
+        return KyriePasswordStrength { score: 0, entropy_bits: 0.0 };
+    }
+    let brute_force_bits = (cardinality(&chars) as f64).log2();
+    let patterns = find_patterns(&chars, &lower);
+
+    // best[i] is the fewest bits that explain the first i characters.
+    let mut best = vec![f64::INFINITY; chars.len() + 1];
+    best[0] = 0.0;
+    for end in 1..=chars.len() {
+        best[end] = best[end - 1] + brute_force_bits;
+        for &(start, _, bits) in patterns.iter().filter(|(_, pattern_end, _)| *pattern_end == end) {
+            best[end] = best[end].min(best[start] + bits);
+        }
+    }
+
+    let entropy_bits = best[chars.len()];
+    let guesses = entropy_bits.exp2();
+    let score = SCORE_THRESHOLDS.iter().filter(|&&threshold| guesses >= threshold).count() as u32;
+    KyriePasswordStrength { score, entropy_bits }
+}
+
+fn cardinality(chars: &[char]) -> u32 {
+    let has = |class: fn(&char) -> bool| chars.iter().any(class);
+    [
+        (has(char::is_ascii_lowercase), 26),
+        (has(char::is_ascii_uppercase), 26),
+        (has(char::is_ascii_digit), 10),
+        (has(char::is_ascii_punctuation), 33),
+        (chars.iter().any(|c| !c.is_ascii_graphic()), 100),
+    ]
+    .iter()
+    .filter(|(present, _)| *present)
+    .map(|(_, size)| size)
+    .sum()
+}
+
+// Every pattern found, as (start, end, bits).
+fn find_patterns(chars: &[char], lower: &[char]) -> Vec<(usize, usize, f64)> {
+    let mut patterns = Vec::new();
+    let unleeted: Vec<char> = lower.iter().map(|c| LEET.iter().find(|(from, _)| from == c).map_or(*c, |(_, to)| *to)).collect();
+    for start in 0..chars.len() {
+        for end in start + 3..=chars.len() {
+            let word: String = lower[start..end].iter().collect();
+            let plain: String = unleeted[start..end].iter().collect();
+            let capitals = chars[start..end].iter().any(|c| c.is_uppercase());
+            let rank = COMMON_PASSWORDS.iter().position(|&common| common == word).map(|rank| (rank, false));
+            let rank = rank.or_else(|| COMMON_PASSWORDS.iter().position(|&common| common == plain).map(|rank| (rank, true)));
+            if let Some((rank, leet)) = rank {
+                let bits = ((rank + 1) as f64).log2() + capitals as u8 as f64 + leet as u8 as f64;
+                patterns.push((start, end, bits));
+            }
+            if KEYBOARD_ROWS.iter().any(|row| row.contains(&word) || row.contains(&word.chars().rev().collect::<String>())) {
+                patterns.push((start, end, ((KEYBOARD_ROWS.len() * 2 * (end - start)) as f64).log2()));
+            }
+            if is_run(&lower[start..end], 0) {
+                patterns.push((start, end, (cardinality(&chars[start..=start]) as f64 * (end - start) as f64).log2()));
+            }
+            if is_run(&lower[start..end], 1) || is_run(&lower[start..end], -1) {
+                let obvious = matches!(lower[start], 'a' | 'z' | '0' | '1' | '9');
+                patterns.push((start, end, ((if obvious { 4 } else { 26 } * 2 * (end - start)) as f64).log2()));
+            }
+        }
+        if let Some(year) = chars.get(start..start + 4) {
+            if matches!(year, ['1', '9', '0'..='9', '0'..='9'] | ['2', '0', '0'..='9', '0'..='9']) {
+                patterns.push((start, start + 4, 200f64.log2()));
+            }
+        }
+    }
+    patterns
+}
+
+// Whether each character is `step` code points after the one before it.
+fn is_run(chars: &[char], step: i64) -> bool {
+    chars.windows(2).all(|pair| pair[1] as i64 - pair[0] as i64 == step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_framed_container;
+    use crate::{encrypt_file_internal, errors};
+    use std::fs;
+
+    #[test]
+    fn test_patterns_score_low_and_validation_skips_the_body() {
+        let scores: Vec<u32> = ["password", "P@ssw0rd", "qwerty123", "aaaaaaaaaaaa", "abcdef1990", "kT9#vq2!Lm4@xZ", "correct horse battery staple"]
+            .iter()
+            .map(|p| password_strength(p.as_bytes()).score)
+            .collect();
+        assert_eq!(scores, [0, 0, 0, 0, 1, 4, 4]);
+        assert!(password_strength(b"P@ssw0rd").entropy_bits < password_strength(b"Pbssw0rd").entropy_bits);
+        assert_eq!(password_strength(b""), KyriePasswordStrength::default());
+
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("photo.jpg");
+        let encrypted = dir.path().join("photo.kyl");
+        fs::write(&plain, vec![1u8; 300_000]).unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        // The header tag decides, so a damaged body does not matter.
+        let mut bytes = fs::read(&encrypted).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(&encrypted, bytes).unwrap();
+        assert!(throttle::password_matches(&encrypted, b"pw").unwrap());
+        assert!(!throttle::password_matches(&encrypted, b"wrong").unwrap());
+
+        let legacy = dir.path().join("legacy.kyl");
+        write_framed_container(&legacy, b"pw", &[b"first", b"second"]);
+        assert!(throttle::password_matches(&legacy, b"pw").unwrap());
+        assert!(!throttle::password_matches(&legacy, b"wrong").unwrap());
+        let missing = throttle::password_matches(&dir.path().join("missing.kyl"), b"pw").unwrap_err();
+        assert_eq!(errors::classify(missing.as_ref()), errors::ERR_FILE_NOT_FOUND);
+    }
+}
@@ -0,0 +1,283 @@
+use aes_gcm::{aead::Aead, AeadInPlace, Nonce};
+use rayon::prelude::*;
+use std::ffi::CStr;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::raw::c_char;
+use zeroize::Zeroizing;
+
+use crate::config::{self, KyrieConfig};
+use crate::fd::{self, KyrieDescriptor};
+use crate::format::{ContainerHeader, ContainerReader, FRAMING_LENGTH_PREFIXED, FRAMING_SINGLE, FRAMING_STREAMED};
+use crate::manifest::ManifestBuilder;
+use crate::nonce::NonceSequence;
+use crate::padding::{self, PaddedStream, PaddingStripper};
+use crate::pipeline::{self, MAX_FRAME_LEN};
+use crate::profile::OptionsProfile;
+use crate::sparse::HoleFiller;
+use crate::progress::{ProgressSink, PROGRESS_PHASE_WRITING};
+use crate::{errors, escrow, format, kdf, key_usage, text, write_encrypted_frame, write_frame, write_header};
+
+// Encrypts from one descriptor to another without seeking either, so both
+// may be pipes or sockets. Nothing about the input is known up front, so the
+// body is always framed and only one batch of chunks is held at a time.
+#[no_mangle]
+pub extern "C" fn encrypt_stream(
+    input_fd: KyrieDescriptor,
+    output_fd: KyrieDescriptor,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let (input, output) = (fd::borrow(input_fd), fd::borrow(output_fd));
+        let padding = OptionsProfile::current().padding;
+        match encrypt_stream_internal(&*input, &*output, password, hint, padding, is_mobile, cpu_cores, &ProgressSink::none()) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Plaintext is written as each batch authenticates, so on failure the
+// output already holds the part before it and must be discarded.
+#[no_mangle]
+pub extern "C" fn decrypt_stream(
+    input_fd: KyrieDescriptor,
+    output_fd: KyrieDescriptor,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    let password = unsafe { std::slice::from_raw_parts(password_ptr, password_len) };
+    let (input, output) = (fd::borrow(input_fd), fd::borrow(output_fd));
+    match decrypt_stream_internal(&*input, &*output, password, is_mobile, cpu_cores, &ProgressSink::none()) {
+        Ok(()) => 0,
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_stream_internal<R: Read, W: Write>(
+    input: R,
+    output: W,
+    password: &[u8],
+    hint: Option<&str>,
+    padding: u8,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = KyrieConfig::current();
+    let chunk_size = config.chunk_size(is_mobile);
+    let batch_size = config.parallel_batch_size(cpu_cores, is_mobile);
+    let padding = padding::for_stream(padding);
+    // No checksums, manifest or trailer: each needs the body's size, and the
+    // header is written before any of it is read.
+    let mut header = ContainerHeader {
+        hint: text::hint_bytes(hint),
+        padding,
+        nonce_scheme: format::NONCE_SCHEME_COUNTER,
+        key_purpose: kdf::KEY_PURPOSE_FILE_DATA,
+        ..Default::default()
+    };
+    header.use_password_kdf(is_mobile);
+    let key = Zeroizing::new(header.new_master_key(password)?);
+    let cipher = header.body_cipher(&key)?;
+    let body_key = header.body_key(&key);
+    header.escrow = escrow::configured_slots(&key)?;
+    header.set_streamed(chunk_size);
+    header.seal_file_id(&key);
+    let mut nonce_seq = NonceSequence::for_header(&header, &key)?;
+
+    let mut output = BufWriter::new(output);
+    write_header(&mut output, &header)?;
+    let mut reader = PaddedStream::new(input, padding);
+    let mut chunk_index = 0;
+    loop {
+        let mut batch = pipeline::read_plain_batch(&mut reader, chunk_size, batch_size, progress, chunk_index)?;
+        if batch.is_empty() {
+            break;
+        }
+        progress.check_cancelled()?;
+        let batch_len = batch.iter().map(Vec::len).sum::<usize>() as u64;
+        key_usage::check(&body_key, batch_len, batch.len() as u64)?;
+        let nonces = batch.iter().map(|_| nonce_seq.next_nonce()).collect::<Result<Vec<_>, _>>()?;
+        let sealed: Result<(), &str> = config::install(|| {
+            batch.par_iter_mut().zip(nonces.par_iter()).try_for_each(|(chunk, nonce_bytes)| {
+                cipher.encrypt_in_place(Nonce::from_slice(nonce_bytes), b"", chunk).map_err(|_| "Encryption failed")
+            })
+        });
+        sealed?;
+        for (nonce_bytes, chunk) in nonces.iter().zip(&batch) {
+            write_encrypted_frame(&mut output, nonce_seq.stored(nonce_bytes), chunk, false)?;
+        }
+        key_usage::record(&body_key, batch_len, batch.len() as u64)?;
+        chunk_index += batch.len();
+    }
+    write_frame(&mut output, &cipher, &mut nonce_seq, &[], false)?;
+    key_usage::record(&body_key, 0, 1)?;
+    output.flush()?;
+    progress.report(PROGRESS_PHASE_WRITING, 1.0);
+    Ok(())
+}
+
+// Reads any container whose body can be walked front to back: streamed and
+// framed ones, and single-chunk ones without a header trailer.
+pub fn decrypt_stream_internal<R: Read, W: Write>(
+    input: R,
+    output: W,
+    password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let batch_size = KyrieConfig::current().parallel_batch_size(cpu_cores, is_mobile);
+    let mut input = BufReader::new(input);
+    let (header, _) = ContainerHeader::read(&mut input)?;
+    // Without seeking, only a frame count tells a body from a trailer.
+    let framing = match header.framing {
+        Some(framing) if !header.dual() && !header.has_convergent_chunks() && (!header.backup || framing.version == FRAMING_LENGTH_PREFIXED) => framing,
+        _ => return Err("Unsupported framing version".into()),
+    };
+    let key = Zeroizing::new(header.master_key(password)?);
+    let cipher = header.body_cipher(&key)?;
+    let mut nonce_seq = NonceSequence::for_header(&header, &key)?;
+    // The holes of a sparse container can be written out as zeros here,
+    // though not skipped.
+    let mut output = BufWriter::new(HoleFiller::new(output, &header.holes));
+    let mut stripper = PaddingStripper::new(header.padding);
+    let mut manifest = ManifestBuilder::default();
+
+    if framing.version == FRAMING_SINGLE {
+        let nonce_bytes = nonce_seq.read_nonce(&mut input).map_err(cut_off)?;
+        let mut sealed = Vec::new();
+        (&mut input).take(MAX_FRAME_LEN).read_to_end(&mut sealed)?;
+        let plain = Zeroizing::new(
+            cipher.decrypt(Nonce::from_slice(&nonce_bytes), sealed.as_ref()).map_err(|_| "Decryption failed")?,
+        );
+        manifest.record_frame(&sealed);
+        header.verify_file_id(&key)?;
+        stripper.feed(&plain, |d| {
+            manifest.record_plaintext(d.len());
+            Ok(output.write_all(d)?)
+        })?;
+    } else {
+        let streamed = framing.version == FRAMING_STREAMED;
+        let mut input = ContainerReader::new(input.take(u64::MAX), &header);
+        let mut chunk_index = 0;
+        let mut ended = false;
+        // A framed body stops at its recorded count, before any trailer.
+        while !ended && (streamed || (chunk_index as u64) < framing.chunk_count) {
+            progress.check_cancelled()?;
+            let count = if streamed { batch_size } else { (framing.chunk_count - chunk_index as u64).min(batch_size as u64) as usize };
+            let batch = pipeline::read_frame_batch(&mut input, &mut nonce_seq, count, progress, chunk_index).map_err(cut_off)?;
+            if batch.is_empty() {
+                return Err("Unexpected end of file".into());
+            }
+            batch.iter().for_each(|(_, chunk)| manifest.record_frame(chunk));
+            let first_index = chunk_index;
+            chunk_index += batch.len();
+            pipeline::open_batch_ordered(batch, first_index, &cipher, progress, |index, plain| {
+                if index == 0 {
+                    header.verify_file_id(&key)?;
+                }
+                if ended {
+                    return Err("Invalid file format".into());
+                }
+                ended = streamed && plain.is_empty();
+                stripper.feed(plain, |d| {
+                    manifest.record_plaintext(d.len());
+                    Ok(output.write_all(d)?)
+                })
+            })?;
+        }
+        // A trailer or parity may follow a framed body; nothing else may.
+        if !header.backup && header.parity.is_none() {
+            input.finish()?;
+        }
+    }
+
+    stripper.finish()?;
+    manifest.check(header.manifest.as_ref())?;
+    output.into_inner().map_err(|e| e.into_error())?.finish()?;
+    progress.report(PROGRESS_PHASE_WRITING, 1.0);
+    Ok(())
+}
+
+// A stream that stops inside a frame is a truncated container, not an I/O
+// failure.
+fn cut_off(error: io::Error) -> Box<dyn std::error::Error> {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof => "Unexpected end of file".into(),
+        _ => error.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::padding::PADDING_PADME;
+    use crate::{decrypt_file_internal, encrypt_file_internal, TAG_SIZE};
+    use std::fs;
+
+    #[test]
+    fn test_streams_round_trip_and_catch_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = dir.path().join("piped.kyl");
+        let decrypted = dir.path().join("piped.out");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+
+        // A plain reader and writer, as a pipe gives: neither can seek.
+        let mut sealed = Vec::new();
+        encrypt_stream_internal(data.as_slice(), &mut sealed, b"pw", Some("pipe"), PADDING_PADME, false, 4, &ProgressSink::none())
+            .unwrap();
+        let (header, _) = ContainerHeader::read(&mut sealed.as_slice()).unwrap();
+        assert!(header.is_streamed() && header.manifest.is_none());
+        let mut opened = Vec::new();
+        decrypt_stream_internal(sealed.as_slice(), &mut opened, b"pw", false, 4, &ProgressSink::none()).unwrap();
+        assert_eq!(opened, data);
+
+        // The same container also decrypts from a file, small as it is.
+        fs::write(&encrypted, &sealed).unwrap();
+        decrypt_file_internal(encrypted.to_str().unwrap(), decrypted.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), data);
+
+        let cut = &sealed[..sealed.len() - TAG_SIZE - 4];
+        let error = decrypt_stream_internal(cut, &mut Vec::new(), b"pw", false, 4, &ProgressSink::none()).unwrap_err();
+        assert_eq!(errors::classify(error.as_ref()), errors::ERR_TRUNCATED);
+        let error = decrypt_stream_internal(&sealed[..1000], &mut Vec::new(), b"pw", false, 4, &ProgressSink::none()).unwrap_err();
+        assert_eq!(errors::classify(error.as_ref()), errors::ERR_TRUNCATED);
+        let extended = [&sealed[..], b"junk"].concat();
+        let error = decrypt_stream_internal(extended.as_slice(), &mut Vec::new(), b"pw", false, 4, &ProgressSink::none()).unwrap_err();
+        assert_eq!(errors::classify(error.as_ref()), errors::ERR_GARBAGE_AT_END);
+        fs::write(&encrypted, cut).unwrap();
+        fs::remove_file(&decrypted).unwrap();
+        assert!(decrypt_file_internal(encrypted.to_str().unwrap(), decrypted.to_str().unwrap(), b"pw", false, 4).is_err());
+        let error = decrypt_stream_internal(sealed.as_slice(), &mut Vec::new(), b"no", false, 4, &ProgressSink::none()).unwrap_err();
+        assert_eq!(errors::classify(error.as_ref()), errors::ERR_WRONG_PASSWORD);
+
+        let mut empty = Vec::new();
+        encrypt_stream_internal(&[][..], &mut empty, b"pw", None, 0, false, 4, &ProgressSink::none()).unwrap();
+        let mut opened = Vec::new();
+        decrypt_stream_internal(empty.as_slice(), &mut opened, b"pw", false, 4, &ProgressSink::none()).unwrap();
+        assert!(opened.is_empty());
+
+        // Files written by encrypt_file stream out too.
+        let plain = dir.path().join("photo.jpg");
+        fs::write(&plain, &data[..1000]).unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        let mut opened = Vec::new();
+        decrypt_stream_internal(fs::File::open(&encrypted).unwrap(), &mut opened, b"pw", false, 4, &ProgressSink::none()).unwrap();
+        assert_eq!(opened, &data[..1000]);
+    }
+}
@@ -0,0 +1,370 @@
+use aes_gcm::{AeadInPlace, Nonce};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Read, Take};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+use zeroize::Zeroizing;
+
+use crate::cipher::BodyCipher;
+use crate::config;
+use crate::digest::ContentHasher;
+use crate::format::ContainerReader;
+use crate::nonce::NonceSequence;
+use crate::progress::{ProgressSink, PROGRESS_PHASE_ENCRYPTING, PROGRESS_PHASE_READING, PROGRESS_PHASE_WRITING};
+use crate::{read_chunk, MAX_CHUNK_SIZE, NONCE_SIZE, TAG_SIZE};
+
+type StageError = Box<dyn std::error::Error + Send + Sync>;
+type SealedBatch = (Vec<Vec<u8>>, Vec<[u8; NONCE_SIZE]>);
+pub type Frame = ([u8; NONCE_SIZE], Vec<u8>);
+
+// The longest frame the format writes: a full desktop chunk and its tag.
+pub const MAX_FRAME_LEN: u64 = (MAX_CHUNK_SIZE + TAG_SIZE) as u64;
+
+// Batches are read one ahead of the one being processed, so at most two are
+// held at a time however large the file is.
+
+pub fn read_plain_batch<R: Read>(
+    reader: &mut R,
+    chunk_size: usize,
+    count: usize,
+    progress: &ProgressSink,
+    first_index: usize,
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut chunks = Vec::with_capacity(count);
+    while chunks.len() < count {
+        let started = Instant::now();
+        // Room for the tag, so the chunk can be sealed in place.
+        let mut chunk = Vec::with_capacity(chunk_size + TAG_SIZE);
+        chunk.resize(chunk_size, 0);
+        match read_chunk(reader, &mut chunk)? {
+            0 => break,
+            n => {
+                chunk.truncate(n);
+                progress.time_read(first_index + chunks.len(), n, started);
+                chunks.push(chunk);
+            }
+        }
+    }
+    Ok(chunks)
+}
+
+pub fn read_frame_batch<R: BufRead>(
+    reader: &mut ContainerReader<R>,
+    nonce_seq: &mut NonceSequence,
+    count: usize,
+    progress: &ProgressSink,
+    first_index: usize,
+) -> io::Result<Vec<Frame>> {
+    let mut frames = Vec::with_capacity(count);
+    while frames.len() < count {
+        let started = Instant::now();
+        let Some(frame) = reader.next_frame(nonce_seq)? else {
+            break;
+        };
+        progress.time_read(first_index + frames.len(), frame.1.len(), started);
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+// Reads, seals and writes at once: a reader thread fills the next batch
+// while a sealing thread hashes and encrypts one on the rayon pool and the
+// caller's thread hands the frames before it to `write`, in order. The
+// channels are rendezvous ones, so three batches are held at most.
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_pipelined<R, F>(
+    reader: &mut R,
+    total_len: usize,
+    chunk_size: usize,
+    batch_size: usize,
+    cipher: &BodyCipher,
+    nonce_seq: &mut NonceSequence,
+    hasher: &mut ContentHasher,
+    progress: &ProgressSink,
+    mut write: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    R: Read + Send,
+    F: FnMut(usize, &[u8; NONCE_SIZE], &[u8]) -> io::Result<()>,
+{
+    thread::scope(|scope| {
+        let (plain_tx, plain_rx) = mpsc::sync_channel::<io::Result<Vec<Vec<u8>>>>(0);
+        let (sealed_tx, sealed_rx) = mpsc::sync_channel::<Result<SealedBatch, StageError>>(0);
+
+        scope.spawn(move || {
+            let mut index = 0;
+            loop {
+                let batch = read_plain_batch(reader, chunk_size, batch_size, progress, index);
+                let last = batch.as_ref().map_or(true, Vec::is_empty);
+                index += batch.as_ref().map_or(0, Vec::len);
+                // A closed channel means the other stages have stopped.
+                if plain_tx.send(batch).is_err() || last {
+                    break;
+                }
+            }
+        });
+
+        scope.spawn(move || {
+            let mut index = 0;
+            let mut processed = 0;
+            for batch in plain_rx {
+                let sealed = batch.map_err(StageError::from).and_then(|mut batch| {
+                    if batch.is_empty() {
+                        return Ok(None);
+                    }
+                    let batch_len: usize = batch.iter().map(Vec::len).sum();
+                    processed += batch_len;
+                    progress.report_bytes(PROGRESS_PHASE_READING, processed, total_len);
+                    let nonces = seal_batch(&mut batch, index, cipher, nonce_seq, hasher, progress).map_err(|e| e.to_string())?;
+                    progress.report_bytes(PROGRESS_PHASE_ENCRYPTING, processed, total_len);
+                    index += batch.len();
+                    Ok(Some((batch, nonces)))
+                });
+                let sent = match sealed {
+                    Ok(None) => break,
+                    Ok(Some(sealed)) => sealed_tx.send(Ok(sealed)),
+                    Err(e) => {
+                        let _ = sealed_tx.send(Err(e));
+                        break;
+                    }
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut index = 0;
+        let mut processed = 0;
+        for sealed in sealed_rx {
+            let (batch, nonces) = sealed.map_err(|e| -> Box<dyn std::error::Error> { e })?;
+            for (encrypted, nonce_bytes) in batch.iter().zip(nonces.iter()) {
+                let started = Instant::now();
+                write(index, nonce_bytes, encrypted)?;
+                progress.time_write(index, started);
+                processed += encrypted.len() - TAG_SIZE;
+                index += 1;
+            }
+            progress.report_bytes(PROGRESS_PHASE_WRITING, processed, total_len);
+        }
+        Ok(())
+    })
+}
+
+// Opens a batch of frames on the rayon pool and hands each chunk to `write`
+// on the caller's thread as soon as it and every chunk before it are open,
+// so a chunk is written and freed while later ones are still decrypting
+// rather than once the whole batch is. The next batch is only read once
+// this returns, so no more than one batch is held at a time.
+pub fn open_batch_ordered<F>(
+    batch: Vec<Frame>,
+    first_index: usize,
+    cipher: &BodyCipher,
+    progress: &ProgressSink,
+    mut write: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(usize, &[u8]) -> Result<(), Box<dyn std::error::Error>>,
+{
+    thread::scope(|scope| {
+        let (opened_tx, opened_rx) = mpsc::channel::<(usize, Result<Zeroizing<Vec<u8>>, &str>)>();
+        let worker = scope.spawn(move || {
+            let started = Instant::now();
+            config::install(|| {
+                batch.into_par_iter().enumerate().for_each_with(opened_tx, |opened_tx, (offset, (nonce_bytes, chunk))| {
+                    let started = Instant::now();
+                    let mut chunk = Zeroizing::new(chunk);
+                    let opened = cipher
+                        .decrypt_in_place(Nonce::from_slice(&nonce_bytes), b"", &mut *chunk)
+                        .map(|_| chunk)
+                        .map_err(|_| "Decryption failed");
+                    progress.time_crypto(first_index + offset, started);
+                    // A closed channel means writing already failed.
+                    let _ = opened_tx.send((offset, opened));
+                })
+            });
+            progress.time_crypto_stage(started);
+        });
+
+        // Chunks that open out of order wait here for the ones before them.
+        let mut waiting = BTreeMap::new();
+        let mut next = 0;
+        let mut written = Ok(());
+        for (offset, opened) in opened_rx.iter() {
+            waiting.insert(offset, opened);
+            while let Some(opened) = waiting.remove(&next) {
+                written = opened.map_err(|e| e.into()).and_then(|chunk| write(first_index + next, &chunk));
+                if written.is_err() {
+                    break;
+                }
+                next += 1;
+            }
+            if written.is_err() {
+                break;
+            }
+        }
+        drop(opened_rx);
+        worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        written
+    })
+}
+
+// Hashes a batch and seals its chunks in place, returning their nonces.
+fn seal_batch(
+    batch: &mut [Vec<u8>],
+    first_index: usize,
+    cipher: &BodyCipher,
+    nonce_seq: &mut NonceSequence,
+    hasher: &mut ContentHasher,
+    progress: &ProgressSink,
+) -> Result<Vec<[u8; NONCE_SIZE]>, Box<dyn std::error::Error>> {
+    progress.check_cancelled()?;
+    let nonces = batch.iter().map(|_| nonce_seq.next_nonce()).collect::<Result<Vec<_>, _>>()?;
+    let started = Instant::now();
+    batch.iter().for_each(|chunk| hasher.update(chunk));
+    let sealed: Result<(), &str> = config::install(|| {
+        batch.par_iter_mut().zip(nonces.par_iter()).enumerate().try_for_each(|(offset, (chunk, nonce_bytes))| {
+            let started = Instant::now();
+            cipher.encrypt_in_place(Nonce::from_slice(nonce_bytes), b"", chunk).map_err(|_| "Encryption failed")?;
+            progress.time_crypto(first_index + offset, started);
+            Ok(())
+        })
+    });
+    sealed?;
+    progress.time_crypto_stage(started);
+    Ok(nonces)
+}
+
+// Frame lengths come from the file, so one is only believed as far as the
+// format allows and the rest of the body reaches; a crafted length would
+// otherwise be a multi-gigabyte allocation.
+pub fn read_frame<R: Read>(reader: &mut Take<R>, len_bytes: [u8; 4]) -> io::Result<Vec<u8>> {
+    let len = u32::from_be_bytes(len_bytes) as u64;
+    if len > MAX_FRAME_LEN || len > reader.limit() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Corrupted chunk"));
+    }
+    let mut frame = vec![0u8; len as usize];
+    reader.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::CIPHER_AES_256_GCM;
+    use crate::format::ContainerHeader;
+    use aes_gcm::aead::Aead;
+
+    #[test]
+    fn test_batches_split_and_end_cleanly() {
+        let data: Vec<u8> = (0..10u8).collect();
+        let mut reader = data.as_slice();
+        let progress = ProgressSink::none();
+        let first = read_plain_batch(&mut reader, 3, 2, &progress, 0).unwrap();
+        assert_eq!(first, vec![vec![0, 1, 2], vec![3, 4, 5]]);
+        assert!(first[0].capacity() >= 3 + TAG_SIZE);
+        let second = read_plain_batch(&mut reader, 3, 2, &progress, 2).unwrap();
+        assert_eq!(second, vec![vec![6, 7, 8], vec![9]]);
+        assert!(read_plain_batch(&mut reader, 3, 2, &progress, 4).unwrap().is_empty());
+
+        let mut framed = vec![1; NONCE_SIZE];
+        framed.extend_from_slice(&2u32.to_be_bytes());
+        framed.extend_from_slice(&[7, 7]);
+        let body = |framed: &[u8]| ContainerReader::new(io::Cursor::new(framed.to_vec()).take(u64::MAX), &ContainerHeader::default());
+        let frames = read_frame_batch(&mut body(&framed), &mut NonceSequence::Random, 4, &progress, 0).unwrap();
+        assert_eq!(frames, vec![([1; NONCE_SIZE], vec![7, 7])]);
+
+        // A truncated frame is an error rather than a quiet end of file,
+        // wherever in the frame the body stops.
+        framed.extend_from_slice(&[1; NONCE_SIZE]);
+        assert!(read_frame_batch(&mut body(&framed), &mut NonceSequence::Random, 4, &progress, 0).is_err());
+        framed.extend_from_slice(&8u32.to_be_bytes());
+        framed.extend_from_slice(&[0; 4]);
+        assert!(read_frame_batch(&mut body(&framed), &mut NonceSequence::Random, 4, &progress, 0).is_err());
+
+        // Lengths past the end of the body or the largest frame are turned
+        // down before anything is allocated for them.
+        let mut reader = [0u8; 64].as_slice().take(32);
+        assert_eq!(read_frame(&mut reader, 33u32.to_be_bytes()).unwrap_err().to_string(), "Corrupted chunk");
+        assert!(read_frame(&mut [0u8; 0].as_slice().take(u64::MAX), u32::MAX.to_be_bytes()).is_err());
+        assert_eq!(read_frame(&mut reader, 32u32.to_be_bytes()).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn test_pipelined_frames_arrive_in_order() {
+        let content: Vec<u8> = (0..700 * 1024u32).map(|i| (i % 253) as u8).collect();
+        let cipher = BodyCipher::new(CIPHER_AES_256_GCM, &[3; 32]).unwrap();
+        let mut nonce_seq = NonceSequence::Counter { prefix: [2; 8], next: 0 };
+        let mut hasher = ContentHasher::new(content.len() as u64);
+        let mut frames = Vec::new();
+        let progress = ProgressSink::none();
+        encrypt_pipelined(&mut content.as_slice(), content.len(), 64 * 1024, 3, &cipher, &mut nonce_seq, &mut hasher, &progress, |index, nonce, encrypted| {
+            assert_eq!(index, frames.len());
+            frames.push(cipher.decrypt(Nonce::from_slice(nonce), encrypted).unwrap());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(frames.len(), content.len().div_ceil(64 * 1024));
+        assert_eq!(frames.concat(), content);
+        let mut expected = ContentHasher::new(content.len() as u64);
+        expected.update(&content);
+        assert_eq!(hasher.finalize(), expected.finalize());
+
+        // A failing writer or reader stops every stage instead of leaving
+        // one blocked on its channel.
+        let mut hasher = ContentHasher::new(content.len() as u64);
+        let mut written = 0;
+        let result = encrypt_pipelined(&mut content.as_slice(), content.len(), 64 * 1024, 2, &cipher, &mut nonce_seq, &mut hasher, &progress, |_, _, _| {
+            written += 1;
+            if written == 3 { Err(io::Error::other("disk gone")) } else { Ok(()) }
+        });
+        assert_eq!(result.unwrap_err().to_string(), "disk gone");
+        let mut failing = FailAfter(200 * 1024);
+        let result = encrypt_pipelined(&mut failing, content.len(), 64 * 1024, 2, &cipher, &mut nonce_seq, &mut hasher, &progress, |_, _, _| Ok(()));
+        assert_eq!(result.unwrap_err().to_string(), "read failed");
+    }
+
+    #[test]
+    fn test_opened_chunks_are_written_in_order() {
+        let cipher = BodyCipher::new(CIPHER_AES_256_GCM, &[3; 32]).unwrap();
+        let mut batch: Vec<Frame> = (0..16u8)
+            .map(|i| {
+                let nonce_bytes = [i; NONCE_SIZE];
+                (nonce_bytes, cipher.encrypt(Nonce::from_slice(&nonce_bytes), vec![i; 1000 * (16 - i as usize)].as_slice()).unwrap())
+            })
+            .collect();
+        let progress = ProgressSink::none();
+        let mut written = Vec::new();
+        open_batch_ordered(batch.clone(), 32, &cipher, &progress, |index, chunk| {
+            written.push((index, chunk.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(written, (0..16u8).map(|i| (32 + i as usize, vec![i; 1000 * (16 - i as usize)])).collect::<Vec<_>>());
+
+        // Nothing from the damaged chunk onwards reaches the writer.
+        batch[5].1[0] ^= 1;
+        let mut written = 0;
+        let result = open_batch_ordered(batch, 0, &cipher, &progress, |_, _| {
+            written += 1;
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "Decryption failed");
+        assert_eq!(written, 5);
+    }
+
+    struct FailAfter(usize);
+
+    impl Read for FailAfter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::other("read failed"));
+            }
+            let n = buf.len().min(self.0);
+            self.0 -= n;
+            Ok(n)
+        }
+    }
+}
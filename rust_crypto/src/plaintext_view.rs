@@ -0,0 +1,137 @@
+use memmap2::{Mmap, MmapOptions};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::format::read_layout;
+use crate::progress::ProgressSink;
+use crate::secure_temp::{SecureTempFile, TempContents};
+use crate::sparse;
+use crate::{decrypt_file_to_memory_internal, decrypt_into, errors, TAG_SIZE};
+
+// Plaintext at most this large is decrypted into the heap; anything bigger
+// goes to a secure temp file that is mapped instead.
+const DESKTOP_MEMORY_LIMIT: u64 = 256 * 1024 * 1024;
+const MOBILE_MEMORY_LIMIT: u64 = 64 * 1024 * 1024;
+
+pub enum KyriePlaintextView {
+    Memory(Zeroizing<Vec<u8>>),
+    // The map is declared first so it is unmapped before the temp file is
+    // shredded.
+    Mapped { map: Mmap, _temp: SecureTempFile },
+}
+
+impl KyriePlaintextView {
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            KyriePlaintextView::Memory(data) => data,
+            KyriePlaintextView::Mapped { map, .. } => map,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_to_mmap(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    data_ptr: *mut *const u8,
+    data_len: *mut usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> *mut KyriePlaintextView {
+    if data_ptr.is_null() || data_len.is_null() {
+        errors::invalid_argument();
+        return std::ptr::null_mut();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                errors::invalid_argument();
+                return std::ptr::null_mut();
+            }
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let limit = if is_mobile { MOBILE_MEMORY_LIMIT } else { DESKTOP_MEMORY_LIMIT };
+        match decrypt_to_view(Path::new(input_path), password, limit, is_mobile, cpu_cores) {
+            Ok(view) => {
+                let data = view.as_slice();
+                *data_ptr = data.as_ptr();
+                *data_len = data.len();
+                Box::into_raw(Box::new(view))
+            }
+            Err(e) => {
+                errors::fail(e.as_ref());
+                std::ptr::null_mut()
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_plaintext_view_free(view: *mut KyriePlaintextView) {
+    if !view.is_null() {
+        drop(unsafe { Box::from_raw(view) });
+    }
+}
+
+pub fn decrypt_to_view(
+    input_path: &Path,
+    password: &[u8],
+    memory_limit: u64,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<KyriePlaintextView, Box<dyn std::error::Error>> {
+    let path = input_path.to_str().ok_or("Invalid path")?;
+    // An upper bound: padding is only stripped while decrypting, and holes
+    // come back as zeros.
+    let layout = read_layout(input_path)?;
+    let padded_len = layout.frames.iter().map(|f| f.len.saturating_sub(TAG_SIZE as u64)).sum::<u64>() + sparse::hole_bytes(&layout.holes);
+    if padded_len <= memory_limit {
+        let data = decrypt_file_to_memory_internal(path, password, is_mobile, cpu_cores)?;
+        return Ok(KyriePlaintextView::Memory(Zeroizing::new(data)));
+    }
+
+    let mut temp = SecureTempFile::for_target(input_path, TempContents::Plaintext)?;
+    let (header, key) = decrypt_into(input_path, temp.file(), password, is_mobile, cpu_cores, &ProgressSink::none())?;
+    header.verify_file_id(&key)?;
+    temp.file().sync_all()?;
+    // A private read-only mapping: writes to the file by others are not
+    // guaranteed to show through, and the caller cannot modify the pages.
+    let map = unsafe { MmapOptions::new().map_copy_read_only(&*temp.file())? };
+    Ok(KyriePlaintextView::Mapped { map, _temp: temp })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+    use std::fs;
+
+    #[test]
+    fn test_large_plaintext_is_mapped_from_a_temp_file() {
+        let _mode = crate::secure_temp::PLAINTEXT_TEMP_MODE.read().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.bin");
+        let encrypted = dir.path().join("plain.kyl");
+        let content: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        fs::write(&plain, &content).unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+
+        let view = decrypt_to_view(&encrypted, b"pw", u64::MAX, false, 4).unwrap();
+        assert!(matches!(view, KyriePlaintextView::Memory(_)));
+        assert_eq!(view.as_slice(), content);
+
+        let view = decrypt_to_view(&encrypted, b"pw", 0, false, 4).unwrap();
+        assert!(matches!(view, KyriePlaintextView::Mapped { .. }));
+        assert_eq!(view.as_slice(), content);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+        drop(view);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert!(decrypt_to_view(&encrypted, b"wrong", 0, false, 4).is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,157 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::slice;
+use zeroize::Zeroizing;
+
+use crate::cipher::BodyCipher;
+use crate::format::{read_header, ContainerHeader};
+use crate::kdf::{self, KeyPurpose, KeyProvider};
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::{encrypt_file_reporting, errors, random_nonce, EncryptOptions, NONCE_SIZE, TAG_SIZE};
+
+// Thumbnails share the header's field area with the checksum index, so they
+// are kept small. A buffer of MAX_PREVIEW_LEN always holds what get_preview
+// returns.
+pub const MAX_PREVIEW_LEN: usize = 16 * 1024;
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_with_preview(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    preview_ptr: *const u8,
+    preview_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if preview_ptr.is_null() || preview_len > MAX_PREVIEW_LEN {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+        let options = EncryptOptions {
+            hint,
+            padding: OptionsProfile::current().padding,
+            preview: Some(slice::from_raw_parts(preview_ptr, preview_len)),
+            ..Default::default()
+        };
+
+        match encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &ProgressSink::none()) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Opens only the header, so listing an album costs one key derivation per
+// file and no body reads.
+#[no_mangle]
+pub extern "C" fn get_preview(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    preview_ptr: *mut u8,
+    preview_len: *mut usize,
+) -> i32 {
+    if preview_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        match get_preview_internal(Path::new(path), password) {
+            Ok(preview) => {
+                *preview_len = preview.len();
+                if !preview_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(preview.as_ptr(), preview_ptr, preview.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn get_preview_internal<K: KeyProvider + ?Sized>(
+    path: &Path,
+    keys: &K,
+) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    open(&header, &header.master_key(keys)?)?.ok_or_else(|| "File has no preview".into())
+}
+
+// Sealed like the metadata, under a subkey of its own.
+pub fn seal(header: &ContainerHeader, master: &[u8; 32], preview: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if preview.len() > MAX_PREVIEW_LEN {
+        return Err("Preview too large".into());
+    }
+    let cipher = BodyCipher::new(header.cipher, &kdf::subkey(master, KeyPurpose::Preview))?;
+    let nonce = random_nonce();
+    let sealed = cipher.encrypt(Nonce::from_slice(&nonce), preview).map_err(|_| "Encryption failed")?;
+    Ok([&nonce[..], &sealed].concat())
+}
+
+pub fn open(header: &ContainerHeader, master: &[u8; 32]) -> Result<Option<Zeroizing<Vec<u8>>>, Box<dyn std::error::Error>> {
+    if header.preview.is_empty() {
+        return Ok(None);
+    }
+    if header.preview.len() < NONCE_SIZE + TAG_SIZE {
+        return Err("Invalid header field".into());
+    }
+    let (nonce, sealed) = header.preview.split_at(NONCE_SIZE);
+    let cipher = BodyCipher::new(header.cipher, &kdf::subkey(master, KeyPurpose::Preview))?;
+    let preview = cipher.decrypt(Nonce::from_slice(nonce), sealed).map_err(|_| "Decryption failed")?;
+    Ok(Some(Zeroizing::new(preview)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, encrypt_file_internal};
+    use std::fs;
+
+    #[test]
+    fn test_preview_opens_without_the_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("video.mp4");
+        let encrypted = dir.path().join("video.kyl");
+        let decrypted = dir.path().join("video.out");
+        fs::write(&plain, vec![7u8; 2 * 1024 * 1024]).unwrap();
+        let thumbnail: Vec<u8> = (0..5000u32).map(|i| (i % 256) as u8).collect();
+
+        let options = EncryptOptions { preview: Some(&thumbnail), ..Default::default() };
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+        encrypt_file_reporting(path(&plain), path(&encrypted), &b"pw"[..], &options, false, 4, &ProgressSink::none()).unwrap();
+        assert_eq!(*get_preview_internal(&encrypted, &b"pw"[..]).unwrap(), thumbnail);
+        assert!(get_preview_internal(&encrypted, &b"wrong"[..]).is_err());
+        decrypt_file_internal(&path(&encrypted), &path(&decrypted), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), fs::read(&plain).unwrap());
+
+        encrypt_file_internal(&path(&plain), &path(&encrypted), b"pw", None, false, 4).unwrap();
+        assert!(get_preview_internal(&encrypted, &b"pw"[..]).is_err());
+        let options = EncryptOptions { preview: Some(&[0; MAX_PREVIEW_LEN + 1]), ..Default::default() };
+        assert!(encrypt_file_reporting(path(&plain), path(&encrypted), &b"pw"[..], &options, false, 4, &ProgressSink::none()).is_err());
+    }
+}
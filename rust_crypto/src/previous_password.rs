@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::format::{encode_kdf_params, parse_kdf_params};
+use crate::kdf::{self, salted_subkey, KdfParams, KeyPurpose, KDF_SALT_SIZE};
+use crate::rng;
+use crate::secure_temp::{SecureTempFile, TempContents};
+
+const VERIFIER_FILE_NAME: &str = ".kyrie_previous_password";
+
+// Lets devices that still hold the pre-rekey password get a clear error.
+// The verifier allows offline guessing of the old password, so it is only
+// written on request, costs a full KDF run per guess, and is deleted once
+// it expires.
+#[derive(Serialize, Deserialize)]
+struct PreviousPasswordVerifier {
+    salt: String,
+    // Verifiers written without it used the unsalted legacy key.
+    #[serde(default)]
+    kdf: String,
+    verifier: String,
+    expires_at: u64,
+}
+
+pub fn record(vault_dir: &Path, old_password: &[u8], expires_at: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let salt: [u8; KDF_SALT_SIZE] = rng::random();
+    let params = KdfParams::for_new_files(false);
+    let record = PreviousPasswordVerifier {
+        salt: hex::encode(salt),
+        kdf: hex::encode(encode_kdf_params(&params)),
+        verifier: hex::encode(verifier(old_password, &salt, &params)?),
+        expires_at,
+    };
+    let path = vault_dir.join(VERIFIER_FILE_NAME);
+    let mut temp = SecureTempFile::for_target(&path, TempContents::Metadata)?;
+    temp.file().write_all(&serde_json::to_vec(&record)?)?;
+    temp.persist(&path)?;
+    Ok(())
+}
+
+pub fn clear(vault_dir: &Path) -> io::Result<()> {
+    match fs::remove_file(vault_dir.join(VERIFIER_FILE_NAME)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Called after a password failed to open the vault; errors only when it is
+// the previous password and the grace period is still running.
+pub fn check(vault_dir: &Path, password: &[u8], now: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let Ok(data) = fs::read(vault_dir.join(VERIFIER_FILE_NAME)) else {
+        return Ok(());
+    };
+    let Ok(record) = serde_json::from_slice::<PreviousPasswordVerifier>(&data) else {
+        return Ok(());
+    };
+    // A legacy verifier is as cheap to guess against as a plain hash, so it
+    // is dropped rather than checked.
+    if now >= record.expires_at || record.kdf.is_empty() {
+        let _ = clear(vault_dir);
+        return Ok(());
+    }
+    let salt = hex::decode(&record.salt)?;
+    let params = parse_kdf_params(&hex::decode(&record.kdf)?)?;
+    if hex::encode(verifier(password, &salt, &params)?) == record.verifier {
+        return Err("Password expired: vault was rekeyed".into());
+    }
+    Ok(())
+}
+
+fn verifier(password: &[u8], salt: &[u8], params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let key = Zeroizing::new(kdf::derive(password, salt, params)?);
+    Ok(salted_subkey(&key, salt, KeyPurpose::PreviousPassword))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verifier_matches_only_until_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check(dir.path(), b"old", 100).is_ok());
+
+        record(dir.path(), b"old", 200).unwrap();
+        assert_eq!(check(dir.path(), b"old", 100).unwrap_err().to_string(), "Password expired: vault was rekeyed");
+        assert!(check(dir.path(), b"other", 100).is_ok());
+
+        assert!(check(dir.path(), b"old", 200).is_ok());
+        assert!(!dir.path().join(VERIFIER_FILE_NAME).exists());
+        clear(dir.path()).unwrap();
+
+        let legacy = r#"{"salt":"00","verifier":"00","expires_at":200}"#;
+        fs::write(dir.path().join(VERIFIER_FILE_NAME), legacy).unwrap();
+        assert!(check(dir.path(), b"old", 100).is_ok());
+        assert!(!dir.path().join(VERIFIER_FILE_NAME).exists());
+    }
+}
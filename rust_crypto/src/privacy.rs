@@ -0,0 +1,287 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::slice;
+use zeroize::Zeroizing;
+
+use crate::cipher::{BodyCipher, CIPHER_AES_256_GCM};
+use crate::format::{read_header, ContainerHeader, PrivateHint};
+use crate::kdf::{self, KdfParams, KeyProvider, KDF_SALT_SIZE};
+use crate::secure_temp::{SecureTempFile, TempContents};
+use crate::{errors, kyrie_core, random_nonce, read_chunk, rng, text, MAGIC_STRING, MAX_HINT_LENGTH, NONCE_SIZE};
+
+pub const STEALTH_SALT_SIZE: usize = 16;
+
+// Published in the format spec next to the raw layout.
+pub const STEALTH_LAYOUT: &str =
+    "container XOR BLAKE3 keyed XOF(key, \"KYRIE_LOCK stealth mask\") || salt(16); key = the file's password KDF with fixed Argon2id parameters (32 MiB, 3 iterations, 2 lanes) over the salt";
+
+const MASK_CONTEXT: &[u8] = b"KYRIE_LOCK stealth mask";
+const MASK_BLOCK: usize = 1024 * 1024;
+
+// Encrypts with the hint kept at one length, sealed under `hint_password`
+// when it is not null, and with `omit_magic` the whole file masked so that
+// nothing in it tells it from random bytes. decrypt_file opens masked files
+// with the right password; with a wrong one they are only "not a valid
+// encrypted file", as any random file would be.
+#[no_mangle]
+pub extern "C" fn encrypt_file_private(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    hint_password_ptr: *const u8,
+    hint_password_len: usize,
+    omit_magic: bool,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+        let hint_password = if hint_password_ptr.is_null() {
+            None
+        } else {
+            Some(slice::from_raw_parts(hint_password_ptr, hint_password_len))
+        };
+
+        let encryptor = kyrie_core::Encryptor::new(password)
+            .with_hint(hint)
+            .with_private_hint(hint_password)
+            .without_magic(omit_magic)
+            .for_mobile(is_mobile)
+            .with_cpu_cores(cpu_cores);
+        match encryptor.encrypt_file(Path::new(input_path), Path::new(output_path)) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(&e),
+        }
+    }
+}
+
+// Opens a hint sealed under a hint password. Hints that are only padded
+// come back whatever the password.
+#[no_mangle]
+pub extern "C" fn get_private_hint(
+    path_ptr: *const c_char,
+    hint_password_ptr: *const u8,
+    hint_password_len: usize,
+    hint_ptr: *mut u8,
+    hint_len: *mut usize,
+) -> i32 {
+    if hint_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let hint_password = slice::from_raw_parts(hint_password_ptr, hint_password_len);
+
+        match read_header(Path::new(path)).and_then(|(header, _)| open_hint(&header, hint_password)) {
+            Ok(hint) => {
+                *hint_len = hint.len();
+                if !hint_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(hint.as_ptr(), hint_ptr, hint.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Moves the hint out of the fixed slot, cut to its size so that every
+// private hint takes the same room.
+pub fn protect_hint(header: &mut ContainerHeader, hint_password: Option<&[u8]>) -> Result<(), Box<dyn std::error::Error>> {
+    let hint = text::hint_prefix(&header.hint).to_vec();
+    header.private_hint = Some(match hint_password {
+        Some(hint_password) => {
+            header.hint.clear();
+            PrivateHint::Sealed(seal_hint(&hint, hint_password, &header.kdf)?)
+        }
+        None => {
+            header.hint = hint;
+            PrivateHint::Padded
+        }
+    });
+    Ok(())
+}
+
+pub fn open_hint(header: &ContainerHeader, hint_password: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let Some(PrivateHint::Sealed(sealed)) = &header.private_hint else {
+        return Ok(header.hint.clone());
+    };
+    let (salt, rest) = sealed.split_at(KDF_SALT_SIZE);
+    let (nonce, sealed) = rest.split_at(NONCE_SIZE);
+    let key = Zeroizing::new(kdf::derive(hint_password, salt, &header.kdf)?);
+    let padded = BodyCipher::new(CIPHER_AES_256_GCM, &key)?
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map_err(|_| "Decryption failed")?;
+    match padded.split_first() {
+        Some((&len, hint)) if len as usize <= hint.len() => Ok(hint[..len as usize].to_vec()),
+        _ => Err("Invalid header field".into()),
+    }
+}
+
+fn seal_hint(hint: &[u8], hint_password: &[u8], params: &KdfParams) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if *params == KdfParams::LegacySha256 {
+        return Err("Private mode needs a password".into());
+    }
+    let mut padded = Zeroizing::new(vec![hint.len() as u8]);
+    padded.extend_from_slice(hint);
+    padded.resize(1 + MAX_HINT_LENGTH, 0);
+
+    let salt: [u8; KDF_SALT_SIZE] = rng::random();
+    let key = Zeroizing::new(kdf::derive(hint_password, &salt, params)?);
+    let nonce = random_nonce();
+    let sealed = BodyCipher::new(CIPHER_AES_256_GCM, &key)?
+        .encrypt(Nonce::from_slice(&nonce), padded.as_slice())
+        .map_err(|_| "Encryption failed")?;
+    Ok([&salt[..], &nonce, &sealed].concat())
+}
+
+// Nothing in a masked file records the parameters, so they never change.
+fn mask_kdf() -> KdfParams {
+    if cfg!(test) {
+        KdfParams::Argon2id { memory_kib: 64, iterations: 1, lanes: 1 }
+    } else {
+        KdfParams::Argon2id { memory_kib: 32 * 1024, iterations: 3, lanes: 2 }
+    }
+}
+
+fn mask_stream(key: &[u8; 32]) -> blake3::OutputReader {
+    blake3::Hasher::new_keyed(key).update(MASK_CONTEXT).finalize_xof()
+}
+
+fn apply_mask(stream: &mut blake3::OutputReader, data: &mut [u8]) {
+    let mut mask = Zeroizing::new(vec![0u8; data.len()]);
+    stream.fill(&mut mask);
+    data.iter_mut().zip(mask.iter()).for_each(|(byte, mask)| *byte ^= mask);
+}
+
+// Masks a finished container in place and appends the salt.
+pub fn conceal<K: KeyProvider + ?Sized>(file: &mut File, keys: &K) -> Result<(), Box<dyn std::error::Error>> {
+    if !keys.derives_from_password() {
+        return Err("Private mode needs a password".into());
+    }
+    let salt: [u8; STEALTH_SALT_SIZE] = rng::random();
+    let mut stream = mask_stream(&Zeroizing::new(keys.key_for(&salt, &mask_kdf())?));
+    let mut block = vec![0u8; MASK_BLOCK];
+    let mut offset = 0;
+    file.seek(SeekFrom::Start(0))?;
+    loop {
+        let n = read_chunk(file, &mut block)?;
+        if n == 0 {
+            break;
+        }
+        apply_mask(&mut stream, &mut block[..n]);
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&block[..n])?;
+        offset += n as u64;
+    }
+    file.write_all(&salt)?;
+    Ok(())
+}
+
+// Unmasks into a temp file when `input_path` is a masked container these
+// keys open. Anything else is left to the normal path to accept or reject.
+pub fn reveal<K: KeyProvider + ?Sized>(input_path: &Path, keys: &K) -> Result<Option<SecureTempFile>, Box<dyn std::error::Error>> {
+    let mut file = File::open(input_path)?;
+    let len = file.metadata()?.len();
+    let mut start = [0u8; MAGIC_STRING.len()];
+    if !keys.derives_from_password() || len < (start.len() + STEALTH_SALT_SIZE) as u64 || file.read_exact(&mut start).is_err() {
+        return Ok(None);
+    }
+    if start == MAGIC_STRING {
+        return Ok(None);
+    }
+    let mut salt = [0u8; STEALTH_SALT_SIZE];
+    file.seek(SeekFrom::End(-(STEALTH_SALT_SIZE as i64)))?;
+    file.read_exact(&mut salt)?;
+    let key = Zeroizing::new(keys.key_for(&salt, &mask_kdf())?);
+    apply_mask(&mut mask_stream(&key), &mut start);
+    if start != MAGIC_STRING {
+        return Ok(None);
+    }
+
+    let mut temp = SecureTempFile::for_target(input_path, TempContents::Ciphertext)?;
+    let mut stream = mask_stream(&key);
+    file.seek(SeekFrom::Start(0))?;
+    let mut masked = file.take(len - STEALTH_SALT_SIZE as u64);
+    let mut block = vec![0u8; MASK_BLOCK];
+    loop {
+        let n = read_chunk(&mut masked, &mut block)?;
+        if n == 0 {
+            break;
+        }
+        apply_mask(&mut stream, &mut block[..n]);
+        temp.file().write_all(&block[..n])?;
+    }
+    Ok(Some(temp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_internal, get_hint_from_file_internal};
+    use std::fs;
+
+    #[test]
+    fn test_private_hints_and_masked_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("diary.txt");
+        fs::write(&plain, b"dear diary").unwrap();
+        let encrypt = |name: &str, hint: &str, hint_password: Option<&[u8]>, omit_magic: bool| {
+            let output = dir.path().join(name);
+            kyrie_core::Encryptor::new(b"pw")
+                .with_hint(Some(hint))
+                .with_private_hint(hint_password)
+                .without_magic(omit_magic)
+                .encrypt_file(&plain, &output)
+                .unwrap();
+            output
+        };
+
+        // Padded hints read as before, and take the same room at any length.
+        let short = encrypt("short.kyl", "cat", None, false);
+        let long = encrypt("long.kyl", "our first flat, the one on the hill", None, false);
+        assert_eq!(get_hint_from_file_internal(short.to_str().unwrap()).unwrap(), b"cat");
+        assert_eq!(read_header(&short).unwrap().1, read_header(&long).unwrap().1);
+
+        let sealed = encrypt("sealed.kyl", "wife's birthday", Some(b"hint pw"), false);
+        let (header, _) = read_header(&sealed).unwrap();
+        assert!(header.hint.is_empty());
+        assert!(!fs::read(&sealed).unwrap().windows(8).any(|w| w == b"birthday"));
+        assert_eq!(open_hint(&header, b"hint pw").unwrap(), b"wife's birthday");
+        let wrong = open_hint(&header, b"guess").unwrap_err();
+        assert_eq!(errors::classify(wrong.as_ref()), errors::ERR_WRONG_PASSWORD);
+
+        let masked = encrypt("masked.kyl", "cat", Some(b"hint pw"), true);
+        let bytes = fs::read(&masked).unwrap();
+        assert!(!bytes.windows(MAGIC_STRING.len()).any(|w| w == MAGIC_STRING));
+        let out = dir.path().join("masked.out");
+        decrypt_file_internal(masked.to_str().unwrap(), out.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&out).unwrap(), b"dear diary");
+        let error = decrypt_file_internal(masked.to_str().unwrap(), dir.path().join("x.out").to_str().unwrap(), b"no", false, 4).unwrap_err();
+        assert_eq!(errors::classify(error.as_ref()), errors::ERR_INVALID_FORMAT);
+        // Only the output is left behind.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 6);
+    }
+}
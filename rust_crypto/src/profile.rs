@@ -0,0 +1,215 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+use crate::get_chunk_size;
+use crate::naming::NamingPolicy;
+use crate::padding::{self, PADDING_NONE};
+use crate::shred::{ShredOptions, SHRED_STRATEGY_FLASH, SHRED_STRATEGY_SINGLE_PASS};
+
+const PROFILE_VERSION: u32 = 1;
+const CIPHER_AES_256_GCM: &str = "aes-256-gcm";
+const KDF_LEGACY_SHA256: &str = "legacy-sha256";
+const COMPRESSION_NONE: &str = "none";
+const KEY_SIZE: usize = 32;
+
+static ACTIVE_PROFILE: Mutex<Option<OptionsProfile>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OptionsProfile {
+    pub version: u32,
+    pub cipher: String,
+    pub kdf: String,
+    pub chunk_size_mobile: usize,
+    pub chunk_size_desktop: usize,
+    pub compression: String,
+    pub padding: u8,
+    pub shred_strategy: i32,
+    pub shred_verify: bool,
+    pub naming: NamingPolicy,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignedProfile {
+    pub profile: OptionsProfile,
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl Default for OptionsProfile {
+    fn default() -> Self {
+        OptionsProfile {
+            version: PROFILE_VERSION,
+            cipher: CIPHER_AES_256_GCM.to_string(),
+            kdf: KDF_LEGACY_SHA256.to_string(),
+            chunk_size_mobile: get_chunk_size(true),
+            chunk_size_desktop: get_chunk_size(false),
+            compression: COMPRESSION_NONE.to_string(),
+            padding: PADDING_NONE,
+            shred_strategy: SHRED_STRATEGY_SINGLE_PASS,
+            shred_verify: false,
+            naming: NamingPolicy::default(),
+        }
+    }
+}
+
+impl OptionsProfile {
+    pub fn current() -> Self {
+        let mut profile = ACTIVE_PROFILE
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .unwrap_or_default();
+        // The naming policy can still be changed on its own after a profile is
+        // applied, so always report the one actually in effect.
+        profile.naming = NamingPolicy::current();
+        profile
+    }
+
+    pub fn shred_options(&self) -> ShredOptions {
+        ShredOptions {
+            strategy: self.shred_strategy,
+            verify: self.shred_verify,
+        }
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let defaults = OptionsProfile::default();
+        if self.version != PROFILE_VERSION {
+            return Err("Unsupported profile version".into());
+        }
+        if self.cipher != defaults.cipher || self.kdf != defaults.kdf || self.compression != defaults.compression {
+            return Err("Profile requires an algorithm this build does not support".into());
+        }
+        if self.chunk_size_mobile != defaults.chunk_size_mobile || self.chunk_size_desktop != defaults.chunk_size_desktop {
+            return Err("Profile chunk sizes are incompatible with this build".into());
+        }
+        if !padding::is_valid_scheme(self.padding)
+            || !(SHRED_STRATEGY_SINGLE_PASS..=SHRED_STRATEGY_FLASH).contains(&self.shred_strategy)
+        {
+            return Err("Invalid profile options".into());
+        }
+        Ok(())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_profile_export(signing_key_ptr: *const u8, json_ptr: *mut u8, json_len: *mut usize) -> i32 {
+    unsafe {
+        if signing_key_ptr.is_null() {
+            return -1;
+        }
+        let mut seed = [0u8; KEY_SIZE];
+        seed.copy_from_slice(std::slice::from_raw_parts(signing_key_ptr, KEY_SIZE));
+
+        match export_profile(&seed) {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_profile_apply(json_ptr: *const u8, json_len: usize, trusted_key_ptr: *const u8) -> i32 {
+    unsafe {
+        if json_ptr.is_null() || trusted_key_ptr.is_null() {
+            return -1;
+        }
+        let json = std::slice::from_raw_parts(json_ptr, json_len);
+        let mut trusted_key = [0u8; KEY_SIZE];
+        trusted_key.copy_from_slice(std::slice::from_raw_parts(trusted_key_ptr, KEY_SIZE));
+
+        match apply_profile(json, &trusted_key) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn export_profile(signing_key: &[u8; KEY_SIZE]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let signing_key = SigningKey::from_bytes(signing_key);
+    let profile = OptionsProfile::current();
+    let signature = signing_key.sign(&serde_json::to_vec(&profile)?);
+    let signed = SignedProfile {
+        profile,
+        public_key: hex::encode(signing_key.verifying_key().to_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    };
+    Ok(serde_json::to_vec_pretty(&signed)?)
+}
+
+pub fn apply_profile(json: &[u8], trusted_key: &[u8; KEY_SIZE]) -> Result<(), Box<dyn std::error::Error>> {
+    let profile = verify_profile(json, trusted_key)?;
+    profile.naming.clone().install()?;
+    *ACTIVE_PROFILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(profile);
+    Ok(())
+}
+
+pub fn verify_profile(json: &[u8], trusted_key: &[u8; KEY_SIZE]) -> Result<OptionsProfile, Box<dyn std::error::Error>> {
+    let signed: SignedProfile = serde_json::from_slice(json)?;
+    if hex::decode(&signed.public_key)? != trusted_key {
+        return Err("Profile was not signed by the trusted key".into());
+    }
+    let verifying_key = VerifyingKey::from_bytes(trusted_key)?;
+    let signature = Signature::from_slice(&hex::decode(&signed.signature)?)?;
+    verifying_key
+        .verify(&serde_json::to_vec(&signed.profile)?, &signature)
+        .map_err(|_| "Profile signature is invalid")?;
+
+    signed.profile.validate()?;
+    Ok(signed.profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::padding::PADDING_PADME;
+
+    fn sign(profile: OptionsProfile, key: &[u8; KEY_SIZE]) -> Vec<u8> {
+        let signing_key = SigningKey::from_bytes(key);
+        let signed = SignedProfile {
+            signature: hex::encode(signing_key.sign(&serde_json::to_vec(&profile).unwrap()).to_bytes()),
+            public_key: hex::encode(signing_key.verifying_key().to_bytes()),
+            profile,
+        };
+        serde_json::to_vec(&signed).unwrap()
+    }
+
+    #[test]
+    fn test_signed_profile_round_trip() {
+        let org_key = [7u8; KEY_SIZE];
+        let trusted = SigningKey::from_bytes(&org_key).verifying_key().to_bytes();
+        let exported = export_profile(&org_key).unwrap();
+        assert_eq!(verify_profile(&exported, &trusted).unwrap().cipher, CIPHER_AES_256_GCM);
+
+        let profile = OptionsProfile {
+            padding: PADDING_PADME,
+            shred_verify: true,
+            naming: NamingPolicy {
+                extension: "vault".to_string(),
+                ..NamingPolicy::default()
+            },
+            ..OptionsProfile::default()
+        };
+        let json = sign(profile.clone(), &org_key);
+        assert_eq!(verify_profile(&json, &trusted).unwrap(), profile);
+        assert!(verify_profile(&json, &[9u8; KEY_SIZE]).is_err());
+        assert!(verify_profile(&sign(profile.clone(), &[9u8; KEY_SIZE]), &trusted).is_err());
+
+        let mut tampered: SignedProfile = serde_json::from_slice(&json).unwrap();
+        tampered.profile.shred_verify = false;
+        assert!(verify_profile(&serde_json::to_vec(&tampered).unwrap(), &trusted).is_err());
+
+        let unsupported = OptionsProfile {
+            cipher: "chacha20-poly1305".to_string(),
+            ..profile
+        };
+        assert!(verify_profile(&sign(unsupported, &org_key), &trusted).is_err());
+    }
+}
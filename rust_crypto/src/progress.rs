@@ -0,0 +1,377 @@
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::diagnostics::ChunkRecorder;
+
+use crate::handles::HandleSet;
+use crate::{decrypt_file_reporting, encrypt_file_reporting, EncryptOptions};
+
+pub const PROGRESS_PHASE_READING: i32 = 0;
+pub const PROGRESS_PHASE_DERIVING_KEY: i32 = 1;
+pub const PROGRESS_PHASE_ENCRYPTING: i32 = 2;
+pub const PROGRESS_PHASE_DECRYPTING: i32 = 3;
+pub const PROGRESS_PHASE_WRITING: i32 = 4;
+
+pub type ProgressCallback = extern "C" fn(phase: i32, fraction: f64, user_data: *mut c_void);
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct KyrieProgressOptions {
+    pub min_interval_ms: u32,
+    pub min_delta: f64,
+}
+
+struct LastEvent {
+    phase: i32,
+    fraction: f64,
+    at: Instant,
+}
+
+pub struct ProgressSink {
+    callback: Option<ProgressCallback>,
+    user_data: usize,
+    min_interval: Duration,
+    min_delta: f64,
+    last: Mutex<Option<LastEvent>>,
+    handles: Option<Arc<HandleSet>>,
+    diagnostics: Option<Arc<ChunkRecorder>>,
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl ProgressSink {
+    pub fn new(callback: Option<ProgressCallback>, user_data: *mut c_void) -> Self {
+        ProgressSink {
+            callback,
+            user_data: user_data as usize,
+            min_interval: Duration::ZERO,
+            min_delta: 0.0,
+            last: Mutex::new(None),
+            handles: None,
+            diagnostics: None,
+            cancelled: None,
+        }
+    }
+
+    pub fn none() -> Self {
+        ProgressSink::new(None, std::ptr::null_mut())
+    }
+
+    pub fn coalesced(mut self, options: &KyrieProgressOptions) -> Self {
+        self.min_interval = Duration::from_millis(options.min_interval_ms as u64);
+        self.min_delta = options.min_delta.max(0.0);
+        self
+    }
+
+    // Files opened through these handles can be closed while the operation
+    // is paused at a report and reopened when it continues.
+    pub fn with_handles(mut self, handles: Arc<HandleSet>) -> Self {
+        self.handles = Some(handles);
+        self
+    }
+
+    pub fn handles(&self) -> Option<&HandleSet> {
+        self.handles.as_deref()
+    }
+
+    pub fn with_diagnostics(mut self, recorder: Arc<ChunkRecorder>) -> Self {
+        self.diagnostics = Some(recorder);
+        self
+    }
+
+    pub fn with_cancel_flag(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.cancelled = Some(cancelled);
+        self
+    }
+
+    // Called between batches; the caller cleans up whatever it wrote.
+    pub fn check_cancelled(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.cancelled {
+            Some(flag) if flag.load(Ordering::SeqCst) => Err("Operation cancelled".into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn time_read(&self, index: usize, bytes: usize, started: Instant) {
+        if let Some(recorder) = &self.diagnostics {
+            recorder.read(index, bytes, started.elapsed());
+        }
+    }
+
+    pub fn time_crypto(&self, index: usize, started: Instant) {
+        if let Some(recorder) = &self.diagnostics {
+            recorder.crypto(index, started.elapsed());
+        }
+    }
+
+    pub fn time_crypto_stage(&self, started: Instant) {
+        if let Some(recorder) = &self.diagnostics {
+            recorder.crypto_stage(started.elapsed());
+        }
+    }
+
+    pub fn time_write(&self, index: usize, started: Instant) {
+        if let Some(recorder) = &self.diagnostics {
+            recorder.write(index, started.elapsed());
+        }
+    }
+
+    pub fn report(&self, phase: i32, fraction: f64) {
+        let Some(callback) = self.callback else {
+            return;
+        };
+        let fraction = fraction.clamp(0.0, 1.0);
+        {
+            let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+            // Phase changes and completion always get through, so the UI
+            // never misses a step or stalls short of 100%.
+            if let Some(prev) = last.as_ref() {
+                let coalesce = prev.phase == phase
+                    && fraction < 1.0
+                    && (prev.at.elapsed() < self.min_interval || (fraction - prev.fraction).abs() < self.min_delta);
+                if coalesce {
+                    return;
+                }
+            }
+            *last = Some(LastEvent { phase, fraction, at: Instant::now() });
+        }
+        callback(phase, fraction, self.user_data as *mut c_void);
+    }
+
+    pub fn report_bytes(&self, phase: i32, done: usize, total: usize) {
+        let fraction = if total == 0 { 1.0 } else { done as f64 / total as f64 };
+        self.report(phase, fraction);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_with_progress(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+    callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+        let progress = ProgressSink::new(callback, user_data);
+        let options = EncryptOptions { hint, ..Default::default() };
+
+        match encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &progress) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_with_progress(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+    callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let progress = ProgressSink::new(callback, user_data);
+
+        match decrypt_file_reporting(input_path, output_path, password, is_mobile, cpu_cores, &progress) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_with_progress_options(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+    callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+    options_ptr: *const KyrieProgressOptions,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+        let progress = ProgressSink::new(callback, user_data).coalesced(&options_ptr.as_ref().copied().unwrap_or_default());
+        let options = EncryptOptions { hint, ..Default::default() };
+
+        match encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &progress) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_with_progress_options(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+    callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+    options_ptr: *const KyrieProgressOptions,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let progress = ProgressSink::new(callback, user_data).coalesced(&options_ptr.as_ref().copied().unwrap_or_default());
+
+        match decrypt_file_reporting(input_path, output_path, password, is_mobile, cpu_cores, &progress) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    extern "C" fn record(phase: i32, fraction: f64, user_data: *mut c_void) {
+        let events = unsafe { &*(user_data as *const Mutex<Vec<(i32, f64)>>) };
+        events.lock().unwrap().push((phase, fraction));
+    }
+
+    #[test]
+    fn test_phases_are_reported_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.bin");
+        let encrypted = dir.path().join("plain.kyl");
+        fs::write(&plain, vec![7u8; 4096]).unwrap();
+
+        let events = Mutex::new(Vec::new());
+        let progress = ProgressSink::new(Some(record), &events as *const _ as *mut c_void);
+        encrypt_file_reporting(
+            plain.to_str().unwrap(),
+            encrypted.to_str().unwrap(),
+            &b"pw"[..],
+            &EncryptOptions::default(),
+            false,
+            4,
+            &progress,
+        )
+        .unwrap();
+
+        let events = events.into_inner().unwrap();
+        let mut phases: Vec<i32> = events.iter().map(|(phase, _)| *phase).collect();
+        phases.dedup();
+        assert_eq!(
+            phases,
+            vec![
+                PROGRESS_PHASE_DERIVING_KEY,
+                PROGRESS_PHASE_READING,
+                PROGRESS_PHASE_ENCRYPTING,
+                PROGRESS_PHASE_WRITING,
+            ]
+        );
+        assert!(events.iter().all(|(_, fraction)| (0.0..=1.0).contains(fraction)));
+        assert_eq!(events.last(), Some(&(PROGRESS_PHASE_WRITING, 1.0)));
+
+        let events = Mutex::new(Vec::new());
+        let progress = ProgressSink::new(Some(record), &events as *const _ as *mut c_void);
+        let decrypted = dir.path().join("decrypted.bin");
+        decrypt_file_reporting(
+            encrypted.to_str().unwrap(),
+            decrypted.to_str().unwrap(),
+            &b"pw"[..],
+            false,
+            4,
+            &progress,
+        )
+        .unwrap();
+        let events = events.into_inner().unwrap();
+        assert!(events.contains(&(PROGRESS_PHASE_DECRYPTING, 1.0)));
+        assert_eq!(fs::read(&decrypted).unwrap(), vec![7u8; 4096]);
+    }
+
+    #[test]
+    fn test_coalescing_keeps_phase_changes_and_completion() {
+        let events = Mutex::new(Vec::<(i32, f64)>::new());
+        let options = KyrieProgressOptions { min_interval_ms: 0, min_delta: 0.25 };
+        let progress = ProgressSink::new(Some(record), &events as *const _ as *mut c_void).coalesced(&options);
+        for i in 0..=100 {
+            progress.report(PROGRESS_PHASE_ENCRYPTING, i as f64 / 100.0);
+        }
+        progress.report(PROGRESS_PHASE_WRITING, 0.0);
+        assert_eq!(
+            events.into_inner().unwrap(),
+            vec![
+                (PROGRESS_PHASE_ENCRYPTING, 0.0),
+                (PROGRESS_PHASE_ENCRYPTING, 0.25),
+                (PROGRESS_PHASE_ENCRYPTING, 0.5),
+                (PROGRESS_PHASE_ENCRYPTING, 0.75),
+                (PROGRESS_PHASE_ENCRYPTING, 1.0),
+                (PROGRESS_PHASE_WRITING, 0.0),
+            ]
+        );
+
+        let events = Mutex::new(Vec::<(i32, f64)>::new());
+        let options = KyrieProgressOptions { min_interval_ms: 60_000, min_delta: 0.0 };
+        let progress = ProgressSink::new(Some(record), &events as *const _ as *mut c_void).coalesced(&options);
+        for i in 0..=10 {
+            progress.report(PROGRESS_PHASE_DECRYPTING, i as f64 / 10.0);
+        }
+        assert_eq!(events.into_inner().unwrap(), vec![(PROGRESS_PHASE_DECRYPTING, 0.0), (PROGRESS_PHASE_DECRYPTING, 1.0)]);
+    }
+}
@@ -0,0 +1,353 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::errors;
+use crate::header_backup;
+use crate::format::{read_header, ContainerHeader, KeyShareSlot, SharePolicy, SEALED_SHARE_SIZE};
+use crate::kdf::{self, KdfParams, KeyProvider, KeyPurpose};
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::rng;
+use crate::secure_temp::{SecureTempFile, TempContents};
+use crate::{decrypt_file_reporting, derive_key, encrypt_file_reporting, shamir, write_header, EncryptOptions};
+
+// The random file key of a share-protected container. It stands in for the
+// password key everywhere the body is encrypted or decrypted.
+pub struct FileKey(pub(crate) Zeroizing<[u8; 32]>);
+
+impl KeyProvider for FileKey {
+    fn key_for(&self, _salt: &[u8], _params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        Ok(*self.0)
+    }
+
+    fn derives_from_password(&self) -> bool {
+        false
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_with_shares(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    passwords_ptr: *const *const u8,
+    password_lens: *const usize,
+    password_count: usize,
+    threshold: u8,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let passwords = match password_list(passwords_ptr, password_lens, password_count) {
+            Some(passwords) => passwords,
+            None => return errors::invalid_argument(),
+        };
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_with_shares(input_path, output_path, &passwords, threshold, hint, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_with_shares(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    passwords_ptr: *const *const u8,
+    password_lens: *const usize,
+    password_count: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let passwords = match password_list(passwords_ptr, password_lens, password_count) {
+            Some(passwords) => passwords,
+            None => return errors::invalid_argument(),
+        };
+
+        match decrypt_with_shares(input_path, output_path, &passwords, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn change_share_policy(
+    path_ptr: *const c_char,
+    unlock_passwords_ptr: *const *const u8,
+    unlock_password_lens: *const usize,
+    unlock_password_count: usize,
+    new_passwords_ptr: *const *const u8,
+    new_password_lens: *const usize,
+    new_password_count: usize,
+    threshold: u8,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let unlock_passwords = match password_list(unlock_passwords_ptr, unlock_password_lens, unlock_password_count) {
+            Some(passwords) => passwords,
+            None => return errors::invalid_argument(),
+        };
+        let new_passwords = match password_list(new_passwords_ptr, new_password_lens, new_password_count) {
+            Some(passwords) => passwords,
+            None => return errors::invalid_argument(),
+        };
+
+        match reshare(Path::new(path), &unlock_passwords, &new_passwords, threshold) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_share_policy(path_ptr: *const c_char, threshold: *mut u8, share_count: *mut u8) -> i32 {
+    if threshold.is_null() || share_count.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        match read_header(Path::new(path)) {
+            Ok((header, _)) => {
+                let (m, n) = header
+                    .share_policy
+                    .map(|policy| (policy.threshold, policy.slots.len() as u8))
+                    .unwrap_or((0, 0));
+                *threshold = m;
+                *share_count = n;
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+unsafe fn password_list<'a>(ptrs: *const *const u8, lens: *const usize, count: usize) -> Option<Vec<&'a [u8]>> {
+    if count == 0 || ptrs.is_null() || lens.is_null() {
+        return None;
+    }
+    let ptrs = std::slice::from_raw_parts(ptrs, count);
+    let lens = std::slice::from_raw_parts(lens, count);
+    ptrs.iter()
+        .zip(lens)
+        .map(|(ptr, len)| (!ptr.is_null()).then(|| std::slice::from_raw_parts(*ptr, *len)))
+        .collect()
+}
+
+pub fn encrypt_with_shares(
+    input_path: &str,
+    output_path: &str,
+    passwords: &[&[u8]],
+    threshold: u8,
+    hint: Option<&str>,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_key = FileKey(Zeroizing::new(rng::random()));
+    let options = EncryptOptions {
+        hint,
+        padding: OptionsProfile::current().padding,
+        share_policy: Some(share_policy(&file_key, passwords, threshold, KdfParams::for_new_files(is_mobile))?),
+        ..Default::default()
+    };
+    encrypt_file_reporting(input_path, output_path, &file_key, &options, is_mobile, cpu_cores, &ProgressSink::none())?;
+    Ok(())
+}
+
+pub fn decrypt_with_shares(
+    input_path: &str,
+    output_path: &str,
+    passwords: &[&[u8]],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (header, _) = read_header(Path::new(input_path))?;
+    let file_key = unlock(&header, passwords)?;
+    decrypt_file_reporting(input_path, output_path, &file_key, is_mobile, cpu_cores, &ProgressSink::none())
+}
+
+// Replaces the holders and threshold of a container. The file key and body
+// are unchanged, so only the header is rewritten.
+pub fn reshare(
+    path: &Path,
+    unlock_passwords: &[&[u8]],
+    new_passwords: &[&[u8]],
+    threshold: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = BufReader::new(File::open(path)?);
+    let (mut header, data_start) = ContainerHeader::read_recovering(&mut input)?;
+    let body_end = header.trailer_start(data_start, input.get_ref().metadata()?.len());
+    let file_key = unlock(&header, unlock_passwords)?;
+    header.share_policy = Some(share_policy(&file_key, new_passwords, threshold, KdfParams::for_new_files(false))?);
+    header.seal_file_id(&file_key.0);
+
+    let mut temp = SecureTempFile::for_target(path, TempContents::Ciphertext)?;
+    write_header(temp.file(), &header)?;
+    input.seek(SeekFrom::Start(data_start))?;
+    io::copy(&mut (&mut input).take(body_end.saturating_sub(data_start)), temp.file())?;
+    if header.backup {
+        header_backup::write_trailer(temp.file(), &header.encode())?;
+    }
+    temp.file().flush()?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+fn share_policy(
+    file_key: &FileKey,
+    passwords: &[&[u8]],
+    threshold: u8,
+    kdf: KdfParams,
+) -> Result<SharePolicy, Box<dyn std::error::Error>> {
+    let count = u8::try_from(passwords.len()).map_err(|_| "Too many key shares")?;
+    for (i, password) in passwords.iter().enumerate() {
+        if passwords[..i].contains(password) {
+            return Err("Key share passwords must differ".into());
+        }
+    }
+
+    let shares = shamir::split(file_key.0.as_slice(), threshold, count)?;
+    let slots = shares
+        .into_iter()
+        .zip(passwords)
+        .map(|((x, share), password)| {
+            let share = Zeroizing::new(share);
+            let salt: [u8; 16] = rng::random();
+            let nonce: [u8; 12] = rng::random();
+            let cipher = Aes256Gcm::new_from_slice(slot_key(password, &salt, &kdf)?.as_slice())?;
+            let sealed = cipher
+                .encrypt(Nonce::from_slice(&nonce), Payload { msg: &share, aad: &[threshold, x] })
+                .map_err(|_| "Encryption failed")?;
+            Ok(KeyShareSlot {
+                x,
+                salt,
+                nonce,
+                sealed: sealed.as_slice().try_into()?,
+            })
+        })
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+    Ok(SharePolicy { threshold, slots, kdf })
+}
+
+// Each password opens at most one slot, so a holder listed once cannot
+// stand in for a second holder.
+pub fn unlock(header: &ContainerHeader, passwords: &[&[u8]]) -> Result<FileKey, Box<dyn std::error::Error>> {
+    let policy = header.share_policy.as_ref().ok_or("File is not protected by key shares")?;
+    let mut opened: Vec<(u8, Zeroizing<Vec<u8>>)> = Vec::new();
+    for password in passwords {
+        let share = policy
+            .slots
+            .iter()
+            .filter(|slot| opened.iter().all(|(x, _)| *x != slot.x))
+            .find_map(|slot| open_slot(slot, policy, password).map(|share| (slot.x, share)));
+        opened.extend(share);
+    }
+    if opened.len() < policy.threshold as usize {
+        return Err("Not enough key shares".into());
+    }
+
+    let shares: Vec<(u8, &[u8])> = opened.iter().map(|(x, share)| (*x, share.as_slice())).collect();
+    let secret = shamir::combine(&shares)?;
+    let file_key = FileKey(Zeroizing::new(secret.as_slice().try_into().map_err(|_| "Invalid key share")?));
+    header.verify_file_id(&file_key.0)?;
+    Ok(file_key)
+}
+
+fn open_slot(slot: &KeyShareSlot, policy: &SharePolicy, password: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+    let cipher = Aes256Gcm::new_from_slice(slot_key(password, &slot.salt, &policy.kdf).ok()?.as_slice()).ok()?;
+    let share = cipher
+        .decrypt(Nonce::from_slice(&slot.nonce), Payload { msg: &slot.sealed, aad: &[policy.threshold, slot.x] })
+        .ok()?;
+    (share.len() == SEALED_SHARE_SIZE - crate::TAG_SIZE).then(|| Zeroizing::new(share))
+}
+
+// Policies from before the KDF was stored keep the legacy password hash,
+// salted per slot.
+fn slot_key(password: &[u8], salt: &[u8], kdf: &KdfParams) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+    let key = match kdf {
+        KdfParams::LegacySha256 => kdf::salted_subkey(&derive_key(password), salt, KeyPurpose::KeyShare),
+        params => kdf::subkey(&Zeroizing::new(kdf::derive(password, salt, params)?), KeyPurpose::KeyShare),
+    };
+    Ok(Zeroizing::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_to_memory_internal;
+    use std::fs;
+
+    #[test]
+    fn test_two_of_three_unlock_and_reshare() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("ledger.csv");
+        let encrypted = dir.path().join("ledger.kyl");
+        let decrypted = dir.path().join("ledger.out");
+        fs::write(&plain, b"quarterly figures").unwrap();
+        let (plain, encrypted, decrypted) = (
+            plain.to_str().unwrap(),
+            encrypted.to_str().unwrap(),
+            decrypted.to_str().unwrap(),
+        );
+        let officers: [&[u8]; 3] = [b"alpha", b"bravo", b"charlie"];
+        encrypt_with_shares(plain, encrypted, &officers, 2, Some("board"), false, 4).unwrap();
+
+        assert!(decrypt_file_to_memory_internal(encrypted, b"alpha", false, 4).is_err());
+        let err = decrypt_with_shares(encrypted, decrypted, &[b"alpha", b"alpha"], false, 4).unwrap_err();
+        assert_eq!(err.to_string(), "Not enough key shares");
+        assert!(decrypt_with_shares(encrypted, decrypted, &[b"alpha", b"delta"], false, 4).is_err());
+        decrypt_with_shares(encrypted, decrypted, &[b"charlie", b"alpha"], false, 4).unwrap();
+        assert_eq!(fs::read(decrypted).unwrap(), b"quarterly figures");
+
+        let path = Path::new(encrypted);
+        assert!(reshare(path, &[b"alpha"], &[b"alpha", b"delta"], 2).is_err());
+        reshare(path, &[b"alpha", b"bravo"], &[b"alpha", b"delta"], 2).unwrap();
+        assert!(decrypt_with_shares(encrypted, decrypted, &[b"alpha", b"bravo"], false, 4).is_err());
+        fs::remove_file(decrypted).unwrap();
+        decrypt_with_shares(encrypted, decrypted, &[b"delta", b"alpha"], false, 4).unwrap();
+        assert_eq!(fs::read(decrypted).unwrap(), b"quarterly figures");
+
+        let (header, _) = read_header(path).unwrap();
+        let policy = header.share_policy.unwrap();
+        assert_eq!((policy.threshold, policy.slots.len()), (2, 2));
+        assert_eq!(policy.kdf, KdfParams::for_new_files(false));
+        assert_eq!(header.hint, b"board");
+    }
+}
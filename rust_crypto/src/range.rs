@@ -0,0 +1,429 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::cipher::BodyCipher;
+use crate::format::{read_layout, ChunkLayout};
+use crate::padding::{PaddingStripper, PADDING_MARKER, PADDING_NONE};
+use crate::sparse;
+use crate::{errors, TAG_SIZE};
+
+#[no_mangle]
+pub extern "C" fn decrypt_prefix(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    prefix_len: usize,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match decrypt_prefix_internal(Path::new(input_path), password, prefix_len) {
+            Ok(data) => {
+                *output_len = data.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), output_ptr, data.len());
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_range(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    offset: u64,
+    length: usize,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+) -> i32 {
+    if output_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match decrypt_range_internal(Path::new(input_path), password, offset, length) {
+            Ok(data) => {
+                *output_len = data.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), output_ptr, data.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// A decrypting reader held across FFI calls, so players and viewers can
+// stream from the container the way they would from a plain file.
+pub type KyrieReader = DecryptingReader;
+
+#[no_mangle]
+pub extern "C" fn kyrie_open_reader(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+) -> *mut KyrieReader {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                errors::invalid_argument();
+                return std::ptr::null_mut();
+            }
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match open_checked(Path::new(input_path), password) {
+            Ok(reader) => Box::into_raw(Box::new(reader)),
+            Err(e) => {
+                errors::fail(e.as_ref());
+                std::ptr::null_mut()
+            }
+        }
+    }
+}
+
+// Fills the buffer unless the end of the content comes first; a read of
+// zero bytes means the end was reached.
+#[no_mangle]
+pub extern "C" fn kyrie_reader_read(
+    reader: *mut KyrieReader,
+    buffer_ptr: *mut u8,
+    buffer_len: usize,
+    read_len: *mut usize,
+) -> i32 {
+    if reader.is_null() || read_len.is_null() || (buffer_ptr.is_null() && buffer_len > 0) {
+        return errors::invalid_argument();
+    }
+    let buffer = if buffer_len == 0 {
+        &mut [][..]
+    } else {
+        unsafe { std::slice::from_raw_parts_mut(buffer_ptr, buffer_len) }
+    };
+    match read_full(unsafe { &mut *reader }, buffer) {
+        Ok(n) => {
+            unsafe { *read_len = n };
+            0
+        }
+        Err(e) => errors::fail(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_reader_seek(reader: *mut KyrieReader, position: u64) -> i32 {
+    if reader.is_null() {
+        return errors::invalid_argument();
+    }
+    match unsafe { &mut *reader }.seek(SeekFrom::Start(position)) {
+        Ok(_) => 0,
+        Err(e) => errors::fail(&e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_reader_len(reader: *const KyrieReader) -> u64 {
+    if reader.is_null() {
+        return 0;
+    }
+    unsafe { &*reader }.plaintext_len()
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_reader_close(reader: *mut KyrieReader) {
+    if !reader.is_null() {
+        drop(unsafe { Box::from_raw(reader) });
+    }
+}
+
+// Decrypting the first chunk up front makes a wrong password fail here
+// rather than on the caller's first read.
+fn open_checked(input_path: &Path, password: &[u8]) -> Result<KyrieReader, Box<dyn std::error::Error>> {
+    let mut reader = DecryptingReader::open(input_path, password)?;
+    if !reader.layout.frames.is_empty() {
+        reader.load_chunk(0)?;
+    }
+    Ok(reader)
+}
+
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+// Decrypts only the chunks covering the range. The result is shorter than
+// `length` when the range runs past the end of the content.
+pub fn decrypt_range_internal(
+    input_path: &Path,
+    password: &[u8],
+    offset: u64,
+    length: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut reader = DecryptingReader::open(input_path, password)?;
+    let available = reader.plaintext_len().saturating_sub(offset);
+    let mut range = Vec::with_capacity(available.min(length as u64) as usize);
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.take(length as u64).read_to_end(&mut range)?;
+    Ok(range)
+}
+
+pub fn decrypt_prefix_internal(
+    input_path: &Path,
+    password: &[u8],
+    prefix_len: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let layout = read_layout(input_path)?;
+    sparse::require_dense(&layout.holes)?;
+    let key = layout.master_key(password)?;
+    let mut reader = BufReader::new(File::open(input_path)?);
+    let layout = layout.unlock(&mut reader, &key)?;
+    let cipher = layout.body_cipher(&key)?;
+
+    let mut prefix = Vec::new();
+    let mut stripper = PaddingStripper::new(layout.padding);
+    let mut frames = layout.frames.iter();
+    while prefix.len() < prefix_len {
+        let frame = match frames.next() {
+            Some(frame) => frame,
+            None => {
+                stripper.finish()?;
+                break;
+            }
+        };
+        let (nonce, ciphertext) = layout.read_frame(&mut reader, frame)?;
+        let decrypted = cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| "Decryption failed")?;
+        stripper.feed(&decrypted, |content| {
+            prefix.extend_from_slice(content);
+            Ok(())
+        })?;
+    }
+
+    prefix.truncate(prefix_len);
+    Ok(prefix)
+}
+
+pub struct DecryptingReader {
+    reader: BufReader<File>,
+    layout: ChunkLayout,
+    cipher: BodyCipher,
+    chunk_starts: Vec<u64>,
+    plaintext_len: u64,
+    position: u64,
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl DecryptingReader {
+    pub fn open(input_path: &Path, password: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let layout = read_layout(input_path)?;
+        sparse::require_dense(&layout.holes)?;
+        let key = layout.master_key(password)?;
+        let mut reader = BufReader::new(File::open(input_path)?);
+        let layout = layout.unlock(&mut reader, &key)?;
+        Self::with_reader(reader, layout, &key)
+    }
+
+    // For a layout already unlocked with `key`.
+    pub fn unlocked(input_path: &Path, layout: ChunkLayout, key: &[u8; 32]) -> Result<Self, Box<dyn std::error::Error>> {
+        sparse::require_dense(&layout.holes)?;
+        Self::with_reader(BufReader::new(File::open(input_path)?), layout, key)
+    }
+
+    fn with_reader(reader: BufReader<File>, layout: ChunkLayout, key: &[u8; 32]) -> Result<Self, Box<dyn std::error::Error>> {
+        let cipher = layout.body_cipher(key)?;
+
+        let mut chunk_starts = Vec::with_capacity(layout.frames.len());
+        let mut plaintext_len = 0u64;
+        for frame in &layout.frames {
+            chunk_starts.push(plaintext_len);
+            plaintext_len += frame.len - TAG_SIZE as u64;
+        }
+
+        let mut reader = DecryptingReader {
+            reader,
+            layout,
+            cipher,
+            chunk_starts,
+            plaintext_len,
+            position: 0,
+            cached: None,
+        };
+        if reader.layout.padding != PADDING_NONE {
+            reader.plaintext_len = reader.content_end()?;
+        }
+        Ok(reader)
+    }
+
+    pub fn plaintext_len(&self) -> u64 {
+        self.plaintext_len
+    }
+
+    fn content_end(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        for index in (0..self.layout.frames.len()).rev() {
+            let start = self.chunk_starts[index];
+            let chunk = self.load_chunk(index)?;
+            match chunk.iter().rposition(|&b| b != 0) {
+                Some(end) if chunk[end] == PADDING_MARKER => return Ok(start + end as u64),
+                Some(_) => break,
+                None => continue,
+            }
+        }
+        Err("Invalid padding".into())
+    }
+
+    fn load_chunk(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.cached.as_ref().map(|(i, _)| *i) != Some(index) {
+            let frame = &self.layout.frames[index];
+            let (nonce, ciphertext) = self
+                .layout
+                .read_frame(&mut self.reader, frame)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let decrypted = self
+                .cipher
+                .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Decryption failed"))?;
+            self.cached = Some((index, decrypted));
+        }
+        Ok(self.cached.as_ref().map(|(_, data)| data.as_slice()).unwrap_or_default())
+    }
+}
+
+impl Read for DecryptingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.plaintext_len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.chunk_starts.partition_point(|&start| start <= self.position) - 1;
+        let offset = (self.position - self.chunk_starts[index]) as usize;
+        // Padded containers end before the last chunk does.
+        let remaining = usize::try_from(self.plaintext_len - self.position).unwrap_or(usize::MAX);
+        let chunk = self.load_chunk(index)?;
+        let n = buf.len().min(chunk.len() - offset).min(remaining);
+        buf[..n].copy_from_slice(&chunk[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for DecryptingReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.plaintext_len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        match target {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::ContainerHeader;
+    use crate::test_util::{write_framed_container, write_framed_container_with};
+
+    #[test]
+    fn test_decrypt_prefix_stops_early() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.kyl");
+        write_framed_container(&path, b"pw", &[b"\x00\x00\x00\x18ftyp", b"mp42", b"rest of the movie"]);
+
+        let prefix = decrypt_prefix_internal(&path, b"pw", 10).unwrap();
+        assert_eq!(prefix, b"\x00\x00\x00\x18ftypmp");
+
+        let all = decrypt_prefix_internal(&path, b"pw", 1000).unwrap();
+        assert_eq!(all.len(), 8 + 4 + 17);
+        assert!(decrypt_prefix_internal(&path, b"wrong", 4).is_err());
+    }
+
+    #[test]
+    fn test_decrypting_reader_seeks_across_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("doc.kyl");
+        write_framed_container(&path, b"pw", &[b"0123", b"4567", b"89"]);
+
+        let mut reader = DecryptingReader::open(&path, b"pw").unwrap();
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 10);
+
+        reader.seek(SeekFrom::Start(3)).unwrap();
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"3456");
+
+        reader.seek(SeekFrom::End(-3)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"789");
+    }
+
+    #[test]
+    fn test_reader_handle_reads_and_seeks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("movie.kyl");
+        write_framed_container(&path, b"pw", &[b"0123", b"4567", b"89"]);
+        let path_c = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+
+        let reader = kyrie_open_reader(path_c.as_ptr(), b"pw".as_ptr(), 2);
+        assert!(!reader.is_null());
+        assert_eq!(kyrie_reader_len(reader), 10);
+
+        let mut buf = [0u8; 6];
+        let mut read = 0;
+        assert_eq!(kyrie_reader_read(reader, buf.as_mut_ptr(), buf.len(), &mut read), 0);
+        assert_eq!(&buf[..read], b"012345");
+        assert_eq!(kyrie_reader_seek(reader, 7), 0);
+        assert_eq!(kyrie_reader_read(reader, buf.as_mut_ptr(), buf.len(), &mut read), 0);
+        assert_eq!(&buf[..read], b"789");
+        assert_eq!(kyrie_reader_read(reader, buf.as_mut_ptr(), buf.len(), &mut read), 0);
+        assert_eq!(read, 0);
+        kyrie_reader_close(reader);
+
+        assert!(kyrie_open_reader(path_c.as_ptr(), b"wrong".as_ptr(), 5).is_null());
+        assert_eq!(kyrie_reader_read(std::ptr::null_mut(), buf.as_mut_ptr(), 1, &mut read), -1);
+    }
+
+    #[test]
+    fn test_decrypt_range_reads_covering_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.kyl");
+        let header = ContainerHeader { chunk_size: Some(4), ..Default::default() };
+        write_framed_container_with(&path, b"pw", &header, &[b"0123", b"4567", b"89"]);
+
+        assert_eq!(decrypt_range_internal(&path, b"pw", 3, 4).unwrap(), b"3456");
+        assert_eq!(decrypt_range_internal(&path, b"pw", 8, 100).unwrap(), b"89");
+        assert!(decrypt_range_internal(&path, b"pw", 20, 4).unwrap().is_empty());
+        assert!(decrypt_range_internal(&path, b"wrong", 0, 4).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::ffi::CStr;
+use std::fs;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::slice;
+use zeroize::Zeroizing;
+
+use crate::cipher::{BodyCipher, CIPHER_AES_256_GCM};
+use crate::kdf::{self, KdfParams, KDF_SALT_SIZE};
+use crate::secure_temp::{self, SecureTempFile, TempContents};
+use crate::{errors, random_nonce, rng, NONCE_SIZE, TAG_SIZE};
+
+// A header-less layout for other tools to read, published in the format
+// spec. Nothing in the file records the KDF parameters, so both sides must
+// agree on them out of band.
+pub const RAW_LAYOUT: &str =
+    "salt(16) || nonce(12) || AES-256-GCM ciphertext || tag(16); key = Argon2id v1.3 (password, salt, caller's parameters), 32 bytes; no associated data";
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_raw(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    memory_kib: u32,
+    iterations: u32,
+    lanes: u32,
+) -> i32 {
+    let params = KdfParams::Argon2id { memory_kib, iterations, lanes };
+    if params.check().is_err() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        match encrypt_file_raw_internal(Path::new(input_path), Path::new(output_path), password, &params) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_raw(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    memory_kib: u32,
+    iterations: u32,
+    lanes: u32,
+) -> i32 {
+    let params = KdfParams::Argon2id { memory_kib, iterations, lanes };
+    if params.check().is_err() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        match decrypt_file_raw_internal(Path::new(input_path), Path::new(output_path), password, &params) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// The file is sealed as one AEAD message, so it is held in memory whole.
+pub fn encrypt_file_raw_internal(
+    input_path: &Path,
+    output_path: &Path,
+    password: &[u8],
+    params: &KdfParams,
+) -> Result<(), Box<dyn std::error::Error>> {
+    secure_temp::check_target(output_path)?;
+    let plaintext = Zeroizing::new(fs::read(input_path)?);
+    let salt: [u8; KDF_SALT_SIZE] = rng::random();
+    let key = Zeroizing::new(kdf::derive(password, &salt, params)?);
+    let nonce = random_nonce();
+    let sealed = BodyCipher::new(CIPHER_AES_256_GCM, &key)?
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| "Encryption failed")?;
+
+    let mut temp = SecureTempFile::for_target(output_path, TempContents::Ciphertext)?;
+    temp.file().write_all(&[&salt[..], &nonce, &sealed].concat())?;
+    temp.persist(output_path)?;
+    Ok(())
+}
+
+pub fn decrypt_file_raw_internal(
+    input_path: &Path,
+    output_path: &Path,
+    password: &[u8],
+    params: &KdfParams,
+) -> Result<(), Box<dyn std::error::Error>> {
+    secure_temp::check_target(output_path)?;
+    let data = fs::read(input_path)?;
+    if data.len() < KDF_SALT_SIZE + NONCE_SIZE + TAG_SIZE {
+        return Err("Invalid file format".into());
+    }
+    let (salt, rest) = data.split_at(KDF_SALT_SIZE);
+    let (nonce, sealed) = rest.split_at(NONCE_SIZE);
+    let key = Zeroizing::new(kdf::derive(password, salt, params)?);
+    let plaintext = Zeroizing::new(
+        BodyCipher::new(CIPHER_AES_256_GCM, &key)?
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| "Decryption failed")?,
+    );
+
+    let mut temp = SecureTempFile::for_output(output_path)?;
+    temp.file().write_all(&plaintext)?;
+    temp.persist(output_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::{Aes256Gcm, KeyInit};
+    use argon2::{Algorithm, Argon2, Params, Version};
+
+    #[test]
+    fn test_raw_layout_opens_with_plain_argon2_and_gcm() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("notes.txt");
+        let raw = dir.path().join("notes.bin");
+        let decrypted = dir.path().join("notes.out");
+        fs::write(&plain, b"for another tool").unwrap();
+        let params = KdfParams::Argon2id { memory_kib: 64, iterations: 2, lanes: 1 };
+        encrypt_file_raw_internal(&plain, &raw, b"pw", &params).unwrap();
+
+        // Read back by the layout alone, as another implementation would.
+        let data = fs::read(&raw).unwrap();
+        assert_eq!(data.len(), KDF_SALT_SIZE + NONCE_SIZE + 16 + TAG_SIZE);
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(64, 2, 1, Some(32)).unwrap())
+            .hash_password_into(b"pw", &data[..16], &mut key)
+            .unwrap();
+        let opened = Aes256Gcm::new_from_slice(&key).unwrap().decrypt(Nonce::from_slice(&data[16..28]), &data[28..]).unwrap();
+        assert_eq!(opened, b"for another tool");
+
+        decrypt_file_raw_internal(&raw, &decrypted, b"pw", &params).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"for another tool");
+        let other = KdfParams::Argon2id { memory_kib: 64, iterations: 1, lanes: 1 };
+        let error = decrypt_file_raw_internal(&raw, &dir.path().join("other.out"), b"pw", &other).unwrap_err();
+        assert_eq!(errors::classify(error.as_ref()), errors::ERR_WRONG_PASSWORD);
+        assert!(!dir.path().join("other.out").exists());
+    }
+}
@@ -0,0 +1,169 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::escrow::{self, agent_id};
+use crate::format::read_header;
+use crate::kdf::KeyPurpose;
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::quorum::FileKey;
+use crate::rng;
+use crate::{decrypt_file_reporting, encrypt_file_reporting, errors, EncryptOptions};
+
+#[no_mangle]
+pub extern "C" fn kyrie_generate_keypair(secret_key_ptr: *mut u8, public_key_ptr: *mut u8) -> i32 {
+    if secret_key_ptr.is_null() || public_key_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    let (secret_key, public_key) = generate_keypair();
+    unsafe {
+        std::ptr::copy_nonoverlapping(secret_key.as_ptr(), secret_key_ptr, 32);
+        std::ptr::copy_nonoverlapping(public_key.as_ptr(), public_key_ptr, 32);
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_to_recipient(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    public_key_ptr: *const u8,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if public_key_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(std::slice::from_raw_parts(public_key_ptr, 32));
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_to_recipient(input_path, output_path, &public_key, hint, is_mobile, cpu_cores) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_with_private_key(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    secret_key_ptr: *const u8,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if secret_key_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let mut secret_key = Zeroizing::new([0u8; 32]);
+        secret_key.copy_from_slice(std::slice::from_raw_parts(secret_key_ptr, 32));
+
+        match decrypt_with_private_key(input_path, output_path, &secret_key, is_mobile, cpu_cores) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn generate_keypair() -> (Zeroizing<[u8; 32]>, [u8; 32]) {
+    let secret = StaticSecret::from(rng::random::<[u8; 32]>());
+    let public_key = PublicKey::from(&secret).to_bytes();
+    (Zeroizing::new(secret.to_bytes()), public_key)
+}
+
+// The body is encrypted under a random file key; the header holds it sealed
+// to the recipient through an ephemeral X25519 agreement, so no password is
+// involved at all.
+pub fn encrypt_to_recipient(
+    input_path: &str,
+    output_path: &str,
+    public_key: &[u8; 32],
+    hint: Option<&str>,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_key = FileKey(Zeroizing::new(rng::random()));
+    let options = EncryptOptions {
+        hint,
+        padding: OptionsProfile::current().padding,
+        recipients: vec![escrow::seal_to_key(public_key, &file_key.0, KeyPurpose::RecipientWrap)?],
+        ..Default::default()
+    };
+    encrypt_file_reporting(input_path, output_path, &file_key, &options, is_mobile, cpu_cores, &ProgressSink::none())?;
+    Ok(())
+}
+
+pub fn decrypt_with_private_key(
+    input_path: &str,
+    output_path: &str,
+    secret_key: &[u8; 32],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let secret = StaticSecret::from(*secret_key);
+    let key_id = agent_id(PublicKey::from(&secret).as_bytes());
+    let (header, _) = read_header(Path::new(input_path))?;
+    let slot = header
+        .recipients
+        .iter()
+        .find(|slot| slot.agent_id == key_id)
+        .ok_or("File is not addressed to this key")?;
+    let file_key = FileKey(escrow::open_slot(slot, &secret, KeyPurpose::RecipientWrap)?);
+    decrypt_file_reporting(input_path, output_path, &file_key, is_mobile, cpu_cores, &ProgressSink::none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_internal;
+    use std::fs;
+
+    #[test]
+    fn test_recipient_key_opens_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        let decrypted = dir.path().join("out.txt");
+        fs::write(&plain, b"for your eyes").unwrap();
+
+        let (secret_key, public_key) = generate_keypair();
+        let (input, output) = (plain.to_str().unwrap(), encrypted.to_str().unwrap());
+        encrypt_to_recipient(input, output, &public_key, None, false, 4).unwrap();
+        assert_eq!(read_header(&encrypted).unwrap().0.recipients.len(), 1);
+
+        let out = decrypted.to_str().unwrap();
+        decrypt_with_private_key(output, out, &secret_key, false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"for your eyes");
+
+        let (other_secret, _) = generate_keypair();
+        assert!(decrypt_with_private_key(output, out, &other_secret, false, 4).is_err());
+        assert!(decrypt_file_internal(output, out, b"", false, 4).is_err());
+    }
+}
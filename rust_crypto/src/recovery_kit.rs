@@ -0,0 +1,369 @@
+use bip39::Mnemonic;
+use sha2::{Digest, Sha256};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::format::{read_header, ContainerHeader, FILE_ID_SIZE};
+use crate::progress::ProgressSink;
+use crate::quorum::FileKey;
+use crate::{decrypt_file_reporting, errors, shamir};
+
+const RECOVERY_SHARE_VERSION: u8 = 1;
+const CHECKSUM_SIZE: usize = 4;
+// version || file uuid || threshold || x || share of the file key || checksum
+pub const RECOVERY_SHARE_SIZE: usize = 1 + FILE_ID_SIZE + 2 + 32 + CHECKSUM_SIZE;
+
+// Shares of the wrapped file key, handed out to family members on paper or
+// in a password manager. They stay valid across password changes because
+// only the wrapping changes; re-encrypting the file retires them.
+#[no_mangle]
+pub extern "C" fn create_recovery_shares(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    share_count: u8,
+    threshold: u8,
+    shares_ptr: *mut u8,
+    shares_len: *mut usize,
+) -> i32 {
+    if shares_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match create_recovery_shares_internal(Path::new(path), password, share_count, threshold) {
+            Ok(shares) => {
+                *shares_len = shares.len() * RECOVERY_SHARE_SIZE;
+                if !shares_ptr.is_null() {
+                    for (i, share) in shares.iter().enumerate() {
+                        std::ptr::copy_nonoverlapping(share.as_ptr(), shares_ptr.add(i * RECOVERY_SHARE_SIZE), RECOVERY_SHARE_SIZE);
+                    }
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Takes the shares back to back, each RECOVERY_SHARE_SIZE bytes long.
+#[no_mangle]
+pub extern "C" fn decrypt_file_with_recovery_shares(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    shares_ptr: *const u8,
+    share_count: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if shares_ptr.is_null() || share_count == 0 {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let shares: Vec<&[u8]> =
+            std::slice::from_raw_parts(shares_ptr, share_count * RECOVERY_SHARE_SIZE).chunks(RECOVERY_SHARE_SIZE).collect();
+
+        match decrypt_with_recovery_shares(input_path, output_path, &shares, is_mobile, cpu_cores) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// The file key itself, as 32 bytes for a QR code and as 24 BIP39 words for
+// paper. Either one opens the file alone, so it belongs in a safe, and like
+// the shares it survives password changes.
+#[no_mangle]
+pub extern "C" fn export_recovery_key(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    key_ptr: *mut u8,
+    words_ptr: *mut u8,
+    words_len: *mut usize,
+) -> i32 {
+    if words_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match export_recovery_key_internal(Path::new(path), password) {
+            Ok(file_key) => {
+                let words = recovery_words(&file_key);
+                *words_len = words.len();
+                if !key_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(file_key.as_ptr(), key_ptr, file_key.len());
+                }
+                if !words_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(words.as_ptr(), words_ptr, words.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Turns typed-in words back into the 32 key bytes.
+#[no_mangle]
+pub extern "C" fn recovery_key_from_words(words_ptr: *const c_char, key_ptr: *mut u8) -> i32 {
+    if key_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    let words = match unsafe { CStr::from_ptr(words_ptr) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return errors::invalid_argument(),
+    };
+    match key_from_words(words) {
+        Ok(file_key) => {
+            unsafe { std::ptr::copy_nonoverlapping(file_key.as_ptr(), key_ptr, file_key.len()) };
+            0
+        }
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_with_exported_key(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    key_ptr: *const u8,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if key_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let mut file_key = Zeroizing::new([0u8; 32]);
+        file_key.copy_from_slice(std::slice::from_raw_parts(key_ptr, 32));
+
+        match decrypt_with_exported_key(input_path, output_path, &file_key, is_mobile, cpu_cores) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn create_recovery_shares_internal(
+    path: &Path,
+    password: &[u8],
+    share_count: u8,
+    threshold: u8,
+) -> Result<Vec<Zeroizing<Vec<u8>>>, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    let file_key = unlock_file_key(&header, password)?;
+    let uuid = file_uuid(&header)?;
+
+    let shares = shamir::split(file_key.as_slice(), threshold, share_count)?;
+    Ok(shares
+        .into_iter()
+        .map(|(x, y)| {
+            let y = Zeroizing::new(y);
+            let mut share = Zeroizing::new(Vec::with_capacity(RECOVERY_SHARE_SIZE));
+            share.push(RECOVERY_SHARE_VERSION);
+            share.extend_from_slice(&uuid);
+            share.extend_from_slice(&[threshold, x]);
+            share.extend_from_slice(&y);
+            let checksum = checksum(&share);
+            share.extend_from_slice(&checksum);
+            share
+        })
+        .collect())
+}
+
+pub fn export_recovery_key_internal(path: &Path, password: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    unlock_file_key(&header, password)
+}
+
+pub fn recovery_words(file_key: &[u8; 32]) -> Zeroizing<String> {
+    Zeroizing::new(Mnemonic::from_entropy(file_key).expect("32 bytes is a valid entropy length").to_string())
+}
+
+// Case and spacing are forgiven; a misspelt word or a swapped pair fails
+// the BIP39 checksum.
+pub fn key_from_words(words: &str) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+    let normalized = Zeroizing::new(words.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase());
+    let mnemonic = Mnemonic::parse_normalized(&normalized).map_err(|_| "Invalid recovery key")?;
+    let (entropy, len) = mnemonic.to_entropy_array();
+    let entropy = Zeroizing::new(entropy);
+    Ok(Zeroizing::new(entropy[..len].try_into().map_err(|_| "Invalid recovery key")?))
+}
+
+pub fn decrypt_with_exported_key(
+    input_path: &str,
+    output_path: &str,
+    file_key: &[u8; 32],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (header, _) = read_header(Path::new(input_path))?;
+    header.verify_file_id(file_key).map_err(|_| "Decryption failed")?;
+    decrypt_file_reporting(input_path, output_path, &FileKey(Zeroizing::new(*file_key)), is_mobile, cpu_cores, &ProgressSink::none())
+}
+
+pub fn decrypt_with_recovery_shares(
+    input_path: &str,
+    output_path: &str,
+    shares: &[&[u8]],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (header, _) = read_header(Path::new(input_path))?;
+    let file_key = recover_file_key(&header, shares)?;
+    decrypt_file_reporting(input_path, output_path, &file_key, is_mobile, cpu_cores, &ProgressSink::none())
+}
+
+// Shares are checked one by one first, so a mistyped or foreign share is
+// reported as such instead of combining into a wrong key.
+fn recover_file_key(header: &ContainerHeader, shares: &[&[u8]]) -> Result<FileKey, Box<dyn std::error::Error>> {
+    let uuid = file_uuid(header)?;
+    let mut threshold = None;
+    let mut points: Vec<(u8, &[u8])> = Vec::with_capacity(shares.len());
+    for share in shares {
+        if share.len() != RECOVERY_SHARE_SIZE || share[0] != RECOVERY_SHARE_VERSION {
+            return Err("Invalid recovery share".into());
+        }
+        let (body, check) = share.split_at(RECOVERY_SHARE_SIZE - CHECKSUM_SIZE);
+        if checksum(body) != check {
+            return Err("Invalid recovery share".into());
+        }
+        let (share_uuid, rest) = body[1..].split_at(FILE_ID_SIZE);
+        if share_uuid != uuid {
+            return Err("Recovery share belongs to another file".into());
+        }
+        if *threshold.get_or_insert(rest[0]) != rest[0] {
+            return Err("Invalid recovery share".into());
+        }
+        if points.iter().all(|(x, _)| *x != rest[1]) {
+            points.push((rest[1], &rest[2..]));
+        }
+    }
+    if points.len() < threshold.unwrap_or(0) as usize {
+        return Err("Not enough key shares".into());
+    }
+
+    let secret = shamir::combine(&points)?;
+    let file_key = FileKey(Zeroizing::new(secret.as_slice().try_into().map_err(|_| "Invalid recovery share")?));
+    header.verify_file_id(&file_key.0)?;
+    Ok(file_key)
+}
+
+// Only a wrapped file key outlives a password change, so older files must
+// have their password changed once before a recovery kit is made.
+fn unlock_file_key(header: &ContainerHeader, password: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error>> {
+    if header.dual() || header.share_policy.is_some() {
+        return Err("File is not password-protected".into());
+    }
+    if header.wrapped_key.is_none() {
+        return Err("File has no key slots; change its password first".into());
+    }
+    let file_key = Zeroizing::new(header.master_key(password)?);
+    header.verify_file_id(&file_key).map_err(|_| "Invalid password")?;
+    Ok(file_key)
+}
+
+fn file_uuid(header: &ContainerHeader) -> Result<[u8; FILE_ID_SIZE], Box<dyn std::error::Error>> {
+    header.file_id.map(|id| id.uuid).ok_or_else(|| "File has no file id".into())
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    Sha256::digest(data)[..CHECKSUM_SIZE].try_into().expect("SHA-256 output is longer than the checksum")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+    use crate::reencrypt::change_password;
+    use std::fs;
+
+    #[test]
+    fn test_any_threshold_of_shares_decrypts() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("album.jpg");
+        let encrypted = dir.path().join("album.kyl");
+        let other = dir.path().join("other.kyl");
+        let recovered = dir.path().join("recovered.jpg");
+        fs::write(&plain, vec![3u8; 300_000]).unwrap();
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+        encrypt_file_internal(&path(&plain), &path(&encrypted), b"pw", None, false, 4).unwrap();
+        encrypt_file_internal(&path(&plain), &path(&other), b"pw", None, false, 4).unwrap();
+
+        assert!(create_recovery_shares_internal(&encrypted, b"wrong", 5, 3).is_err());
+        let shares = create_recovery_shares_internal(&encrypted, b"pw", 5, 3).unwrap();
+        assert!(shares.iter().all(|share| share.len() == RECOVERY_SHARE_SIZE));
+
+        // Shares outlive a password change, which only rewraps the file key.
+        change_password(&encrypted, b"pw", b"forgotten", false).unwrap();
+        let pick = |picks: &[usize]| -> Vec<&[u8]> { picks.iter().map(|&i| shares[i].as_slice()).collect() };
+        decrypt_with_recovery_shares(&path(&encrypted), &path(&recovered), &pick(&[4, 0, 2]), false, 4).unwrap();
+        assert_eq!(fs::read(&recovered).unwrap(), fs::read(&plain).unwrap());
+
+        let result = decrypt_with_recovery_shares(&path(&encrypted), &path(&recovered), &pick(&[1, 1, 3]), false, 4);
+        assert_eq!(result.unwrap_err().to_string(), "Not enough key shares");
+        let result = decrypt_with_recovery_shares(&path(&other), &path(&recovered), &pick(&[0, 1, 2]), false, 4);
+        assert_eq!(result.unwrap_err().to_string(), "Recovery share belongs to another file");
+        let mut mistyped = shares[3].to_vec();
+        mistyped[20] ^= 1;
+        let result = decrypt_with_recovery_shares(&path(&encrypted), &path(&recovered), &[&shares[0], &shares[1], &mistyped], false, 4);
+        assert_eq!(result.unwrap_err().to_string(), "Invalid recovery share");
+    }
+    #[test]
+    fn test_exported_key_round_trips_through_words() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("archive.zip");
+        let encrypted = dir.path().join("archive.kyl");
+        let recovered = dir.path().join("recovered.zip");
+        fs::write(&plain, b"tax returns 2019-2025").unwrap();
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+        encrypt_file_internal(&path(&plain), &path(&encrypted), b"pw", None, false, 4).unwrap();
+
+        let file_key = export_recovery_key_internal(&encrypted, b"pw").unwrap();
+        let words = recovery_words(&file_key);
+        assert_eq!(words.split(' ').count(), 24);
+        let typed = format!("  {}\n", words.to_uppercase().replace(' ', "   "));
+        assert_eq!(*key_from_words(&typed).unwrap(), *file_key);
+        // The checksum is only 8 bits and misses 1 in 256 swaps, so this
+        // uses a key whose swap it is known to catch.
+        let fixed = recovery_words(&[0x5a; 32]);
+        let swapped: Vec<&str> = fixed.split(' ').collect();
+        let swapped = [&[swapped[1], swapped[0]], &swapped[2..]].concat().join(" ");
+        assert!(key_from_words(&swapped).is_err());
+
+        change_password(&encrypted, b"pw", b"forgotten", false).unwrap();
+        decrypt_with_exported_key(&path(&encrypted), &path(&recovered), &file_key, false, 4).unwrap();
+        assert_eq!(fs::read(&recovered).unwrap(), b"tax returns 2019-2025");
+        let result = decrypt_with_exported_key(&path(&encrypted), &path(&recovered), &[0; 32], false, 4);
+        assert_eq!(crate::errors::classify(result.unwrap_err().as_ref()), crate::errors::ERR_WRONG_PASSWORD);
+    }
+}
@@ -0,0 +1,581 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::file_lock;
+use crate::format::{read_header, read_layout, ContainerHeader, MAX_CHUNK_CHECKSUMS, MAX_HEADER_LEN, NONCE_SCHEME_COUNTER};
+use crate::kdf::KEY_PURPOSE_FILE_DATA;
+use crate::manifest::{Manifest, ManifestBuilder};
+use crate::nonce::NonceSequence;
+use crate::padding::PaddingStripper;
+use crate::secure_temp::{self, SecureTempFile, TempContents};
+use crate::{
+    app_metadata, digest, errors, escrow, for_each_decrypted_chunk, header_backup, key_usage, metadata, parity, preview, text, upgrade, write_encrypted_frame, write_header,
+    TAG_SIZE,
+};
+
+#[derive(Default)]
+pub struct ReencryptOptions<'a> {
+    pub hint: Option<&'a str>,
+    pub upgrade: bool,
+}
+
+impl ReencryptOptions<'_> {
+    pub fn from_policy() -> Self {
+        ReencryptOptions { upgrade: upgrade::enabled(), ..Default::default() }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn reencrypt_file(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    old_password_ptr: *const u8,
+    old_password_len: usize,
+    new_password_ptr: *const u8,
+    new_password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let old_password = std::slice::from_raw_parts(old_password_ptr, old_password_len);
+        let new_password = std::slice::from_raw_parts(new_password_ptr, new_password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            match CStr::from_ptr(hint_ptr).to_str() {
+                Ok(s) => Some(s),
+                Err(_) => return -1,
+            }
+        };
+
+        let options = ReencryptOptions { hint, ..ReencryptOptions::from_policy() };
+        match reencrypt_file_internal(input_path, output_path, old_password, new_password, &options, is_mobile) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_change_password(
+    path_ptr: *const c_char,
+    old_password_ptr: *const u8,
+    old_password_len: usize,
+    new_password_ptr: *const u8,
+    new_password_len: usize,
+    is_mobile: bool,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let old_password = std::slice::from_raw_parts(old_password_ptr, old_password_len);
+        let new_password = std::slice::from_raw_parts(new_password_ptr, new_password_len);
+
+        match change_password(Path::new(path), old_password, new_password, is_mobile) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Files with a wrapped file key only get their key field rewritten, in
+// place; older files are re-encrypted, which also moves them to wrapped keys.
+pub fn change_password(
+    path: &Path,
+    old_password: &[u8],
+    new_password: &[u8],
+    is_mobile: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut header, header_len) = read_header(path)?;
+    if header.wrapped_key.is_none() {
+        let path = path.to_str().ok_or("Invalid path")?;
+        return reencrypt_file_internal(path, path, old_password, new_password, &ReencryptOptions::from_policy(), is_mobile);
+    }
+    let (slot, file_key) = header.unlock_slot(old_password)?;
+    let file_key = Zeroizing::new(file_key);
+    header.verify_file_id(&file_key)?;
+    header.rewrap_slot(slot, new_password, &file_key)?;
+    header.seal_file_id(&file_key);
+
+    let encoded = header.encode();
+    if encoded.len() as u64 != header_len {
+        return Err("Invalid file format".into());
+    }
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&encoded)?;
+    if header.backup {
+        file.seek(SeekFrom::End(-((header_len + header_backup::TRAILER_SUFFIX_SIZE) as i64)))?;
+        header_backup::write_trailer(&mut file, &encoded)?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_add_key_slot(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    new_password_ptr: *const u8,
+    new_password_len: usize,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let new_password = std::slice::from_raw_parts(new_password_ptr, new_password_len);
+
+        match add_key_slot(Path::new(path), password, new_password) {
+            Ok(slot) => slot as i32,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_remove_key_slot(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    slot_index: usize,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match remove_key_slot(Path::new(path), password, slot_index) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_key_slot_count(path_ptr: *const c_char) -> i32 {
+    let path = match unsafe { CStr::from_ptr(path_ptr) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return errors::invalid_argument(),
+    };
+    match read_header(Path::new(path)) {
+        Ok((header, _)) => header.slot_count() as i32,
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+// The hint is covered by the header MAC, so changing it takes the password
+// even though the body is left alone.
+#[no_mangle]
+pub extern "C" fn kyrie_set_hint(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let hint = match CStr::from_ptr(hint_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match set_hint(Path::new(path), password, hint) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_remove_hint(path_ptr: *const c_char, password_ptr: *const u8, password_len: usize) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match remove_hint(Path::new(path), password) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn set_hint(path: &Path, password: &[u8], hint: &str) -> Result<(), Box<dyn std::error::Error>> {
+    rewrite_header(path, password, |header, _| {
+        header.hint = text::hint_bytes(Some(hint));
+        Ok(())
+    })
+}
+
+pub fn remove_hint(path: &Path, password: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    set_hint(path, password, "")
+}
+
+// Returns the index of the new slot.
+pub fn add_key_slot(path: &Path, password: &[u8], new_password: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut index = 0;
+    rewrite_header(path, password, |header, file_key| {
+        if header.slot_count() == 0 {
+            return Err("File has no key slots; change its password first".into());
+        }
+        index = header.slot_count();
+        header.rewrap_slot(index, new_password, file_key)
+    })?;
+    Ok(index)
+}
+
+pub fn remove_key_slot(path: &Path, password: &[u8], slot_index: usize) -> Result<(), Box<dyn std::error::Error>> {
+    rewrite_header(path, password, |header, _| header.remove_slot(slot_index))
+}
+
+// Applies a change to the header of a password-protected file and writes
+// it back in front of the unchanged body and parity.
+pub fn rewrite_header<F>(path: &Path, password: &[u8], change: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&mut ContainerHeader, &[u8; 32]) -> Result<(), Box<dyn std::error::Error>>,
+{
+    rewrite_container(path, password, change, None)
+}
+
+pub type ParityWriter<'a> = dyn FnMut(&mut File) -> Result<(), Box<dyn std::error::Error>> + 'a;
+
+// As rewrite_header, with any existing parity replaced by what
+// `write_parity` writes after the body.
+pub fn rewrite_container<F>(
+    path: &Path,
+    password: &[u8],
+    change: F,
+    write_parity: Option<&mut ParityWriter>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&mut ContainerHeader, &[u8; 32]) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let _lock = file_lock::lock_output(path)?;
+    let mut input = BufReader::new(File::open(path)?);
+    let (mut header, data_start) = ContainerHeader::read_recovering(&mut input)?;
+    if header.dual() || header.share_policy.is_some() {
+        return Err("File is not password-protected".into());
+    }
+    let file_len = input.get_ref().metadata()?.len();
+    let kept_end = match write_parity {
+        Some(_) => header.body_end(data_start, file_len),
+        None => header.trailer_start(data_start, file_len),
+    };
+    let master = Zeroizing::new(header.master_key(password)?);
+    header.verify_file_id(&master).map_err(|_| "Invalid password")?;
+    change(&mut header, &master)?;
+    header.seal_file_id(&master);
+    if header.encode().len() > MAX_HEADER_LEN {
+        return Err("Header too large".into());
+    }
+
+    let mut temp = SecureTempFile::for_target(path, TempContents::Ciphertext)?;
+    write_header(temp.file(), &header)?;
+    input.seek(SeekFrom::Start(data_start))?;
+    io::copy(&mut (&mut input).take(kept_end.saturating_sub(data_start)), temp.file())?;
+    if let Some(write_parity) = write_parity {
+        write_parity(temp.file())?;
+    }
+    if header.backup {
+        header_backup::write_trailer(temp.file(), &header.encode())?;
+    }
+    temp.file().flush()?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+pub fn reencrypt_file_internal(
+    input_path: &str,
+    output_path: &str,
+    old_password: &[u8],
+    new_password: &[u8],
+    options: &ReencryptOptions,
+    is_mobile: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let in_place = secure_temp::same_file(Path::new(input_path), Path::new(output_path));
+    let parity = read_header(Path::new(input_path))?.0.parity;
+
+    if in_place {
+        let mut temp = SecureTempFile::for_target(Path::new(output_path), TempContents::Ciphertext)?;
+        write_reencrypted(input_path, temp.file(), old_password, new_password, options, is_mobile)?;
+        temp.persist(Path::new(output_path))?;
+    } else {
+        let result = File::create(output_path)
+            .map_err(|e| e.into())
+            .and_then(|mut file| write_reencrypted(input_path, &mut file, old_password, new_password, options, is_mobile));
+        if result.is_err() {
+            let _ = fs::remove_file(output_path);
+        }
+        result?;
+    }
+    // The new body gets parity of its own, at the same strength.
+    if let Some(parity) = parity {
+        parity::add_parity(Path::new(output_path), new_password, parity.percent)?;
+    }
+    Ok(())
+}
+
+fn write_reencrypted(
+    input_path: &str,
+    output: &mut File,
+    old_password: &[u8],
+    new_password: &[u8],
+    options: &ReencryptOptions,
+    is_mobile: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut header, _) = read_header(Path::new(input_path))?;
+    if header.dual() {
+        return Err("Dual-slot containers cannot be re-encrypted".into());
+    }
+    if let Some(hint) = options.hint {
+        header.hint = text::hint_bytes(Some(hint));
+    }
+    // The file id survives re-encryption unless upgrading, but the body key
+    // is always a fresh random file key, so counter nonces cannot repeat
+    // under it even with the same id and different chunk boundaries.
+    if options.upgrade {
+        header.file_id = None;
+    }
+    header.nonce_scheme = NONCE_SCHEME_COUNTER;
+    // The metadata, preview, app entries and content hash are sealed under
+    // the old master key, so they are opened before the header changes and
+    // sealed again under the new one.
+    let old_key = Zeroizing::new(header.master_key(old_password)?);
+    let file_metadata = metadata::open(&header, &old_key)?;
+    let thumbnail = preview::open(&header, &old_key)?;
+    let entries = app_metadata::open(&header, &old_key)?;
+    let content_hash = digest::open(&header, &old_key)?;
+    header.key_purpose = KEY_PURPOSE_FILE_DATA;
+    header.use_password_kdf(is_mobile);
+    header.keyfile = false;
+    let key = header.new_master_key(new_password)?;
+    // Escrow slots wrap the old file key, so only the configured agent's
+    // slot can be carried over.
+    header.escrow = escrow::configured_slots(&key)?;
+    // Device slots wrap the old file key; each device enrolls again.
+    header.device_slots.clear();
+    header.parity = None;
+    if let Some(file_metadata) = &file_metadata {
+        header.metadata = metadata::seal(&header, &key, file_metadata)?;
+    }
+    if let Some(thumbnail) = &thumbnail {
+        header.preview = preview::seal(&header, &key, thumbnail)?;
+    }
+    if !entries.is_empty() {
+        header.app_metadata = app_metadata::seal(&header, &key, &entries)?;
+    }
+    if let Some(content_hash) = &content_hash {
+        header.content_digest = digest::seal(&header, &key, content_hash)?;
+    }
+    let cipher = header.body_cipher(&key)?;
+    let layout = read_layout(Path::new(input_path))?;
+    let chunk_count = layout.frames.len();
+    // Chunk boundaries carry over from the old file.
+    let chunk_size = layout.frames[0].len.saturating_sub(TAG_SIZE as u64) as usize;
+    header.set_framing(layout.single_chunk, chunk_size, chunk_count);
+    let input_len = std::fs::metadata(input_path)?.len();
+    key_usage::check(&header.body_key(&key), input_len, chunk_count as u64)?;
+    header.chunk_checksums = if chunk_count <= MAX_CHUNK_CHECKSUMS { vec![0; chunk_count] } else { Vec::new() };
+    header.manifest = Some(Manifest::default());
+    header.seal_file_id(&key);
+    if header.encode().len() > MAX_HEADER_LEN {
+        header.chunk_checksums.clear();
+    }
+    let mut nonce_seq = NonceSequence::for_header(&header, &key)?;
+
+    let mut output_file = BufWriter::new(output);
+    write_header(&mut output_file, &header)?;
+
+    let mut checksums = Vec::with_capacity(chunk_count);
+    let mut bytes = 0;
+    // Chunks come back with their padding, which the manifest length leaves out.
+    let mut manifest = ManifestBuilder::default();
+    let mut stripper = PaddingStripper::new(header.padding);
+    for_each_decrypted_chunk(input_path, old_password, is_mobile, |chunk, single_chunk| {
+        let nonce_bytes = nonce_seq.next_nonce()?;
+        let encrypted = cipher.encrypt(Nonce::from_slice(&nonce_bytes), chunk).map_err(|_| "Encryption failed")?;
+        manifest.record_frame(&encrypted);
+        checksums.push(write_encrypted_frame(&mut output_file, nonce_seq.stored(&nonce_bytes), &encrypted, single_chunk)?);
+        stripper.feed(chunk, |content| {
+            manifest.record_plaintext(content.len());
+            Ok(())
+        })?;
+        bytes += chunk.len() as u64;
+        Ok(())
+    })?;
+    stripper.finish()?;
+    key_usage::record(&header.body_key(&key), bytes, checksums.len() as u64)?;
+
+    output_file.flush()?;
+    let output = output_file.into_inner().map_err(|e| e.into_error())?;
+    if !header.chunk_checksums.is_empty() {
+        if checksums.len() != chunk_count {
+            return Err("Invalid file format".into());
+        }
+        header.chunk_checksums = checksums;
+    }
+    header.manifest = Some(manifest.finish());
+    header.seal_file_id(&key);
+    output.seek(SeekFrom::Start(0))?;
+    write_header(output, &header)?;
+    if header.backup {
+        output.seek(SeekFrom::End(0))?;
+        header_backup::write_trailer(output, &header.encode())?;
+    }
+    output.sync_all()?;
+    Ok(())
+}
+
+pub fn rekey_in_place(path: &Path, password: &[u8], is_mobile: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let path = path.to_str().ok_or("Invalid path")?;
+    reencrypt_file_internal(path, path, password, password, &ReencryptOptions::from_policy(), is_mobile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_to_memory_internal, encrypt_file_internal, get_hint_from_file_internal};
+
+    #[test]
+    fn test_reencrypt_changes_password_and_hint() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let original = dir.path().join("original.kyl");
+        let rekeyed = dir.path().join("rekeyed.kyl");
+        fs::write(&plain, b"trans-encrypted content").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), original.to_str().unwrap(), b"old", Some("old hint"), false, 4)
+            .unwrap();
+
+        let options = ReencryptOptions { hint: Some("new hint"), ..Default::default() };
+        reencrypt_file_internal(
+            original.to_str().unwrap(),
+            rekeyed.to_str().unwrap(),
+            b"old",
+            b"new",
+            &options,
+            false,
+        )
+        .unwrap();
+
+        let rekeyed = rekeyed.to_str().unwrap();
+        assert!(decrypt_file_to_memory_internal(rekeyed, b"old", false, 4).is_err());
+        assert_eq!(decrypt_file_to_memory_internal(rekeyed, b"new", false, 4).unwrap(), b"trans-encrypted content");
+        assert_eq!(get_hint_from_file_internal(rekeyed).unwrap(), b"new hint");
+    }
+
+    #[test]
+    fn test_hint_edits_keep_the_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("file.kyl");
+        fs::write(&plain, b"content").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", Some("short"), false, 4).unwrap();
+        let body_len = fs::metadata(&encrypted).unwrap().len() - read_header(&encrypted).unwrap().1;
+
+        // Twelve three-byte characters no longer fit the fixed slot.
+        let long_hint = "\u{6211}\u{7684}\u{7b2c}\u{4e00}\u{53ea}\u{732b}\u{7684}\u{540d}\u{5b57}\u{548c}\u{751f}\u{65e5}";
+        set_hint(&encrypted, b"pw", long_hint).unwrap();
+        let path = encrypted.to_str().unwrap();
+        assert_eq!(get_hint_from_file_internal(path).unwrap(), long_hint.as_bytes());
+        assert_eq!(decrypt_file_to_memory_internal(path, b"pw", false, 4).unwrap(), b"content");
+        assert_eq!(fs::metadata(&encrypted).unwrap().len() - read_header(&encrypted).unwrap().1, body_len);
+
+        assert!(set_hint(&encrypted, b"wrong", "phished").is_err());
+        remove_hint(&encrypted, b"pw").unwrap();
+        assert!(get_hint_from_file_internal(path).unwrap().is_empty());
+        assert_eq!(decrypt_file_to_memory_internal(path, b"pw", false, 4).unwrap(), b"content");
+    }
+
+    #[test]
+    fn test_reencrypt_wrong_password_keeps_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("file.kyl");
+        fs::write(&plain, b"content").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        let before = fs::read(&encrypted).unwrap();
+
+        let path = encrypted.to_str().unwrap();
+        assert!(reencrypt_file_internal(path, path, b"wrong", b"new", &ReencryptOptions::default(), false).is_err());
+        assert_eq!(fs::read(&encrypted).unwrap(), before);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_change_password_keeps_the_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("file.kyl");
+        fs::write(&plain, b"wrapped content").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"old", None, false, 4).unwrap();
+        let (header, header_len) = read_header(&encrypted).unwrap();
+        assert!(header.wrapped_key.is_some());
+        let before = fs::read(&encrypted).unwrap();
+
+        assert!(change_password(&encrypted, b"wrong", b"new", false).is_err());
+        assert_eq!(fs::read(&encrypted).unwrap(), before);
+        change_password(&encrypted, b"old", b"new", false).unwrap();
+        let after = fs::read(&encrypted).unwrap();
+        assert_eq!(after[header_len as usize..], before[header_len as usize..]);
+        assert_ne!(after[..header_len as usize], before[..header_len as usize]);
+
+        let path = encrypted.to_str().unwrap();
+        assert!(decrypt_file_to_memory_internal(path, b"old", false, 4).is_err());
+        assert_eq!(decrypt_file_to_memory_internal(path, b"new", false, 4).unwrap(), b"wrapped content");
+    }
+
+    #[test]
+    fn test_key_slots_each_open_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("file.kyl");
+        fs::write(&plain, b"shared content").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"alice", None, false, 4).unwrap();
+        let path = encrypted.to_str().unwrap();
+
+        assert!(add_key_slot(&encrypted, b"wrong", b"bob").is_err());
+        assert_eq!(add_key_slot(&encrypted, b"alice", b"bob").unwrap(), 1);
+        assert_eq!(add_key_slot(&encrypted, b"bob", b"carol").unwrap(), 2);
+        assert_eq!(read_header(&encrypted).unwrap().0.slot_count(), 3);
+        for password in [&b"alice"[..], b"bob", b"carol"] {
+            assert_eq!(decrypt_file_to_memory_internal(path, password, false, 4).unwrap(), b"shared content");
+        }
+
+        // Changing a password only touches the slot it opens.
+        change_password(&encrypted, b"bob", b"robert", false).unwrap();
+        assert!(decrypt_file_to_memory_internal(path, b"bob", false, 4).is_err());
+        assert_eq!(decrypt_file_to_memory_internal(path, b"alice", false, 4).unwrap(), b"shared content");
+
+        remove_key_slot(&encrypted, b"robert", 0).unwrap();
+        assert!(decrypt_file_to_memory_internal(path, b"alice", false, 4).is_err());
+        assert_eq!(decrypt_file_to_memory_internal(path, b"robert", false, 4).unwrap(), b"shared content");
+        remove_key_slot(&encrypted, b"carol", 1).unwrap();
+        assert!(remove_key_slot(&encrypted, b"robert", 0).is_err());
+        assert_eq!(decrypt_file_to_memory_internal(path, b"robert", false, 4).unwrap(), b"shared content");
+    }
+}
@@ -0,0 +1,389 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use crate::cipher::BodyCipher;
+use crate::errors;
+use crate::format::{read_header, read_layout, ChunkLayout, ContainerHeader};
+use crate::header_backup;
+use crate::parity::{self, ParityCheck};
+use crate::secure_temp::{SecureTempFile, TempContents};
+use crate::vault::Vault;
+
+#[derive(Default, Debug)]
+pub struct RepairReport {
+    pub total_chunks: u64,
+    pub damaged_chunks: Vec<u64>,
+    // Frames rebuilt from parity name the repaired file itself.
+    pub repaired: Vec<(u64, PathBuf)>,
+    pub unrecovered_chunks: Vec<u64>,
+    // Set for vault entries, whose manifest digest is checked after repair.
+    pub digest_verified: Option<bool>,
+    pub header_restored: bool,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieRepairReport {
+    pub total_chunks: u64,
+    pub damaged_chunks: u64,
+    pub repaired_chunks: u64,
+    pub unrecovered_chunks: u64,
+    pub digest_checked: bool,
+    pub digest_verified: bool,
+    pub header_restored: bool,
+}
+
+impl From<&RepairReport> for KyrieRepairReport {
+    fn from(report: &RepairReport) -> Self {
+        KyrieRepairReport {
+            total_chunks: report.total_chunks,
+            damaged_chunks: report.damaged_chunks.len() as u64,
+            repaired_chunks: report.repaired.len() as u64,
+            unrecovered_chunks: report.unrecovered_chunks.len() as u64,
+            digest_checked: report.digest_verified.is_some(),
+            digest_verified: report.digest_verified.unwrap_or(false),
+            header_restored: report.header_restored,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn repair_file(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    copy_paths_ptr: *const *const c_char,
+    copy_count: usize,
+    report_ptr: *mut KyrieRepairReport,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = (!password_ptr.is_null()).then(|| std::slice::from_raw_parts(password_ptr, password_len));
+        let copies = match path_list(copy_paths_ptr, copy_count) {
+            Some(copies) => copies,
+            None => return errors::invalid_argument(),
+        };
+
+        match repair_file_internal(Path::new(path), password, &copies) {
+            Ok(report) => {
+                if !report_ptr.is_null() {
+                    *report_ptr = KyrieRepairReport::from(&report);
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vault_repair_entry(
+    vault_dir_ptr: *const c_char,
+    file_name_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    copy_paths_ptr: *const *const c_char,
+    copy_count: usize,
+    report_ptr: *mut KyrieRepairReport,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let file_name = match CStr::from_ptr(file_name_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = (!password_ptr.is_null()).then(|| std::slice::from_raw_parts(password_ptr, password_len));
+        let copies = match path_list(copy_paths_ptr, copy_count) {
+            Some(copies) => copies,
+            None => return errors::invalid_argument(),
+        };
+
+        match repair_vault_entry(Path::new(vault_dir), file_name, password, &copies) {
+            Ok(report) => {
+                if !report_ptr.is_null() {
+                    *report_ptr = KyrieRepairReport::from(&report);
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+unsafe fn path_list(ptrs: *const *const c_char, count: usize) -> Option<Vec<PathBuf>> {
+    if count == 0 {
+        return Some(Vec::new());
+    }
+    if ptrs.is_null() {
+        return None;
+    }
+    std::slice::from_raw_parts(ptrs, count)
+        .iter()
+        .map(|&ptr| CStr::from_ptr(ptr).to_str().ok().map(PathBuf::from))
+        .collect()
+}
+
+// Damaged entries are patched from other vault entries recorded with the
+// same digest, then from the caller's copies.
+pub fn repair_vault_entry(
+    vault_dir: &Path,
+    file_name: &str,
+    password: Option<&[u8]>,
+    copies: &[PathBuf],
+) -> Result<RepairReport, Box<dyn std::error::Error>> {
+    let vault = Vault::load(vault_dir)?;
+    let entry = vault.entries().iter().find(|e| e.file_name == file_name).ok_or("Unknown vault entry")?;
+    let digest_of = |e: &crate::vault::VaultEntry| if e.blake3.is_empty() { e.sha256.clone() } else { e.blake3.clone() };
+    let digest = digest_of(entry);
+    let mut sources: Vec<PathBuf> = vault
+        .entries()
+        .iter()
+        .filter(|other| other.file_name != file_name && !digest.is_empty() && digest_of(other) == digest)
+        .map(|other| vault.entry_path(&other.file_name))
+        .collect();
+    sources.extend_from_slice(copies);
+
+    let path = vault.entry_path(file_name);
+    let mut report = repair_file_internal(&path, password, &sources)?;
+    report.digest_verified = Some(entry.matches(&path).map_err(|e| e.to_string())?);
+    Ok(report)
+}
+
+// Finds damaged frames with the CRC index or, given the password, by
+// authenticating every frame, and replaces each from the first copy whose
+// frame at the same offset passes the same checks. Files with parity have
+// their damaged sectors rebuilt first, before anything is authenticated.
+pub fn repair_file_internal(
+    path: &Path,
+    password: Option<&[u8]>,
+    copies: &[PathBuf],
+) -> Result<RepairReport, Box<dyn std::error::Error>> {
+    let (header, data_start) = read_header(path)?;
+    let file_len = fs::metadata(path)?.len();
+    let canonical = fs::canonicalize(path)?;
+    let copies: Vec<&PathBuf> = copies
+        .iter()
+        .filter(|copy| fs::metadata(copy).map(|m| m.len() == file_len).unwrap_or(false))
+        .filter(|copy| fs::canonicalize(copy).map(|c| c != canonical).unwrap_or(false))
+        .collect();
+
+    let layout = reference_layout(path, header.chunk_checksums.len(), &copies)?;
+    let mut reader = BufReader::new(File::open(path)?);
+    let (layout, cipher) = match password {
+        Some(password) => {
+            if layout.dual {
+                return Err("Dual-slot containers cannot be repaired".into());
+            }
+            let key = header.master_key(password)?;
+            header.verify_file_id(&key)?;
+            let layout = layout.unlock(&mut reader, &key)?;
+            let cipher = layout.body_cipher(&key)?;
+            (layout, Some(cipher))
+        }
+        None if header.chunk_checksums.is_empty() && header.parity.is_none() => {
+            return Err("Repair needs a password, a chunk index or parity".into())
+        }
+        None => (layout, None),
+    };
+    let check = FrameCheck {
+        layout: &layout,
+        checksums: &header.chunk_checksums,
+        cipher,
+    };
+
+    // read_header already fell back to the backup if the header at the
+    // start of the file is unreadable; that copy is written back in place.
+    reader.seek(SeekFrom::Start(0))?;
+    let restored_header = match ContainerHeader::read(&mut reader) {
+        Ok(_) => None,
+        Err(_) => header_backup::read_trailer(&mut reader)?,
+    };
+    let mut report = RepairReport {
+        total_chunks: layout.frames.len() as u64,
+        header_restored: restored_header.is_some(),
+        ..Default::default()
+    };
+    let mut patches: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut block_offset = header.body_end(data_start, file_len);
+    for index in 0..layout.frames.len() {
+        let frame = &layout.frames[index];
+        let frame_len = layout.frame_size(frame);
+        let parity_check = header.parity.as_ref().map(|parity| {
+            let check = parity::check_frame(&mut reader, frame.offset, frame_len, block_offset, parity);
+            block_offset += parity::block_len(frame_len, parity);
+            check
+        });
+        let damaged = match parity_check {
+            Some(ParityCheck::Intact) => false,
+            Some(ParityCheck::Repaired { frame: repaired, block, frame_damaged }) => {
+                if frame_damaged {
+                    report.damaged_chunks.push(index as u64);
+                    report.repaired.push((index as u64, path.to_path_buf()));
+                    patches.push((frame.offset, repaired));
+                }
+                patches.push((block_offset - block.len() as u64, block));
+                false
+            }
+            Some(ParityCheck::Unrecoverable) => true,
+            None => !check.frame_ok(index, &mut reader),
+        };
+        if !damaged {
+            continue;
+        }
+        report.damaged_chunks.push(index as u64);
+        let source = copies.iter().find(|copy| {
+            File::open(copy).map(|file| check.frame_ok(index, &mut BufReader::new(file))).unwrap_or(false)
+        });
+        match source {
+            Some(copy) => {
+                patches.push((frame.offset, read_region(&mut File::open(copy)?, frame.offset, frame_len)?));
+                report.repaired.push((index as u64, (*copy).clone()));
+            }
+            None => report.unrecovered_chunks.push(index as u64),
+        }
+    }
+
+    if !patches.is_empty() || restored_header.is_some() {
+        let mut temp = SecureTempFile::for_target(path, TempContents::Ciphertext)?;
+        reader.seek(SeekFrom::Start(0))?;
+        io::copy(&mut reader, temp.file())?;
+        if let Some(header_bytes) = &restored_header {
+            temp.file().seek(SeekFrom::Start(0))?;
+            temp.file().write_all(header_bytes)?;
+        }
+        for (offset, region) in patches {
+            temp.file().seek(SeekFrom::Start(offset))?;
+            temp.file().write_all(&region)?;
+        }
+        temp.persist(path)?;
+    }
+    Ok(report)
+}
+
+// A damaged length field changes how the body splits into frames, so the
+// frame boundaries may have to come from an intact copy.
+fn reference_layout(path: &Path, indexed_chunks: usize, copies: &[&PathBuf]) -> Result<ChunkLayout, Box<dyn std::error::Error>> {
+    let fits = |layout: &ChunkLayout| indexed_chunks == 0 || layout.frames.len() == indexed_chunks;
+    std::iter::once(path)
+        .chain(copies.iter().map(|copy| copy.as_path()))
+        .filter_map(|candidate| read_layout(candidate).ok())
+        .find(fits)
+        .ok_or_else(|| "Invalid file format".into())
+}
+
+struct FrameCheck<'a> {
+    layout: &'a ChunkLayout,
+    checksums: &'a [u32],
+    cipher: Option<BodyCipher>,
+}
+
+impl FrameCheck<'_> {
+    fn frame_ok<R: Read + Seek>(&self, index: usize, reader: &mut R) -> bool {
+        let frame = &self.layout.frames[index];
+        if let Some(expected) = self.checksums.get(index) {
+            match read_region(reader, frame.offset, self.layout.frame_size(frame)) {
+                Ok(region) if crc32fast::hash(&region) == *expected => {}
+                _ => return false,
+            }
+        }
+        match &self.cipher {
+            Some(cipher) => match self.layout.read_frame(reader, frame) {
+                Ok((nonce, ciphertext)) => cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref()).is_ok(),
+                Err(_) => false,
+            },
+            None => true,
+        }
+    }
+}
+
+fn read_region<R: Read + Seek>(reader: &mut R, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut region = vec![0u8; len as usize];
+    reader.read_exact(&mut region)?;
+    Ok(region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::range::decrypt_prefix_internal;
+    use crate::test_util::{write_framed_container, write_framed_container_with};
+
+    fn damage(path: &Path, chunks: &[usize]) {
+        let layout = read_layout(path).unwrap();
+        let mut bytes = fs::read(path).unwrap();
+        for &index in chunks {
+            let frame = &layout.frames[index];
+            bytes[(frame.offset + layout.frame_size(frame) - 1) as usize] ^= 0xFF;
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_repair_pulls_frames_from_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("photos.kyl");
+        let chunks: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i; 16]).collect();
+        let chunk_refs: Vec<&[u8]> = chunks.iter().map(|c| c.as_slice()).collect();
+        write_framed_container(&original, b"pw", &chunk_refs);
+        let first_copy = dir.path().join("backup1.kyl");
+        let second_copy = dir.path().join("backup2.kyl");
+        fs::copy(&original, &first_copy).unwrap();
+        fs::copy(&original, &second_copy).unwrap();
+        damage(&original, &[1, 4, 5]);
+        damage(&first_copy, &[4, 5]);
+        damage(&second_copy, &[1, 5]);
+
+        assert!(repair_file_internal(&original, None, &[]).is_err());
+        let report = repair_file_internal(&original, Some(b"pw"), &[first_copy.clone(), second_copy.clone()]).unwrap();
+        assert_eq!(report.damaged_chunks, vec![1, 4, 5]);
+        assert_eq!(report.repaired, vec![(1, first_copy), (4, second_copy)]);
+        assert_eq!(report.unrecovered_chunks, vec![5]);
+
+        let report = repair_file_internal(&original, Some(b"pw"), &[]).unwrap();
+        assert_eq!(report.damaged_chunks, vec![5]);
+    }
+
+    #[test]
+    fn test_repair_restores_header_from_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.kyl");
+        let header = ContainerHeader { backup: true, ..Default::default() };
+        write_framed_container_with(&path, b"pw", &header, &[b"first", b"second"]);
+        let intact = fs::read(&path).unwrap();
+        let mut bytes = intact.clone();
+        bytes[3] ^= 0xFF;
+        fs::write(&path, bytes).unwrap();
+
+        let report = repair_file_internal(&path, Some(b"pw"), &[]).unwrap();
+        assert!(report.header_restored);
+        assert!(report.damaged_chunks.is_empty());
+        assert_eq!(fs::read(&path).unwrap(), intact);
+        assert!(!repair_file_internal(&path, Some(b"pw"), &[]).unwrap().header_restored);
+    }
+
+    #[test]
+    fn test_vault_repair_uses_duplicate_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunks: [&[u8]; 3] = [b"first", b"second", b"third"];
+        write_framed_container(&dir.path().join("a.kyl"), b"pw", &chunks);
+        fs::copy(dir.path().join("a.kyl"), dir.path().join("b.kyl")).unwrap();
+        Vault::open(dir.path()).unwrap().save().unwrap();
+        damage(&dir.path().join("a.kyl"), &[2]);
+
+        let report = repair_vault_entry(dir.path(), "a.kyl", Some(b"pw"), &[]).unwrap();
+        assert_eq!(report.repaired, vec![(2, dir.path().join("b.kyl"))]);
+        assert_eq!(report.digest_verified, Some(true));
+        assert_eq!(decrypt_prefix_internal(&dir.path().join("a.kyl"), b"pw", 100).unwrap(), b"firstsecondthird");
+    }
+}
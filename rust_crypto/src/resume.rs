@@ -0,0 +1,438 @@
+use aes_gcm::{aead::AeadInPlace, Nonce};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use zeroize::Zeroizing;
+
+use crate::cipher::BodyCipher;
+use crate::config::{self, KyrieConfig};
+use crate::format::{self, ContainerHeader, NONCE_SCHEME_COUNTER};
+use crate::manifest::{Manifest, ManifestBuilder};
+use crate::metadata::{self, FileMetadata};
+use crate::nonce::{self, PREFIX_SIZE};
+use crate::progress::ProgressSink;
+use crate::secure_temp::{self, sidecar_path, SecureTempFile, TempContents};
+use crate::{
+    encrypt_file_reporting, errors, escrow, header_backup, key_usage, pipeline, text, write_encrypted_frame, write_header,
+    EncryptOptions,
+};
+
+const PART_SUFFIX: &str = "kyriepart";
+const STATE_SUFFIX: &str = "kyriestate";
+
+// Rewritten after every batch, once the frames it covers are on disk.
+// Nonces are derived from the frame index, so the chunk count is also the
+// count of nonces used, and the header hash ties the state to the one
+// partial output it was written for.
+#[derive(Serialize, Deserialize, Debug)]
+struct ResumeState {
+    input_len: u64,
+    input_modified: Option<u64>,
+    header_hash: String,
+    chunk_size: usize,
+    chunks_done: u64,
+    sealed_len: u64,
+    checksums: Vec<u32>,
+    manifest: ManifestBuilder,
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_resumable(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_resumable(Path::new(input_path), Path::new(output_path), password, hint, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn encrypt_file_resume(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match resume_encryption(Path::new(input_path), Path::new(output_path), password, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Returns 1 when an interrupted run for this output can be resumed.
+#[no_mangle]
+pub extern "C" fn kyrie_resume_pending(output_path_ptr: *const c_char) -> i32 {
+    match unsafe { CStr::from_ptr(output_path_ptr) }.to_str() {
+        Ok(path) => resume_pending(Path::new(path)) as i32,
+        Err(_) => errors::invalid_argument(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_resume_discard(output_path_ptr: *const c_char) -> i32 {
+    let path = match unsafe { CStr::from_ptr(output_path_ptr) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return errors::invalid_argument(),
+    };
+    match discard_resume(Path::new(path)) {
+        Ok(_) => 0,
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+// Files that fit in one chunk are sealed in a single step, so there is
+// nothing to checkpoint and they take the regular path.
+pub fn encrypt_resumable(
+    input: &Path,
+    output: &Path,
+    password: &[u8],
+    hint: Option<&str>,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = KyrieConfig::current();
+    let chunk_size = config.chunk_size(is_mobile);
+    if fs::metadata(input)?.len() <= chunk_size as u64 {
+        let options = EncryptOptions { hint, ..Default::default() };
+        let input = input.to_str().ok_or("Invalid path")?;
+        let output = output.to_str().ok_or("Invalid path")?;
+        encrypt_file_reporting(input, output, password, &options, is_mobile, cpu_cores, &ProgressSink::none())?;
+        return Ok(());
+    }
+    let batch_size = config.parallel_batch_size(cpu_cores, is_mobile);
+    ResumableJob::begin(input, output, password, hint, chunk_size, batch_size, is_mobile)?.run()
+}
+
+pub fn resume_encryption(
+    input: &Path,
+    output: &Path,
+    password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let batch_size = KyrieConfig::current().parallel_batch_size(cpu_cores, is_mobile);
+    ResumableJob::resume(input, output, password, batch_size)?.run()
+}
+
+pub fn resume_pending(output: &Path) -> bool {
+    sidecar_path(output, STATE_SUFFIX).exists()
+}
+
+// The partial output only ever holds ciphertext, so it is removed rather
+// than shredded.
+pub fn discard_resume(output: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    for suffix in [STATE_SUFFIX, PART_SUFFIX] {
+        let path = sidecar_path(output, suffix);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+struct ResumableJob {
+    output: PathBuf,
+    reader: BufReader<File>,
+    part: File,
+    header: ContainerHeader,
+    key: Zeroizing<[u8; 32]>,
+    cipher: BodyCipher,
+    prefix: [u8; PREFIX_SIZE],
+    batch_size: usize,
+    state: ResumeState,
+}
+
+impl ResumableJob {
+    fn new(
+        input: &Path,
+        output: &Path,
+        mut part: File,
+        header: ContainerHeader,
+        key: Zeroizing<[u8; 32]>,
+        state: ResumeState,
+        batch_size: usize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // Anything past the checkpoint is from a batch that never finished.
+        part.set_len(state.sealed_len)?;
+        part.seek(SeekFrom::Start(state.sealed_len))?;
+        let mut input = File::open(input)?;
+        input.seek(SeekFrom::Start(state.chunks_done * state.chunk_size as u64))?;
+        let file_id = header.file_id.as_ref().ok_or("Invalid file format")?;
+        Ok(ResumableJob {
+            output: output.to_path_buf(),
+            reader: BufReader::new(input),
+            part,
+            cipher: header.body_cipher(&key)?,
+            prefix: nonce::counter_prefix(&header.body_key(&key), file_id),
+            header,
+            key,
+            batch_size,
+            state,
+        })
+    }
+
+    // The header is sized up front the way the regular pipeline does it, by
+    // reserving the checksum index and manifest, so finishing only has to
+    // overwrite it.
+    fn begin(
+        input: &Path,
+        output: &Path,
+        password: &[u8],
+        hint: Option<&str>,
+        chunk_size: usize,
+        batch_size: usize,
+        is_mobile: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        secure_temp::check_target(output)?;
+        discard_resume(output)?;
+        let input_metadata = fs::metadata(input)?;
+        let content_len = input_metadata.len();
+        let chunk_count = content_len.div_ceil(chunk_size as u64) as usize;
+
+        let mut header = ContainerHeader {
+            hint: text::hint_bytes(hint),
+            nonce_scheme: NONCE_SCHEME_COUNTER,
+            backup: header_backup::enabled(),
+            ..Default::default()
+        };
+        header.use_password_kdf(is_mobile);
+        header.set_framing(false, chunk_size, chunk_count);
+        let key = Zeroizing::new(header.new_master_key(password)?);
+        header.escrow = escrow::configured_slots(&key)?;
+        header.metadata = metadata::seal(&header, &key, &FileMetadata::for_input(input)?)?;
+        key_usage::check(&header.body_key(&key), content_len, chunk_count as u64)?;
+        if chunk_count <= format::MAX_CHUNK_CHECKSUMS {
+            header.chunk_checksums = vec![0; chunk_count];
+        }
+        header.manifest = Some(Manifest::default());
+        header.seal_file_id(&key);
+
+        let encoded = header.encode();
+        let mut part = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(sidecar_path(output, PART_SUFFIX))?;
+        part.write_all(&encoded)?;
+        part.sync_data()?;
+
+        let mut manifest = ManifestBuilder::default();
+        manifest.record_plaintext(content_len as usize);
+        let state = ResumeState {
+            input_len: content_len,
+            input_modified: modified_nanos(&input_metadata),
+            header_hash: hex::encode(Sha256::digest(&encoded)),
+            chunk_size,
+            chunks_done: 0,
+            sealed_len: encoded.len() as u64,
+            checksums: Vec::with_capacity(chunk_count),
+            manifest,
+        };
+        write_state(output, &state)?;
+        ResumableJob::new(input, output, part, header, key, state, batch_size)
+    }
+
+    // The input and the partial output both have to be the ones the
+    // checkpoint was written for before a single frame is added.
+    fn resume(input: &Path, output: &Path, password: &[u8], batch_size: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let state = load_state(output)?.ok_or("No resumable encryption is pending")?;
+        let input_metadata = fs::metadata(input)?;
+        if input_metadata.len() != state.input_len || modified_nanos(&input_metadata) != state.input_modified {
+            return Err("Input file changed during encryption".into());
+        }
+
+        let mut part = OpenOptions::new().read(true).write(true).open(sidecar_path(output, PART_SUFFIX))?;
+        let (header, header_len) = ContainerHeader::read(&mut part)?;
+        let mut encoded = vec![0u8; header_len as usize];
+        part.seek(SeekFrom::Start(0))?;
+        part.read_exact(&mut encoded)?;
+        if hex::encode(Sha256::digest(&encoded)) != state.header_hash || part.metadata()?.len() < state.sealed_len {
+            return Err("Partial output does not match its checkpoint".into());
+        }
+
+        let key = Zeroizing::new(header.master_key(password)?);
+        header.verify_file_id(&key)?;
+        ResumableJob::new(input, output, part, header, key, state, batch_size)
+    }
+
+    fn run(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        while self.step()? {}
+        self.finish()
+    }
+
+    // Seals the next batch and moves the checkpoint past it. Returns false
+    // once the input is used up.
+    fn step(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let first_index = self.state.chunks_done;
+        let mut batch = pipeline::read_plain_batch(
+            &mut self.reader,
+            self.state.chunk_size,
+            self.batch_size,
+            &ProgressSink::none(),
+            first_index as usize,
+        )?;
+        if batch.is_empty() {
+            return Ok(false);
+        }
+
+        let nonces = (first_index..first_index + batch.len() as u64)
+            .map(|index| nonce::counter_nonce(&self.prefix, index))
+            .collect::<Result<Vec<_>, _>>()?;
+        let cipher = &self.cipher;
+        config::install(|| {
+            batch.par_iter_mut().zip(nonces.par_iter()).try_for_each(|(chunk, nonce_bytes)| {
+                cipher.encrypt_in_place(Nonce::from_slice(nonce_bytes), b"", chunk).map_err(|_| "Encryption failed")
+            })
+        })?;
+
+        let mut output = BufWriter::new(&mut self.part);
+        for encrypted in &batch {
+            self.state.manifest.record_frame(encrypted);
+            self.state.checksums.push(write_encrypted_frame(&mut output, &[], encrypted, false)?);
+        }
+        output.flush()?;
+        drop(output);
+        self.part.sync_data()?;
+
+        self.state.chunks_done += batch.len() as u64;
+        self.state.sealed_len = self.part.stream_position()?;
+        write_state(&self.output, &self.state)?;
+        Ok(true)
+    }
+
+    fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let chunk_count = self.state.input_len.div_ceil(self.state.chunk_size as u64);
+        if self.state.chunks_done != chunk_count {
+            return Err("Input file changed during encryption".into());
+        }
+        if !self.header.chunk_checksums.is_empty() {
+            self.header.chunk_checksums = std::mem::take(&mut self.state.checksums);
+        }
+        self.header.manifest = Some(self.state.manifest.finish());
+        self.header.seal_file_id(&self.key);
+
+        self.part.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.part, &self.header)?;
+        if self.header.backup {
+            self.part.seek(SeekFrom::End(0))?;
+            header_backup::write_trailer(&mut self.part, &self.header.encode())?;
+        }
+        self.part.sync_all()?;
+        drop(self.part);
+
+        secure_temp::check_target(&self.output)?;
+        fs::rename(sidecar_path(&self.output, PART_SUFFIX), &self.output)?;
+        fs::remove_file(sidecar_path(&self.output, STATE_SUFFIX))?;
+        key_usage::record(&self.header.body_key(&self.key), self.state.input_len, chunk_count)?;
+        Ok(())
+    }
+}
+
+fn modified_nanos(metadata: &fs::Metadata) -> Option<u64> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(modified.as_nanos() as u64)
+}
+
+fn load_state(output: &Path) -> Result<Option<ResumeState>, Box<dyn std::error::Error>> {
+    let state_path = sidecar_path(output, STATE_SUFFIX);
+    if !state_path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(state_path)?)?))
+}
+
+// Replaced atomically, so a run killed mid-write still finds the previous
+// checkpoint.
+fn write_state(output: &Path, state: &ResumeState) -> Result<(), Box<dyn std::error::Error>> {
+    let state_path = sidecar_path(output, STATE_SUFFIX);
+    let mut temp = SecureTempFile::for_target(&state_path, TempContents::Metadata)?;
+    serde_json::to_writer(temp.file(), state)?;
+    temp.persist(&state_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_to_memory_internal;
+
+    fn content() -> Vec<u8> {
+        (0..20_000u32).map(|i| (i * 13) as u8).collect()
+    }
+
+    #[test]
+    fn test_interrupted_encryption_resumes_from_its_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("movie.bin");
+        let encrypted = dir.path().join("movie.kyl");
+        fs::write(&plain, content()).unwrap();
+
+        // One batch of two out of five chunks, then the app is killed.
+        let mut job = ResumableJob::begin(&plain, &encrypted, b"pw", None, 4096, 2, false).unwrap();
+        assert!(job.step().unwrap());
+        drop(job);
+        assert!(resume_pending(&encrypted));
+        assert!(!encrypted.exists());
+        assert!(resume_encryption(&plain, &encrypted, b"wrong", false, 4).is_err());
+
+        resume_encryption(&plain, &encrypted, b"pw", false, 4).unwrap();
+        assert!(!resume_pending(&encrypted));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert_eq!(decrypt_file_to_memory_internal(encrypted.to_str().unwrap(), b"pw", false, 4).unwrap(), content());
+
+        // An input that changed since the checkpoint cannot be continued.
+        let copy = dir.path().join("copy.kyl");
+        let mut job = ResumableJob::begin(&plain, &copy, b"pw", None, 4096, 2, false).unwrap();
+        job.step().unwrap();
+        drop(job);
+        fs::write(&plain, b"something else").unwrap();
+        assert!(resume_encryption(&plain, &copy, b"pw", false, 4).is_err());
+        discard_resume(&copy).unwrap();
+        assert!(!resume_pending(&copy));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
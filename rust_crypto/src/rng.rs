@@ -0,0 +1,92 @@
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+#[cfg(feature = "test-rng")]
+use std::sync::Mutex;
+
+// Where keys, salts and nonces come from. This is always the OS generator
+// unless a seeded one has been swapped in, either for the whole process by a
+// build with the test-rng feature or for one thread by the self test, so
+// that ports on other platforms can reproduce a container byte for byte.
+pub trait Rng: Send {
+    fn fill(&mut self, dest: &mut [u8]);
+}
+
+// Block i is SHA-256 of the seed and i, both u64 little endian, which any
+// port can rebuild in a few lines.
+pub struct SeededRng {
+    seed: u64,
+    counter: u64,
+    block: [u8; 32],
+    used: usize,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        SeededRng { seed, counter: 0, block: [0; 32], used: 32 }
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill(&mut self, dest: &mut [u8]) {
+        for byte in dest {
+            if self.used == self.block.len() {
+                let mut hasher = Sha256::new();
+                hasher.update(self.seed.to_le_bytes());
+                hasher.update(self.counter.to_le_bytes());
+                self.block = hasher.finalize().into();
+                self.counter += 1;
+                self.used = 0;
+            }
+            *byte = self.block[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+#[cfg(feature = "test-rng")]
+static TEST_RNG: Mutex<Option<Box<dyn Rng>>> = Mutex::new(None);
+
+thread_local! {
+    static THREAD_RNG: RefCell<Option<Box<dyn Rng>>> = const { RefCell::new(None) };
+}
+
+// Makes every key, salt and nonce that follows depend only on the seed.
+// Containers written this way are only fit for comparing implementations.
+#[cfg(feature = "test-rng")]
+#[no_mangle]
+pub extern "C" fn kyrie_set_test_rng(seed: u64) {
+    *TEST_RNG.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(SeededRng::new(seed)));
+}
+
+#[cfg(feature = "test-rng")]
+#[no_mangle]
+pub extern "C" fn kyrie_clear_test_rng() {
+    *TEST_RNG.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+pub fn fill(dest: &mut [u8]) {
+    let filled = THREAD_RNG.with(|rng| rng.borrow_mut().as_mut().map(|rng| rng.fill(dest)).is_some());
+    if filled {
+        return;
+    }
+    #[cfg(feature = "test-rng")]
+    if let Some(rng) = TEST_RNG.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        return rng.fill(dest);
+    }
+    rand::thread_rng().fill_bytes(dest);
+}
+
+pub fn random<T: Default + AsMut<[u8]>>() -> T {
+    let mut value = T::default();
+    fill(value.as_mut());
+    value
+}
+
+// Runs `op` with `rng` in place of the OS generator on this thread only.
+pub fn with_rng<R>(rng: Box<dyn Rng>, op: impl FnOnce() -> R) -> R {
+    let previous = THREAD_RNG.with(|slot| slot.borrow_mut().replace(rng));
+    let result = op();
+    THREAD_RNG.with(|slot| *slot.borrow_mut() = previous);
+    result
+}
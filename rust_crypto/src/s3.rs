@@ -0,0 +1,719 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+use crate::cipher::{BodyCipher, CIPHER_AES_256_GCM};
+use crate::config::KyrieConfig;
+use crate::format::ContainerHeader;
+use crate::kdf::{self, KEY_PURPOSE_FILE_DATA};
+use crate::nonce::NonceSequence;
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::secure_temp::{SecureTempFile, TempContents};
+use crate::storage::{self, Storage};
+use crate::vault::unix_now;
+use crate::{errors, text, write_frame, write_header};
+
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+const SIGNING_ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UploadedPart {
+    pub number: u32,
+    pub etag: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadState {
+    pub upload_id: String,
+    pub input_len: u64,
+    pub plaintext_offset: u64,
+    pub parts: Vec<UploadedPart>,
+    // The encoded container header, so a resumed upload unwraps the same
+    // file key. Uploads started before it was kept used the unsalted legacy
+    // key, so they start over instead of adding parts under it.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub header: String,
+}
+
+pub trait MultipartBackend {
+    fn create_upload(&mut self) -> Result<String, Box<dyn std::error::Error>>;
+    fn upload_part(&mut self, upload_id: &str, number: u32, data: &[u8]) -> Result<String, Box<dyn std::error::Error>>;
+    fn complete_upload(&mut self, upload_id: &str, parts: &[UploadedPart]) -> Result<(), Box<dyn std::error::Error>>;
+
+    // Only reading a container back through ObjectStorage needs these.
+    fn object_len(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        Err("Storage read failed".into())
+    }
+
+    fn read_range(&mut self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Box<dyn std::error::Error>> {
+        Err("Storage read failed".into())
+    }
+}
+
+pub struct MultipartSink<'a, B: MultipartBackend> {
+    backend: &'a mut B,
+    state: UploadState,
+    state_path: PathBuf,
+    part_size: usize,
+    buffer: Vec<u8>,
+}
+
+impl<B: MultipartBackend> Write for MultipartSink<'_, B> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, B: MultipartBackend> MultipartSink<'a, B> {
+    pub fn new(backend: &'a mut B, state: UploadState, state_path: &Path, part_size: usize) -> Self {
+        MultipartSink {
+            backend,
+            state,
+            state_path: state_path.to_path_buf(),
+            part_size: part_size.max(MIN_PART_SIZE),
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn commit_point(&mut self, plaintext_offset: u64) -> Result<(), Box<dyn std::error::Error>> {
+        if self.buffer.len() >= self.part_size {
+            self.upload_buffer(plaintext_offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.buffer.is_empty() || self.state.parts.is_empty() {
+            let offset = self.state.input_len;
+            self.upload_buffer(offset)?;
+        }
+        self.backend.complete_upload(&self.state.upload_id, &self.state.parts)?;
+        let _ = fs::remove_file(&self.state_path);
+        Ok(())
+    }
+
+    fn upload_buffer(&mut self, plaintext_offset: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let number = self.state.parts.len() as u32 + 1;
+        let etag = self.backend.upload_part(&self.state.upload_id, number, &self.buffer)?;
+        self.buffer.clear();
+        self.state.parts.push(UploadedPart { number, etag });
+        self.state.plaintext_offset = plaintext_offset;
+        save_state(&self.state_path, &self.state)
+    }
+}
+
+pub struct S3Backend {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub key: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl S3Backend {
+    fn object_path(&self) -> String {
+        format!("/{}/{}", uri_encode(&self.bucket, false), uri_encode(&self.key, false))
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<ureq::Response, Box<dyn std::error::Error>> {
+        let host = self
+            .endpoint
+            .split("://")
+            .nth(1)
+            .unwrap_or(&self.endpoint)
+            .trim_end_matches('/')
+            .to_string();
+        let path = self.object_path();
+        let mut query: Vec<(String, String)> =
+            query.iter().map(|(k, v)| (uri_encode(k, true), uri_encode(v, true))).collect();
+        query.sort();
+        let query = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&");
+
+        let amz_date = amz_timestamp(unix_now());
+        let payload_hash = hex::encode(Sha256::digest(body));
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, path, query, host, payload_hash, amz_date, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", &amz_date[..8], self.region);
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            SIGNING_ALGORITHM,
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.secret_key, &amz_date[..8], &self.region, "s3");
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "{} Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            SIGNING_ALGORITHM, self.access_key, scope, signature
+        );
+
+        let url = format!("{}{}?{}", self.endpoint.trim_end_matches('/'), path, query);
+        let mut request = ureq::request(method, &url)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &amz_date)
+            .set("Authorization", &authorization);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        Ok(request.send_bytes(body)?)
+    }
+}
+
+impl MultipartBackend for S3Backend {
+    fn create_upload(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+        let body = self.send("POST", &[("uploads", "")], &[], b"")?.into_string()?;
+        Ok(xml_value(&body, "UploadId").ok_or("Missing UploadId")?.to_string())
+    }
+
+    fn upload_part(&mut self, upload_id: &str, number: u32, data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        let number = number.to_string();
+        let response = self.send("PUT", &[("partNumber", &number), ("uploadId", upload_id)], &[], data)?;
+        Ok(response.header("ETag").ok_or("Missing ETag")?.to_string())
+    }
+
+    fn complete_upload(&mut self, upload_id: &str, parts: &[UploadedPart]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for part in parts {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                part.number, part.etag
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let response = self.send("POST", &[("uploadId", upload_id)], &[], body.as_bytes())?.into_string()?;
+        if response.contains("<Error>") {
+            return Err("Multipart upload completion failed".into());
+        }
+        Ok(())
+    }
+
+    fn object_len(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        let response = self.send("HEAD", &[], &[], b"")?;
+        Ok(response.header("Content-Length").and_then(|len| len.parse().ok()).ok_or("Missing content length")?)
+    }
+
+    // The Range header is left unsigned, which SigV4 allows.
+    fn read_range(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Box<dyn std::error::Error>> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let range = format!("bytes={}-{}", offset, offset + buf.len() as u64 - 1);
+        let mut reader = self.send("GET", &[], &[("Range", &range)], b"")?.into_reader();
+        let mut filled = 0;
+        while filled < buf.len() {
+            match reader.read(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        Ok(filled)
+    }
+}
+
+// A container written as one multipart upload and read back with ranged
+// GETs. Parts go out as they fill, so at most one is held in memory.
+pub struct ObjectStorage<B: MultipartBackend> {
+    backend: B,
+    upload_id: Option<String>,
+    parts: Vec<UploadedPart>,
+    buffer: Vec<u8>,
+    part_size: usize,
+}
+
+impl<B: MultipartBackend> ObjectStorage<B> {
+    pub fn new(backend: B, part_size: usize) -> Self {
+        ObjectStorage { backend, upload_id: None, parts: Vec::new(), buffer: Vec::new(), part_size: part_size.max(MIN_PART_SIZE) }
+    }
+
+    fn upload_buffer(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => self.upload_id.insert(self.backend.create_upload()?).clone(),
+        };
+        let number = self.parts.len() as u32 + 1;
+        let etag = self.backend.upload_part(&upload_id, number, &self.buffer)?;
+        self.buffer.clear();
+        self.parts.push(UploadedPart { number, etag });
+        Ok(())
+    }
+}
+
+impl<B: MultipartBackend> Storage for ObjectStorage<B> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.backend.read_range(offset, buf).map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= self.part_size {
+            self.upload_buffer().map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        self.backend.object_len().map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    fn finalize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.buffer.is_empty() || self.parts.is_empty() {
+            self.upload_buffer()?;
+        }
+        let upload_id = self.upload_id.as_deref().ok_or("Storage write failed")?;
+        self.backend.complete_upload(upload_id, &self.parts)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_s3_upload_file(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    endpoint_ptr: *const c_char,
+    region_ptr: *const c_char,
+    bucket_ptr: *const c_char,
+    key_ptr: *const c_char,
+    access_key_ptr: *const c_char,
+    secret_key_ptr: *const c_char,
+    state_path_ptr: *const c_char,
+    part_size: usize,
+    is_mobile: bool,
+) -> i32 {
+    unsafe {
+        let strings: Option<Vec<&str>> = [
+            input_path_ptr,
+            endpoint_ptr,
+            region_ptr,
+            bucket_ptr,
+            key_ptr,
+            access_key_ptr,
+            secret_key_ptr,
+            state_path_ptr,
+        ]
+        .iter()
+        .map(|ptr| CStr::from_ptr(*ptr).to_str().ok())
+        .collect();
+        let strings = match strings {
+            Some(strings) => strings,
+            None => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let mut backend = S3Backend {
+            endpoint: strings[1].to_string(),
+            region: strings[2].to_string(),
+            bucket: strings[3].to_string(),
+            key: strings[4].to_string(),
+            access_key: strings[5].to_string(),
+            secret_key: strings[6].to_string(),
+        };
+        match encrypt_to_multipart(
+            Path::new(strings[0]),
+            password,
+            hint,
+            &mut backend,
+            Path::new(strings[7]),
+            part_size,
+            is_mobile,
+            KyrieConfig::current().chunk_size(is_mobile),
+        ) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+// Encrypts straight into an object, with no local copy and no resume
+// state: an interrupted run leaves only an incomplete upload for the
+// bucket's lifecycle rules to clear.
+#[no_mangle]
+pub extern "C" fn kyrie_s3_encrypt_to_object(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    endpoint_ptr: *const c_char,
+    region_ptr: *const c_char,
+    bucket_ptr: *const c_char,
+    key_ptr: *const c_char,
+    access_key_ptr: *const c_char,
+    secret_key_ptr: *const c_char,
+    part_size: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let Some(backend) = s3_backend(endpoint_ptr, region_ptr, bucket_ptr, key_ptr, access_key_ptr, secret_key_ptr) else {
+            return errors::invalid_argument();
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let mut object = ObjectStorage::new(backend, part_size);
+        let padding = OptionsProfile::current().padding;
+        let result = File::open(input_path).map_err(|e| e.into()).and_then(|input| {
+            storage::encrypt_to_storage(input, &mut object, password, hint, padding, is_mobile, cpu_cores, &ProgressSink::none())
+        });
+        match result {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_s3_decrypt_object(
+    endpoint_ptr: *const c_char,
+    region_ptr: *const c_char,
+    bucket_ptr: *const c_char,
+    key_ptr: *const c_char,
+    access_key_ptr: *const c_char,
+    secret_key_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let Some(backend) = s3_backend(endpoint_ptr, region_ptr, bucket_ptr, key_ptr, access_key_ptr, secret_key_ptr) else {
+            return errors::invalid_argument();
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let mut object = ObjectStorage::new(backend, MIN_PART_SIZE);
+        match storage::decrypt_from_storage_to_path(&mut object, Path::new(output_path), password, is_mobile, cpu_cores) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+unsafe fn s3_backend(
+    endpoint_ptr: *const c_char,
+    region_ptr: *const c_char,
+    bucket_ptr: *const c_char,
+    key_ptr: *const c_char,
+    access_key_ptr: *const c_char,
+    secret_key_ptr: *const c_char,
+) -> Option<S3Backend> {
+    let string = |ptr: *const c_char| CStr::from_ptr(ptr).to_str().ok().map(str::to_string);
+    Some(S3Backend {
+        endpoint: string(endpoint_ptr)?,
+        region: string(region_ptr)?,
+        bucket: string(bucket_ptr)?,
+        key: string(key_ptr)?,
+        access_key: string(access_key_ptr)?,
+        secret_key: string(secret_key_ptr)?,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_to_multipart<B: MultipartBackend>(
+    input_path: &Path,
+    password: &[u8],
+    hint: Option<&str>,
+    backend: &mut B,
+    state_path: &Path,
+    part_size: usize,
+    is_mobile: bool,
+    chunk_size: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = File::open(input_path)?;
+    let input_len = input.metadata()?.len();
+    let single_chunk = input_len <= chunk_size as u64;
+    let new_header = || {
+        let mut header = ContainerHeader::with_hint(&text::hint_bytes(hint));
+        header.key_purpose = KEY_PURPOSE_FILE_DATA;
+        header.set_framing(single_chunk, chunk_size, input_len.div_ceil(chunk_size as u64).max(1) as usize);
+        header
+    };
+    let (state, header, key) = match load_state(state_path)?.filter(|state| !state.header.is_empty()) {
+        Some(state) if state.input_len == input_len => {
+            let (header, _) = ContainerHeader::read(&mut hex::decode(&state.header)?.as_slice())?;
+            let key = Zeroizing::new(header.master_key(password)?);
+            (state, header, key)
+        }
+        Some(_) => return Err("Input changed since the interrupted upload".into()),
+        None => {
+            let mut header = new_header();
+            header.use_password_kdf(is_mobile);
+            let key = Zeroizing::new(header.new_master_key(password)?);
+            header.seal_file_id(&key);
+            let state = UploadState {
+                upload_id: backend.create_upload()?,
+                input_len,
+                plaintext_offset: 0,
+                parts: Vec::new(),
+                header: hex::encode(header.encode()),
+            };
+            save_state(state_path, &state)?;
+            (state, header, key)
+        }
+    };
+
+    let cipher = BodyCipher::new(CIPHER_AES_256_GCM, &kdf::body_key(&key, KEY_PURPOSE_FILE_DATA))?;
+    let mut offset = state.plaintext_offset;
+    let fresh = state.parts.is_empty();
+    let mut sink = MultipartSink::new(backend, state, state_path, part_size);
+
+    if fresh {
+        write_header(&mut sink, &header)?;
+    }
+    input.seek(SeekFrom::Start(offset))?;
+
+    // A resumed upload has no header to derive counter nonces from, so
+    // uploads keep storing random ones.
+    let mut nonce_seq = NonceSequence::Random;
+    if fresh && input_len == 0 {
+        write_frame(&mut sink, &cipher, &mut nonce_seq, &[], true)?;
+    }
+    let mut chunk = vec![0u8; chunk_size];
+    while offset < input_len {
+        let n = (input_len - offset).min(chunk_size as u64) as usize;
+        input.read_exact(&mut chunk[..n])?;
+        write_frame(&mut sink, &cipher, &mut nonce_seq, &chunk[..n], single_chunk)?;
+        offset += n as u64;
+        sink.commit_point(offset)?;
+    }
+    sink.finish()
+}
+
+fn load_state(state_path: &Path) -> Result<Option<UploadState>, Box<dyn std::error::Error>> {
+    if !state_path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(state_path)?)?))
+}
+
+fn save_state(state_path: &Path, state: &UploadState) -> Result<(), Box<dyn std::error::Error>> {
+    let mut temp = SecureTempFile::for_target(state_path, TempContents::Metadata)?;
+    temp.file().write_all(&serde_json::to_vec(state)?)?;
+    temp.persist(state_path)?;
+    Ok(())
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+fn uri_encode(input: &str, encode_slash: bool) -> String {
+    input
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn amz_timestamp(unix_secs: u64) -> String {
+    let days = (unix_secs / 86_400) as i64;
+    let secs = unix_secs % 86_400;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&format!("</{}>", tag))? + start;
+    Some(&xml[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::range::DecryptingReader;
+
+    #[derive(Default)]
+    struct MemoryBackend {
+        parts: Vec<(u32, Vec<u8>)>,
+        fail_on_part: Option<u32>,
+        completed: Option<Vec<u8>>,
+    }
+
+    impl MultipartBackend for MemoryBackend {
+        fn create_upload(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+            Ok("upload-1".to_string())
+        }
+
+        fn upload_part(&mut self, _: &str, number: u32, data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+            if self.fail_on_part == Some(number) {
+                self.fail_on_part = None;
+                return Err("Connection reset".into());
+            }
+            self.parts.retain(|(n, _)| *n != number);
+            self.parts.push((number, data.to_vec()));
+            Ok(format!("\"etag-{}\"", number))
+        }
+
+        fn complete_upload(&mut self, _: &str, parts: &[UploadedPart]) -> Result<(), Box<dyn std::error::Error>> {
+            let mut object = Vec::new();
+            for part in parts {
+                let (_, data) = self.parts.iter().find(|(n, _)| *n == part.number).ok_or("Unknown part")?;
+                object.extend_from_slice(data);
+            }
+            self.completed = Some(object);
+            Ok(())
+        }
+
+        fn object_len(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+            Ok(self.completed.as_ref().ok_or("No such object")?.len() as u64)
+        }
+
+        fn read_range(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Box<dyn std::error::Error>> {
+            let object = self.completed.as_ref().ok_or("No such object")?;
+            let available = object.get(offset as usize..).unwrap_or_default();
+            let n = available.len().min(buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_upload_resumes_after_interruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("backup.bin");
+        let state = dir.path().join("backup.upload.json");
+        let chunk_size = 1024 * 1024;
+        let data: Vec<u8> = (0..chunk_size * 12 + 1000).map(|i| (i % 251) as u8).collect();
+        fs::write(&input, &data).unwrap();
+
+        let mut backend = MemoryBackend {
+            fail_on_part: Some(2),
+            ..Default::default()
+        };
+        assert!(encrypt_to_multipart(&input, b"pw", Some("hint"), &mut backend, &state, MIN_PART_SIZE, false, chunk_size).is_err());
+        let saved = load_state(&state).unwrap().unwrap();
+        assert_eq!(saved.parts, vec![UploadedPart { number: 1, etag: "\"etag-1\"".to_string() }]);
+        assert_eq!(saved.plaintext_offset, 5 * chunk_size as u64);
+        let (header, _) = ContainerHeader::read(&mut hex::decode(&saved.header).unwrap().as_slice()).unwrap();
+        assert!(matches!(header.kdf, kdf::KdfParams::Argon2id { .. }));
+
+        encrypt_to_multipart(&input, b"pw", Some("hint"), &mut backend, &state, MIN_PART_SIZE, false, chunk_size).unwrap();
+        assert!(!state.exists());
+        assert_eq!(backend.parts.len(), 3);
+
+        let object = dir.path().join("object.kyl");
+        fs::write(&object, backend.completed.unwrap()).unwrap();
+        let mut decrypted = Vec::new();
+        DecryptingReader::open(&object, b"pw").unwrap().read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn test_upload_state_without_a_header_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("backup.bin");
+        let state = dir.path().join("backup.upload.json");
+        fs::write(&input, b"resumed under the legacy key").unwrap();
+        let legacy = UploadState {
+            upload_id: "upload-0".to_string(),
+            input_len: 28,
+            plaintext_offset: 10,
+            parts: vec![UploadedPart { number: 1, etag: "\"etag-1\"".to_string() }],
+            header: String::new(),
+        };
+        save_state(&state, &legacy).unwrap();
+
+        let mut backend = MemoryBackend::default();
+        encrypt_to_multipart(&input, b"pw", None, &mut backend, &state, MIN_PART_SIZE, false, 1024 * 1024).unwrap();
+        let object = dir.path().join("object.kyl");
+        fs::write(&object, backend.completed.unwrap()).unwrap();
+        let (header, _) = crate::format::read_header(&object).unwrap();
+        assert!(matches!(header.kdf, kdf::KdfParams::Argon2id { .. }));
+        let mut decrypted = Vec::new();
+        DecryptingReader::open(&object, b"pw").unwrap().read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, b"resumed under the legacy key");
+    }
+
+    #[test]
+    fn test_object_storage_round_trips_without_local_copies() {
+        let data: Vec<u8> = (0..MIN_PART_SIZE * 2 + 1000).map(|i| (i % 239) as u8).collect();
+        let mut object = ObjectStorage::new(MemoryBackend::default(), MIN_PART_SIZE);
+        storage::encrypt_to_storage(&data[..], &mut object, b"pw", None, 0, false, 4, &ProgressSink::none()).unwrap();
+        assert!(object.parts.len() >= 2 && object.buffer.is_empty());
+
+        let mut decrypted = Vec::new();
+        storage::decrypt_from_storage(&mut object, &mut decrypted, b"pw", false, 4, &ProgressSink::none()).unwrap();
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn test_signing_helpers() {
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex::encode(key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+        assert_eq!(amz_timestamp(1_369_353_600), "20130524T000000Z");
+        assert_eq!(uri_encode("photos/a b.kyl", false), "photos/a%20b.kyl");
+        assert_eq!(xml_value("<R><UploadId>abc</UploadId></R>", "UploadId"), Some("abc"));
+    }
+}
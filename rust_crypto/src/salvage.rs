@@ -0,0 +1,193 @@
+use aes_gcm::{aead::Aead, Nonce};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::errors;
+use crate::format::{read_layout, ContainerHeader};
+use crate::padding::PaddingStripper;
+use crate::secure_temp::{self, SecureTempFile};
+use crate::sparse;
+use crate::TAG_SIZE;
+
+// What takes the place of a chunk that fails to authenticate.
+pub const SALVAGE_ZERO_FILL: u8 = 0;
+pub const SALVAGE_OMIT: u8 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct KyrieDamagedRange {
+    pub chunk_index: u64,
+    // Where the chunk's plaintext starts in the output. With SALVAGE_OMIT
+    // this is where the missing bytes would have been.
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Default, Debug)]
+pub struct SalvageReport {
+    pub total_chunks: u64,
+    pub damaged: Vec<KyrieDamagedRange>,
+    pub recovered_bytes: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieSalvageReport {
+    pub total_chunks: u64,
+    pub damaged_chunks: u64,
+    pub recovered_bytes: u64,
+    pub lost_bytes: u64,
+}
+
+impl From<&SalvageReport> for KyrieSalvageReport {
+    fn from(report: &SalvageReport) -> Self {
+        KyrieSalvageReport {
+            total_chunks: report.total_chunks,
+            damaged_chunks: report.damaged.len() as u64,
+            recovered_bytes: report.recovered_bytes,
+            lost_bytes: report.damaged.iter().map(|range| range.len).sum(),
+        }
+    }
+}
+
+// Decrypts whatever still authenticates. The first `ranges_capacity` damaged
+// ranges are copied to `ranges_ptr`; the report holds the full count.
+#[no_mangle]
+pub extern "C" fn decrypt_file_partial(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    policy: u8,
+    report_ptr: *mut KyrieSalvageReport,
+    ranges_ptr: *mut KyrieDamagedRange,
+    ranges_capacity: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        if policy != SALVAGE_ZERO_FILL && policy != SALVAGE_OMIT {
+            return errors::invalid_argument();
+        }
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match decrypt_file_partial_internal(Path::new(input_path), Path::new(output_path), password, policy) {
+            Ok(report) => {
+                if !report_ptr.is_null() {
+                    *report_ptr = KyrieSalvageReport::from(&report);
+                }
+                if !ranges_ptr.is_null() {
+                    let count = report.damaged.len().min(ranges_capacity);
+                    std::ptr::copy_nonoverlapping(report.damaged.as_ptr(), ranges_ptr, count);
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Ranges are in terms of the padded plaintext, so a damaged last chunk may
+// report bytes that were only padding. A damaged length field shifts every
+// later frame, which leaves those frames unrecoverable here; repair_file is
+// the tool for that.
+pub fn decrypt_file_partial_internal(
+    input_path: &Path,
+    output_path: &Path,
+    password: &[u8],
+    policy: u8,
+) -> Result<SalvageReport, Box<dyn std::error::Error>> {
+    let layout = read_layout(input_path)?;
+    sparse::require_dense(&layout.holes)?;
+    let (header, _) = ContainerHeader::read(&mut layout.header.as_slice())?;
+    let mut reader = BufReader::new(File::open(input_path)?);
+    let key = Zeroizing::new(layout.master_key(password)?);
+    let layout = layout.unlock(&mut reader, &key)?;
+    let cipher = layout.body_cipher(&key)?;
+
+    secure_temp::check_target(output_path)?;
+    let mut temp = SecureTempFile::for_output(output_path)?;
+    let mut output = BufWriter::new(temp.file());
+    let mut report = SalvageReport { total_chunks: layout.frames.len() as u64, ..Default::default() };
+    let mut stripper = PaddingStripper::new(layout.padding);
+    let mut position = 0u64;
+    for (index, frame) in layout.frames.iter().enumerate() {
+        let opened = layout
+            .read_frame(&mut reader, frame)
+            .ok()
+            .and_then(|(nonce, ciphertext)| cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref()).ok());
+        let plaintext = match opened {
+            Some(plaintext) => {
+                report.recovered_bytes += plaintext.len() as u64;
+                Zeroizing::new(plaintext)
+            }
+            None => {
+                let len = frame.len.saturating_sub(TAG_SIZE as u64);
+                report.damaged.push(KyrieDamagedRange { chunk_index: index as u64, offset: position, len });
+                if policy == SALVAGE_OMIT {
+                    continue;
+                }
+                Zeroizing::new(vec![0u8; len as usize])
+            }
+        };
+        position += plaintext.len() as u64;
+        stripper.feed(&plaintext, |content| output.write_all(content).map_err(Into::into))?;
+    }
+
+    // Without a header tag, nothing but the chunks shows the key to be right.
+    if report.recovered_bytes == 0 && header.file_id.is_none() {
+        return Err("Decryption failed".into());
+    }
+    // The padding marker may have been in a chunk that was lost.
+    if report.damaged.is_empty() {
+        stripper.finish()?;
+    }
+    output.flush()?;
+    drop(output);
+    temp.persist(output_path)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_framed_container;
+    use std::fs;
+
+    #[test]
+    fn test_damaged_chunk_is_zero_filled_or_omitted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.kyl");
+        write_framed_container(&path, b"pw", &[b"first ", b"middle", b" last"]);
+        let layout = read_layout(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[(layout.frames[1].offset + layout.frame_size(&layout.frames[1]) - 1) as usize] ^= 0xFF;
+        fs::write(&path, bytes).unwrap();
+
+        let filled = dir.path().join("filled.jpg");
+        let report = decrypt_file_partial_internal(&path, &filled, b"pw", SALVAGE_ZERO_FILL).unwrap();
+        assert_eq!(report.total_chunks, 3);
+        assert_eq!(report.damaged, vec![KyrieDamagedRange { chunk_index: 1, offset: 6, len: 6 }]);
+        assert_eq!(fs::read(&filled).unwrap(), b"first \0\0\0\0\0\0 last");
+
+        let omitted = dir.path().join("omitted.jpg");
+        let report = decrypt_file_partial_internal(&path, &omitted, b"pw", SALVAGE_OMIT).unwrap();
+        assert_eq!(report.recovered_bytes, 11);
+        assert_eq!(fs::read(&omitted).unwrap(), b"first  last");
+
+        let wrong = dir.path().join("wrong.jpg");
+        let error = decrypt_file_partial_internal(&path, &wrong, b"wrong", SALVAGE_ZERO_FILL).unwrap_err();
+        assert_eq!(errors::classify(error.as_ref()), errors::ERR_WRONG_PASSWORD);
+        assert!(!wrong.exists());
+    }
+}
@@ -0,0 +1,423 @@
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use crate::digest::plaintext_digest;
+use crate::kdf::KdfParams;
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::vault::{file_blake3, new_signing_kdf, signing_key_from_password, unix_now};
+use crate::{encrypt_file_reporting, errors, EncryptOptions};
+
+const SEAL_SUFFIX: &str = ".kyrie_seal";
+const PARITY_SUFFIX: &str = ".kyrie_parity";
+const SEAL_VERSION: u32 = 1;
+// One XOR parity block per group rebuilds any single damaged block in that
+// group, for about six percent of the container size.
+const PARITY_BLOCK_SIZE: usize = if cfg!(test) { 4096 } else { 1024 * 1024 };
+const PARITY_GROUP_SIZE: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SealBody {
+    pub version: u32,
+    pub sealed_at: u64,
+    pub file_len: u64,
+    pub file_blake3: String,
+    pub content_digest: String,
+    pub block_size: usize,
+    pub group_size: usize,
+    pub block_blake3: Vec<String>,
+    pub parity_blake3: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SealRecord {
+    pub body: SealBody,
+    pub public_key: String,
+    pub signature: String,
+    // As in the vault manifest; records without it use the legacy key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_kdf: Option<String>,
+}
+
+#[derive(Default, Debug)]
+pub struct SealReport {
+    pub signature_valid: bool,
+    pub damaged_blocks: Vec<u64>,
+    pub parity_intact: bool,
+    pub recoverable: bool,
+    pub content_verified: bool,
+}
+
+impl SealReport {
+    pub fn is_intact(&self) -> bool {
+        self.signature_valid && self.damaged_blocks.is_empty() && self.parity_intact && self.content_verified
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieSealReport {
+    pub intact: bool,
+    pub signature_valid: bool,
+    pub damaged_blocks: u64,
+    pub parity_intact: bool,
+    pub recoverable: bool,
+    pub content_verified: bool,
+}
+
+impl From<&SealReport> for KyrieSealReport {
+    fn from(report: &SealReport) -> Self {
+        KyrieSealReport {
+            intact: report.is_intact(),
+            signature_valid: report.signature_valid,
+            damaged_blocks: report.damaged_blocks.len() as u64,
+            parity_intact: report.parity_intact,
+            recoverable: report.recoverable,
+            content_verified: report.content_verified,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_seal_file(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match seal_file(input_path, output_path, password, hint, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_verify_seal(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    report_ptr: *mut KyrieSealReport,
+) -> i32 {
+    if report_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match verify_seal(path, password, is_mobile) {
+            Ok(report) => {
+                *report_ptr = KyrieSealReport::from(&report);
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_repair_seal(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    repaired_ptr: *mut u64,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match repair_seal(path, password) {
+            Ok(repaired) => {
+                if !repaired_ptr.is_null() {
+                    *repaired_ptr = repaired.len() as u64;
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Writes the container with the archive KDF profile and a header backup,
+// then a parity file and a signed seal record next to it.
+pub fn seal_file(
+    input_path: &str,
+    output_path: &str,
+    password: &[u8],
+    hint: Option<&str>,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<SealRecord, Box<dyn std::error::Error>> {
+    let options = EncryptOptions {
+        hint,
+        padding: OptionsProfile::current().padding,
+        kdf: Some(KdfParams::for_archives()),
+        backup: true,
+        ..Default::default()
+    };
+    let digest = encrypt_file_reporting(input_path, output_path, password, &options, is_mobile, cpu_cores, &ProgressSink::none())?;
+
+    let path = Path::new(output_path);
+    let block_blake3 = write_parity(path, &sidecar_path(path, PARITY_SUFFIX))?;
+    let body = SealBody {
+        version: SEAL_VERSION,
+        sealed_at: unix_now(),
+        file_len: fs::metadata(path)?.len(),
+        file_blake3: file_blake3(path)?,
+        content_digest: hex::encode(digest),
+        block_size: PARITY_BLOCK_SIZE,
+        group_size: PARITY_GROUP_SIZE,
+        block_blake3,
+        parity_blake3: file_blake3(&sidecar_path(path, PARITY_SUFFIX))?,
+    };
+
+    let signing_kdf = new_signing_kdf();
+    let signing_key = signing_key_from_password(password, Some(&signing_kdf))?;
+    let record = SealRecord {
+        signature: hex::encode(signing_key.sign(&serde_json::to_vec(&body)?).to_bytes()),
+        public_key: hex::encode(signing_key.verifying_key().as_bytes()),
+        signing_kdf: Some(signing_kdf),
+        body,
+    };
+    fs::write(sidecar_path(path, SEAL_SUFFIX), serde_json::to_vec_pretty(&record)?)?;
+    Ok(record)
+}
+
+pub fn verify_seal(path: &str, password: &[u8], is_mobile: bool) -> Result<SealReport, Box<dyn std::error::Error>> {
+    let path = Path::new(path);
+    let record = read_record(path)?;
+    let mut report = SealReport { signature_valid: signature_valid(&record, password)?, ..Default::default() };
+    // Nothing in an unsigned record can be trusted to judge the file by.
+    if !report.signature_valid {
+        return Ok(report);
+    }
+
+    let body = &record.body;
+    report.damaged_blocks = damaged_blocks(path, body)?;
+    let parity_path = sidecar_path(path, PARITY_SUFFIX);
+    report.parity_intact = parity_path.exists() && file_blake3(&parity_path)? == body.parity_blake3;
+    report.recoverable = report.parity_intact && !report.damaged_blocks.is_empty() && one_per_group(&report.damaged_blocks, body.group_size);
+    if report.damaged_blocks.is_empty() {
+        let digest = plaintext_digest(path.to_str().ok_or("Invalid path")?, password, is_mobile)?;
+        report.content_verified = hex::encode(digest) == body.content_digest;
+    }
+    Ok(report)
+}
+
+// Rebuilds damaged blocks from the parity file and returns their indices.
+pub fn repair_seal(path: &str, password: &[u8]) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let path = Path::new(path);
+    let record = read_record(path)?;
+    if !signature_valid(&record, password)? {
+        return Err("Seal signature is invalid".into());
+    }
+    let body = &record.body;
+    let damaged = damaged_blocks(path, body)?;
+    if damaged.is_empty() {
+        return Ok(damaged);
+    }
+    let parity_path = sidecar_path(path, PARITY_SUFFIX);
+    if !parity_path.exists() || file_blake3(&parity_path)? != body.parity_blake3 || !one_per_group(&damaged, body.group_size) {
+        return Err("Seal damage is not recoverable".into());
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    file.set_len(body.file_len)?;
+    let mut parity = File::open(&parity_path)?;
+    for &index in &damaged {
+        let group = index as usize / body.group_size;
+        let mut block = vec![0u8; body.block_size];
+        parity.seek(SeekFrom::Start((group * body.block_size) as u64))?;
+        read_up_to(&mut parity, &mut block)?;
+        let first = group * body.group_size;
+        let last = (first + body.group_size).min(body.block_blake3.len());
+        for other in (first..last).filter(|&i| i as u64 != index) {
+            let other_block = read_block(&mut file, body, other as u64)?;
+            block.iter_mut().zip(&other_block).for_each(|(b, o)| *b ^= o);
+        }
+        block.truncate(block_len(body, index));
+        if blake3::hash(&block).to_hex().as_str() != body.block_blake3[index as usize] {
+            return Err("Seal damage is not recoverable".into());
+        }
+        file.seek(SeekFrom::Start(index * body.block_size as u64))?;
+        file.write_all(&block)?;
+    }
+    file.sync_all()?;
+    Ok(damaged)
+}
+
+pub fn sidecar_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn read_record(path: &Path) -> Result<SealRecord, Box<dyn std::error::Error>> {
+    let record: SealRecord = serde_json::from_slice(&fs::read(sidecar_path(path, SEAL_SUFFIX))?)?;
+    if record.body.version != SEAL_VERSION {
+        return Err("Unsupported version".into());
+    }
+    if record.body.block_size == 0 || record.body.group_size == 0 {
+        return Err("Invalid seal record".into());
+    }
+    Ok(record)
+}
+
+fn signature_valid(record: &SealRecord, password: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
+    let verifying_key = signing_key_from_password(password, record.signing_kdf.as_deref())?.verifying_key();
+    if hex::encode(verifying_key.as_bytes()) != record.public_key {
+        return Ok(false);
+    }
+    let Ok(signature) = hex::decode(&record.signature).map_err(|_| ()).and_then(|s| Signature::from_slice(&s).map_err(|_| ())) else {
+        return Ok(false);
+    };
+    Ok(verifying_key.verify(&serde_json::to_vec(&record.body)?, &signature).is_ok())
+}
+
+fn write_parity(path: &Path, parity_path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut parity_file = BufWriter::new(File::create(parity_path)?);
+    let mut hashes = Vec::new();
+    let mut parity = vec![0u8; PARITY_BLOCK_SIZE];
+    let mut parity_len = 0;
+    let mut block = vec![0u8; PARITY_BLOCK_SIZE];
+    loop {
+        let n = read_up_to(&mut reader, &mut block)?;
+        if n > 0 {
+            hashes.push(blake3::hash(&block[..n]).to_hex().to_string());
+            parity.iter_mut().zip(&block[..n]).for_each(|(p, b)| *p ^= b);
+            parity_len = parity_len.max(n);
+        }
+        if parity_len > 0 && (n < PARITY_BLOCK_SIZE || hashes.len() % PARITY_GROUP_SIZE == 0) {
+            parity_file.write_all(&parity[..parity_len])?;
+            parity.fill(0);
+            parity_len = 0;
+        }
+        if n < PARITY_BLOCK_SIZE {
+            break;
+        }
+    }
+    parity_file.flush()?;
+    Ok(hashes)
+}
+
+fn damaged_blocks(path: &Path, body: &SealBody) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let mut damaged = Vec::new();
+    for (index, expected) in body.block_blake3.iter().enumerate() {
+        let block = read_block(&mut file, body, index as u64)?;
+        if block.len() != block_len(body, index as u64) || blake3::hash(&block).to_hex().as_str() != expected {
+            damaged.push(index as u64);
+        }
+    }
+    if file.metadata()?.len() != body.file_len && damaged.is_empty() {
+        damaged.push(body.block_blake3.len().saturating_sub(1) as u64);
+    }
+    Ok(damaged)
+}
+
+fn read_block(file: &mut File, body: &SealBody, index: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut block = vec![0u8; block_len(body, index)];
+    file.seek(SeekFrom::Start(index * body.block_size as u64))?;
+    let n = read_up_to(file, &mut block)?;
+    block.truncate(n);
+    Ok(block)
+}
+
+fn block_len(body: &SealBody, index: u64) -> usize {
+    (body.file_len - index * body.block_size as u64).min(body.block_size as u64) as usize
+}
+
+fn one_per_group(blocks: &[u64], group_size: usize) -> bool {
+    blocks.windows(2).all(|pair| pair[0] as usize / group_size != pair[1] as usize / group_size)
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt_file_internal;
+    use crate::format::read_header;
+
+    #[test]
+    fn test_seal_verifies_and_repairs_from_parity() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.bin");
+        let sealed = dir.path().join("sealed.kyl");
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&plain, &content).unwrap();
+        let sealed_str = sealed.to_str().unwrap();
+        seal_file(plain.to_str().unwrap(), sealed_str, b"pw", Some("archive"), false, 4).unwrap();
+
+        let (header, _) = read_header(&sealed).unwrap();
+        assert_eq!(header.kdf, KdfParams::for_archives());
+        assert!(header.backup);
+        assert!(verify_seal(sealed_str, b"pw", false).unwrap().is_intact());
+        assert!(!verify_seal(sealed_str, b"wrong", false).unwrap().signature_valid);
+
+        // Two damaged blocks in different groups are both rebuilt.
+        let mut bytes = fs::read(&sealed).unwrap();
+        bytes[PARITY_BLOCK_SIZE + 10] ^= 0xFF;
+        bytes[PARITY_BLOCK_SIZE * PARITY_GROUP_SIZE + 1] ^= 0xFF;
+        fs::write(&sealed, &bytes).unwrap();
+        let report = verify_seal(sealed_str, b"pw", false).unwrap();
+        assert_eq!(report.damaged_blocks, vec![1, PARITY_GROUP_SIZE as u64]);
+        assert!(report.recoverable && !report.content_verified);
+        assert_eq!(repair_seal(sealed_str, b"pw").unwrap().len(), 2);
+        assert!(verify_seal(sealed_str, b"pw", false).unwrap().is_intact());
+
+        let restored = dir.path().join("restored.bin");
+        decrypt_file_internal(sealed_str, restored.to_str().unwrap(), b"pw", false, 4).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), content);
+
+        // A tampered record no longer verifies.
+        let seal = sidecar_path(&sealed, SEAL_SUFFIX);
+        let mut record: SealRecord = serde_json::from_slice(&fs::read(&seal).unwrap()).unwrap();
+        record.body.content_digest = hex::encode([0u8; 32]);
+        fs::write(&seal, serde_json::to_vec(&record).unwrap()).unwrap();
+        assert!(!verify_seal(sealed_str, b"pw", false).unwrap().signature_valid);
+        assert!(repair_seal(sealed_str, b"pw").is_err());
+    }
+}
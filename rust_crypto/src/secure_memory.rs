@@ -0,0 +1,27 @@
+use std::slice;
+
+use zeroize::Zeroize;
+
+// Plaintext handed across the FFI lands in buffers the caller allocated, so
+// Rust cannot scrub them on drop. The app calls this on each one before it
+// releases the allocation itself.
+#[no_mangle]
+pub extern "C" fn kyrie_secure_free(ptr: *mut u8, len: usize) {
+    if ptr.is_null() || len == 0 {
+        return;
+    }
+    unsafe { slice::from_raw_parts_mut(ptr, len) }.zeroize();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secure_free_scrubs_the_buffer() {
+        let mut buffer = b"plaintext".to_vec();
+        kyrie_secure_free(buffer.as_mut_ptr(), buffer.len());
+        assert!(buffer.iter().all(|&b| b == 0));
+        kyrie_secure_free(std::ptr::null_mut(), 4);
+    }
+}
@@ -0,0 +1,367 @@
+use std::ffi::{CStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::shred::shred_file_internal;
+
+const TEMP_SUFFIX: &str = "kyrietmp";
+
+static TEMP_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+static NO_PLAINTEXT_TEMP: AtomicBool = AtomicBool::new(false);
+static OVERWRITE_EXISTING: AtomicBool = AtomicBool::new(true);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TempContents {
+    Ciphertext,
+    Metadata,
+    Plaintext,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_temp_dir(dir_ptr: *const c_char) -> i32 {
+    let dir = if dir_ptr.is_null() {
+        None
+    } else {
+        match unsafe { CStr::from_ptr(dir_ptr) }.to_str() {
+            Ok(s) if Path::new(s).is_dir() => Some(PathBuf::from(s)),
+            _ => return -1,
+        }
+    };
+    *TEMP_DIR.lock().unwrap_or_else(|e| e.into_inner()) = dir;
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_no_plaintext_temp(enabled: bool) {
+    NO_PLAINTEXT_TEMP.store(enabled, Ordering::SeqCst);
+}
+
+// When off, encrypting or decrypting onto an existing file fails instead of
+// replacing it.
+#[no_mangle]
+pub extern "C" fn kyrie_set_overwrite_existing(enabled: bool) {
+    OVERWRITE_EXISTING.store(enabled, Ordering::SeqCst);
+}
+
+pub fn check_target(target: &Path) -> io::Result<()> {
+    check_overwrite_allowed(target, OVERWRITE_EXISTING.load(Ordering::SeqCst))
+}
+
+pub struct SecureTempFile {
+    path: PathBuf,
+    file: Option<File>,
+    direct: bool,
+}
+
+impl SecureTempFile {
+    pub fn for_target(target: &Path, contents: TempContents) -> io::Result<Self> {
+        check_contents_allowed(contents, NO_PLAINTEXT_TEMP.load(Ordering::SeqCst))?;
+        let configured = TEMP_DIR.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        match configured {
+            Some(dir) => Self::in_dir(&dir),
+            None => Self::in_dir(parent_dir(target)),
+        }
+    }
+
+    // The decrypted file a caller names is the result, not an intermediate
+    // temp. With plaintext temps disabled it is written in place instead, so
+    // a failed decryption removes it rather than leaving the old contents.
+    pub fn for_output(target: &Path) -> io::Result<Self> {
+        if !NO_PLAINTEXT_TEMP.load(Ordering::SeqCst) {
+            return Self::for_target(target, TempContents::Plaintext);
+        }
+        let file = output_options().open(target)?;
+        exclude_from_backup(target);
+        Ok(SecureTempFile { path: target.to_path_buf(), file: Some(file), direct: true })
+    }
+
+    pub fn in_dir(dir: &Path) -> io::Result<Self> {
+        loop {
+            let path = dir.join(format!(".kyrie-{}.{}", hex::encode(rand::random::<[u8; 8]>()), TEMP_SUFFIX));
+            match restricted_options().open(&path) {
+                Ok(file) => {
+                    exclude_from_backup(&path);
+                    return Ok(SecureTempFile { path, file: Some(file), direct: false });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("temp file is open until persisted")
+    }
+
+    // Renames only work within one volume, which a configured temp dir or
+    // an app-group container often is not. A sibling of the target keeps the
+    // replacement atomic; where the sandbox allows no new files next to the
+    // target, it is overwritten in place instead.
+    pub fn persist(mut self, target: &Path) -> io::Result<()> {
+        let mut file = self.file.take().expect("temp file is open until persisted");
+        file.sync_all()?;
+        if self.direct {
+            debug_assert_eq!(self.path, target);
+            self.path = PathBuf::new();
+            return Ok(());
+        }
+
+        if same_volume(&self.path, target) != Some(false) && fs::rename(&self.path, target).is_ok() {
+            self.path = PathBuf::new();
+            return Ok(());
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        if let Ok(mut sibling) = SecureTempFile::in_dir(parent_dir(target)) {
+            io::copy(&mut file, sibling.file())?;
+            sibling.file.take().expect("temp file is open until persisted").sync_all()?;
+            if fs::rename(&sibling.path, target).is_ok() {
+                sibling.path = PathBuf::new();
+                return Ok(());
+            }
+            file.seek(SeekFrom::Start(0))?;
+        }
+        let mut output = File::create(target)?;
+        io::copy(&mut file, &mut output)?;
+        output.sync_all()
+    }
+}
+
+// Output files usually do not exist yet, so their parent is resolved
+// instead. Windows verbatim prefixes are dropped so that paths compare the
+// way they were passed in.
+pub fn canonical_path(path: &Path) -> io::Result<PathBuf> {
+    let resolved = match fs::canonicalize(path) {
+        Ok(resolved) => resolved,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let name = path.file_name().ok_or(e)?;
+            fs::canonicalize(parent_dir(path))?.join(name)
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(strip_verbatim(resolved))
+}
+
+pub fn same_file(first: &Path, second: &Path) -> bool {
+    matches!((canonical_path(first), canonical_path(second)), (Ok(first), Ok(second)) if first == second)
+}
+
+// A file kept next to `path` for as long as an operation on it is pending.
+pub fn sidecar_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// None when the platform cannot tell.
+pub fn same_volume(first: &Path, second: &Path) -> Option<bool> {
+    Some(volume_id(first)? == volume_id(second)?)
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."))
+}
+
+#[cfg(unix)]
+fn volume_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    let existing = if path.exists() { path } else { parent_dir(path) };
+    fs::metadata(existing).ok().map(|metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn volume_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(windows)]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    match path.to_str().and_then(|s| s.strip_prefix(r"\\?\")) {
+        Some(stripped) if !stripped.starts_with("UNC\\") => PathBuf::from(stripped),
+        _ => path,
+    }
+}
+
+#[cfg(not(windows))]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    path
+}
+
+impl Drop for SecureTempFile {
+    fn drop(&mut self) {
+        drop(self.file.take());
+        if !self.path.as_os_str().is_empty() && self.path.exists() && shred_file_internal(&self.path).is_err() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn check_contents_allowed(contents: TempContents, no_plaintext_temp: bool) -> io::Result<()> {
+    if no_plaintext_temp && contents == TempContents::Plaintext {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Plaintext temp files are disabled; use the in-memory or streaming APIs",
+        ));
+    }
+    Ok(())
+}
+
+fn check_overwrite_allowed(target: &Path, overwrite_existing: bool) -> io::Result<()> {
+    if !overwrite_existing && target.exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Output file already exists"));
+    }
+    Ok(())
+}
+
+fn output_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+fn restricted_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_ATTRIBUTE_TEMPORARY: u32 = 0x100;
+        const FILE_ATTRIBUTE_NOT_CONTENT_INDEXED: u32 = 0x2000;
+        options.attributes(FILE_ATTRIBUTE_TEMPORARY | FILE_ATTRIBUTE_NOT_CONTENT_INDEXED);
+    }
+    options
+}
+
+#[cfg(target_vendor = "apple")]
+fn exclude_from_backup(path: &Path) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    const EXCLUDE_ATTR: &[u8] = b"com.apple.metadata:com_apple_backup_excludeItem\0";
+    const EXCLUDE_VALUE: &[u8] = b"com.apple.backupd";
+    if let Ok(path) = CString::new(path.as_os_str().as_bytes()) {
+        unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                EXCLUDE_ATTR.as_ptr() as *const c_char,
+                EXCLUDE_VALUE.as_ptr() as *const libc::c_void,
+                EXCLUDE_VALUE.len(),
+                0,
+                0,
+            );
+        }
+    }
+}
+
+#[cfg(not(target_vendor = "apple"))]
+fn exclude_from_backup(_path: &Path) {}
+
+// Switching plaintext temps off is process wide, so tests that need such a
+// temp hold this shared while the strict-mode test holds it exclusively.
+#[cfg(test)]
+pub static PLAINTEXT_TEMP_MODE: std::sync::RwLock<()> = std::sync::RwLock::new(());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_temp_file_is_private_and_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.kyl");
+
+        let mut temp = SecureTempFile::for_target(&target, TempContents::Ciphertext).unwrap();
+        temp.file().write_all(b"partial output").unwrap();
+        let temp_path = temp.path.clone();
+        assert_eq!(temp_path.parent(), Some(dir.path()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&temp_path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        drop(temp);
+        assert!(!temp_path.exists());
+
+        let mut temp = SecureTempFile::for_target(&target, TempContents::Ciphertext).unwrap();
+        temp.file().write_all(b"complete output").unwrap();
+        temp.persist(&target).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"complete output");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_paths_resolve_before_they_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("in.kyl");
+        fs::write(&existing, b"x").unwrap();
+        let missing = dir.path().join(".").join("out.kyl");
+
+        let root = canonical_path(dir.path()).unwrap();
+        assert_eq!(canonical_path(&missing).unwrap(), root.join("out.kyl"));
+        assert!(same_file(&existing, &dir.path().join(".").join("in.kyl")));
+        assert!(!same_file(&existing, &missing));
+        #[cfg(unix)]
+        assert_eq!(same_volume(&existing, &missing), Some(true));
+    }
+
+    #[test]
+    fn test_existing_target_is_kept_unless_overwriting() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.kyl");
+        assert!(check_overwrite_allowed(&target, false).is_ok());
+        fs::write(&target, b"keep").unwrap();
+        assert!(check_overwrite_allowed(&target, true).is_ok());
+        assert_eq!(check_overwrite_allowed(&target, false).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_plaintext_temp_rejected_in_strict_mode() {
+        assert!(check_contents_allowed(TempContents::Plaintext, false).is_ok());
+        assert!(check_contents_allowed(TempContents::Ciphertext, true).is_ok());
+        assert!(check_contents_allowed(TempContents::Metadata, true).is_ok());
+        let err = check_contents_allowed(TempContents::Plaintext, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_decrypt_writes_output_directly_in_strict_mode() {
+        let _mode = PLAINTEXT_TEMP_MODE.write().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.txt");
+        let encrypted = dir.path().join("in.kyl");
+        let output = dir.path().join("out.txt");
+        fs::write(&input, b"kept out of temp files").unwrap();
+        crate::encrypt_file_internal(input.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 1).unwrap();
+
+        kyrie_set_no_plaintext_temp(true);
+        let result = crate::decrypt_file_internal(encrypted.to_str().unwrap(), output.to_str().unwrap(), b"pw", false, 1);
+        let wrong = crate::decrypt_file_internal(encrypted.to_str().unwrap(), dir.path().join("bad.txt").to_str().unwrap(), b"no", false, 1);
+        kyrie_set_no_plaintext_temp(false);
+
+        result.unwrap();
+        assert!(wrong.is_err());
+        assert_eq!(fs::read(&output).unwrap(), b"kept out of temp files");
+        assert!(!dir.path().join("bad.txt").exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+}
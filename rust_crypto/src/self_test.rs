@@ -0,0 +1,180 @@
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::Nonce;
+use argon2::{Algorithm, Argon2, AssociatedData, ParamsBuilder, Version};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::cipher::{BodyCipher, CIPHER_AES_256_GCM};
+use crate::format::{ContainerHeader, NONCE_SCHEME_COUNTER};
+use crate::kdf::{self, KdfParams, KEY_PURPOSE_FILE_DATA};
+use crate::manifest::ManifestBuilder;
+use crate::nonce::NonceSequence;
+use crate::rng::{self, SeededRng};
+use crate::write_encrypted_frame;
+
+// The group whose vectors failed; 0 means every vector passed.
+pub const SELF_TEST_AES_GCM: i32 = 1;
+pub const SELF_TEST_KDF: i32 = 2;
+pub const SELF_TEST_CONTAINER: i32 = 3;
+
+struct GcmVector {
+    key: &'static str,
+    nonce: &'static str,
+    aad: &'static str,
+    plaintext: &'static str,
+    ciphertext_and_tag: &'static str,
+}
+
+// Test cases 14 and 16 of the GCM specification.
+const GCM_VECTORS: [GcmVector; 2] = [
+    GcmVector {
+        key: "0000000000000000000000000000000000000000000000000000000000000000",
+        nonce: "000000000000000000000000",
+        aad: "",
+        plaintext: "00000000000000000000000000000000",
+        ciphertext_and_tag: "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919",
+    },
+    GcmVector {
+        key: "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+        nonce: "cafebabefacedbaddecaf888",
+        aad: "feedfacedeadbeeffeedfacedeadbeefabaddad2",
+        plaintext: "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        ciphertext_and_tag: "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f66276fc6ece0f4e1768cddf8853bb2d551b",
+    },
+];
+
+// RFC 9106 section 5.3.
+const ARGON2ID_TAG: &str = "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659";
+// RFC 5869 test case 1.
+const HKDF_OKM: &str = "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865";
+// The key of version 1 files, SHA-256 of "abc".
+const LEGACY_KEY: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+// A single-chunk container written with the seeded generator, which other
+// implementations reproduce by running the same steps under kyrie_set_test_rng.
+const CONTAINER_SEED: u64 = 0x004b_5952_4945;
+const CONTAINER_PASSWORD: &[u8] = b"correct horse battery staple";
+const CONTAINER_PLAINTEXT: &[u8] = b"KyrieLock known-answer container";
+const CONTAINER_KDF: KdfParams = KdfParams::Argon2id { memory_kib: 64, iterations: 1, lanes: 1 };
+const CONTAINER_SHA256: &str = "7f147e8451bbfed7bb5439f68169c53a2f374f9f85048ce7f6dfa44ca1c93477";
+
+// Runs every known-answer vector and returns the first group that failed.
+#[no_mangle]
+pub extern "C" fn kyrie_self_test() -> i32 {
+    self_test()
+}
+
+pub fn self_test() -> i32 {
+    if !aes_gcm_vectors_pass() {
+        return SELF_TEST_AES_GCM;
+    }
+    if !kdf_vectors_pass() {
+        return SELF_TEST_KDF;
+    }
+    if !container_vector_passes() {
+        return SELF_TEST_CONTAINER;
+    }
+    0
+}
+
+fn aes_gcm_vectors_pass() -> bool {
+    GCM_VECTORS.iter().all(|vector| {
+        let (key, nonce, aad) = (unhex(vector.key), unhex(vector.nonce), unhex(vector.aad));
+        let (plaintext, expected) = (unhex(vector.plaintext), unhex(vector.ciphertext_and_tag));
+        let Ok(cipher) = BodyCipher::new(CIPHER_AES_256_GCM, &key.as_slice().try_into().expect("vector keys are 32 bytes")) else {
+            return false;
+        };
+        let nonce = Nonce::from_slice(&nonce);
+        let sealed = cipher.encrypt(nonce, Payload { msg: &plaintext, aad: &aad });
+        let opened = cipher.decrypt(nonce, Payload { msg: &expected, aad: &aad });
+        sealed.ok() == Some(expected.clone()) && opened.ok() == Some(plaintext)
+    })
+}
+
+fn kdf_vectors_pass() -> bool {
+    let argon2 = ParamsBuilder::new()
+        .m_cost(32)
+        .t_cost(3)
+        .p_cost(4)
+        .data(AssociatedData::new(&[4; 12]).expect("12 bytes of associated data are allowed"))
+        .output_len(32)
+        .build()
+        .and_then(|params| Argon2::new_with_secret(&[3; 8], Algorithm::Argon2id, Version::V0x13, params));
+    let mut tag = [0u8; 32];
+    let argon2_ok = argon2.and_then(|argon2| argon2.hash_password_into(&[1; 32], &[2; 16], &mut tag)).is_ok()
+        && tag[..] == unhex(ARGON2ID_TAG)[..];
+
+    let mut okm = [0u8; 42];
+    let salt: Vec<u8> = (0x00..=0x0c).collect();
+    let info: Vec<u8> = (0xf0..=0xf9).collect();
+    let hkdf_ok = Hkdf::<Sha256>::new(Some(&salt), &[0x0b; 22]).expand(&info, &mut okm).is_ok() && okm[..] == unhex(HKDF_OKM)[..];
+
+    let legacy_ok = kdf::derive(b"abc", &[], &KdfParams::LegacySha256).is_ok_and(|key| key[..] == unhex(LEGACY_KEY)[..]);
+    argon2_ok && hkdf_ok && legacy_ok
+}
+
+fn container_vector_passes() -> bool {
+    let Ok(container) = rng::with_rng(Box::new(SeededRng::new(CONTAINER_SEED)), known_answer_container) else {
+        return false;
+    };
+    Sha256::digest(&container)[..] == unhex(CONTAINER_SHA256)[..]
+        && open_container(&container).is_ok_and(|plaintext| plaintext[..] == *CONTAINER_PLAINTEXT)
+}
+
+fn known_answer_container() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut header = ContainerHeader {
+        nonce_scheme: NONCE_SCHEME_COUNTER,
+        key_purpose: KEY_PURPOSE_FILE_DATA,
+        cipher: CIPHER_AES_256_GCM,
+        kdf: CONTAINER_KDF,
+        kdf_salt: rng::random::<[u8; kdf::KDF_SALT_SIZE]>().to_vec(),
+        ..Default::default()
+    };
+    header.set_framing(true, CONTAINER_PLAINTEXT.len(), 1);
+    let key = Zeroizing::new(header.new_master_key(CONTAINER_PASSWORD)?);
+    let cipher = header.body_cipher(&key)?;
+    let mut manifest = ManifestBuilder::default();
+    manifest.record_plaintext(CONTAINER_PLAINTEXT.len());
+    header.seal_file_id(&key);
+
+    let mut nonce_seq = NonceSequence::for_header(&header, &key)?;
+    let nonce = nonce_seq.next_nonce()?;
+    let encrypted = cipher.encrypt(Nonce::from_slice(&nonce), CONTAINER_PLAINTEXT).map_err(|_| "Encryption failed")?;
+    let mut frame = Vec::new();
+    header.chunk_checksums = vec![write_encrypted_frame(&mut frame, nonce_seq.stored(&nonce), &encrypted, true)?];
+    manifest.record_frame(&encrypted);
+    header.manifest = Some(manifest.finish());
+    header.seal_file_id(&key);
+
+    let mut container = header.encode();
+    container.extend_from_slice(&frame);
+    Ok(container)
+}
+
+fn open_container(container: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut reader = container;
+    let (header, _) = ContainerHeader::read(&mut reader)?;
+    let key = Zeroizing::new(header.master_key(CONTAINER_PASSWORD)?);
+    header.verify_file_id(&key)?;
+    let nonce = NonceSequence::for_header(&header, &key)?.read_nonce(&mut reader)?;
+    Ok(header.body_cipher(&key)?.decrypt(Nonce::from_slice(&nonce), reader).map_err(|_| "Decryption failed")?)
+}
+
+fn unhex(s: &str) -> Vec<u8> {
+    hex::decode(s).expect("vectors are valid hex")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_answers_pass_and_the_seed_fixes_the_container() {
+        let container = rng::with_rng(Box::new(SeededRng::new(CONTAINER_SEED)), known_answer_container).unwrap();
+        assert_eq!(self_test(), 0);
+        let again = rng::with_rng(Box::new(SeededRng::new(CONTAINER_SEED)), known_answer_container).unwrap();
+        assert_eq!(container, again);
+        assert_ne!(known_answer_container().unwrap(), container);
+    }
+}
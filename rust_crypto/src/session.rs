@@ -0,0 +1,483 @@
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::decrypt_cache::{self, DecryptCache, KyrieCacheStats};
+use crate::kdf::{derive, KdfParams, KeyProvider};
+use crate::nonce::NonceAllocator;
+use crate::progress::ProgressSink;
+use crate::{decrypt_file_reporting, decrypt_file_to_memory_with, encrypt_file_reporting, errors, EncryptOptions, NONCE_SIZE};
+
+static OPEN_SESSIONS: Mutex<Vec<Weak<Mutex<SessionState>>>> = Mutex::new(Vec::new());
+
+pub struct KyrieSession {
+    state: Arc<Mutex<SessionState>>,
+}
+
+struct SessionState {
+    password: Option<Zeroizing<Vec<u8>>>,
+    keys: HashMap<(Vec<u8>, KdfParams), Zeroizing<[u8; 32]>>,
+    cache: Option<DecryptCache>,
+    nonces: NonceAllocator,
+}
+
+impl SessionState {
+    fn wipe(&mut self) -> bool {
+        let had_secrets = self.password.is_some() || !self.keys.is_empty();
+        self.password = None;
+        for (_, key) in self.keys.iter_mut() {
+            key.zeroize();
+        }
+        self.keys.clear();
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+        had_secrets
+    }
+}
+
+impl KyrieSession {
+    pub fn new(password: &[u8]) -> Self {
+        let state = Arc::new(Mutex::new(SessionState {
+            password: Some(Zeroizing::new(password.to_vec())),
+            keys: HashMap::new(),
+            cache: None,
+            nonces: NonceAllocator::new(),
+        }));
+        let mut open = OPEN_SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+        open.retain(|session| session.strong_count() > 0);
+        open.push(Arc::downgrade(&state));
+        KyrieSession { state }
+    }
+
+    pub fn lock(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).wipe();
+    }
+
+    // For callers that still take a password, such as the name vault.
+    pub fn password(&self) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().map_err(|_| "Session state poisoned")?;
+        Ok(state.password.clone().ok_or("Session is locked")?)
+    }
+
+    // Nonces for encrypt_data and friends; unique for the life of the session.
+    pub fn next_nonces(&self, count: usize) -> Vec<[u8; NONCE_SIZE]> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        (0..count).map(|_| state.nonces.allocate()).collect()
+    }
+
+    // encrypt_data and decrypt_data key records with the unsalted legacy
+    // derivation, so a session keeps that key cached alongside the file keys.
+    fn data_cipher(&self) -> Result<Aes256Gcm, Box<dyn std::error::Error>> {
+        let key = Zeroizing::new(self.key_for(&[], &KdfParams::LegacySha256)?);
+        Ok(Aes256Gcm::new_from_slice(&key[..])?)
+    }
+
+    pub fn encrypt_data(&self, data: &[u8], nonce: &[u8; NONCE_SIZE]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(self.data_cipher()?.encrypt(Nonce::from_slice(nonce), data).map_err(|_| "Encryption failed")?)
+    }
+
+    pub fn decrypt_data(&self, encrypted: &[u8], nonce: &[u8; NONCE_SIZE]) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+        let decrypted = self.data_cipher()?.decrypt(Nonce::from_slice(nonce), encrypted).map_err(|_| "Decryption failed")?;
+        Ok(Zeroizing::new(decrypted))
+    }
+
+    pub fn enable_cache(&self, capacity_bytes: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.cache = (capacity_bytes > 0).then(|| DecryptCache::new(capacity_bytes));
+    }
+
+    pub fn invalidate_cache(&self, path: Option<&Path>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(cache) = state.cache.as_mut() {
+            match path {
+                Some(path) => {
+                    cache.invalidate(path);
+                }
+                None => cache.clear(),
+            }
+        }
+    }
+
+    pub fn cache_stats(&self) -> KyrieCacheStats {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.cache.as_ref().map(DecryptCache::stats).unwrap_or_default()
+    }
+
+    // The state lock is not held while decrypting, since key derivation
+    // goes through key_for and takes it again.
+    pub fn decrypt_to_memory(
+        &self,
+        input_path: &str,
+        is_mobile: bool,
+        cpu_cores: usize,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let path = Path::new(input_path);
+        let stamp = {
+            let mut state = self.state.lock().map_err(|_| "Session state poisoned")?;
+            if state.password.is_none() {
+                return Err("Session is locked".into());
+            }
+            match state.cache.as_mut() {
+                Some(cache) => match cache.get(path) {
+                    Some(plaintext) => return Ok(plaintext),
+                    None => decrypt_cache::stamp(path),
+                },
+                None => None,
+            }
+        };
+
+        let plaintext = decrypt_file_to_memory_with(input_path, self, is_mobile, cpu_cores)?;
+        if let Some(stamp) = stamp {
+            let mut state = self.state.lock().map_err(|_| "Session state poisoned")?;
+            // A lock that raced the decryption must not leave plaintext behind.
+            if state.password.is_some() {
+                if let Some(cache) = state.cache.as_mut() {
+                    cache.insert(path, stamp, &plaintext);
+                }
+            }
+        }
+        Ok(plaintext)
+    }
+}
+
+// Wipes the password and cached keys of every session that is still open,
+// returning how many of them held secrets.
+pub fn lock_all() -> u32 {
+    let open = std::mem::take(&mut *OPEN_SESSIONS.lock().unwrap_or_else(|e| e.into_inner()));
+    open.iter()
+        .filter_map(Weak::upgrade)
+        .filter(|state| state.lock().unwrap_or_else(|e| e.into_inner()).wipe())
+        .count() as u32
+}
+
+impl KeyProvider for KyrieSession {
+    fn key_for(&self, salt: &[u8], params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        let mut state = self.state.lock().map_err(|_| "Session state poisoned")?;
+        let cache_key = (salt.to_vec(), params.clone());
+        if let Some(key) = state.keys.get(&cache_key) {
+            return Ok(**key);
+        }
+
+        let password = state.password.as_ref().ok_or("Session is locked")?;
+        let key = derive(password, salt, params)?;
+        state.keys.insert(cache_key, Zeroizing::new(key));
+        Ok(key)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_open(password_ptr: *const u8, password_len: usize) -> *mut KyrieSession {
+    if password_ptr.is_null() {
+        return std::ptr::null_mut();
+    }
+    let password = unsafe { std::slice::from_raw_parts(password_ptr, password_len) };
+    Box::into_raw(Box::new(KyrieSession::new(password)))
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_lock(handle: *mut KyrieSession) -> i32 {
+    if handle.is_null() {
+        return -1;
+    }
+    unsafe { &*handle }.lock();
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_close(handle: *mut KyrieSession) {
+    if handle.is_null() {
+        return;
+    }
+    let session = unsafe { Box::from_raw(handle) };
+    session.lock();
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_encrypt_file(
+    handle: *mut KyrieSession,
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if handle.is_null() {
+        return -1;
+    }
+    unsafe {
+        let session = &*handle;
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let options = EncryptOptions { hint, ..Default::default() };
+        match encrypt_file_reporting(input_path, output_path, session, &options, is_mobile, cpu_cores, &ProgressSink::none()) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_decrypt_file(
+    handle: *mut KyrieSession,
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if handle.is_null() {
+        return -1;
+    }
+    unsafe {
+        let session = &*handle;
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+
+        match decrypt_file_reporting(input_path, output_path, session, is_mobile, cpu_cores, &ProgressSink::none()) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_encrypt_data(
+    handle: *mut KyrieSession,
+    data_ptr: *const u8,
+    data_len: usize,
+    nonce_ptr: *const u8,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+) -> i32 {
+    if handle.is_null() || nonce_ptr.is_null() || output_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let data = std::slice::from_raw_parts(data_ptr, data_len);
+        let nonce = &*(nonce_ptr as *const [u8; NONCE_SIZE]);
+
+        match (*handle).encrypt_data(data, nonce) {
+            Ok(encrypted) => {
+                *output_len = encrypted.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(encrypted.as_ptr(), output_ptr, encrypted.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_decrypt_data(
+    handle: *mut KyrieSession,
+    encrypted_ptr: *const u8,
+    encrypted_len: usize,
+    nonce_ptr: *const u8,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+) -> i32 {
+    if handle.is_null() || nonce_ptr.is_null() || output_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let encrypted = std::slice::from_raw_parts(encrypted_ptr, encrypted_len);
+        let nonce = &*(nonce_ptr as *const [u8; NONCE_SIZE]);
+
+        match (*handle).decrypt_data(encrypted, nonce) {
+            Ok(decrypted) => {
+                *output_len = decrypted.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(decrypted.as_ptr(), output_ptr, decrypted.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_next_nonces(handle: *mut KyrieSession, count: usize, nonces_ptr: *mut u8) -> i32 {
+    if handle.is_null() || nonces_ptr.is_null() {
+        return -1;
+    }
+    unsafe {
+        let nonces = (*handle).next_nonces(count);
+        std::ptr::copy_nonoverlapping(nonces.as_flattened().as_ptr(), nonces_ptr, count * NONCE_SIZE);
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_enable_cache(handle: *mut KyrieSession, capacity_bytes: u64) -> i32 {
+    if handle.is_null() {
+        return -1;
+    }
+    unsafe { &*handle }.enable_cache(capacity_bytes as usize);
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_invalidate_cache(handle: *mut KyrieSession, path_ptr: *const c_char) -> i32 {
+    if handle.is_null() {
+        return -1;
+    }
+    unsafe {
+        let session = &*handle;
+        if path_ptr.is_null() {
+            session.invalidate_cache(None);
+            return 0;
+        }
+        match CStr::from_ptr(path_ptr).to_str() {
+            Ok(path) => {
+                session.invalidate_cache(Some(Path::new(path)));
+                0
+            }
+            Err(_) => -1,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_cache_stats(handle: *mut KyrieSession, stats_ptr: *mut KyrieCacheStats) -> i32 {
+    if handle.is_null() || stats_ptr.is_null() {
+        return -1;
+    }
+    unsafe {
+        *stats_ptr = (*handle).cache_stats();
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_session_decrypt_to_memory(
+    handle: *mut KyrieSession,
+    input_path_ptr: *const c_char,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if handle.is_null() || output_len.is_null() {
+        return -1;
+    }
+    unsafe {
+        let session = &*handle;
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+
+        match session.decrypt_to_memory(input_path, is_mobile, cpu_cores) {
+            Ok(data) => {
+                *output_len = data.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), output_ptr, data.len());
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+    use std::fs;
+
+    #[test]
+    fn test_session_caches_keys_until_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        let decrypted = dir.path().join("plain.out");
+        fs::write(&plain, b"session data").unwrap();
+        let (plain, encrypted, decrypted) = (
+            plain.to_str().unwrap(),
+            encrypted.to_str().unwrap(),
+            decrypted.to_str().unwrap(),
+        );
+
+        let session = KyrieSession::new(b"pw");
+        encrypt_file_internal(plain, encrypted, b"pw", None, false, 4).unwrap();
+        decrypt_file_reporting(encrypted, decrypted, &session, false, 4, &ProgressSink::none()).unwrap();
+        assert_eq!(fs::read(decrypted).unwrap(), b"session data");
+        assert_eq!(session.state.lock().unwrap().keys.len(), 1);
+
+        let (header, _) = crate::format::read_header(Path::new(encrypted)).unwrap();
+        session.key_for(&header.kdf_salt, &header.kdf).unwrap();
+        assert_eq!(session.state.lock().unwrap().keys.len(), 1);
+
+        session.lock();
+        assert!(session.state.lock().unwrap().keys.is_empty());
+        assert!(decrypt_file_reporting(encrypted, decrypted, &session, false, 4, &ProgressSink::none()).is_err());
+    }
+
+    #[test]
+    fn test_session_cache_serves_repeat_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("plain.kyl");
+        fs::write(&plain, b"cached data").unwrap();
+        let (plain, encrypted) = (plain.to_str().unwrap(), encrypted.to_str().unwrap());
+        encrypt_file_internal(plain, encrypted, b"pw", None, false, 4).unwrap();
+
+        let session = KyrieSession::new(b"pw");
+        session.enable_cache(1 << 20);
+        for _ in 0..3 {
+            assert_eq!(session.decrypt_to_memory(encrypted, false, 4).unwrap(), b"cached data");
+        }
+        assert_eq!((session.cache_stats().hits, session.cache_stats().misses), (2, 1));
+
+        fs::write(plain, b"changed data").unwrap();
+        encrypt_file_internal(plain, encrypted, b"pw", None, false, 4).unwrap();
+        session.invalidate_cache(Some(Path::new(encrypted)));
+        assert_eq!(session.decrypt_to_memory(encrypted, false, 4).unwrap(), b"changed data");
+        assert_eq!(session.cache_stats().entries, 1);
+
+        let nonces = session.next_nonces(3);
+        assert!(nonces[0] != nonces[1] && nonces[1] != nonces[2] && nonces[2] != session.next_nonces(1)[0]);
+
+        let record = session.encrypt_data(b"thumbnail", &nonces[0]).unwrap();
+        let mut output = vec![0u8; record.len()];
+        let mut output_len = 0;
+        assert_eq!(
+            crate::decrypt_data(record.as_ptr(), record.len(), b"pw".as_ptr(), 2, nonces[0].as_ptr(), output.as_mut_ptr(), &mut output_len),
+            0
+        );
+        assert_eq!(&output[..output_len], b"thumbnail");
+        assert_eq!(&session.decrypt_data(&record, &nonces[0]).unwrap()[..], b"thumbnail");
+        assert!(session.decrypt_data(&record, &nonces[1]).is_err());
+
+        session.lock();
+        assert!(session.decrypt_data(&record, &nonces[0]).is_err());
+        assert_eq!(session.cache_stats().entries, 0);
+        assert!(session.decrypt_to_memory(encrypted, false, 4).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use zeroize::Zeroizing;
+
+use crate::rng;
+
+// Shamir secret sharing over GF(2^8) with the AES reduction polynomial,
+// splitting each byte of the secret independently. Share x coordinates
+// run from 1 to the share count; x = 0 is the secret itself.
+
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+fn inverse(a: u8) -> u8 {
+    // a^254 = a^-1 in GF(2^8).
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exponent = 254u8;
+    while exponent != 0 {
+        if exponent & 1 != 0 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+pub type Share = (u8, Vec<u8>);
+
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Share>, Box<dyn std::error::Error>> {
+    if threshold == 0 || threshold > count {
+        return Err("Invalid share threshold".into());
+    }
+    let mut shares: Vec<Share> = (1..=count).map(|x| (x, Vec::with_capacity(secret.len()))).collect();
+    let mut coefficients = Zeroizing::new(vec![0u8; threshold as usize]);
+    for &byte in secret {
+        coefficients[0] = byte;
+        rng::fill(&mut coefficients[1..]);
+        for (x, share) in shares.iter_mut() {
+            // Horner's rule from the highest coefficient down.
+            let y = coefficients.iter().rev().fold(0u8, |acc, &c| mul(acc, *x) ^ c);
+            share.push(y);
+        }
+    }
+    Ok(shares)
+}
+
+pub fn combine(shares: &[(u8, &[u8])]) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+    let len = shares.first().map(|(_, y)| y.len()).ok_or("Not enough key shares")?;
+    for (i, (x, y)) in shares.iter().enumerate() {
+        if *x == 0 || y.len() != len || shares[..i].iter().any(|(other, _)| other == x) {
+            return Err("Invalid key share".into());
+        }
+    }
+
+    // Lagrange basis polynomials evaluated at x = 0.
+    let weights: Vec<u8> = shares
+        .iter()
+        .map(|(xi, _)| {
+            shares.iter().filter(|(xj, _)| xj != xi).fold(1u8, |acc, (xj, _)| mul(acc, mul(*xj, inverse(xi ^ xj))))
+        })
+        .collect();
+    let mut secret = Zeroizing::new(vec![0u8; len]);
+    for ((_, y), weight) in shares.iter().zip(&weights) {
+        for (byte, share_byte) in secret.iter_mut().zip(y.iter()) {
+            *byte ^= mul(*share_byte, *weight);
+        }
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_any_threshold_subset_recovers_secret() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inverse(a)), 1);
+        }
+
+        let secret = b"thirty-two bytes of file key....";
+        let shares = split(secret, 3, 5).unwrap();
+        let view = |picks: &[usize]| -> Vec<(u8, &[u8])> {
+            picks.iter().map(|&i| (shares[i].0, shares[i].1.as_slice())).collect()
+        };
+        assert_eq!(combine(&view(&[0, 1, 2])).unwrap().as_slice(), secret);
+        assert_eq!(combine(&view(&[4, 1, 3])).unwrap().as_slice(), secret);
+        assert_eq!(combine(&view(&[0, 1, 2, 3, 4])).unwrap().as_slice(), secret);
+        assert_ne!(combine(&view(&[0, 1])).unwrap().as_slice(), secret);
+        assert!(combine(&view(&[0, 0, 1])).is_err());
+        assert!(split(secret, 3, 2).is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+use crate::format::{read_header, FILE_ID_SIZE};
+use crate::kdf::{self, KdfParams, KeyProvider, KDF_SALT_SIZE};
+use crate::progress::ProgressSink;
+use crate::rng;
+use crate::{decrypt_file_reporting, errors, random_nonce, NONCE_SIZE};
+
+const SHARE_KEY_MAGIC: &[u8] = b"KYRIE_SHARE";
+const SHARE_KEY_VERSION: u8 = 1;
+// magic || version || salt || memory_kib (u32 LE) || iterations (u32 LE) ||
+// lanes (u8), all authenticated as associated data.
+const SHARE_KEY_PREFIX_LEN: usize = SHARE_KEY_MAGIC.len() + 1 + KDF_SALT_SIZE + 4 + 4 + 1;
+
+// What the sender allows the recipient to do with the key. The token holds
+// the file key itself, so these limits bind conforming clients rather than
+// the cryptography.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ShareScope {
+    pub can_reshare: bool,
+    // Seconds since the epoch; zero for a key that does not expire.
+    pub expires_at: u64,
+    pub label: String,
+}
+
+impl ShareScope {
+    fn expired(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.expires_at != 0 && now >= self.expires_at
+    }
+
+    // A re-shared key can never allow more than the key it came from.
+    fn narrowed_by(&self, parent: &ShareScope) -> ShareScope {
+        let expires_at = match (self.expires_at, parent.expires_at) {
+            (0, parent) => parent,
+            (own, 0) => own,
+            (own, parent) => own.min(parent),
+        };
+        ShareScope {
+            can_reshare: self.can_reshare && parent.can_reshare,
+            expires_at,
+            label: self.label.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ShareKeyInfo {
+    pub file_id: String,
+    pub expired: bool,
+    #[serde(flatten)]
+    pub scope: ShareScope,
+}
+
+pub struct ShareKey {
+    key: Zeroizing<[u8; 32]>,
+    file_uuid: [u8; FILE_ID_SIZE],
+    pub scope: ShareScope,
+}
+
+impl KeyProvider for ShareKey {
+    fn key_for(&self, _salt: &[u8], _params: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        Ok(*self.key)
+    }
+
+    fn derives_from_password(&self) -> bool {
+        false
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_export_share_key(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    share_password_ptr: *const u8,
+    share_password_len: usize,
+    can_reshare: bool,
+    expires_at: u64,
+    label_ptr: *const c_char,
+    token_ptr: *mut u8,
+    token_len: *mut usize,
+) -> i32 {
+    if token_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let Some(scope) = scope_from_ffi(can_reshare, expires_at, label_ptr) else {
+            return errors::invalid_argument();
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let share_password = std::slice::from_raw_parts(share_password_ptr, share_password_len);
+
+        match export_share_key(Path::new(path), password, share_password, &scope) {
+            Ok(token) => copy_out(&token, token_ptr, token_len),
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_reshare_share_key(
+    token_ptr: *const u8,
+    token_len: usize,
+    share_password_ptr: *const u8,
+    share_password_len: usize,
+    new_share_password_ptr: *const u8,
+    new_share_password_len: usize,
+    can_reshare: bool,
+    expires_at: u64,
+    label_ptr: *const c_char,
+    new_token_ptr: *mut u8,
+    new_token_len: *mut usize,
+) -> i32 {
+    if new_token_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let Some(scope) = scope_from_ffi(can_reshare, expires_at, label_ptr) else {
+            return errors::invalid_argument();
+        };
+        let token = std::slice::from_raw_parts(token_ptr, token_len);
+        let share_password = std::slice::from_raw_parts(share_password_ptr, share_password_len);
+        let new_share_password = std::slice::from_raw_parts(new_share_password_ptr, new_share_password_len);
+
+        match reshare_share_key(token, share_password, new_share_password, &scope) {
+            Ok(new_token) => copy_out(&new_token, new_token_ptr, new_token_len),
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_share_key_info(
+    token_ptr: *const u8,
+    token_len: usize,
+    share_password_ptr: *const u8,
+    share_password_len: usize,
+    json_ptr: *mut u8,
+    json_len: *mut usize,
+) -> i32 {
+    if json_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let token = std::slice::from_raw_parts(token_ptr, token_len);
+        let share_password = std::slice::from_raw_parts(share_password_ptr, share_password_len);
+
+        match share_key_info(token, share_password).and_then(|info| Ok(serde_json::to_vec(&info)?)) {
+            Ok(json) => copy_out(&json, json_ptr, json_len),
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_decrypt_with_share_key(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    token_ptr: *const u8,
+    token_len: usize,
+    share_password_ptr: *const u8,
+    share_password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let token = std::slice::from_raw_parts(token_ptr, token_len);
+        let share_password = std::slice::from_raw_parts(share_password_ptr, share_password_len);
+
+        match decrypt_with_share_key(input_path, output_path, token, share_password, is_mobile, cpu_cores) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+unsafe fn scope_from_ffi(can_reshare: bool, expires_at: u64, label_ptr: *const c_char) -> Option<ShareScope> {
+    let label = if label_ptr.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(label_ptr).to_str().ok()?.to_string()
+    };
+    Some(ShareScope { can_reshare, expires_at, label })
+}
+
+unsafe fn copy_out(bytes: &[u8], ptr: *mut u8, len: *mut usize) -> i32 {
+    *len = bytes.len();
+    if !ptr.is_null() {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+    }
+    0
+}
+
+// Only files keyed through a salted KDF have a key of their own; the legacy
+// key is the same for every file under the password.
+pub fn export_share_key(
+    path: &Path,
+    password: &[u8],
+    share_password: &[u8],
+    scope: &ShareScope,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    if header.kdf == KdfParams::LegacySha256 || header.share_policy.is_some() {
+        return Err("File has no key of its own to share".into());
+    }
+    let file_id = header.file_id.ok_or("File has no key of its own to share")?;
+    let key = Zeroizing::new(header.master_key(password)?);
+    header.verify_file_id(&key).map_err(|_| "Invalid password")?;
+
+    let share_key = ShareKey { key, file_uuid: file_id.uuid, scope: scope.clone() };
+    seal(&share_key, share_password)
+}
+
+pub fn open_share_key(token: &[u8], share_password: &[u8]) -> Result<ShareKey, Box<dyn std::error::Error>> {
+    if token.len() < SHARE_KEY_PREFIX_LEN + NONCE_SIZE || &token[..SHARE_KEY_MAGIC.len()] != SHARE_KEY_MAGIC {
+        return Err("Invalid share key".into());
+    }
+    let (prefix, rest) = token.split_at(SHARE_KEY_PREFIX_LEN);
+    let (nonce, sealed) = rest.split_at(NONCE_SIZE);
+    let fields = &prefix[SHARE_KEY_MAGIC.len()..];
+    if fields[0] != SHARE_KEY_VERSION {
+        return Err("Unsupported version".into());
+    }
+    let (salt, params) = fields[1..].split_at(KDF_SALT_SIZE);
+    let params = KdfParams::Argon2id {
+        memory_kib: u32::from_le_bytes(params[..4].try_into()?),
+        iterations: u32::from_le_bytes(params[4..8].try_into()?),
+        lanes: params[8] as u32,
+    };
+
+    let wrapping_key = Zeroizing::new(kdf::derive(share_password, salt, &params)?);
+    let cipher = Aes256Gcm::new_from_slice(wrapping_key.as_slice())?;
+    let payload = Zeroizing::new(
+        cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: prefix })
+            .map_err(|_| "Decryption failed")?,
+    );
+    if payload.len() < 32 + FILE_ID_SIZE {
+        return Err("Invalid share key".into());
+    }
+    let (key, rest) = payload.split_at(32);
+    let (file_uuid, scope) = rest.split_at(FILE_ID_SIZE);
+    Ok(ShareKey {
+        key: Zeroizing::new(key.try_into()?),
+        file_uuid: file_uuid.try_into()?,
+        scope: serde_json::from_slice(scope).map_err(|_| "Invalid share key")?,
+    })
+}
+
+pub fn share_key_info(token: &[u8], share_password: &[u8]) -> Result<ShareKeyInfo, Box<dyn std::error::Error>> {
+    let share_key = open_share_key(token, share_password)?;
+    let hex = hex::encode(share_key.file_uuid);
+    Ok(ShareKeyInfo {
+        file_id: format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]),
+        expired: share_key.scope.expired(),
+        scope: share_key.scope,
+    })
+}
+
+pub fn reshare_share_key(
+    token: &[u8],
+    share_password: &[u8],
+    new_share_password: &[u8],
+    scope: &ShareScope,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let share_key = open_share_key(token, share_password)?;
+    if share_key.scope.expired() {
+        return Err("Share key has expired".into());
+    }
+    if !share_key.scope.can_reshare {
+        return Err("Share key does not allow re-sharing".into());
+    }
+    let scope = scope.narrowed_by(&share_key.scope);
+    seal(&ShareKey { scope, ..share_key }, new_share_password)
+}
+
+pub fn decrypt_with_share_key(
+    input_path: &str,
+    output_path: &str,
+    token: &[u8],
+    share_password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let share_key = open_share_key(token, share_password)?;
+    if share_key.scope.expired() {
+        return Err("Share key has expired".into());
+    }
+    let (header, _) = read_header(Path::new(input_path))?;
+    if header.file_id.map(|id| id.uuid) != Some(share_key.file_uuid) {
+        return Err("Share key is for a different file".into());
+    }
+    decrypt_file_reporting(input_path, output_path, &share_key, is_mobile, cpu_cores, &ProgressSink::none())
+}
+
+fn seal(share_key: &ShareKey, share_password: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let salt: [u8; KDF_SALT_SIZE] = rng::random();
+    let params = KdfParams::for_new_files(false);
+    let KdfParams::Argon2id { memory_kib, iterations, lanes } = params else {
+        return Err("Unsupported KDF parameters".into());
+    };
+    let mut token = Vec::with_capacity(SHARE_KEY_PREFIX_LEN + NONCE_SIZE);
+    token.extend_from_slice(SHARE_KEY_MAGIC);
+    token.push(SHARE_KEY_VERSION);
+    token.extend_from_slice(&salt);
+    token.extend_from_slice(&memory_kib.to_le_bytes());
+    token.extend_from_slice(&iterations.to_le_bytes());
+    token.push(lanes as u8);
+
+    let mut payload = Zeroizing::new(Vec::new());
+    payload.extend_from_slice(share_key.key.as_slice());
+    payload.extend_from_slice(&share_key.file_uuid);
+    payload.extend_from_slice(&serde_json::to_vec(&share_key.scope)?);
+
+    let wrapping_key = Zeroizing::new(kdf::derive(share_password, &salt, &params)?);
+    let cipher = Aes256Gcm::new_from_slice(wrapping_key.as_slice())?;
+    let nonce = random_nonce();
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: payload.as_slice(), aad: &token })
+        .map_err(|_| "Encryption failed")?;
+    token.extend_from_slice(&nonce);
+    token.extend_from_slice(&sealed);
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+    use std::fs;
+
+    #[test]
+    fn test_share_key_scope_is_enforced() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let encrypted = dir.path().join("report.kyl");
+        let decrypted = dir.path().join("report.out");
+        fs::write(&plain, b"quarterly report").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+
+        let scope = ShareScope { can_reshare: true, expires_at: 0, label: "for Sam".to_string() };
+        let token = export_share_key(&encrypted, b"pw", b"share", &scope).unwrap();
+        assert!(export_share_key(&encrypted, b"wrong", b"share", &scope).is_err());
+        let info = share_key_info(&token, b"share").unwrap();
+        assert_eq!((&info.scope, info.expired), (&scope, false));
+        assert_eq!(info.file_id, read_header(&encrypted).unwrap().0.file_id.unwrap().to_uuid_string());
+
+        let (input, output) = (encrypted.to_str().unwrap(), decrypted.to_str().unwrap());
+        decrypt_with_share_key(input, output, &token, b"share", false, 4).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"quarterly report");
+        assert!(decrypt_with_share_key(input, output, &token, b"wrong", false, 4).is_err());
+
+        // The re-shared key cannot be passed on or outlive its parent.
+        let wider = ShareScope { can_reshare: true, expires_at: 0, label: "onward".to_string() };
+        let parent = ShareScope { can_reshare: true, expires_at: 1, label: String::new() };
+        let narrowed = wider.narrowed_by(&parent);
+        assert_eq!((narrowed.can_reshare, narrowed.expires_at), (true, 1));
+        let read_only = ShareScope { can_reshare: false, ..wider };
+        let reshared = reshare_share_key(&token, b"share", b"friend", &read_only).unwrap();
+        decrypt_with_share_key(input, output, &reshared, b"friend", false, 4).unwrap();
+        let err = reshare_share_key(&reshared, b"friend", b"other", &scope).unwrap_err();
+        assert_eq!(errors::classify(err.as_ref()), errors::ERR_SHARE_NOT_PERMITTED);
+
+        let expired = export_share_key(&encrypted, b"pw", b"share", &parent).unwrap();
+        assert!(share_key_info(&expired, b"share").unwrap().expired);
+        let err = decrypt_with_share_key(input, output, &expired, b"share", false, 4).unwrap_err();
+        assert_eq!(errors::classify(err.as_ref()), errors::ERR_SHARE_EXPIRED);
+    }
+}
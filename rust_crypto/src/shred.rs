@@ -0,0 +1,215 @@
+use rand::RngCore;
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::profile::OptionsProfile;
+use crate::vault::hash_file;
+
+const SHRED_BUFFER_SIZE: usize = 1024 * 1024;
+
+const MULTI_PASS_PATTERNS: [Option<u8>; 3] = [Some(0x00), Some(0xFF), None];
+
+pub const SHRED_STRATEGY_SINGLE_PASS: i32 = 0;
+pub const SHRED_STRATEGY_MULTI_PASS: i32 = 1;
+pub const SHRED_STRATEGY_FLASH: i32 = 2;
+
+pub const SHRED_VERIFY_SKIPPED: i32 = 0;
+pub const SHRED_VERIFY_PASSED: i32 = 1;
+pub const SHRED_VERIFY_FAILED: i32 = 2;
+
+#[derive(Default)]
+pub struct ShredOptions {
+    pub strategy: i32,
+    pub verify: bool,
+}
+
+#[repr(C)]
+#[derive(Default, Debug, PartialEq)]
+pub struct KyrieShredReport {
+    pub strategy: i32,
+    pub passes: u32,
+    pub bytes_overwritten: u64,
+    pub trim_hinted: bool,
+    pub verification: i32,
+}
+
+#[no_mangle]
+pub extern "C" fn shred_file(path_ptr: *const c_char) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+
+        match shred_file_internal(Path::new(path)) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn shred_file_with_report(
+    path_ptr: *const c_char,
+    strategy: i32,
+    verify: bool,
+    report_ptr: *mut KyrieShredReport,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        if !(SHRED_STRATEGY_SINGLE_PASS..=SHRED_STRATEGY_FLASH).contains(&strategy) {
+            return -1;
+        }
+
+        match shred_file_with_options(Path::new(path), &ShredOptions { strategy, verify }) {
+            Ok(report) => {
+                let verified = report.verification != SHRED_VERIFY_FAILED;
+                if !report_ptr.is_null() {
+                    *report_ptr = report;
+                }
+                if verified {
+                    0
+                } else {
+                    -2
+                }
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+pub(crate) fn shred_file_internal(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    shred_file_with_options(path, &OptionsProfile::current().shred_options())?;
+    Ok(())
+}
+
+pub fn shred_file_with_options(path: &Path, options: &ShredOptions) -> Result<KyrieShredReport, Box<dyn std::error::Error>> {
+    let file_size = fs::metadata(path)?.len();
+    let original_digest = if options.verify { Some(file_digest(path)?) } else { None };
+
+    let mut report = KyrieShredReport {
+        strategy: options.strategy,
+        verification: SHRED_VERIFY_SKIPPED,
+        ..Default::default()
+    };
+    let path = if options.strategy == SHRED_STRATEGY_FLASH {
+        let renamed = path.with_file_name(format!(".{}", hex::encode(rand::random::<[u8; 8]>())));
+        fs::rename(path, &renamed)?;
+        let file = OpenOptions::new().write(true).open(&renamed)?;
+        report.trim_hinted = punch_hole(&file, file_size);
+        file.set_len(0)?;
+        file.sync_all()?;
+        renamed
+    } else {
+        let patterns: &[Option<u8>] = if options.strategy == SHRED_STRATEGY_MULTI_PASS {
+            &MULTI_PASS_PATTERNS
+        } else {
+            &[None]
+        };
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        for pattern in patterns {
+            overwrite_pass(&mut file, file_size, *pattern)?;
+            report.passes += 1;
+            report.bytes_overwritten += file_size;
+        }
+        path.to_path_buf()
+    };
+
+    if let Some(original_digest) = original_digest {
+        let unchanged = file_size > 0 && file_digest(&path)? == original_digest;
+        if unchanged {
+            report.verification = SHRED_VERIFY_FAILED;
+            return Ok(report);
+        }
+        report.verification = SHRED_VERIFY_PASSED;
+    }
+
+    fs::remove_file(&path)?;
+    Ok(report)
+}
+
+fn overwrite_pass(file: &mut File, file_size: u64, pattern: Option<u8>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buffer = vec![pattern.unwrap_or(0); SHRED_BUFFER_SIZE];
+    let mut rng = rand::thread_rng();
+    let mut remaining = file_size;
+    file.seek(SeekFrom::Start(0))?;
+    while remaining > 0 {
+        let n = remaining.min(SHRED_BUFFER_SIZE as u64) as usize;
+        if pattern.is_none() {
+            rng.fill_bytes(&mut buffer[..n]);
+        }
+        file.write_all(&buffer[..n])?;
+        remaining -= n as u64;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn punch_hole(file: &File, len: u64) -> bool {
+    use std::os::unix::io::AsRawFd;
+    if len == 0 {
+        return false;
+    }
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    unsafe { libc::fallocate(file.as_raw_fd(), mode, 0, len as libc::off_t) == 0 }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn punch_hole(_file: &File, _len: u64) -> bool {
+    false
+}
+
+fn file_digest(path: &Path) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let mut hasher = blake3::Hasher::new();
+    hash_file(&mut hasher, path)?;
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shred_removes_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.txt");
+        fs::write(&path, vec![0x41u8; SHRED_BUFFER_SIZE + 17]).unwrap();
+
+        shred_file_internal(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_shred_with_verification_reports_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.txt");
+        fs::write(&path, vec![0x42u8; 4096]).unwrap();
+
+        let options = ShredOptions {
+            strategy: SHRED_STRATEGY_MULTI_PASS,
+            verify: true,
+        };
+        let report = shred_file_with_options(&path, &options).unwrap();
+        assert_eq!(report.passes, 3);
+        assert_eq!(report.bytes_overwritten, 3 * 4096);
+        assert_eq!(report.verification, SHRED_VERIFY_PASSED);
+        assert!(!path.exists());
+
+        fs::write(&path, vec![0x42u8; 4096]).unwrap();
+        let options = ShredOptions {
+            strategy: SHRED_STRATEGY_FLASH,
+            verify: true,
+        };
+        let report = shred_file_with_options(&path, &options).unwrap();
+        assert_eq!((report.passes, report.bytes_overwritten), (0, 0));
+        assert_eq!(report.verification, SHRED_VERIFY_PASSED);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
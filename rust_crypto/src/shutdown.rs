@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+use crate::config;
+use crate::operations::OperationRegistry;
+use crate::session;
+
+#[repr(C)]
+#[derive(Default, Debug, PartialEq)]
+pub struct KyrieShutdownSummary {
+    pub completed: u32,
+    pub cancelled: u32,
+    pub checkpointed: u32,
+    pub interrupted: u32,
+    pub sessions_locked: u32,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_shutdown(timeout_ms: u64, summary_ptr: *mut KyrieShutdownSummary) -> i32 {
+    let summary = shutdown(Duration::from_millis(timeout_ms));
+    let drained = summary.interrupted == 0;
+    if !summary_ptr.is_null() {
+        unsafe { *summary_ptr = summary };
+    }
+    if drained {
+        0
+    } else {
+        -2
+    }
+}
+
+// Finished operations have already synced and renamed their outputs into
+// place, so once the registry is drained only the key material is left.
+pub fn shutdown(timeout: Duration) -> KyrieShutdownSummary {
+    let mut summary = OperationRegistry::global().shutdown(timeout);
+    summary.sessions_locked = session::lock_all();
+    config::release_pool();
+    summary
+}
@@ -0,0 +1,302 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use zeroize::Zeroize;
+
+use crate::format::{Hole, MAX_HOLES};
+
+// Smaller holes are sealed as zeros; each one costs header space and a seek
+// on both ends.
+const MIN_HOLE: u64 = 64 * 1024;
+// Zero runs written to a sparse output smaller than this are written out.
+const MIN_SKIP: usize = 4096;
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+// The unallocated runs of the file, from SEEK_HOLE and SEEK_DATA. File
+// systems without them report none, as do platforms this does not cover.
+pub fn find_holes(path: &Path) -> io::Result<Vec<Hole>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut holes = Vec::new();
+    let mut position = 0;
+    while position < len {
+        let Some(start) = seek_hole(&file, position)? else { break };
+        if start >= len {
+            break;
+        }
+        let end = seek_data(&file, start)?.unwrap_or(len).min(len);
+        if end <= start {
+            break;
+        }
+        if end - start >= MIN_HOLE {
+            holes.push(Hole { offset: start, len: end - start });
+        }
+        position = end;
+    }
+    if holes.len() > MAX_HOLES {
+        holes.sort_by_key(|hole| std::cmp::Reverse(hole.len));
+        holes.truncate(MAX_HOLES);
+        holes.sort_by_key(|hole| hole.offset);
+    }
+    Ok(holes)
+}
+
+pub fn hole_bytes(holes: &[Hole]) -> u64 {
+    holes.iter().map(|hole| hole.len).sum()
+}
+
+// For paths that hand out plaintext by offset and would need the hole map
+// to do it; they turn sparse containers away like any unknown field.
+pub fn require_dense(holes: &[Hole]) -> Result<(), Box<dyn std::error::Error>> {
+    match holes.is_empty() {
+        true => Ok(()),
+        false => Err("Unsupported header field".into()),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+fn seek_hole(file: &File, from: u64) -> io::Result<Option<u64>> {
+    lseek(file, from, libc::SEEK_HOLE)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+fn seek_data(file: &File, from: u64) -> io::Result<Option<u64>> {
+    lseek(file, from, libc::SEEK_DATA)
+}
+
+// None where the file system cannot say, or past the last data for SEEK_DATA.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+fn lseek(file: &File, from: u64, whence: i32) -> io::Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+    let offset = unsafe { libc::lseek(file.as_raw_fd(), from as libc::off_t, whence) };
+    if offset >= 0 {
+        return Ok(Some(offset as u64));
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::ENXIO) | Some(libc::EINVAL) => Ok(None),
+        _ => Err(error),
+    }
+}
+
+// Windows would take FSCTL_QUERY_ALLOCATED_RANGES, which is not wired up,
+// so files there are always read whole.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+fn seek_hole(_file: &File, _from: u64) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+fn seek_data(_file: &File, _from: u64) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+// Reads the file with the holes left out.
+pub struct ExtentReader<R> {
+    inner: R,
+    holes: Vec<Hole>,
+    next: usize,
+    position: u64,
+}
+
+impl<R: Read + Seek> ExtentReader<R> {
+    pub fn new(inner: R, holes: &[Hole]) -> Self {
+        ExtentReader { inner, holes: holes.to_vec(), next: 0, position: 0 }
+    }
+}
+
+impl<R: Read + Seek> Read for ExtentReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(hole) = self.holes.get(self.next).filter(|hole| hole.offset == self.position) {
+            self.position = self.inner.seek(SeekFrom::Start(hole.offset + hole.len))?;
+            self.next += 1;
+        }
+        let limit = match self.holes.get(self.next) {
+            Some(hole) => ((hole.offset - self.position) as usize).min(buf.len()),
+            None => buf.len(),
+        };
+        let n = self.inner.read(&mut buf[..limit])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+pub enum Piece<'a> {
+    Data(&'a [u8]),
+    Hole(u64),
+}
+
+// Tracks where the data read back from a body falls in the plaintext, and
+// which holes come before and between it.
+#[derive(Default)]
+pub struct HoleCursor {
+    holes: Vec<Hole>,
+    next: usize,
+    position: u64,
+}
+
+impl HoleCursor {
+    pub fn new(holes: &[Hole]) -> Self {
+        HoleCursor { holes: holes.to_vec(), next: 0, position: 0 }
+    }
+
+    pub fn feed<E>(&mut self, mut data: &[u8], mut emit: impl FnMut(Piece) -> Result<(), E>) -> Result<(), E> {
+        loop {
+            while let Some(hole) = self.holes.get(self.next).filter(|hole| hole.offset == self.position).copied() {
+                emit(Piece::Hole(hole.len))?;
+                self.position += hole.len;
+                self.next += 1;
+            }
+            if data.is_empty() {
+                return Ok(());
+            }
+            let n = match self.holes.get(self.next) {
+                Some(hole) => ((hole.offset - self.position) as usize).min(data.len()),
+                None => data.len(),
+            };
+            emit(Piece::Data(&data[..n]))?;
+            self.position += n as u64;
+            data = &data[n..];
+        }
+    }
+
+    // Emits the holes at the end; any left over lie past the data, which a
+    // body that matches its header never leaves.
+    pub fn finish<E>(&mut self, emit: impl FnMut(Piece) -> Result<(), E>) -> Result<bool, E> {
+        self.feed(&[], emit)?;
+        Ok(self.next == self.holes.len())
+    }
+}
+
+pub fn zero_blocks(len: u64) -> impl Iterator<Item = &'static [u8]> {
+    let block = ZEROS.len() as u64;
+    (0..len.div_ceil(block)).map(move |i| &ZEROS[..(len - i * block).min(block) as usize])
+}
+
+// Puts the holes back as zeros between the data written through it.
+pub struct HoleFiller<W: Write> {
+    inner: W,
+    cursor: HoleCursor,
+}
+
+impl<W: Write> HoleFiller<W> {
+    pub fn new(inner: W, holes: &[Hole]) -> Self {
+        HoleFiller { inner, cursor: HoleCursor::new(holes) }
+    }
+
+    pub fn finish(mut self) -> Result<W, Box<dyn std::error::Error>> {
+        let inner = &mut self.inner;
+        if !self.cursor.finish(|piece| write_piece(inner, piece))? {
+            return Err("Invalid file format".into());
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for HoleFiller<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.cursor.feed(buf, |piece| write_piece(inner, piece))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_piece<W: Write>(output: &mut W, piece: Piece) -> io::Result<()> {
+    match piece {
+        Piece::Data(data) => output.write_all(data),
+        Piece::Hole(len) => zero_blocks(len).try_for_each(|zeros| output.write_all(zeros)),
+    }
+}
+
+// For plaintext decrypted whole into memory.
+pub fn fill_holes(mut data: Vec<u8>, holes: &[Hole]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if holes.is_empty() {
+        return Ok(data);
+    }
+    let mut filler = HoleFiller::new(Vec::with_capacity(data.len() + hole_bytes(holes) as usize), holes);
+    filler.write_all(&data)?;
+    data.zeroize();
+    filler.finish()
+}
+
+// Seeks over runs of zeros instead of writing them, so a file put back from
+// a sparse container is sparse again. Zeros at the end are left to the
+// length set on flush.
+pub struct SparseWriter<'a> {
+    file: &'a mut File,
+    pending: u64,
+}
+
+impl<'a> SparseWriter<'a> {
+    pub fn new(file: &'a mut File) -> Self {
+        SparseWriter { file, pending: 0 }
+    }
+}
+
+impl Write for SparseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() >= MIN_SKIP && buf.iter().all(|&b| b == 0) {
+            self.pending += buf.len() as u64;
+            return Ok(buf.len());
+        }
+        if self.pending > 0 {
+            self.file.seek(SeekFrom::Current(self.pending as i64))?;
+            self.pending = 0;
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending > 0 {
+            let end = self.file.stream_position()? + self.pending;
+            self.file.set_len(end)?;
+            self.file.seek(SeekFrom::Start(end))?;
+            self.pending = 0;
+        }
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::read_header;
+    use crate::{decrypt_file_internal, decrypt_file_to_memory_internal, encrypt_file_internal};
+    use std::fs;
+
+    #[test]
+    fn test_sparse_files_round_trip_without_their_holes() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("disk.img");
+        let encrypted = dir.path().join("disk.kyl");
+        let decrypted = dir.path().join("disk.out");
+        // Data, a 4 MiB hole, data, then a hole to the end.
+        let mut file = File::create(&plain).unwrap();
+        file.write_all(&vec![7u8; 1_000_000]).unwrap();
+        file.seek(SeekFrom::Start(4 * 1024 * 1024)).unwrap();
+        file.write_all(&vec![9u8; 1_000_000]).unwrap();
+        file.set_len(12 * 1024 * 1024).unwrap();
+        drop(file);
+        let holes = find_holes(&plain).unwrap();
+        let (plain_str, encrypted_str) = (plain.to_str().unwrap(), encrypted.to_str().unwrap());
+
+        encrypt_file_internal(plain_str, encrypted_str, b"pw", None, false, 4).unwrap();
+        let (header, _) = read_header(&encrypted).unwrap();
+        assert_eq!(header.holes, holes);
+        decrypt_file_internal(encrypted_str, decrypted.to_str().unwrap(), b"pw", false, 4).unwrap();
+        let original = fs::read(&plain).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), original);
+        assert_eq!(decrypt_file_to_memory_internal(encrypted_str, b"pw", false, 4).unwrap(), original);
+
+        // Only file systems that report holes give a smaller container.
+        if !holes.is_empty() {
+            assert!(fs::metadata(&encrypted).unwrap().len() < 3 * 1024 * 1024);
+            assert_eq!(find_holes(&decrypted).unwrap().len(), holes.len());
+        }
+    }
+}
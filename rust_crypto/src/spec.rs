@@ -0,0 +1,384 @@
+use serde::Serialize;
+
+use crate::cipher::{CIPHER_AES_256_GCM, CIPHER_CHACHA20_POLY1305};
+use crate::errors;
+use crate::format::{
+    CHECKSUM_SIZE, CHECKSUMS_PER_FIELD, CHUNK_INDEX_SIZE, CHUNK_LEN_SIZE, EXTENDED_VERSION, FIELD_CHUNK_CHECKSUMS,
+    FIELD_CHUNK_INDEX, FIELD_CONVERGENT, FIELD_ESCROW, FIELD_WRAPPED_KEY, WRAPPED_KEY_SIZE, FIELD_KEY_SLOT, KEY_SLOT_SIZE, MAX_KEY_SLOTS,
+    FIELD_CRITICAL, FIELD_FILE_ID, FIELD_HEADER_BACKUP, FIELD_KDF, FIELD_KEY_PURPOSE, FIELD_KEY_SHARE, FIELD_NONCE_SCHEME,
+    ESCROW_SLOT_SIZE, FIELD_PADDING, FIELD_SHARE_POLICY, KDF_ARGON2ID, KDF_FIELD_SIZE, KEY_SHARE_SIZE, MAX_CHUNK_CHECKSUMS,
+    MAX_HEADER_LEN, NONCE_SCHEME_COUNTER, NONCE_SCHEME_RANDOM, SEALED_CONTENT_KEY_SIZE, SHARE_SALT_SIZE, FIELD_CIPHER_SUITE,
+    FIELD_FRAMING, FRAMING_SIZE, FIELD_KEYFILE, FIELD_RECIPIENT, FIELD_METADATA, FIELD_MANIFEST, FRAMING_SINGLE, FRAMING_LENGTH_PREFIXED, FRAMING_STREAMED, FRAMING_CONVERGENT_CHUNKS,
+    FIELD_PREVIEW, FIELD_HINT, FIELD_DEVICE_SLOT, MAX_DEVICE_BLOB_LEN, FIELD_PARITY, PARITY_FIELD_SIZE, FIELD_APP_METADATA,
+    FIELD_PRIVATE_HINT, PRIVATE_HINT_PADDED, PRIVATE_HINT_SEALED, FIELD_HOLES, MAX_HOLES,
+};
+use crate::kdf::{KDF_SALT_SIZE, KEY_PURPOSE_DEVICE, KEY_PURPOSE_FILE_DATA, KEY_PURPOSE_LEGACY, KEY_PURPOSE_METADATA};
+use crate::key_protector::{PROTECTOR_DPAPI, PROTECTOR_HOST_KEYSTORE};
+use crate::manifest::MANIFEST_SIZE;
+use crate::padding::{PADDING_BUCKET, PADDING_MARKER, PADDING_NONE, PADDING_PADME, PADDING_POWER_OF_TWO, PADDING_SLOTS};
+use crate::pipeline::MAX_FRAME_LEN;
+use crate::privacy::STEALTH_LAYOUT;
+use crate::raw::RAW_LAYOUT;
+use crate::{get_chunk_size, HEADER_SIZE, MAGIC_STRING, MAX_HINT_LENGTH, MAX_LONG_HINT_LENGTH, NONCE_SIZE, TAG_SIZE, VERSION};
+
+// Built from the constants the codec itself uses, so the published layout
+// cannot drift from what is read and written.
+#[derive(Serialize, Debug)]
+pub struct FormatSpec {
+    pub magic: &'static str,
+    pub versions: Vec<VersionSpec>,
+    pub fixed_header: Vec<FixedField>,
+    pub header_fields: Vec<TlvField>,
+    pub body: BodySpec,
+    pub values: Vec<ValueSet>,
+    pub limits: Vec<Limit>,
+    pub raw_layout: &'static str,
+    pub stealth_layout: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VersionSpec {
+    pub version: u32,
+    pub description: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FixedField {
+    pub name: &'static str,
+    // None when the position depends on the hint length.
+    pub offset: Option<usize>,
+    pub size: Option<usize>,
+    pub encoding: &'static str,
+    pub since_version: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TlvField {
+    pub tag: u8,
+    pub name: &'static str,
+    pub critical: bool,
+    pub size: Option<usize>,
+    pub repeatable: bool,
+    pub layout: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BodySpec {
+    pub nonce_size: usize,
+    pub tag_size: usize,
+    pub chunk_length_size: u64,
+    pub chunk_length_encoding: &'static str,
+    pub desktop_chunk_size: usize,
+    pub mobile_chunk_size: usize,
+    pub framed_record: &'static str,
+    pub single_chunk_record: &'static str,
+    pub backup_trailer: &'static str,
+    pub padding_marker: u8,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ValueSet {
+    pub name: &'static str,
+    pub values: Vec<(u8, &'static str)>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Limit {
+    pub name: &'static str,
+    pub value: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_format_spec(json_ptr: *mut u8, json_len: *mut usize) -> i32 {
+    if json_len.is_null() {
+        return errors::invalid_argument();
+    }
+    match serde_json::to_vec(&format_spec()) {
+        Ok(json) => unsafe {
+            *json_len = json.len();
+            if !json_ptr.is_null() {
+                std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+            }
+            0
+        },
+        Err(e) => errors::fail(&e),
+    }
+}
+
+pub fn format_spec() -> FormatSpec {
+    let hint_len_offset = HEADER_SIZE;
+    FormatSpec {
+        magic: std::str::from_utf8(MAGIC_STRING).expect("the magic string is ASCII"),
+        versions: vec![
+            VersionSpec { version: VERSION, description: "Fixed header and hint only" },
+            VersionSpec { version: EXTENDED_VERSION, description: "Fixed header, hint and a TLV field area" },
+        ],
+        fixed_header: vec![
+            fixed("magic", Some(0), Some(MAGIC_STRING.len()), "ASCII", VERSION),
+            fixed("version", Some(MAGIC_STRING.len()), Some(HEADER_SIZE - MAGIC_STRING.len()), "u32 little-endian", VERSION),
+            fixed("hint_length", Some(hint_len_offset), Some(1), "u8", VERSION),
+            fixed("hint", Some(hint_len_offset + 1), None, "hint_length bytes of UTF-8, a prefix of the hint field when there is one", VERSION),
+            fixed("field_area_length", None, Some(2), "u16 little-endian, after the hint", EXTENDED_VERSION),
+            fixed("field_area", None, None, "field_area_length bytes of TLV fields", EXTENDED_VERSION),
+        ],
+        header_fields: vec![
+            tlv(FIELD_FILE_ID, "file_id", None, false, "uuid(16) || truncated HMAC-SHA256 of the header(16), then a second tag(16) for the other slot when the padding is slots"),
+            tlv(
+                FIELD_CHUNK_CHECKSUMS,
+                "chunk_checksums",
+                None,
+                true,
+                "Up to CHECKSUMS_PER_FIELD CRC32 values, u32 little-endian, over each stored frame",
+            ),
+            tlv(
+                FIELD_CHUNK_INDEX,
+                "chunk_index",
+                Some(CHUNK_INDEX_SIZE),
+                false,
+                "Plaintext size of every frame but the last, u32 little-endian",
+            ),
+            tlv(
+                FIELD_FRAMING,
+                "framing",
+                Some(FRAMING_SIZE),
+                false,
+                "framing version(1) || chunk size u32 LE || chunk count u64 LE; replaces chunk_index",
+            ),
+            tlv(
+                FIELD_MANIFEST,
+                "manifest",
+                Some(MANIFEST_SIZE),
+                false,
+                "chunk count u64 LE || plaintext length u64 LE || SHA-256 chain over (index u64 LE || tag) of every frame",
+            ),
+            tlv(
+                FIELD_ESCROW,
+                "escrow",
+                Some(ESCROW_SLOT_SIZE),
+                true,
+                "agent id(8) || ephemeral X25519 key(32) || nonce(12) || sealed master key(48)",
+            ),
+            tlv(
+                FIELD_RECIPIENT,
+                "recipient",
+                Some(ESCROW_SLOT_SIZE),
+                true,
+                "recipient key id(8) || ephemeral X25519 key(32) || nonce(12) || sealed file key(48)",
+            ),
+            tlv(
+                FIELD_DEVICE_SLOT,
+                "device_slot",
+                None,
+                true,
+                "Consecutive fields joined: per slot, keystore kind(1) || length u16 LE || keystore-wrapped file key",
+            ),
+            tlv(
+                FIELD_HINT,
+                "hint",
+                None,
+                true,
+                "Consecutive fields joined: length u16 LE || UTF-8 hint, written when it exceeds the fixed slot",
+            ),
+            tlv(
+                FIELD_PRIVATE_HINT,
+                "private_hint",
+                None,
+                false,
+                "private hint mode(1) || hint as that mode stores it; the fixed hint slot is then empty",
+            ),
+            tlv(
+                FIELD_METADATA,
+                "metadata",
+                None,
+                true,
+                "Consecutive fields joined: nonce(12) || original name, size, mtime and MIME type as sealed JSON",
+            ),
+            tlv(
+                FIELD_PREVIEW,
+                "preview",
+                None,
+                true,
+                "Consecutive fields joined: nonce(12) || sealed thumbnail bytes, under the preview subkey",
+            ),
+            tlv(
+                FIELD_APP_METADATA,
+                "app_metadata",
+                None,
+                true,
+                "Consecutive fields joined: nonce(12) || sealed entries of key length(1) || UTF-8 key || value length u16 LE || value",
+            ),
+            tlv(
+                FIELD_HOLES,
+                "holes",
+                None,
+                true,
+                "Consecutive fields joined: per hole, plaintext offset u64 LE || length u64 LE, sorted; the body holds only the data between them",
+            ),
+            tlv(FIELD_PADDING, "padding", Some(1), false, "Padding scheme"),
+            tlv(FIELD_HEADER_BACKUP, "header_backup", Some(0), false, "Empty; the file ends in a backup trailer"),
+            tlv(
+                FIELD_PARITY,
+                "parity",
+                Some(PARITY_FIELD_SIZE),
+                false,
+                "percent(1) || shard size u32 LE || parity length u64 LE; per frame, parity shards || CRC32 of every shard follow the body",
+            ),
+            tlv(FIELD_KEYFILE, "keyfile", Some(0), false, "Empty; the KDF input is the password followed by the keyfile hash"),
+            tlv(FIELD_NONCE_SCHEME, "nonce_scheme", Some(1), false, "Nonce scheme"),
+            tlv(FIELD_KEY_PURPOSE, "key_purpose", Some(1), false, "Key purpose of the body key"),
+            tlv(FIELD_CIPHER_SUITE, "cipher_suite", Some(1), false, "Body AEAD; absent means AES-256-GCM"),
+            tlv(
+                FIELD_SHARE_POLICY,
+                "share_policy",
+                None,
+                false,
+                "threshold(1) || share count(1), then kdf id(1) || memory KiB u32 LE || iterations u32 LE || lanes(1) unless the slots use the legacy key",
+            ),
+            tlv(FIELD_KEY_SHARE, "key_share", Some(KEY_SHARE_SIZE), true, "x(1) || salt(16) || nonce(12) || sealed share(48)"),
+            tlv(
+                FIELD_KDF,
+                "kdf",
+                Some(KDF_FIELD_SIZE),
+                false,
+                "kdf id(1) || memory KiB u32 LE || iterations u32 LE || lanes(1) || salt(16)",
+            ),
+            tlv(
+                FIELD_WRAPPED_KEY,
+                "wrapped_key",
+                Some(WRAPPED_KEY_SIZE),
+                false,
+                "nonce(12) || file key sealed under the password key(48)",
+            ),
+            tlv(
+                FIELD_KEY_SLOT,
+                "key_slot",
+                Some(KEY_SLOT_SIZE),
+                true,
+                "salt(16) || nonce(12) || file key sealed under that slot's password key(48)",
+            ),
+            tlv(FIELD_CONVERGENT, "convergent", Some(SEALED_CONTENT_KEY_SIZE), false, "nonce(12) || sealed content key(48)"),
+        ],
+        body: BodySpec {
+            nonce_size: NONCE_SIZE,
+            tag_size: TAG_SIZE,
+            chunk_length_size: CHUNK_LEN_SIZE,
+            chunk_length_encoding: "u32 big-endian, ciphertext length including the tag",
+            desktop_chunk_size: get_chunk_size(false),
+            mobile_chunk_size: get_chunk_size(true),
+            framed_record: "nonce (absent with counter nonces) || chunk length || AEAD ciphertext",
+            single_chunk_record: "nonce (absent with counter nonces) || AEAD ciphertext to the end of the file",
+            backup_trailer: "header copy || header length, u32 little-endian || KYRIE_LOCK_BACKUP",
+            padding_marker: PADDING_MARKER,
+        },
+        values: vec![
+            ValueSet {
+                name: "padding",
+                values: vec![
+                    (PADDING_NONE, "none"),
+                    (PADDING_PADME, "padme"),
+                    (PADDING_POWER_OF_TWO, "power of two"),
+                    (PADDING_SLOTS, "padme, in a body of two equal slots"),
+                    (PADDING_BUCKET, "multiples of 512 << (scheme & 0x0f) bytes, for schemes 0x10 to 0x1f"),
+                ],
+            },
+            ValueSet {
+                name: "nonce_scheme",
+                values: vec![(NONCE_SCHEME_RANDOM, "random, stored"), (NONCE_SCHEME_COUNTER, "counter, derived")],
+            },
+            ValueSet {
+                name: "key_purpose",
+                values: vec![
+                    (KEY_PURPOSE_LEGACY, "password key"),
+                    (KEY_PURPOSE_FILE_DATA, "file data subkey"),
+                    (KEY_PURPOSE_METADATA, "metadata subkey"),
+                    (KEY_PURPOSE_DEVICE, "device document subkey"),
+                ],
+            },
+            ValueSet {
+                name: "framing",
+                values: vec![
+                    (FRAMING_SINGLE, "single unframed record"),
+                    (FRAMING_LENGTH_PREFIXED, "length-prefixed frames"),
+                    (FRAMING_STREAMED, "length-prefixed frames of unknown count, ending in an empty frame"),
+                    (FRAMING_CONVERGENT_CHUNKS, "frames each led by their sealed content key, then a MAC over the header and the keys"),
+                ],
+            },
+            ValueSet {
+                name: "cipher_suite",
+                values: vec![(CIPHER_AES_256_GCM, "AES-256-GCM"), (CIPHER_CHACHA20_POLY1305, "ChaCha20-Poly1305")],
+            },
+            ValueSet { name: "kdf", values: vec![(KDF_ARGON2ID, "Argon2id v1.3")] },
+            ValueSet {
+                name: "private_hint",
+                values: vec![
+                    (PRIVATE_HINT_PADDED, "hint length(1) || UTF-8 hint zero-padded to the fixed slot size"),
+                    (
+                        PRIVATE_HINT_SEALED,
+                        "salt(16) || nonce(12) || AES-256-GCM sealed hint length(1) and padded hint || tag(16), keyed by the header's KDF over the hint password",
+                    ),
+                ],
+            },
+            ValueSet {
+                name: "device_slot_kind",
+                values: vec![(PROTECTOR_HOST_KEYSTORE, "host keystore (Android Keystore, iOS Keychain)"), (PROTECTOR_DPAPI, "Windows DPAPI")],
+            },
+        ],
+        limits: vec![
+            Limit { name: "max_hint_length", value: MAX_HINT_LENGTH as u64 },
+            Limit { name: "max_long_hint_length", value: MAX_LONG_HINT_LENGTH as u64 },
+            Limit { name: "max_header_length", value: MAX_HEADER_LEN as u64 },
+            Limit { name: "max_device_blob_length", value: MAX_DEVICE_BLOB_LEN as u64 },
+            Limit { name: "max_frame_length", value: MAX_FRAME_LEN },
+            Limit { name: "max_chunk_checksums", value: MAX_CHUNK_CHECKSUMS as u64 },
+            Limit { name: "checksums_per_field", value: CHECKSUMS_PER_FIELD as u64 },
+            Limit { name: "checksum_size", value: CHECKSUM_SIZE as u64 },
+            Limit { name: "share_salt_size", value: SHARE_SALT_SIZE as u64 },
+            Limit { name: "kdf_salt_size", value: KDF_SALT_SIZE as u64 },
+            Limit { name: "max_key_slots", value: MAX_KEY_SLOTS as u64 },
+            Limit { name: "max_holes", value: MAX_HOLES as u64 },
+        ],
+        raw_layout: RAW_LAYOUT,
+        stealth_layout: STEALTH_LAYOUT,
+    }
+}
+
+fn fixed(
+    name: &'static str,
+    offset: Option<usize>,
+    size: Option<usize>,
+    encoding: &'static str,
+    since_version: u32,
+) -> FixedField {
+    FixedField { name, offset, size, encoding, since_version }
+}
+
+fn tlv(tag: u8, name: &'static str, size: Option<usize>, repeatable: bool, layout: &'static str) -> TlvField {
+    TlvField { tag, name, critical: tag & FIELD_CRITICAL != 0, size, repeatable, layout }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::ContainerHeader;
+
+    #[test]
+    fn test_spec_matches_encoded_header() {
+        let spec = format_spec();
+        let header = ContainerHeader { padding: PADDING_PADME, ..ContainerHeader::with_hint(b"hint") };
+        let bytes = header.encode();
+
+        let field = |name| spec.fixed_header.iter().find(|f| f.name == name).unwrap();
+        let version = field("version");
+        let at = version.offset.unwrap();
+        assert_eq!(bytes[..field("magic").size.unwrap()], *spec.magic.as_bytes());
+        assert_eq!(bytes[at..at + version.size.unwrap()], EXTENDED_VERSION.to_le_bytes());
+        assert_eq!(bytes[field("hint_length").offset.unwrap()], 4);
+        assert_eq!(bytes[field("hint").offset.unwrap()..][..4], *b"hint");
+
+        let mut tags: Vec<u8> = spec.header_fields.iter().map(|f| f.tag).collect();
+        tags.sort_unstable();
+        tags.dedup();
+        assert_eq!(tags.len(), spec.header_fields.len());
+        assert!(serde_json::to_string(&spec).unwrap().contains("\"kdf\""));
+    }
+}
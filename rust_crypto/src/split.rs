@@ -0,0 +1,519 @@
+use rand::RngCore;
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use crate::errors;
+use crate::format::{read_layout, ChunkLayout, ContainerHeader, MAX_HEADER_LEN};
+use crate::header_backup;
+use crate::secure_temp::{self, SecureTempFile, TempContents};
+use crate::{encrypt_into, metadata, profile, EncryptOptions, PlainInput, ProgressSink};
+
+const PART_MAGIC: &[u8] = b"KYRIE_PART";
+const PART_VERSION: u32 = 1;
+const SET_ID_SIZE: usize = 16;
+
+struct PartHeader {
+    set_id: [u8; SET_ID_SIZE],
+    part_index: u32,
+    part_count: u32,
+    first_chunk: u64,
+    chunk_count: u64,
+    single_chunk: bool,
+    container_header: Vec<u8>,
+}
+
+impl PartHeader {
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(PART_MAGIC)?;
+        writer.write_all(&PART_VERSION.to_le_bytes())?;
+        writer.write_all(&self.set_id)?;
+        writer.write_all(&self.part_index.to_le_bytes())?;
+        writer.write_all(&self.part_count.to_le_bytes())?;
+        writer.write_all(&self.first_chunk.to_le_bytes())?;
+        writer.write_all(&self.chunk_count.to_le_bytes())?;
+        writer.write_all(&[self.single_chunk as u8])?;
+        writer.write_all(&(self.container_header.len() as u32).to_le_bytes())?;
+        writer.write_all(&self.container_header)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut magic = [0u8; 10];
+        reader.read_exact(&mut magic)?;
+        if magic != PART_MAGIC {
+            return Err("Invalid part format".into());
+        }
+        if read_u32(reader)? != PART_VERSION {
+            return Err("Unsupported part version".into());
+        }
+
+        let mut set_id = [0u8; SET_ID_SIZE];
+        reader.read_exact(&mut set_id)?;
+        let part_index = read_u32(reader)?;
+        let part_count = read_u32(reader)?;
+        let first_chunk = read_u64(reader)?;
+        let chunk_count = read_u64(reader)?;
+        let mut single_chunk = [0u8; 1];
+        reader.read_exact(&mut single_chunk)?;
+
+        let header_len = read_u32(reader)? as usize;
+        if header_len > MAX_HEADER_LEN {
+            return Err("Invalid part format".into());
+        }
+        let mut container_header = vec![0u8; header_len];
+        reader.read_exact(&mut container_header)?;
+
+        Ok(PartHeader {
+            set_id,
+            part_index,
+            part_count,
+            first_chunk,
+            chunk_count,
+            single_chunk: single_chunk[0] != 0,
+            container_header,
+        })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn split_file(
+    input_path_ptr: *const c_char,
+    parts: usize,
+    part_count_ptr: *mut usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+
+        match split_file_internal(Path::new(input_path), parts) {
+            Ok(paths) => {
+                if !part_count_ptr.is_null() {
+                    *part_count_ptr = paths.len();
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn join_files(
+    part_paths_ptr: *const *const c_char,
+    part_count: usize,
+    output_path_ptr: *const c_char,
+) -> i32 {
+    unsafe {
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let mut part_paths = Vec::with_capacity(part_count);
+        for &ptr in std::slice::from_raw_parts(part_paths_ptr, part_count) {
+            match CStr::from_ptr(ptr).to_str() {
+                Ok(s) => part_paths.push(PathBuf::from(s)),
+                Err(_) => return -1,
+            }
+        }
+
+        match join_files_internal(&part_paths, Path::new(output_path)) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn encrypt_file_split(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    max_volume_size: u64,
+    is_mobile: bool,
+    cpu_cores: usize,
+    volume_count_ptr: *mut usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        match encrypt_file_split_internal(input_path, Path::new(output_path), password, hint, max_volume_size, is_mobile, cpu_cores) {
+            Ok(paths) => {
+                if !volume_count_ptr.is_null() {
+                    *volume_count_ptr = paths.len();
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn part_path(input_path: &Path, index: usize) -> PathBuf {
+    let mut name = input_path.as_os_str().to_owned();
+    name.push(format!(".{:03}", index + 1));
+    PathBuf::from(name)
+}
+
+type OpenPart = (PartHeader, BufReader<File>);
+
+// How many frames a part holds and how many bytes it copies; the last parts
+// also carry any parity after the frames.
+struct PartPlan {
+    chunk_count: usize,
+    len: u64,
+}
+
+pub fn split_file_internal(input_path: &Path, parts: usize) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let layout = read_layout(input_path)?;
+    let part_count = parts.clamp(1, layout.frames.len());
+    let base = layout.frames.len() / part_count;
+    let extra = layout.frames.len() % part_count;
+    let mut first_chunk = 0usize;
+    let mut plans = Vec::with_capacity(part_count);
+    for part_index in 0..part_count {
+        let chunk_count = base + usize::from(part_index < extra);
+        let frames = &layout.frames[first_chunk..first_chunk + chunk_count];
+        plans.push(PartPlan { chunk_count, len: frames.iter().map(|f| layout.frame_size(f)).sum() });
+        first_chunk += chunk_count;
+    }
+    if let Some(last) = plans.last_mut() {
+        last.len += trailer_len(input_path, &layout)?;
+    }
+    write_parts(input_path, input_path, &layout, &plans)
+}
+
+// Cuts the container into volumes of at most `max_volume_size` bytes each,
+// named after `base_path`, for file systems and uploads with a size limit.
+pub fn split_file_by_size_internal(
+    input_path: &Path,
+    base_path: &Path,
+    max_volume_size: u64,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let layout = read_layout(input_path)?;
+    let room = max_volume_size
+        .checked_sub(part_header_len(layout.header.len()))
+        .filter(|&room| room > 0)
+        .ok_or("Volume size is smaller than a chunk")?;
+
+    let mut plans = vec![PartPlan { chunk_count: 0, len: 0 }];
+    for frame in &layout.frames {
+        let size = layout.frame_size(frame);
+        if size > room {
+            return Err("Volume size is smaller than a chunk".into());
+        }
+        if plans.last().is_some_and(|plan| plan.len + size > room) {
+            plans.push(PartPlan { chunk_count: 0, len: 0 });
+        }
+        let plan = plans.last_mut().expect("there is always a part");
+        plan.chunk_count += 1;
+        plan.len += size;
+    }
+    // Parity is plain bytes, so it fills up the last volume and spills over
+    // into volumes without frames.
+    let mut trailer = trailer_len(input_path, &layout)?;
+    while trailer > 0 {
+        let plan = plans.last_mut().expect("there is always a part");
+        let n = trailer.min(room - plan.len);
+        plan.len += n;
+        trailer -= n;
+        if trailer > 0 {
+            plans.push(PartPlan { chunk_count: 0, len: 0 });
+        }
+    }
+    write_parts(input_path, base_path, &layout, &plans)
+}
+
+// Encrypts into a temp file and splits that into volumes, so the whole
+// container never has to fit on the destination. The temp file lives in the
+// configured temp dir, which should be set when the destination is the one
+// with the size limit.
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_file_split_internal(
+    input_path: &str,
+    output_path: &Path,
+    password: &[u8],
+    hint: Option<&str>,
+    max_volume_size: u64,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let options = EncryptOptions { hint, padding: profile::OptionsProfile::current().padding, ..Default::default() };
+    let file_metadata = metadata::FileMetadata::for_input(Path::new(input_path))?;
+    let mut temp = SecureTempFile::for_target(output_path, TempContents::Ciphertext)?;
+    encrypt_into(
+        PlainInput::Path(Path::new(input_path)),
+        temp.file(),
+        password,
+        &options,
+        Some(&file_metadata),
+        is_mobile,
+        cpu_cores,
+        &ProgressSink::none(),
+    )?;
+    split_file_by_size_internal(temp.path(), output_path, max_volume_size)
+}
+
+fn part_header_len(container_header_len: usize) -> u64 {
+    (PART_MAGIC.len() + 4 + SET_ID_SIZE + 4 + 4 + 8 + 8 + 1 + 4 + container_header_len) as u64
+}
+
+// Parity between the last frame and any backup trailer, which join rebuilds.
+fn trailer_len(input_path: &Path, layout: &ChunkLayout) -> Result<u64, Box<dyn std::error::Error>> {
+    let (header, header_len) = ContainerHeader::read(&mut layout.header.as_slice())?;
+    let body_end = layout.frames.last().map_or(header_len, |frame| frame.offset + layout.frame_size(frame));
+    Ok(header.trailer_start(header_len, fs::metadata(input_path)?.len()).saturating_sub(body_end))
+}
+
+fn write_parts(
+    input_path: &Path,
+    base_path: &Path,
+    layout: &ChunkLayout,
+    plans: &[PartPlan],
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut set_id = [0u8; SET_ID_SIZE];
+    rand::thread_rng().fill_bytes(&mut set_id);
+
+    let mut reader = BufReader::new(File::open(input_path)?);
+    reader.seek(SeekFrom::Start(layout.frames[0].offset))?;
+    let mut written = Vec::with_capacity(plans.len());
+    let mut first_chunk = 0usize;
+
+    for (part_index, plan) in plans.iter().enumerate() {
+        let path = part_path(base_path, part_index);
+        let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+            secure_temp::check_target(&path)?;
+            let mut output = BufWriter::new(File::create(&path)?);
+            PartHeader {
+                set_id,
+                part_index: part_index as u32,
+                part_count: plans.len() as u32,
+                first_chunk: first_chunk as u64,
+                chunk_count: plan.chunk_count as u64,
+                single_chunk: layout.single_chunk,
+                container_header: layout.header.clone(),
+            }
+            .write(&mut output)?;
+            if io::copy(&mut (&mut reader).take(plan.len), &mut output)? != plan.len {
+                return Err("Unexpected end of file".into());
+            }
+            output.flush()?;
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&path);
+            for path in &written {
+                let _ = fs::remove_file(path);
+            }
+            return Err(e);
+        }
+        written.push(path);
+        first_chunk += plan.chunk_count;
+    }
+
+    Ok(written)
+}
+
+pub fn join_files_internal(part_paths: &[PathBuf], output_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let parts = open_parts(part_paths)?;
+    let result = File::create(output_path).map_err(Into::into).and_then(|output| join_parts(parts, output));
+    if result.is_err() {
+        let _ = fs::remove_file(output_path);
+    }
+    result
+}
+
+// Decrypting a split file by its own name, or by the name of any of its
+// volumes, joins the volumes into a temp file first. None when the path is
+// an ordinary container.
+pub fn stitch_volumes(input_path: &Path) -> Result<Option<SecureTempFile>, Box<dyn std::error::Error>> {
+    let (base_path, named_part) = if input_path.exists() {
+        if !is_part(input_path) {
+            return Ok(None);
+        }
+        (input_path.with_extension(""), input_path.to_path_buf())
+    } else {
+        let first = part_path(input_path, 0);
+        if !first.exists() {
+            return Ok(None);
+        }
+        (input_path.to_path_buf(), first)
+    };
+
+    let part_count = PartHeader::read(&mut BufReader::new(File::open(&named_part)?))?.part_count;
+    let part_paths: Vec<PathBuf> = (0..part_count as usize).map(|index| part_path(&base_path, index)).collect();
+    if let Some(missing) = part_paths.iter().find(|path| !path.exists()) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Missing volume {}", missing.display())).into());
+    }
+    let mut temp = SecureTempFile::for_target(&base_path, TempContents::Ciphertext)?;
+    join_parts(open_parts(&part_paths)?, temp.file())?;
+    Ok(Some(temp))
+}
+
+fn is_part(path: &Path) -> bool {
+    let mut magic = [0u8; PART_MAGIC.len()];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && magic == PART_MAGIC
+}
+
+fn open_parts(part_paths: &[PathBuf]) -> Result<Vec<OpenPart>, Box<dyn std::error::Error>> {
+    let mut parts = Vec::with_capacity(part_paths.len());
+    for path in part_paths {
+        let mut reader = BufReader::new(File::open(path)?);
+        let header = PartHeader::read(&mut reader)?;
+        parts.push((header, reader));
+    }
+    parts.sort_by_key(|(header, _)| header.part_index);
+
+    let first = &parts.first().ok_or("No parts given")?.0;
+    let (set_id, part_count) = (first.set_id, first.part_count);
+    if parts.len() != part_count as usize {
+        return Err("Missing or extra parts".into());
+    }
+    let mut next_chunk = 0u64;
+    for (expected_index, (header, _)) in parts.iter().enumerate() {
+        if header.set_id != set_id
+            || header.part_count != part_count
+            || header.part_index != expected_index as u32
+            || header.first_chunk != next_chunk
+            || header.container_header != first.container_header
+        {
+            return Err("Parts do not belong together".into());
+        }
+        next_chunk += header.chunk_count;
+    }
+    Ok(parts)
+}
+
+fn join_parts<W: Write>(mut parts: Vec<OpenPart>, output: W) -> Result<(), Box<dyn std::error::Error>> {
+    let container_header = parts[0].0.container_header.clone();
+    let mut output = BufWriter::new(output);
+    output.write_all(&container_header)?;
+    for (_, reader) in parts.iter_mut() {
+        io::copy(reader, &mut output)?;
+    }
+    // Parts carry only frames and parity, so the trailer is rebuilt from the
+    // header.
+    if ContainerHeader::read(&mut container_header.as_slice())?.0.backup {
+        header_backup::write_trailer(&mut output, &container_header)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parity;
+    use crate::{decrypt_file_internal, encrypt_file_internal};
+    use crate::test_util::write_framed_container;
+
+    #[test]
+    fn test_split_and_join_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("archive.kyl");
+        write_framed_container(&input, b"pw", &[b"one", b"two", b"three", b"four", b"five"]);
+
+        let parts = split_file_internal(&input, 2).unwrap();
+        assert_eq!(parts, vec![part_path(&input, 0), part_path(&input, 1)]);
+
+        let joined = dir.path().join("joined.kyl");
+        let reversed: Vec<PathBuf> = parts.iter().rev().cloned().collect();
+        join_files_internal(&reversed, &joined).unwrap();
+        assert_eq!(fs::read(&joined).unwrap(), fs::read(&input).unwrap());
+
+        assert!(join_files_internal(&parts[..1], &joined).is_err());
+    }
+
+    #[test]
+    fn test_split_clamps_to_chunk_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("small.kyl");
+        write_framed_container(&input, b"pw", &[b"single"]);
+
+        let parts = split_file_internal(&input, 4).unwrap();
+        assert_eq!(parts.len(), 1);
+
+        let joined = dir.path().join("joined.kyl");
+        join_files_internal(&parts, &joined).unwrap();
+        assert_eq!(fs::read(&joined).unwrap(), fs::read(&input).unwrap());
+    }
+
+    #[test]
+    fn test_volumes_stay_under_the_size_limit_and_decrypt_stitched() {
+        let dir = tempfile::tempdir().unwrap();
+        let framed = dir.path().join("framed.kyl");
+        write_framed_container(&framed, b"pw", &[b"one", b"two", b"six", b"ten", b"end"]);
+        let layout = read_layout(&framed).unwrap();
+        let frame_size = layout.frame_size(&layout.frames[0]);
+        let limit = part_header_len(layout.header.len()) + 2 * frame_size;
+        let volumes = split_file_by_size_internal(&framed, &framed, limit).unwrap();
+        assert_eq!(volumes.len(), 3);
+        assert!(volumes.iter().all(|path| fs::metadata(path).unwrap().len() <= limit));
+        assert!(split_file_by_size_internal(&framed, &framed, limit - frame_size - 1).is_err());
+        let joined = dir.path().join("joined.kyl");
+        join_files_internal(&volumes, &joined).unwrap();
+        assert_eq!(fs::read(&joined).unwrap(), fs::read(&framed).unwrap());
+
+        // Parity spills over into volumes of its own.
+        let plain = dir.path().join("video.mp4");
+        let content: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        fs::write(&plain, &content).unwrap();
+        let input = dir.path().join("video.kyl");
+        encrypt_file_internal(plain.to_str().unwrap(), input.to_str().unwrap(), b"pw", None, false, 2).unwrap();
+        parity::add_parity(&input, b"pw", 50).unwrap();
+        let layout = read_layout(&input).unwrap();
+        let limit = part_header_len(layout.header.len()) + layout.frame_size(&layout.frames[0]) + 600;
+        let base = dir.path().join("video.kyrie");
+        let volumes = split_file_by_size_internal(&input, &base, limit).unwrap();
+        assert_eq!(volumes.len(), 2);
+        assert!(volumes.iter().all(|path| fs::metadata(path).unwrap().len() <= limit));
+
+        let output = dir.path().join("restored.mp4");
+        for name in [&base, &volumes[1]] {
+            decrypt_file_internal(name.to_str().unwrap(), output.to_str().unwrap(), b"pw", false, 2).unwrap();
+            assert_eq!(fs::read(&output).unwrap(), content);
+        }
+        fs::remove_file(&volumes[1]).unwrap();
+        let error = decrypt_file_internal(base.to_str().unwrap(), output.to_str().unwrap(), b"pw", false, 2).unwrap_err();
+        assert!(error.to_string().ends_with("video.kyrie.002"));
+        assert_eq!(errors::classify(error.as_ref()), errors::ERR_FILE_NOT_FOUND);
+
+        let split = dir.path().join("clip.kyrie");
+        let volumes = encrypt_file_split_internal(plain.to_str().unwrap(), &split, b"pw", None, 1 << 20, false, 2).unwrap();
+        assert_eq!(volumes, vec![part_path(&split, 0)]);
+        decrypt_file_internal(split.to_str().unwrap(), output.to_str().unwrap(), b"pw", false, 2).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), content);
+    }
+}
@@ -0,0 +1,181 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::slice;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::config::{self, KyrieConfig};
+use crate::diagnostics::ChunkRecorder;
+use crate::progress::ProgressSink;
+use crate::{errors, kyrie_core};
+
+// What one file operation did, for lines like "encrypted 4.2 GB in 18 s
+// (233 MB/s, 8 threads)".
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct KyrieStats {
+    // Wall time of the whole operation, key derivation included.
+    pub elapsed_ms: u64,
+    // Chunk data read from the input: plaintext when encrypting, sealed
+    // chunks when decrypting.
+    pub bytes: u64,
+    pub chunks: u64,
+    pub threads: u32,
+    // Share of the threads' time in the parallel stages spent sealing or
+    // opening chunks, 0 to 1.
+    pub thread_utilization: f64,
+    pub bytes_per_second: f64,
+}
+
+// Variants of encrypt_file and decrypt_file that also fill in `stats_ptr`
+// when it is not null. It is left untouched when the operation fails.
+#[no_mangle]
+pub extern "C" fn encrypt_file_v2(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+    stats_ptr: *mut KyrieStats,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let encryptor = kyrie_core::Encryptor::new(password)
+            .with_hint(hint)
+            .for_mobile(is_mobile)
+            .with_cpu_cores(cpu_cores);
+        match encryptor.encrypt_file(Path::new(input_path), Path::new(output_path)) {
+            Ok(stats) => store(stats_ptr, stats),
+            Err(e) => errors::fail(&e),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_v2(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+    stats_ptr: *mut KyrieStats,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        let decryptor = kyrie_core::Decryptor::new(password).for_mobile(is_mobile).with_cpu_cores(cpu_cores);
+        match decryptor.decrypt_file(Path::new(input_path), Path::new(output_path)) {
+            Ok(stats) => store(stats_ptr, stats),
+            Err(e) => errors::fail(&e),
+        }
+    }
+}
+
+unsafe fn store(stats_ptr: *mut KyrieStats, stats: KyrieStats) -> i32 {
+    if !stats_ptr.is_null() {
+        *stats_ptr = stats;
+    }
+    0
+}
+
+// Runs `operation` with chunk timings recorded on `progress` and sums them
+// up once it succeeds.
+pub fn collect<F>(progress: ProgressSink, is_mobile: bool, cpu_cores: usize, operation: F) -> Result<KyrieStats, Box<dyn std::error::Error>>
+where
+    F: FnOnce(&ProgressSink) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let recorder = Arc::new(ChunkRecorder::default());
+    let progress = progress.with_diagnostics(Arc::clone(&recorder));
+    let started = Instant::now();
+    operation(&progress)?;
+    let elapsed = started.elapsed();
+
+    let report = recorder.report();
+    let chunks = report.chunks.len();
+    let bytes = report.chunks.iter().map(|c| c.bytes).sum::<u64>();
+    // No batch runs wider than the pool, the batch size or the file.
+    let pool = config::install(rayon::current_num_threads);
+    let threads = pool.min(KyrieConfig::current().parallel_batch_size(cpu_cores, is_mobile)).min(chunks).max(1);
+    let busy_us = report.chunks.iter().map(|c| c.crypto_us).sum::<u64>();
+    let available_us = report.crypto_us * threads as u64;
+
+    Ok(KyrieStats {
+        elapsed_ms: elapsed.as_millis() as u64,
+        bytes,
+        chunks: chunks as u64,
+        threads: threads as u32,
+        thread_utilization: if available_us == 0 { 0.0 } else { (busy_us as f64 / available_us as f64).min(1.0) },
+        bytes_per_second: bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs;
+
+    #[test]
+    fn test_v2_entry_points_report_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("video.mp4");
+        let encrypted = dir.path().join("video.kyl");
+        let decrypted = dir.path().join("video.out");
+        let data: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&plain, &data).unwrap();
+        let (plain_c, encrypted_c, decrypted_c) = (
+            CString::new(plain.to_str().unwrap()).unwrap(),
+            CString::new(encrypted.to_str().unwrap()).unwrap(),
+            CString::new(decrypted.to_str().unwrap()).unwrap(),
+        );
+
+        let mut stats = KyrieStats::default();
+        let result =
+            encrypt_file_v2(plain_c.as_ptr(), encrypted_c.as_ptr(), b"pw".as_ptr(), 2, std::ptr::null(), false, 4, &mut stats);
+        assert_eq!(result, 0);
+        assert_eq!(stats.bytes, data.len() as u64);
+        assert_eq!((stats.chunks, stats.threads), (1, 1));
+        assert!(stats.bytes_per_second > 0.0);
+        assert!((0.0..=1.0).contains(&stats.thread_utilization));
+
+        let mut opened = KyrieStats::default();
+        assert_eq!(decrypt_file_v2(encrypted_c.as_ptr(), decrypted_c.as_ptr(), b"pw".as_ptr(), 2, false, 4, &mut opened), 0);
+        assert_eq!(fs::read(&decrypted).unwrap(), data);
+        assert_eq!(opened.chunks, stats.chunks);
+        assert!(opened.bytes > stats.bytes);
+
+        // Stats are optional, and a failure leaves them alone.
+        fs::remove_file(&decrypted).unwrap();
+        assert_eq!(decrypt_file_v2(encrypted_c.as_ptr(), decrypted_c.as_ptr(), b"pw".as_ptr(), 2, false, 4, std::ptr::null_mut()), 0);
+        let mut untouched = KyrieStats::default();
+        let other = CString::new(dir.path().join("other.out").to_str().unwrap()).unwrap();
+        assert_eq!(decrypt_file_v2(encrypted_c.as_ptr(), other.as_ptr(), b"no".as_ptr(), 2, false, 4, &mut untouched), -2);
+        assert_eq!(untouched, KyrieStats::default());
+    }
+}
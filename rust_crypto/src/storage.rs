@@ -0,0 +1,397 @@
+use std::ffi::{c_void, CStr};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use crate::pipe::{decrypt_stream_internal, encrypt_stream_internal};
+use crate::profile::OptionsProfile;
+use crate::progress::ProgressSink;
+use crate::secure_temp::{self, SecureTempFile, TempContents};
+use crate::errors;
+
+// Where a container is kept when it need not be a local file. Containers
+// go to storage in one front-to-back pass, so a backend only ever appends;
+// reads may start anywhere.
+pub trait Storage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    fn len(&mut self) -> io::Result<u64>;
+    // Makes what was written visible under its name, for example by
+    // completing an upload. Never called when encryption fails.
+    fn finalize(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+// Returns 0 on success. `read_at` sets `*read_len` to the bytes it filled,
+// which is 0 only past the end of the object.
+pub type StorageReadCallback =
+    extern "C" fn(offset: u64, buf: *mut u8, len: usize, read_len: *mut usize, user_data: *mut c_void) -> i32;
+pub type StorageWriteCallback = extern "C" fn(data: *const u8, len: usize, user_data: *mut c_void) -> i32;
+pub type StorageLenCallback = extern "C" fn(len: *mut u64, user_data: *mut c_void) -> i32;
+pub type StorageFinalizeCallback = extern "C" fn(user_data: *mut c_void) -> i32;
+
+// A backend living in the host app, such as a cloud SDK or a document
+// provider. Encrypting needs `write` and `finalize`, decrypting `read_at`
+// and `len`.
+#[repr(C)]
+pub struct KyrieStorageCallbacks {
+    pub read_at: Option<StorageReadCallback>,
+    pub write: Option<StorageWriteCallback>,
+    pub len: Option<StorageLenCallback>,
+    pub finalize: Option<StorageFinalizeCallback>,
+    pub user_data: *mut c_void,
+}
+
+struct CallbackStorage<'a> {
+    callbacks: &'a KyrieStorageCallbacks,
+}
+
+impl Storage for CallbackStorage<'_> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let read_at = self.callbacks.read_at.ok_or(io::ErrorKind::Unsupported)?;
+        let mut read_len = 0;
+        if read_at(offset, buf.as_mut_ptr(), buf.len(), &mut read_len, self.callbacks.user_data) != 0 || read_len > buf.len() {
+            return Err(io::Error::other("Storage read failed"));
+        }
+        Ok(read_len)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let write = self.callbacks.write.ok_or(io::ErrorKind::Unsupported)?;
+        match write(data.as_ptr(), data.len(), self.callbacks.user_data) {
+            0 => Ok(()),
+            _ => Err(io::Error::other("Storage write failed")),
+        }
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        let len_of = self.callbacks.len.ok_or(io::ErrorKind::Unsupported)?;
+        let mut len = 0;
+        match len_of(&mut len, self.callbacks.user_data) {
+            0 => Ok(len),
+            _ => Err(io::Error::other("Storage read failed")),
+        }
+    }
+
+    fn finalize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.callbacks.finalize.map_or(0, |finalize| finalize(self.callbacks.user_data)) {
+            0 => Ok(()),
+            _ => Err("Storage write failed".into()),
+        }
+    }
+}
+
+// The default: a file opened for reading, or a temp file that only
+// replaces its target once finalized.
+pub enum LocalStorage {
+    Reading(File),
+    Writing(SecureTempFile, PathBuf),
+}
+
+impl LocalStorage {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(LocalStorage::Reading(File::open(path)?))
+    }
+
+    pub fn create(path: &Path) -> io::Result<Self> {
+        secure_temp::check_target(path)?;
+        Ok(LocalStorage::Writing(SecureTempFile::for_target(path, TempContents::Ciphertext)?, path.to_path_buf()))
+    }
+
+    fn file(&mut self) -> &mut File {
+        match self {
+            LocalStorage::Reading(file) => file,
+            LocalStorage::Writing(temp, _) => temp.file(),
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let file = self.file();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read(buf)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let file = self.file();
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(data)
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.file().metadata()?.len())
+    }
+
+    fn finalize(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let reading = LocalStorage::Reading(self.file().try_clone()?);
+        if let LocalStorage::Writing(temp, target) = std::mem::replace(self, reading) {
+            temp.persist(&target)?;
+        }
+        Ok(())
+    }
+}
+
+struct StorageWriter<'a, S: Storage + ?Sized>(&'a mut S);
+
+impl<S: Storage + ?Sized> Write for StorageWriter<'_, S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.write(data)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reads stop at the length the backend reported, so one that pads or
+// errors past the end of an object is never asked for more.
+struct StorageReader<'a, S: Storage + ?Sized> {
+    storage: &'a mut S,
+    position: u64,
+    len: u64,
+}
+
+impl<S: Storage + ?Sized> Read for StorageReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let wanted = buf.len().min(self.len.saturating_sub(self.position) as usize);
+        if wanted == 0 {
+            return Ok(0);
+        }
+        let n = self.storage.read_at(self.position, &mut buf[..wanted])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_encrypt_to_storage(
+    input_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    storage_ptr: *const KyrieStorageCallbacks,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if input_path_ptr.is_null() || password_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let padding = OptionsProfile::current().padding;
+        let result = open_storage(storage_ptr, output_path_ptr, LocalStorage::create).and_then(|mut storage| {
+            let input = File::open(input_path)?;
+            encrypt_to_storage(input, storage.as_mut(), password, hint, padding, is_mobile, cpu_cores, &ProgressSink::none())
+        });
+        match result {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Plaintext goes to a temp file next to the output and only replaces it
+// once the whole container has authenticated.
+#[no_mangle]
+pub extern "C" fn kyrie_decrypt_from_storage(
+    input_path_ptr: *const c_char,
+    storage_ptr: *const KyrieStorageCallbacks,
+    output_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    if output_path_ptr.is_null() || password_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let result = open_storage(storage_ptr, input_path_ptr, LocalStorage::open).and_then(|mut storage| {
+            decrypt_from_storage_to_path(storage.as_mut(), Path::new(output_path), password, is_mobile, cpu_cores)
+        });
+        match result {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Host callbacks when given, otherwise the local file at `path_ptr`.
+unsafe fn open_storage<'a>(
+    storage_ptr: *const KyrieStorageCallbacks,
+    path_ptr: *const c_char,
+    local: fn(&Path) -> io::Result<LocalStorage>,
+) -> Result<Box<dyn Storage + 'a>, Box<dyn std::error::Error>> {
+    if let Some(callbacks) = storage_ptr.as_ref() {
+        return Ok(Box::new(CallbackStorage { callbacks }));
+    }
+    if path_ptr.is_null() {
+        return Err("Invalid argument".into());
+    }
+    let path = CStr::from_ptr(path_ptr).to_str()?;
+    Ok(Box::new(local(Path::new(path))?))
+}
+
+// Written as a streamed container, which needs no seeking back to fill in
+// the header, so nothing is staged locally on the way to the backend.
+#[allow(clippy::too_many_arguments)]
+pub fn encrypt_to_storage<R: Read, S: Storage + ?Sized>(
+    input: R,
+    storage: &mut S,
+    password: &[u8],
+    hint: Option<&str>,
+    padding: u8,
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<(), Box<dyn std::error::Error>> {
+    encrypt_stream_internal(input, StorageWriter(&mut *storage), password, hint, padding, is_mobile, cpu_cores, progress)?;
+    storage.finalize()
+}
+
+// Takes any container that can be read front to back, which includes all
+// those written by encrypt_to_storage.
+pub fn decrypt_from_storage<S: Storage + ?Sized, W: Write>(
+    storage: &mut S,
+    output: W,
+    password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+    progress: &ProgressSink,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let len = storage.len()?;
+    let reader = StorageReader { storage, position: 0, len };
+    decrypt_stream_internal(reader, output, password, is_mobile, cpu_cores, progress)
+}
+
+pub fn decrypt_from_storage_to_path<S: Storage + ?Sized>(
+    storage: &mut S,
+    output_path: &Path,
+    password: &[u8],
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    secure_temp::check_target(output_path)?;
+    let mut temp = SecureTempFile::for_output(output_path)?;
+    decrypt_from_storage(storage, temp.file(), password, is_mobile, cpu_cores, &ProgressSink::none())?;
+    temp.persist(output_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ERR_WRONG_PASSWORD;
+    use crate::encrypt_file_internal;
+    use std::ffi::CString;
+    use std::fs;
+
+    #[derive(Default)]
+    struct MemoryObject {
+        data: Vec<u8>,
+        finalized: bool,
+    }
+
+    extern "C" fn read_object(offset: u64, buf: *mut u8, len: usize, read_len: *mut usize, user_data: *mut c_void) -> i32 {
+        let object = unsafe { &*(user_data as *const MemoryObject) };
+        let available = object.data.get(offset as usize..).unwrap_or_default();
+        let n = available.len().min(len);
+        unsafe {
+            std::ptr::copy_nonoverlapping(available.as_ptr(), buf, n);
+            *read_len = n;
+        }
+        0
+    }
+
+    extern "C" fn write_object(data: *const u8, len: usize, user_data: *mut c_void) -> i32 {
+        let object = unsafe { &mut *(user_data as *mut MemoryObject) };
+        object.data.extend_from_slice(unsafe { std::slice::from_raw_parts(data, len) });
+        0
+    }
+
+    extern "C" fn object_len(len: *mut u64, user_data: *mut c_void) -> i32 {
+        unsafe { *len = (*(user_data as *const MemoryObject)).data.len() as u64 };
+        0
+    }
+
+    extern "C" fn finalize_object(user_data: *mut c_void) -> i32 {
+        unsafe { (*(user_data as *mut MemoryObject)).finalized = true };
+        0
+    }
+
+    fn callbacks(object: &mut MemoryObject) -> KyrieStorageCallbacks {
+        KyrieStorageCallbacks {
+            read_at: Some(read_object),
+            write: Some(write_object),
+            len: Some(object_len),
+            finalize: Some(finalize_object),
+            user_data: object as *mut MemoryObject as *mut c_void,
+        }
+    }
+
+    #[test]
+    fn test_containers_round_trip_through_host_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("photo.jpg");
+        let output = dir.path().join("photo.out");
+        let data: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i| (i % 253) as u8).collect();
+        fs::write(&input, &data).unwrap();
+        let (input_c, output_c) = (CString::new(input.to_str().unwrap()).unwrap(), CString::new(output.to_str().unwrap()).unwrap());
+
+        let mut object = MemoryObject::default();
+        let storage = callbacks(&mut object);
+        let result = kyrie_encrypt_to_storage(input_c.as_ptr(), std::ptr::null(), &storage, b"pw".as_ptr(), 2, std::ptr::null(), false, 4);
+        assert_eq!(result, 0);
+        assert!(object.finalized && object.data.starts_with(crate::MAGIC_STRING));
+
+        let storage = callbacks(&mut object);
+        assert_eq!(kyrie_decrypt_from_storage(std::ptr::null(), &storage, output_c.as_ptr(), b"no".as_ptr(), 2, false, 4), -2);
+        assert_eq!(errors::kyrie_last_error(), ERR_WRONG_PASSWORD);
+        assert!(!output.exists());
+        assert_eq!(kyrie_decrypt_from_storage(std::ptr::null(), &storage, output_c.as_ptr(), b"pw".as_ptr(), 2, false, 4), 0);
+        assert_eq!(fs::read(&output).unwrap(), data);
+    }
+
+    #[test]
+    fn test_local_storage_is_the_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let note = dir.path().join("note.txt");
+        let container = dir.path().join("note.kyl");
+        let regular = dir.path().join("regular.kyl");
+
+        let mut storage = LocalStorage::create(&container).unwrap();
+        encrypt_to_storage(&b"local only"[..], &mut storage, b"pw", None, 0, false, 4, &ProgressSink::none()).unwrap();
+        assert!(container.exists());
+        let mut decrypted = Vec::new();
+        decrypt_from_storage(&mut LocalStorage::open(&container).unwrap(), &mut decrypted, b"pw", false, 4, &ProgressSink::none()).unwrap();
+        assert_eq!(decrypted, b"local only");
+
+        fs::write(&note, b"written as usual").unwrap();
+        encrypt_file_internal(note.to_str().unwrap(), regular.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt_from_storage(&mut LocalStorage::open(&regular).unwrap(), &mut decrypted, b"pw", false, 4, &ProgressSink::none()).unwrap();
+        assert_eq!(decrypted, b"written as usual");
+    }
+}
@@ -0,0 +1,133 @@
+use std::ffi::{c_void, CStr};
+use std::io::{self, Write};
+use std::os::raw::c_char;
+use std::slice;
+
+use crate::errors;
+use crate::progress::ProgressSink;
+use crate::decrypt_into;
+
+// Returns 0 to keep going; anything else aborts the decryption.
+pub type PlaintextSink = extern "C" fn(data: *const u8, len: usize, user_data: *mut c_void) -> i32;
+
+// Plaintext is handed over as each batch is opened, before the end of the
+// body is authenticated, so a caller must drop what it received when this
+// fails.
+#[no_mangle]
+pub extern "C" fn decrypt_file_streaming(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    sink: Option<PlaintextSink>,
+    user_data: *mut c_void,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let Some(sink) = sink else {
+            return errors::invalid_argument();
+        };
+        if password_ptr.is_null() {
+            return errors::invalid_argument();
+        }
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        let mut output = SinkWriter { sink, user_data, aborted: false };
+        match decrypt_streaming(input_path, password, &mut output, is_mobile, cpu_cores) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+pub fn decrypt_streaming(
+    input_path: &str,
+    password: &[u8],
+    output: &mut SinkWriter,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match decrypt_into(std::path::Path::new(input_path), output, password, is_mobile, cpu_cores, &ProgressSink::none()) {
+        Err(_) if output.aborted => Err("Operation cancelled".into()),
+        result => result.map(|_| ()),
+    }
+}
+
+pub struct SinkWriter {
+    sink: PlaintextSink,
+    user_data: *mut c_void,
+    aborted: bool,
+}
+
+impl Write for SinkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.sink)(buf.as_ptr(), buf.len(), self.user_data) != 0 {
+            self.aborted = true;
+            return Err(io::Error::other("Operation cancelled"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ERR_CANCELLED;
+    use crate::test_util::write_framed_container;
+    use crate::encrypt_file_internal;
+    use std::fs;
+
+    struct Received {
+        data: Vec<u8>,
+        calls: usize,
+        stop_after: usize,
+    }
+
+    extern "C" fn collect(data: *const u8, len: usize, user_data: *mut c_void) -> i32 {
+        let received = unsafe { &mut *(user_data as *mut Received) };
+        received.data.extend_from_slice(unsafe { slice::from_raw_parts(data, len) });
+        received.calls += 1;
+        (received.calls >= received.stop_after) as i32
+    }
+
+    fn stream(path: &str, password: &[u8], stop_after: usize) -> (Result<(), Box<dyn std::error::Error>>, Received) {
+        let mut received = Received { data: Vec::new(), calls: 0, stop_after };
+        let mut output = SinkWriter { sink: collect, user_data: &mut received as *mut Received as *mut c_void, aborted: false };
+        let result = decrypt_streaming(path, password, &mut output, false, 4);
+        (result, received)
+    }
+
+    #[test]
+    fn test_plaintext_reaches_the_sink_until_it_aborts() {
+        let dir = tempfile::tempdir().unwrap();
+        let framed = dir.path().join("framed.kyl");
+        let chunks: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 1024 * 1024]).collect();
+        let chunk_refs: Vec<&[u8]> = chunks.iter().map(Vec::as_slice).collect();
+        write_framed_container(&framed, b"pw", &chunk_refs);
+
+        let (result, received) = stream(framed.to_str().unwrap(), b"pw", usize::MAX);
+        result.unwrap();
+        assert_eq!(received.data, chunks.concat());
+        assert!(received.calls >= chunks.len());
+
+        let (result, received) = stream(framed.to_str().unwrap(), b"pw", 1);
+        assert_eq!(errors::classify(result.unwrap_err().as_ref()), ERR_CANCELLED);
+        assert_eq!(received.calls, 1);
+        let (result, received) = stream(framed.to_str().unwrap(), b"wrong", usize::MAX);
+        assert!(result.is_err() && received.data.is_empty());
+
+        let plain = dir.path().join("note.txt");
+        let small = dir.path().join("note.kyl");
+        fs::write(&plain, b"small enough for one read").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), small.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        assert_eq!(stream(small.to_str().unwrap(), b"pw", usize::MAX).1.data, b"small enough for one read");
+    }
+}
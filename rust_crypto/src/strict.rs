@@ -0,0 +1,81 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::format::{read_layout, ChunkLayout};
+use crate::{get_chunk_size, TAG_SIZE};
+
+static STRICT_AEAD: AtomicBool = AtomicBool::new(false);
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_strict_aead(enabled: bool) {
+    STRICT_AEAD.store(enabled, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    STRICT_AEAD.load(Ordering::SeqCst)
+}
+
+// For decrypt paths that parse frames themselves instead of going through
+// read_layout, which applies the policy on its own.
+pub fn enforce(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if enabled() {
+        read_layout(path)?;
+    }
+    Ok(())
+}
+
+// Rejects chunk structures no reference encoder produces: empty frames in
+// a framed body, frames larger than any chunk size, and frames that break
+// the equal-size pattern every body except the last follows. Each of these
+// is how a truncated or spliced file tends to look.
+pub fn check_layout(layout: &ChunkLayout) -> Result<(), Box<dyn std::error::Error>> {
+    let tag = TAG_SIZE as u64;
+    let max_len = (get_chunk_size(false) + TAG_SIZE) as u64;
+    let slots = if layout.dual { 2 } else { 1 };
+    let per_slot = layout.frames.len() / slots;
+    if per_slot == 0 || per_slot * slots != layout.frames.len() || per_slot > u32::MAX as usize {
+        return Err(rejected());
+    }
+    for slot in layout.frames.chunks(per_slot) {
+        let first = slot[0].len;
+        let (last, body) = slot.split_last().expect("slots are not empty");
+        if first < tag || first > max_len || last.len > first {
+            return Err(rejected());
+        }
+        if layout.single_chunk {
+            continue;
+        }
+        if last.len <= tag || body.iter().any(|frame| frame.len != first) {
+            return Err(rejected());
+        }
+    }
+    Ok(())
+}
+
+fn rejected() -> Box<dyn std::error::Error> {
+    "Rejected by strict AEAD policy".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_framed_container;
+
+    #[test]
+    fn test_degenerate_chunk_structures_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.kyl");
+        let check = |chunks: &[&[u8]]| {
+            write_framed_container(&path, b"pw", chunks);
+            check_layout(&read_layout(&path).unwrap()).is_ok()
+        };
+
+        assert!(check(&[b"four", b"four", b"tw"]));
+        assert!(check(&[b"only chunk"]));
+        assert!(check(&[b""]));
+        assert!(!check(&[b"four", b"", b"four"]));
+        assert!(!check(&[b"four", b"four", b""]));
+        assert!(!check(&[b"four", b"six!!!", b"tw"]));
+        assert!(!check(&[b"four", b"four", b"longer"]));
+    }
+}
@@ -0,0 +1,34 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::format::ContainerHeader;
+use crate::{derive_key, header_backup, random_nonce, write_header};
+
+pub fn write_framed_container(path: &Path, password: &[u8], chunks: &[&[u8]]) {
+    write_framed_container_with(path, password, &ContainerHeader::default(), chunks);
+}
+
+// The header has to keep the legacy key and random nonces.
+pub fn write_framed_container_with(path: &Path, password: &[u8], header: &ContainerHeader, chunks: &[&[u8]]) {
+    let cipher = Aes256Gcm::new_from_slice(&derive_key(password)).unwrap();
+    let mut output = File::create(path).unwrap();
+    write_header(&mut output, header).unwrap();
+
+    for chunk in chunks {
+        let nonce_bytes = random_nonce();
+        let encrypted = cipher.encrypt(Nonce::from_slice(&nonce_bytes), *chunk).unwrap();
+        output.write_all(&nonce_bytes).unwrap();
+        if chunks.len() > 1 {
+            output.write_all(&(encrypted.len() as u32).to_be_bytes()).unwrap();
+        }
+        output.write_all(&encrypted).unwrap();
+    }
+    if header.backup {
+        header_backup::write_trailer(&mut output, &header.encode()).unwrap();
+    }
+}
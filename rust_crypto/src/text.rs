@@ -0,0 +1,67 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{MAX_HINT_LENGTH, MAX_LONG_HINT_LENGTH};
+
+// iOS and Android keyboards can produce different code point sequences for
+// the same text, so everything user-typed is stored as NFC.
+pub fn normalize(text: &str) -> String {
+    text.nfc().collect()
+}
+
+// Cuts on a grapheme boundary so an emoji or combining sequence is dropped
+// whole rather than split into invalid or different-looking text.
+pub fn truncate_graphemes(text: &str, max_bytes: usize) -> &str {
+    let end = text
+        .grapheme_indices(true)
+        .map(|(start, grapheme)| start + grapheme.len())
+        .take_while(|&end| end <= max_bytes)
+        .last()
+        .unwrap_or(0);
+    &text[..end]
+}
+
+pub fn hint_bytes(hint: Option<&str>) -> Vec<u8> {
+    let hint = normalize(hint.unwrap_or_default());
+    truncate_graphemes(&hint, MAX_LONG_HINT_LENGTH).as_bytes().to_vec()
+}
+
+// What goes in the fixed hint slot of the header.
+pub fn hint_prefix(hint: &[u8]) -> &[u8] {
+    match std::str::from_utf8(hint) {
+        Ok(text) => truncate_graphemes(text, MAX_HINT_LENGTH).as_bytes(),
+        Err(_) => &hint[..hint.len().min(MAX_HINT_LENGTH)],
+    }
+}
+
+// Older versions cut hints at 32 bytes, sometimes inside a character; the
+// partial character is dropped rather than shown as replacement glyphs.
+pub fn readable_hint(hint: &[u8]) -> &[u8] {
+    match std::str::from_utf8(hint) {
+        Ok(_) => hint,
+        Err(e) => &hint[..e.valid_up_to()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hints_are_normalized_and_cut_between_graphemes() {
+        assert_eq!(normalize("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(hint_bytes(Some("cafe\u{301}")), hint_bytes(Some("caf\u{e9}")));
+
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        let hint = format!("{}{}", "a".repeat(MAX_LONG_HINT_LENGTH - 4), family);
+        assert_eq!(hint_bytes(Some(&hint)), "a".repeat(MAX_LONG_HINT_LENGTH - 4).into_bytes());
+        let hint = format!("{}{}", "a".repeat(MAX_HINT_LENGTH - 4), family);
+        assert_eq!(hint_prefix(hint.as_bytes()), "a".repeat(MAX_HINT_LENGTH - 4).as_bytes());
+        let cjk = "\u{5bc6}\u{7801}".repeat(6);
+        assert_eq!(hint_prefix(cjk.as_bytes()), "\u{5bc6}\u{7801}".repeat(5).as_bytes());
+        assert_eq!(readable_hint(&cjk.as_bytes()[..MAX_HINT_LENGTH]), "\u{5bc6}\u{7801}".repeat(5).as_bytes());
+        assert_eq!(truncate_graphemes("\u{1f1ef}\u{1f1f5}x", 9), "\u{1f1ef}\u{1f1f5}x");
+        assert_eq!(truncate_graphemes("\u{1f1ef}\u{1f1f5}x", 7), "");
+        assert!(hint_bytes(None).is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use aes_gcm::{aead::Aead, Nonce};
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::BufReader;
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::format::{read_layout, ContainerHeader};
+use crate::vault::unix_now;
+use crate::{decrypt_file_to_memory_internal, errors, write_encrypted_atomic};
+
+const FREE_ATTEMPTS: u32 = 3;
+const BASE_DELAY_SECS: u64 = 30;
+const MAX_DELAY_SECS: u64 = 60 * 60;
+
+pub const VERIFY_THROTTLED: i32 = -3;
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct ThrottleState {
+    pub failures: u32,
+    pub last_failure_at: u64,
+}
+
+impl ThrottleState {
+    pub fn lockout_secs(&self) -> u64 {
+        if self.failures < FREE_ATTEMPTS {
+            return 0;
+        }
+        let doublings = (self.failures - FREE_ATTEMPTS).min(16);
+        (BASE_DELAY_SECS << doublings).min(MAX_DELAY_SECS)
+    }
+
+    pub fn retry_after(&self, now: u64) -> u64 {
+        (self.last_failure_at + self.lockout_secs()).saturating_sub(now)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn verify_password(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    state_path_ptr: *const c_char,
+    state_key_ptr: *const u8,
+    state_key_len: usize,
+    retry_after_ptr: *mut u64,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let state_path = match CStr::from_ptr(state_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let state_key = std::slice::from_raw_parts(state_key_ptr, state_key_len);
+
+        match verify_password_throttled(Path::new(input_path), password, Path::new(state_path), state_key, unix_now()) {
+            Ok((result, retry_after)) => {
+                if !retry_after_ptr.is_null() {
+                    *retry_after_ptr = retry_after;
+                }
+                result
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_throttle_retry_after(
+    state_path_ptr: *const c_char,
+    state_key_ptr: *const u8,
+    state_key_len: usize,
+    retry_after_ptr: *mut u64,
+) -> i32 {
+    unsafe {
+        let state_path = match CStr::from_ptr(state_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let state_key = std::slice::from_raw_parts(state_key_ptr, state_key_len);
+        if retry_after_ptr.is_null() {
+            return -1;
+        }
+
+        match load_state(Path::new(state_path), state_key) {
+            Ok(state) => {
+                *retry_after_ptr = state.retry_after(unix_now());
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn verify_password_throttled(
+    input_path: &Path,
+    password: &[u8],
+    state_path: &Path,
+    state_key: &[u8],
+    now: u64,
+) -> Result<(i32, u64), Box<dyn std::error::Error>> {
+    let mut state = load_state(state_path, state_key)?;
+    let retry_after = state.retry_after(now);
+    if retry_after > 0 {
+        return Ok((VERIFY_THROTTLED, retry_after));
+    }
+
+    if password_matches(input_path, password)? {
+        if state != ThrottleState::default() {
+            write_encrypted_atomic(state_path, state_key, &serde_json::to_vec(&ThrottleState::default())?)?;
+        }
+        return Ok((1, 0));
+    }
+
+    state.failures = state.failures.saturating_add(1);
+    state.last_failure_at = now;
+    write_encrypted_atomic(state_path, state_key, &serde_json::to_vec(&state)?)?;
+    Ok((0, state.retry_after(now)))
+}
+
+// Decides on the cheapest evidence the file carries: the wrapped key or the
+// header tag when there is one, otherwise the tag of the first chunk.
+pub fn password_matches(input_path: &Path, password: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
+    let layout = read_layout(input_path)?;
+    let key = match layout.master_key(password) {
+        Ok(key) => Zeroizing::new(key),
+        Err(e) if errors::classify(e.as_ref()) == errors::ERR_WRONG_PASSWORD => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let (header, _) = ContainerHeader::read(&mut layout.header.as_slice())?;
+    if header.file_id.is_some() {
+        return Ok(header.verify_file_id(&key).is_ok());
+    }
+    let mut reader = BufReader::new(File::open(input_path)?);
+    let layout = layout.unlock(&mut reader, &key)?;
+    let cipher = layout.body_cipher(&key)?;
+    let (nonce, ciphertext) = layout.read_frame(&mut reader, &layout.frames[0])?;
+    Ok(cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref()).is_ok())
+}
+
+fn load_state(state_path: &Path, state_key: &[u8]) -> Result<ThrottleState, Box<dyn std::error::Error>> {
+    if !state_path.exists() {
+        return Ok(ThrottleState::default());
+    }
+    let path = state_path.to_str().ok_or("Invalid state path")?;
+    let data = decrypt_file_to_memory_internal(path, state_key, false, 1)?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_framed_container;
+
+    #[test]
+    fn test_lockout_curve() {
+        let delays: Vec<u64> = (0..12)
+            .map(|failures| ThrottleState { failures, last_failure_at: 0 }.lockout_secs())
+            .collect();
+        assert_eq!(delays, vec![0, 0, 0, 30, 60, 120, 240, 480, 960, 1920, 3600, 3600]);
+    }
+
+    #[test]
+    fn test_failures_are_persisted_and_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("secret.kyl");
+        let state = dir.path().join("throttle.kyl");
+        write_framed_container(&file, b"right", &[b"secret"]);
+
+        for attempt in 1..=3 {
+            let (result, retry_after) = verify_password_throttled(&file, b"wrong", &state, b"device", 1000).unwrap();
+            assert_eq!(result, 0);
+            assert_eq!(retry_after, if attempt < 3 { 0 } else { 30 });
+        }
+        assert!(load_state(&state, b"other device").is_err());
+
+        let (result, retry_after) = verify_password_throttled(&file, b"right", &state, b"device", 1010).unwrap();
+        assert_eq!((result, retry_after), (VERIFY_THROTTLED, 20));
+
+        let (result, _) = verify_password_throttled(&file, b"right", &state, b"device", 1030).unwrap();
+        assert_eq!(result, 1);
+        assert_eq!(load_state(&state, b"device").unwrap(), ThrottleState::default());
+    }
+}
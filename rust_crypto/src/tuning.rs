@@ -0,0 +1,166 @@
+use aes_gcm::{AeadInPlace, Nonce};
+use rayon::prelude::*;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::cipher::{BodyCipher, CIPHER_AES_256_GCM};
+use crate::config::{self, kyrie_set_config, KyrieConfig, MIN_CHUNK_SIZE};
+use crate::{errors, get_chunk_size, random_nonce};
+
+// Longer samples only slow the app's first launch without changing the
+// recommendation much.
+const MAX_SAMPLE_SIZE: usize = 256 * 1024 * 1024;
+const MAX_BATCH_SIZE: usize = 16;
+// A batch should take about this long to write, which keeps progress
+// reports and cancellation responsive while still amortising the fsyncs.
+const TARGET_BATCH_TIME: Duration = Duration::from_millis(250);
+
+// Measured speeds and the chunk layout they suggest. Every field is a plain
+// number, so the app can store the struct and pass it back on later launches
+// instead of benchmarking again.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct KyrieTuning {
+    pub aes_bytes_per_sec: u64,
+    pub parallel_aes_bytes_per_sec: u64,
+    pub storage_bytes_per_sec: u64,
+    pub chunk_size: usize,
+    pub parallel_batch_size: usize,
+}
+
+// Encrypts and writes `sample_size` bytes of scratch data. A null directory
+// measures the system temp directory, which on a phone is rarely where the
+// vault lives.
+#[no_mangle]
+pub extern "C" fn kyrie_benchmark(sample_size: usize, dir_ptr: *const c_char, is_mobile: bool, tuning_ptr: *mut KyrieTuning) -> i32 {
+    if tuning_ptr.is_null() || sample_size == 0 {
+        return errors::invalid_argument();
+    }
+    let dir = if dir_ptr.is_null() {
+        std::env::temp_dir()
+    } else {
+        match unsafe { CStr::from_ptr(dir_ptr) }.to_str() {
+            Ok(s) => PathBuf::from(s),
+            Err(_) => return errors::invalid_argument(),
+        }
+    };
+
+    match benchmark(sample_size, &dir, is_mobile) {
+        Ok(tuning) => {
+            unsafe { *tuning_ptr = tuning };
+            0
+        }
+        Err(e) => errors::fail(e.as_ref()),
+    }
+}
+
+// Replaces the chunk size and batch size of the active config, keeping its
+// thread count and memory budget.
+#[no_mangle]
+pub extern "C" fn kyrie_apply_tuning(tuning_ptr: *const KyrieTuning) -> i32 {
+    let Some(tuning) = (unsafe { tuning_ptr.as_ref() }) else {
+        return errors::invalid_argument();
+    };
+    kyrie_set_config(&tuning.config(KyrieConfig::current()))
+}
+
+impl KyrieTuning {
+    pub fn config(&self, base: KyrieConfig) -> KyrieConfig {
+        KyrieConfig { chunk_size: self.chunk_size, max_parallel_chunks: self.parallel_batch_size, ..base }
+    }
+}
+
+pub fn benchmark(sample_size: usize, dir: &Path, is_mobile: bool) -> Result<KyrieTuning, Box<dyn std::error::Error>> {
+    let sample_size = sample_size.min(MAX_SAMPLE_SIZE);
+    let cipher = BodyCipher::new(CIPHER_AES_256_GCM, &rand::random())?;
+    let mut sample = vec![0u8; sample_size];
+
+    // The first pass warms the caches and the backend selection.
+    seal(&cipher, &mut sample[..sample_size.min(MIN_CHUNK_SIZE)])?;
+    let started = Instant::now();
+    seal(&cipher, &mut sample)?;
+    let aes = bytes_per_sec(sample_size, started.elapsed());
+
+    let threads = config::install(rayon::current_num_threads);
+    let piece = sample_size.div_ceil(threads);
+    let started = Instant::now();
+    config::install(|| sample.par_chunks_mut(piece).try_for_each(|chunk| seal(&cipher, chunk)))?;
+    let parallel_aes = bytes_per_sec(sample_size, started.elapsed());
+
+    let storage = measure_storage(dir, &sample)?;
+    Ok(recommend(aes, parallel_aes, storage, is_mobile))
+}
+
+// Detached, so the sample is sealed where it lies without growing by a tag.
+fn seal(cipher: &BodyCipher, data: &mut [u8]) -> Result<(), &'static str> {
+    cipher.encrypt_in_place_detached(Nonce::from_slice(&random_nonce()), b"", data).map(|_| ()).map_err(|_| "Encryption failed")
+}
+
+fn measure_storage(dir: &Path, sample: &[u8]) -> Result<u64, Box<dyn std::error::Error>> {
+    let path = dir.join(format!(".kyrie-benchmark-{}", hex::encode(rand::random::<[u8; 8]>())));
+    let result = (|| -> std::io::Result<Duration> {
+        let started = Instant::now();
+        let mut file = File::create(&path)?;
+        file.write_all(sample)?;
+        file.sync_all()?;
+        Ok(started.elapsed())
+    })();
+    let _ = std::fs::remove_file(&path);
+    Ok(bytes_per_sec(sample.len(), result?))
+}
+
+fn bytes_per_sec(bytes: usize, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64().max(1e-9)) as u64
+}
+
+// Only as many chunks run at once as it takes to keep ahead of the disk,
+// and no more than the cores actually scaled to; chunks are then sized so
+// a batch is about TARGET_BATCH_TIME of writing.
+fn recommend(aes: u64, parallel_aes: u64, storage: u64, is_mobile: bool) -> KyrieTuning {
+    let scaling = (parallel_aes as f64 / aes.max(1) as f64).round().max(1.0) as usize;
+    let needed = (storage as f64 / aes.max(1) as f64).ceil() as usize + 1;
+    let parallel_batch_size = needed.min(scaling).clamp(1, MAX_BATCH_SIZE);
+
+    let batch_bytes = (storage as f64 * TARGET_BATCH_TIME.as_secs_f64()) as usize;
+    let chunk_size = (batch_bytes / parallel_batch_size).clamp(MIN_CHUNK_SIZE, get_chunk_size(is_mobile));
+    KyrieTuning {
+        aes_bytes_per_sec: aes,
+        parallel_aes_bytes_per_sec: parallel_aes,
+        storage_bytes_per_sec: storage,
+        // Whole powers of two keep frames aligned with storage blocks.
+        chunk_size: 1 << chunk_size.ilog2(),
+        parallel_batch_size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_benchmark_recommends_a_valid_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let tuning = benchmark(2 * 1024 * 1024, dir.path(), true).unwrap();
+        assert!(tuning.aes_bytes_per_sec > 0 && tuning.storage_bytes_per_sec > 0);
+        assert!((MIN_CHUNK_SIZE..=get_chunk_size(true)).contains(&tuning.chunk_size));
+        assert!(tuning.chunk_size.is_power_of_two());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let config = tuning.config(KyrieConfig { thread_count: 2, ..Default::default() });
+        assert_eq!(config.chunk_size(true), tuning.chunk_size);
+        assert_eq!(config.parallel_batch_size(8, true), tuning.parallel_batch_size);
+        assert_eq!(config.thread_count, 2);
+
+        // A slow card is kept ahead of by two chunks at a time; a fast disk
+        // needs more of them, and chunks big enough to fill its batch time.
+        let slow = recommend(400 << 20, 1600 << 20, 20 << 20, true);
+        assert_eq!((slow.parallel_batch_size, slow.chunk_size), (2, 2 << 20));
+        let fast = recommend(1 << 30, 8 << 30, 3 << 30, false);
+        assert_eq!((fast.parallel_batch_size, fast.chunk_size), (4, 128 << 20));
+        assert_eq!(kyrie_benchmark(0, std::ptr::null(), false, std::ptr::null_mut()), -1);
+    }
+}
@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fs;
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::format::read_header;
+use crate::progress::ProgressSink;
+use crate::quorum::FileKey;
+use crate::vault::unix_now;
+use crate::{decrypt_file_reporting, decrypt_file_to_memory_internal, errors, write_encrypted_atomic};
+
+// Only the most recent operations can be undone.
+const MAX_ENTRIES: usize = 20;
+
+// An encryption the app may want to take back, for when the wrong file was
+// locked and its original is already gone. The journal holding these is
+// itself encrypted under a key the app provides, and `file_key` opens the
+// container without its password, so that key should be a random secret
+// kept by the app rather than something typed in.
+#[derive(Serialize, Deserialize)]
+struct JournalRecord {
+    id: u64,
+    encrypted_path: String,
+    original_path: String,
+    recorded_at: u64,
+    file_key: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct UndoEntry {
+    pub id: u64,
+    pub encrypted_path: String,
+    pub original_path: String,
+    pub recorded_at: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_journal_record(
+    journal_path_ptr: *const c_char,
+    journal_key_ptr: *const u8,
+    journal_key_len: usize,
+    encrypted_path_ptr: *const c_char,
+    original_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    entry_id_ptr: *mut u64,
+) -> i32 {
+    unsafe {
+        let journal_path = match CStr::from_ptr(journal_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let encrypted_path = match CStr::from_ptr(encrypted_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let original_path = match CStr::from_ptr(original_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let journal_key = std::slice::from_raw_parts(journal_key_ptr, journal_key_len);
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match record_operation(Path::new(journal_path), journal_key, encrypted_path, original_path, password) {
+            Ok(id) => {
+                if !entry_id_ptr.is_null() {
+                    *entry_id_ptr = id;
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_journal_list(
+    journal_path_ptr: *const c_char,
+    journal_key_ptr: *const u8,
+    journal_key_len: usize,
+    json_ptr: *mut u8,
+    json_len: *mut usize,
+) -> i32 {
+    if json_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let journal_path = match CStr::from_ptr(journal_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let journal_key = std::slice::from_raw_parts(journal_key_ptr, journal_key_len);
+
+        match list_operations(Path::new(journal_path), journal_key).and_then(|entries| Ok(serde_json::to_vec(&entries)?)) {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_journal_undo(
+    journal_path_ptr: *const c_char,
+    journal_key_ptr: *const u8,
+    journal_key_len: usize,
+    entry_id: u64,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let journal_path = match CStr::from_ptr(journal_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let journal_key = std::slice::from_raw_parts(journal_key_ptr, journal_key_len);
+
+        match undo_operation(Path::new(journal_path), journal_key, entry_id, is_mobile, cpu_cores) {
+            Ok(()) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Called after `encrypted_path` was written from `original_path`; the
+// password is only needed here, to take the file key out of the header.
+pub fn record_operation(
+    journal_path: &Path,
+    journal_key: &[u8],
+    encrypted_path: &str,
+    original_path: &str,
+    password: &[u8],
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(Path::new(encrypted_path))?;
+    if header.dual() || header.share_policy.is_some() {
+        return Err("File is not password-protected".into());
+    }
+    let file_key = Zeroizing::new(header.master_key(password)?);
+    header.verify_file_id(&file_key).map_err(|_| "Invalid password")?;
+
+    let mut records = load_records(journal_path, journal_key)?;
+    let id = records.iter().map(|r| r.id).max().map_or(1, |last| last + 1);
+    records.push(JournalRecord {
+        id,
+        encrypted_path: encrypted_path.to_string(),
+        original_path: original_path.to_string(),
+        recorded_at: unix_now(),
+        file_key: hex::encode(*file_key),
+    });
+    if records.len() > MAX_ENTRIES {
+        records.drain(..records.len() - MAX_ENTRIES);
+    }
+    save_records(journal_path, journal_key, &records)?;
+    Ok(id)
+}
+
+// Newest first, without the keys.
+pub fn list_operations(journal_path: &Path, journal_key: &[u8]) -> Result<Vec<UndoEntry>, Box<dyn std::error::Error>> {
+    Ok(load_records(journal_path, journal_key)?
+        .into_iter()
+        .rev()
+        .map(|r| UndoEntry {
+            id: r.id,
+            encrypted_path: r.encrypted_path,
+            original_path: r.original_path,
+            recorded_at: r.recorded_at,
+        })
+        .collect())
+}
+
+// Decrypts the container back to where the original was, then deletes the
+// container and the entry. Nothing is removed unless the original was
+// restored, and an existing file at the original path is left alone.
+pub fn undo_operation(
+    journal_path: &Path,
+    journal_key: &[u8],
+    entry_id: u64,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut records = load_records(journal_path, journal_key)?;
+    let position = records.iter().position(|r| r.id == entry_id).ok_or("Journal entry not found")?;
+    let record = &records[position];
+
+    let mut file_key = Zeroizing::new([0u8; 32]);
+    hex::decode_to_slice(&record.file_key, &mut file_key[..]).map_err(|_| "Invalid file format")?;
+    decrypt_file_reporting(
+        &record.encrypted_path,
+        &record.original_path,
+        &FileKey(file_key),
+        is_mobile,
+        cpu_cores,
+        &ProgressSink::none(),
+    )?;
+    fs::remove_file(&record.encrypted_path)?;
+
+    records.remove(position);
+    save_records(journal_path, journal_key, &records)
+}
+
+fn load_records(journal_path: &Path, journal_key: &[u8]) -> Result<Vec<JournalRecord>, Box<dyn std::error::Error>> {
+    if !journal_path.exists() {
+        return Ok(Vec::new());
+    }
+    let path = journal_path.to_str().ok_or("Invalid journal path")?;
+    let data = Zeroizing::new(decrypt_file_to_memory_internal(path, journal_key, false, 1)?);
+    Ok(serde_json::from_slice(&data)?)
+}
+
+fn save_records(journal_path: &Path, journal_key: &[u8], records: &[JournalRecord]) -> Result<(), Box<dyn std::error::Error>> {
+    let data = Zeroizing::new(serde_json::to_vec(records)?);
+    write_encrypted_atomic(journal_path, journal_key, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+
+    #[test]
+    fn test_undo_restores_the_original_and_forgets_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = dir.path().join("undo.journal");
+        let plain = dir.path().join("wrong.jpg");
+        let encrypted = dir.path().join("wrong.kyl");
+        fs::write(&plain, b"not this one").unwrap();
+        let (plain_str, encrypted_str) = (plain.to_str().unwrap(), encrypted.to_str().unwrap());
+        encrypt_file_internal(plain_str, encrypted_str, b"pw", None, false, 4).unwrap();
+        fs::remove_file(&plain).unwrap();
+
+        let wrong = record_operation(&journal, b"journal key", encrypted_str, plain_str, b"nope").unwrap_err();
+        assert_eq!(errors::classify(wrong.as_ref()), errors::ERR_WRONG_PASSWORD);
+        let id = record_operation(&journal, b"journal key", encrypted_str, plain_str, b"pw").unwrap();
+        // The journal gives away neither the paths nor the key.
+        let stored = fs::read(&journal).unwrap();
+        assert!(!stored.windows(b"wrong.jpg".len()).any(|w| w == b"wrong.jpg"));
+        let entries = list_operations(&journal, b"journal key").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].id, entries[0].original_path.as_str()), (id, plain_str));
+        let locked = list_operations(&journal, b"other key").unwrap_err();
+        assert_eq!(errors::classify(locked.as_ref()), errors::ERR_WRONG_PASSWORD);
+
+        let missing = undo_operation(&journal, b"journal key", id + 1, false, 4).unwrap_err();
+        assert_eq!(errors::classify(missing.as_ref()), errors::ERR_INVALID_ARGUMENT);
+        undo_operation(&journal, b"journal key", id, false, 4).unwrap();
+        assert_eq!(fs::read(&plain).unwrap(), b"not this one");
+        assert!(!encrypted.exists());
+        assert!(list_operations(&journal, b"journal key").unwrap().is_empty());
+    }
+}
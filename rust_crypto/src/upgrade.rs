@@ -0,0 +1,176 @@
+use std::ffi::CStr;
+use std::fs;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::Zeroizing;
+
+use crate::errors;
+use crate::format::{read_header, ContainerHeader, NONCE_SCHEME_COUNTER};
+use crate::kdf::{KdfParams, KEY_PURPOSE_DEVICE, KEY_PURPOSE_LEGACY};
+use crate::naming::NamingPolicy;
+use crate::reencrypt::{reencrypt_file_internal, ReencryptOptions};
+
+// When set, rewrites such as rekeying or changing the hint also give the
+// file a new file id. Its body moves to counter nonces under a fresh file
+// key on every re-encryption either way.
+static UPGRADE_ON_WRITE: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+#[derive(Default, Debug, PartialEq)]
+pub struct KyrieLegacyCount {
+    pub scanned: u64,
+    pub legacy: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_set_upgrade_on_write(enabled: bool) {
+    UPGRADE_ON_WRITE.store(enabled, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    UPGRADE_ON_WRITE.load(Ordering::SeqCst)
+}
+
+#[no_mangle]
+pub extern "C" fn count_legacy_files(vault_dir_ptr: *const c_char, count_ptr: *mut KyrieLegacyCount) -> i32 {
+    if count_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        match count_legacy_files_internal(Path::new(vault_dir)) {
+            Ok(count) => {
+                *count_ptr = count;
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Returns 1 when the file was rewritten and 0 when it was already current.
+#[no_mangle]
+pub extern "C" fn upgrade_file(path_ptr: *const c_char, password_ptr: *const u8, password_len: usize, is_mobile: bool) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match upgrade_file_internal(Path::new(path), password, is_mobile) {
+            Ok(upgraded) => upgraded as i32,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Moves a legacy file to a salted KDF, a wrapped file key, counter nonces
+// and a chunk index in one pass. Chunks are decrypted and sealed again in
+// memory, into a temp file that replaces the original once complete.
+pub fn upgrade_file_internal(path: &Path, password: &[u8], is_mobile: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(path)?;
+    if is_current(&header) {
+        // Still checked, so a wrong password is not taken for success.
+        let master = Zeroizing::new(header.master_key(password)?);
+        header.verify_file_id(&master).map_err(|_| "Invalid password")?;
+        return Ok(false);
+    }
+    let path = path.to_str().ok_or("Invalid path")?;
+    let options = ReencryptOptions { upgrade: true, ..Default::default() };
+    reencrypt_file_internal(path, path, password, password, &options, is_mobile)?;
+    Ok(true)
+}
+
+// Files whose header cannot be read are not containers and are skipped.
+// Dual-slot containers cannot be rewritten, so they are never counted as
+// legacy.
+pub fn count_legacy_files_internal(vault_dir: &Path) -> Result<KyrieLegacyCount, Box<dyn std::error::Error>> {
+    let policy = NamingPolicy::current();
+    let mut count = KyrieLegacyCount::default();
+    for dir_entry in fs::read_dir(vault_dir)? {
+        let path = dir_entry?.path();
+        if !path.is_file() || !policy.is_encrypted_name(&path) {
+            continue;
+        }
+        let Ok((header, _)) = read_header(&path) else {
+            continue;
+        };
+        count.scanned += 1;
+        if !header.dual() && !is_current(&header) {
+            count.legacy += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Containers keyed without the password (shared, device and convergent
+// ones) have no KDF to upgrade.
+pub fn is_current(header: &ContainerHeader) -> bool {
+    let password_keyed =
+        header.share_policy.is_none() && header.convergent.is_none() && header.key_purpose != KEY_PURPOSE_DEVICE;
+    header.file_id.is_some()
+        && header.nonce_scheme == NONCE_SCHEME_COUNTER
+        && header.key_purpose != KEY_PURPOSE_LEGACY
+        && (!password_keyed || (header.kdf != KdfParams::LegacySha256 && header.wrapped_key.is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+    use crate::test_util::write_framed_container;
+
+    #[test]
+    fn test_rewrites_upgrade_legacy_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        fs::write(&plain, b"current").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), dir.path().join("new.kyl").to_str().unwrap(), b"pw", None, false, 4)
+            .unwrap();
+        let legacy = dir.path().join("old.kyl");
+        write_framed_container(&legacy, b"pw", &[b"legacy"]);
+        fs::remove_file(&plain).unwrap();
+        assert_eq!(count_legacy_files_internal(dir.path()).unwrap(), KyrieLegacyCount { scanned: 2, legacy: 1 });
+
+        let path = legacy.to_str().unwrap();
+        // Any rewrite moves to a wrapped file key and counter nonces; only an
+        // upgrade also gives up the old id.
+        reencrypt_file_internal(path, path, b"pw", b"pw", &ReencryptOptions::default(), false).unwrap();
+        let (header, _) = read_header(&legacy).unwrap();
+        assert!(is_current(&header));
+        let id_before = header.file_id.unwrap().uuid;
+        reencrypt_file_internal(path, path, b"pw", b"pw", &ReencryptOptions::default(), false).unwrap();
+        assert_eq!(read_header(&legacy).unwrap().0.file_id.unwrap().uuid, id_before);
+
+        let options = ReencryptOptions { upgrade: true, ..Default::default() };
+        reencrypt_file_internal(path, path, b"pw", b"pw", &options, false).unwrap();
+        let (header, _) = read_header(&legacy).unwrap();
+        assert!(is_current(&header));
+        assert_ne!(header.file_id.unwrap().uuid, id_before);
+        assert_eq!(count_legacy_files_internal(dir.path()).unwrap(), KyrieLegacyCount { scanned: 2, legacy: 0 });
+        assert_eq!(crate::decrypt_file_to_memory_internal(path, b"pw", false, 4).unwrap(), b"legacy");
+    }
+
+    #[test]
+    fn test_upgrade_file_rewrites_only_legacy_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("old.kyl");
+        write_framed_container(&legacy, b"pw", &[b"first ", b"second"]);
+        assert!(upgrade_file_internal(&legacy, b"wrong", false).is_err());
+        assert!(!is_current(&read_header(&legacy).unwrap().0));
+
+        assert!(upgrade_file_internal(&legacy, b"pw", false).unwrap());
+        let (header, _) = read_header(&legacy).unwrap();
+        assert!(is_current(&header) && header.chunk_size.is_some());
+        assert!(!upgrade_file_internal(&legacy, b"pw", false).unwrap());
+        let wrong = upgrade_file_internal(&legacy, b"wrong", false).unwrap_err();
+        assert_eq!(errors::classify(wrong.as_ref()), errors::ERR_WRONG_PASSWORD);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(crate::decrypt_file_to_memory_internal(legacy.to_str().unwrap(), b"pw", false, 4).unwrap(), b"first second");
+    }
+}
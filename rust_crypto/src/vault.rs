@@ -0,0 +1,566 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+use crate::config;
+use crate::rng;
+use crate::secure_temp::{SecureTempFile, TempContents};
+use crate::derive_key;
+use crate::format::{encode_kdf_params, parse_kdf_params, KDF_PARAMS_SIZE};
+use crate::kdf::{self, KdfParams, KDF_SALT_SIZE};
+use crate::naming::NamingPolicy;
+
+pub(crate) const MANIFEST_FILE_NAME: &str = ".kyrie_vault.json";
+const SIGNING_KEY_CONTEXT: &[u8] = b"KYRIE_VAULT_SIGNING";
+const DIGEST_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct VaultManifest {
+    pub entries: Vec<VaultEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    // Hex of the KDF parameters and salt the signing key is derived with.
+    // Manifests signed before it was stored use the legacy key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_kdf: Option<String>,
+    // Per-device save counters, so edits made on two devices without seeing
+    // each other can be told apart from a plain fast-forward.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub versions: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VaultEntry {
+    pub file_name: String,
+    // Entries recorded before BLAKE3 keep their SHA-256 digest so that
+    // existing manifest signatures still verify.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub blake3: String,
+    pub keyed_at: u64,
+    pub verified_at: u64,
+}
+
+#[derive(Default)]
+pub struct IntegrityReport {
+    pub signature_valid: bool,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub replaced: Vec<String>,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieVaultIntegrityReport {
+    pub signature_valid: bool,
+    pub added: u64,
+    pub removed: u64,
+    pub replaced: u64,
+}
+
+pub struct Vault {
+    dir: PathBuf,
+    manifest: VaultManifest,
+    signing_key: Option<SigningKey>,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vault_enable_signing(
+    vault_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let result = Vault::open(Path::new(vault_dir)).and_then(|mut vault| {
+            vault.enable_signing(password)?;
+            vault.save()
+        });
+        match result {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vault_add_entry(
+    vault_dir_ptr: *const c_char,
+    file_name_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let file_name = match CStr::from_ptr(file_name_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match register_entry(Path::new(vault_dir), file_name, password) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn verify_vault_integrity(
+    vault_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    report_ptr: *mut KyrieVaultIntegrityReport,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        let result = Vault::load(Path::new(vault_dir)).and_then(|vault| vault.verify_integrity(password));
+        match result {
+            Ok(report) => {
+                if !report_ptr.is_null() {
+                    *report_ptr = KyrieVaultIntegrityReport {
+                        signature_valid: report.signature_valid,
+                        added: report.added.len() as u64,
+                        removed: report.removed.len() as u64,
+                        replaced: report.replaced.len() as u64,
+                    };
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn register_entry(
+    vault_dir: &Path,
+    file_name: &str,
+    password: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut vault = Vault::open(vault_dir)?;
+    if vault.is_signed() {
+        vault.unlock_signing(password)?;
+    }
+    vault.add_entry(file_name)?;
+    vault.save()
+}
+
+impl Vault {
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let manifest_path = dir.join(MANIFEST_FILE_NAME);
+        let manifest = if manifest_path.exists() {
+            serde_json::from_slice(&fs::read(&manifest_path)?)?
+        } else {
+            VaultManifest::default()
+        };
+
+        Ok(Vault {
+            dir: dir.to_path_buf(),
+            manifest,
+            signing_key: None,
+        })
+    }
+
+    pub fn open(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut vault = Self::load(dir)?;
+        if !vault.is_signed() {
+            vault.sync()?;
+        }
+        Ok(vault)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.manifest.public_key.is_some()
+    }
+
+    pub fn manifest(&self) -> &VaultManifest {
+        &self.manifest
+    }
+
+    pub(crate) fn manifest_mut(&mut self) -> &mut VaultManifest {
+        &mut self.manifest
+    }
+
+    pub fn entries(&self) -> &[VaultEntry] {
+        &self.manifest.entries
+    }
+
+    pub fn entry_path(&self, file_name: &str) -> PathBuf {
+        self.dir.join(file_name)
+    }
+
+    pub fn entry_mut(&mut self, file_name: &str) -> Option<&mut VaultEntry> {
+        self.manifest
+            .entries
+            .iter_mut()
+            .find(|e| e.file_name == file_name)
+    }
+
+    pub fn add_entry(&mut self, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let blake3 = file_blake3(&self.entry_path(file_name))?;
+        let now = unix_now();
+        match self.entry_mut(file_name) {
+            Some(entry) => {
+                entry.set_digest(blake3);
+                entry.keyed_at = now;
+                entry.verified_at = now;
+            }
+            None => {
+                self.manifest.entries.push(VaultEntry {
+                    file_name: file_name.to_string(),
+                    sha256: String::new(),
+                    blake3,
+                    keyed_at: now,
+                    verified_at: now,
+                });
+                self.manifest
+                    .entries
+                    .sort_by(|a, b| a.file_name.cmp(&b.file_name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn refresh_entry(&mut self, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let blake3 = file_blake3(&self.entry_path(file_name))?;
+        let entry = self.entry_mut(file_name).ok_or("Unknown vault entry")?;
+        entry.set_digest(blake3);
+        Ok(())
+    }
+
+    pub fn enable_signing(&mut self, password: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.new_signing_key(password)?;
+        let names: Vec<String> = self.entries().iter().map(|e| e.file_name.clone()).collect();
+        for name in names {
+            self.refresh_entry(&name)?;
+        }
+        Ok(())
+    }
+
+    pub fn unlock_signing(&mut self, password: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let signing_key = signing_key_from_password(password, self.manifest.signing_kdf.as_deref())?;
+        let public_key = hex::encode(signing_key.verifying_key().as_bytes());
+        if self.manifest.public_key.as_deref() != Some(public_key.as_str()) {
+            return Err("Wrong vault password".into());
+        }
+        if self.manifest.signing_kdf.is_some() {
+            self.signing_key = Some(signing_key);
+            return Ok(());
+        }
+        // A manifest under the unsalted legacy key moves to a salted one on
+        // first unlock. It is re-signed right away only if its entries still
+        // match the old signature; otherwise the mismatch stays visible until
+        // the app saves.
+        let signature = self.manifest.signature.as_deref().and_then(|s| hex::decode(s).ok()).and_then(|b| Signature::from_slice(&b).ok());
+        let entries = serde_json::to_vec(&self.manifest.entries)?;
+        let intact = signature.is_some_and(|signature| signing_key.verifying_key().verify(&entries, &signature).is_ok());
+        self.new_signing_key(password)?;
+        if intact {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn new_signing_key(&mut self, password: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let signing_kdf = new_signing_kdf();
+        let signing_key = signing_key_from_password(password, Some(&signing_kdf))?;
+        self.manifest.signing_kdf = Some(signing_kdf);
+        self.manifest.public_key = Some(hex::encode(signing_key.verifying_key().as_bytes()));
+        self.signing_key = Some(signing_key);
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.is_signed() {
+            let signing_key = self.signing_key.as_ref().ok_or("Vault signing key is locked")?;
+            let signature = signing_key.sign(&serde_json::to_vec(&self.manifest.entries)?);
+            self.manifest.signature = Some(hex::encode(signature.to_bytes()));
+        }
+
+        let manifest_path = self.dir.join(MANIFEST_FILE_NAME);
+        let mut temp = SecureTempFile::for_target(&manifest_path, TempContents::Metadata)?;
+        temp.file().write_all(&serde_json::to_vec_pretty(&self.manifest)?)?;
+        temp.persist(&manifest_path)?;
+        Ok(())
+    }
+
+    pub fn verify_integrity(&self, password: &[u8]) -> Result<IntegrityReport, Box<dyn std::error::Error>> {
+        let verifying_key = signing_key_from_password(password, self.manifest.signing_kdf.as_deref())?.verifying_key();
+        let public_key_matches =
+            self.manifest.public_key.as_deref() == Some(hex::encode(verifying_key.as_bytes()).as_str());
+        let signature = self
+            .manifest
+            .signature
+            .as_deref()
+            .and_then(|s| hex::decode(s).ok())
+            .and_then(|b| Signature::from_slice(&b).ok());
+        let signature_valid = match signature {
+            Some(signature) if public_key_matches => verifying_key
+                .verify(&serde_json::to_vec(&self.manifest.entries)?, &signature)
+                .is_ok(),
+            _ => false,
+        };
+
+        let present = self.encrypted_files()?;
+        let mut report = IntegrityReport {
+            signature_valid,
+            ..Default::default()
+        };
+        for name in &present {
+            if !self.entries().iter().any(|e| e.file_name == *name) {
+                report.added.push(name.clone());
+            }
+        }
+        let (missing, kept): (Vec<&VaultEntry>, Vec<&VaultEntry>) =
+            self.entries().iter().partition(|e| !present.contains(&e.file_name));
+        report.removed = missing.iter().map(|e| e.file_name.clone()).collect();
+        let unchanged = config::install(|| {
+            kept.par_iter()
+                .map(|entry| entry.matches(&self.entry_path(&entry.file_name)))
+                .collect::<Result<Vec<bool>, _>>()
+                .map_err(|e| e.to_string())
+        })?;
+        report.replaced = kept
+            .iter()
+            .zip(unchanged)
+            .filter(|(_, unchanged)| !unchanged)
+            .map(|(entry, _)| entry.file_name.clone())
+            .collect();
+        Ok(report)
+    }
+
+    fn encrypted_files(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut names = Vec::new();
+        let policy = NamingPolicy::current();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if !path.is_file() || !policy.is_encrypted_name(&path) {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub(crate) fn sync(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let present = self.encrypted_files()?;
+        self.manifest
+            .entries
+            .retain(|e| present.contains(&e.file_name));
+        for name in present {
+            if self.entry_mut(&name).is_none() {
+                let path = self.entry_path(&name);
+                let modified = modified_secs(&path);
+                self.manifest.entries.push(VaultEntry {
+                    sha256: String::new(),
+                    blake3: file_blake3(&path)?,
+                    file_name: name,
+                    keyed_at: modified,
+                    verified_at: modified,
+                });
+            }
+        }
+        self.manifest
+            .entries
+            .sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        Ok(())
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub(crate) fn new_signing_kdf() -> String {
+    let mut record = encode_kdf_params(&KdfParams::for_new_files(false));
+    record.extend_from_slice(&rng::random::<[u8; KDF_SALT_SIZE]>());
+    hex::encode(record)
+}
+
+pub(crate) fn signing_key_from_password(
+    password: &[u8],
+    signing_kdf: Option<&str>,
+) -> Result<SigningKey, Box<dyn std::error::Error>> {
+    let key = Zeroizing::new(match signing_kdf {
+        Some(record) => {
+            let record = hex::decode(record)?;
+            if record.len() != KDF_PARAMS_SIZE + KDF_SALT_SIZE {
+                return Err("Unsupported KDF parameters".into());
+            }
+            let (params, salt) = record.split_at(KDF_PARAMS_SIZE);
+            kdf::derive(password, salt, &parse_kdf_params(params)?)?
+        }
+        None => derive_key(password),
+    });
+    let mut hasher = Sha256::new();
+    hasher.update(SIGNING_KEY_CONTEXT);
+    hasher.update(key.as_slice());
+    Ok(SigningKey::from_bytes(&hasher.finalize().into()))
+}
+
+impl VaultEntry {
+    fn set_digest(&mut self, blake3: String) {
+        self.sha256.clear();
+        self.blake3 = blake3;
+    }
+
+    pub fn matches(&self, path: &Path) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let matches = if self.blake3.is_empty() {
+            file_sha256(path).map(|digest| digest == self.sha256)
+        } else {
+            file_blake3(path).map(|digest| digest == self.blake3)
+        };
+        matches.map_err(|e| e.to_string().into())
+    }
+}
+
+// Hashes large files on all cores; SHA-256 is kept only where a digest
+// format already depends on it.
+pub fn file_blake3(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let mut hasher = blake3::Hasher::new();
+    hash_file(&mut hasher, path)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+// Browser builds have nothing to map, so they read the file in order.
+pub fn hash_file(hasher: &mut blake3::Hasher, path: &Path) -> std::io::Result<()> {
+    #[cfg(not(target_arch = "wasm32"))]
+    hasher.update_mmap_rayon(path)?;
+    #[cfg(target_arch = "wasm32")]
+    hasher.update_reader(File::open(path)?)?;
+    Ok(())
+}
+
+pub fn file_sha256(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; DIGEST_BUFFER_SIZE];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            n => hasher.update(&buffer[..n]),
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn modified_secs(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_tracks_encrypted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.jpg.kyl"), b"a").unwrap();
+        fs::write(dir.path().join("plain.txt"), b"b").unwrap();
+
+        let mut vault = Vault::open(dir.path()).unwrap();
+        assert_eq!(vault.entries().len(), 1);
+        assert_eq!(vault.entries()[0].file_name, "a.jpg.kyl");
+        vault.save().unwrap();
+
+        fs::remove_file(dir.path().join("a.jpg.kyl")).unwrap();
+        fs::write(dir.path().join("b.jpg.kyl"), b"b").unwrap();
+        let vault = Vault::open(dir.path()).unwrap();
+        assert_eq!(vault.entries().len(), 1);
+        assert_eq!(vault.entries()[0].file_name, "b.jpg.kyl");
+    }
+
+    #[test]
+    fn test_signed_vault_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.kyl"), b"a").unwrap();
+        fs::write(dir.path().join("b.kyl"), b"b").unwrap();
+
+        let mut vault = Vault::open(dir.path()).unwrap();
+        vault.enable_signing(b"pw").unwrap();
+        vault.save().unwrap();
+
+        let report = Vault::load(dir.path()).unwrap().verify_integrity(b"pw").unwrap();
+        assert!(report.signature_valid);
+        assert!(report.added.is_empty() && report.removed.is_empty() && report.replaced.is_empty());
+
+        fs::write(dir.path().join("a.kyl"), b"swapped").unwrap();
+        fs::remove_file(dir.path().join("b.kyl")).unwrap();
+        fs::write(dir.path().join("c.kyl"), b"c").unwrap();
+
+        let vault = Vault::open(dir.path()).unwrap();
+        let report = vault.verify_integrity(b"pw").unwrap();
+        assert!(report.signature_valid);
+        assert_eq!(report.replaced, vec!["a.kyl".to_string()]);
+        assert_eq!(report.removed, vec!["b.kyl".to_string()]);
+        assert_eq!(report.added, vec!["c.kyl".to_string()]);
+        assert!(!vault.verify_integrity(b"wrong").unwrap().signature_valid);
+
+        register_entry(dir.path(), "c.kyl", b"pw").unwrap();
+        let report = Vault::load(dir.path()).unwrap().verify_integrity(b"pw").unwrap();
+        assert!(report.signature_valid && report.added.is_empty());
+        assert!(register_entry(dir.path(), "c.kyl", b"wrong").is_err());
+    }
+
+    #[test]
+    fn test_signing_key_is_salted_and_legacy_manifests_upgrade_on_unlock() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.kyl"), b"a").unwrap();
+        let mut vault = Vault::open(dir.path()).unwrap();
+        vault.enable_signing(b"pw").unwrap();
+        let legacy = signing_key_from_password(b"pw", None).unwrap();
+        assert!(vault.manifest.signing_kdf.is_some());
+        assert_ne!(vault.manifest.public_key, Some(hex::encode(legacy.verifying_key().as_bytes())));
+
+        vault.manifest.signing_kdf = None;
+        let legacy_public = legacy.verifying_key();
+        vault.manifest.public_key = Some(hex::encode(legacy_public.as_bytes()));
+        vault.signing_key = Some(legacy);
+        vault.save().unwrap();
+        let mut vault = Vault::load(dir.path()).unwrap();
+        assert!(vault.verify_integrity(b"pw").unwrap().signature_valid);
+        assert!(!vault.verify_integrity(b"wrong").unwrap().signature_valid);
+
+        vault.unlock_signing(b"pw").unwrap();
+        let vault = Vault::load(dir.path()).unwrap();
+        assert!(vault.manifest.signing_kdf.is_some());
+        assert_ne!(vault.manifest.public_key, Some(hex::encode(legacy_public.as_bytes())));
+        assert!(vault.verify_integrity(b"pw").unwrap().signature_valid);
+    }
+}
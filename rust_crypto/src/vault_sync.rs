@@ -0,0 +1,355 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use crate::errors;
+use crate::vault::{unix_now, Vault, VaultEntry, VaultManifest, MANIFEST_FILE_NAME};
+
+const LOCK_FILE_NAME: &str = ".kyrie_vault.lock";
+// A device that crashed or went offline mid-edit must not block the vault
+// forever; sync services can also take a while to deliver the release.
+const STALE_LOCK_SECS: u64 = 10 * 60;
+
+pub type VersionVector = BTreeMap<String, u64>;
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct LockInfo {
+    device_id: String,
+    token: String,
+    acquired_at: u64,
+}
+
+// Held while a device rewrites the manifest. Released when dropped, unless
+// another device has since broken it as stale.
+pub struct VaultLock {
+    path: PathBuf,
+    info: LockInfo,
+}
+
+#[derive(Default)]
+pub struct ConflictReport {
+    // Copies holding edits the manifest has not seen.
+    pub conflicts: Vec<String>,
+    // Copies the manifest already includes; safe to delete.
+    pub stale: Vec<String>,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieVaultConflictReport {
+    pub conflicts: u64,
+    pub stale: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vault_check_conflicts(
+    vault_dir_ptr: *const c_char,
+    report_ptr: *mut KyrieVaultConflictReport,
+) -> i32 {
+    if report_ptr.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        match detect_conflicts(Path::new(vault_dir)) {
+            Ok(report) => {
+                *report_ptr = KyrieVaultConflictReport {
+                    conflicts: report.conflicts.len() as u64,
+                    stale: report.stale.len() as u64,
+                };
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vault_add_entry_synced(
+    vault_dir_ptr: *const c_char,
+    file_name_ptr: *const c_char,
+    device_id_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let file_name = match CStr::from_ptr(file_name_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let device_id = match CStr::from_ptr(device_id_ptr).to_str() {
+            Ok(s) if !s.is_empty() => s,
+            _ => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match register_entry_synced(Path::new(vault_dir), file_name, device_id, password) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vault_resolve_conflicts(
+    vault_dir_ptr: *const c_char,
+    device_id_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+) -> i32 {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let device_id = match CStr::from_ptr(device_id_ptr).to_str() {
+            Ok(s) if !s.is_empty() => s,
+            _ => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match resolve_conflicts(Path::new(vault_dir), device_id, password) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+impl VaultLock {
+    pub fn acquire(dir: &Path, device_id: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = dir.join(LOCK_FILE_NAME);
+        let mut token = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut token);
+        let info = LockInfo { device_id: device_id.to_string(), token: hex::encode(token), acquired_at: unix_now() };
+
+        for _ in 0..2 {
+            match File::create_new(&path) {
+                Ok(mut file) => {
+                    file.write_all(&serde_json::to_vec(&info)?)?;
+                    file.sync_all()?;
+                    return Ok(VaultLock { path, info });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let holder: Option<LockInfo> = fs::read(&path).ok().and_then(|b| serde_json::from_slice(&b).ok());
+                    // An unreadable lock may still be arriving from the sync
+                    // service, so it ages out like any other.
+                    let acquired_at = holder.map(|h| h.acquired_at).unwrap_or_else(|| modified_secs(&path));
+                    if unix_now().saturating_sub(acquired_at) < STALE_LOCK_SECS {
+                        return Err("Vault is locked by another device".into());
+                    }
+                    let _ = fs::remove_file(&path);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err("Vault is locked by another device".into())
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        let still_ours = fs::read(&self.path)
+            .ok()
+            .and_then(|b| serde_json::from_slice::<LockInfo>(&b).ok())
+            .is_some_and(|holder| holder == self.info);
+        if still_ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// True when every edit counted in `a` is also counted in `b`.
+pub fn descends_from(b: &VersionVector, a: &VersionVector) -> bool {
+    a.iter().all(|(device, count)| b.get(device).is_some_and(|c| c >= count))
+}
+
+// Saves a vault loaded earlier, refusing if another device saved in between
+// or a sync service has left a conflicting copy of the manifest.
+pub fn save_synced(vault: &mut Vault, dir: &Path, device_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let _lock = VaultLock::acquire(dir, device_id)?;
+    if !detect_conflicts(dir)?.conflicts.is_empty() {
+        return Err("Vault manifest conflict".into());
+    }
+    let on_disk = read_manifest(&dir.join(MANIFEST_FILE_NAME))?.versions;
+    if !descends_from(&vault.manifest().versions, &on_disk) {
+        return Err("Vault manifest conflict".into());
+    }
+    *vault.manifest_mut().versions.entry(device_id.to_string()).or_insert(0) += 1;
+    vault.save()
+}
+
+pub fn register_entry_synced(
+    vault_dir: &Path,
+    file_name: &str,
+    device_id: &str,
+    password: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut vault = Vault::open(vault_dir)?;
+    if vault.is_signed() {
+        vault.unlock_signing(password)?;
+    }
+    vault.add_entry(file_name)?;
+    save_synced(&mut vault, vault_dir, device_id)
+}
+
+pub fn detect_conflicts(dir: &Path) -> Result<ConflictReport, Box<dyn std::error::Error>> {
+    let current = read_manifest(&dir.join(MANIFEST_FILE_NAME))?.versions;
+    let mut report = ConflictReport::default();
+    for name in conflict_copies(dir)? {
+        // A copy that cannot be parsed may hold edits, so it is never
+        // treated as stale.
+        match read_manifest(&dir.join(&name)) {
+            Ok(copy) if descends_from(&current, &copy.versions) => report.stale.push(name),
+            _ => report.conflicts.push(name),
+        }
+    }
+    Ok(report)
+}
+
+// Merges every conflicting copy into the manifest: entries are unioned, and
+// where two devices recorded the same file the record matching the file that
+// won the sync is kept, else the most recently keyed one.
+pub fn resolve_conflicts(dir: &Path, device_id: &str, password: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+    let _lock = VaultLock::acquire(dir, device_id)?;
+    let mut vault = Vault::load(dir)?;
+    if vault.is_signed() {
+        vault.unlock_signing(password)?;
+    }
+
+    let copies = conflict_copies(dir)?;
+    let mut candidates: BTreeMap<String, Vec<VaultEntry>> = BTreeMap::new();
+    let mut versions = vault.manifest().versions.clone();
+    for entry in vault.entries() {
+        candidates.entry(entry.file_name.clone()).or_default().push(entry.clone());
+    }
+    let mut merged = 0;
+    for name in &copies {
+        let Ok(copy) = read_manifest(&dir.join(name)) else {
+            continue;
+        };
+        if !descends_from(&versions, &copy.versions) {
+            merged += 1;
+        }
+        for (device, count) in copy.versions {
+            let slot = versions.entry(device).or_insert(0);
+            *slot = (*slot).max(count);
+        }
+        for entry in copy.entries {
+            candidates.entry(entry.file_name.clone()).or_default().push(entry);
+        }
+    }
+
+    let mut entries = Vec::with_capacity(candidates.len());
+    for (name, mut records) in candidates {
+        records.sort_by_key(|e| std::cmp::Reverse((e.keyed_at, e.verified_at)));
+        let path = vault.entry_path(&name);
+        let on_disk = if records.len() > 1 && path.is_file() {
+            records.iter().position(|e| e.matches(&path).unwrap_or(false))
+        } else {
+            None
+        };
+        entries.push(records.swap_remove(on_disk.unwrap_or(0)));
+    }
+
+    let manifest = vault.manifest_mut();
+    manifest.entries = entries;
+    manifest.versions = versions;
+    *manifest.versions.entry(device_id.to_string()).or_insert(0) += 1;
+    vault.sync()?;
+    vault.save()?;
+    for name in copies {
+        fs::remove_file(dir.join(name))?;
+    }
+    Ok(merged)
+}
+
+// Dropbox, iCloud and Syncthing all keep the stem and extension when they
+// write a conflicting copy, e.g. ".kyrie_vault (conflicted copy).json".
+fn conflict_copies(dir: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let (stem, extension) = MANIFEST_FILE_NAME.rsplit_once('.').ok_or("Invalid manifest name")?;
+    let mut names = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let name = dir_entry?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name != MANIFEST_FILE_NAME && name.starts_with(stem) && name.ends_with(&format!(".{}", extension)) {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn read_manifest(path: &Path) -> Result<VaultManifest, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Ok(VaultManifest::default());
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+fn modified_secs(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::{classify, ERR_VAULT_BUSY, ERR_VAULT_CONFLICT};
+
+    #[test]
+    fn test_concurrent_edits_are_detected_and_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.kyl"), b"a").unwrap();
+        let mut vault = Vault::open(dir.path()).unwrap();
+        save_synced(&mut vault, dir.path(), "phone").unwrap();
+
+        let lock = VaultLock::acquire(dir.path(), "laptop").unwrap();
+        let error = register_entry_synced(dir.path(), "a.kyl", "phone", b"").unwrap_err();
+        assert_eq!(classify(error.as_ref()), ERR_VAULT_BUSY);
+        drop(lock);
+        assert!(!dir.path().join(LOCK_FILE_NAME).exists());
+
+        // Both devices start from the same manifest; the laptop saves first.
+        let mut phone = Vault::open(dir.path()).unwrap();
+        fs::write(dir.path().join("b.kyl"), b"b").unwrap();
+        register_entry_synced(dir.path(), "b.kyl", "laptop", b"").unwrap();
+        fs::write(dir.path().join("c.kyl"), b"c").unwrap();
+        phone.add_entry("c.kyl").unwrap();
+        let error = save_synced(&mut phone, dir.path(), "phone").unwrap_err();
+        assert_eq!(classify(error.as_ref()), ERR_VAULT_CONFLICT);
+
+        // A sync service kept the phone's version as a conflicted copy.
+        let mut copy: VaultManifest = serde_json::from_slice(&serde_json::to_vec(phone.manifest()).unwrap()).unwrap();
+        copy.versions = BTreeMap::from([("phone".to_string(), 2)]);
+        let copy_name = ".kyrie_vault (phone's conflicted copy).json";
+        fs::write(dir.path().join(copy_name), serde_json::to_vec(&copy).unwrap()).unwrap();
+        assert_eq!(detect_conflicts(dir.path()).unwrap().conflicts, vec![copy_name.to_string()]);
+
+        assert_eq!(resolve_conflicts(dir.path(), "phone", b"").unwrap(), 1);
+        let report = detect_conflicts(dir.path()).unwrap();
+        assert!(report.conflicts.is_empty() && report.stale.is_empty());
+        let merged = Vault::load(dir.path()).unwrap();
+        let names: Vec<&str> = merged.entries().iter().map(|e| e.file_name.as_str()).collect();
+        assert_eq!(names, ["a.kyl", "b.kyl", "c.kyl"]);
+        assert_eq!(merged.manifest().versions["phone"], 3);
+        assert_eq!(merged.manifest().versions["laptop"], 1);
+    }
+}
@@ -0,0 +1,278 @@
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::error::Error;
+use std::ffi::CStr;
+use std::io;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use crate::errors;
+use crate::name_vault::{self, NameEntry};
+use crate::session::KyrieSession;
+
+// One open vault: its own session (password, derived keys, decrypt cache)
+// and its own worker pool, so two vaults never share secrets or threads.
+pub struct KyrieVaultHandle {
+    dir: PathBuf,
+    session: KyrieSession,
+    pool: ThreadPool,
+    thread_budget: usize,
+}
+
+impl KyrieVaultHandle {
+    pub fn open(dir: &Path, password: &[u8], thread_budget: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        if !dir.is_dir() {
+            return Err("Vault directory not found".into());
+        }
+        let thread_budget = thread_budget.max(1);
+        let pool = ThreadPoolBuilder::new().num_threads(thread_budget).build()?;
+        let handle = KyrieVaultHandle {
+            dir: dir.to_path_buf(),
+            session: KyrieSession::new(password),
+            pool,
+            thread_budget,
+        };
+        // Fails on a wrong password before any file is touched.
+        handle.list()?;
+        Ok(handle)
+    }
+
+    pub fn lock(&self) {
+        self.session.lock();
+    }
+
+    pub fn session(&self) -> &KyrieSession {
+        &self.session
+    }
+
+    pub fn add(&self, input_path: &Path, is_mobile: bool) -> Result<String, Box<dyn std::error::Error>> {
+        let password = self.session.password()?;
+        self.run(|| {
+            let stored = name_vault::add_file(&self.dir, input_path, &password, is_mobile, self.thread_budget)?;
+            self.session.invalidate_cache(Some(&self.dir.join(&stored)));
+            Ok(stored)
+        })
+    }
+
+    pub fn extract(&self, name: &str, output_path: &str, is_mobile: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let password = self.session.password()?;
+        self.run(|| name_vault::extract_file(&self.dir, name, output_path, &password, is_mobile, self.thread_budget))
+    }
+
+    pub fn read(&self, name: &str, is_mobile: bool) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let password = self.session.password()?;
+        let path = name_vault::stored_path(&self.dir, name, &password)?;
+        let path = path.to_str().ok_or("Invalid vault path")?;
+        self.run(|| self.session.decrypt_to_memory(path, is_mobile, self.thread_budget))
+    }
+
+    // Runs on this vault's pool so its rayon work stays within the budget.
+    // Errors cross the pool boundary as io errors or plain messages, which
+    // keeps them classifiable.
+    fn run<T: Send>(&self, operation: impl FnOnce() -> Result<T, Box<dyn Error>> + Send) -> Result<T, Box<dyn Error>> {
+        self.pool
+            .install(|| {
+                operation().map_err(|e| -> Box<dyn Error + Send + Sync> {
+                    match e.downcast::<io::Error>() {
+                        Ok(io_error) => io_error,
+                        Err(other) => other.to_string().into(),
+                    }
+                })
+            })
+            .map_err(|e| e as Box<dyn Error>)
+    }
+
+    pub fn list(&self) -> Result<Vec<NameEntry>, Box<dyn std::error::Error>> {
+        let password = self.session.password()?;
+        name_vault::list_entries(&self.dir, &password)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vaults_open(
+    vault_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    thread_budget: usize,
+) -> *mut KyrieVaultHandle {
+    if vault_dir_ptr.is_null() || password_ptr.is_null() {
+        errors::invalid_argument();
+        return std::ptr::null_mut();
+    }
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => {
+                errors::invalid_argument();
+                return std::ptr::null_mut();
+            }
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        match KyrieVaultHandle::open(Path::new(vault_dir), password, thread_budget) {
+            Ok(handle) => Box::into_raw(Box::new(handle)),
+            Err(e) => {
+                errors::fail(e.as_ref());
+                std::ptr::null_mut()
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vaults_close(handle: *mut KyrieVaultHandle) {
+    if handle.is_null() {
+        return;
+    }
+    let handle = unsafe { Box::from_raw(handle) };
+    handle.lock();
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vaults_lock(handle: *mut KyrieVaultHandle) -> i32 {
+    if handle.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe { &*handle }.lock();
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vaults_enable_cache(handle: *mut KyrieVaultHandle, capacity_bytes: u64) -> i32 {
+    if handle.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe { &*handle }.session().enable_cache(capacity_bytes as usize);
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vaults_add(handle: *mut KyrieVaultHandle, input_path_ptr: *const c_char, is_mobile: bool) -> i32 {
+    if handle.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        match (*handle).add(Path::new(input_path), is_mobile) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vaults_extract(
+    handle: *mut KyrieVaultHandle,
+    name_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+    is_mobile: bool,
+) -> i32 {
+    if handle.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let name = match CStr::from_ptr(name_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let output_path = match CStr::from_ptr(output_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        match (*handle).extract(name, output_path, is_mobile) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vaults_read(
+    handle: *mut KyrieVaultHandle,
+    name_ptr: *const c_char,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+    is_mobile: bool,
+) -> i32 {
+    if handle.is_null() || output_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        let name = match CStr::from_ptr(name_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        match (*handle).read(name, is_mobile) {
+            Ok(data) => {
+                *output_len = data.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), output_ptr, data.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_vaults_list(handle: *mut KyrieVaultHandle, json_ptr: *mut u8, json_len: *mut usize) -> i32 {
+    if handle.is_null() || json_len.is_null() {
+        return errors::invalid_argument();
+    }
+    unsafe {
+        match (*handle).list().and_then(|entries| Ok(serde_json::to_vec(&entries)?)) {
+            Ok(json) => {
+                *json_len = json.len();
+                if !json_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(json.as_ptr(), json_ptr, json.len());
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_vaults_are_isolated() {
+        let dir = tempfile::tempdir().unwrap();
+        let work_dir = dir.path().join("work");
+        let personal_dir = dir.path().join("personal");
+        fs::create_dir(&work_dir).unwrap();
+        fs::create_dir(&personal_dir).unwrap();
+        let report = dir.path().join("report.txt");
+        let diary = dir.path().join("diary.txt");
+        fs::write(&report, b"q3 numbers").unwrap();
+        fs::write(&diary, b"dear diary").unwrap();
+
+        let work = KyrieVaultHandle::open(&work_dir, b"work pw", 2).unwrap();
+        let personal = KyrieVaultHandle::open(&personal_dir, b"home pw", 1).unwrap();
+        work.session().enable_cache(1 << 20);
+        work.add(&report, false).unwrap();
+        personal.add(&diary, false).unwrap();
+
+        assert_eq!(work.read("report.txt", false).unwrap(), b"q3 numbers");
+        assert_eq!(work.read("report.txt", false).unwrap(), b"q3 numbers");
+        assert_eq!(work.session().cache_stats().hits, 1);
+        assert!(work.read("diary.txt", false).is_err());
+        assert_eq!(personal.list().unwrap()[0].name, "diary.txt");
+
+        work.lock();
+        assert!(work.read("report.txt", false).is_err());
+        assert_eq!(work.session().cache_stats().entries, 0);
+        assert_eq!(personal.read("diary.txt", false).unwrap(), b"dear diary");
+
+        fs::write(&report, b"reopened").unwrap();
+        assert!(KyrieVaultHandle::open(&work_dir, b"home pw", 2).is_err());
+        let work = KyrieVaultHandle::open(&work_dir, b"work pw", 2).unwrap();
+        work.add(&report, false).unwrap();
+        assert_eq!(work.read("report.txt", false).unwrap(), b"reopened");
+    }
+}
@@ -0,0 +1,437 @@
+use aes_gcm::{aead::Aead, Nonce};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::audit;
+use crate::cipher::BodyCipher;
+use crate::errors::{self, ERR_CORRUPTED_CHUNK, ERR_NONE, ERR_WRONG_PASSWORD};
+use crate::format::{read_header, read_layout, ChunkLayout, ContainerHeader};
+use crate::manifest::ManifestBuilder;
+use crate::padding::PaddingStripper;
+
+// Confidence is reported as the probability that sampling would have caught
+// damage affecting this fraction of the file's chunks.
+const CONFIDENCE_CORRUPTION_RATE: f64 = 0.01;
+const SCAN_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyOutcome {
+    Ok,
+    WrongPassword,
+    CorruptedAtChunk(u64),
+}
+
+#[derive(Default)]
+pub struct SampledVerifyReport {
+    pub total_chunks: u64,
+    pub checked_chunks: u64,
+    pub failed_chunks: Vec<u64>,
+    pub escalated: bool,
+    pub confidence: f64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieSampledVerifyReport {
+    pub total_chunks: u64,
+    pub checked_chunks: u64,
+    pub failed_chunks: u64,
+    pub escalated: bool,
+    pub confidence: f64,
+}
+
+#[derive(Default, Debug)]
+pub struct CiphertextScanReport {
+    pub total_chunks: u64,
+    pub indexed: bool,
+    pub corrupt_chunks: Vec<u64>,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieCiphertextScanReport {
+    pub total_chunks: u64,
+    pub indexed: bool,
+    pub corrupt_chunks: u64,
+}
+
+// Returns 0 for an intact file. A corrupted chunk fails with
+// ERR_CORRUPTED_CHUNK and its index in chunk_index.
+#[no_mangle]
+pub extern "C" fn verify_file(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    chunk_index: *mut u64,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match verify_file_internal(Path::new(input_path), password) {
+            Ok(VerifyOutcome::Ok) => 0,
+            Ok(VerifyOutcome::WrongPassword) => errors::fail_with(ERR_WRONG_PASSWORD),
+            Ok(VerifyOutcome::CorruptedAtChunk(index)) => {
+                if !chunk_index.is_null() {
+                    *chunk_index = index;
+                }
+                errors::fail_with(ERR_CORRUPTED_CHUNK)
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn verify_file_sampled(
+    input_path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    coverage_percent: f64,
+    seed: u64,
+    report_ptr: *mut KyrieSampledVerifyReport,
+) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match verify_file_sampled_internal(Path::new(input_path), password, coverage_percent, seed) {
+            Ok(report) => {
+                if !report_ptr.is_null() {
+                    *report_ptr = KyrieSampledVerifyReport {
+                        total_chunks: report.total_chunks,
+                        checked_chunks: report.checked_chunks,
+                        failed_chunks: report.failed_chunks.len() as u64,
+                        escalated: report.escalated,
+                        confidence: report.confidence,
+                    };
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn scan_ciphertext(input_path_ptr: *const c_char, report_ptr: *mut KyrieCiphertextScanReport) -> i32 {
+    unsafe {
+        let input_path = match CStr::from_ptr(input_path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return errors::invalid_argument(),
+        };
+
+        match scan_ciphertext_internal(Path::new(input_path)) {
+            Ok(report) => {
+                if !report_ptr.is_null() {
+                    *report_ptr = KyrieCiphertextScanReport {
+                        total_chunks: report.total_chunks,
+                        indexed: report.indexed,
+                        corrupt_chunks: report.corrupt_chunks.len() as u64,
+                    };
+                }
+                0
+            }
+            Err(e) => errors::fail(e.as_ref()),
+        }
+    }
+}
+
+// Compares every stored frame against the CRC32 index in the header. This
+// needs no key, so it only catches accidental damage, not tampering.
+pub fn scan_ciphertext_internal(input_path: &Path) -> Result<CiphertextScanReport, Box<dyn std::error::Error>> {
+    let (header, _) = read_header(input_path)?;
+    let layout = read_layout(input_path)?;
+    let checksums = &header.chunk_checksums;
+    let mut report = CiphertextScanReport {
+        total_chunks: layout.frames.len() as u64,
+        indexed: !checksums.is_empty(),
+        ..Default::default()
+    };
+    if !report.indexed {
+        return Ok(report);
+    }
+    // A damaged length field changes how the body splits into frames, in
+    // which case none of the frames can be trusted.
+    if checksums.len() != layout.frames.len() {
+        report.total_chunks = checksums.len() as u64;
+        report.corrupt_chunks = (0..checksums.len() as u64).collect();
+        return Ok(report);
+    }
+
+    let mut reader = BufReader::new(File::open(input_path)?);
+    let mut buffer = vec![0u8; SCAN_BUFFER_SIZE];
+    for (index, (frame, expected)) in layout.frames.iter().zip(checksums).enumerate() {
+        reader.seek(SeekFrom::Start(frame.offset))?;
+        let mut checksum = crc32fast::Hasher::new();
+        let mut remaining = layout.frame_size(frame);
+        while remaining > 0 {
+            let n = remaining.min(buffer.len() as u64) as usize;
+            reader.read_exact(&mut buffer[..n])?;
+            checksum.update(&buffer[..n]);
+            remaining -= n as u64;
+        }
+        if checksum.finalize() != *expected {
+            report.corrupt_chunks.push(index as u64);
+        }
+    }
+    Ok(report)
+}
+
+// Opens every chunk and checks the header tag and manifest, dropping the
+// plaintext as it goes so none of it reaches the disk.
+pub fn verify_file_internal(input_path: &Path, password: &[u8]) -> Result<VerifyOutcome, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let result = verify_file_unlogged(input_path, password);
+    let code = match &result {
+        Ok(VerifyOutcome::Ok) => ERR_NONE,
+        Ok(VerifyOutcome::WrongPassword) => ERR_WRONG_PASSWORD,
+        Ok(VerifyOutcome::CorruptedAtChunk(_)) => ERR_CORRUPTED_CHUNK,
+        Err(e) => errors::classify(e.as_ref()),
+    };
+    audit::record_code("verify", input_path, started, code);
+    result
+}
+
+fn verify_file_unlogged(input_path: &Path, password: &[u8]) -> Result<VerifyOutcome, Box<dyn std::error::Error>> {
+    let layout = read_layout(input_path)?;
+    let (header, _) = ContainerHeader::read(&mut layout.header.as_slice())?;
+    let mut reader = BufReader::new(File::open(input_path)?);
+    let unlocked = layout.master_key(password).and_then(|key| {
+        let layout = layout.unlock(&mut reader, &key)?;
+        Ok((layout.body_cipher(&key)?, layout))
+    });
+    let (cipher, layout) = match unlocked {
+        Ok(unlocked) => unlocked,
+        Err(e) if errors::classify(e.as_ref()) == ERR_WRONG_PASSWORD => return Ok(VerifyOutcome::WrongPassword),
+        Err(e) => return Err(e),
+    };
+
+    let mut manifest = ManifestBuilder::default();
+    let mut stripper = PaddingStripper::new(layout.padding);
+    for (index, frame) in layout.frames.iter().enumerate() {
+        let opened = layout.read_frame(&mut reader, frame).ok().and_then(|(nonce, ciphertext)| {
+            manifest.record_frame(&ciphertext);
+            cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref()).ok()
+        });
+        match opened {
+            Some(plaintext) => stripper.feed(&plaintext, |content| {
+                manifest.record_plaintext(content.len());
+                Ok(())
+            })?,
+            // Without a header tag, nothing has shown the key to be right
+            // before the first chunk.
+            None if index == 0 && header.file_id.is_none() => return Ok(VerifyOutcome::WrongPassword),
+            None => return Ok(VerifyOutcome::CorruptedAtChunk(index as u64)),
+        }
+    }
+    stripper.finish()?;
+    manifest.check(header.manifest.as_ref())?;
+    Ok(VerifyOutcome::Ok)
+}
+
+pub fn verify_file_sampled_internal(
+    input_path: &Path,
+    password: &[u8],
+    coverage_percent: f64,
+    seed: u64,
+) -> Result<SampledVerifyReport, Box<dyn std::error::Error>> {
+    let layout = read_layout(input_path)?;
+    let key = layout.master_key(password)?;
+    let mut reader = BufReader::new(File::open(input_path)?);
+    let layout = layout.unlock(&mut reader, &key)?;
+    let cipher = layout.body_cipher(&key)?;
+
+    let total = layout.frames.len();
+    let coverage = coverage_percent.clamp(0.0, 100.0) / 100.0;
+    let sample_size = ((total as f64 * coverage).ceil() as usize).clamp(1, total);
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut sample = rand::seq::index::sample(&mut rng, total, sample_size).into_vec();
+    sample.sort_unstable();
+
+    let mut report = SampledVerifyReport {
+        total_chunks: total as u64,
+        checked_chunks: sample.len() as u64,
+        confidence: detection_confidence(total, sample.len()),
+        ..Default::default()
+    };
+    report.failed_chunks = check_chunks(&layout, &cipher, &mut reader, &sample);
+
+    if !report.failed_chunks.is_empty() && sample.len() < total {
+        let all: Vec<usize> = (0..total).collect();
+        report.failed_chunks = check_chunks(&layout, &cipher, &mut reader, &all);
+        report.checked_chunks = total as u64;
+        report.escalated = true;
+        report.confidence = 1.0;
+    }
+
+    Ok(report)
+}
+
+fn check_chunks(
+    layout: &ChunkLayout,
+    cipher: &BodyCipher,
+    reader: &mut BufReader<File>,
+    indices: &[usize],
+) -> Vec<u64> {
+    indices
+        .iter()
+        .filter(|&&index| {
+            let frame = &layout.frames[index];
+            match layout.read_frame(reader, frame) {
+                Ok((nonce, ciphertext)) => cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref()).is_err(),
+                Err(_) => true,
+            }
+        })
+        .map(|&index| index as u64)
+        .collect()
+}
+
+fn detection_confidence(total: usize, sampled: usize) -> f64 {
+    if sampled >= total {
+        return 1.0;
+    }
+    let damaged = ((total as f64 * CONFIDENCE_CORRUPTION_RATE).ceil() as usize).max(1);
+    let mut miss_probability = 1.0;
+    for i in 0..sampled {
+        if total - i <= damaged {
+            return 1.0;
+        }
+        miss_probability *= (total - damaged - i) as f64 / (total - i) as f64;
+    }
+    1.0 - miss_probability
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+    use crate::format::ContainerHeader;
+    use crate::test_util::write_framed_container;
+    use std::fs;
+
+    #[test]
+    fn test_verify_reports_password_and_first_damaged_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        let path = dir.path().join("plain.kyl");
+        fs::write(&plain, b"backed up").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), path.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        assert_eq!(verify_file_internal(&path, b"pw").unwrap(), VerifyOutcome::Ok);
+        assert_eq!(verify_file_internal(&path, b"wrong").unwrap(), VerifyOutcome::WrongPassword);
+
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(verify_file_internal(&path, b"pw").unwrap(), VerifyOutcome::CorruptedAtChunk(0));
+
+        let framed = dir.path().join("framed.kyl");
+        let chunks: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 8]).collect();
+        let chunk_refs: Vec<&[u8]> = chunks.iter().map(|c| c.as_slice()).collect();
+        write_framed_container(&framed, b"pw", &chunk_refs);
+        let layout = read_layout(&framed).unwrap();
+        let mut bytes = fs::read(&framed).unwrap();
+        bytes[(layout.frames[2].offset + layout.frame_size(&layout.frames[2]) - 1) as usize] ^= 0xFF;
+        fs::write(&framed, bytes).unwrap();
+        assert_eq!(verify_file_internal(&framed, b"pw").unwrap(), VerifyOutcome::CorruptedAtChunk(2));
+    }
+
+    #[test]
+    fn test_sampled_verification_is_reproducible() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.kyl");
+        let chunks: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 8]).collect();
+        let chunk_refs: Vec<&[u8]> = chunks.iter().map(|c| c.as_slice()).collect();
+        write_framed_container(&path, b"pw", &chunk_refs);
+
+        let report = verify_file_sampled_internal(&path, b"pw", 25.0, 7).unwrap();
+        assert_eq!(report.total_chunks, 20);
+        assert_eq!(report.checked_chunks, 5);
+        assert!(report.failed_chunks.is_empty() && !report.escalated);
+        assert!(report.confidence > 0.0 && report.confidence < 1.0);
+
+        let full = verify_file_sampled_internal(&path, b"pw", 100.0, 7).unwrap();
+        assert_eq!(full.checked_chunks, 20);
+        assert_eq!(full.confidence, 1.0);
+    }
+
+    #[test]
+    fn test_sampled_verification_escalates_on_damage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.kyl");
+        let chunks: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 8]).collect();
+        let chunk_refs: Vec<&[u8]> = chunks.iter().map(|c| c.as_slice()).collect();
+        write_framed_container(&path, b"pw", &chunk_refs);
+
+        let layout = read_layout(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        for index in [2usize, 6] {
+            let last_byte = layout.frames[index].offset + layout.frame_size(&layout.frames[index]) - 1;
+            bytes[last_byte as usize] ^= 0xFF;
+        }
+        fs::write(&path, bytes).unwrap();
+
+        let report = verify_file_sampled_internal(&path, b"pw", 100.0, 1).unwrap();
+        assert_eq!(report.failed_chunks, vec![2, 6]);
+
+        let sampled = (0..50)
+            .map(|seed| verify_file_sampled_internal(&path, b"pw", 30.0, seed).unwrap())
+            .find(|r| r.escalated)
+            .unwrap();
+        assert_eq!(sampled.checked_chunks, 10);
+        assert_eq!(sampled.failed_chunks, vec![2, 6]);
+    }
+
+    #[test]
+    fn test_ciphertext_scan_finds_damaged_chunks_without_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("legacy.kyl");
+        let path = dir.path().join("archive.kyl");
+        let chunks: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 8]).collect();
+        let chunk_refs: Vec<&[u8]> = chunks.iter().map(|c| c.as_slice()).collect();
+        write_framed_container(&legacy, b"pw", &chunk_refs);
+        assert!(!scan_ciphertext_internal(&legacy).unwrap().indexed);
+
+        let layout = read_layout(&legacy).unwrap();
+        let data = fs::read(&legacy).unwrap();
+        let (mut header, header_len) = ContainerHeader::read(&mut data.as_slice()).unwrap();
+        header.chunk_checksums = layout
+            .frames
+            .iter()
+            .map(|f| crc32fast::hash(&data[f.offset as usize..(f.offset + layout.frame_size(f)) as usize]))
+            .collect();
+        let mut indexed = header.encode();
+        indexed.extend_from_slice(&data[header_len as usize..]);
+        fs::write(&path, &indexed).unwrap();
+        let report = scan_ciphertext_internal(&path).unwrap();
+        assert_eq!((report.total_chunks, report.indexed), (4, true));
+        assert!(report.corrupt_chunks.is_empty());
+
+        let layout = read_layout(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[layout.frames[1].offset as usize] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(scan_ciphertext_internal(&path).unwrap().corrupt_chunks, vec![1]);
+
+        let small = dir.path().join("small.kyl");
+        let plain = dir.path().join("plain.txt");
+        fs::write(&plain, b"bit rot").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), small.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        let report = scan_ciphertext_internal(&small).unwrap();
+        assert_eq!((report.total_chunks, report.corrupt_chunks.len()), (1, 0));
+    }
+}
@@ -0,0 +1,389 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::kdf::{self, KdfParams, KDF_SALT_SIZE};
+use crate::rng;
+use crate::{derive_key, random_nonce, NONCE_SIZE, TAG_SIZE};
+
+// Both header slots always hold SLOT_SIZE bytes of ciphertext or random fill,
+// so a container without a hidden volume looks exactly like one with it.
+const SLOT_SIZE: usize = 16 * 1024;
+const OUTER_SLOT: u64 = 0;
+const HIDDEN_SLOT: u64 = SLOT_SIZE as u64;
+const HEADER_AREA: u64 = 2 * SLOT_SIZE as u64;
+const FILL_BLOCK: usize = 1024 * 1024;
+// A slot starts with its salt, which looks like the random fill around it.
+// The Argon2id costs cannot be stored next to it without marking the slot
+// as used, so every volume takes these. Volumes from before the salt was
+// stored have no salt and use the legacy key.
+const VOLUME_KDF: KdfParams = KdfParams::Argon2id { memory_kib: 64 * 1024, iterations: 3, lanes: 4 };
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct VolumeEntry {
+    name: String,
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct VolumeHeader {
+    data_start: u64,
+    capacity: u64,
+    entries: Vec<VolumeEntry>,
+}
+
+impl VolumeHeader {
+    fn used_end(&self) -> u64 {
+        self.entries.iter().map(|e| e.offset + e.len).max().unwrap_or(self.data_start)
+    }
+}
+
+struct SlotKey {
+    salt: Option<[u8; KDF_SALT_SIZE]>,
+    cipher: Aes256Gcm,
+}
+
+struct OpenVolume {
+    slot: u64,
+    key: SlotKey,
+    header: VolumeHeader,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_volume_create(
+    path_ptr: *const c_char,
+    size: u64,
+    password_ptr: *const u8,
+    password_len: usize,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match create_volume(Path::new(path), size, password) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_volume_create_hidden(
+    path_ptr: *const c_char,
+    outer_password_ptr: *const u8,
+    outer_password_len: usize,
+    hidden_password_ptr: *const u8,
+    hidden_password_len: usize,
+    hidden_size: u64,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let outer_password = std::slice::from_raw_parts(outer_password_ptr, outer_password_len);
+        let hidden_password = std::slice::from_raw_parts(hidden_password_ptr, hidden_password_len);
+
+        match create_hidden_volume(Path::new(path), outer_password, hidden_password, hidden_size) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_volume_put(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    protect_password_ptr: *const u8,
+    protect_password_len: usize,
+    name_ptr: *const c_char,
+    data_ptr: *const u8,
+    data_len: usize,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let name = match CStr::from_ptr(name_ptr).to_str() {
+            Ok(s) if !s.is_empty() => s,
+            _ => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let protect_password = if protect_password_ptr.is_null() {
+            None
+        } else {
+            Some(std::slice::from_raw_parts(protect_password_ptr, protect_password_len))
+        };
+        let data = std::slice::from_raw_parts(data_ptr, data_len);
+
+        match volume_put(Path::new(path), password, protect_password, name, data) {
+            Ok(_) => 0,
+            Err(_) => -2,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_volume_get(
+    path_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    name_ptr: *const c_char,
+    output_ptr: *mut u8,
+    output_len: *mut usize,
+) -> i32 {
+    unsafe {
+        let path = match CStr::from_ptr(path_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let name = match CStr::from_ptr(name_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return -1,
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match volume_get(Path::new(path), password, name) {
+            Ok(data) => {
+                *output_len = data.len();
+                if !output_ptr.is_null() {
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), output_ptr, data.len());
+                }
+                0
+            }
+            Err(_) => -2,
+        }
+    }
+}
+
+pub fn create_volume(path: &Path, size: u64, password: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    if size <= HEADER_AREA {
+        return Err("Volume is too small".into());
+    }
+    let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(path)?;
+
+    let mut block = vec![0u8; FILL_BLOCK];
+    let mut remaining = size;
+    while remaining > 0 {
+        let n = remaining.min(FILL_BLOCK as u64) as usize;
+        rand::thread_rng().fill_bytes(&mut block[..n]);
+        file.write_all(&block[..n])?;
+        remaining -= n as u64;
+    }
+
+    let header = VolumeHeader {
+        data_start: HEADER_AREA,
+        capacity: size - HEADER_AREA,
+        entries: Vec::new(),
+    };
+    write_slot(&mut file, OUTER_SLOT, &SlotKey::new(password)?, &header)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+pub fn create_hidden_volume(
+    path: &Path,
+    outer_password: &[u8],
+    hidden_password: &[u8],
+    hidden_size: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if outer_password == hidden_password {
+        return Err("Hidden volume password must differ from the outer password".into());
+    }
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let (_, outer) = read_slot(&mut file, OUTER_SLOT, outer_password)?.ok_or("Invalid password")?;
+
+    let volume_end = outer.data_start + outer.capacity;
+    let hidden_start = volume_end.checked_sub(hidden_size).filter(|&s| s >= outer.used_end() && hidden_size > 0);
+    let hidden_start = hidden_start.ok_or("Hidden volume does not fit in the outer volume's free space")?;
+
+    let header = VolumeHeader {
+        data_start: hidden_start,
+        capacity: hidden_size,
+        entries: Vec::new(),
+    };
+    write_slot(&mut file, HIDDEN_SLOT, &SlotKey::new(hidden_password)?, &header)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+pub fn volume_put(
+    path: &Path,
+    password: &[u8],
+    protect_password: Option<&[u8]>,
+    name: &str,
+    data: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut volume = open_volume(&mut file, password)?;
+
+    let protected_start = match protect_password {
+        Some(hidden_password) if volume.slot == OUTER_SLOT => {
+            let (_, hidden) = read_slot(&mut file, HIDDEN_SLOT, hidden_password)?.ok_or("Invalid hidden volume password")?;
+            Some(hidden.data_start)
+        }
+        _ => None,
+    };
+
+    volume.header.entries.retain(|e| e.name != name);
+    let offset = volume.header.used_end();
+    let len = (NONCE_SIZE + data.len() + TAG_SIZE) as u64;
+    if offset + len > volume.header.data_start + volume.header.capacity {
+        return Err("Volume is full".into());
+    }
+    if protected_start.is_some_and(|start| offset + len > start) {
+        return Err("Write would overwrite the hidden volume".into());
+    }
+
+    let nonce_bytes = random_nonce();
+    let encrypted = volume
+        .key
+        .cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), data)
+        .map_err(|_| "Encryption failed")?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&nonce_bytes)?;
+    file.write_all(&encrypted)?;
+
+    volume.header.entries.push(VolumeEntry {
+        name: name.to_string(),
+        offset,
+        len,
+    });
+    write_slot(&mut file, volume.slot, &volume.key, &volume.header)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+pub fn volume_get(path: &Path, password: &[u8], name: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let volume = open_volume(&mut file, password)?;
+    let entry = volume.header.entries.iter().find(|e| e.name == name).ok_or("Entry not found")?;
+
+    let mut sealed = vec![0u8; entry.len as usize];
+    file.seek(SeekFrom::Start(entry.offset))?;
+    file.read_exact(&mut sealed)?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    Ok(volume
+        .key
+        .cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Decryption failed")?)
+}
+
+impl SlotKey {
+    fn new(password: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::derive(password, Some(rng::random()))
+    }
+
+    fn derive(password: &[u8], salt: Option<[u8; KDF_SALT_SIZE]>) -> Result<Self, Box<dyn std::error::Error>> {
+        let key = Zeroizing::new(match &salt {
+            Some(salt) => kdf::derive(password, salt, &VOLUME_KDF)?,
+            None => derive_key(password),
+        });
+        Ok(SlotKey { salt, cipher: Aes256Gcm::new_from_slice(key.as_slice())? })
+    }
+
+    // Where the slot's nonce and ciphertext start.
+    fn sealed_start(&self) -> usize {
+        self.salt.map_or(0, |salt| salt.len())
+    }
+}
+
+fn open_volume(file: &mut File, password: &[u8]) -> Result<OpenVolume, Box<dyn std::error::Error>> {
+    for slot in [OUTER_SLOT, HIDDEN_SLOT] {
+        if let Some((key, header)) = read_slot(file, slot, password)? {
+            return Ok(OpenVolume { slot, key, header });
+        }
+    }
+    Err("Invalid password".into())
+}
+
+// Every slot has a salt of its own, so the key is derived per slot; the
+// legacy key is tried after it.
+fn read_slot(file: &mut File, slot: u64, password: &[u8]) -> Result<Option<(SlotKey, VolumeHeader)>, Box<dyn std::error::Error>> {
+    let mut sealed = vec![0u8; SLOT_SIZE];
+    file.seek(SeekFrom::Start(slot))?;
+    file.read_exact(&mut sealed)?;
+    let salt = sealed[..KDF_SALT_SIZE].try_into()?;
+    for key in [SlotKey::derive(password, Some(salt))?, SlotKey::derive(password, None)?] {
+        let (nonce, ciphertext) = sealed[key.sealed_start()..].split_at(NONCE_SIZE);
+        if let Ok(plaintext) = key.cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+            let json_len = u32::from_le_bytes(plaintext[..4].try_into()?) as usize;
+            let json = plaintext.get(4..4 + json_len).ok_or("Invalid volume header")?;
+            return Ok(Some((key, serde_json::from_slice(json)?)));
+        }
+    }
+    Ok(None)
+}
+
+fn write_slot(
+    file: &mut File,
+    slot: u64,
+    key: &SlotKey,
+    header: &VolumeHeader,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::to_vec(header)?;
+    let capacity = SLOT_SIZE - key.sealed_start() - NONCE_SIZE - TAG_SIZE;
+    if 4 + json.len() > capacity {
+        return Err("Volume index is full".into());
+    }
+    let mut plaintext = Vec::with_capacity(capacity);
+    plaintext.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    plaintext.extend_from_slice(&json);
+    plaintext.resize(capacity, 0);
+
+    let nonce_bytes = random_nonce();
+    let encrypted = key
+        .cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), plaintext.as_ref())
+        .map_err(|_| "Encryption failed")?;
+    file.seek(SeekFrom::Start(slot))?;
+    if let Some(salt) = &key.salt {
+        file.write_all(salt)?;
+    }
+    file.write_all(&nonce_bytes)?;
+    file.write_all(&encrypted)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hidden_volume_is_protected_from_outer_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("container.kyv");
+        create_volume(&path, HEADER_AREA + 4096, b"outer").unwrap();
+        create_hidden_volume(&path, b"outer", b"hidden", 2048).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), HEADER_AREA + 4096);
+
+        volume_put(&path, b"hidden", None, "diary.txt", b"the real diary").unwrap();
+        volume_put(&path, b"outer", Some(b"hidden"), "cover.txt", &[1u8; 1000]).unwrap();
+        let err = volume_put(&path, b"outer", Some(b"hidden"), "big.bin", &[2u8; 1500]).unwrap_err();
+        assert_eq!(err.to_string(), "Write would overwrite the hidden volume");
+        assert!(volume_put(&path, b"outer", Some(b"wrong"), "x", b"x").is_err());
+
+        assert_eq!(volume_get(&path, b"outer", "cover.txt").unwrap(), vec![1u8; 1000]);
+        assert_eq!(volume_get(&path, b"hidden", "diary.txt").unwrap(), b"the real diary");
+        assert!(volume_get(&path, b"outer", "diary.txt").is_err());
+        assert!(volume_get(&path, b"wrong", "cover.txt").is_err());
+    }
+}
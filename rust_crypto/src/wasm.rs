@@ -0,0 +1,309 @@
+// In-memory encryption and decryption for the web viewer, which has no file
+// system to write temp files to. Everything here works on byte buffers and
+// is exported to JavaScript through wasm-bindgen with the `wasm` feature.
+use aes_gcm::{aead::Aead, Nonce};
+use std::io::Cursor;
+use zeroize::Zeroizing;
+
+use crate::cipher::BodyCipher;
+use crate::format::{self, read_layout_from_bytes, ContainerHeader, FRAMING_SINGLE, MAX_HEADER_LEN, NONCE_SCHEME_COUNTER};
+use crate::manifest::ManifestBuilder;
+use crate::nonce::{self, NonceSequence};
+use crate::padding::PaddingStripper;
+use crate::pipeline::MAX_FRAME_LEN;
+use crate::sparse;
+use crate::{text, write_encrypted_frame, TAG_SIZE};
+
+// A browser tab holds the whole container and one opened frame at a time,
+// so frames are kept far smaller than the native pipeline's.
+const CHUNK_SIZE: usize = if cfg!(test) { 4096 } else { 4 * 1024 * 1024 };
+
+// Writes a regular container, readable by every other build.
+pub fn encrypt_bytes(data: &[u8], password: &[u8], hint: Option<&str>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let chunks: Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(CHUNK_SIZE).collect() };
+    let single_chunk = chunks.len() == 1;
+
+    let mut header = ContainerHeader {
+        hint: text::hint_bytes(hint),
+        nonce_scheme: NONCE_SCHEME_COUNTER,
+        ..Default::default()
+    };
+    header.use_password_kdf(true);
+    header.set_framing(single_chunk, CHUNK_SIZE, chunks.len());
+    let key = Zeroizing::new(header.new_master_key(password)?);
+    let cipher = header.body_cipher(&key)?;
+    // Sealing picks the file id, which the counter nonces are derived from.
+    header.seal_file_id(&key);
+    let prefix = nonce::counter_prefix(&header.body_key(&key), header.file_id.as_ref().ok_or("Invalid file format")?);
+
+    let mut body = Vec::with_capacity(data.len() + chunks.len() * (format::CHUNK_LEN_SIZE as usize + TAG_SIZE));
+    let mut checksums = Vec::with_capacity(chunks.len());
+    let mut manifest = ManifestBuilder::default();
+    manifest.record_plaintext(data.len());
+    for (index, chunk) in chunks.iter().enumerate() {
+        let nonce_bytes = nonce::counter_nonce(&prefix, index as u64)?;
+        let encrypted = cipher.encrypt(Nonce::from_slice(&nonce_bytes), *chunk).map_err(|_| "Encryption failed")?;
+        manifest.record_frame(&encrypted);
+        checksums.push(write_encrypted_frame(&mut body, &[], &encrypted, single_chunk)?);
+    }
+    if checksums.len() <= format::MAX_CHUNK_CHECKSUMS {
+        header.chunk_checksums = checksums;
+    }
+    header.manifest = Some(manifest.finish());
+    header.seal_file_id(&key);
+
+    let mut container = header.encode();
+    container.extend_from_slice(&body);
+    Ok(container)
+}
+
+// Takes any container the native build reads, dual and legacy ones included.
+pub fn decrypt_bytes(container: &[u8], password: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let layout = read_layout_from_bytes(container)?;
+    sparse::require_dense(&layout.holes)?;
+    let (header, _) = ContainerHeader::read(&mut layout.header.as_slice())?;
+    let mut reader = Cursor::new(container);
+    let key = Zeroizing::new(layout.master_key(password)?);
+    let layout = layout.unlock(&mut reader, &key)?;
+    let cipher = layout.body_cipher(&key)?;
+
+    let mut plaintext = Vec::new();
+    let mut manifest = ManifestBuilder::default();
+    let mut stripper = PaddingStripper::new(layout.padding);
+    for frame in &layout.frames {
+        let (nonce_bytes, ciphertext) = layout.read_frame(&mut reader, frame)?;
+        manifest.record_frame(&ciphertext);
+        let opened = Zeroizing::new(
+            cipher.decrypt(Nonce::from_slice(&nonce_bytes), ciphertext.as_ref()).map_err(|_| "Decryption failed")?,
+        );
+        stripper.feed(&opened, |content| {
+            manifest.record_plaintext(content.len());
+            plaintext.extend_from_slice(content);
+            Ok(())
+        })?;
+    }
+    stripper.finish()?;
+    manifest.check(header.manifest.as_ref())?;
+    Ok(plaintext)
+}
+
+// Opens a container as it arrives, for files too large to hold twice. The
+// total length is needed up front to find where the body ends. Only files
+// that record their framing can be read this way; older and dual ones go
+// through decrypt_bytes.
+pub struct ChunkDecryptor {
+    password: Zeroizing<Vec<u8>>,
+    total_len: u64,
+    buffer: Vec<u8>,
+    consumed: u64,
+    stream: Option<Stream>,
+}
+
+struct Stream {
+    header: ContainerHeader,
+    key: Zeroizing<[u8; 32]>,
+    cipher: BodyCipher,
+    nonce_seq: NonceSequence,
+    body_end: u64,
+    single_chunk: bool,
+    stripper: PaddingStripper,
+    manifest: ManifestBuilder,
+    verified: bool,
+}
+
+impl ChunkDecryptor {
+    pub fn new(password: &[u8], total_len: u64) -> Self {
+        ChunkDecryptor {
+            password: Zeroizing::new(password.to_vec()),
+            total_len,
+            buffer: Vec::new(),
+            consumed: 0,
+            stream: None,
+        }
+    }
+
+    // Takes the next bytes of the container and returns the plaintext of
+    // every frame they complete.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.buffer.extend_from_slice(bytes);
+        if self.stream.is_none() && !self.start()? {
+            return Ok(Vec::new());
+        }
+        let stream = self.stream.as_mut().expect("stream starts with the header");
+        let mut plaintext = Vec::new();
+        while let Some(used) = stream.open_next(&self.buffer, self.consumed, &mut plaintext)? {
+            self.buffer.drain(..used);
+            self.consumed += used as u64;
+        }
+        Ok(plaintext)
+    }
+
+    // Fails unless the whole body arrived and adds up to its manifest.
+    pub fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        let stream = self.stream.ok_or("Unexpected end of file")?;
+        if self.consumed != stream.body_end {
+            return Err("Unexpected end of file".into());
+        }
+        stream.stripper.finish()?;
+        stream.manifest.check(stream.header.manifest.as_ref())
+    }
+
+    // Returns false while the header is still incomplete.
+    fn start(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let (header, header_len) = match ContainerHeader::read(&mut self.buffer.as_slice()) {
+            Ok(parsed) => parsed,
+            Err(_) if self.buffer.len() < MAX_HEADER_LEN && (self.buffer.len() as u64) < self.total_len => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let single_chunk = match &header.framing {
+            Some(framing) if !header.dual() => framing.version == FRAMING_SINGLE,
+            _ => return Err("Container cannot be streamed".into()),
+        };
+        sparse::require_dense(&header.holes)?;
+        let key = Zeroizing::new(header.master_key(&self.password[..])?);
+        self.buffer.drain(..header_len as usize);
+        self.consumed = header_len;
+        self.stream = Some(Stream {
+            cipher: header.body_cipher(&key)?,
+            nonce_seq: NonceSequence::for_header(&header, &key)?,
+            body_end: header.body_end(header_len, self.total_len),
+            single_chunk,
+            stripper: PaddingStripper::new(header.padding),
+            manifest: ManifestBuilder::default(),
+            verified: false,
+            header,
+            key,
+        });
+        Ok(true)
+    }
+}
+
+impl Stream {
+    // Opens the frame at the front of the buffer once all of it is there,
+    // returning how many bytes it took.
+    fn open_next(&mut self, buffer: &[u8], consumed: u64, plaintext: &mut Vec<u8>) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        let remaining = self.body_end.saturating_sub(consumed);
+        let window = &buffer[..remaining.min(buffer.len() as u64) as usize];
+        let nonce_len = self.nonce_seq.stored_len();
+        let (prefix_len, frame_len) = if self.single_chunk {
+            if (window.len() as u64) < remaining || remaining == 0 {
+                return Ok(None);
+            }
+            (nonce_len, (remaining as usize).checked_sub(nonce_len).ok_or("Invalid file format")?)
+        } else {
+            let prefix_len = nonce_len + format::CHUNK_LEN_SIZE as usize;
+            let Some(len_bytes) = window.get(nonce_len..prefix_len) else {
+                return Ok(None);
+            };
+            let len = u32::from_be_bytes(len_bytes.try_into()?) as u64;
+            if len > MAX_FRAME_LEN || prefix_len as u64 + len > remaining {
+                return Err("Corrupted chunk".into());
+            }
+            if window.len() < prefix_len + len as usize {
+                return Ok(None);
+            }
+            (prefix_len, len as usize)
+        };
+
+        let nonce_bytes = self.nonce_seq.read_nonce(&mut &window[..nonce_len])?;
+        let ciphertext = &window[prefix_len..prefix_len + frame_len];
+        let opened = Zeroizing::new(
+            self.cipher.decrypt(Nonce::from_slice(&nonce_bytes), ciphertext).map_err(|_| "Decryption failed")?,
+        );
+        // Checked once the first frame has shown the password is right, and
+        // before any plaintext is handed out.
+        if !self.verified {
+            self.header.verify_file_id(&self.key)?;
+            self.verified = true;
+        }
+        self.manifest.record_frame(ciphertext);
+        self.stripper.feed(&opened, |content| {
+            self.manifest.record_plaintext(content.len());
+            plaintext.extend_from_slice(content);
+            Ok(())
+        })?;
+        Ok(Some(prefix_len + frame_len))
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod bindings {
+    use wasm_bindgen::prelude::*;
+
+    fn js_error(error: Box<dyn std::error::Error>) -> JsError {
+        JsError::new(&error.to_string())
+    }
+
+    #[wasm_bindgen]
+    pub fn encrypt_bytes(data: &[u8], password: &[u8], hint: Option<String>) -> Result<Vec<u8>, JsError> {
+        super::encrypt_bytes(data, password, hint.as_deref()).map_err(js_error)
+    }
+
+    #[wasm_bindgen]
+    pub fn decrypt_bytes(container: &[u8], password: &[u8]) -> Result<Vec<u8>, JsError> {
+        super::decrypt_bytes(container, password).map_err(js_error)
+    }
+
+    #[wasm_bindgen]
+    pub struct ChunkDecryptor(super::ChunkDecryptor);
+
+    #[wasm_bindgen]
+    impl ChunkDecryptor {
+        // File sizes arrive as JavaScript numbers rather than BigInts.
+        #[wasm_bindgen(constructor)]
+        pub fn new(password: &[u8], total_len: f64) -> ChunkDecryptor {
+            ChunkDecryptor(super::ChunkDecryptor::new(password, total_len as u64))
+        }
+
+        pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<u8>, JsError> {
+            self.0.push(bytes).map_err(js_error)
+        }
+
+        pub fn finish(self) -> Result<(), JsError> {
+            self.0.finish().map_err(js_error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_to_memory_internal, encrypt_file_internal};
+    use std::fs;
+
+    fn content() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i * 31) as u8).collect()
+    }
+
+    fn stream(container: &[u8], password: &[u8], piece: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut decryptor = ChunkDecryptor::new(password, container.len() as u64);
+        let mut plaintext = Vec::new();
+        for bytes in container.chunks(piece) {
+            plaintext.extend(decryptor.push(bytes)?);
+        }
+        decryptor.finish()?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_byte_containers_match_the_file_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = dir.path().join("photo.kyl");
+
+        let container = encrypt_bytes(&content(), b"pw", Some("h")).unwrap();
+        assert_eq!(decrypt_bytes(&container, b"pw").unwrap(), content());
+        assert!(decrypt_bytes(&container, b"wrong").is_err());
+        assert_eq!(stream(&container, b"pw", 1000).unwrap(), content());
+        assert!(stream(&container[..container.len() - 1], b"pw", 1000).is_err());
+        fs::write(&encrypted, &container).unwrap();
+        assert_eq!(decrypt_file_to_memory_internal(encrypted.to_str().unwrap(), b"pw", false, 4).unwrap(), content());
+
+        let plain = dir.path().join("photo.jpg");
+        fs::write(&plain, b"from the app").unwrap();
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+        let container = fs::read(&encrypted).unwrap();
+        assert_eq!(decrypt_bytes(&container, b"pw").unwrap(), b"from the app");
+        assert_eq!(stream(&container, b"pw", 7).unwrap(), b"from the app");
+        assert_eq!(decrypt_bytes(&encrypt_bytes(b"", b"pw", None).unwrap(), b"pw").unwrap(), b"");
+    }
+}
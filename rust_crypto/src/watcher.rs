@@ -0,0 +1,370 @@
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::ffi::{c_void, CStr, CString};
+use std::fs;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::naming::NamingPolicy;
+use crate::shred::{shred_file_with_options, ShredOptions, SHRED_VERIFY_FAILED};
+use crate::vault::register_entry;
+use crate::encrypt_file_internal;
+
+const SETTLE_DELAY: Duration = Duration::from_millis(1000);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub const WATCH_EVENT_DETECTED: i32 = 0;
+pub const WATCH_EVENT_ENCRYPTED: i32 = 1;
+pub const WATCH_EVENT_SHREDDED: i32 = 2;
+pub const WATCH_EVENT_FAILED: i32 = 3;
+
+pub type WatchEventCallback =
+    extern "C" fn(event: i32, path: *const c_char, user_data: *mut c_void);
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KyrieWatchStatus {
+    pub running: bool,
+    pub pending: u64,
+    pub encrypted: u64,
+    pub failed: u64,
+}
+
+pub struct KyrieWatcher {
+    watcher: Option<RecommendedWatcher>,
+    stop: Arc<AtomicBool>,
+    stats: Arc<WatchStats>,
+    worker: Option<JoinHandle<()>>,
+}
+
+struct WatchConfig {
+    vault_dir: PathBuf,
+    password: Vec<u8>,
+    hint: Option<String>,
+    shred_originals: bool,
+    verify_shred: bool,
+    is_mobile: bool,
+    cpu_cores: usize,
+}
+
+#[derive(Default)]
+struct WatchStats {
+    running: AtomicBool,
+    pending: AtomicU64,
+    encrypted: AtomicU64,
+    failed: AtomicU64,
+}
+
+struct EventSink {
+    callback: Option<WatchEventCallback>,
+    user_data: usize,
+}
+
+impl EventSink {
+    fn emit(&self, event: i32, path: &Path) {
+        if let Some(callback) = self.callback {
+            if let Ok(path) = CString::new(path.to_string_lossy().as_bytes()) {
+                callback(event, path.as_ptr(), self.user_data as *mut c_void);
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_watch_start(
+    watch_dir_ptr: *const c_char,
+    vault_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    shred_originals: bool,
+    verify_shred: bool,
+    is_mobile: bool,
+    cpu_cores: usize,
+    callback: Option<WatchEventCallback>,
+    user_data: *mut c_void,
+) -> *mut KyrieWatcher {
+    unsafe {
+        let watch_dir = match CStr::from_ptr(watch_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let config = WatchConfig {
+            vault_dir: PathBuf::from(vault_dir),
+            password: password.to_vec(),
+            hint: hint.map(|h| h.to_string()),
+            shred_originals,
+            verify_shred,
+            is_mobile,
+            cpu_cores,
+        };
+        let sink = EventSink {
+            callback,
+            user_data: user_data as usize,
+        };
+
+        match start_watcher(Path::new(watch_dir), config, sink) {
+            Ok(watcher) => Box::into_raw(Box::new(watcher)),
+            Err(_) => std::ptr::null_mut(),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_watch_stop(handle: *mut KyrieWatcher) -> i32 {
+    if handle.is_null() {
+        return -1;
+    }
+    let mut watcher = unsafe { Box::from_raw(handle) };
+    watcher.stop();
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_watch_status(
+    handle: *const KyrieWatcher,
+    status_ptr: *mut KyrieWatchStatus,
+) -> i32 {
+    if handle.is_null() || status_ptr.is_null() {
+        return -1;
+    }
+    unsafe {
+        *status_ptr = (*handle).status();
+    }
+    0
+}
+
+impl KyrieWatcher {
+    fn status(&self) -> KyrieWatchStatus {
+        KyrieWatchStatus {
+            running: self.stats.running.load(Ordering::SeqCst),
+            pending: self.stats.pending.load(Ordering::SeqCst),
+            encrypted: self.stats.encrypted.load(Ordering::SeqCst),
+            failed: self.stats.failed.load(Ordering::SeqCst),
+        }
+    }
+
+    fn stop(&mut self) {
+        self.watcher.take();
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        self.stats.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for KyrieWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn start_watcher(
+    watch_dir: &Path,
+    config: WatchConfig,
+    sink: EventSink,
+) -> Result<KyrieWatcher, Box<dyn std::error::Error>> {
+    fs::create_dir_all(&config.vault_dir)?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(watch_dir, RecursiveMode::NonRecursive)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stats = Arc::new(WatchStats::default());
+    stats.running.store(true, Ordering::SeqCst);
+
+    let worker = {
+        let stop = stop.clone();
+        let stats = stats.clone();
+        thread::spawn(move || run_worker(config, sink, rx, stop, stats))
+    };
+
+    Ok(KyrieWatcher {
+        watcher: Some(watcher),
+        stop,
+        stats,
+        worker: Some(worker),
+    })
+}
+
+fn run_worker(
+    config: WatchConfig,
+    sink: EventSink,
+    rx: Receiver<notify::Result<Event>>,
+    stop: Arc<AtomicBool>,
+    stats: Arc<WatchStats>,
+) {
+    let mut pending: HashMap<PathBuf, (u64, Instant)> = HashMap::new();
+
+    while !stop.load(Ordering::SeqCst) {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        if !is_candidate(&path) {
+                            continue;
+                        }
+                        let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                        if pending
+                            .insert(path.clone(), (size, Instant::now()))
+                            .is_none()
+                        {
+                            sink.emit(WATCH_EVENT_DETECTED, &path);
+                        }
+                    }
+                }
+            }
+            Ok(Err(_)) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, (_, seen))| seen.elapsed() >= SETTLE_DELAY)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in settled {
+            let size = match pending.remove(&path) {
+                Some((size, _)) => size,
+                None => continue,
+            };
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.len() == size => {
+                    if process_file(&path, &config, &sink) {
+                        stats.encrypted.fetch_add(1, Ordering::SeqCst);
+                    } else {
+                        stats.failed.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+                Ok(metadata) => {
+                    pending.insert(path, (metadata.len(), Instant::now()));
+                }
+                Err(_) => {}
+            }
+        }
+
+        stats.pending.store(pending.len() as u64, Ordering::SeqCst);
+    }
+
+    stats.running.store(false, Ordering::SeqCst);
+}
+
+fn process_file(path: &Path, config: &WatchConfig, sink: &EventSink) -> bool {
+    let output_path = match NamingPolicy::current().output_path(&config.vault_dir, path) {
+        Some(p) => p,
+        None => {
+            sink.emit(WATCH_EVENT_FAILED, path);
+            return false;
+        }
+    };
+
+    let encrypted = match (path.to_str(), output_path.to_str()) {
+        (Some(input), Some(output)) => encrypt_file_internal(
+            input,
+            output,
+            &config.password,
+            config.hint.as_deref(),
+            config.is_mobile,
+            config.cpu_cores,
+        )
+        .is_ok(),
+        _ => false,
+    };
+
+    if !encrypted {
+        let _ = fs::remove_file(&output_path);
+        sink.emit(WATCH_EVENT_FAILED, path);
+        return false;
+    }
+    let registered = output_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(|name| register_entry(&config.vault_dir, name, &config.password).is_ok())
+        .unwrap_or(false);
+    if !registered {
+        sink.emit(WATCH_EVENT_FAILED, &output_path);
+        return false;
+    }
+    sink.emit(WATCH_EVENT_ENCRYPTED, &output_path);
+
+    if config.shred_originals {
+        let options = ShredOptions {
+            verify: config.verify_shred,
+            ..Default::default()
+        };
+        match shred_file_with_options(path, &options) {
+            Ok(report) if report.verification != SHRED_VERIFY_FAILED => {}
+            _ => {
+                sink.emit(WATCH_EVENT_FAILED, path);
+                return false;
+            }
+        }
+        sink.emit(WATCH_EVENT_SHREDDED, path);
+    }
+    true
+}
+
+fn is_candidate(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n,
+        None => return false,
+    };
+    if name.starts_with('.') {
+        return false;
+    }
+    if NamingPolicy::current().is_encrypted_name(path) {
+        return false;
+    }
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_file_encrypts_and_shreds() {
+        let watch_dir = tempfile::tempdir().unwrap();
+        let vault_dir = tempfile::tempdir().unwrap();
+        let input = watch_dir.path().join("note.txt");
+        fs::write(&input, b"watched content").unwrap();
+        assert!(is_candidate(&input));
+
+        let config = WatchConfig {
+            vault_dir: vault_dir.path().to_path_buf(),
+            password: b"watch_password".to_vec(),
+            hint: None,
+            shred_originals: true,
+            verify_shred: true,
+            is_mobile: false,
+            cpu_cores: 4,
+        };
+        let sink = EventSink {
+            callback: None,
+            user_data: 0,
+        };
+
+        assert!(process_file(&input, &config, &sink));
+        assert!(!input.exists());
+        assert!(vault_dir.path().join("note.txt.kyl").exists());
+    }
+}
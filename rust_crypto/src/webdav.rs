@@ -0,0 +1,411 @@
+use std::ffi::CStr;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use crate::naming::NamingPolicy;
+use crate::range::DecryptingReader;
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+const ALLOWED_METHODS: &str = "OPTIONS, PROPFIND, GET, HEAD";
+
+pub struct KyrieWebDavServer {
+    port: u16,
+    stop: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+struct ServeConfig {
+    vault_dir: PathBuf,
+    password: Zeroizing<Vec<u8>>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    depth: Option<String>,
+    range: Option<String>,
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_webdav_start(
+    vault_dir_ptr: *const c_char,
+    password_ptr: *const u8,
+    password_len: usize,
+    port: u16,
+    bound_port_ptr: *mut u16,
+) -> *mut KyrieWebDavServer {
+    unsafe {
+        let vault_dir = match CStr::from_ptr(vault_dir_ptr).to_str() {
+            Ok(s) => s,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        let password = std::slice::from_raw_parts(password_ptr, password_len);
+
+        match start_server(Path::new(vault_dir), password, port) {
+            Ok(server) => {
+                if !bound_port_ptr.is_null() {
+                    *bound_port_ptr = server.port;
+                }
+                Box::into_raw(Box::new(server))
+            }
+            Err(_) => std::ptr::null_mut(),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kyrie_webdav_stop(handle: *mut KyrieWebDavServer) -> i32 {
+    if handle.is_null() {
+        return -1;
+    }
+    let mut server = unsafe { Box::from_raw(handle) };
+    server.stop();
+    0
+}
+
+impl KyrieWebDavServer {
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+impl Drop for KyrieWebDavServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub fn start_server(vault_dir: &Path, password: &[u8], port: u16) -> Result<KyrieWebDavServer, Box<dyn std::error::Error>> {
+    if !vault_dir.is_dir() {
+        return Err("Vault directory does not exist".into());
+    }
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    listener.set_nonblocking(true)?;
+    let port = listener.local_addr()?.port();
+
+    let config = Arc::new(ServeConfig {
+        vault_dir: vault_dir.to_path_buf(),
+        password: Zeroizing::new(password.to_vec()),
+    });
+    let stop = Arc::new(AtomicBool::new(false));
+    let acceptor = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let config = config.clone();
+                        thread::spawn(move || {
+                            let _ = handle_connection(stream, &config);
+                        });
+                    }
+                    Err(_) => thread::sleep(ACCEPT_POLL_INTERVAL),
+                }
+            }
+        })
+    };
+
+    Ok(KyrieWebDavServer {
+        port,
+        stop,
+        acceptor: Some(acceptor),
+    })
+}
+
+fn handle_connection(stream: TcpStream, config: &ServeConfig) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut writer = stream.try_clone()?;
+    let request = match read_request(&mut BufReader::new(stream))? {
+        Some(request) => request,
+        None => return respond(&mut writer, "400 Bad Request", &[], b""),
+    };
+
+    match request.method.as_str() {
+        "OPTIONS" => respond(&mut writer, "200 OK", &[("DAV", "1".into()), ("Allow", ALLOWED_METHODS.into())], b""),
+        "PROPFIND" => propfind(&mut writer, config, &request),
+        "GET" | "HEAD" => get(&mut writer, config, &request),
+        _ => respond(&mut writer, "405 Method Not Allowed", &[("Allow", ALLOWED_METHODS.into())], b""),
+    }
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut lines = Vec::new();
+    let mut total = 0;
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line)?;
+        total += n;
+        if n == 0 || total > MAX_HEADER_BYTES {
+            return Ok(None);
+        }
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut parts = lines.first().map(|l| l.split_whitespace()).into_iter().flatten();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Ok(None),
+    };
+    let path = match percent_decode(target.split('?').next().unwrap_or_default()) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let header = |name: &str| {
+        lines[1..].iter().find_map(|l| {
+            let (key, value) = l.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+        })
+    };
+    Ok(Some(Request {
+        method,
+        path,
+        depth: header("Depth"),
+        range: header("Range"),
+    }))
+}
+
+fn propfind<W: Write>(writer: &mut W, config: &ServeConfig, request: &Request) -> io::Result<()> {
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+    let name = request.path.trim_start_matches('/');
+
+    if name.is_empty() {
+        body.push_str("<D:response><D:href>/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype>");
+        body.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n");
+        if request.depth.as_deref() != Some("0") {
+            for (name, path) in list_entries(&config.vault_dir)? {
+                if let Ok(len) = plaintext_len(&path, &config.password) {
+                    push_file_response(&mut body, &name, len);
+                }
+            }
+        }
+    } else {
+        let path = match resolve_entry(&config.vault_dir, name)? {
+            Some(path) => path,
+            None => return respond(writer, "404 Not Found", &[], b""),
+        };
+        match plaintext_len(&path, &config.password) {
+            Ok(len) => push_file_response(&mut body, name, len),
+            Err(_) => return respond(writer, "403 Forbidden", &[], b""),
+        }
+    }
+    body.push_str("</D:multistatus>\n");
+
+    respond(
+        writer,
+        "207 Multi-Status",
+        &[("Content-Type", "application/xml; charset=utf-8".into())],
+        body.as_bytes(),
+    )
+}
+
+fn push_file_response(body: &mut String, name: &str, len: u64) {
+    body.push_str(&format!(
+        "<D:response><D:href>/{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>\
+         <D:resourcetype/><D:getcontentlength>{}</D:getcontentlength></D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        percent_encode(name),
+        xml_escape(name),
+        len
+    ));
+}
+
+fn get<W: Write>(writer: &mut W, config: &ServeConfig, request: &Request) -> io::Result<()> {
+    let path = match resolve_entry(&config.vault_dir, request.path.trim_start_matches('/'))? {
+        Some(path) => path,
+        None => return respond(writer, "404 Not Found", &[], b""),
+    };
+    let mut reader = match DecryptingReader::open(&path, &config.password) {
+        Ok(reader) => reader,
+        Err(_) => return respond(writer, "403 Forbidden", &[], b""),
+    };
+    let len = reader.seek(SeekFrom::End(0))?;
+
+    let (status, start, end) = match request.range.as_deref() {
+        None => ("200 OK", 0, len),
+        Some(range) => match parse_range(range, len) {
+            Some((start, end)) => ("206 Partial Content", start, end),
+            None => {
+                return respond(writer, "416 Range Not Satisfiable", &[("Content-Range", format!("bytes */{}", len))], b"");
+            }
+        },
+    };
+
+    let mut headers = vec![
+        ("Content-Type", "application/octet-stream".to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+        ("Content-Length", (end - start).to_string()),
+    ];
+    if status.starts_with("206") {
+        headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end - 1, len)));
+    }
+    write_head(writer, status, &headers)?;
+    if request.method == "GET" {
+        reader.seek(SeekFrom::Start(start))?;
+        io::copy(&mut reader.take(end - start), writer)?;
+    }
+    writer.flush()
+}
+
+fn parse_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (len.saturating_sub(suffix), len)
+        }
+        (first, "") => (first.parse().ok()?, len),
+        (first, last) => (first.parse().ok()?, last.parse::<u64>().ok()?.saturating_add(1).min(len)),
+    };
+    (start < end).then_some((start, end))
+}
+
+fn list_entries(vault_dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let policy = NamingPolicy::current();
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(vault_dir)? {
+        let path = dir_entry?.path();
+        if !path.is_file() || !policy.is_encrypted_name(&path) {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+            entries.push((stem.to_string(), path.clone()));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+fn resolve_entry(vault_dir: &Path, name: &str) -> io::Result<Option<PathBuf>> {
+    Ok(list_entries(vault_dir)?.into_iter().find(|(n, _)| n == name).map(|(_, path)| path))
+}
+
+fn plaintext_len(path: &Path, password: &[u8]) -> Result<u64, Box<dyn std::error::Error>> {
+    Ok(DecryptingReader::open(path, password)?.seek(SeekFrom::End(0))?)
+}
+
+fn respond<W: Write>(writer: &mut W, status: &str, headers: &[(&str, String)], body: &[u8]) -> io::Result<()> {
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Length", body.len().to_string()));
+    write_head(writer, status, &headers)?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+fn write_head<W: Write>(writer: &mut W, status: &str, headers: &[(&str, String)]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes())
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn percent_encode(input: &str) -> String {
+    input
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn xml_escape(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt_file_internal;
+
+    fn request(port: u16, raw: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(raw.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serves_listing_and_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.txt");
+        fs::write(&plain, b"hello webdav world").unwrap();
+        let vault = dir.path().join("vault");
+        fs::create_dir(&vault).unwrap();
+        let encrypted = vault.join("my notes.txt.kyl");
+        encrypt_file_internal(plain.to_str().unwrap(), encrypted.to_str().unwrap(), b"pw", None, false, 4).unwrap();
+
+        let mut server = start_server(&vault, b"pw", 0).unwrap();
+        let port = server.port;
+
+        let listing = request(port, "PROPFIND / HTTP/1.1\r\nDepth: 1\r\n\r\n");
+        assert!(listing.starts_with("HTTP/1.1 207"));
+        assert!(listing.contains("<D:href>/my%20notes.txt</D:href>"));
+        assert!(listing.contains("<D:getcontentlength>18</D:getcontentlength>"));
+
+        let full = request(port, "GET /my%20notes.txt HTTP/1.1\r\n\r\n");
+        assert!(full.starts_with("HTTP/1.1 200 OK") && full.ends_with("\r\n\r\nhello webdav world"));
+
+        let partial = request(port, "GET /my%20notes.txt HTTP/1.1\r\nRange: bytes=6-11\r\n\r\n");
+        assert!(partial.starts_with("HTTP/1.1 206"));
+        assert!(partial.contains("Content-Range: bytes 6-11/18") && partial.ends_with("\r\n\r\nwebdav"));
+
+        assert!(request(port, "PUT /x HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+        assert!(request(port, "GET /missing HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(request(port, "GET /my%20notes.txt HTTP/1.1\r\nRange: bytes=40-\r\n\r\n").starts_with("HTTP/1.1 416"));
+        server.stop();
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-", 10), Some((0, 10)));
+        assert_eq!(parse_range("bytes=-4", 10), Some((6, 10)));
+        assert_eq!(parse_range("bytes=2-100", 10), Some((2, 10)));
+        assert_eq!(parse_range("bytes=10-", 10), None);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+    }
+}
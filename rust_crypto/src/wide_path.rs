@@ -0,0 +1,126 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::PathBuf;
+use std::slice;
+
+use crate::{errors, kyrie_core};
+
+// Variants of encrypt_file and decrypt_file for callers holding UTF-16
+// paths, such as Windows APIs. The path goes to the file system untouched,
+// so names with unpaired surrogates and long \\?\ paths open as they are.
+#[no_mangle]
+pub extern "C" fn encrypt_file_w(
+    input_path_ptr: *const u16,
+    output_path_ptr: *const u16,
+    password_ptr: *const u8,
+    password_len: usize,
+    hint_ptr: *const c_char,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let Some(input_path) = wide_path(input_path_ptr) else {
+            return errors::invalid_argument();
+        };
+        let Some(output_path) = wide_path(output_path_ptr) else {
+            return errors::invalid_argument();
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+        let hint = if hint_ptr.is_null() {
+            None
+        } else {
+            CStr::from_ptr(hint_ptr).to_str().ok()
+        };
+
+        let encryptor = kyrie_core::Encryptor::new(password)
+            .with_hint(hint)
+            .for_mobile(is_mobile)
+            .with_cpu_cores(cpu_cores);
+        match encryptor.encrypt_file(&input_path, &output_path) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(&e),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn decrypt_file_w(
+    input_path_ptr: *const u16,
+    output_path_ptr: *const u16,
+    password_ptr: *const u8,
+    password_len: usize,
+    is_mobile: bool,
+    cpu_cores: usize,
+) -> i32 {
+    unsafe {
+        let Some(input_path) = wide_path(input_path_ptr) else {
+            return errors::invalid_argument();
+        };
+        let Some(output_path) = wide_path(output_path_ptr) else {
+            return errors::invalid_argument();
+        };
+        let password = slice::from_raw_parts(password_ptr, password_len);
+
+        let decryptor = kyrie_core::Decryptor::new(password).for_mobile(is_mobile).with_cpu_cores(cpu_cores);
+        match decryptor.decrypt_file(&input_path, &output_path) {
+            Ok(_) => 0,
+            Err(e) => errors::fail(&e),
+        }
+    }
+}
+
+// Reads a NUL-terminated UTF-16 path. Only Windows can name a file with an
+// unpaired surrogate, so elsewhere one makes the path invalid.
+unsafe fn wide_path(ptr: *const u16) -> Option<PathBuf> {
+    if ptr.is_null() {
+        return None;
+    }
+    let len = (0..).take_while(|&i| *ptr.add(i) != 0).count();
+    let wide = slice::from_raw_parts(ptr, len);
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStringExt;
+        Some(PathBuf::from(std::ffi::OsString::from_wide(wide)))
+    }
+    #[cfg(not(windows))]
+    {
+        String::from_utf16(wide).ok().map(PathBuf::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_wide_paths_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("相册").join("долгий путь 📷");
+        fs::create_dir_all(&nested).unwrap();
+        let plain = nested.join("фото.jpg");
+        fs::write(&plain, b"wide").unwrap();
+        let wide = |path: PathBuf| path.to_str().unwrap().encode_utf16().chain([0]).collect::<Vec<u16>>();
+        let (plain_w, encrypted_w, decrypted_w) =
+            (wide(plain.clone()), wide(nested.join("фото.kyl")), wide(nested.join("фото.out")));
+
+        assert_eq!(encrypt_file_w(plain_w.as_ptr(), encrypted_w.as_ptr(), b"pw".as_ptr(), 2, std::ptr::null(), false, 2), 0);
+        assert_eq!(decrypt_file_w(encrypted_w.as_ptr(), decrypted_w.as_ptr(), b"pw".as_ptr(), 2, false, 2), 0);
+        assert_eq!(fs::read(nested.join("фото.out")).unwrap(), b"wide");
+        assert_eq!(decrypt_file_w(encrypted_w.as_ptr(), decrypted_w.as_ptr(), b"no".as_ptr(), 2, false, 2), -2);
+        assert_eq!(errors::kyrie_last_error(), errors::ERR_WRONG_PASSWORD);
+
+        let unpaired = [0x0061, 0xD800, 0x0062, 0];
+        assert_eq!(decrypt_file_w(unpaired.as_ptr(), decrypted_w.as_ptr(), b"pw".as_ptr(), 2, false, 2), -1);
+
+        // Paths need not be UTF-8 on the way through either.
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let raw = nested.join(std::ffi::OsStr::from_bytes(b"raw\xff.kyl"));
+            kyrie_core::Encryptor::new(b"pw").encrypt_file(&plain, &raw).unwrap();
+            kyrie_core::Decryptor::new(b"pw").decrypt_file(&raw, &nested.join("raw.out")).unwrap();
+            assert_eq!(fs::read(nested.join("raw.out")).unwrap(), b"wide");
+        }
+    }
+}